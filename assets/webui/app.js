@@ -0,0 +1,201 @@
+// Talks to the Transmission RPC of the same server, refreshing the list
+// every few seconds.
+"use strict";
+
+const RPC_PATH = "/transmission/rpc";
+const SESSION_ID_HEADER = "X-Transmission-Session-Id";
+const REFRESH_MS = 2000;
+const STATUS = {
+  0: "Stopped",
+  3: "Queued",
+  4: "Downloading",
+  5: "Queued",
+  6: "Seeding",
+};
+const FIELDS = [
+  "id",
+  "name",
+  "status",
+  "percentDone",
+  "rateDownload",
+  "rateUpload",
+  "peersConnected",
+  "eta",
+  "errorString",
+  "peers",
+  "trackerStats",
+];
+
+let sessionId = "";
+let selected = null;
+
+async function rpc(method, args) {
+  const body = JSON.stringify({ method: method, arguments: args || {} });
+  for (;;) {
+    const res = await fetch(RPC_PATH, {
+      method: "POST",
+      headers: { [SESSION_ID_HEADER]: sessionId },
+      body: body,
+    });
+    // The first request tells which session id to use
+    if (res.status === 409) {
+      sessionId = res.headers.get(SESSION_ID_HEADER);
+      continue;
+    }
+    if (!res.ok) {
+      throw new Error("HTTP " + res.status);
+    }
+    const answer = await res.json();
+    if (answer.result !== "success") {
+      throw new Error(answer.result);
+    }
+    return answer.arguments;
+  }
+}
+
+function bytes(n) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (n >= 1024 && i < units.length - 1) {
+    n /= 1024;
+    i++;
+  }
+  return n.toFixed(i === 0 ? 0 : 1) + " " + units[i];
+}
+
+function showError(e) {
+  const error = document.getElementById("error");
+  error.textContent = e ? e.message : "";
+  error.hidden = !e;
+}
+
+function cell(row, content) {
+  const td = row.insertCell();
+  if (content instanceof Node) {
+    td.appendChild(content);
+  } else {
+    td.textContent = content;
+  }
+  return td;
+}
+
+function button(label, onClick) {
+  const b = document.createElement("button");
+  b.textContent = label;
+  b.addEventListener("click", (e) => {
+    e.stopPropagation();
+    onClick().then(refresh).catch(showError);
+  });
+  return b;
+}
+
+function renderDetails(torrents) {
+  const details = document.getElementById("details");
+  const torrent = torrents.find((t) => t.id === selected);
+  details.hidden = !torrent;
+  if (!torrent) {
+    return;
+  }
+  document.getElementById("details-name").textContent = torrent.name;
+  const peers = document.getElementById("details-peers");
+  peers.replaceChildren(
+    ...torrent.peers.map((p) => {
+      const li = document.createElement("li");
+      li.textContent = p.address + ":" + p.port;
+      return li;
+    })
+  );
+  const trackers = document.getElementById("details-trackers");
+  trackers.replaceChildren(
+    ...torrent.trackerStats.map((t) => {
+      const li = document.createElement("li");
+      let text = t.announce + ": " + (t.hasAnnounced ? t.lastAnnounceResult : "Not announced");
+      if (t.seederCount >= 0) {
+        text += " (" + t.seederCount + " seeds, " + t.leecherCount + " leechers)";
+      }
+      li.textContent = text;
+      return li;
+    })
+  );
+}
+
+function render(torrents) {
+  const tbody = document.querySelector("#torrents tbody");
+  tbody.replaceChildren();
+  for (const t of torrents) {
+    const row = tbody.insertRow();
+    row.classList.toggle("selected", t.id === selected);
+    row.addEventListener("click", () => {
+      selected = t.id;
+      render(torrents);
+    });
+    cell(row, t.name);
+    cell(row, STATUS[t.status] || "Unknown");
+    const progress = document.createElement("progress");
+    progress.max = 1;
+    progress.value = t.percentDone;
+    progress.title = (t.percentDone * 100).toFixed(1) + "%";
+    cell(row, progress);
+    cell(row, bytes(t.rateDownload) + "/s");
+    cell(row, bytes(t.rateUpload) + "/s");
+    cell(row, t.peersConnected);
+    const tracker = cell(row, t.errorString || "OK");
+    tracker.classList.toggle("tracker-error", !!t.errorString);
+
+    const actions = document.createElement("span");
+    if (t.status === 0) {
+      actions.appendChild(button("Start", () => rpc("torrent-start", { ids: [t.id] })));
+    } else {
+      actions.appendChild(button("Stop", () => rpc("torrent-stop", { ids: [t.id] })));
+    }
+    actions.appendChild(button("Remove", () => rpc("torrent-remove", { ids: [t.id] })));
+    cell(row, actions);
+  }
+  renderDetails(torrents);
+}
+
+async function refresh() {
+  try {
+    const [list, stats] = await Promise.all([
+      rpc("torrent-get", { fields: FIELDS }),
+      rpc("session-stats"),
+    ]);
+    render(list.torrents);
+    document.getElementById("speed").textContent =
+      "Down " + bytes(stats.downloadSpeed) + "/s, up " + bytes(stats.uploadSpeed) + "/s";
+    showError(null);
+  } catch (e) {
+    showError(e);
+  }
+}
+
+function readBase64(file) {
+  return new Promise((resolve, reject) => {
+    const reader = new FileReader();
+    // Data URL, the content follows the comma
+    reader.onload = () => resolve(reader.result.split(",")[1]);
+    reader.onerror = () => reject(reader.error);
+    reader.readAsDataURL(file);
+  });
+}
+
+document.getElementById("add").addEventListener("submit", async (e) => {
+  e.preventDefault();
+  const uri = document.getElementById("add-uri");
+  const file = document.getElementById("add-file");
+  try {
+    if (file.files.length > 0) {
+      await rpc("torrent-add", { metainfo: await readBase64(file.files[0]) });
+    } else if (uri.value) {
+      await rpc("torrent-add", { filename: uri.value });
+    }
+    uri.value = "";
+    file.value = "";
+    await refresh();
+  } catch (err) {
+    showError(err);
+  }
+});
+
+refresh();
+setInterval(refresh, REFRESH_MS);
@@ -0,0 +1,130 @@
+// How many connected peers have each piece of a torrent. Peers update it from
+// their bitfield, have and have-all messages and remove what they announced
+// when they disconnect.
+use std::sync::{Arc, Mutex};
+
+/// Shared by the peers of a torrent.
+pub type SharedAvailability = Arc<Mutex<Availability>>;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Availability {
+    counts: Vec<u32>,
+    peers: usize,
+}
+
+impl Availability {
+    pub fn new(num_pieces: usize) -> Self {
+        Availability {
+            counts: vec![0; num_pieces],
+            peers: 0,
+        }
+    }
+
+    pub fn shared(num_pieces: usize) -> SharedAvailability {
+        Arc::new(Mutex::new(Availability::new(num_pieces)))
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Peers counted, whether they have pieces or not.
+    pub fn peers(&self) -> usize {
+        self.peers
+    }
+
+    pub fn add_peer(&mut self, have: &[bool]) {
+        self.peers += 1;
+        self.update(&[], have);
+    }
+
+    pub fn remove_peer(&mut self, have: &[bool]) {
+        self.peers = self.peers.saturating_sub(1);
+        self.update(have, &[]);
+    }
+
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.counts.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// A peer's pieces changed from `old` to `new`, e.g. on a bitfield or
+    /// have-all message. Missing entries count as not had.
+    pub fn update(&mut self, old: &[bool], new: &[bool]) {
+        for (index, count) in self.counts.iter_mut().enumerate() {
+            let had = old.get(index).copied().unwrap_or(false);
+            let has = new.get(index).copied().unwrap_or(false);
+            match (had, has) {
+                (false, true) => *count += 1,
+                (true, false) => *count = count.saturating_sub(1),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn get(&self, index: usize) -> u32 {
+        self.counts[index]
+    }
+
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    /// Availability of the rarest piece, 0 when some piece can't be found.
+    pub fn min(&self) -> u32 {
+        self.counts.iter().copied().min().unwrap_or(0)
+    }
+
+    /// Number of full copies in the swarm, the fraction being the share of
+    /// pieces available more than the rarest one.
+    pub fn distributed_copies(&self) -> f64 {
+        let min = self.min();
+        if self.counts.is_empty() {
+            return 0.0;
+        }
+        let above = self.counts.iter().filter(|&&c| c > min).count();
+        min as f64 + above as f64 / self.counts.len() as f64
+    }
+
+    /// Number of pieces by availability, entry `n` counting the pieces that
+    /// `n` peers have.
+    pub fn histogram(&self) -> Vec<usize> {
+        let max = self.counts.iter().copied().max().unwrap_or(0) as usize;
+        let mut res = vec![0; max + 1];
+        for &count in &self.counts {
+            res[count as usize] += 1;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod availability_tests {
+    use super::*;
+
+    #[test]
+    fn peers_come_and_go() {
+        let mut availability = Availability::new(4);
+        let a = vec![true, true, false, false];
+        let seed = vec![true; 4];
+
+        availability.add_peer(&a);
+        availability.add_peer(&[]);
+        availability.add_have(2);
+        assert_eq!(&[1, 1, 1, 0], availability.counts());
+        assert_eq!(0, availability.min());
+        assert_eq!(0.75, availability.distributed_copies());
+
+        // The second peer sends have-all
+        availability.update(&[false, false, true, false], &seed);
+        assert_eq!(&[2, 2, 1, 1], availability.counts());
+        assert_eq!(vec![0, 2, 2], availability.histogram());
+        assert_eq!(1.5, availability.distributed_copies());
+
+        availability.remove_peer(&a);
+        assert_eq!(&[1, 1, 1, 1], availability.counts());
+        assert_eq!(1, availability.peers());
+        assert_eq!(1.0, availability.distributed_copies());
+    }
+}
@@ -0,0 +1,557 @@
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+use crate::uring::{self, Op, OpKind, Ring};
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const DEFAULT_RING_DEPTH: usize = 256;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const DEFAULT_REGISTERED_BUFFERS: usize = 16;
+// A piece of most torrents fits, larger transfers use their own buffer
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub const DEFAULT_FIXED_FILES: usize = 64;
+
+/// Setup of the io_uring instance.
+///
+/// Registered buffers are pinned once instead of on every operation, and
+/// count against the limit of locked memory. The ring goes without them, or
+/// without fixed files, if the kernel refuses to register them.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct UringConfig {
+    // Number of submission queue entries
+    pub depth: usize,
+    // Let a kernel thread poll the submission queue, saves syscalls at the
+    // cost of a busy core
+    pub sq_poll: bool,
+    // Buffers registered with the ring, transfers of up to `buffer_size`
+    // bytes go through them
+    pub registered_buffers: usize,
+    pub buffer_size: usize,
+    // Files registered with the ring, the least recently used make room
+    // for the others
+    pub fixed_files: usize,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl Default for UringConfig {
+    fn default() -> Self {
+        UringConfig {
+            depth: DEFAULT_RING_DEPTH,
+            sq_poll: false,
+            registered_buffers: DEFAULT_REGISTERED_BUFFERS,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            fixed_files: DEFAULT_FIXED_FILES,
+        }
+    }
+}
+
+/// How positioned reads and writes reach the disk.
+///
+/// `Blocking` works everywhere by running `pread`/`pwrite` (or their Windows
+/// equivalent) on tokio's blocking pool. On Linux, io_uring can be used
+/// instead by enabling the `io-uring` feature.
+#[derive(Debug, Clone)]
+pub enum IoBackend {
+    Blocking,
+    // Clones submit to the same ring concurrently
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    Uring(Arc<Ring>),
+}
+
+impl Default for IoBackend {
+    /// io_uring when compiled in and supported by the kernel, blocking I/O
+    /// otherwise.
+    fn default() -> Self {
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        if let Ok(backend) = IoBackend::io_uring() {
+            return backend;
+        }
+
+        IoBackend::Blocking
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn pread(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+pub(crate) fn pwrite(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn pread(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn pwrite(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+/// Positioned read until the buffer is full or the end of the file is
+/// reached, returning the number of bytes read.
+pub(crate) fn pread_full(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        match pread(file, &mut buf[pos..], offset + pos as u64) {
+            Ok(0) => break,
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+/// Positioned read filling the whole buffer, like `read_exact`.
+pub(crate) fn pread_exact(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match pread(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Positioned write of the whole buffer, like `write_all`.
+pub(crate) fn pwrite_all(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match pwrite(file, buf, offset) {
+            Ok(0) => return Err(short_write()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn join_error(e: tokio::task::JoinError) -> io::Error {
+    io::Error::other(e)
+}
+
+fn short_write() -> io::Error {
+    io::Error::new(io::ErrorKind::WriteZero, "Short write")
+}
+
+/// Writes gathered to be submitted together. Writes contiguous with the
+/// previous one are merged into a single operation.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    ops: Vec<(u64, Vec<u8>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn push(&mut self, offset: u64, data: Vec<u8>) {
+        match self.ops.last_mut() {
+            Some((last, buf)) if *last + buf.len() as u64 == offset => buf.extend(data),
+            _ => self.ops.push((offset, data)),
+        }
+    }
+
+    /// Number of operations after merging.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.ops.iter().map(|(_, buf)| buf.len()).sum()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<(u64, Vec<u8>)> {
+        self.ops
+    }
+}
+
+impl IoBackend {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn io_uring() -> io::Result<Self> {
+        IoBackend::io_uring_with(UringConfig::default())
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn io_uring_with(config: UringConfig) -> io::Result<Self> {
+        Ok(IoBackend::Uring(Arc::new(Ring::new(&config)?)))
+    }
+
+    /// Read into `buf` from `offset`, returning the number of bytes read.
+    /// Short transfers are retried, less than `buf.len()` is only returned
+    /// at the end of the file.
+    pub async fn read_at(
+        &self,
+        file: &Arc<File>,
+        buf: &mut Vec<u8>,
+        offset: u64,
+    ) -> io::Result<usize> {
+        match self {
+            IoBackend::Blocking => {
+                // The buffer is moved to the blocking pool and back
+                let file = file.clone();
+                let mut owned = std::mem::take(buf);
+                let (owned, res) = tokio::task::spawn_blocking(move || {
+                    let res = pread_full(&file, &mut owned, offset);
+                    (owned, res)
+                })
+                .await
+                .map_err(join_error)?;
+                *buf = owned;
+                res
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(ring) => {
+                let mut pos = 0;
+                while pos < buf.len() {
+                    let op = Op {
+                        kind: OpKind::Read,
+                        file: file.clone(),
+                        buf: std::mem::take(buf),
+                        pos,
+                        offset: offset + pos as u64,
+                    };
+                    let (owned, res) = ring.run(op).await;
+                    *buf = owned;
+                    match res {
+                        Ok(0) => break,
+                        Ok(n) => pos += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(pos)
+            }
+        }
+    }
+
+    /// Write the whole `buf` at `offset`, returning the number of bytes
+    /// written. Short transfers are retried, a write making no progress is
+    /// an error.
+    pub async fn write_at(
+        &self,
+        file: &Arc<File>,
+        buf: &mut Vec<u8>,
+        offset: u64,
+    ) -> io::Result<usize> {
+        match self {
+            IoBackend::Blocking => {
+                let file = file.clone();
+                let owned = std::mem::take(buf);
+                let (owned, res) = tokio::task::spawn_blocking(move || {
+                    let res = pwrite_all(&file, &owned, offset).map(|_| owned.len());
+                    (owned, res)
+                })
+                .await
+                .map_err(join_error)?;
+                *buf = owned;
+                res
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(ring) => {
+                let (owned, res) =
+                    write_all_uring(ring, file, std::mem::take(buf), 0, offset).await;
+                *buf = owned;
+                res.map(|_| buf.len())
+            }
+        }
+    }
+
+    /// Sync the file data to the disk.
+    pub async fn sync(&self, file: &Arc<File>) -> io::Result<()> {
+        match self {
+            IoBackend::Blocking => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || file.sync_data())
+                    .await
+                    .map_err(join_error)?
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(ring) => {
+                let op = Op {
+                    kind: OpKind::Sync,
+                    file: file.clone(),
+                    buf: Vec::new(),
+                    pos: 0,
+                    offset: 0,
+                };
+                ring.run(op).await.1.map(drop)
+            }
+        }
+    }
+
+    /// Write a whole batch. With io_uring every operation is queued before a
+    /// single submission, and they complete together.
+    pub async fn write_batch(&self, file: &Arc<File>, batch: WriteBatch) -> io::Result<()> {
+        match self {
+            IoBackend::Blocking => {
+                let file = file.clone();
+                tokio::task::spawn_blocking(move || {
+                    for (offset, buf) in &batch.ops {
+                        pwrite_all(&file, buf, *offset)?;
+                    }
+                    Ok(())
+                })
+                .await
+                .map_err(join_error)?
+            }
+            #[cfg(all(target_os = "linux", feature = "io-uring"))]
+            IoBackend::Uring(ring) => {
+                let (offsets, ops): (Vec<u64>, Vec<Op>) = batch
+                    .ops
+                    .into_iter()
+                    .map(|(offset, buf)| {
+                        let op = Op {
+                            kind: OpKind::Write,
+                            file: file.clone(),
+                            buf,
+                            pos: 0,
+                            offset,
+                        };
+                        (offset, op)
+                    })
+                    .unzip();
+                let completions = ring.submit(ops);
+
+                let mut res = Ok(());
+                for (completion, offset) in completions.into_iter().zip(offsets) {
+                    let (buf, done) = uring::wait(completion).await;
+                    match done {
+                        Ok(n) if n == buf.len() => (),
+                        // The rest of a short write is written on its own
+                        Ok(n) if n > 0 => {
+                            let (_, done) = write_all_uring(ring, file, buf, n, offset).await;
+                            res = res.and(done);
+                        }
+                        Ok(_) => res = res.and(Err(short_write())),
+                        Err(e) => res = res.and(Err(e)),
+                    }
+                }
+                res
+            }
+        }
+    }
+}
+
+// Write `buf[pos..]` at `offset + pos`, the buffer is handed back
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn write_all_uring(
+    ring: &Ring,
+    file: &Arc<File>,
+    mut buf: Vec<u8>,
+    mut pos: usize,
+    offset: u64,
+) -> (Vec<u8>, io::Result<()>) {
+    while pos < buf.len() {
+        let op = Op {
+            kind: OpKind::Write,
+            file: file.clone(),
+            buf,
+            pos,
+            offset: offset + pos as u64,
+        };
+        let (owned, res) = ring.run(op).await;
+        buf = owned;
+        match res {
+            Ok(0) => return (buf, Err(short_write())),
+            Ok(n) => pos += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return (buf, Err(e)),
+        }
+    }
+    (buf, Ok(()))
+}
+
+#[cfg(test)]
+mod backend_tests {
+    use super::*;
+    use std::fs;
+
+    async fn roundtrip(backend: IoBackend, path: &str) {
+        let file = Arc::new(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .unwrap(),
+        );
+
+        let mut data = b"hello world".to_vec();
+        assert_eq!(11, backend.write_at(&file, &mut data, 5).await.unwrap());
+        assert_eq!(b"hello world".to_vec(), data);
+
+        let mut buf = vec![0u8; 5];
+        assert_eq!(5, backend.read_at(&file, &mut buf, 11).await.unwrap());
+        assert_eq!(b"world".to_vec(), buf);
+
+        // Reading past the end is a short read, not an error
+        let mut buf = vec![0u8; 10];
+        assert_eq!(6, backend.read_at(&file, &mut buf, 10).await.unwrap());
+
+        drop(file);
+        fs::remove_file(path).unwrap();
+    }
+
+    async fn batch(backend: IoBackend, path: &str) {
+        let file = Arc::new(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .unwrap(),
+        );
+
+        let mut batch = WriteBatch::new();
+        batch.push(0, vec![1; 4]);
+        batch.push(4, vec![2; 4]);
+        batch.push(12, vec![3; 4]);
+        assert_eq!(2, batch.len());
+        assert_eq!(12, batch.bytes());
+
+        backend.write_batch(&file, batch).await.unwrap();
+        backend.sync(&file).await.unwrap();
+        assert_eq!(
+            vec![1, 1, 1, 1, 2, 2, 2, 2, 0, 0, 0, 0, 3, 3, 3, 3],
+            fs::read(path).unwrap()
+        );
+
+        drop(file);
+        fs::remove_file(path).unwrap();
+    }
+
+    // Writes from several tasks share the backend without waiting on each other
+    async fn concurrent(backend: IoBackend, path: &str) {
+        let file = Arc::new(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .unwrap(),
+        );
+
+        let tasks: Vec<_> = (0..8u8)
+            .map(|i| {
+                let backend = backend.clone();
+                let file = file.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![i; 1024];
+                    backend.write_at(&file, &mut buf, i as u64 * 1024).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(1024, task.await.unwrap().unwrap());
+        }
+
+        let content = fs::read(path).unwrap();
+        for (i, chunk) in content.chunks(1024).enumerate() {
+            assert!(chunk.iter().all(|&b| b == i as u8));
+        }
+
+        drop(file);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn blocking_concurrent() {
+        concurrent(IoBackend::Blocking, "./test_blocking_concurrent").await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn uring_concurrent() {
+        concurrent(IoBackend::io_uring().unwrap(), "./test_uring_concurrent").await;
+    }
+
+    #[tokio::test]
+    async fn blocking_batch() {
+        batch(IoBackend::Blocking, "./test_blocking_batch").await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test]
+    async fn uring_batch() {
+        batch(IoBackend::io_uring().unwrap(), "./test_uring_batch").await;
+    }
+
+    #[tokio::test]
+    async fn blocking_roundtrip() {
+        roundtrip(IoBackend::Blocking, "./test_blocking_roundtrip").await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test]
+    async fn uring_roundtrip() {
+        roundtrip(IoBackend::io_uring().unwrap(), "./test_uring_roundtrip").await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test]
+    async fn registered_buffers_and_files() {
+        let backend = IoBackend::io_uring_with(UringConfig {
+            registered_buffers: 2,
+            buffer_size: 8,
+            fixed_files: 1,
+            ..UringConfig::default()
+        })
+        .unwrap();
+        let IoBackend::Uring(ring) = &backend else {
+            unreachable!()
+        };
+        assert_eq!((2, 1), ring.registered());
+
+        // Transfers beyond the size of the buffers, more files than slots
+        roundtrip(backend.clone(), "./test_uring_registered_a").await;
+        roundtrip(backend.clone(), "./test_uring_registered_b").await;
+        batch(backend.clone(), "./test_uring_registered_c").await;
+        concurrent(backend, "./test_uring_registered_d").await;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    #[tokio::test]
+    async fn small_ring() {
+        let backend = IoBackend::io_uring_with(UringConfig {
+            depth: 2,
+            ..UringConfig::default()
+        })
+        .unwrap();
+
+        // More operations than the ring has entries
+        let path = "./test_small_ring";
+        batch(backend.clone(), path).await;
+        concurrent(backend, path).await;
+    }
+}
@@ -0,0 +1,86 @@
+// Command line parsing shared by the subcommands: positional arguments,
+// options taking a value, as `-o DIR` or `--output=DIR`, and switches.
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    options: HashMap<String, String>,
+    switches: HashSet<String>,
+}
+
+impl Args {
+    /// Short and long names of the options are listed separately, e.g.
+    /// `["-o", "--output"]`.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        options: &[&str],
+        switches: &[&str],
+    ) -> Result<Self, String> {
+        let mut res = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                res.positional.push(arg);
+                continue;
+            }
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            if value.is_none() && switches.contains(&name.as_str()) {
+                res.switches.insert(name);
+                continue;
+            }
+            if !options.contains(&name.as_str()) {
+                return Err(format!("Unknown option {}", name));
+            }
+            let value = match value {
+                Some(value) => value,
+                None => args.next().ok_or(format!("{} needs a value", name))?,
+            };
+            res.options.insert(name, value);
+        }
+        Ok(res)
+    }
+
+    /// Value of the first of `names` given.
+    pub fn get(&self, names: &[&str]) -> Option<&str> {
+        names
+            .iter()
+            .find_map(|n| self.options.get(*n))
+            .map(String::as_str)
+    }
+
+    pub fn has(&self, names: &[&str]) -> bool {
+        names.iter().any(|n| self.switches.contains(*n))
+    }
+
+    pub fn parsed<T: std::str::FromStr>(&self, names: &[&str]) -> Result<Option<T>, String> {
+        self.get(names)
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| format!("Invalid value for {}", names[0]))
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod args_tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let args = ["a.torrent", "-o", "out", "--seed=10", "--json"].map(String::from);
+        let args = Args::parse(args, &["-o", "--output", "--seed"], &["--json"]).unwrap();
+        assert_eq!(vec!["a.torrent"], args.positional);
+        assert_eq!(Some("out"), args.get(&["-o", "--output"]));
+        assert_eq!(Ok(Some(10u64)), args.parsed(&["--seed"]));
+        assert!(args.has(&["--json"]));
+        assert!(!args.has(&["--private"]));
+
+        assert!(Args::parse(["--nope".to_string()], &[], &[]).is_err());
+        assert!(Args::parse(["-o".to_string()], &["-o"], &[]).is_err());
+    }
+}
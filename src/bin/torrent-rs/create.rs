@@ -0,0 +1,60 @@
+// `torrent-rs create`: make a `.torrent` file from a file or a directory.
+use std::{error::Error, fs, path::PathBuf};
+
+use torrent_rs::create::TorrentBuilder;
+use torrent_rs::decode_torrent;
+
+use crate::args::Args;
+use crate::format;
+
+pub const USAGE: &str = "\
+Usage: torrent-rs create <path> --tracker URL [options]
+
+Options:
+  --tracker URL        Tracker announce URL
+  --piece-size BYTES   Power of two, picked from the content size by default
+  --private            Only get peers from the tracker (BEP 27)
+  --comment TEXT       Free-form comment
+  -o, --output FILE    Where to save the torrent (default: <name>.torrent)
+  --magnet             Also print the magnet link";
+
+pub fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(
+        args,
+        &["--tracker", "--piece-size", "--comment", "-o", "--output"],
+        &["--private", "--magnet"],
+    )?;
+    let ([path], Some(tracker)) = (&args.positional[..], args.get(&["--tracker"])) else {
+        return Err(USAGE.into());
+    };
+
+    let mut builder = TorrentBuilder::new(path)
+        .announce(tracker)
+        .private(args.has(&["--private"]));
+    if let Some(piece_length) = args.parsed(&["--piece-size"])? {
+        builder = builder.piece_length(piece_length);
+    }
+    if let Some(comment) = args.get(&["--comment"]) {
+        builder = builder.comment(comment);
+    }
+    let torrent = builder.build()?;
+
+    let output = match args.get(&["-o", "--output"]) {
+        Some(output) => PathBuf::from(output),
+        None => PathBuf::from(format!("{}.torrent", torrent.name)),
+    };
+    fs::write(&output, &torrent.bytes)?;
+    println!(
+        "{} ({})",
+        output.display(),
+        format::bytes(torrent.bytes.len() as u64)
+    );
+    println!(
+        "Info hash: {}",
+        decode_torrent::bytes_to_hash(&torrent.info_hash)
+    );
+    if args.has(&["--magnet"]) {
+        println!("{}", torrent.magnet());
+    }
+    Ok(())
+}
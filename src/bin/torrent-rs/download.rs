@@ -0,0 +1,183 @@
+// `torrent-rs download`: fetch a torrent or magnet link with a session,
+// showing the progress until it completes, then seed for a while if asked.
+use std::{
+    error::Error,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use bendy::decoding::FromBencode;
+use tokio::time::{self, Duration, Instant};
+
+use torrent_rs::decode_torrent::{self, MetaInfo, MetaInfoError};
+use torrent_rs::definitions::InfoHash;
+use torrent_rs::dht::DhtConfig;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+use torrent_rs::fuse;
+use torrent_rs::session::{
+    Session, SessionConfig, SessionConfigBuilder, SharedTorrent, DEFAULT_LISTEN_PORT,
+};
+use torrent_rs::stats::TorrentStats;
+use torrent_rs::torrent::{SeedLimitAction, SeedLimits, TorrentState};
+
+use crate::args::Args;
+use crate::format;
+
+pub const USAGE: &str = "\
+Usage: torrent-rs download <file.torrent|magnet> [options]
+
+Options:
+  -o, --output DIR   Directory to save the content in (default: .)
+  -p, --port PORT    Port to listen for peers on, FIRST-LAST for a free one
+                     of a range or random (default: 6881)
+  --seed SECS        Seed for SECS seconds once complete
+  --mount DIR        Show the files in DIR while they download, reads go
+                     first (fuse feature, Linux)
+  --capture FILE     Record the messages exchanged with peers and trackers
+                     to FILE
+  --dht              Find peers in the DHT as well, e.g. without a tracker
+  --dht-bootstrap HOSTS
+                     Join the DHT through HOSTS, as host:port separated by
+                     commas, instead of the usual routers (implies --dht)";
+
+pub const STEP_INTERVAL: Duration = Duration::from_millis(100);
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const BAR_WIDTH: usize = 30;
+
+fn progress_line(stats: &TorrentStats) -> String {
+    let filled = (stats.progress * BAR_WIDTH as f64) as usize;
+    let eta = match stats.eta {
+        Some(eta) => format::duration(eta),
+        None => "-".to_string(),
+    };
+    format!(
+        "[{}{}] {:5.1}%  down {}/s  up {}/s  peers {}/{}  eta {}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        stats.progress * 100.0,
+        format::bytes(stats.download_rate as u64),
+        format::bytes(stats.upload_rate as u64),
+        stats.connected_peers,
+        stats.known_peers,
+        eta
+    )
+}
+
+/// Listen on the port of `-p`: a port, a range `FIRST-LAST` or `random`.
+pub fn listen_port(
+    config: SessionConfigBuilder,
+    port: Option<&str>,
+) -> Result<SessionConfigBuilder, String> {
+    let invalid = || "Invalid value for -p".to_string();
+    Ok(match port {
+        None => config.listen_port(DEFAULT_LISTEN_PORT),
+        Some("random") => config.random_listen_port(),
+        Some(port) => match port.split_once('-') {
+            Some((first, last)) => {
+                let first = first.parse().map_err(|_| invalid())?;
+                let last = last.parse().map_err(|_| invalid())?;
+                config.listen_port_range(first..=last)
+            }
+            None => config.listen_port(port.parse().map_err(|_| invalid())?),
+        },
+    })
+}
+
+/// Decode a `.torrent` file, also used by the other commands.
+pub fn read_torrent(path: &str) -> io::Result<(MetaInfo, InfoHash)> {
+    let bytes = fs::read(path)?;
+    let invalid = |e: MetaInfoError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let meta = MetaInfo::from_bencode(&bytes).map_err(|e| invalid(e.into()))?;
+    Ok((
+        meta,
+        decode_torrent::get_info_hash(&bytes).map_err(invalid)?,
+    ))
+}
+
+async fn add(session: &Session, source: &str) -> Result<SharedTorrent, Box<dyn Error>> {
+    if source.starts_with("magnet:") {
+        return Ok(session.add_magnet(source).await?);
+    }
+    let (meta, info_hash) = read_torrent(source)?;
+    let torrent = session.open_torrent(meta, info_hash).await?;
+    Ok(session.add_torrent(torrent)?)
+}
+
+pub async fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(
+        args,
+        &[
+            "-o",
+            "--output",
+            "-p",
+            "--port",
+            "--seed",
+            "--mount",
+            "--capture",
+            "--dht-bootstrap",
+        ],
+        &["--dht"],
+    )?;
+    let [source] = &args.positional[..] else {
+        return Err(USAGE.into());
+    };
+    let output = PathBuf::from(args.get(&["-o", "--output"]).unwrap_or("."));
+    let seed = args.parsed::<u64>(&["--seed"])?.map(Duration::from_secs);
+
+    fs::create_dir_all(&output)?;
+    let mut config = listen_port(SessionConfig::builder(), args.get(&["-p", "--port"]))?
+        .save_path(output)
+        .seed_limits(SeedLimits {
+            time: Some(seed.unwrap_or(Duration::ZERO)),
+            action: SeedLimitAction::Stop,
+            ..Default::default()
+        });
+    if let Some(capture) = args.get(&["--capture"]) {
+        config = config.capture(capture);
+    }
+    if let Some(hosts) = args.get(&["--dht-bootstrap"]) {
+        config = config.dht(DhtConfig {
+            bootstrap: hosts.split(',').map(str::to_string).collect(),
+            ..Default::default()
+        });
+    } else if args.has(&["--dht"]) {
+        config = config.dht(DhtConfig::default());
+    }
+    let config = config.build()?;
+    let session = Session::new(config).await?;
+    let torrent = add(&session, source).await?;
+    println!("{}", torrent.lock().await.meta().info.name);
+    #[cfg(all(target_os = "linux", feature = "fuse"))]
+    let _mount = match args.get(&["--mount"]) {
+        Some(dir) => Some(fuse::mount(vec![torrent.clone()], dir).await?),
+        None => None,
+    };
+    #[cfg(not(all(target_os = "linux", feature = "fuse")))]
+    if args.get(&["--mount"]).is_some() {
+        return Err("Built without the fuse feature".into());
+    }
+
+    // The torrent announces itself and connects to its peers as it steps
+    let mut last_refresh = Instant::now();
+    loop {
+        session.step().await?;
+
+        let (stats, state) = {
+            let torrent = torrent.lock().await;
+            (torrent.stats(), torrent.state())
+        };
+        if last_refresh.elapsed() >= REFRESH_INTERVAL || state == TorrentState::Stopped {
+            print!("\r{}", progress_line(&stats));
+            io::stdout().flush()?;
+            last_refresh = Instant::now();
+        }
+        // Stopped by the seed limit once complete
+        if state == TorrentState::Stopped {
+            println!();
+            torrent.lock().await.file().lock().await.sync().await?;
+            return Ok(());
+        }
+        time::sleep(STEP_INTERVAL).await;
+    }
+}
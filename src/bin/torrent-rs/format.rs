@@ -0,0 +1,41 @@
+// Human readable sizes and durations for the terminal.
+use std::time::Duration;
+
+const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+pub fn bytes(n: u64) -> String {
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", n),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+pub fn duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod format_tests {
+    use super::*;
+
+    #[test]
+    fn human_readable() {
+        assert_eq!("512 B", bytes(512));
+        assert_eq!("1.5 KiB", bytes(1536));
+        assert_eq!("2.0 GiB", bytes(2 << 30));
+        assert_eq!("42s", duration(Duration::from_secs(42)));
+        assert_eq!("2m05s", duration(Duration::from_secs(125)));
+        assert_eq!("3h20m", duration(Duration::from_secs(12000)));
+    }
+}
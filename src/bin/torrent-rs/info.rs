@@ -0,0 +1,146 @@
+// `torrent-rs info`: describe a torrent or a magnet link, as text or as JSON
+// for scripts.
+use std::error::Error;
+
+use serde_json::{json, Value};
+
+use torrent_rs::decode_torrent::{self, MetaInfo};
+use torrent_rs::definitions::InfoHash;
+use torrent_rs::layout::Layout;
+use torrent_rs::magnet::Magnet;
+
+use crate::args::Args;
+use crate::download::read_torrent;
+use crate::format;
+
+pub const USAGE: &str = "\
+Usage: torrent-rs info <file.torrent|magnet> [options]
+
+Options:
+  --json   Print a JSON object instead";
+
+fn torrent_json(meta: &MetaInfo, info_hash: &InfoHash) -> Result<Value, Box<dyn Error>> {
+    let layout = Layout::from_info(&meta.info)?;
+    let files: Vec<Value> = layout
+        .files()
+        .iter()
+        .filter(|f| !f.attr.padding)
+        .map(|f| json!({ "path": f.path, "length": f.length }))
+        .collect();
+    let mut web_seeds: Vec<&String> = meta.url_list.iter().flatten().collect();
+    web_seeds.extend(meta.http_seeds.iter().flatten());
+
+    Ok(json!({
+        "name": meta.info.name,
+        "info_hash": decode_torrent::bytes_to_hash(info_hash),
+        "size": layout.size(),
+        "piece_size": layout.piece_size(),
+        "pieces": layout.num_pieces(),
+        "files": files,
+        "trackers": [meta.announce],
+        "web_seeds": web_seeds,
+        "comment": meta.comment,
+        "created_by": meta.created_by,
+        "creation_date": meta.creation_date,
+        "private": meta.info.private,
+        "multi_file": meta.info.is_multi_file(),
+    }))
+}
+
+fn magnet_json(magnet: &Magnet) -> Value {
+    let peers: Vec<String> = magnet.peers.iter().map(ToString::to_string).collect();
+    json!({
+        "name": magnet.name,
+        "info_hash": decode_torrent::bytes_to_hash(&magnet.info_hash),
+        "trackers": magnet.trackers,
+        "peers": peers,
+    })
+}
+
+// Order of the fields in the text output, the JSON ones are sorted
+const TEXT_FIELDS: [&str; 14] = [
+    "name",
+    "info_hash",
+    "size",
+    "piece_size",
+    "pieces",
+    "private",
+    "multi_file",
+    "comment",
+    "created_by",
+    "creation_date",
+    "trackers",
+    "web_seeds",
+    "peers",
+    "files",
+];
+
+// Text version of the JSON, the fields are the same either way
+fn print_text(value: &Value) {
+    for key in TEXT_FIELDS {
+        match (key, &value[key]) {
+            (_, Value::Null) => (),
+            (_, Value::Array(values)) if values.is_empty() => (),
+            ("size" | "piece_size", Value::Number(n)) => {
+                println!("{}: {}", key, format::bytes(n.as_u64().unwrap_or(0)))
+            }
+            ("files", Value::Array(files)) => {
+                println!("files:");
+                for file in files {
+                    println!(
+                        "  {} ({})",
+                        file["path"].as_str().unwrap_or_default(),
+                        format::bytes(file["length"].as_u64().unwrap_or(0))
+                    );
+                }
+            }
+            (_, Value::Array(values)) => {
+                println!("{}:", key);
+                for value in values {
+                    println!("  {}", value.as_str().unwrap_or_default());
+                }
+            }
+            (_, Value::String(s)) => println!("{}: {}", key, s),
+            (_, value) => println!("{}: {}", key, value),
+        }
+    }
+}
+
+pub fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(args, &[], &["--json"])?;
+    let [source] = &args.positional[..] else {
+        return Err(USAGE.into());
+    };
+
+    let value = if source.starts_with("magnet:") {
+        magnet_json(&Magnet::parse(source)?)
+    } else {
+        let (meta, info_hash) = read_torrent(source)?;
+        torrent_json(&meta, &info_hash)?
+    };
+    if args.has(&["--json"]) {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    } else {
+        print_text(&value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod info_tests {
+    use super::*;
+
+    #[test]
+    fn test_torrent() {
+        let (meta, info_hash) = read_torrent("./tests/torrent_files/test.torrent").unwrap();
+        let value = torrent_json(&meta, &info_hash).unwrap();
+        assert_eq!(
+            "manjaro-gnome-21.2.1-minimal-220103-linux515.iso",
+            value["name"]
+        );
+        assert_eq!(meta.info.pieces.len() as u64, value["pieces"]);
+        assert_eq!(1, value["files"].as_array().unwrap().len());
+        assert_eq!(false, value["private"]);
+        assert_eq!("udp://tracker.opentrackr.org:1337", value["trackers"][0]);
+    }
+}
@@ -0,0 +1,52 @@
+// Command line client, also the reference integration of the library.
+use std::{env, error::Error, process::ExitCode};
+
+mod args;
+mod create;
+mod download;
+mod format;
+mod info;
+#[cfg(feature = "rpc")]
+mod serve;
+mod verify;
+
+const USAGE: &str = "\
+Usage: torrent-rs <command> [options]
+
+Commands:
+  create     Create a .torrent file from a file or a directory
+  download   Download a torrent or a magnet link
+  info       Show what a torrent or a magnet link contains
+  serve      Run a session controlled over RPC (rpc feature)
+  verify     Check downloaded data against a torrent
+
+Run `torrent-rs <command>` without arguments for its options.";
+
+async fn run(command: &str, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    match command {
+        "create" => create::run(args),
+        "download" => download::run(args).await,
+        "info" => info::run(args),
+        #[cfg(feature = "rpc")]
+        "serve" => serve::run(args).await,
+        "verify" => verify::run(args),
+        _ => Err(USAGE.into()),
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+
+    match run(&command, args.collect()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
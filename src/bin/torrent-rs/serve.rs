@@ -0,0 +1,125 @@
+// `torrent-rs serve`: run a session in the background, controlled with the
+// Transmission RPC, and the web UI when built with it.
+use std::{error::Error, fs, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use tokio::{signal, time};
+
+#[cfg(feature = "rss")]
+use torrent_rs::feed::{Feed, FeedConfig, FeedFilter};
+#[cfg(feature = "geoip")]
+use torrent_rs::geoip::GeoIp;
+use torrent_rs::rpc::Rpc;
+use torrent_rs::session::{Session, SessionConfig};
+use torrent_rs::watch::{self, WatchConfig};
+
+use crate::args::Args;
+use crate::download::{self, STEP_INTERVAL};
+
+pub const USAGE: &str = "\
+Usage: torrent-rs serve [options]
+
+Options:
+  -o, --output DIR   Directory to save the content in (default: .)
+  -p, --port PORT    Port to listen for peers on, FIRST-LAST for a free one
+                     of a range or random (default: 6881)
+  --rpc ADDR         Address of the RPC server (default: 127.0.0.1:9091)
+  --auth USER:PASS   Credentials required by the RPC
+  --state DIR        Directory the torrents are saved in between runs
+  --watch DIR        Add the .torrent and .magnet files dropped in DIR
+  --feed URL         Add the torrents of an RSS or Atom feed (rss feature)
+  --feed-filter RE   Only the feed entries whose title matches RE
+  --geoip DB[,DB]    Locate peers with MaxMind databases (geoip feature)";
+
+const DEFAULT_RPC_ADDR: &str = "127.0.0.1:9091";
+
+pub async fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(
+        args,
+        &[
+            "-o",
+            "--output",
+            "-p",
+            "--port",
+            "--rpc",
+            "--auth",
+            "--state",
+            "--watch",
+            "--feed",
+            "--feed-filter",
+            "--geoip",
+        ],
+        &[],
+    )?;
+    if !args.positional.is_empty() {
+        return Err(USAGE.into());
+    }
+    let output = PathBuf::from(args.get(&["-o", "--output"]).unwrap_or("."));
+    let rpc_addr: SocketAddr = args.get(&["--rpc"]).unwrap_or(DEFAULT_RPC_ADDR).parse()?;
+
+    fs::create_dir_all(&output)?;
+    let mut config = download::listen_port(SessionConfig::builder(), args.get(&["-p", "--port"]))?
+        .save_path(output);
+    if let Some(dir) = args.get(&["--state"]) {
+        config = config.state_dir(dir);
+    }
+    #[cfg(feature = "geoip")]
+    if let Some(paths) = args.get(&["--geoip"]) {
+        let paths: Vec<&str> = paths.split(',').collect();
+        config = config.geoip(GeoIp::open_all(&paths)?);
+    }
+    #[cfg(not(feature = "geoip"))]
+    if args.get(&["--geoip"]).is_some() {
+        return Err("Built without the geoip feature".into());
+    }
+    let config = config.build()?;
+    let session = Arc::new(Session::new(config).await?);
+    let mut rpc = Rpc::new(session.clone());
+    if let Some(auth) = args.get(&["--auth"]) {
+        let (user, password) = auth.split_once(':').ok_or("--auth needs USER:PASS")?;
+        rpc = rpc.credentials(user, password);
+    }
+    let (addr, _server) = rpc.serve(rpc_addr)?;
+    println!("Listening on http://{}", addr);
+    println!("Peers connect on port {}", session.listen_port());
+    let _watcher = match args.get(&["--watch"]) {
+        Some(dir) => Some(watch::watch(session.clone(), WatchConfig::new(dir))?),
+        None => None,
+    };
+    #[cfg(feature = "rss")]
+    let _feed = match args.get(&["--feed"]) {
+        Some(url) => {
+            let mut config = FeedConfig::new(url);
+            if let Some(pattern) = args.get(&["--feed-filter"]) {
+                config.filters.push(FeedFilter::new().include(pattern)?);
+            }
+            Some(Feed::new(session.clone(), config).spawn())
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "rss"))]
+    if args.get(&["--feed"]).is_some() {
+        return Err("Built without the rss feature".into());
+    }
+
+    // Torrents announce themselves and connect to their peers as they step
+    loop {
+        session.step().await?;
+
+        tokio::select! {
+            _ = time::sleep(STEP_INTERVAL) => (),
+            res = signal::ctrl_c() => {
+                res?;
+                break;
+            }
+        }
+    }
+
+    // Nothing received is lost on exit
+    for info_hash in session.list() {
+        if let Some(torrent) = session.get(&info_hash) {
+            torrent.lock().await.file().lock().await.sync().await?;
+        }
+    }
+    session.save_state().await?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+// `torrent-rs verify`: check the data of a torrent on disk against its piece
+// hashes, without modifying anything.
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+};
+
+use sha1::{Digest, Sha1};
+
+use torrent_rs::layout::Layout;
+use torrent_rs::tracker::hash_to_bytes;
+
+use crate::args::Args;
+use crate::download::read_torrent;
+
+pub const USAGE: &str = "\
+Usage: torrent-rs verify <file.torrent> [options]
+
+Options:
+  --data DIR   Directory holding the content (default: .)";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FileStatus {
+    Complete,
+    // Valid pieces out of the pieces of the file
+    Incomplete(usize, usize),
+    Missing,
+    // Also incomplete, a file of the wrong size can't be right
+    WrongSize(u64),
+}
+
+fn read_slice(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+// Whether each piece matches its hash. Missing and short files only fail the
+// pieces they are part of.
+fn check_pieces(layout: &Layout, root: &Path, hashes: &[String]) -> Vec<bool> {
+    let mut files: Vec<Option<File>> = layout
+        .files()
+        .iter()
+        .map(|f| File::open(root.join(&f.path)).ok())
+        .collect();
+
+    (0..layout.num_pieces())
+        .map(|index| {
+            let mut hasher = Sha1::new();
+            let slices = layout
+                .piece_len(index)
+                .and_then(|len| layout.slices(index, 0, len))
+                .unwrap_or_default();
+            for slice in slices {
+                let mut buf = vec![0; slice.length];
+                // Padding is zeros
+                if !layout.files()[slice.file].attr.padding {
+                    let Some(file) = &mut files[slice.file] else {
+                        return false;
+                    };
+                    if read_slice(file, slice.offset, &mut buf).is_err() {
+                        return false;
+                    }
+                }
+                hasher.update(&buf);
+            }
+            hash_to_bytes(&hashes[index]).is_ok_and(|h| hasher.finalize()[..] == h[..])
+        })
+        .collect()
+}
+
+fn file_status(layout: &Layout, root: &Path, file: usize, pieces: &[bool]) -> FileStatus {
+    let f = &layout.files()[file];
+    let length = match fs::metadata(root.join(&f.path)) {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return FileStatus::Missing,
+    };
+    if length != f.length {
+        return FileStatus::WrongSize(length);
+    }
+    let range = layout.file_pieces(file);
+    let valid = range.clone().filter(|&i| pieces[i]).count();
+    match range.len() {
+        total if valid == total => FileStatus::Complete,
+        total => FileStatus::Incomplete(valid, total),
+    }
+}
+
+// Consecutive indexes of the pieces failing the check
+fn bad_ranges(pieces: &[bool]) -> Vec<Range<usize>> {
+    let mut res: Vec<Range<usize>> = Vec::new();
+    for index in (0..pieces.len()).filter(|&i| !pieces[i]) {
+        match res.last_mut() {
+            Some(last) if last.end == index => last.end += 1,
+            _ => res.push(index..index + 1),
+        }
+    }
+    res
+}
+
+pub fn run(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let args = Args::parse(args, &["--data"], &[])?;
+    let [source] = &args.positional[..] else {
+        return Err(USAGE.into());
+    };
+    let root = Path::new(args.get(&["--data"]).unwrap_or("."));
+
+    let (meta, _) = read_torrent(source)?;
+    let layout = Layout::from_info(&meta.info)?;
+    let pieces = check_pieces(&layout, root, &meta.info.pieces);
+
+    for (file, f) in layout.files().iter().enumerate() {
+        if f.attr.padding {
+            continue;
+        }
+        let status = match file_status(&layout, root, file, &pieces) {
+            FileStatus::Complete => "complete".to_string(),
+            FileStatus::Incomplete(valid, total) => {
+                format!("incomplete, {}/{} pieces", valid, total)
+            }
+            FileStatus::Missing => "missing".to_string(),
+            FileStatus::WrongSize(length) => {
+                format!("wrong size, {} instead of {} bytes", length, f.length)
+            }
+        };
+        println!("{}: {}", f.path.display(), status);
+    }
+
+    let bad = bad_ranges(&pieces);
+    let valid = pieces.iter().filter(|&&p| p).count();
+    println!("{}/{} pieces valid", valid, pieces.len());
+    if bad.is_empty() {
+        return Ok(());
+    }
+    let bad: Vec<String> = bad
+        .iter()
+        .map(|r| match r.len() {
+            1 => r.start.to_string(),
+            _ => format!("{}-{}", r.start, r.end - 1),
+        })
+        .collect();
+    println!("Bad pieces: {}", bad.join(", "));
+    Err("Incomplete".into())
+}
+
+#[cfg(test)]
+mod verify_tests {
+    use super::*;
+    use torrent_rs::create::TorrentBuilder;
+
+    #[test]
+    fn corrupted_piece() {
+        const DIR: &str = "./test_verify_corrupted_piece";
+        const PIECE: usize = 16384;
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(format!("{}/content", DIR)).unwrap();
+        fs::write(format!("{}/content/a", DIR), vec![1; PIECE]).unwrap();
+        fs::write(format!("{}/content/b", DIR), vec![2; 3 * PIECE]).unwrap();
+        let torrent = TorrentBuilder::new(format!("{}/content", DIR))
+            .announce("udp://x:1")
+            .piece_length(PIECE)
+            .build()
+            .unwrap();
+        let file = format!("{}/content.torrent", DIR);
+        fs::write(&file, &torrent.bytes).unwrap();
+
+        let (meta, _) = read_torrent(&file).unwrap();
+        let layout = Layout::from_info(&meta.info).unwrap();
+        let root = Path::new(DIR);
+        assert!(check_pieces(&layout, root, &meta.info.pieces)
+            .iter()
+            .all(|&p| p));
+
+        let mut data = vec![2; 3 * PIECE];
+        data[PIECE] = 0;
+        fs::write(format!("{}/content/b", DIR), data).unwrap();
+        let pieces = check_pieces(&layout, root, &meta.info.pieces);
+        assert_eq!(vec![true, true, false, true], pieces);
+        assert_eq!(FileStatus::Complete, file_status(&layout, root, 0, &pieces));
+        assert_eq!(
+            FileStatus::Incomplete(2, 3),
+            file_status(&layout, root, 1, &pieces)
+        );
+        assert_eq!(vec![2..3], bad_ranges(&pieces));
+
+        fs::remove_file(format!("{}/content/a", DIR)).unwrap();
+        let pieces = check_pieces(&layout, root, &meta.info.pieces);
+        assert_eq!(vec![false, true, false, true], pieces);
+        assert_eq!(FileStatus::Missing, file_status(&layout, root, 0, &pieces));
+        assert_eq!(vec![0..1, 2..3], bad_ranges(&pieces));
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+// State of the blocks of the pieces being downloaded. Blocks of one piece can
+// come from several peers, a block is only requested again once it timed out
+// or its peer went away.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::definitions::BLOCK_SIZE;
+use crate::resume::UnfinishedPiece;
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockState {
+    Missing,
+    Requested { peer: SocketAddr, at: Instant },
+    // Requested too long ago, the block may be requested from someone else
+    // but is still accepted from the first peer
+    TimedOut { peer: SocketAddr },
+    Received,
+}
+
+impl BlockState {
+    /// The block can be requested.
+    pub fn is_needed(&self) -> bool {
+        matches!(self, BlockState::Missing | BlockState::TimedOut { .. })
+    }
+}
+
+#[derive(Debug)]
+pub struct BlockTracker {
+    piece_size: usize,
+    size: u64,
+    timeout: Duration,
+    // Pieces with at least one block requested or received
+    pieces: HashMap<usize, Vec<BlockState>>,
+}
+
+impl BlockTracker {
+    pub fn new(piece_size: usize, size: u64) -> Self {
+        assert!(piece_size > 0);
+        BlockTracker {
+            piece_size,
+            size,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            pieces: HashMap::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        (self.size as usize).div_ceil(self.piece_size)
+    }
+
+    pub fn piece_len(&self, index: usize) -> usize {
+        let start = (index * self.piece_size) as u64;
+        (self.size - start).min(self.piece_size as u64) as usize
+    }
+
+    pub fn num_blocks(&self, index: usize) -> usize {
+        self.piece_len(index).div_ceil(BLOCK_SIZE)
+    }
+
+    /// Offset in the piece and length of a block, the last one may be
+    /// shorter.
+    pub fn block_range(&self, index: usize, block: usize) -> (usize, usize) {
+        let begin = block * BLOCK_SIZE;
+        (begin, BLOCK_SIZE.min(self.piece_len(index) - begin))
+    }
+
+    fn blocks_mut(&mut self, index: usize) -> &mut Vec<BlockState> {
+        let num_blocks = self.num_blocks(index);
+        self.pieces
+            .entry(index)
+            .or_insert_with(|| vec![BlockState::Missing; num_blocks])
+    }
+
+    pub fn blocks(&self, index: usize) -> Option<&[BlockState]> {
+        self.pieces.get(&index).map(Vec::as_slice)
+    }
+
+    pub fn is_in_progress(&self, index: usize) -> bool {
+        self.pieces.contains_key(&index)
+    }
+
+    /// Pieces with blocks requested or received, in no particular order.
+    pub fn in_progress(&self) -> impl Iterator<Item = usize> + '_ {
+        self.pieces.keys().copied()
+    }
+
+    /// Blocks of the piece still to request, all of them if the piece
+    /// wasn't started.
+    pub fn needed_blocks(&self, index: usize) -> Vec<usize> {
+        match self.pieces.get(&index) {
+            Some(blocks) => (0..blocks.len())
+                .filter(|&b| blocks[b].is_needed())
+                .collect(),
+            None => (0..self.num_blocks(index)).collect(),
+        }
+    }
+
+    pub fn request(&mut self, index: usize, block: usize, peer: SocketAddr, now: Instant) {
+        self.blocks_mut(index)[block] = BlockState::Requested { peer, at: now };
+    }
+
+    /// The request wasn't sent, the block is missing again.
+    pub fn cancel(&mut self, index: usize, block: usize) {
+        if let Some(state) = self.pieces.get_mut(&index).and_then(|b| b.get_mut(block)) {
+            if *state != BlockState::Received {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Record a block received at `begin` in the piece. Returns `false` for
+    /// blocks which weren't expected: already received, not aligned on a
+    /// block or out of the piece.
+    pub fn received(&mut self, index: usize, begin: usize) -> bool {
+        if index >= self.num_pieces() || !begin.is_multiple_of(BLOCK_SIZE) {
+            return false;
+        }
+        let block = begin / BLOCK_SIZE;
+        match self.blocks_mut(index).get_mut(block) {
+            Some(state) if *state != BlockState::Received => {
+                *state = BlockState::Received;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_complete(&self, index: usize) -> bool {
+        self.pieces
+            .get(&index)
+            .is_some_and(|blocks| blocks.iter().all(|b| *b == BlockState::Received))
+    }
+
+    /// Forget the piece, once verified, or to download it again from scratch
+    /// after a hash failure.
+    pub fn remove(&mut self, index: usize) {
+        self.pieces.remove(&index);
+    }
+
+    /// Pieces with blocks received, to save them in the resume data.
+    pub fn unfinished(&self) -> Vec<UnfinishedPiece> {
+        let mut res: Vec<UnfinishedPiece> = self
+            .pieces
+            .iter()
+            .filter(|(_, blocks)| blocks.contains(&BlockState::Received))
+            .map(|(&index, blocks)| UnfinishedPiece {
+                index,
+                blocks: blocks.iter().map(|b| *b == BlockState::Received).collect(),
+            })
+            .collect();
+        res.sort_unstable_by_key(|p| p.index);
+        res
+    }
+
+    /// Blocks received before a restart. Returns `false` if the piece
+    /// doesn't fit the torrent.
+    pub fn restore(&mut self, piece: &UnfinishedPiece) -> bool {
+        if piece.index >= self.num_pieces() || piece.blocks.len() != self.num_blocks(piece.index) {
+            return false;
+        }
+        let blocks = self.blocks_mut(piece.index);
+        for (state, &received) in blocks.iter_mut().zip(&piece.blocks) {
+            if received {
+                *state = BlockState::Received;
+            }
+        }
+        true
+    }
+
+    /// Requests of `peer` which haven't been answered.
+    pub fn requests_of(&self, peer: SocketAddr) -> Vec<(usize, usize)> {
+        let mut res = Vec::new();
+        for (&index, blocks) in &self.pieces {
+            for (block, state) in blocks.iter().enumerate() {
+                if matches!(state, BlockState::Requested { peer: p, .. } if *p == peer) {
+                    res.push((index, block));
+                }
+            }
+        }
+        res
+    }
+
+    /// The peer disconnected or choked us, its pending blocks are missing
+    /// again. Returns how many were reclaimed.
+    pub fn peer_gone(&mut self, peer: SocketAddr) -> usize {
+        let mut reclaimed = 0;
+        for blocks in self.pieces.values_mut() {
+            for state in blocks.iter_mut() {
+                let pending = match state {
+                    BlockState::Requested { peer: p, .. } | BlockState::TimedOut { peer: p } => {
+                        *p == peer
+                    }
+                    _ => false,
+                };
+                if pending {
+                    *state = BlockState::Missing;
+                    reclaimed += 1;
+                }
+            }
+        }
+        reclaimed
+    }
+
+    /// Mark requests older than the timeout as timed out. Returns the
+    /// blocks which timed out.
+    pub fn expire(&mut self, now: Instant) -> Vec<(usize, usize)> {
+        let mut res = Vec::new();
+        for (&index, blocks) in self.pieces.iter_mut() {
+            for (block, state) in blocks.iter_mut().enumerate() {
+                if let BlockState::Requested { peer, at } = *state {
+                    if now.duration_since(at) >= self.timeout {
+                        *state = BlockState::TimedOut { peer };
+                        res.push((index, block));
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod blocks_tests {
+    use super::*;
+
+    #[test]
+    fn share_a_piece() {
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        // Two pieces of 3 blocks, the last block is 100 bytes
+        let mut tracker = BlockTracker::new(3 * BLOCK_SIZE, (5 * BLOCK_SIZE + 100) as u64);
+        assert_eq!(2, tracker.num_pieces());
+        assert_eq!((2 * BLOCK_SIZE, 100), tracker.block_range(1, 2));
+        assert_eq!(vec![0, 1, 2], tracker.needed_blocks(1));
+
+        let now = Instant::now();
+        tracker.request(0, 0, a, now);
+        tracker.request(0, 1, b, now);
+        assert_eq!(vec![2], tracker.needed_blocks(0));
+        assert_eq!(vec![(0, 1)], tracker.requests_of(b));
+
+        assert!(tracker.received(0, 0));
+        assert!(!tracker.received(0, 0));
+        assert!(!tracker.received(0, 10));
+
+        // b goes away, only its block is requested again
+        assert_eq!(1, tracker.peer_gone(b));
+        assert_eq!(vec![1, 2], tracker.needed_blocks(0));
+
+        tracker.request(0, 1, a, now);
+        tracker.request(0, 2, a, now);
+        let later = now + DEFAULT_REQUEST_TIMEOUT;
+        assert_eq!(2, tracker.expire(later).len());
+        assert_eq!(vec![1, 2], tracker.needed_blocks(0));
+
+        // A late block is still welcome
+        assert!(tracker.received(0, BLOCK_SIZE));
+        assert!(tracker.received(0, 2 * BLOCK_SIZE));
+        assert!(tracker.is_complete(0));
+
+        tracker.remove(0);
+        assert!(!tracker.is_in_progress(0));
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::file::Piece;
+
+// 64 MiB, i.e. 16 pieces of 4 MiB
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// In-memory pieces of a file, bounded by a byte budget. Once the budget is
+/// exceeded the least recently used pieces are evicted.
+#[derive(Debug)]
+pub struct PieceCache {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    // piece index -> (last use, piece)
+    pieces: HashMap<usize, (u64, Piece)>,
+    // last use -> piece index, oldest first
+    lru: BTreeMap<u64, usize>,
+}
+
+impl Default for PieceCache {
+    fn default() -> Self {
+        PieceCache::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl PieceCache {
+    pub fn new(budget: usize) -> Self {
+        PieceCache {
+            budget,
+            used: 0,
+            tick: 0,
+            pieces: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Bytes currently held by cached pieces.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.pieces.contains_key(&index)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Mark the piece as recently used.
+    pub fn touch(&mut self, index: usize) {
+        let tick = self.next_tick();
+        if let Some((last, _)) = self.pieces.get_mut(&index) {
+            self.lru.remove(last);
+            *last = tick;
+            self.lru.insert(tick, index);
+        }
+    }
+
+    /// Access a piece without changing its position in the LRU order.
+    pub fn peek(&self, index: usize) -> Option<&Piece> {
+        self.pieces.get(&index).map(|(_, p)| p)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Piece> {
+        self.touch(index);
+        self.pieces.get_mut(&index).map(|(_, p)| p)
+    }
+
+    /// Cache a piece and return the pieces evicted to make room for it. Clean
+    /// pieces are dropped, dirty ones are handed back so they can be written
+    /// to disk. Pieces still being downloaded are never evicted.
+    pub fn insert(&mut self, index: usize, piece: Piece) -> Vec<(usize, Piece)> {
+        self.remove(index);
+
+        let tick = self.next_tick();
+        self.used += piece.bytes.len();
+        self.pieces.insert(index, (tick, piece));
+        self.lru.insert(tick, index);
+
+        let mut evicted = vec![];
+        while self.used > self.budget {
+            let oldest = self
+                .lru
+                .values()
+                .copied()
+                .find(|&i| i != index && !self.pieces[&i].1.is_downloading());
+            let Some(oldest) = oldest else {
+                break;
+            };
+
+            let piece = self.remove(oldest).unwrap();
+            if piece.is_dirty() {
+                evicted.push((oldest, piece));
+            }
+        }
+
+        evicted
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Piece> {
+        let (tick, piece) = self.pieces.remove(&index)?;
+        self.lru.remove(&tick);
+        self.used -= piece.bytes.len();
+        Some(piece)
+    }
+
+    pub fn dirty_bytes(&self) -> usize {
+        self.pieces.values().map(|(_, p)| p.dirty_bytes()).sum()
+    }
+
+    /// Indexes of the pieces holding data which isn't on disk yet.
+    pub fn dirty(&self) -> Vec<usize> {
+        let mut res: Vec<usize> = self
+            .pieces
+            .iter()
+            .filter(|(_, (_, p))| p.is_dirty())
+            .map(|(&i, _)| i)
+            .collect();
+        res.sort_unstable();
+        res
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+    use crate::backend::IoBackend;
+
+    fn piece(backend: &IoBackend, size: usize) -> Piece {
+        Piece::new(size, size, backend.clone())
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let backend = IoBackend::Blocking;
+        let mut cache = PieceCache::new(300);
+
+        assert!(cache.insert(0, piece(&backend, 100)).is_empty());
+        assert!(cache.insert(1, piece(&backend, 100)).is_empty());
+        assert!(cache.insert(2, piece(&backend, 100)).is_empty());
+        assert_eq!(300, cache.used());
+
+        // 0 becomes the most recently used, so 1 goes first
+        cache.touch(0);
+        assert!(cache.insert(3, piece(&backend, 100)).is_empty());
+
+        assert!(cache.contains(0));
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+        assert!(cache.contains(3));
+        assert_eq!(300, cache.used());
+    }
+
+    #[test]
+    fn dirty_pieces_are_returned() {
+        let backend = IoBackend::Blocking;
+        let mut cache = PieceCache::new(200);
+
+        cache.insert(0, piece(&backend, 100));
+        cache.insert(1, piece(&backend, 100));
+        cache.get_mut(0).unwrap().update(0, &[1, 2, 3]).unwrap();
+        cache.touch(1);
+        assert_eq!(vec![0], cache.dirty());
+
+        let evicted = cache.insert(2, piece(&backend, 100));
+        assert_eq!(1, evicted.len());
+        assert_eq!(0, evicted[0].0);
+        assert_eq!(&[1, 2, 3], &evicted[0].1.bytes[..3]);
+        assert_eq!(2, cache.len());
+    }
+
+    #[test]
+    fn downloading_pieces_are_pinned() {
+        let backend = IoBackend::Blocking;
+        let mut cache = PieceCache::new(200);
+
+        cache.insert(0, piece(&backend, 100));
+        cache.get_mut(0).unwrap().add_block(0, &[1; 10]).unwrap();
+        cache.insert(1, piece(&backend, 100));
+        cache.insert(2, piece(&backend, 100));
+
+        assert!(cache.contains(0));
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2));
+    }
+
+    #[test]
+    fn oversized_piece_is_kept() {
+        let backend = IoBackend::Blocking;
+        let mut cache = PieceCache::new(50);
+
+        cache.insert(0, piece(&backend, 10));
+        cache.insert(1, piece(&backend, 100));
+
+        assert!(!cache.contains(0));
+        assert!(cache.contains(1));
+        assert_eq!(100, cache.used());
+    }
+}
@@ -0,0 +1,212 @@
+// Capture of the messages exchanged with peers and trackers, to debug
+// interoperability problems. Each message is a line of the capture file:
+//
+//     <microseconds since 1970> <in|out> <peer|tracker> <address> <hex bytes>
+//
+// Peer messages are whole frames, length prefix included, handshakes are
+// recorded as sent. A capture can be read back with `read` and its bytes
+// replayed to a connection.
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufRead, LineWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const HEADER: &str = "# torrent-rs capture v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Peer,
+    Tracker,
+}
+
+/// A message as captured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub protocol: Protocol,
+    // Remote end of the connection
+    pub addr: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// File the messages are written to, shared by every connection which
+/// clones it. Records are flushed as they are written.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    out: Arc<Mutex<LineWriter<File>>>,
+}
+
+impl Capture {
+    /// Capture to `path`, replacing what it held.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = LineWriter::new(File::create(path)?);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Capture {
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    pub fn record(&self, direction: Direction, protocol: Protocol, addr: SocketAddr, data: &[u8]) {
+        let record = Record {
+            time: SystemTime::now(),
+            direction,
+            protocol,
+            addr,
+            data: data.to_vec(),
+        };
+        // A failed write only loses the record, the connection goes on
+        let _ = writeln!(self.out.lock().unwrap(), "{}", record);
+    }
+
+    /// Record a message received from a peer.
+    pub fn peer_in(&self, addr: SocketAddr, data: &[u8]) {
+        self.record(Direction::In, Protocol::Peer, addr, data);
+    }
+
+    /// Record a message sent to a peer.
+    pub fn peer_out(&self, addr: SocketAddr, data: &[u8]) {
+        self.record(Direction::Out, Protocol::Peer, addr, data);
+    }
+}
+
+/// Records of a capture, in the order they were written. Comments and empty
+/// lines are skipped.
+pub fn read(reader: impl BufRead) -> impl Iterator<Item = io::Result<Record>> {
+    reader.lines().filter_map(|line| match line {
+        Ok(line) if line.is_empty() || line.starts_with('#') => None,
+        Ok(line) => Some(line.parse()),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        })
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Peer => "peer",
+            Protocol::Tracker => "tracker",
+        })
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let micros = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        write!(
+            f,
+            "{} {} {} {} ",
+            micros, self.direction, self.protocol, self.addr
+        )?;
+        self.data.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid capture record: {}", line),
+    )
+}
+
+impl FromStr for Record {
+    type Err = io::Error;
+
+    fn from_str(line: &str) -> io::Result<Self> {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let [micros, direction, protocol, addr, data] = fields[..] else {
+            return Err(invalid(line));
+        };
+        let micros: u64 = micros.parse().map_err(|_| invalid(line))?;
+        let direction = match direction {
+            "in" => Direction::In,
+            "out" => Direction::Out,
+            _ => return Err(invalid(line)),
+        };
+        let protocol = match protocol {
+            "peer" => Protocol::Peer,
+            "tracker" => Protocol::Tracker,
+            _ => return Err(invalid(line)),
+        };
+        if data.len() % 2 != 0 || !data.is_ascii() {
+            return Err(invalid(line));
+        }
+        let data = (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&data[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid(line))?;
+
+        Ok(Record {
+            time: UNIX_EPOCH + Duration::from_micros(micros),
+            direction,
+            protocol,
+            addr: addr.parse().map_err(|_| invalid(line))?,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod capture_tests {
+    use super::*;
+    use std::fs;
+    use std::io::BufReader;
+
+    #[test]
+    fn records_read_back() {
+        const FILE: &str = "./test_capture_records";
+        let capture = Capture::create(FILE).unwrap();
+        let peer: SocketAddr = "[::1]:6881".parse().unwrap();
+        let tracker: SocketAddr = "127.0.0.1:6969".parse().unwrap();
+        capture.peer_out(peer, &[0, 0, 0, 1, 2]);
+        capture.clone().peer_in(peer, &[0, 0, 0, 0]);
+        capture.record(Direction::Out, Protocol::Tracker, tracker, &[0xff; 16]);
+
+        let records: Vec<Record> = read(BufReader::new(File::open(FILE).unwrap()))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        fs::remove_file(FILE).unwrap();
+
+        assert_eq!(3, records.len());
+        assert_eq!(
+            (Direction::Out, Protocol::Peer, peer),
+            (records[0].direction, records[0].protocol, records[0].addr)
+        );
+        assert_eq!(vec![0, 0, 0, 1, 2], records[0].data);
+        assert_eq!(vec![0, 0, 0, 0], records[1].data);
+        assert_eq!(Protocol::Tracker, records[2].protocol);
+        assert_eq!(vec![0xff; 16], records[2].data);
+        assert!(records[0].time <= records[2].time);
+
+        // Written as parsed, to the microsecond
+        let line = "1700000000000001 in peer 10.0.0.1:51413 00000005040000002a";
+        assert_eq!(line, line.parse::<Record>().unwrap().to_string());
+        assert!("1 in peer 10.0.0.1:1 0".parse::<Record>().is_err());
+        assert!("1 up peer 10.0.0.1:1 00".parse::<Record>().is_err());
+    }
+}
@@ -0,0 +1,369 @@
+// Creation of `.torrent` files from a file or a directory on disk
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bendy::encoding::{AsString, SingleItemEncoder, ToBencode};
+use sha1::{Digest, Sha1};
+
+use crate::definitions::{InfoHash, BLOCK_SIZE};
+use crate::magnet::Magnet;
+
+pub const CREATED_BY: &str = concat!("torrent-rs ", env!("CARGO_PKG_VERSION"));
+// Piece sizes picked when none is given, aiming for about `TARGET_PIECES`
+pub const MIN_PIECE_LENGTH: usize = BLOCK_SIZE;
+pub const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+const TARGET_PIECES: u64 = 1500;
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Power of two piece length giving about 1500 pieces for `size` bytes.
+pub fn auto_piece_length(size: u64) -> usize {
+    let target = size / TARGET_PIECES;
+    let mut res = MIN_PIECE_LENGTH;
+    while (res as u64) < target && res < MAX_PIECE_LENGTH {
+        res *= 2;
+    }
+    res
+}
+
+struct NewFile {
+    // Path components, relative to the torrent directory
+    path: Vec<String>,
+    length: u64,
+}
+
+impl ToBencode for NewFile {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"length", self.length)?;
+            e.emit_pair(b"path", &self.path)
+        })
+    }
+}
+
+struct NewInfo {
+    name: String,
+    piece_length: usize,
+    pieces: Vec<u8>,
+    // Single file when empty
+    files: Vec<NewFile>,
+    length: u64,
+    private: bool,
+}
+
+impl ToBencode for NewInfo {
+    const MAX_DEPTH: usize = NewFile::MAX_DEPTH + 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            if self.files.is_empty() {
+                e.emit_pair(b"length", self.length)?;
+            } else {
+                e.emit_pair(b"files", &self.files)?;
+            }
+            e.emit_pair(b"name", &self.name)?;
+            e.emit_pair(b"piece length", self.piece_length)?;
+            e.emit_pair(b"pieces", AsString(&self.pieces))?;
+            if self.private {
+                e.emit_pair(b"private", 1)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+struct NewMetaInfo<'a> {
+    announce: &'a str,
+    comment: Option<&'a str>,
+    created_by: &'a str,
+    creation_date: u64,
+    info: &'a NewInfo,
+}
+
+impl ToBencode for NewMetaInfo<'_> {
+    const MAX_DEPTH: usize = NewInfo::MAX_DEPTH + 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"announce", self.announce)?;
+            if let Some(comment) = self.comment {
+                e.emit_pair(b"comment", comment)?;
+            }
+            e.emit_pair(b"created by", self.created_by)?;
+            e.emit_pair(b"creation date", self.creation_date)?;
+            e.emit_pair(b"info", self.info)
+        })
+    }
+}
+
+/// A freshly created torrent, ready to be saved or added to a session.
+#[derive(Debug, Clone)]
+pub struct NewTorrent {
+    // Bencoded `.torrent` file
+    pub bytes: Vec<u8>,
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub announce: String,
+}
+
+impl NewTorrent {
+    pub fn magnet(&self) -> Magnet {
+        Magnet {
+            info_hash: self.info_hash,
+            name: Some(self.name.clone()),
+            trackers: vec![self.announce.clone()],
+            peers: Vec::new(),
+            sources: Vec::new(),
+            select_only: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<usize>,
+    announce: Option<String>,
+    private: bool,
+    comment: Option<String>,
+    created_by: String,
+    creation_date: Option<u64>,
+}
+
+// Regular files under `dir`, sorted so that the same tree always gives the
+// same torrent
+fn walk(dir: &Path, prefix: &mut Vec<String>, res: &mut Vec<(PathBuf, NewFile)>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| invalid_data("File name isn't valid UTF-8"))?;
+        let file_type = entry.file_type()?;
+        prefix.push(name);
+        if file_type.is_dir() {
+            walk(&entry.path(), prefix, res)?;
+        } else if file_type.is_file() {
+            let file = NewFile {
+                path: prefix.clone(),
+                length: entry.metadata()?.len(),
+            };
+            res.push((entry.path(), file));
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+impl TorrentBuilder {
+    /// Torrent of the file or directory at `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        TorrentBuilder {
+            path: path.as_ref().to_path_buf(),
+            piece_length: None,
+            announce: None,
+            private: false,
+            comment: None,
+            created_by: CREATED_BY.to_string(),
+            creation_date: None,
+        }
+    }
+
+    /// Picked from the content size by default, see `auto_piece_length`.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn announce(mut self, url: &str) -> Self {
+        self.announce = Some(url.to_string());
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: &str) -> Self {
+        self.created_by = created_by.to_string();
+        self
+    }
+
+    /// Seconds since the epoch, now by default.
+    pub fn creation_date(mut self, creation_date: u64) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    /// Read and hash the content.
+    pub fn build(&self) -> io::Result<NewTorrent> {
+        let announce = self
+            .announce
+            .as_deref()
+            .ok_or_else(|| invalid_input("A tracker is required"))?;
+        if let Some(piece_length) = self.piece_length {
+            if !piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH {
+                return Err(invalid_input(
+                    "Piece size must be a power of two of at least 16 KiB",
+                ));
+            }
+        }
+        // Also names `.` after the current directory
+        let name = fs::canonicalize(&self.path)?
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| invalid_input("Invalid path"))?
+            .to_string();
+
+        let (sources, files) = if fs::metadata(&self.path)?.is_dir() {
+            let mut files = Vec::new();
+            walk(&self.path, &mut Vec::new(), &mut files)?;
+            if files.is_empty() {
+                return Err(invalid_input("No files to share"));
+            }
+            files.into_iter().unzip()
+        } else {
+            (vec![self.path.clone()], Vec::new())
+        };
+        let mut length = 0;
+        for path in &sources {
+            length += fs::metadata(path)?.len();
+        }
+        let piece_length = self
+            .piece_length
+            .unwrap_or_else(|| auto_piece_length(length));
+
+        // Pieces run over file boundaries
+        let mut pieces = Vec::new();
+        let mut buf = vec![0; piece_length];
+        let mut filled = 0;
+        for path in &sources {
+            let mut file = File::open(path)?;
+            loop {
+                let n = file.read(&mut buf[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+                if filled == piece_length {
+                    pieces.extend_from_slice(&Sha1::digest(&buf));
+                    filled = 0;
+                }
+            }
+        }
+        if filled > 0 {
+            pieces.extend_from_slice(&Sha1::digest(&buf[..filled]));
+        }
+
+        let info = NewInfo {
+            name: name.clone(),
+            piece_length,
+            pieces,
+            files,
+            length,
+            private: self.private,
+        };
+        let info_hash = Sha1::digest(info.to_bencode().map_err(invalid_data)?).into();
+        let creation_date = self.creation_date.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+        let bytes = NewMetaInfo {
+            announce,
+            comment: self.comment.as_deref(),
+            created_by: &self.created_by,
+            creation_date,
+            info: &info,
+        }
+        .to_bencode()
+        .map_err(invalid_data)?;
+
+        Ok(NewTorrent {
+            bytes,
+            info_hash,
+            name,
+            announce: announce.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod create_tests {
+    use super::*;
+    use crate::decode_torrent::{self, MetaInfo};
+    use bendy::decoding::FromBencode;
+
+    #[test]
+    fn directory() {
+        const DIR: &str = "./test_create_directory";
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(format!("{}/sub", DIR)).unwrap();
+        let a: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let b = vec![7u8; 30000];
+        fs::write(format!("{}/a", DIR), &a).unwrap();
+        fs::write(format!("{}/sub/b", DIR), &b).unwrap();
+
+        let torrent = TorrentBuilder::new(DIR)
+            .piece_length(BLOCK_SIZE)
+            .announce("udp://tracker.example:6969")
+            .private(true)
+            .comment("Test")
+            .creation_date(1)
+            .build()
+            .unwrap();
+        fs::remove_dir_all(DIR).unwrap();
+
+        assert_eq!(
+            torrent.info_hash,
+            decode_torrent::get_info_hash(&torrent.bytes).unwrap()
+        );
+        let meta = MetaInfo::from_bencode(&torrent.bytes).unwrap();
+        assert_eq!("udp://tracker.example:6969", meta.announce);
+        assert_eq!(Some("Test".to_string()), meta.comment);
+        assert_eq!(Some(1), meta.creation_date);
+        let info = meta.info;
+        assert_eq!("test_create_directory", info.name);
+        assert!(info.private);
+        assert_eq!("50000", info.file_length);
+        let files = info.files.unwrap();
+        assert_eq!(vec!["sub".to_string(), "b".to_string()], files[1].path);
+
+        // The second piece spans both files
+        let data = [a, b].concat();
+        assert_eq!(4, info.pieces.len());
+        assert_eq!(
+            decode_torrent::bytes_to_hash(&Sha1::digest(&data[BLOCK_SIZE..2 * BLOCK_SIZE]).into()),
+            info.pieces[1]
+        );
+
+        let magnet = Magnet::parse(&torrent.magnet().to_string()).unwrap();
+        assert_eq!(torrent.info_hash, magnet.info_hash);
+
+        assert!(TorrentBuilder::new(DIR).build().is_err());
+        assert!(TorrentBuilder::new("Cargo.toml")
+            .announce("udp://x:1")
+            .piece_length(1000)
+            .build()
+            .is_err());
+    }
+}
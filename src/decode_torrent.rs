@@ -1,12 +1,50 @@
 // Module heavily inspired by https://github.com/P3KI/bendy/blob/master/examples/decode_torrent.rs
 use bendy::{
-    decoding::{Error, FromBencode, Object, ResultExt},
-    encoding::AsString,
+    decoding::{Decoder, Error, FromBencode, Object, ResultExt},
+    encoding::{AsString, SingleItemEncoder, ToBencode},
 };
 
+use std::collections::BTreeMap;
+
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use thiserror::Error as ThisError;
+
+use crate::definitions::{InfoHash, InfoHashV2, BLOCK_SIZE};
+use crate::hash::{HashKind, PieceHash, Sha256Hash};
+use crate::tracker::hash_to_bytes;
+
+/// Errors of torrent files and metadata, which come from other people and
+/// can't be trusted to be well-formed.
+#[derive(Debug, ThisError)]
+pub enum MetaInfoError {
+    // Bencode errors don't implement `std::error::Error`, only their message
+    // is kept
+    #[error("Invalid torrent: {0}")]
+    Decode(String),
+    #[error("Info hash mismatch")]
+    InfoHashMismatch,
+    #[error("Invalid piece length")]
+    InvalidPieceLength,
+    #[error("Invalid length")]
+    InvalidLength,
+    #[error("Invalid pieces length")]
+    InvalidPiecesLength,
+    #[error("Wrong number of pieces")]
+    WrongPieceCount,
+    #[error("Invalid piece hash {0:?}")]
+    InvalidHash(String),
+    #[error("Unsupported meta version {0}")]
+    UnsupportedVersion(u64),
+    #[error("Missing piece layer")]
+    MissingPieceLayer,
+}
 
-use crate::definitions::InfoHash;
+impl From<Error> for MetaInfoError {
+    fn from(e: Error) -> Self {
+        MetaInfoError::Decode(e.to_string())
+    }
+}
 
 #[derive(Debug)]
 pub struct MetaInfo {
@@ -16,99 +54,248 @@ pub struct MetaInfo {
     pub created_by: Option<String>,
     pub creation_date: Option<u64>,
     pub http_seeds: Option<Vec<String>>,
-    pub url_list: Option<String>,
+    // Web seeds, a single URL or a list of them, see BEP 19
+    pub url_list: Option<Vec<String>>,
+    // DHT nodes to bootstrap from, as `(host, port)`, see BEP 5
+    pub nodes: Option<Vec<(String, u16)>>,
+    // Hashes of the pieces of each file larger than a piece, by the root of
+    // the file's merkle tree, see BEP 52
+    pub piece_layers: Option<BTreeMap<Sha256Hash, Vec<Sha256Hash>>>,
 }
 
-// File related information (Single-file format)
+// File related information
 #[derive(Debug)]
 pub struct Info {
     pub piece_length: String,
     pub pieces: Vec<String>,
+    // File name, or directory name in the multi-file format
     pub name: String,
+    // Total length of the content, the sum of `files` in the multi-file format
     pub file_length: String,
     pub md5sum: Option<String>,
+    // Only present in the multi-file format
+    pub files: Option<Vec<FileInfo>>,
+    // BEP 27, peers must only come from the trackers
+    pub private: bool,
+    // 2 for v2 and hybrid torrents, see BEP 52
+    pub meta_version: Option<u64>,
+    // Files of v2 and hybrid torrents, in the order of their pieces. Pure v2
+    // torrents have no `pieces`, their `files` are made up from this with
+    // padding files aligning each file to a piece, as hybrid ones have them.
+    pub file_tree: Option<Vec<TreeFile>>,
 }
 
-fn bytes_to_num(input: &[u8]) -> usize {
-    let mut res = 0;
+// A file of a multi-file torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub length: u64,
+    // Path components, relative to the torrent directory
+    pub path: Vec<String>,
+    pub md5sum: Option<String>,
+    // BEP 47 attributes, one letter each: p(adding), x(ecutable), h(idden),
+    // l(ink)
+    pub attr: Option<String>,
+    // Target of a symlink, relative to the torrent directory
+    pub symlink_path: Option<Vec<String>>,
+    pub sha1: Option<Vec<u8>>,
+}
 
-    for &x in input {
-        res *= 10;
-        res += (x - b'0') as usize;
+// A file of the file tree of a v2 torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeFile {
+    // Path components, relative to the torrent directory, or the name of a
+    // single-file torrent
+    pub path: Vec<String>,
+    pub length: u64,
+    // Root of the merkle tree of the file, empty files have none
+    pub pieces_root: Option<Sha256Hash>,
+}
+
+impl FileInfo {
+    fn has_attr(&self, attr: char) -> bool {
+        self.attr.as_ref().is_some_and(|a| a.contains(attr))
     }
 
-    res
+    /// Alignment filler, never written to disk.
+    pub fn is_padding(&self) -> bool {
+        self.has_attr('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.has_attr('x')
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.has_attr('h')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.has_attr('l')
+    }
 }
 
-// TODO: Find a more elegant / normal way of getting Info Hash
-pub fn get_info_hash(input: &[u8]) -> InfoHash {
-    let mut idx = 0;
-    let mut buf = vec![];
+impl Info {
+    pub fn is_multi_file(&self) -> bool {
+        self.files.is_some()
+    }
 
-    loop {
-        let bytes: [u8; 7] = input[idx..idx + 7].try_into().unwrap();
-        if bytes == *b"4:infod" {
-            break;
+    pub fn piece_size(&self) -> Result<usize, MetaInfoError> {
+        match self.piece_length.parse() {
+            Ok(0) | Err(_) => Err(MetaInfoError::InvalidPieceLength),
+            Ok(n) => Ok(n),
         }
-        idx += 1;
     }
 
-    idx += 7;
-    buf.push(b'd');
+    /// Total length of the content.
+    pub fn length(&self) -> Result<u64, MetaInfoError> {
+        self.file_length
+            .parse()
+            .map_err(|_| MetaInfoError::InvalidLength)
+    }
 
-    let mut stack = 0;
+    /// Has a file tree, v2 or hybrid.
+    pub fn is_v2(&self) -> bool {
+        self.file_tree.is_some()
+    }
 
-    loop {
-        match input[idx] {
-            b'e' if stack == 0 => break,
-            b'e' => {
-                stack -= 1;
-                buf.push(input[idx]);
-                idx += 1;
-            }
-            n if (b'0'..=b'9').contains(&n) => {
-                let mut idx2 = 0;
+    // Pure v2 torrents have no SHA-1 piece hashes, nor anything of v1
+    fn has_v1(&self) -> bool {
+        !self.is_v2() || !self.pieces.is_empty()
+    }
 
-                while input[idx + idx2] != b':' {
-                    buf.push(input[idx + idx2]);
-                    idx2 += 1;
-                }
+    pub fn num_pieces(&self) -> usize {
+        if self.has_v1() {
+            return self.pieces.len();
+        }
+        let piece_size = self.piece_size().map_or(1, |size| size as u64);
+        self.length().unwrap_or(0).div_ceil(piece_size) as usize
+    }
 
-                let num = bytes_to_num(&input[idx..idx + idx2]);
+    /// Decode an info dictionary fetched from peers, e.g. for a magnet link.
+    /// It must hash to `info_hash`.
+    pub fn from_metadata(metadata: &[u8], info_hash: &InfoHash) -> Result<Self, MetaInfoError> {
+        if Sha1::digest(metadata)[..] != info_hash[..] {
+            return Err(MetaInfoError::InfoHashMismatch);
+        }
+        Ok(Info::from_bencode(metadata)?)
+    }
 
-                idx += idx2;
-                for _ in 0..num + 1 {
-                    buf.push(input[idx]);
-                    idx += 1;
-                }
+    // There is a hash for each piece of the content, v2 pieces are whole
+    // merkle trees of blocks
+    fn check_piece_count(&self) -> Result<(), MetaInfoError> {
+        let piece_size = self.piece_size()?;
+        if self.is_v2() && (!piece_size.is_power_of_two() || piece_size < BLOCK_SIZE) {
+            return Err(MetaInfoError::InvalidPieceLength);
+        }
+        if self.has_v1() && self.pieces.len() as u64 != self.length()?.div_ceil(piece_size as u64) {
+            return Err(MetaInfoError::WrongPieceCount);
+        }
+        Ok(())
+    }
+}
+
+impl MetaInfo {
+    // Hashes of the pieces of each file of a v2 torrent, in order
+    fn v2_layers(&self) -> Option<impl Iterator<Item = Result<&[Sha256Hash], MetaInfoError>>> {
+        let piece_size = self.info.piece_size().ok()? as u64;
+        let files = self.info.file_tree.as_ref()?.iter();
+        Some(files.filter(|f| f.length > 0).map(move |f| {
+            let root = f
+                .pieces_root
+                .as_ref()
+                .ok_or(MetaInfoError::MissingPieceLayer)?;
+            // A file of one piece is checked against its root
+            if f.length <= piece_size {
+                return Ok(std::slice::from_ref(root));
             }
-            b'i' => {
-                while input[idx] != b'e' {
-                    buf.push(input[idx]);
-                    idx += 1;
+            self.piece_layers
+                .as_ref()
+                .and_then(|layers| layers.get(root))
+                .filter(|layer| layer.len() as u64 == f.length.div_ceil(piece_size))
+                .map(Vec::as_slice)
+                .ok_or(MetaInfoError::MissingPieceLayer)
+        }))
+    }
+
+    /// Pieces are checked with SHA-256 merkle trees when the torrent is v2,
+    /// unless it is a hybrid one whose piece layers are missing, e.g. when it
+    /// comes from a magnet link.
+    pub fn hash_kind(&self) -> HashKind {
+        let Some(mut layers) = self.v2_layers() else {
+            return HashKind::Sha1;
+        };
+        if !self.info.has_v1() || layers.all(|layer| layer.is_ok()) {
+            HashKind::Sha256
+        } else {
+            HashKind::Sha1
+        }
+    }
+
+    /// Expected hash of each piece, of [`Self::hash_kind`].
+    pub fn piece_hashes(&self) -> Result<Vec<PieceHash>, MetaInfoError> {
+        match (self.hash_kind(), self.v2_layers()) {
+            (HashKind::Sha256, Some(layers)) => {
+                let mut res = Vec::with_capacity(self.info.num_pieces());
+                for layer in layers {
+                    res.extend(layer?.iter().map(|&hash| PieceHash::V2(hash)));
                 }
-                buf.push(input[idx]);
-                idx += 1;
-            }
-            b'l' => {
-                stack += 1;
-                buf.push(input[idx]);
-                idx += 1;
+                Ok(res)
             }
-            b'd' => {
-                stack += 1;
-                buf.push(input[idx]);
-                idx += 1;
+            _ => self
+                .info
+                .pieces
+                .iter()
+                .map(|hash| Ok(hash_to_bytes(hash)?.into()))
+                .collect(),
+        }
+    }
+
+    /// Expected hash of a piece, `None` past the last one.
+    pub fn piece_hash(&self, mut index: usize) -> Result<Option<PieceHash>, MetaInfoError> {
+        match (self.hash_kind(), self.v2_layers()) {
+            (HashKind::Sha256, Some(layers)) => {
+                for layer in layers {
+                    let layer = layer?;
+                    if index < layer.len() {
+                        return Ok(Some(PieceHash::V2(layer[index])));
+                    }
+                    index -= layer.len();
+                }
+                Ok(None)
             }
-            x => panic!("Unexpected byte: {}", x),
+            _ => self
+                .info
+                .pieces
+                .get(index)
+                .map(|hash| Ok(hash_to_bytes(hash)?.into()))
+                .transpose(),
         }
     }
+}
 
-    buf.push(b'e');
-    let mut hasher = Sha1::new();
-    hasher.update(&buf);
+// The `info` dictionary of a torrent, as it is in `input`
+fn raw_info(input: &[u8]) -> Result<&[u8], MetaInfoError> {
+    let mut decoder = Decoder::new(input);
+    let mut dict = match decoder.next_object()? {
+        Some(Object::Dict(dict)) => dict,
+        _ => return Err(MetaInfoError::Decode("Not a dictionary".to_string())),
+    };
+    while let Some(pair) = dict.next_pair()? {
+        if let (b"info", Object::Dict(info)) = pair {
+            return Ok(info.into_raw()?);
+        }
+    }
+    Err(Error::missing_field("info").into())
+}
+
+/// SHA-1 of the `info` dictionary of a torrent, as it is in `input`.
+pub fn get_info_hash(input: &[u8]) -> Result<InfoHash, MetaInfoError> {
+    Ok(Sha1::digest(raw_info(input)?).into())
+}
 
-    hasher.finalize().try_into().unwrap()
+/// SHA-256 of the `info` dictionary, the info hash of v2 torrents.
+pub fn get_info_hash_v2(input: &[u8]) -> Result<InfoHashV2, MetaInfoError> {
+    Ok(Sha256::digest(raw_info(input)?).into())
 }
 
 impl FromBencode for MetaInfo {
@@ -153,6 +340,8 @@ impl FromBencode for MetaInfo {
         let mut info = None;
         let mut created_by = None;
         let mut url_list = None;
+        let mut nodes = None;
+        let mut piece_layers = None;
 
         let mut dict_dec = object.try_into_dictionary()?;
         while let Some(pair) = dict_dec.next_pair()? {
@@ -188,8 +377,14 @@ impl FromBencode for MetaInfo {
                         .map(Some)?;
                 }
                 (b"url-list", value) => {
-                    url_list = String::decode_bencode_object(value)
-                        .context("url-list")
+                    url_list = decode_url_list(value).context("url-list").map(Some)?;
+                }
+                (b"nodes", value) => {
+                    nodes = decode_nodes(value).context("nodes").map(Some)?;
+                }
+                (b"piece layers", value) => {
+                    piece_layers = decode_piece_layers(value)
+                        .context("piece layers")
                         .map(Some)?;
                 }
                 (unknown_field, _) => {
@@ -200,7 +395,12 @@ impl FromBencode for MetaInfo {
             }
         }
 
-        let announce = announce.ok_or_else(|| Error::missing_field("announce"))?;
+        // Trackerless torrents have nodes instead
+        let announce = match (announce, &nodes) {
+            (Some(announce), _) => announce,
+            (None, Some(_)) => String::new(),
+            (None, None) => return Err(Error::missing_field("announce")),
+        };
         let info = info.ok_or_else(|| Error::missing_field("info"))?;
 
         Ok(MetaInfo {
@@ -211,28 +411,238 @@ impl FromBencode for MetaInfo {
             creation_date,
             http_seeds,
             url_list,
+            nodes,
+            piece_layers,
         })
     }
 }
 
-pub fn bytes_to_hash(hash: &InfoHash) -> String {
-    hash.iter().map(|c| format!("{:02x}", c)).collect()
+// A URL or a list of them, empty ones left out
+fn decode_url_list(object: Object) -> Result<Vec<String>, Error> {
+    let urls = match object {
+        Object::Bytes(url) => vec![String::from_utf8_lossy(url).into_owned()],
+        object => Vec::decode_bencode_object(object)?,
+    };
+    Ok(urls.into_iter().filter(|url| !url.is_empty()).collect())
 }
 
-pub fn pieces_to_hash(input: &[u8]) -> Vec<String> {
-    assert!(input.len() % 20 == 0);
-
+// A list of `[host, port]` lists
+fn decode_nodes(object: Object) -> Result<Vec<(String, u16)>, Error> {
     let mut res = Vec::new();
+    let mut list = object.try_into_list()?;
+    while let Some(node) = list.next_object()? {
+        let mut node = node.try_into_list()?;
+        let host = match node.next_object()? {
+            Some(host) => String::decode_bencode_object(host)?,
+            None => return Err(Error::missing_field("host")),
+        };
+        let port = match node.next_object()? {
+            Some(port) => u16::decode_bencode_object(port)?,
+            None => return Err(Error::missing_field("port")),
+        };
+        res.push((host, port));
+    }
+    Ok(res)
+}
 
-    for chk in input.chunks(20) {
-        res.push(bytes_to_hash(chk.try_into().unwrap()));
+// Concatenated hashes by the root of their tree
+fn decode_piece_layers(object: Object) -> Result<BTreeMap<Sha256Hash, Vec<Sha256Hash>>, Error> {
+    let mut res = BTreeMap::new();
+    let mut dict = object.try_into_dictionary()?;
+    while let Some((root, layer)) = dict.next_pair()? {
+        let layer = AsString::decode_bencode_object(layer)?.0;
+        res.insert(sha256_hash(root)?, sha256_hashes(&layer)?);
     }
+    Ok(res)
+}
+
+fn sha256_hash(bytes: &[u8]) -> Result<Sha256Hash, Error> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::malformed_content(MetaInfoError::InvalidPiecesLength))
+}
+
+fn sha256_hashes(bytes: &[u8]) -> Result<Vec<Sha256Hash>, Error> {
+    if !bytes.len().is_multiple_of(32) {
+        return Err(Error::malformed_content(MetaInfoError::InvalidPiecesLength));
+    }
+    bytes.chunks(32).map(sha256_hash).collect()
+}
 
+// Directories are dictionaries down to the files, whose own dictionary is
+// under an empty key
+fn decode_file_tree(
+    object: Object,
+    path: &mut Vec<String>,
+    files: &mut Vec<TreeFile>,
+) -> Result<(), Error> {
+    let mut dict = object.try_into_dictionary()?;
+    while let Some((name, value)) = dict.next_pair()? {
+        if !name.is_empty() {
+            path.push(String::decode_bencode_object(Object::Bytes(name))?);
+            decode_file_tree(value, path, files)?;
+            path.pop();
+            continue;
+        }
+        if path.is_empty() {
+            return Err(Error::missing_field("path"));
+        }
+
+        let mut length = None;
+        let mut pieces_root = None;
+        let mut file = value.try_into_dictionary()?;
+        while let Some(pair) = file.next_pair()? {
+            match pair {
+                (b"length", value) => {
+                    length = u64::decode_bencode_object(value)
+                        .context("length")
+                        .map(Some)?;
+                }
+                (b"pieces root", value) => {
+                    let bytes = AsString::decode_bencode_object(value).context("pieces root")?;
+                    pieces_root = sha256_hash(&bytes.0).context("pieces root").map(Some)?;
+                }
+                (unknown_field, _) => {
+                    return Err(Error::unexpected_field(String::from_utf8_lossy(
+                        unknown_field,
+                    )));
+                }
+            }
+        }
+        let length = length.ok_or_else(|| Error::missing_field("length"))?;
+        if length > 0 && pieces_root.is_none() {
+            return Err(Error::missing_field("pieces root"));
+        }
+        files.push(TreeFile {
+            path: path.clone(),
+            length,
+            pieces_root,
+        });
+    }
+    Ok(())
+}
+
+// The files of a pure v2 torrent as a hybrid one lists them, padded to a
+// piece boundary when another file has pieces after them
+fn aligned_files(tree: &[TreeFile], piece_size: u64) -> Vec<FileInfo> {
+    let mut res = Vec::new();
+    for (i, f) in tree.iter().enumerate() {
+        res.push(FileInfo {
+            length: f.length,
+            path: f.path.clone(),
+            md5sum: None,
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        });
+        let padding = (piece_size - f.length % piece_size) % piece_size;
+        if padding > 0 && tree[i + 1..].iter().any(|f| f.length > 0) {
+            res.push(FileInfo {
+                length: padding,
+                path: vec![".pad".to_string(), padding.to_string()],
+                md5sum: None,
+                attr: Some("p".to_string()),
+                symlink_path: None,
+                sha1: None,
+            });
+        }
+    }
     res
 }
 
+pub fn bytes_to_hash(hash: &InfoHash) -> String {
+    hash.iter().map(|c| format!("{:02x}", c)).collect()
+}
+
+pub fn pieces_to_hash(input: &[u8]) -> Result<Vec<String>, MetaInfoError> {
+    if !input.len().is_multiple_of(20) {
+        return Err(MetaInfoError::InvalidPiecesLength);
+    }
+    Ok(input
+        .chunks(20)
+        .map(|chk| bytes_to_hash(chk.try_into().unwrap()))
+        .collect())
+}
+
+impl FromBencode for FileInfo {
+    const EXPECTED_RECURSION_DEPTH: usize = 2;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut length = None;
+        let mut path = None;
+        let mut md5sum = None;
+        let mut attr = None;
+        let mut symlink_path = None;
+        let mut sha1 = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"length", value) => {
+                    length = u64::decode_bencode_object(value)
+                        .context("length")
+                        .map(Some)?;
+                }
+                (b"path", value) => {
+                    path = Vec::decode_bencode_object(value)
+                        .context("path")
+                        .map(Some)?;
+                }
+                (b"md5sum", value) => {
+                    md5sum = String::decode_bencode_object(value)
+                        .context("md5sum")
+                        .map(Some)?;
+                }
+                (b"attr", value) => {
+                    attr = String::decode_bencode_object(value)
+                        .context("attr")
+                        .map(Some)?;
+                }
+                (b"symlink path", value) => {
+                    symlink_path = Vec::decode_bencode_object(value)
+                        .context("symlink path")
+                        .map(Some)?;
+                }
+                (b"sha1", value) => {
+                    sha1 = AsString::decode_bencode_object(value)
+                        .context("sha1")
+                        .map(|bytes| Some(bytes.0))?;
+                }
+                (unknown_field, _) => {
+                    return Err(Error::unexpected_field(String::from_utf8_lossy(
+                        unknown_field,
+                    )));
+                }
+            }
+        }
+
+        let length = length.ok_or_else(|| Error::missing_field("length"))?;
+        let path: Vec<String> = path.ok_or_else(|| Error::missing_field("path"))?;
+        if path.is_empty() {
+            return Err(Error::missing_field("path"));
+        }
+
+        Ok(FileInfo {
+            length,
+            path,
+            md5sum,
+            attr,
+            symlink_path,
+            sha1,
+        })
+    }
+}
+
+// Directories of a file tree, with the file and its own dictionary
+const FILE_TREE_DEPTH: usize = 32;
+
 impl FromBencode for Info {
-    const EXPECTED_RECURSION_DEPTH: usize = 1;
+    // info -> file tree -> directories -> file -> properties, deeper than
+    // info -> files -> file -> path
+    const EXPECTED_RECURSION_DEPTH: usize = FILE_TREE_DEPTH + 1;
 
     /// Treats object as dictionary containing all fields for the info struct.
     /// On success the dictionary is parsed for the fields of info which are
@@ -247,10 +657,19 @@ impl FromBencode for Info {
         let mut piece_length = None;
         let mut pieces = None;
         let mut md5sum = None;
+        let mut files = None;
+        let mut private = false;
+        let mut meta_version = None;
+        let mut file_tree = None;
 
         let mut dict_dec = object.try_into_dictionary()?;
         while let Some(pair) = dict_dec.next_pair()? {
             match pair {
+                (b"files", value) => {
+                    files = Vec::decode_bencode_object(value)
+                        .context("files")
+                        .map(Some)?;
+                }
                 (b"length", value) => {
                     file_length = value
                         .try_into_integer()
@@ -271,15 +690,30 @@ impl FromBencode for Info {
                         .map(Some)?;
                 }
                 (b"pieces", value) => {
-                    pieces = AsString::decode_bencode_object(value)
+                    let bytes = AsString::decode_bencode_object(value).context("pieces")?;
+                    pieces = pieces_to_hash(&bytes.0)
+                        .map_err(Error::malformed_content)
                         .context("pieces")
-                        .map(|bytes| Some(pieces_to_hash(&bytes.0)))?;
+                        .map(Some)?;
                 }
                 (b"md5sum", value) => {
                     md5sum = String::decode_bencode_object(value)
                         .context("md5sum")
                         .map(Some)?;
                 }
+                (b"private", value) => {
+                    private = u64::decode_bencode_object(value).context("private")? == 1;
+                }
+                (b"meta version", value) => {
+                    meta_version = u64::decode_bencode_object(value)
+                        .context("meta version")
+                        .map(Some)?;
+                }
+                (b"file tree", value) => {
+                    let mut tree = Vec::new();
+                    decode_file_tree(value, &mut Vec::new(), &mut tree).context("file tree")?;
+                    file_tree = Some(tree);
+                }
                 (unknown_field, _) => {
                     return Err(Error::unexpected_field(String::from_utf8_lossy(
                         unknown_field,
@@ -288,18 +722,223 @@ impl FromBencode for Info {
             }
         }
 
-        let file_length = file_length.ok_or_else(|| Error::missing_field("file_length"))?;
+        match (meta_version, &file_tree) {
+            (None, None) | (Some(2), Some(_)) => (),
+            (Some(2), None) => return Err(Error::missing_field("file tree")),
+            (None, Some(_)) => return Err(Error::missing_field("meta version")),
+            (Some(version), _) => {
+                return Err(Error::malformed_content(MetaInfoError::UnsupportedVersion(
+                    version,
+                )))
+            }
+        }
         let name = name.ok_or_else(|| Error::missing_field("name"))?;
         let piece_length = piece_length.ok_or_else(|| Error::missing_field("piece_length"))?;
+
+        // Pure v2, without anything of v1
+        if let (Some(tree), None, None, None) = (&file_tree, &pieces, &files, &file_length) {
+            match tree.as_slice() {
+                [file] if file.path.len() == 1 && file.path[0] == name => {
+                    file_length = Some(file.length.to_string());
+                }
+                _ => {
+                    let piece_size = match piece_length.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(Error::malformed_content(MetaInfoError::InvalidPieceLength))
+                        }
+                        Ok(n) => n,
+                    };
+                    files = Some(aligned_files(tree, piece_size));
+                }
+            }
+            pieces = Some(Vec::new());
+        }
+
+        let file_length = match (&files, file_length) {
+            (Some(files), None) => files
+                .iter()
+                .map(|f: &FileInfo| f.length)
+                .sum::<u64>()
+                .to_string(),
+            (None, Some(length)) => length,
+            (Some(_), Some(_)) => return Err(Error::unexpected_field("length")),
+            (None, None) => return Err(Error::missing_field("file_length")),
+        };
         let pieces = pieces.ok_or_else(|| Error::missing_field("pieces"))?;
 
-        // Check that we discovered all necessary fields
-        Ok(Info {
+        let info = Info {
             file_length,
             name,
             piece_length,
             pieces,
             md5sum,
+            files,
+            private,
+            meta_version,
+            file_tree,
+        };
+        info.check_piece_count()
+            .map_err(Error::malformed_content)
+            .context("pieces")?;
+        Ok(info)
+    }
+}
+
+// Encoding, e.g. to save a torrent added from a magnet link. Keys are sorted
+// and the decoding is strict, so a canonical torrent keeps its info hash.
+fn malformed(e: MetaInfoError) -> bendy::encoding::Error {
+    bendy::encoding::Error::malformed_content(e)
+}
+
+impl ToBencode for FileInfo {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            if let Some(attr) = &self.attr {
+                e.emit_pair(b"attr", attr)?;
+            }
+            e.emit_pair(b"length", self.length)?;
+            if let Some(md5sum) = &self.md5sum {
+                e.emit_pair(b"md5sum", md5sum)?;
+            }
+            e.emit_pair(b"path", &self.path)?;
+            if let Some(sha1) = &self.sha1 {
+                e.emit_pair(b"sha1", AsString(sha1))?;
+            }
+            if let Some(symlink_path) = &self.symlink_path {
+                e.emit_pair(b"symlink path", symlink_path)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// Files sharing the first `depth` components of their path, sorted
+fn encode_file_tree(
+    files: &[TreeFile],
+    depth: usize,
+    encoder: SingleItemEncoder,
+) -> Result<(), bendy::encoding::Error> {
+    encoder.emit_dict(|mut e| {
+        let mut rest = files;
+        while let Some(first) = rest.first() {
+            if first.path.len() == depth {
+                e.emit_pair_with(b"", |e| {
+                    e.emit_dict(|mut e| {
+                        e.emit_pair(b"length", first.length)?;
+                        if let Some(root) = &first.pieces_root {
+                            e.emit_pair(b"pieces root", AsString(root))?;
+                        }
+                        Ok(())
+                    })
+                })?;
+                rest = &rest[1..];
+                continue;
+            }
+            let name = &first.path[depth];
+            let n = rest
+                .iter()
+                .take_while(|f| f.path.get(depth) == Some(name))
+                .count();
+            e.emit_pair_with(name.as_bytes(), |e| {
+                encode_file_tree(&rest[..n], depth + 1, e)
+            })?;
+            rest = &rest[n..];
+        }
+        Ok(())
+    })
+}
+
+impl ToBencode for Info {
+    const MAX_DEPTH: usize = FILE_TREE_DEPTH + 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        let mut pieces = Vec::with_capacity(20 * self.pieces.len());
+        for hash in &self.pieces {
+            pieces.extend_from_slice(&hash_to_bytes(hash).map_err(malformed)?);
+        }
+        let piece_size = self.piece_size().map_err(malformed)?;
+        // The files of pure v2 torrents were made up when decoding
+        let v1 = self.has_v1();
+        encoder.emit_dict(|mut e| {
+            if let Some(tree) = &self.file_tree {
+                e.emit_pair_with(b"file tree", |e| encode_file_tree(tree, 0, e))?;
+            }
+            match &self.files {
+                Some(files) if v1 => e.emit_pair(b"files", files)?,
+                None if v1 => e.emit_pair(b"length", self.length().map_err(malformed)?)?,
+                _ => (),
+            }
+            if let Some(md5sum) = &self.md5sum {
+                e.emit_pair(b"md5sum", md5sum)?;
+            }
+            if let Some(meta_version) = self.meta_version {
+                e.emit_pair(b"meta version", meta_version)?;
+            }
+            e.emit_pair(b"name", &self.name)?;
+            e.emit_pair(b"piece length", piece_size)?;
+            if v1 {
+                e.emit_pair(b"pieces", AsString(&pieces))?;
+            }
+            if self.private {
+                e.emit_pair(b"private", 1)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl ToBencode for MetaInfo {
+    const MAX_DEPTH: usize = Info::MAX_DEPTH + 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            // Left out of trackerless torrents, as it came
+            if !self.announce.is_empty() || self.nodes.is_none() {
+                e.emit_pair(b"announce", &self.announce)?;
+            }
+            if let Some(comment) = &self.comment {
+                e.emit_pair(b"comment", comment)?;
+            }
+            if let Some(created_by) = &self.created_by {
+                e.emit_pair(b"created by", created_by)?;
+            }
+            if let Some(creation_date) = self.creation_date {
+                e.emit_pair(b"creation date", creation_date)?;
+            }
+            if let Some(http_seeds) = &self.http_seeds {
+                e.emit_pair(b"httpseeds", http_seeds)?;
+            }
+            e.emit_pair(b"info", &self.info)?;
+            if let Some(nodes) = &self.nodes {
+                e.emit_pair_with(b"nodes", |e| {
+                    e.emit_list(|e| {
+                        nodes.iter().try_for_each(|(host, port)| {
+                            e.emit_list(|e| {
+                                e.emit_str(host)?;
+                                e.emit_int(*port)
+                            })
+                        })
+                    })
+                })?;
+            }
+            if let Some(layers) = &self.piece_layers {
+                e.emit_pair_with(b"piece layers", |e| {
+                    e.emit_dict(|mut e| {
+                        for (root, layer) in layers {
+                            e.emit_pair(root, AsString(layer.concat()))?;
+                        }
+                        Ok(())
+                    })
+                })?;
+            }
+            match self.url_list.as_deref() {
+                Some([url]) => e.emit_pair(b"url-list", url)?,
+                Some(urls) => e.emit_pair(b"url-list", urls)?,
+                None => (),
+            }
+            Ok(())
         })
     }
 }
@@ -325,7 +964,7 @@ mod decode_torrent_tests {
         );
         assert_eq!(
             meta_info.url_list.unwrap(),
-            "https://download.manjaro.org/gnome/21.2.1/manjaro-gnome-21.2.1-minimal-220103-linux515.iso"
+            vec!["https://download.manjaro.org/gnome/21.2.1/manjaro-gnome-21.2.1-minimal-220103-linux515.iso"]
         );
     }
 
@@ -336,13 +975,169 @@ mod decode_torrent_tests {
         assert_eq!(meta_info.announce, "udp://192.168.0.101:3000");
     }
 
+    #[test]
+    fn multi_file_torrent() {
+        let torrent = b"d8:announce9:udp://x:14:infod5:filesld6:lengthi3e4:pathl1:a5:b.txteed6:lengthi5e4:pathl5:c.isoeee4:name3:dir12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let meta_info = MetaInfo::from_bencode(torrent).unwrap();
+        let info = meta_info.info;
+
+        assert!(info.is_multi_file());
+        assert_eq!("8", info.file_length);
+        let files = info.files.unwrap();
+        assert_eq!(2, files.len());
+        assert_eq!(vec!["a".to_string(), "b.txt".to_string()], files[0].path);
+        assert_eq!(5, files[1].length);
+    }
+
+    #[test]
+    fn file_attributes() {
+        let torrent = b"d8:announce9:udp://x:14:infod5:filesld4:attr2:xh6:lengthi3e4:pathl1:xeed4:attr1:p6:lengthi1e4:pathl4:.pad1:1eed4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl1:xeee4:name3:dir12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let files = MetaInfo::from_bencode(torrent).unwrap().info.files.unwrap();
+
+        assert!(files[0].is_executable() && files[0].is_hidden());
+        assert!(!files[0].is_padding() && !files[0].is_symlink());
+        assert!(files[1].is_padding());
+        assert!(files[2].is_symlink());
+        assert_eq!(Some(vec!["x".to_string()]), files[2].symlink_path);
+    }
+
+    #[test]
+    fn encode() {
+        let torrent = read_torrent("./tests/torrent_files/test.torrent");
+        let encoded = MetaInfo::from_bencode(&torrent)
+            .unwrap()
+            .to_bencode()
+            .unwrap();
+        assert_eq!(
+            get_info_hash(&torrent).unwrap(),
+            get_info_hash(&encoded).unwrap()
+        );
+
+        let torrent = b"d8:announce9:udp://x:14:infod5:filesld4:attr2:xh6:lengthi3e4:pathl1:xeed4:attr1:p6:lengthi1e4:pathl4:.pad1:1eed4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl1:xeee4:name3:dir12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1eee";
+        let meta_info = MetaInfo::from_bencode(torrent).unwrap();
+        assert_eq!(torrent.to_vec(), meta_info.to_bencode().unwrap());
+    }
+
+    #[test]
+    fn trackerless_nodes() {
+        let torrent = b"d4:infod6:lengthi1e4:name1:x12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel14:router.examplei1eeee";
+        let meta_info = MetaInfo::from_bencode(torrent).unwrap();
+        assert_eq!("", meta_info.announce);
+        assert_eq!(
+            Some(vec![
+                ("127.0.0.1".to_string(), 6881),
+                ("router.example".to_string(), 1)
+            ]),
+            meta_info.nodes
+        );
+        assert_eq!(torrent.to_vec(), meta_info.to_bencode().unwrap());
+        assert!(MetaInfo::from_bencode(b"d5:nodesll1:xeee").is_err());
+    }
+
+    #[test]
+    fn v2_torrents() {
+        let root = "r".repeat(32);
+        let info = format!(
+            "d9:file treed1:xd0:d6:lengthi4e11:pieces root32:{root}eee12:meta versioni2e4:name1:x12:piece lengthi16384ee"
+        );
+        let torrent = format!("d8:announce9:udp://x:14:info{info}e");
+        let meta_info = MetaInfo::from_bencode(torrent.as_bytes()).unwrap();
+        assert!(meta_info.info.is_v2() && !meta_info.info.is_multi_file());
+        assert_eq!("4", meta_info.info.file_length);
+        assert_eq!(1, meta_info.info.num_pieces());
+        assert_eq!(HashKind::Sha256, meta_info.hash_kind());
+        assert_eq!(
+            vec![PieceHash::V2(root.as_bytes().try_into().unwrap())],
+            meta_info.piece_hashes().unwrap()
+        );
+        assert_eq!(torrent.as_bytes(), meta_info.to_bencode().unwrap());
+        let hash: InfoHashV2 = Sha256::digest(&info).into();
+        assert_eq!(hash, get_info_hash_v2(torrent.as_bytes()).unwrap());
+
+        // Files aligned to pieces, the first one with a layer of two pieces
+        let (a, b) = ("a".repeat(32), "b".repeat(32));
+        let layer = "l".repeat(32) + &"m".repeat(32);
+        let torrent = format!(
+            "d8:announce9:udp://x:14:infod9:file treed1:ad0:d6:lengthi20000e11:pieces root32:{a}ee1:bd0:d6:lengthi5e11:pieces root32:{b}ee1:ed0:d6:lengthi0eeee12:meta versioni2e4:name3:dir12:piece lengthi16384ee12:piece layersd32:{a}64:{layer}ee"
+        );
+        let meta_info = MetaInfo::from_bencode(torrent.as_bytes()).unwrap();
+        let files = meta_info.info.files.as_ref().unwrap();
+        assert_eq!(4, files.len());
+        assert!(files[1].is_padding());
+        assert_eq!(16384 * 2 - 20000, files[1].length);
+        assert_eq!("32773", meta_info.info.file_length);
+        let hashes: Vec<_> = meta_info
+            .piece_hashes()
+            .unwrap()
+            .iter()
+            .map(|h| h.as_bytes().to_vec())
+            .collect();
+        assert_eq!(
+            vec![b"l".repeat(32), b"m".repeat(32), b.into_bytes()],
+            hashes
+        );
+        assert_eq!(
+            Some(PieceHash::V2([b'm'; 32])),
+            meta_info.piece_hash(1).unwrap()
+        );
+        assert_eq!(None, meta_info.piece_hash(3).unwrap());
+        assert_eq!(torrent.as_bytes(), meta_info.to_bencode().unwrap());
+
+        // Without its piece layers, the first file can't be checked
+        let meta_info = MetaInfo {
+            piece_layers: None,
+            ..meta_info
+        };
+        assert!(meta_info.piece_hashes().is_err());
+
+        let info = |version: u64, piece_length: u64| {
+            format!("d9:file treed1:xd0:d6:lengthi4e11:pieces root32:{root}eee12:meta versioni{version}e4:name1:x12:piece lengthi{piece_length}ee")
+        };
+        assert!(Info::from_bencode(info(2, 16384).as_bytes()).is_ok());
+        assert!(Info::from_bencode(info(3, 16384).as_bytes()).is_err());
+        assert!(Info::from_bencode(info(2, 20000).as_bytes()).is_err());
+        assert!(Info::from_bencode(info(2, 8192).as_bytes()).is_err());
+        assert!(Info::from_bencode(
+            b"d6:lengthi4e12:meta versioni2e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae"
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_get_info_hash() {
         let torrent = read_torrent("./tests/torrent_files/test_local.torrent");
-        let hash = get_info_hash(&torrent);
+        let hash = get_info_hash(&torrent).unwrap();
         assert_eq!(
             "52b62d34a8336f2e934df62181ad4c2f1b43c185".to_string(),
             bytes_to_hash(&hash)
         );
     }
+
+    #[test]
+    fn untrusted_torrents() {
+        let info = b"d6:lengthi8e4:name1:x12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+        let torrent = [
+            b"d8:announce9:udp://x:17:comment7:4:infod4:info".as_slice(),
+            info,
+            b"e",
+        ]
+        .concat();
+        // The info dictionary itself is hashed, not the first `4:infod`
+        let hash: InfoHash = Sha1::digest(info).into();
+        assert_eq!(hash, get_info_hash(&torrent).unwrap());
+        assert!(MetaInfo::from_bencode(&torrent).is_ok());
+
+        assert!(get_info_hash(b"garbage").is_err());
+        assert!(get_info_hash(b"d8:announce9:udp://x:1e").is_err());
+        // Hashes of 19 bytes
+        assert!(Info::from_bencode(
+            b"d6:lengthi4e4:name1:x12:piece lengthi4e6:pieces19:aaaaaaaaaaaaaaaaaaae"
+        )
+        .is_err());
+        // One hash for two pieces
+        assert!(Info::from_bencode(
+            b"d6:lengthi8e4:name1:x12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae"
+        )
+        .is_err());
+    }
 }
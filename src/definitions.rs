@@ -1,7 +1,33 @@
 pub const INFO_HASH_LEN: usize = 20;
+pub const INFO_HASH_V2_LEN: usize = 32;
 pub const PEER_ID_LEN: usize = 20;
-pub const TORRENT_RS_PEER_ID: &[u8; PEER_ID_LEN] = b"-RS0001-RANDOM_CHARA";
+// Size of the blocks requested from peers, the last block of a piece may be shorter
+pub const BLOCK_SIZE: usize = 16 * 1024;
+// Client and version part of our peer IDs, Azureus style
+pub const PEER_ID_PREFIX: &str = "-RS0001-";
 
 pub type InfoHash = [u8; INFO_HASH_LEN];
+// SHA-256 of the info dictionary of v2 and hybrid torrents, see BEP 52
+pub type InfoHashV2 = [u8; INFO_HASH_V2_LEN];
 
 pub type PeerId = [u8; PEER_ID_LEN];
+
+/// Random peer ID of this client, for what isn't given one by its session.
+pub fn new_peer_id() -> PeerId {
+    peer_id_with_prefix(PEER_ID_PREFIX.as_bytes())
+}
+
+/// Peer ID starting with `prefix`, completed with random alphanumerics.
+/// Longer prefixes are truncated.
+pub fn peer_id_with_prefix(prefix: &[u8]) -> PeerId {
+    use rand::{distributions::Alphanumeric, Rng};
+
+    let mut res: PeerId = [0; PEER_ID_LEN];
+    let len = prefix.len().min(PEER_ID_LEN);
+    res[..len].copy_from_slice(&prefix[..len]);
+    let mut rng = rand::thread_rng();
+    for byte in &mut res[len..] {
+        *byte = rng.sample(Alphanumeric);
+    }
+    res
+}
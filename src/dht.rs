@@ -0,0 +1,1561 @@
+// Mainline DHT node, see http://bittorrent.org/beps/bep_0005.html. Finds the
+// peers of torrents without a tracker, and stores those announced by others
+// for the infohashes close to our ID. Only IPv4 is spoken.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bendy::{
+    decoding::{self, FromBencode, Object},
+    encoding::{SingleItemEncoder, ToBencode},
+};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+    time::{self, Duration, Instant},
+};
+
+use crate::definitions::InfoHash;
+use crate::dht_item::{self, Item, ItemError, Mutable, PublicKey, Value};
+use crate::krpc::{self, BloomFilter, Body, Message, Query, Response};
+use crate::routing::{self, Inserted, NodeId, NodeInfo, RoutingTable, K, NODE_TIMEOUT};
+use crate::state;
+
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+// Queries in flight during a lookup
+pub const ALPHA: usize = 3;
+// Tokens are valid for two rotations, announces must follow a lookup
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+// Announced peers are forgotten unless announced again
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+// Peers kept per infohash, and returned to a get_peers
+pub const MAX_STORED_PEERS: usize = 200;
+pub const MAX_VALUES: usize = 50;
+// Infohashes others announced to us, the oldest are dropped beyond
+pub const MAX_STORED_TORRENTS: usize = 2000;
+// Infohashes returned to a sample_infohashes, and how long before asking
+// again
+pub const MAX_SAMPLES: usize = 20;
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Items are forgotten unless put again
+pub const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+// Items others put to us, the oldest are dropped beyond
+pub const MAX_STORED_ITEMS: usize = 2000;
+// Nodes which must agree on our address before it is believed
+pub const EXTERNAL_IP_VOTES: usize = 3;
+// Of a started node: stale buckets are looked up, and the infohashes kept
+// announced are announced again, this often
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// In the state directory of a session, next to `state::STATE_FILE`
+pub const DHT_STATE_FILE: &str = "dht.state";
+
+const MAX_PACKET: usize = 65536;
+const TOKEN_LEN: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error("DHT socket error: {0}")]
+    Io(#[from] io::Error),
+    #[error("DHT query timed out")]
+    Timeout,
+    // Sent by the queried node
+    #[error("DHT error {0}: {1}")]
+    Remote(i64, String),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct DhtConfig {
+    // Port 0 takes the one of the session, or any free one otherwise
+    pub bind: SocketAddr,
+    // Nodes to join the DHT through, as `host:port`
+    pub bootstrap: Vec<String>,
+    // Derived from our external address unless given, see BEP 42
+    pub node_id: Option<NodeId>,
+    // Learnt from the other nodes if not known
+    pub external_ip: Option<Ipv4Addr>,
+    // Keep out of the routing table the nodes whose ID doesn't match their
+    // address, save for local ones
+    pub enforce_node_ids: bool,
+    // Only look up, answering no query and so storing nothing, e.g. on a
+    // battery. Others keep the node out of their routing table, see BEP 43
+    pub read_only: bool,
+    pub query_timeout: Duration,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            node_id: None,
+            external_ip: None,
+            enforce_node_ids: true,
+            read_only: false,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+        }
+    }
+}
+
+/// Nodes closest to a target found by a lookup, with the peers of the
+/// infohash if it was one.
+#[derive(Debug, Clone, Default)]
+pub struct Lookup {
+    // Closest first, with the token to announce to them
+    pub nodes: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    pub peers: Vec<SocketAddr>,
+    // Swarm of the closest nodes when scraped
+    pub seeds: BloomFilter,
+    pub downloaders: BloomFilter,
+    // Answers of the nodes which had the item looked up
+    pub items: Vec<Response>,
+}
+
+/// Infohashes a node stores, as sampled by it, see BEP 51.
+#[derive(Debug, Clone, Default)]
+pub struct Samples {
+    pub samples: Vec<InfoHash>,
+    // Infohashes the node stores
+    pub num: u64,
+    // Before which the node shouldn't be asked again
+    pub interval: Duration,
+    // Closest to the target, to crawl further
+    pub nodes: Vec<NodeInfo>,
+}
+
+/// Health of a node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DhtStats {
+    pub nodes: usize,
+    // Nodes which answered lately
+    pub good_nodes: usize,
+    // Nodes in each bucket of the routing table, of `K`
+    pub buckets: Vec<usize>,
+    // Since the last bootstrap or refresh
+    pub last_refresh: Option<Duration>,
+}
+
+/// What a node needs to rejoin the DHT after a restart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhtState {
+    pub id: NodeId,
+    pub external_ip: Option<Ipv4Addr>,
+    pub nodes: Vec<NodeInfo>,
+}
+
+impl DhtState {
+    /// Read the state saved in `dir`, `None` if there is none yet.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Option<Self>> {
+        match fs::read(dir.as_ref().join(DHT_STATE_FILE)) {
+            Ok(bytes) => DhtState::from_bencode(&bytes)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let bytes = self
+            .to_bencode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        state::write_atomic(dir.as_ref().join(DHT_STATE_FILE), &bytes)
+    }
+}
+
+impl ToBencode for DhtState {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair_with(b"id", |e| e.emit_bytes(&self.id))?;
+            if let Some(ip) = self.external_ip {
+                e.emit_pair_with(b"ip", |e| e.emit_bytes(&ip.octets()))?;
+            }
+            let nodes = krpc::compact_nodes(&self.nodes);
+            e.emit_pair_with(b"nodes", |e| e.emit_bytes(&nodes))
+        })
+    }
+}
+
+impl FromBencode for DhtState {
+    const EXPECTED_RECURSION_DEPTH: usize = 1;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, decoding::Error> {
+        let mut dict = object.try_into_dictionary()?;
+        let (mut id, mut external_ip, mut nodes) = (None, None, Vec::new());
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"id", value) => id = NodeId::try_from(value.try_into_bytes()?).ok(),
+                (b"ip", value) => {
+                    external_ip = <[u8; 4]>::try_from(value.try_into_bytes()?)
+                        .ok()
+                        .map(Ipv4Addr::from)
+                }
+                (b"nodes", value) => nodes = krpc::parse_compact_nodes(value.try_into_bytes()?),
+                _ => (),
+            }
+        }
+        Ok(DhtState {
+            id: id.ok_or_else(|| decoding::Error::missing_field("id"))?,
+            external_ip,
+            nodes,
+        })
+    }
+}
+
+/// Size of a swarm, as estimated by the nodes storing its peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Scrape {
+    pub seeds: u32,
+    pub leechers: u32,
+}
+
+// Secret tokens are derived from, the previous one is still accepted
+#[derive(Debug)]
+struct Tokens {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new() -> Self {
+        let secret = rand::random();
+        Tokens {
+            current: secret,
+            previous: secret,
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(secret: &[u8; 16], addr: &SocketAddrV4) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(addr.ip().octets());
+        hasher.update(secret);
+        hasher.finalize()[..TOKEN_LEN].to_vec()
+    }
+
+    fn issue(&mut self, addr: &SocketAddrV4) -> Vec<u8> {
+        self.rotate(Instant::now());
+        Tokens::token(&self.current, addr)
+    }
+
+    fn is_valid(&mut self, token: &[u8], addr: &SocketAddrV4) -> bool {
+        self.rotate(Instant::now());
+        token == Tokens::token(&self.current, addr) || token == Tokens::token(&self.previous, addr)
+    }
+}
+
+// Peers announced to us, by infohash, with when and whether they seed
+#[derive(Debug, Default)]
+struct PeerStore {
+    torrents: HashMap<InfoHash, HashMap<SocketAddr, (Instant, bool)>>,
+}
+
+impl PeerStore {
+    fn add(&mut self, info_hash: InfoHash, peer: SocketAddr, seed: bool, now: Instant) {
+        self.expire(now);
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_STORED_TORRENTS {
+            let oldest = self
+                .torrents
+                .iter()
+                .min_by_key(|(_, peers)| peers.values().map(|(at, _)| *at).max())
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.torrents.remove(&oldest);
+            }
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if !peers.contains_key(&peer) && peers.len() >= MAX_STORED_PEERS {
+            if let Some(oldest) = peers.iter().min_by_key(|(_, (at, _))| *at).map(|(p, _)| *p) {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(peer, (now, seed));
+    }
+
+    // Without the seeds if `noseed`
+    fn get(&mut self, info_hash: &InfoHash, noseed: bool, now: Instant) -> Vec<SocketAddr> {
+        self.expire(now);
+        match self.torrents.get(info_hash) {
+            Some(peers) => {
+                let mut peers: Vec<SocketAddr> = peers
+                    .iter()
+                    .filter(|(_, (_, seed))| !(noseed && *seed))
+                    .map(|(peer, _)| *peer)
+                    .collect();
+                // Different ones each time when there are too many
+                if peers.len() > MAX_VALUES {
+                    use rand::seq::SliceRandom;
+                    peers.shuffle(&mut rand::thread_rng());
+                    peers.truncate(MAX_VALUES);
+                }
+                peers
+            }
+            None => Vec::new(),
+        }
+    }
+
+    // Some of the infohashes, and how many there are
+    fn sample(&mut self, now: Instant) -> (Vec<InfoHash>, usize) {
+        use rand::seq::IteratorRandom;
+        self.expire(now);
+        let samples = self
+            .torrents
+            .keys()
+            .copied()
+            .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES);
+        (samples, self.torrents.len())
+    }
+
+    // Seeds and downloaders, as bloom filters
+    fn scrape(&mut self, info_hash: &InfoHash, now: Instant) -> (BloomFilter, BloomFilter) {
+        self.expire(now);
+        let (mut seeds, mut downloaders) = (BloomFilter::default(), BloomFilter::default());
+        for (peer, (_, seed)) in self.torrents.get(info_hash).into_iter().flatten() {
+            match seed {
+                true => seeds.insert(peer.ip()),
+                false => downloaders.insert(peer.ip()),
+            }
+        }
+        (seeds, downloaders)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, (at, _)| now.duration_since(*at) < PEER_TTL);
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+// Our address as seen by the nodes answering us, by who saw it
+#[derive(Debug, Default)]
+struct ExternalIp {
+    ip: Option<Ipv4Addr>,
+    votes: HashMap<Ipv4Addr, HashSet<Ipv4Addr>>,
+}
+
+impl ExternalIp {
+    // Returns the address if the vote changed it
+    fn vote(&mut self, voter: Ipv4Addr, ip: Ipv4Addr) -> Option<Ipv4Addr> {
+        if routing::is_local(voter) || routing::is_local(ip) || self.ip == Some(ip) {
+            return None;
+        }
+        // Forget the votes once too many addresses were claimed
+        if !self.votes.contains_key(&ip) && self.votes.len() >= MAX_VALUES {
+            self.votes.clear();
+        }
+        let voters = self.votes.entry(ip).or_default();
+        voters.insert(voter);
+        if voters.len() < EXTERNAL_IP_VOTES {
+            return None;
+        }
+        self.votes.clear();
+        self.ip = Some(ip);
+        self.ip
+    }
+}
+
+// Items put to us, by target
+#[derive(Debug, Default)]
+struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>,
+}
+
+impl ItemStore {
+    // Verified items only, an error is a code and message to answer
+    fn put(
+        &mut self,
+        item: Item,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<(), (i64, &'static str)> {
+        self.expire(now);
+        let target = item.target();
+        if let (Some((stored, _)), Some(seq)) = (self.items.get(&target), item.seq()) {
+            let stored = stored.seq().unwrap_or_default();
+            if cas.is_some_and(|cas| cas != stored) {
+                return Err((krpc::CAS_MISMATCH, "CAS mismatch"));
+            }
+            if seq < stored {
+                return Err((krpc::SEQ_TOO_LOW, "Sequence number less than current"));
+            }
+        }
+        if !self.items.contains_key(&target) && self.items.len() >= MAX_STORED_ITEMS {
+            let oldest = self
+                .items
+                .iter()
+                .min_by_key(|(_, (_, at))| *at)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    fn get(&mut self, target: &NodeId, now: Instant) -> Option<Item> {
+        self.expire(now);
+        self.items.get(target).map(|(item, _)| item.clone())
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.items
+            .retain(|_, (_, at)| now.duration_since(*at) < ITEM_TTL);
+    }
+}
+
+// Infohash announced by `Dht::keep_announcing`
+#[derive(Debug, Clone, Copy)]
+struct Announced {
+    port: Option<u16>,
+    seed: bool,
+    at: Instant,
+}
+
+type Reply = Result<(NodeId, Response), DhtError>;
+type Transaction = [u8; 2];
+
+// Query sent to a node, answered through `reply`
+#[derive(Debug)]
+struct Pending {
+    to: SocketAddr,
+    reply: oneshot::Sender<Reply>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    socket: UdpSocket,
+    config: DhtConfig,
+    // Our ID is the one of the table, it changes with our address
+    table: Mutex<RoutingTable>,
+    external_ip: Mutex<ExternalIp>,
+    // Queries waiting for an answer, by transaction
+    pending: Mutex<HashMap<Transaction, Pending>>,
+    next_transaction: AtomicU16,
+    tokens: Mutex<Tokens>,
+    peers: Mutex<PeerStore>,
+    items: Mutex<ItemStore>,
+    last_refresh: Mutex<Option<Instant>>,
+    announced: Mutex<HashMap<InfoHash, Announced>>,
+}
+
+/// A node of the DHT, answering the queries of others as long as it lives.
+/// A bound node is driven by its owner, e.g. a `Session`, a started one
+/// keeps itself in the DHT.
+#[derive(Debug)]
+pub struct Dht {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Dht {
+    /// Start a node on `config.bind`. It knows no other node until
+    /// `bootstrap` is called.
+    pub async fn bind(config: DhtConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(config.bind).await?;
+        let id = match (config.node_id, config.external_ip) {
+            (Some(id), _) => id,
+            (None, Some(ip)) => routing::secure_id(ip),
+            (None, None) => routing::random_id(),
+        };
+        let inner = Arc::new(Inner {
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            external_ip: Mutex::new(ExternalIp {
+                ip: config.external_ip,
+                ..Default::default()
+            }),
+            pending: Mutex::default(),
+            next_transaction: AtomicU16::new(rand::random()),
+            tokens: Mutex::new(Tokens::new()),
+            peers: Mutex::default(),
+            items: Mutex::default(),
+            last_refresh: Mutex::default(),
+            announced: Mutex::default(),
+            config,
+        });
+        let task = tokio::spawn(inner.clone().run());
+        Ok(Dht {
+            inner,
+            tasks: vec![task],
+        })
+    }
+
+    /// Start a node on `config.bind` for discovery without a session: it
+    /// bootstraps, then refreshes its routing table and announces again
+    /// what it keeps announced on its own.
+    pub async fn start(config: DhtConfig) -> io::Result<Self> {
+        let mut dht = Dht::bind(config).await?;
+        dht.tasks.push(tokio::spawn(dht.inner.clone().maintain()));
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id()
+    }
+
+    /// Our address, as given or agreed on by the nodes answering us.
+    pub fn external_ip(&self) -> Option<Ipv4Addr> {
+        self.inner.external_ip.lock().unwrap().ip
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// Nodes in the routing table.
+    pub fn nodes(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    pub fn routing_table(&self) -> RoutingTable {
+        self.inner.table.lock().unwrap().clone()
+    }
+
+    pub fn stats(&self) -> DhtStats {
+        let now = Instant::now();
+        let table = self.inner.table.lock().unwrap();
+        DhtStats {
+            nodes: table.len(),
+            good_nodes: table.good_nodes(now),
+            buckets: table.buckets(),
+            last_refresh: self
+                .inner
+                .last_refresh
+                .lock()
+                .unwrap()
+                .map(|at| now.duration_since(at)),
+        }
+    }
+
+    /// Our ID, address and routing table, to `restore` them.
+    pub fn state(&self) -> DhtState {
+        let table = self.inner.table.lock().unwrap();
+        DhtState {
+            id: *table.id(),
+            external_ip: self.external_ip(),
+            nodes: table.nodes().collect(),
+        }
+    }
+
+    /// Take back a saved state: its nodes, questionable until they answer,
+    /// and its ID unless configured or not matching our address anymore.
+    pub fn restore(&self, state: &DhtState) {
+        let ip = {
+            let mut external = self.inner.external_ip.lock().unwrap();
+            if external.ip.is_none() {
+                external.ip = state.external_ip;
+            }
+            external.ip
+        };
+        let id_valid = ip.is_none_or(|ip| routing::is_secure_id(&state.id, ip));
+        if self.inner.config.node_id.is_none() && id_valid {
+            self.inner.set_id(state.id);
+        }
+        let now = Instant::now();
+        let seen = now.checked_sub(NODE_TIMEOUT).unwrap_or(now);
+        let mut table = self.inner.table.lock().unwrap();
+        for node in &state.nodes {
+            if self.inner.accepts(node) {
+                table.insert(*node, seen);
+            }
+        }
+    }
+
+    /// Add a node to the routing table if it answers a ping.
+    pub async fn add_node(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        self.inner.query(addr, Query::Ping).await.map(|(id, _)| id)
+    }
+
+    /// Add the nodes at `host`, e.g. from the `nodes` of a torrent. Returns
+    /// those which answered.
+    pub async fn add_host(&self, host: &str, port: u16) -> usize {
+        let Ok(addrs) = lookup_host((host, port)).await else {
+            return 0;
+        };
+        let mut res = 0;
+        for addr in addrs.filter(SocketAddr::is_ipv4) {
+            if self.add_node(addr).await.is_ok() {
+                res += 1;
+            }
+        }
+        res
+    }
+
+    /// Join the DHT through the bootstrap nodes, then look up our own ID to
+    /// know our neighbours. Returns the nodes then known.
+    pub async fn bootstrap(&self) -> usize {
+        self.inner.bootstrap().await
+    }
+
+    /// Look up the buckets nobody was heard of for a while, and bootstrap
+    /// again if every node was lost.
+    pub async fn refresh(&self) {
+        self.inner.refresh().await
+    }
+
+    /// Nodes closest to `target` found by an iterative lookup.
+    pub async fn find_node(&self, target: NodeId) -> Vec<NodeInfo> {
+        let lookup = self.inner.find_node(target).await;
+        lookup.nodes.into_iter().map(|(node, _)| node).collect()
+    }
+
+    /// Peers of `info_hash` known by the nodes closest to it.
+    pub async fn get_peers(&self, info_hash: InfoHash) -> Vec<SocketAddr> {
+        self.inner.get_peers(info_hash, false, false).await.peers
+    }
+
+    /// Estimate the size of the swarm of `info_hash` from the peers the
+    /// nodes closest to it store, see BEP 33.
+    pub async fn scrape(&self, info_hash: InfoHash) -> Scrape {
+        let lookup = self.inner.get_peers(info_hash, true, false).await;
+        Scrape {
+            seeds: lookup.seeds.estimate().round() as u32,
+            leechers: lookup.downloaders.estimate().round() as u32,
+        }
+    }
+
+    /// Store `item` on the nodes closest to its target, replacing there the
+    /// mutable item of sequence `cas` if given. Returns the nodes which
+    /// stored it.
+    pub async fn put(&self, item: Item, cas: Option<i64>) -> usize {
+        let lookup = self.inner.get(item.target()).await;
+        let mut stored = JoinSet::new();
+        for (node, token) in lookup.nodes.into_iter().take(K) {
+            let Some(token) = token else {
+                continue;
+            };
+            let query = Query::Put {
+                token,
+                item: item.clone(),
+                cas,
+            };
+            let inner = self.inner.clone();
+            stored.spawn(async move { inner.query(node.addr.into(), query).await });
+        }
+        let mut res = 0;
+        while let Some(reply) = stored.join_next().await {
+            if let Ok(Ok(_)) = reply {
+                res += 1;
+            }
+        }
+        res
+    }
+
+    /// Value of the immutable item of `target`.
+    pub async fn get_immutable(&self, target: NodeId) -> Option<Value> {
+        let lookup = self.inner.get(target).await;
+        lookup
+            .items
+            .into_iter()
+            .filter_map(|response| Item::immutable(response.value?).ok())
+            .find(|item| item.target() == target)
+            .map(|item| item.value)
+    }
+
+    /// Newest mutable item of `key` under `salt`, signed by `key`.
+    pub async fn get_mutable(&self, key: &PublicKey, salt: &[u8]) -> Option<Item> {
+        let lookup = self.inner.get(dht_item::mutable_target(key, salt)).await;
+        lookup
+            .items
+            .into_iter()
+            .filter_map(|response| {
+                let item = Item {
+                    value: response.value?,
+                    mutable: Some(Mutable {
+                        key: response.key?,
+                        salt: salt.to_vec(),
+                        seq: response.seq?,
+                        signature: response.signature?,
+                    }),
+                };
+                (response.key == Some(*key) && item.verify().is_ok()).then_some(item)
+            })
+            .max_by_key(|item| item.seq())
+    }
+
+    /// Ask the node at `addr` for some of the infohashes it stores, and for
+    /// the nodes it knows closest to `target`.
+    pub async fn sample_infohashes(
+        &self,
+        addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Samples, DhtError> {
+        let query = Query::SampleInfohashes { target };
+        let (_, response) = self.inner.query(addr, query).await?;
+        Ok(Samples {
+            num: response.num.unwrap_or(response.samples.len() as u64),
+            samples: response.samples,
+            interval: Duration::from_secs(response.interval.unwrap_or_default().into()),
+            nodes: response.nodes,
+        })
+    }
+
+    /// Tell the nodes closest to `info_hash` that we have it, on `port` or
+    /// the port of the DHT if `None`, and whether we `seed` it. Returns the
+    /// peers found on the way, without the seeds if we seed.
+    pub async fn announce(
+        &self,
+        info_hash: InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddr> {
+        self.inner.announce(info_hash, port, seed).await
+    }
+
+    /// Announce `info_hash` now, then every `ANNOUNCE_INTERVAL` if the node
+    /// was started, until `stop_announcing`.
+    pub async fn keep_announcing(
+        &self,
+        info_hash: InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddr> {
+        let announced = Announced {
+            port,
+            seed,
+            at: Instant::now(),
+        };
+        self.inner
+            .announced
+            .lock()
+            .unwrap()
+            .insert(info_hash, announced);
+        self.announce(info_hash, port, seed).await
+    }
+
+    /// The peers announced expire after `PEER_TTL`.
+    pub fn stop_announcing(&self, info_hash: &InfoHash) {
+        self.inner.announced.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Inner {
+    fn id(&self) -> NodeId {
+        *self.table.lock().unwrap().id()
+    }
+
+    async fn run(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                // e.g. ICMP unreachable of a previous send on some systems
+                Err(_) => continue,
+            };
+            let SocketAddr::V4(from) = from else {
+                continue;
+            };
+            // Garbage is dropped, there is nobody to answer
+            if let Ok(msg) = Message::from_bytes(&buf[..n]) {
+                self.handle(msg, from).await;
+            }
+        }
+    }
+
+    async fn handle(self: &Arc<Self>, msg: Message, from: SocketAddrV4) {
+        match msg.body {
+            Body::Query(..) if self.config.read_only => (),
+            Body::Query(id, query) => {
+                let reply = self.answer(msg.transaction, query, from);
+                self.send(&reply, from.into()).await;
+                // Read-only nodes would answer no query of ours
+                if !msg.read_only {
+                    self.heard_from(NodeInfo { id, addr: from });
+                }
+            }
+            Body::Response(id, response) => {
+                let matched = self.reply(&msg.transaction, from, Ok((id, response)));
+                if let (true, Some(ip)) = (matched, msg.ip) {
+                    self.voted(*from.ip(), *ip.ip());
+                }
+            }
+            Body::Error(code, message) => {
+                self.reply(&msg.transaction, from, Err(DhtError::Remote(code, message)));
+            }
+        }
+    }
+
+    // Answers tell the querying node its address
+    fn answer(&self, transaction: Vec<u8>, query: Query, from: SocketAddrV4) -> Message {
+        let mut msg = self.answer_query(transaction, query, from);
+        msg.ip = Some(from);
+        msg
+    }
+
+    fn answer_query(&self, transaction: Vec<u8>, query: Query, from: SocketAddrV4) -> Message {
+        let closest = |target| self.table.lock().unwrap().closest(target, K);
+        let response = match query {
+            Query::Ping => Response::default(),
+            Query::FindNode { target } => Response {
+                nodes: closest(&target),
+                ..Default::default()
+            },
+            Query::GetPeers {
+                info_hash,
+                scrape,
+                noseed,
+            } => {
+                let now = Instant::now();
+                let mut peers = self.peers.lock().unwrap();
+                let values = peers.get(&info_hash, noseed, now);
+                let (seeds, downloaders) = match scrape {
+                    true => {
+                        let (seeds, downloaders) = peers.scrape(&info_hash, now);
+                        (Some(seeds), Some(downloaders))
+                    }
+                    false => (None, None),
+                };
+                drop(peers);
+                Response {
+                    nodes: match values.is_empty() {
+                        true => closest(&info_hash),
+                        false => Vec::new(),
+                    },
+                    values,
+                    token: Some(self.tokens.lock().unwrap().issue(&from)),
+                    seeds,
+                    downloaders,
+                    ..Default::default()
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                seed,
+            } => {
+                if !self.tokens.lock().unwrap().is_valid(&token, &from) {
+                    return Message::error(transaction, krpc::PROTOCOL_ERROR, "Bad token");
+                }
+                let port = match implied_port {
+                    true => from.port(),
+                    false => port,
+                };
+                let peer = SocketAddrV4::new(*from.ip(), port).into();
+                self.peers
+                    .lock()
+                    .unwrap()
+                    .add(info_hash, peer, seed, Instant::now());
+                Response::default()
+            }
+            Query::SampleInfohashes { target } => {
+                let (samples, num) = self.peers.lock().unwrap().sample(Instant::now());
+                Response {
+                    nodes: closest(&target),
+                    interval: Some(SAMPLE_INTERVAL.as_secs() as u32),
+                    num: Some(num as u64),
+                    samples,
+                    ..Default::default()
+                }
+            }
+            Query::Get { target, seq } => {
+                let mut response = Response {
+                    nodes: closest(&target),
+                    token: Some(self.tokens.lock().unwrap().issue(&from)),
+                    ..Default::default()
+                };
+                let item = self.items.lock().unwrap().get(&target, Instant::now());
+                if let Some(Item { value, mutable }) = item {
+                    match mutable {
+                        // The querying node has it already
+                        Some(mutable) if seq.is_some_and(|seq| mutable.seq <= seq) => {
+                            response.seq = Some(mutable.seq)
+                        }
+                        Some(mutable) => {
+                            response.key = Some(mutable.key);
+                            response.seq = Some(mutable.seq);
+                            response.signature = Some(mutable.signature);
+                            response.value = Some(value);
+                        }
+                        None => response.value = Some(value),
+                    }
+                }
+                response
+            }
+            Query::Put { token, item, cas } => {
+                if !self.tokens.lock().unwrap().is_valid(&token, &from) {
+                    return Message::error(transaction, krpc::PROTOCOL_ERROR, "Bad token");
+                }
+                if let Err(e) = item.verify() {
+                    let code = match e {
+                        ItemError::ValueTooBig => krpc::VALUE_TOO_BIG,
+                        ItemError::SaltTooBig => krpc::SALT_TOO_BIG,
+                        ItemError::InvalidSignature => krpc::INVALID_SIGNATURE,
+                    };
+                    return Message::error(transaction, code, &e.to_string());
+                }
+                let stored = self.items.lock().unwrap().put(item, cas, Instant::now());
+                if let Err((code, message)) = stored {
+                    return Message::error(transaction, code, message);
+                }
+                Response::default()
+            }
+            Query::Unknown(_) => {
+                return Message::error(transaction, krpc::METHOD_UNKNOWN, "Method Unknown")
+            }
+        };
+        Message::response(transaction, self.id(), response)
+    }
+
+    // Returns whether the reply answered a query of ours
+    fn reply(self: &Arc<Self>, transaction: &[u8], from: SocketAddrV4, reply: Reply) -> bool {
+        let Ok(transaction) = Transaction::try_from(transaction) else {
+            return false;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        // Answers from another address than the one queried are spoofed
+        if pending
+            .get(&transaction)
+            .is_some_and(|p| p.to == SocketAddr::V4(from))
+        {
+            let query = pending.remove(&transaction).expect("checked above");
+            drop(pending);
+            if let Ok((id, _)) = &reply {
+                self.heard_from(NodeInfo {
+                    id: *id,
+                    addr: from,
+                });
+            }
+            let _ = query.reply.send(reply);
+            return true;
+        }
+        false
+    }
+
+    // Our ID follows our address once enough nodes agree on it, the nodes
+    // known are kept
+    fn voted(&self, voter: Ipv4Addr, ip: Ipv4Addr) {
+        let Some(ip) = self.external_ip.lock().unwrap().vote(voter, ip) else {
+            return;
+        };
+        if self.config.node_id.is_none() {
+            self.set_id(routing::secure_id(ip));
+        }
+    }
+
+    // The nodes known stay in the table
+    fn set_id(&self, id: NodeId) {
+        let mut table = self.table.lock().unwrap();
+        let mut rebuilt = RoutingTable::new(id);
+        for node in table.nodes() {
+            rebuilt.insert(node, Instant::now());
+        }
+        *table = rebuilt;
+    }
+
+    // Whether the node may be in the table, see `DhtConfig::enforce_node_ids`
+    fn accepts(&self, node: &NodeInfo) -> bool {
+        let ip = *node.addr.ip();
+        !self.config.enforce_node_ids
+            || routing::is_local(ip)
+            || routing::is_secure_id(&node.id, ip)
+    }
+
+    // Nodes get in the table once they answered or queried us
+    fn heard_from(self: &Arc<Self>, node: NodeInfo) {
+        if !self.accepts(&node) {
+            return;
+        }
+        let inserted = self.table.lock().unwrap().insert(node, Instant::now());
+        if let Inserted::Full {
+            questionable: Some(questionable),
+        } = inserted
+        {
+            // The new node takes the place of the questionable one if it
+            // doesn't answer
+            let inner = self.clone();
+            tokio::spawn(async move {
+                if inner
+                    .query(questionable.addr.into(), Query::Ping)
+                    .await
+                    .is_err()
+                {
+                    let mut table = inner.table.lock().unwrap();
+                    table.remove(&questionable.id);
+                    table.insert(node, Instant::now());
+                }
+            });
+        }
+    }
+
+    async fn send(&self, msg: &Message, to: SocketAddr) {
+        if let Ok(bytes) = msg.to_bytes() {
+            // Lost datagrams are the norm, the query times out
+            let _ = self.socket.send_to(&bytes, to).await;
+        }
+    }
+
+    async fn query(self: &Arc<Self>, to: SocketAddr, query: Query) -> Reply {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let (reply, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction, Pending { to, reply });
+        let mut msg = Message::query(transaction.to_vec(), self.id(), query);
+        msg.read_only = self.config.read_only;
+        self.send(&msg, to).await;
+
+        let res = time::timeout(self.config.query_timeout, receiver).await;
+        self.pending.lock().unwrap().remove(&transaction);
+        match res {
+            Ok(Ok(reply)) => reply,
+            _ => {
+                if let SocketAddr::V4(addr) = to {
+                    self.table.lock().unwrap().failed(&addr);
+                }
+                Err(DhtError::Timeout)
+            }
+        }
+    }
+
+    async fn bootstrap(self: &Arc<Self>) -> usize {
+        let mut joined = JoinSet::new();
+        for host in &self.config.bootstrap {
+            let Ok(addrs) = lookup_host(host).await else {
+                continue;
+            };
+            for addr in addrs.filter(SocketAddr::is_ipv4) {
+                let inner = self.clone();
+                let target = self.id();
+                joined.spawn(async move { inner.query(addr, Query::FindNode { target }).await });
+            }
+        }
+        // The routers answering are in the table, they know the others
+        while joined.join_next().await.is_some() {}
+        self.find_node(self.id()).await;
+        let mut table = self.table.lock().unwrap();
+        table.touch(Instant::now());
+        *self.last_refresh.lock().unwrap() = Some(Instant::now());
+        table.len()
+    }
+
+    async fn refresh(self: &Arc<Self>) {
+        if self.table.lock().unwrap().is_empty() {
+            self.bootstrap().await;
+            return;
+        }
+        let targets = self.table.lock().unwrap().refresh_targets(Instant::now());
+        for target in targets {
+            self.find_node(target).await;
+        }
+        *self.last_refresh.lock().unwrap() = Some(Instant::now());
+    }
+
+    async fn announce(
+        self: &Arc<Self>,
+        info_hash: InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddr> {
+        let lookup = self.get_peers(info_hash, false, seed).await;
+        let mut announced = JoinSet::new();
+        for (node, token) in lookup.nodes.into_iter().take(K) {
+            let Some(token) = token else {
+                continue;
+            };
+            let query = Query::AnnouncePeer {
+                info_hash,
+                port: port.unwrap_or(0),
+                implied_port: port.is_none(),
+                token,
+                seed,
+            };
+            let inner = self.clone();
+            announced.spawn(async move { inner.query(node.addr.into(), query).await });
+        }
+        // Nodes which don't answer are marked failed by `query`
+        while announced.join_next().await.is_some() {}
+        lookup.peers
+    }
+
+    // What the owner of a bound node does, for a started one
+    async fn maintain(self: Arc<Self>) {
+        self.bootstrap().await;
+        let mut interval = time::interval_at(Instant::now() + REFRESH_INTERVAL, REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.refresh().await;
+            let now = Instant::now();
+            let due: Vec<_> = self
+                .announced
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .filter(|(_, a)| now.duration_since(a.at) >= ANNOUNCE_INTERVAL)
+                .map(|(hash, a)| {
+                    a.at = now;
+                    (*hash, *a)
+                })
+                .collect();
+            for (info_hash, a) in due {
+                self.announce(info_hash, a.port, a.seed).await;
+            }
+        }
+    }
+
+    async fn find_node(self: &Arc<Self>, target: NodeId) -> Lookup {
+        self.lookup(target, Query::FindNode { target }).await
+    }
+
+    async fn get(self: &Arc<Self>, target: NodeId) -> Lookup {
+        self.lookup(target, Query::Get { target, seq: None }).await
+    }
+
+    async fn get_peers(
+        self: &Arc<Self>,
+        info_hash: InfoHash,
+        scrape: bool,
+        noseed: bool,
+    ) -> Lookup {
+        let query = Query::GetPeers {
+            info_hash,
+            scrape,
+            noseed,
+        };
+        self.lookup(info_hash, query).await
+    }
+
+    // Iterative lookup of the nodes closest to `target`, asking `ALPHA` of
+    // them at a time, closer ones first, until the `K` closest answered
+    async fn lookup(self: &Arc<Self>, target: NodeId, query: Query) -> Lookup {
+        #[derive(PartialEq)]
+        enum State {
+            New,
+            Queried,
+            Answered(Box<Response>),
+            Failed,
+        }
+        let mut candidates: BTreeMap<NodeId, (NodeInfo, State)> = BTreeMap::new();
+        let id = self.id();
+        let add = |candidates: &mut BTreeMap<_, _>, node: NodeInfo| {
+            if node.id != id {
+                candidates
+                    .entry(routing::distance(&node.id, &target))
+                    .or_insert((node, State::New));
+            }
+        };
+        for node in self.table.lock().unwrap().closest(&target, K) {
+            add(&mut candidates, node);
+        }
+
+        let mut peers = HashSet::new();
+        let mut items = Vec::new();
+        let mut in_flight = JoinSet::new();
+        loop {
+            // The next closest which weren't asked, among the `K` closest
+            // which may still answer
+            let next: Vec<NodeId> = candidates
+                .iter()
+                .filter(|(_, (_, state))| *state != State::Failed)
+                .take(K)
+                .filter(|(_, (_, state))| *state == State::New)
+                .map(|(distance, _)| *distance)
+                .take(ALPHA.saturating_sub(in_flight.len()))
+                .collect();
+            for distance in next {
+                let (node, state) = candidates.get_mut(&distance).expect("just found");
+                *state = State::Queried;
+                let (inner, addr, query) = (self.clone(), node.addr, query.clone());
+                in_flight.spawn(async move { (distance, inner.query(addr.into(), query).await) });
+            }
+
+            let Some(res) = in_flight.join_next().await else {
+                break;
+            };
+            let Ok((distance, reply)) = res else {
+                continue;
+            };
+            let state = match reply {
+                Ok((_, mut response)) => {
+                    peers.extend(response.values.drain(..));
+                    for node in response.nodes.drain(..) {
+                        add(&mut candidates, node);
+                    }
+                    if response.value.is_some() {
+                        items.push(response.clone());
+                    }
+                    State::Answered(Box::new(response))
+                }
+                Err(_) => State::Failed,
+            };
+            if let Some((_, s)) = candidates.get_mut(&distance) {
+                *s = state;
+            }
+        }
+
+        let mut lookup = Lookup {
+            peers: peers.into_iter().collect(),
+            items,
+            ..Default::default()
+        };
+        let answered = candidates
+            .into_values()
+            .filter_map(|(node, state)| match state {
+                State::Answered(response) => Some((node, response)),
+                _ => None,
+            })
+            .take(K);
+        for (node, response) in answered {
+            if let Some(seeds) = &response.seeds {
+                lookup.seeds.union(seeds);
+            }
+            if let Some(downloaders) = &response.downloaders {
+                lookup.downloaders.union(downloaders);
+            }
+            lookup.nodes.push((node, response.token));
+        }
+        lookup
+    }
+}
+
+#[cfg(test)]
+mod dht_tests {
+    use super::*;
+
+    async fn local_node() -> Dht {
+        Dht::bind(DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: Vec::new(),
+            query_timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    // A small DHT on localhost, every node knows the first one
+    async fn local_dht(size: usize) -> Vec<Dht> {
+        let mut nodes = Vec::new();
+        for _ in 0..size {
+            nodes.push(local_node().await);
+        }
+        let first = nodes[0].local_addr().unwrap();
+        for node in &nodes[1..] {
+            node.add_node(first).await.unwrap();
+        }
+        for node in &nodes {
+            node.find_node(node.id()).await;
+        }
+        nodes
+    }
+
+    #[tokio::test]
+    async fn find_and_announce_peers() {
+        let nodes = local_dht(12).await;
+        assert!(nodes.iter().all(|n| n.nodes() > ALPHA));
+
+        let info_hash = [7; 20];
+        assert!(nodes[3].get_peers(info_hash).await.is_empty());
+        nodes[3].announce(info_hash, Some(51413), true).await;
+        // Announced without a port, the one of the DHT is taken
+        nodes[5].announce(info_hash, None, false).await;
+
+        let mut peers = nodes[9].get_peers(info_hash).await;
+        peers.sort();
+        let mut expected = vec![
+            SocketAddr::from(([127, 0, 0, 1], 51413)),
+            nodes[5].local_addr().unwrap(),
+        ];
+        expected.sort();
+        assert_eq!(expected, peers);
+
+        // A seed is only told of downloaders, the swarm is counted by address
+        let peers = nodes[9].announce(info_hash, Some(1), true).await;
+        assert_eq!(vec![nodes[5].local_addr().unwrap()], peers);
+        let scrape = nodes[11].scrape(info_hash).await;
+        assert_eq!(
+            Scrape {
+                seeds: 1,
+                leechers: 1
+            },
+            scrape
+        );
+    }
+
+    #[tokio::test]
+    async fn tokens_and_errors() {
+        let node = local_node().await;
+        let client = local_node().await;
+        let addr = node.local_addr().unwrap();
+        let info_hash = [1; 20];
+
+        // A token is needed to announce, it comes from a get_peers
+        let bad = Query::AnnouncePeer {
+            info_hash,
+            port: 1,
+            implied_port: false,
+            token: b"nope".to_vec(),
+            seed: false,
+        };
+        assert!(matches!(
+            client.inner.query(addr, bad).await,
+            Err(DhtError::Remote(krpc::PROTOCOL_ERROR, _))
+        ));
+        let (id, response) = client
+            .inner
+            .query(
+                addr,
+                Query::GetPeers {
+                    info_hash,
+                    scrape: false,
+                    noseed: false,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(node.id(), id);
+        let announce = Query::AnnouncePeer {
+            info_hash,
+            port: 1,
+            implied_port: false,
+            token: response.token.unwrap(),
+            seed: false,
+        };
+        client.inner.query(addr, announce).await.unwrap();
+        assert_eq!(
+            vec![SocketAddr::from(([127, 0, 0, 1], 1))],
+            client.get_peers(info_hash).await
+        );
+
+        let unknown = Query::Unknown("vote".to_string());
+        assert!(matches!(
+            client.inner.query(addr, unknown).await,
+            Err(DhtError::Remote(krpc::METHOD_UNKNOWN, _))
+        ));
+        // Both know each other now, a silent node times out
+        assert_eq!(1, node.nodes());
+        drop(node);
+        assert!(matches!(
+            client.add_node(addr).await,
+            Err(DhtError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn put_and_get_items() {
+        let nodes = local_dht(10).await;
+        let value = Value::Bytes(b"Hello World!".to_vec());
+        let item = Item::immutable(value.clone()).unwrap();
+        assert!(nodes[2].put(item.clone(), None).await > 0);
+        assert_eq!(Some(value), nodes[7].get_immutable(item.target()).await);
+        assert_eq!(None, nodes[7].get_immutable([0; 20]).await);
+
+        // Mutable items are replaced by newer ones only
+        let key = ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng);
+        let public = key.verifying_key().to_bytes();
+        let signed = |v: i64, seq| Item::signed(Value::Int(v), &key, b"salt", seq).unwrap();
+        assert!(nodes[1].put(signed(1, 1), None).await > 0);
+        assert!(nodes[4].put(signed(2, 2), Some(1)).await > 0);
+        assert_eq!(0, nodes[4].put(signed(3, 3), Some(1)).await);
+        assert_eq!(0, nodes[4].put(signed(0, 0), None).await);
+        let item = nodes[9].get_mutable(&public, b"salt").await.unwrap();
+        assert_eq!((Some(2), Value::Int(2)), (item.seq(), item.value));
+        assert_eq!(None, nodes[9].get_mutable(&public, b"pepper").await);
+
+        // Forged items aren't stored
+        let mut forged = signed(5, 5);
+        forged.value = Value::Int(6);
+        assert_eq!(0, nodes[3].put(forged, None).await);
+    }
+
+    #[tokio::test]
+    async fn sample_infohashes() {
+        let node = local_node().await;
+        let client = local_node().await;
+        let addr = node.local_addr().unwrap();
+        client.add_node(addr).await.unwrap();
+        let hashes: Vec<InfoHash> = (0..MAX_SAMPLES as u8 + 5).map(|i| [i; 20]).collect();
+        for hash in &hashes {
+            client.announce(*hash, Some(1), false).await;
+        }
+
+        let samples = client.sample_infohashes(addr, [0; 20]).await.unwrap();
+        assert_eq!(MAX_SAMPLES, samples.samples.len());
+        assert!(samples.samples.iter().all(|s| hashes.contains(s)));
+        assert_eq!(hashes.len() as u64, samples.num);
+        assert_eq!(SAMPLE_INTERVAL, samples.interval);
+        assert_eq!(client.id(), samples.nodes[0].id);
+    }
+
+    #[tokio::test]
+    async fn node_ids_follow_addresses() {
+        let node = local_node().await;
+        let addr = "21.75.31.124:6881".parse().unwrap();
+        // Remote nodes get in the table only with an ID matching their address
+        node.inner.heard_from(NodeInfo {
+            id: [0xff; 20],
+            addr,
+        });
+        assert_eq!(0, node.nodes());
+        let id = routing::secure_id(*addr.ip());
+        node.inner.heard_from(NodeInfo { id, addr });
+        assert_eq!(1, node.nodes());
+
+        // Our ID changes once enough remote nodes agree on our address
+        let ip = Ipv4Addr::new(124, 31, 75, 21);
+        for voter in 1..EXTERNAL_IP_VOTES as u8 {
+            node.inner.voted(Ipv4Addr::new(1, 1, 1, voter), ip);
+            node.inner.voted(Ipv4Addr::new(127, 0, 0, voter + 1), ip);
+        }
+        assert_eq!(None, node.external_ip());
+        node.inner.voted(Ipv4Addr::new(1, 1, 1, 1), ip);
+        node.inner.voted(Ipv4Addr::new(1, 1, 1, 200), ip);
+        assert_eq!(Some(ip), node.external_ip());
+        assert!(routing::is_secure_id(&node.id(), ip));
+        assert_eq!(1, node.nodes());
+
+        // Local nodes answer with our address as they see it
+        let client = local_node().await;
+        let local = node.local_addr().unwrap();
+        let SocketAddr::V4(from) = client.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let answer = node.inner.answer(b"aa".to_vec(), Query::Ping, from);
+        assert_eq!(Some(from), answer.ip);
+        client.add_node(local).await.unwrap();
+        assert_eq!(None, client.external_ip());
+    }
+    #[tokio::test]
+    async fn state_survives_restarts() {
+        const DIR: &str = "test_dht_state_survives_restarts";
+        let nodes = local_dht(3).await;
+        let state = nodes[0].state();
+        assert_eq!(2, state.nodes.len());
+        fs::create_dir_all(DIR).unwrap();
+        state.save(DIR).unwrap();
+        assert_eq!(Some(state.clone()), DhtState::load(DIR).unwrap());
+        fs::remove_dir_all(DIR).unwrap();
+        assert_eq!(None, DhtState::load(DIR).unwrap());
+
+        // Saved nodes are questionable until they answer again
+        let node = local_node().await;
+        node.restore(&state);
+        assert_eq!(state.id, node.id());
+        let stats = node.stats();
+        assert_eq!(
+            (2, 0, None),
+            (stats.nodes, stats.good_nodes, stats.last_refresh)
+        );
+        node.find_node(node.id()).await;
+        assert_eq!(2, node.stats().good_nodes);
+        node.refresh().await;
+        assert!(node.stats().last_refresh.is_some());
+
+        // Bootstrap nodes as in the `nodes` of a torrent
+        let other = local_node().await;
+        let port = nodes[1].local_addr().unwrap().port();
+        assert_eq!(1, other.add_host("127.0.0.1", port).await);
+        assert_eq!(1, other.nodes());
+    }
+    #[tokio::test]
+    async fn started_node() {
+        let router = local_node().await;
+        let node = Dht::start(DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: vec![router.local_addr().unwrap().to_string()],
+            query_timeout: Duration::from_millis(500),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while node.stats().last_refresh.is_none() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, node.nodes());
+
+        let info_hash = [3; 20];
+        node.keep_announcing(info_hash, Some(6881), false).await;
+        // Lookups ask the others, the router stores what was announced
+        let stored = router
+            .inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(&info_hash, false, Instant::now());
+        assert_eq!(vec![SocketAddr::from(([127, 0, 0, 1], 6881))], stored);
+        node.stop_announcing(&info_hash);
+        assert!(node.inner.announced.lock().unwrap().is_empty());
+    }
+    #[tokio::test]
+    async fn read_only_node() {
+        let node = local_node().await;
+        let read_only = Dht::bind(DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: Vec::new(),
+            query_timeout: Duration::from_millis(200),
+            read_only: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let addr = node.local_addr().unwrap();
+        assert_eq!(node.id(), read_only.add_node(addr).await.unwrap());
+        assert_eq!(1, read_only.nodes());
+        // Nobody hears of it, it answers nobody
+        assert_eq!(0, node.nodes());
+        let addr = read_only.local_addr().unwrap();
+        assert!(matches!(node.add_node(addr).await, Err(DhtError::Timeout)));
+
+        // Lookups and announces still work
+        read_only.announce([4; 20], Some(6881), false).await;
+        let stored = node
+            .inner
+            .peers
+            .lock()
+            .unwrap()
+            .get(&[4; 20], false, Instant::now());
+        assert_eq!(vec![SocketAddr::from(([127, 0, 0, 1], 6881))], stored);
+    }
+}
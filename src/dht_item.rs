@@ -0,0 +1,256 @@
+// Data stored in the DHT, see http://bittorrent.org/beps/bep_0044.html.
+// Immutable items are found by the SHA-1 of their value, mutable ones by the
+// one of their public key and salt, and are signed by the private key.
+use std::collections::BTreeMap;
+
+use bendy::{
+    decoding::{Error, FromBencode, Object},
+    encoding::{SingleItemEncoder, ToBencode},
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+use crate::routing::NodeId;
+
+// Nodes refuse bigger values, once bencoded
+pub const MAX_VALUE_LEN: usize = 1000;
+pub const MAX_SALT_LEN: usize = 64;
+// Values nested deeper can't be decoded
+pub const MAX_VALUE_DEPTH: usize = 16;
+
+pub type PublicKey = [u8; 32];
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ItemError {
+    #[error("Value is bigger than {MAX_VALUE_LEN} bytes")]
+    ValueTooBig,
+    #[error("Salt is bigger than {MAX_SALT_LEN} bytes")]
+    SaltTooBig,
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
+
+/// Any bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl ToBencode for Value {
+    const MAX_DEPTH: usize = MAX_VALUE_DEPTH;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        match self {
+            Value::Int(i) => encoder.emit_int(*i),
+            Value::Bytes(bytes) => encoder.emit_bytes(bytes),
+            Value::List(list) => encoder.emit_list(|e| list.iter().try_for_each(|v| e.emit(v))),
+            Value::Dict(dict) => {
+                encoder.emit_dict(|mut e| dict.iter().try_for_each(|(k, v)| e.emit_pair(k, v)))
+            }
+        }
+    }
+}
+
+impl FromBencode for Value {
+    const EXPECTED_RECURSION_DEPTH: usize = MAX_VALUE_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error> {
+        Ok(match object {
+            Object::Integer(_) => Value::Int(i64::decode_bencode_object(object)?),
+            Object::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+            Object::List(mut list) => {
+                let mut res = Vec::new();
+                while let Some(value) = list.next_object()? {
+                    res.push(Value::decode_bencode_object(value)?);
+                }
+                Value::List(res)
+            }
+            Object::Dict(mut dict) => {
+                let mut res = BTreeMap::new();
+                while let Some((key, value)) = dict.next_pair()? {
+                    res.insert(key.to_vec(), Value::decode_bencode_object(value)?);
+                }
+                Value::Dict(res)
+            }
+        })
+    }
+}
+
+/// What makes an item mutable: it is stored under its key and salt, and
+/// replaced by the items of higher `seq`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutable {
+    pub key: PublicKey,
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub signature: [u8; 64],
+}
+
+/// A value stored in the DHT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub value: Value,
+    pub mutable: Option<Mutable>,
+}
+
+impl Item {
+    pub fn immutable(value: Value) -> Result<Self, ItemError> {
+        encode(&value)?;
+        Ok(Item {
+            value,
+            mutable: None,
+        })
+    }
+
+    /// `value` signed with `key`, under `salt` if not empty.
+    pub fn signed(
+        value: Value,
+        key: &SigningKey,
+        salt: &[u8],
+        seq: i64,
+    ) -> Result<Self, ItemError> {
+        if salt.len() > MAX_SALT_LEN {
+            return Err(ItemError::SaltTooBig);
+        }
+        let signature = key.sign(&signed_bytes(salt, seq, &encode(&value)?));
+        Ok(Item {
+            value,
+            mutable: Some(Mutable {
+                key: key.verifying_key().to_bytes(),
+                salt: salt.to_vec(),
+                seq,
+                signature: signature.to_bytes(),
+            }),
+        })
+    }
+
+    /// ID the item is stored close to.
+    pub fn target(&self) -> NodeId {
+        match &self.mutable {
+            Some(mutable) => mutable_target(&mutable.key, &mutable.salt),
+            None => Sha1::digest(self.value.to_bencode().unwrap_or_default()).into(),
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|m| m.seq)
+    }
+
+    /// Check an item received from another node.
+    pub fn verify(&self) -> Result<(), ItemError> {
+        let value = encode(&self.value)?;
+        let Some(mutable) = &self.mutable else {
+            return Ok(());
+        };
+        if mutable.salt.len() > MAX_SALT_LEN {
+            return Err(ItemError::SaltTooBig);
+        }
+        let key =
+            VerifyingKey::from_bytes(&mutable.key).map_err(|_| ItemError::InvalidSignature)?;
+        let signature = Signature::from_bytes(&mutable.signature);
+        key.verify(
+            &signed_bytes(&mutable.salt, mutable.seq, &value),
+            &signature,
+        )
+        .map_err(|_| ItemError::InvalidSignature)
+    }
+}
+
+/// ID the mutable items of `key` under `salt` are stored close to.
+pub fn mutable_target(key: &PublicKey, salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+fn encode(value: &Value) -> Result<Vec<u8>, ItemError> {
+    match value.to_bencode() {
+        Ok(bytes) if bytes.len() <= MAX_VALUE_LEN => Ok(bytes),
+        _ => Err(ItemError::ValueTooBig),
+    }
+}
+
+// What is signed, as if the fields were in a dictionary of their own
+fn signed_bytes(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut res = Vec::new();
+    if !salt.is_empty() {
+        res.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        res.extend_from_slice(salt);
+    }
+    res.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    res.extend_from_slice(value);
+    res
+}
+
+#[cfg(test)]
+mod dht_item_tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn bep44_vectors() {
+        let value = Value::Bytes(b"Hello World!".to_vec());
+        let item = Item::immutable(value.clone()).unwrap();
+        assert_eq!(
+            hex("e5f96f6f38320f0f33959cb4d3d656452117aadb"),
+            item.target()
+        );
+
+        // Signed by the BEP, without then with a salt
+        let key = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let mut item = Item {
+            value,
+            mutable: Some(Mutable {
+                key: key.clone().try_into().unwrap(),
+                salt: Vec::new(),
+                seq: 1,
+                signature: hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01").try_into().unwrap(),
+            }),
+        };
+        assert_eq!(Ok(()), item.verify());
+        assert_eq!(
+            hex("4a533d47ec9c7d95b1ad75f576cffc641853b750"),
+            item.target()
+        );
+        let mutable = item.mutable.as_mut().unwrap();
+        mutable.salt = b"foobar".to_vec();
+        assert_eq!(Err(ItemError::InvalidSignature), item.verify());
+        item.mutable.as_mut().unwrap().signature = hex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08").try_into().unwrap();
+        assert_eq!(Ok(()), item.verify());
+        assert_eq!(
+            hex("411eba73b6f087ca51a3795d9c8c938d365e32c1"),
+            item.target()
+        );
+
+        // Our own signatures, on any value
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut dict = BTreeMap::new();
+        dict.insert(b"a".to_vec(), Value::List(vec![Value::Int(-1)]));
+        let item = Item::signed(Value::Dict(dict), &key, b"salt", 7).unwrap();
+        assert_eq!(Ok(()), item.verify());
+        assert_eq!(Some(7), item.seq());
+        let bytes = item.value.to_bencode().unwrap();
+        assert_eq!(&b"d1:ali-1eee"[..], &bytes[..]);
+        assert_eq!(item.value, Value::from_bencode(&bytes).unwrap());
+
+        let big = Value::Bytes(vec![0; MAX_VALUE_LEN]);
+        assert_eq!(Err(ItemError::ValueTooBig), Item::immutable(big));
+        let salt = [0; MAX_SALT_LEN + 1];
+        let small = Value::Int(0);
+        assert_eq!(
+            Err(ItemError::SaltTooBig),
+            Item::signed(small, &key, &salt, 1)
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+use crate::handshake::Handshake;
+use crate::ipfilter::SharedIpFilter;
+use crate::peer::PeerError;
+use crate::proxy::{self, ProxyConfig};
+
+// Consumer routers tend to drop NAT entries (or the whole connection table)
+// when hundreds of SYNs leave at once, so keep the default low.
+pub const DEFAULT_MAX_HALF_OPEN: usize = 8;
+pub const DEFAULT_DIAL_INTERVAL: Duration = Duration::from_millis(50);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct DialConfig {
+    // Maximum number of outbound connections in SYN-sent or handshaking state
+    pub max_half_open: usize,
+    // Minimum delay between two consecutive dials
+    pub dial_interval: Duration,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    // Route every peer connection through this SOCKS5 proxy
+    pub proxy: Option<ProxyConfig>,
+    // Blocked addresses are never dialed
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub ip_filter: SharedIpFilter,
+}
+
+/// Shared entry point for outbound peer connections. Cloning a `Dialer` shares
+/// its half-open slots and dial pacing.
+#[derive(Debug, Clone)]
+pub struct Dialer {
+    config: DialConfig,
+    half_open: Arc<Semaphore>,
+    next_dial: Arc<Mutex<Instant>>,
+}
+
+/// A connected socket which hasn't completed the BitTorrent handshake yet.
+/// It holds one of the dialer's half-open slots until it is handshaked or
+/// dropped.
+#[derive(Debug)]
+pub struct HalfOpen {
+    stream: TcpStream,
+    handshake_timeout: Duration,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        DialConfig {
+            max_half_open: DEFAULT_MAX_HALF_OPEN,
+            dial_interval: DEFAULT_DIAL_INTERVAL,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            proxy: None,
+            ip_filter: SharedIpFilter::default(),
+        }
+    }
+}
+
+impl Default for Dialer {
+    fn default() -> Self {
+        Dialer::new(DialConfig::default())
+    }
+}
+
+impl Dialer {
+    pub fn new(config: DialConfig) -> Self {
+        assert!(config.max_half_open > 0);
+
+        Dialer {
+            half_open: Arc::new(Semaphore::new(config.max_half_open)),
+            next_dial: Arc::new(Mutex::new(Instant::now())),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &DialConfig {
+        &self.config
+    }
+
+    /// Number of connections currently connecting or handshaking.
+    pub fn half_open(&self) -> usize {
+        self.config.max_half_open - self.half_open.available_permits()
+    }
+
+    // Dials are serialized through `next_dial` so that a burst of peers from
+    // an announce is spread over time instead of leaving all at once.
+    async fn pace(&self) {
+        let mut next = self.next_dial.lock().await;
+        time::sleep_until(*next).await;
+        *next = Instant::now() + self.config.dial_interval;
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<HalfOpen> {
+        if self.config.ip_filter.is_blocked(addr.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Blocked by the IP filter",
+            ));
+        }
+        let permit = self
+            .half_open
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Half-open semaphore closed");
+        self.pace().await;
+
+        let connect = async {
+            match &self.config.proxy {
+                Some(p) => proxy::connect(p, addr).await,
+                None => TcpStream::connect(addr).await,
+            }
+        };
+        let stream = time::timeout(self.config.connect_timeout, connect)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connection timed out"))??;
+
+        Ok(HalfOpen {
+            stream,
+            handshake_timeout: self.config.handshake_timeout,
+            _permit: permit,
+        })
+    }
+}
+
+impl HalfOpen {
+    pub fn get_stream_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    /// The stream, for another protocol than BitTorrent. The half-open slot
+    /// is released.
+    pub fn into_stream(self) -> TcpStream {
+        self.stream
+    }
+
+    /// Exchange handshakes with the remote peer. The half-open slot is released
+    /// whether it succeeds or not.
+    pub async fn handshake(mut self, hs: Handshake) -> Result<(TcpStream, Handshake), PeerError> {
+        let res = time::timeout(self.handshake_timeout, hs.send(&mut self.stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))??;
+
+        Ok((self.stream, res))
+    }
+}
+
+#[cfg(test)]
+mod dialer_tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn listener() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn blocked_address() {
+        use crate::ipfilter::IpFilter;
+
+        let (_listener, addr) = listener().await;
+        let ip_filter = SharedIpFilter::default();
+        let dialer = Dialer::new(DialConfig {
+            ip_filter: ip_filter.clone(),
+            ..DialConfig::default()
+        });
+        assert!(dialer.connect(addr).await.is_ok());
+
+        ip_filter.set(IpFilter::parse("127.0.0.0/8").unwrap());
+        let err = dialer.connect(addr).await.unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        assert_eq!(0, dialer.half_open());
+    }
+
+    #[tokio::test]
+    async fn half_open_limit() {
+        let (_listener, addr) = listener().await;
+        let dialer = Dialer::new(DialConfig {
+            max_half_open: 2,
+            dial_interval: Duration::ZERO,
+            ..DialConfig::default()
+        });
+
+        let first = dialer.connect(addr).await.unwrap();
+        let _second = dialer.connect(addr).await.unwrap();
+        assert_eq!(2, dialer.half_open());
+
+        // No slot left, the third dial must wait
+        let third = time::timeout(Duration::from_millis(100), dialer.connect(addr)).await;
+        assert!(third.is_err());
+
+        drop(first);
+        assert_eq!(1, dialer.half_open());
+        assert!(dialer.connect(addr).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn dial_pacing() {
+        let (_listener, addr) = listener().await;
+        let dialer = Dialer::new(DialConfig {
+            dial_interval: Duration::from_millis(100),
+            ..DialConfig::default()
+        });
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            dialer.connect(addr).await.unwrap();
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn handshake_releases_slot() {
+        let (listener, addr) = listener().await;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 68];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let dialer = Dialer::default();
+        let half_open = dialer.connect(addr).await.unwrap();
+        assert_eq!(1, dialer.half_open());
+
+        let (_, hs) = half_open.handshake(Handshake::default()).await.unwrap();
+        assert_eq!(hs, Handshake::default());
+        assert_eq!(0, dialer.half_open());
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout() {
+        let (listener, addr) = listener().await;
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let dialer = Dialer::new(DialConfig {
+            handshake_timeout: Duration::from_millis(100),
+            ..DialConfig::default()
+        });
+        let half_open = dialer.connect(addr).await.unwrap();
+
+        assert!(half_open.handshake(Handshake::default()).await.is_err());
+        assert_eq!(0, dialer.half_open());
+    }
+}
@@ -0,0 +1,153 @@
+// Direct I/O bypassing the page cache, so torrent traffic doesn't evict the
+// cache of everything else on a seedbox. O_DIRECT needs the offset, length
+// and memory of every transfer aligned, data goes through an aligned bounce
+// buffer and unaligned transfers must use a regular handle.
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
+    ptr::NonNull,
+};
+
+use crate::backend::{pread_full, pwrite_all};
+
+// Logical block size of most devices, and the page size
+pub const DIRECT_IO_ALIGN: usize = 4096;
+
+/// Heap buffer aligned on `DIRECT_IO_ALIGN`.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the buffer owns its allocation like a Vec<u8>
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    /// Zeroed buffer of `len` bytes, rounded up to the alignment.
+    pub fn new(len: usize) -> Self {
+        let len = len.div_ceil(DIRECT_IO_ALIGN).max(1) * DIRECT_IO_ALIGN;
+        let layout = Layout::from_size_align(len, DIRECT_IO_ALIGN).unwrap();
+        // SAFETY: the layout has a non zero size
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        AlignedBuf { ptr, len }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: `ptr` points to `len` initialized bytes owned by us
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as above, and `&mut self` guarantees exclusive access
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.len, DIRECT_IO_ALIGN).unwrap();
+        // SAFETY: allocated in `new` with the same layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), layout) }
+    }
+}
+
+/// The transfer can go through a direct handle.
+pub fn is_aligned(offset: u64, len: usize) -> bool {
+    offset.is_multiple_of(DIRECT_IO_ALIGN as u64) && len.is_multiple_of(DIRECT_IO_ALIGN)
+}
+
+/// Open an existing file for direct I/O. Returns `None` when direct I/O isn't
+/// available, e.g. on tmpfs or outside of Linux.
+pub fn open_direct<P: AsRef<Path>>(path: P) -> io::Result<Option<File>> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let res = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path);
+        match res {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        Ok(None)
+    }
+}
+
+/// Read `buf.len()` aligned bytes at an aligned `offset` from a direct handle.
+pub fn pread_direct(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    debug_assert!(is_aligned(offset, buf.len()));
+    let mut aligned = AlignedBuf::new(buf.len());
+    let n = pread_full(file, &mut aligned[..buf.len()], offset)?;
+    buf[..n].copy_from_slice(&aligned[..n]);
+    Ok(n)
+}
+
+/// Write aligned `buf` at an aligned `offset` to a direct handle.
+pub fn pwrite_direct(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    debug_assert!(is_aligned(offset, buf.len()));
+    let mut aligned = AlignedBuf::new(buf.len());
+    aligned[..buf.len()].copy_from_slice(buf);
+    pwrite_all(file, &aligned[..buf.len()], offset)?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod direct_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn aligned_buffer() {
+        let buf = AlignedBuf::new(10);
+        assert_eq!(DIRECT_IO_ALIGN, buf.len());
+        assert_eq!(0, buf.as_ptr() as usize % DIRECT_IO_ALIGN);
+        assert!(buf.iter().all(|&b| b == 0));
+
+        assert!(is_aligned(2 * DIRECT_IO_ALIGN as u64, DIRECT_IO_ALIGN));
+        assert!(!is_aligned(0, 100));
+    }
+
+    #[test]
+    fn direct_roundtrip() {
+        const FILE: &str = "./test_direct_roundtrip";
+        fs::write(FILE, vec![0u8; 2 * DIRECT_IO_ALIGN]).unwrap();
+
+        // Some filesystems, like tmpfs, don't support it
+        let Some(file) = open_direct(FILE).unwrap() else {
+            fs::remove_file(FILE).unwrap();
+            return;
+        };
+        let data = vec![7u8; DIRECT_IO_ALIGN];
+        let offset = DIRECT_IO_ALIGN as u64;
+        assert_eq!(data.len(), pwrite_direct(&file, &data, offset).unwrap());
+
+        let mut buf = vec![0u8; DIRECT_IO_ALIGN];
+        assert_eq!(buf.len(), pread_direct(&file, &mut buf, offset).unwrap());
+        assert_eq!(data, buf);
+
+        drop(file);
+        fs::remove_file(FILE).unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+// Errors of the crate as a whole, for callers which don't care which part
+// failed. Each part has its own error type.
+use std::io;
+
+use thiserror::Error;
+
+use crate::decode_torrent::MetaInfoError;
+use crate::peer::PeerError;
+use crate::storage::StorageError;
+use crate::tracker::TrackerError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    MetaInfo(#[from] MetaInfoError),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("No peer sent the metadata")]
+    NoMetadata,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,147 @@
+// Notifications of what happens to the torrents of a session, so that
+// applications can react to them instead of polling.
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+
+use crate::definitions::InfoHash;
+#[cfg(feature = "geoip")]
+use crate::geoip::PeerLocation;
+
+/// Events kept for a subscriber before it starts missing some.
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Event {
+    PieceVerified {
+        info_hash: InfoHash,
+        index: usize,
+    },
+    // The piece didn't match its hash and is downloaded again
+    PieceFailed {
+        info_hash: InfoHash,
+        index: usize,
+    },
+    // Every wanted piece is verified
+    TorrentCompleted {
+        info_hash: InfoHash,
+    },
+    PeerConnected {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+        #[cfg(feature = "geoip")]
+        location: Option<PeerLocation>,
+    },
+    PeerDisconnected {
+        info_hash: InfoHash,
+        addr: SocketAddr,
+    },
+    TrackerError {
+        info_hash: InfoHash,
+        message: String,
+    },
+    DiskError {
+        info_hash: InfoHash,
+        message: String,
+    },
+    // Paused or stopped, see `SeedLimits`
+    SeedLimitReached {
+        info_hash: InfoHash,
+    },
+}
+
+impl Event {
+    pub fn info_hash(&self) -> &InfoHash {
+        match self {
+            Event::PieceVerified { info_hash, .. }
+            | Event::PieceFailed { info_hash, .. }
+            | Event::TorrentCompleted { info_hash }
+            | Event::PeerConnected { info_hash, .. }
+            | Event::PeerDisconnected { info_hash, .. }
+            | Event::TrackerError { info_hash, .. }
+            | Event::DiskError { info_hash, .. }
+            | Event::SeedLimitReached { info_hash } => info_hash,
+        }
+    }
+}
+
+pub type EventSender = broadcast::Sender<Event>;
+
+/// Subscription to the events of a session, see `Session::events`. Only
+/// events sent after subscribing are received.
+#[derive(Debug)]
+pub struct Events {
+    receiver: broadcast::Receiver<Event>,
+    missed: u64,
+}
+
+impl Events {
+    pub fn new(receiver: broadcast::Receiver<Event>) -> Self {
+        Events {
+            receiver,
+            missed: 0,
+        }
+    }
+
+    /// Wait for the next event, `None` once the session is gone. Events
+    /// missed by a slow subscriber are skipped, see `missed`.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(n)) => self.missed += n,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Next event if there is one already, e.g. from a UI refresh loop.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(event) => return Some(event),
+                Err(TryRecvError::Lagged(n)) => self.missed += n,
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Number of events dropped because they weren't received in time.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod events_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_subscriber() {
+        let (sender, _) = broadcast::channel(2);
+        let mut events = Events::new(sender.subscribe());
+        for index in 0..3 {
+            let info_hash = [1; 20];
+            sender
+                .send(Event::PieceVerified { info_hash, index })
+                .unwrap();
+        }
+
+        // The first event was dropped to make room
+        let event = events.recv().await.unwrap();
+        assert_eq!(
+            Event::PieceVerified {
+                info_hash: [1; 20],
+                index: 1
+            },
+            event
+        );
+        assert_eq!(1, events.missed());
+        assert_eq!(&[1; 20], events.try_recv().unwrap().info_hash());
+        assert!(events.try_recv().is_none());
+
+        drop(sender);
+        assert!(events.recv().await.is_none());
+    }
+}
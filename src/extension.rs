@@ -0,0 +1,231 @@
+// Extension protocol, see http://bittorrent.org/beps/bep_0010.html
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object, ResultExt},
+    encoding::{AsString, SingleItemEncoder, ToBencode},
+};
+
+pub const EXTENDED_MSG_ID: u8 = 20;
+pub const HANDSHAKE_EXT_ID: u8 = 0;
+
+// Same default as libtorrent, used when the peer doesn't advertise `reqq`
+pub const DEFAULT_REQQ: u32 = 250;
+
+pub const CLIENT_VERSION: &str = concat!("torrent-rs ", env!("CARGO_PKG_VERSION"));
+
+/// Content of the extension handshake, exchanged right after the BitTorrent
+/// handshake when both peers set the extension protocol reserved bit.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    // Extension name -> message id, an id of 0 means disabled
+    pub m: BTreeMap<String, u8>,
+    // Client name and version
+    pub v: Option<String>,
+    // Number of outstanding requests the client accepts
+    pub reqq: Option<u32>,
+    // Local TCP listen port
+    pub p: Option<u16>,
+    // The IP address of the receiving peer, as seen by the sender
+    pub yourip: Option<IpAddr>,
+    // Size of the info dictionary (BEP 9)
+    pub metadata_size: Option<u64>,
+}
+
+impl ExtensionHandshake {
+    /// Our own handshake, to be sent to `peer_ip`.
+    pub fn ours(
+        listen_port: Option<u16>,
+        peer_ip: Option<IpAddr>,
+        metadata_size: Option<u64>,
+    ) -> Self {
+        ExtensionHandshake {
+            m: BTreeMap::new(),
+            v: Some(CLIENT_VERSION.to_string()),
+            reqq: Some(DEFAULT_REQQ),
+            p: listen_port,
+            yourip: peer_ip,
+            metadata_size,
+        }
+    }
+
+    /// Message id the remote peer expects for `name`, if it supports it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|&id| id != 0)
+    }
+
+    /// Maximum number of requests we may pipeline to the peer.
+    pub fn request_limit(&self) -> usize {
+        self.reqq.unwrap_or(DEFAULT_REQQ) as usize
+    }
+
+    /// Full wire message: length prefix, message id and payload.
+    pub fn to_message(&self) -> Result<Vec<u8>, bendy::encoding::Error> {
+        let payload = self.to_bencode()?;
+        let mut res = Vec::with_capacity(6 + payload.len());
+        res.extend_from_slice(&(payload.len() as u32 + 2).to_be_bytes());
+        res.push(EXTENDED_MSG_ID);
+        res.push(HANDSHAKE_EXT_ID);
+        res.extend_from_slice(&payload);
+        Ok(res)
+    }
+}
+
+fn ip_to_bytes(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn bytes_to_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::from(
+            <[u8; 4]>::try_from(bytes).unwrap(),
+        ))),
+        16 => Some(IpAddr::V6(Ipv6Addr::from(
+            <[u8; 16]>::try_from(bytes).unwrap(),
+        ))),
+        _ => None,
+    }
+}
+
+impl ToBencode for ExtensionHandshake {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        // Keys must be emitted in lexicographic order
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"m", &self.m)?;
+            if let Some(size) = self.metadata_size {
+                e.emit_pair(b"metadata_size", size)?;
+            }
+            if let Some(p) = self.p {
+                e.emit_pair(b"p", p)?;
+            }
+            if let Some(reqq) = self.reqq {
+                e.emit_pair(b"reqq", reqq)?;
+            }
+            if let Some(v) = &self.v {
+                e.emit_pair(b"v", v)?;
+            }
+            if let Some(ip) = &self.yourip {
+                e.emit_pair(b"yourip", AsString(ip_to_bytes(ip)))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl FromBencode for ExtensionHandshake {
+    // Leave room for nested values of extensions we don't know about
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    /// Unlike the metainfo, unknown keys are expected here and ignored, as
+    /// required by BEP 10.
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut res = ExtensionHandshake::default();
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"m", value) => {
+                    res.m = BTreeMap::decode_bencode_object(value).context("m")?;
+                }
+                (b"v", value) => {
+                    // Some clients don't send valid UTF-8 here
+                    res.v = AsString::decode_bencode_object(value).context("v").map(
+                        |bytes: AsString<Vec<u8>>| {
+                            Some(String::from_utf8_lossy(&bytes.0).into_owned())
+                        },
+                    )?;
+                }
+                (b"reqq", value) => {
+                    res.reqq = u32::decode_bencode_object(value)
+                        .context("reqq")
+                        .map(Some)?;
+                }
+                (b"p", value) => {
+                    res.p = u16::decode_bencode_object(value).context("p").map(Some)?;
+                }
+                (b"yourip", value) => {
+                    res.yourip = bytes_to_ip(value.try_into_bytes().context("yourip")?);
+                }
+                (b"metadata_size", value) => {
+                    res.metadata_size = u64::decode_bencode_object(value)
+                        .context("metadata_size")
+                        .map(Some)?;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod extension_tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut hs = ExtensionHandshake::ours(
+            Some(6881),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            Some(31235),
+        );
+        hs.m.insert("ut_metadata".to_string(), 3);
+
+        let bytes = hs.to_bencode().unwrap();
+        assert_eq!(hs, ExtensionHandshake::from_bencode(&bytes).unwrap());
+    }
+
+    #[test]
+    fn encoding_order() {
+        let hs = ExtensionHandshake {
+            p: Some(6881),
+            reqq: Some(500),
+            v: Some("x".to_string()),
+            yourip: Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            b"d1:mde1:pi6881e4:reqqi500e1:v1:x6:yourip4:\x01\x02\x03\x04e".to_vec(),
+            hs.to_bencode().unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_with_unknown_keys() {
+        let bytes = b"d12:complete_agoi1e1:md11:lt_donthavei7e6:ut_pexi1ee1:pi51413e4:reqqi512e11:upload_onlyi0e1:v17:Transmission 2.946:yourip16:\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01e";
+        let hs = ExtensionHandshake::from_bencode(bytes).unwrap();
+
+        assert_eq!(Some(51413), hs.p);
+        assert_eq!(Some(512), hs.reqq);
+        assert_eq!(512, hs.request_limit());
+        assert_eq!(Some("Transmission 2.94".to_string()), hs.v);
+        assert_eq!(Some(IpAddr::V6(Ipv6Addr::LOCALHOST)), hs.yourip);
+        assert_eq!(Some(1), hs.extension_id("ut_pex"));
+        assert_eq!(None, hs.extension_id("ut_metadata"));
+        assert_eq!(None, hs.metadata_size);
+    }
+
+    #[test]
+    fn default_request_limit() {
+        let hs = ExtensionHandshake::from_bencode(b"d1:mdee").unwrap();
+        assert_eq!(DEFAULT_REQQ as usize, hs.request_limit());
+    }
+
+    #[test]
+    fn message_framing() {
+        let msg = ExtensionHandshake::default().to_message().unwrap();
+        assert_eq!(&[0, 0, 0, 9, EXTENDED_MSG_ID, HANDSHAKE_EXT_ID], &msg[..6]);
+        assert_eq!(b"d1:mdee", &msg[6..]);
+    }
+}
@@ -0,0 +1,320 @@
+// Sharing the limits of a session between its torrents. Each torrent gets a
+// part in proportion to its weight but never more than it asks for, what it
+// leaves goes to the others (max-min fairness). A minimum share keeps slow
+// torrents from starving behind busy ones.
+use std::{
+    cmp::Reverse,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::definitions::BLOCK_SIZE;
+
+pub const DEFAULT_WEIGHT: u32 = 1;
+// Bytes per second each torrent which wants some gets of a limited rate
+pub const MIN_RATE: u64 = BLOCK_SIZE as u64;
+
+/// Limits of the session, shared between its running torrents.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FairnessConfig {
+    // Bytes per second
+    pub upload_rate: Option<u64>,
+    pub download_rate: Option<u64>,
+    // Peers unchoked at the same time
+    pub upload_slots: Option<usize>,
+    pub connections: Option<usize>,
+    // Least bytes per second of a torrent, as long as the limit allows
+    pub min_rate: u64,
+}
+
+impl Default for FairnessConfig {
+    fn default() -> Self {
+        FairnessConfig {
+            upload_rate: None,
+            download_rate: None,
+            upload_slots: None,
+            connections: None,
+            min_rate: MIN_RATE,
+        }
+    }
+}
+
+/// What a torrent asks for of a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Demand {
+    pub weight: u32,
+    pub wanted: u64,
+}
+
+/// What a torrent would use of each limit, see `Torrent::demands`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Demands {
+    // Bytes since the last allocation, sent or waiting to be
+    pub upload: u64,
+    pub download: u64,
+    // Interested peers
+    pub upload_slots: usize,
+    // Peers connected or known
+    pub connections: usize,
+}
+
+/// Part of each limit given to a torrent, `None` when unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Shares {
+    // Bytes until the next allocation
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+    pub upload_slots: Option<usize>,
+    pub connections: Option<usize>,
+}
+
+/// Split `total` between `demands`, in the same order. Every demand which
+/// wants something gets `min` first, or an equal part of `total` if there
+/// isn't enough. A weight of 0 only gets that minimum.
+pub fn fair_shares(total: u64, min: u64, demands: &[Demand]) -> Vec<u64> {
+    let mut shares = vec![0; demands.len()];
+    let wanting = demands.iter().filter(|d| d.wanted > 0).count() as u64;
+    if wanting == 0 {
+        return shares;
+    }
+    let floor = min.min(total / wanting);
+    for (share, demand) in shares.iter_mut().zip(demands) {
+        *share = floor.min(demand.wanted);
+    }
+    let mut remaining = total - shares.iter().sum::<u64>();
+
+    while remaining > 0 {
+        let mut active: Vec<usize> = (0..demands.len())
+            .filter(|&i| demands[i].weight > 0 && shares[i] < demands[i].wanted)
+            .collect();
+        if active.is_empty() {
+            break;
+        }
+        let weights: u128 = active.iter().map(|&i| demands[i].weight as u128).sum();
+        let mut given = 0;
+        for &i in &active {
+            let part = (remaining as u128 * demands[i].weight as u128 / weights) as u64;
+            let give = part.min(demands[i].wanted - shares[i]);
+            shares[i] += give;
+            given += give;
+        }
+        if given == 0 {
+            // Less than one unit for each, the heaviest first
+            active.sort_by_key(|&i| Reverse(demands[i].weight));
+            for i in active.into_iter().take(remaining as usize) {
+                shares[i] += 1;
+            }
+            break;
+        }
+        remaining -= given;
+    }
+    shares
+}
+
+/// `fair_shares` of a limit, `None` for each demand without one.
+pub fn share_limit(limit: Option<u64>, min: u64, demands: &[Demand]) -> Vec<Option<u64>> {
+    match limit {
+        Some(total) => fair_shares(total, min, demands)
+            .into_iter()
+            .map(Some)
+            .collect(),
+        None => vec![None; demands.len()],
+    }
+}
+
+/// Held by a peer connection, see `ConnectionLimit`.
+pub type ConnectionSlot = OwnedSemaphorePermit;
+
+/// Peer connections of a session across its torrents, each holding a slot
+/// as long as it is open. Cloning shares the slots.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    max: usize,
+    slots: Arc<Semaphore>,
+}
+
+impl Default for ConnectionLimit {
+    fn default() -> Self {
+        ConnectionLimit::new(None)
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        let max = max.unwrap_or(Semaphore::MAX_PERMITS);
+        ConnectionLimit {
+            max,
+            slots: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// A slot for a new connection, `None` if every one is taken.
+    pub fn try_acquire(&self) -> Option<ConnectionSlot> {
+        self.slots.clone().try_acquire_owned().ok()
+    }
+
+    /// Open connections.
+    pub fn connections(&self) -> usize {
+        self.max - self.slots.available_permits()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.slots.available_permits() == 0
+    }
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    // Bytes left until the next refill, `None` when unlimited. A transfer
+    // starts as long as some are left and may overdraw them.
+    available: Option<i64>,
+    used: u64,
+    // Transfers waiting for a refill, and those given up until then
+    waiting: u64,
+    denied: u64,
+}
+
+/// Bytes a torrent may transfer until the session allocates again, shared
+/// with the tasks of its peers. Unlimited until refilled with a share.
+#[derive(Debug, Default)]
+pub struct RateBudget {
+    state: Mutex<BudgetState>,
+    refilled: Notify,
+}
+
+impl RateBudget {
+    /// Take `bytes` if some are left, counting them as wanted otherwise.
+    pub fn try_take(&self, bytes: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.available {
+            Some(available) if available <= 0 => {
+                state.denied += bytes;
+                false
+            }
+            available => {
+                state.available = available.map(|a| a - bytes as i64);
+                state.used += bytes;
+                true
+            }
+        }
+    }
+
+    /// Take `bytes`, waiting for a refill if none are left.
+    pub async fn take(&self, bytes: u64) {
+        let mut waiting = false;
+        loop {
+            let refilled = self.refilled.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.available.is_none_or(|a| a > 0) {
+                    state.available = state.available.map(|a| a - bytes as i64);
+                    state.used += bytes;
+                    if waiting {
+                        state.waiting -= bytes;
+                    }
+                    return;
+                }
+                if !waiting {
+                    state.waiting += bytes;
+                    waiting = true;
+                }
+            }
+            refilled.await;
+        }
+    }
+
+    /// Bytes used and wanted since the last refill.
+    pub fn demand(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.used + state.waiting + state.denied
+    }
+
+    /// Start a new period with `share` bytes, `None` for no limit. What was
+    /// left of the previous one is kept, up to `share`.
+    pub fn refill(&self, share: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.available = share.map(|share| {
+            let share = share.min(i64::MAX as u64) as i64;
+            state
+                .available
+                .unwrap_or(0)
+                .saturating_add(share)
+                .min(share)
+        });
+        state.used = 0;
+        state.denied = 0;
+        drop(state);
+        self.refilled.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod fairness_tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::{self, Duration};
+
+    fn demand(weight: u32, wanted: u64) -> Demand {
+        Demand { weight, wanted }
+    }
+
+    #[test]
+    fn weighted_max_min_shares() {
+        // What the small one leaves goes to the others, by weight
+        let demands = [demand(1, 10), demand(1, 1000), demand(2, 1000)];
+        assert_eq!(vec![10, 330, 660], fair_shares(1000, 0, &demands));
+        // Nobody gets more than asked
+        assert_eq!(vec![10, 1000, 1000], fair_shares(5000, 0, &demands));
+        assert_eq!(
+            vec![0, 0],
+            fair_shares(100, 10, &[demand(1, 0), demand(1, 0)])
+        );
+
+        // The minimum comes first, even for a weight of 0
+        let demands = [demand(0, 1000), demand(10, 1000)];
+        assert_eq!(vec![100, 900], fair_shares(1000, 100, &demands));
+        // Split evenly when there isn't enough for it
+        assert_eq!(vec![50, 50], fair_shares(100, 100, &demands));
+
+        // Rounding leftovers go to the heaviest
+        let demands = [demand(1, 10), demand(2, 10), demand(1, 10)];
+        assert_eq!(vec![1, 2, 1], fair_shares(4, 0, &demands));
+        assert_eq!(5, fair_shares(5, 0, &demands).iter().sum::<u64>());
+    }
+
+    #[tokio::test]
+    async fn budget_waits_for_refill() {
+        let budget = Arc::new(RateBudget::default());
+        // Unlimited until refilled, usage is still counted
+        assert!(budget.try_take(100));
+        assert_eq!(100, budget.demand());
+
+        budget.refill(Some(10));
+        assert_eq!(0, budget.demand());
+        // Overdrawn by the first transfer, the next ones wait
+        assert!(budget.try_take(16));
+        assert!(!budget.try_take(16));
+        let waiting = budget.clone();
+        let take = tokio::spawn(async move { waiting.take(16).await });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!take.is_finished());
+        assert_eq!(48, budget.demand());
+
+        // The debt is paid first
+        budget.refill(Some(5));
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!take.is_finished());
+        budget.refill(Some(10));
+        time::timeout(Duration::from_secs(1), take)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(16, budget.demand());
+    }
+}
@@ -0,0 +1,324 @@
+// libtorrent's `.fastresume` format, as written by qBittorrent and other
+// libtorrent based clients
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object, ResultExt},
+    encoding::{AsString, SingleItemEncoder, ToBencode},
+};
+
+use crate::definitions::{InfoHash, BLOCK_SIZE};
+use crate::resume::{pack_bits, unpack_bits, ResumeData, UnfinishedPiece};
+
+pub const FILE_FORMAT: &str = "libtorrent resume file";
+pub const FILE_VERSION: u64 = 1;
+
+// Bit set in the per-piece byte of `pieces` when the piece is downloaded
+const PIECE_HAVE: u8 = 1;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FastResume {
+    pub info_hash: InfoHash,
+    pub name: Option<String>,
+    pub save_path: Option<String>,
+    // Downloaded pieces
+    pub pieces: Vec<bool>,
+    // Piece index -> bitmask of the received blocks, packed MSB first
+    pub unfinished: BTreeMap<usize, Vec<u8>>,
+    // Size and mtime of every file of the torrent
+    pub file_sizes: Vec<(u64, u64)>,
+    pub total_uploaded: u64,
+    pub total_downloaded: u64,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl FastResume {
+    /// Convert our own resume data for a single-file torrent.
+    pub fn from_resume_data(data: &ResumeData, info_hash: InfoHash, name: &str) -> Self {
+        FastResume {
+            info_hash,
+            name: Some(name.to_string()),
+            save_path: None,
+            pieces: data.pieces.clone(),
+            unfinished: data
+                .unfinished
+                .iter()
+                .map(|p| (p.index, pack_bits(&p.blocks)))
+                .collect(),
+            file_sizes: vec![(data.file_size, data.file_mtime)],
+            total_uploaded: data.uploaded,
+            total_downloaded: data.downloaded,
+        }
+    }
+
+    /// Convert to our resume data. The piece size isn't part of the format
+    /// and must come from the torrent. Without file sizes the result won't
+    /// match any file and a recheck is done instead.
+    pub fn to_resume_data(&self, piece_size: usize) -> ResumeData {
+        let (file_size, file_mtime) = self.file_sizes.first().copied().unwrap_or_default();
+        let num_pieces = self.pieces.len();
+
+        let unfinished = self
+            .unfinished
+            .iter()
+            .filter(|(&index, _)| index < num_pieces)
+            .filter_map(|(&index, mask)| {
+                let len = if index == num_pieces - 1 {
+                    (file_size as usize).saturating_sub(index * piece_size)
+                } else {
+                    piece_size
+                };
+                let count = len.div_ceil(BLOCK_SIZE);
+                // libtorrent may pad the mask
+                let mask = mask.get(..count.div_ceil(8))?;
+                Some(UnfinishedPiece {
+                    index,
+                    blocks: unpack_bits(mask, count)?,
+                })
+            })
+            .collect();
+
+        ResumeData {
+            piece_size,
+            file_size,
+            file_mtime,
+            pieces: self.pieces.clone(),
+            unfinished,
+            uploaded: self.total_uploaded,
+            downloaded: self.total_downloaded,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        FastResume::from_bencode(&fs::read(path)?).map_err(invalid_data)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bencode().map_err(invalid_data)?)
+    }
+}
+
+struct UnfinishedEntry(usize, Vec<u8>);
+
+impl ToBencode for UnfinishedEntry {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"bitmask", AsString(&self.1))?;
+            e.emit_pair(b"piece", self.0)
+        })
+    }
+}
+
+impl FromBencode for UnfinishedEntry {
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut piece = None;
+        let mut bitmask = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"bitmask", value) => {
+                    bitmask = Some(value.try_into_bytes().context("bitmask")?.to_vec());
+                }
+                (b"piece", value) => {
+                    piece = Some(usize::decode_bencode_object(value).context("piece")?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(UnfinishedEntry(
+            piece.ok_or_else(|| Error::missing_field("piece"))?,
+            bitmask.ok_or_else(|| Error::missing_field("bitmask"))?,
+        ))
+    }
+}
+
+impl ToBencode for FastResume {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        let pieces: Vec<u8> = self
+            .pieces
+            .iter()
+            .map(|&p| if p { PIECE_HAVE } else { 0 })
+            .collect();
+        let file_sizes: Vec<Vec<u64>> = self
+            .file_sizes
+            .iter()
+            .map(|&(size, mtime)| vec![size, mtime])
+            .collect();
+        let unfinished: Vec<UnfinishedEntry> = self
+            .unfinished
+            .iter()
+            .map(|(&i, mask)| UnfinishedEntry(i, mask.clone()))
+            .collect();
+
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"file-format", FILE_FORMAT)?;
+            e.emit_pair(b"file-version", FILE_VERSION)?;
+            e.emit_pair(b"file_sizes", &file_sizes)?;
+            e.emit_pair(b"info-hash", AsString(&self.info_hash))?;
+            if let Some(name) = &self.name {
+                e.emit_pair(b"name", name)?;
+            }
+            e.emit_pair(b"pieces", AsString(&pieces))?;
+            if let Some(save_path) = &self.save_path {
+                e.emit_pair(b"save_path", save_path)?;
+            }
+            e.emit_pair(b"total_downloaded", self.total_downloaded)?;
+            e.emit_pair(b"total_uploaded", self.total_uploaded)?;
+            e.emit_pair(b"unfinished", &unfinished)
+        })
+    }
+}
+
+impl FromBencode for FastResume {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    /// Only the fields we have a use for are read, libtorrent writes many
+    /// more (queue position, limits, peers...).
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut res = FastResume::default();
+        let mut info_hash = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"file-format", value) => {
+                    let format = String::decode_bencode_object(value).context("file-format")?;
+                    if format != FILE_FORMAT {
+                        return Err(Error::unexpected_token(FILE_FORMAT, format));
+                    }
+                }
+                (b"file_sizes", value) => {
+                    let sizes =
+                        Vec::<Vec<u64>>::decode_bencode_object(value).context("file_sizes")?;
+                    res.file_sizes = sizes
+                        .into_iter()
+                        .map(|s| {
+                            (
+                                s.first().copied().unwrap_or(0),
+                                s.get(1).copied().unwrap_or(0),
+                            )
+                        })
+                        .collect();
+                }
+                (b"info-hash", value) => {
+                    let bytes = value.try_into_bytes().context("info-hash")?;
+                    info_hash = Some(<InfoHash>::try_from(bytes).map_err(|_| {
+                        Error::malformed_content(invalid_data("Invalid info-hash length"))
+                            .context("info-hash")
+                    })?);
+                }
+                (b"name", value) => {
+                    res.name = String::decode_bencode_object(value)
+                        .context("name")
+                        .map(Some)?;
+                }
+                (b"pieces", value) => {
+                    res.pieces = value
+                        .try_into_bytes()
+                        .context("pieces")?
+                        .iter()
+                        .map(|b| b & PIECE_HAVE != 0)
+                        .collect();
+                }
+                (b"save_path", value) => {
+                    res.save_path = String::decode_bencode_object(value)
+                        .context("save_path")
+                        .map(Some)?;
+                }
+                (b"total_downloaded", value) => {
+                    res.total_downloaded =
+                        u64::decode_bencode_object(value).context("total_downloaded")?;
+                }
+                (b"total_uploaded", value) => {
+                    res.total_uploaded =
+                        u64::decode_bencode_object(value).context("total_uploaded")?;
+                }
+                (b"unfinished", value) => {
+                    res.unfinished = Vec::<UnfinishedEntry>::decode_bencode_object(value)
+                        .context("unfinished")?
+                        .into_iter()
+                        .map(|UnfinishedEntry(i, mask)| (i, mask))
+                        .collect();
+                }
+                _ => (),
+            }
+        }
+
+        res.info_hash = info_hash.ok_or_else(|| Error::missing_field("info-hash"))?;
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod fastresume_tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let data = ResumeData {
+            piece_size: 2 * BLOCK_SIZE,
+            file_size: 5 * BLOCK_SIZE as u64,
+            file_mtime: 1_700_000_000,
+            pieces: vec![true, false, false],
+            unfinished: vec![
+                UnfinishedPiece {
+                    index: 1,
+                    blocks: vec![false, true],
+                },
+                UnfinishedPiece {
+                    index: 2,
+                    blocks: vec![true],
+                },
+            ],
+            uploaded: 7,
+            downloaded: 3 * BLOCK_SIZE as u64,
+        };
+
+        let fr = FastResume::from_resume_data(&data, [1; 20], "file.iso");
+        let bytes = fr.to_bencode().unwrap();
+        let decoded = FastResume::from_bencode(&bytes).unwrap();
+        assert_eq!(fr, decoded);
+        assert_eq!(data, decoded.to_resume_data(2 * BLOCK_SIZE));
+    }
+
+    #[test]
+    fn decode_libtorrent() {
+        // Trimmed down file written by qBittorrent
+        let bytes = b"d11:active_timei120e11:file-format22:libtorrent resume file12:file-versioni1e10:file_sizesll\
+i32768ei1650000000eee9:info-hash20:aaaaaaaaaaaaaaaaaaaa18:libtorrent-version6:1.2.196:pieces2:\x01\x009:save_path\
+5:/data16:total_downloadedi16384e14:total_uploadedi0e10:unfinishedld7:bitmask4:\x80\0\0\x005:piecei1eeee";
+
+        let fr = FastResume::from_bencode(bytes).unwrap();
+        assert_eq!([b'a'; 20], fr.info_hash);
+        assert_eq!(Some("/data".to_string()), fr.save_path);
+        assert_eq!(vec![true, false], fr.pieces);
+        assert_eq!(vec![(32768, 1_650_000_000)], fr.file_sizes);
+
+        let data = fr.to_resume_data(BLOCK_SIZE);
+        assert_eq!(1, data.unfinished.len());
+        assert_eq!(vec![true], data.unfinished[0].blocks);
+        assert_eq!(16384, data.downloaded);
+    }
+
+    #[test]
+    fn wrong_format() {
+        let bytes = b"d11:file-format7:unknown9:info-hash20:aaaaaaaaaaaaaaaaaaaae";
+        assert!(FastResume::from_bencode(bytes).is_err());
+    }
+}
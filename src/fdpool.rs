@@ -0,0 +1,135 @@
+// Bounded set of open files. Torrents with thousands of files can't keep all
+// of them open, files are opened on demand and the least recently used ones
+// are closed to stay under the limit.
+use std::{collections::HashMap, fs::File, io, sync::Arc};
+
+pub const DEFAULT_MAX_OPEN_FILES: usize = 512;
+
+#[derive(Debug)]
+pub struct FilePool {
+    max_open: usize,
+    // File and the time it was last used
+    open: HashMap<usize, (Arc<File>, u64)>,
+    clock: u64,
+}
+
+impl Default for FilePool {
+    fn default() -> Self {
+        FilePool::new(DEFAULT_MAX_OPEN_FILES)
+    }
+}
+
+impl FilePool {
+    pub fn new(max_open: usize) -> Self {
+        assert!(max_open > 0);
+        FilePool {
+            max_open,
+            open: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn max_open(&self) -> usize {
+        self.max_open
+    }
+
+    pub fn set_max_open(&mut self, max_open: usize) {
+        assert!(max_open > 0);
+        self.max_open = max_open;
+        while self.open.len() > self.max_open {
+            self.evict();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.open.is_empty()
+    }
+
+    pub fn is_open(&self, key: usize) -> bool {
+        self.open.contains_key(&key)
+    }
+
+    /// Get the file `key`, opening it with `open` if needed. Closing a file
+    /// only drops the pool's reference, I/O still holding the returned handle
+    /// finishes before the descriptor is actually closed.
+    pub fn get_or_open<F>(&mut self, key: usize, open: F) -> io::Result<Arc<File>>
+    where
+        F: FnOnce() -> io::Result<File>,
+    {
+        self.clock += 1;
+        if let Some((file, used)) = self.open.get_mut(&key) {
+            *used = self.clock;
+            return Ok(file.clone());
+        }
+
+        let file = Arc::new(open()?);
+        if self.open.len() >= self.max_open {
+            self.evict();
+        }
+        self.open.insert(key, (file.clone(), self.clock));
+
+        Ok(file)
+    }
+
+    fn evict(&mut self) {
+        let lru = self
+            .open
+            .iter()
+            .min_by_key(|(_, (_, used))| *used)
+            .map(|(&key, _)| key);
+        if let Some(key) = lru {
+            self.open.remove(&key);
+        }
+    }
+
+    pub fn close(&mut self, key: usize) {
+        self.open.remove(&key);
+    }
+
+    pub fn clear(&mut self) {
+        self.open.clear();
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Arc<File>> {
+        self.open.values().map(|(file, _)| file)
+    }
+}
+
+#[cfg(test)]
+mod fdpool_tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn least_recently_used() {
+        const FILE: &str = "./test_fdpool_lru";
+        fs::write(FILE, b"data").unwrap();
+        let open = || File::open(FILE);
+
+        let mut pool = FilePool::new(2);
+        let first = pool.get_or_open(0, open).unwrap();
+        pool.get_or_open(1, open).unwrap();
+        pool.get_or_open(0, open).unwrap();
+        pool.get_or_open(2, open).unwrap();
+
+        assert_eq!(2, pool.len());
+        assert!(pool.is_open(0) && !pool.is_open(1) && pool.is_open(2));
+
+        // A handle in use outlives its eviction
+        pool.set_max_open(1);
+        assert!(!pool.is_open(0));
+        assert!(first.metadata().is_ok());
+
+        // Opening errors aren't cached
+        let res = pool.get_or_open(3, || File::open("./test_fdpool_missing"));
+        assert!(res.is_err());
+        assert!(!pool.is_open(3));
+
+        drop(first);
+        fs::remove_file(FILE).unwrap();
+    }
+}
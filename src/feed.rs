@@ -0,0 +1,607 @@
+// RSS and Atom feeds of torrents polled by the session, the entries matching
+// the filters are added. Feeds and `.torrent` links are fetched over plain
+// HTTP.
+use std::{collections::HashSet, sync::Arc};
+
+use hyper::{body::HttpBody, client::HttpConnector, header::LOCATION, Client, StatusCode, Uri};
+use regex_automata::{meta::Regex, util::syntax};
+use thiserror::Error;
+use tokio::{
+    task::JoinHandle,
+    time::{self, Duration},
+};
+
+use crate::definitions::InfoHash;
+use crate::error;
+use crate::magnet::Magnet;
+use crate::session::Session;
+use crate::tracker::hash_to_bytes;
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MAX_BODY: usize = 16 * 1024 * 1024;
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Error)]
+pub enum FeedError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("HTTP status {0}")]
+    Status(StatusCode),
+    #[error("Unsupported URL {0}")]
+    Unsupported(String),
+    #[error("Response over {} bytes", MAX_BODY)]
+    TooLarge,
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+    #[error(transparent)]
+    Session(#[from] error::Error),
+}
+
+/// An entry of a feed, with what it says about its torrent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeedEntry {
+    pub title: String,
+    // Identifies the entry across polls, its link if the feed has no ID
+    pub guid: String,
+    // Magnet link or URL of the `.torrent`
+    pub url: Option<String>,
+    pub size: Option<u64>,
+    pub info_hash: Option<InfoHash>,
+}
+
+/// Which entries to add. Titles are matched case-insensitively, and sizes
+/// only checked when the feed gives them.
+#[derive(Debug, Clone, Default)]
+pub struct FeedFilter {
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+fn regex(pattern: &str) -> Result<Regex, FeedError> {
+    Regex::builder()
+        .syntax(syntax::Config::new().case_insensitive(true))
+        .build(pattern)
+        .map_err(|e| FeedError::InvalidPattern(e.to_string()))
+}
+
+impl FeedFilter {
+    pub fn new() -> Self {
+        FeedFilter::default()
+    }
+
+    /// Only titles matching `pattern`.
+    pub fn include(mut self, pattern: &str) -> Result<Self, FeedError> {
+        self.include = Some(regex(pattern)?);
+        Ok(self)
+    }
+
+    /// No title matching `pattern`.
+    pub fn exclude(mut self, pattern: &str) -> Result<Self, FeedError> {
+        self.exclude = Some(regex(pattern)?);
+        Ok(self)
+    }
+
+    pub fn matches(&self, entry: &FeedEntry) -> bool {
+        let title = entry.title.as_str();
+        self.include.as_ref().is_none_or(|r| r.is_match(title))
+            && !self.exclude.as_ref().is_some_and(|r| r.is_match(title))
+            && entry.size.is_none_or(|size| {
+                self.min_size.is_none_or(|min| size >= min)
+                    && self.max_size.is_none_or(|max| size <= max)
+            })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedConfig {
+    pub url: String,
+    pub interval: Duration,
+    // Entries matching any of them are added, all of them without filters
+    pub filters: Vec<FeedFilter>,
+    // Added paused instead of waiting for a slot in the queue
+    pub paused: bool,
+}
+
+impl FeedConfig {
+    pub fn new(url: &str) -> Self {
+        FeedConfig {
+            url: url.to_string(),
+            interval: DEFAULT_POLL_INTERVAL,
+            filters: Vec::new(),
+            paused: false,
+        }
+    }
+}
+
+// Element of a document, only what feeds need of XML
+#[derive(Debug)]
+struct Element<'a> {
+    // Without its namespace prefix
+    name: &'a str,
+    attrs: &'a str,
+    inner: &'a str,
+}
+
+impl Element<'_> {
+    fn attr(&self, name: &str) -> Option<String> {
+        let mut rest = self.attrs;
+        while let Some(eq) = rest.find('=') {
+            let key = rest[..eq].trim();
+            let value = rest[eq + 1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote != '"' && quote != '\'' {
+                return None;
+            }
+            let end = value[1..].find(quote)? + 1;
+            if key == name {
+                return Some(unescape(&value[1..end]));
+            }
+            rest = &value[end + 1..];
+        }
+        None
+    }
+
+    fn text(&self) -> String {
+        let text = self.inner.trim();
+        match text
+            .strip_prefix("<![CDATA[")
+            .and_then(|t| t.strip_suffix("]]>"))
+        {
+            Some(cdata) => cdata.trim().to_string(),
+            None => unescape(text),
+        }
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        res.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(|code| code.ok())
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                res.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+// Elements directly in `xml`, the nested ones are in their `inner`
+fn children(xml: &str) -> Vec<Element<'_>> {
+    let mut res = Vec::new();
+    let mut rest = xml;
+    while let Some(open) = rest.find('<') {
+        rest = &rest[open..];
+        if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            rest = after.find("]]>").map_or("", |end| &after[end + 3..]);
+            continue;
+        }
+        if rest.starts_with("<!--") {
+            rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(['?', '!', '/']) {
+            continue;
+        }
+
+        let (tag, closed) = match tag.strip_suffix('/') {
+            Some(tag) => (tag, true),
+            None => (tag, false),
+        };
+        let (full_name, attrs) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let name = full_name.rsplit(':').next().unwrap_or(full_name);
+        let inner = match closed {
+            true => "",
+            false => {
+                let close = format!("</{}>", full_name);
+                let end = rest.find(&close).unwrap_or(rest.len());
+                let inner = &rest[..end];
+                rest = rest.get(end + close.len()..).unwrap_or("");
+                inner
+            }
+        };
+        res.push(Element { name, attrs, inner });
+    }
+    res
+}
+
+// `123`, or `1.5 GiB` as some feeds give it
+fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if let Ok(size) = text.parse() {
+        return Some(size);
+    }
+    let (number, unit) = text.split_once(' ')?;
+    let number: f64 = number.parse().ok()?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "b" | "bytes" => 1u64,
+        "kb" | "kib" => 1 << 10,
+        "mb" | "mib" => 1 << 20,
+        "gb" | "gib" => 1 << 30,
+        "tb" | "tib" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * scale as f64) as u64)
+}
+
+fn parse_entry(item: &Element) -> FeedEntry {
+    let mut res = FeedEntry::default();
+    let (mut link, mut enclosure, mut magnet) = (None, None, None);
+    for child in children(item.inner) {
+        match child.name {
+            "title" => res.title = child.text(),
+            "guid" | "id" => res.guid = child.text(),
+            // Atom links are in attributes, the torrent is an enclosure
+            "link" => match child.attr("href") {
+                Some(href) if child.attr("rel").as_deref() == Some("enclosure") => {
+                    enclosure = Some(href)
+                }
+                Some(href) => link = link.or(Some(href)),
+                None => link = Some(child.text()),
+            },
+            "enclosure" => {
+                enclosure = child.attr("url");
+                res.size = res
+                    .size
+                    .or(child.attr("length").and_then(|l| parse_size(&l)));
+            }
+            "magnetURI" => magnet = Some(child.text()),
+            "infoHash" => res.info_hash = hash_to_bytes(&child.text().to_lowercase()).ok(),
+            "contentLength" | "size" => res.size = parse_size(&child.text()).or(res.size),
+            _ => (),
+        }
+    }
+
+    if res.guid.is_empty() {
+        res.guid = link.clone().unwrap_or_else(|| res.title.clone());
+    }
+    res.url = magnet.or(enclosure).or(link).filter(|url| !url.is_empty());
+    if res.info_hash.is_none() {
+        res.info_hash = res
+            .url
+            .as_deref()
+            .and_then(|url| Magnet::parse(url).ok())
+            .map(|m| m.info_hash);
+    }
+    res
+}
+
+fn find_entries<'a>(xml: &'a str, res: &mut Vec<Element<'a>>) {
+    for element in children(xml) {
+        match element.name {
+            "item" | "entry" => res.push(element),
+            _ => find_entries(element.inner, res),
+        }
+    }
+}
+
+/// Entries of an RSS or Atom document, in their order.
+pub fn parse(xml: &str) -> Vec<FeedEntry> {
+    let mut entries = Vec::new();
+    find_entries(xml, &mut entries);
+    entries.iter().map(parse_entry).collect()
+}
+
+/// A feed polled for a session, which remembers the entries it already saw.
+pub struct Feed {
+    session: Arc<Session>,
+    config: FeedConfig,
+    client: Client<HttpConnector>,
+    seen: HashSet<String>,
+}
+
+impl Feed {
+    pub fn new(session: Arc<Session>, config: FeedConfig) -> Self {
+        Feed {
+            session,
+            config,
+            client: Client::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn config(&self) -> &FeedConfig {
+        &self.config
+    }
+
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, FeedError> {
+        let mut url = url.to_string();
+        for _ in 0..=MAX_REDIRECTS {
+            let uri: Uri = url
+                .parse()
+                .map_err(|_| FeedError::Unsupported(url.clone()))?;
+            if uri.scheme_str() != Some("http") {
+                return Err(FeedError::Unsupported(url));
+            }
+            let mut res = self.client.get(uri).await?;
+            if res.status().is_redirection() {
+                match res.headers().get(LOCATION).and_then(|l| l.to_str().ok()) {
+                    Some(location) => url = location.to_string(),
+                    None => return Err(FeedError::Status(res.status())),
+                }
+                continue;
+            }
+            if !res.status().is_success() {
+                return Err(FeedError::Status(res.status()));
+            }
+
+            let mut body = Vec::new();
+            while let Some(chunk) = res.body_mut().data().await {
+                body.extend_from_slice(&chunk?);
+                if body.len() > MAX_BODY {
+                    return Err(FeedError::TooLarge);
+                }
+            }
+            return Ok(body);
+        }
+        Err(FeedError::Unsupported(url))
+    }
+
+    // Whether the session has the torrent, added from this feed or not
+    fn known(&self, info_hash: Option<InfoHash>) -> bool {
+        info_hash.is_some_and(|hash| self.session.get(&hash).is_some())
+    }
+
+    async fn add(&self, url: &str) -> Result<Option<InfoHash>, FeedError> {
+        let res = match url.starts_with("magnet:") {
+            true => self.session.add_magnet(url).await,
+            false => {
+                self.session
+                    .add_torrent_bytes(&self.fetch(url).await?)
+                    .await
+            }
+        };
+        let torrent = match res {
+            Ok(torrent) => torrent,
+            Err(error::Error::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut t = torrent.lock().await;
+        if self.config.paused {
+            t.pause();
+        }
+        Ok(Some(*t.info_hash()))
+    }
+
+    /// Fetch the feed and add the torrents of the new entries which match
+    /// the filters. Returns the info hashes of those added. An entry whose
+    /// torrent failed to be added is tried again next time.
+    pub async fn poll(&mut self) -> Result<Vec<InfoHash>, FeedError> {
+        let xml = self.fetch(&self.config.url).await?;
+        let mut res = Vec::new();
+        for entry in parse(&String::from_utf8_lossy(&xml)) {
+            if self.seen.contains(&entry.guid) {
+                continue;
+            }
+            let wanted = self.config.filters.is_empty()
+                || self.config.filters.iter().any(|f| f.matches(&entry));
+            let added = match &entry.url {
+                Some(url) if wanted && !self.known(entry.info_hash) => self.add(url).await,
+                _ => Ok(None),
+            };
+            match added {
+                Ok(hash) => {
+                    res.extend(hash);
+                    self.seen.insert(entry.guid);
+                }
+                Err(_) => continue,
+            }
+        }
+        self.session.update_queue().await;
+        Ok(res)
+    }
+
+    /// Poll the feed every `config.interval` until the task is aborted. A
+    /// feed which can't be fetched is tried again at the next interval.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let _ = self.poll().await;
+                time::sleep(self.config.interval).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod feed_tests {
+    use super::*;
+    use crate::decode_torrent::{self, Info, MetaInfo};
+    use crate::session::SessionConfig;
+    use crate::torrent::TorrentState;
+    use bendy::encoding::ToBencode;
+    use std::fs;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const RSS: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:torrent="http://xmlns.ezrss.it/0.1/">
+  <channel>
+    <title>Releases</title>
+    <item>
+      <title><![CDATA[Distro 1.0 <x86_64>]]></title>
+      <guid>release-1</guid>
+      <enclosure url="http://example.org/a.torrent?id=1&amp;k=2" length="2048" type="application/x-bittorrent"/>
+    </item>
+    <item>
+      <title>Distro 1.0 source &amp; docs</title>
+      <link>magnet:?xt=urn:btih:5252525252525252525252525252525252525252</link>
+      <torrent:contentLength>1.5 GiB</torrent:contentLength>
+    </item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Releases</title>
+  <entry>
+    <title>Other 2.0</title>
+    <id>urn:other-2</id>
+    <link rel="alternate" href="http://example.org/other"/>
+    <link rel="enclosure" href="http://example.org/other.torrent" length="10"/>
+  </entry>
+</feed>"#;
+
+    #[test]
+    fn parse_feeds() {
+        let entries = parse(RSS);
+        assert_eq!(2, entries.len());
+        assert_eq!("Distro 1.0 <x86_64>", entries[0].title);
+        assert_eq!("release-1", entries[0].guid);
+        assert_eq!(
+            Some("http://example.org/a.torrent?id=1&k=2"),
+            entries[0].url.as_deref()
+        );
+        assert_eq!(Some(2048), entries[0].size);
+        assert_eq!("Distro 1.0 source & docs", entries[1].title);
+        assert_eq!(Some([0x52; 20]), entries[1].info_hash);
+        assert_eq!(Some(3 << 29), entries[1].size);
+
+        let entries = parse(ATOM);
+        assert_eq!("urn:other-2", entries[0].guid);
+        assert_eq!(
+            Some("http://example.org/other.torrent"),
+            entries[0].url.as_deref()
+        );
+
+        let mut filter = FeedFilter::new()
+            .include("^distro")
+            .unwrap()
+            .exclude("source")
+            .unwrap();
+        filter.max_size = Some(1 << 20);
+        let entries = parse(RSS);
+        assert!(filter.matches(&entries[0]));
+        assert!(!filter.matches(&entries[1]));
+        filter.min_size = Some(4096);
+        assert!(!filter.matches(&entries[0]));
+        assert!(FeedFilter::new().include("(").is_err());
+    }
+
+    // Answer every request with the document of its path
+    async fn serve(listener: TcpListener, documents: Vec<(&'static str, Vec<u8>)>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).to_string();
+            let path = request.split(' ').nth(1).unwrap_or("");
+            let body = documents
+                .iter()
+                .find(|(p, _)| *p == path)
+                .map(|(_, body)| body.clone());
+            let head = match &body {
+                Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()),
+                None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+            };
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body.unwrap_or_default()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn add_matching_entries() {
+        const DIR: &str = "./test_feed_add_matching_entries";
+        fs::create_dir_all(DIR).unwrap();
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .build()
+            .unwrap();
+        let session = Arc::new(Session::new(config).await.unwrap());
+
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: "wanted".to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let torrent = meta.to_bencode().unwrap();
+        let hash = decode_torrent::get_info_hash(&torrent).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let xml = format!(
+            "<rss><channel>\
+             <item><title>Wanted</title><guid>1</guid>\
+             <enclosure url=\"http://{addr}/wanted.torrent\"/></item>\
+             <item><title>Unwanted</title><guid>2</guid>\
+             <enclosure url=\"http://{addr}/unwanted.torrent\"/></item>\
+             <item><title>Wanted too</title><guid>3</guid>\
+             <enclosure url=\"http://{addr}/missing.torrent\"/></item>\
+             </channel></rss>"
+        );
+        let server = tokio::spawn(serve(
+            listener,
+            vec![("/feed", xml.into_bytes()), ("/wanted.torrent", torrent)],
+        ));
+
+        let mut config = FeedConfig::new(&format!("http://{}/feed", addr));
+        config.filters = vec![FeedFilter::new().include("^wanted").unwrap()];
+        config.paused = true;
+        let mut feed = Feed::new(session.clone(), config);
+        assert_eq!(vec![hash], feed.poll().await.unwrap());
+        assert_eq!(
+            TorrentState::Paused,
+            session.get(&hash).unwrap().lock().await.state()
+        );
+        // The torrent which couldn't be fetched is tried again
+        assert!(feed.poll().await.unwrap().is_empty());
+        assert!(!feed.seen.contains("3"));
+        assert!(feed.seen.contains("2"));
+
+        let mut feed = Feed::new(session, FeedConfig::new("https://example.org/feed"));
+        assert!(matches!(feed.poll().await, Err(FeedError::Unsupported(_))));
+        server.abort();
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
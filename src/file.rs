@@ -1,267 +1,2111 @@
 use std::{
+    collections::HashMap,
     fs::{self, File},
     io,
-    io::Error,
-    os::{raw::c_int, unix::fs::MetadataExt, unix::prelude::AsRawFd},
-    path::Path,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::definitions::InfoHash;
-
-use rio::Rio;
+use crate::backend::{IoBackend, WriteBatch};
+use crate::cache::{PieceCache, DEFAULT_CACHE_SIZE};
+use crate::decode_torrent::Info;
+use crate::definitions::{InfoHash, BLOCK_SIZE};
+use crate::direct::{self, is_aligned};
+use crate::hash::{HashPool, PieceHash, PieceHasher};
+use crate::journal::{self, PieceJournal, PieceState};
+use crate::layout::Layout;
+use crate::recheck::RecheckHandle;
+use crate::resume::{self, ResumeData, UnfinishedPiece};
+use crate::sink::PieceSink;
+use crate::stats::DiskStats;
+use crate::storage::{
+    self, MultiFileStorage, StorageDirs, StorageError, StorageResult, TorrentStorage,
+};
 
+use bytes::Bytes;
 use sha1::{Digest, Sha1};
+use tokio::task::JoinHandle;
 
-use tokio::{net::TcpStream, sync::Mutex};
+pub const DEFAULT_READ_AHEAD: usize = 2;
+
+/// Storage of a torrent shared by its peers.
+pub type SharedFile = Arc<tokio::sync::Mutex<Storage>>;
 
 #[derive(Debug)]
 pub struct Piece {
+    #[allow(dead_code)]
     piece_size: usize,
-    ring: Arc<Mutex<Rio>>,
-    pub bytes: Vec<u8>,
+    backend: IoBackend,
+    // Used instead of the regular handle when the piece is aligned
+    direct: Option<Arc<File>>,
+    // Shared with the blocks being sent, which are slices of it
+    pub bytes: Bytes,
+    // Blocks modified in memory since the piece was last read or written
+    dirty: Vec<bool>,
+    // Blocks received from peers, the piece can't be trusted until verified
+    received: Vec<bool>,
+}
+
+/// How the space of a new file is reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Allocation {
+    // Only set the file length, blocks are allocated as they are written
+    Sparse,
+    // Reserve the blocks without writing them, falls back to `Full` when the
+    // filesystem doesn't support it
+    #[default]
+    Fallocate,
+    // Write zeros over the whole file
+    Full,
+}
+
+/// When data kept in memory is written out and synced to the disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FlushPolicy {
+    // fsync as soon as a verified piece is written
+    pub on_verify: bool,
+    // Flush once this many bytes were received or written since the last sync
+    pub every_bytes: Option<usize>,
+    // Flush when this much time passed since the last sync, checked on write
+    // and by `FileEntity::tick`
+    pub interval: Option<Duration>,
+    // Flush in `FileEntity::shutdown`
+    pub on_shutdown: bool,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            on_verify: false,
+            every_bytes: None,
+            interval: None,
+            on_shutdown: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct StorageConfig {
+    // Maximum number of bytes of pieces kept in memory
+    pub cache_size: usize,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub backend: IoBackend,
+    pub allocation: Allocation,
+    pub flush: FlushPolicy,
+    // Pieces prefetched after sequential reads, 0 to disable
+    pub read_ahead: usize,
+    // Bypass the page cache with O_DIRECT where supported, aligned pieces
+    // only, the rest still goes through the page cache
+    pub direct_io: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hasher: HashPool,
+    // Start over when the file already exists instead of resuming from it
+    pub truncate_existing: bool,
+    // Keep a journal of the pieces written next to the file, so that after a
+    // crash `FileEntity::recover` only hashes the pieces being written
+    pub journal: bool,
+    // Verified pieces are also, or only, delivered there
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub sink: Option<PieceSink>,
+    // Rechecks read pieces 16 KiB at a time instead of whole, for large
+    // pieces under a tight memory budget
+    pub chunked_hashing: bool,
 }
 
 #[derive(Debug)]
 pub struct FileEntity {
-    file: File,
-    ring: Arc<Mutex<Rio>>,
+    path: PathBuf,
+    file: Arc<File>,
+    // Same file opened for direct I/O
+    direct: Option<Arc<File>>,
+    // Pieces which passed hash verification and are on disk
+    verified: Vec<bool>,
+    backend: IoBackend,
     piece_size: usize,
-    pieces: Vec<Option<Piece>>,
+    size: usize,
+    num_pieces: usize,
+    pieces: PieceCache,
+    // The file was already on disk and its content must be checked
+    existing: bool,
+    flush: FlushPolicy,
+    // Bytes received or written since the last sync
+    unsynced: usize,
+    last_sync: Instant,
+    read_ahead: usize,
+    // Last piece read with `read_block`, to detect sequential access
+    last_read: Option<usize>,
+    prefetch: HashMap<usize, JoinHandle<StorageResult<Piece>>>,
+    hasher: HashPool,
+    journal: Option<PieceJournal>,
+    sink: Option<PieceSink>,
+    chunked_hashing: bool,
+    disk_stats: Arc<DiskStats>,
 }
 
 impl Piece {
-    pub fn new(piece_size: usize, actual_size: usize, ring: Arc<Mutex<Rio>>) -> Self {
+    pub fn new(piece_size: usize, actual_size: usize, backend: IoBackend) -> Self {
         Piece {
             piece_size,
-            ring,
-            bytes: vec![0u8; actual_size],
+            backend,
+            direct: None,
+            bytes: Bytes::from(vec![0u8; actual_size]),
+            dirty: vec![false; actual_size.div_ceil(BLOCK_SIZE)],
+            received: vec![false; actual_size.div_ceil(BLOCK_SIZE)],
         }
     }
 
-    pub async fn read(&self, file: &File, offset: usize) -> io::Result<()> {
-        let bytes_read = self
-            .ring
-            .lock()
-            .await
-            .read_at(file, &self.bytes, offset as u64)
-            .await?;
-        assert!(bytes_read == self.bytes.len());
+    /// A clean piece whose content was read elsewhere.
+    pub fn loaded(piece_size: usize, bytes: Bytes, backend: IoBackend) -> Self {
+        let blocks = bytes.len().div_ceil(BLOCK_SIZE);
+        Piece {
+            piece_size,
+            backend,
+            direct: None,
+            bytes,
+            dirty: vec![false; blocks],
+            received: vec![false; blocks],
+        }
+    }
+
+    /// Read and write through a handle opened for direct I/O whenever the
+    /// piece is aligned for it.
+    pub fn with_direct(mut self, direct: Option<Arc<File>>) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.iter().any(|&d| d)
+    }
+
+    /// Bytes of the blocks which aren't on disk yet.
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty
+            .iter()
+            .enumerate()
+            .filter(|(_, &d)| d)
+            .map(|(block, _)| BLOCK_SIZE.min(self.bytes.len() - block * BLOCK_SIZE))
+            .sum()
+    }
+
+    fn mark_clean(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = false);
+    }
+
+    /// Some blocks were downloaded but the piece wasn't verified yet. Such a
+    /// piece must stay in memory until it is either committed or discarded.
+    pub fn is_downloading(&self) -> bool {
+        self.received.iter().any(|&r| r)
+    }
+
+    /// Every block of the piece was received.
+    pub fn is_complete(&self) -> bool {
+        self.received.iter().all(|&r| r)
+    }
+
+    /// Store a block received from a peer.
+    pub fn add_block(&mut self, offset: usize, data: &[u8]) -> StorageResult<()> {
+        self.update(offset, data)?;
+        if data.is_empty() {
+            return Ok(());
+        }
 
+        for block in offset / BLOCK_SIZE..=(offset + data.len() - 1) / BLOCK_SIZE {
+            self.received[block] = true;
+        }
         Ok(())
     }
 
-    pub fn update(&mut self, offset: usize, data: &[u8]) {
-        assert!(offset + data.len() <= self.bytes.len());
-        self.bytes[offset..offset + data.len()].copy_from_slice(data);
+    // The buffer can only be modified when it isn't shared, blocks still
+    // being sent get a copy
+    fn take_buf(&mut self) -> Vec<u8> {
+        Vec::from(std::mem::take(&mut self.bytes))
     }
 
-    pub async fn write(&mut self, file: &File, offset: usize) -> io::Result<()> {
-        let bytes_wrote = self
-            .ring
-            .lock()
+    pub async fn read(&mut self, file: &Arc<File>, offset: usize) -> StorageResult<()> {
+        let mut buf = self.take_buf();
+        let len = buf.len();
+        let res = match self.direct_handle(offset, len) {
+            Some(direct) => tokio::task::spawn_blocking(move || {
+                let res = direct::pread_direct(&direct, &mut buf, offset as u64);
+                (buf, res)
+            })
             .await
-            .write_at(file, &self.bytes, offset as u64)
-            .await?;
-        assert!(bytes_wrote == self.bytes.len());
+            .unwrap_or_else(|e| (vec![0; len], Err(io::Error::other(e)))),
+            None => {
+                let res = self.backend.read_at(file, &mut buf, offset as u64).await;
+                (buf, res)
+            }
+        };
+        self.bytes = Bytes::from(res.0);
+
+        let bytes_read = res.1?;
+        if bytes_read != self.bytes.len() {
+            return Err(StorageError::ShortRead {
+                expected: self.bytes.len(),
+                read: bytes_read,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, offset: usize, data: &[u8]) -> StorageResult<()> {
+        if offset
+            .checked_add(data.len())
+            .is_none_or(|end| end > self.bytes.len())
+        {
+            return Err(StorageError::OutOfRange);
+        }
+        let mut buf = self.take_buf();
+        buf[offset..offset + data.len()].copy_from_slice(data);
+        self.bytes = Bytes::from(buf);
+        if !data.is_empty() {
+            let blocks = offset / BLOCK_SIZE..=(offset + data.len() - 1) / BLOCK_SIZE;
+            self.dirty[blocks].iter_mut().for_each(|d| *d = true);
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, file: &Arc<File>, offset: usize) -> StorageResult<()> {
+        let mut buf = self.take_buf();
+        let res = match self.direct_handle(offset, buf.len()) {
+            Some(direct) => write_direct(direct, &mut buf, offset as u64).await,
+            None => self.backend.write_at(file, &mut buf, offset as u64).await,
+        };
+        self.bytes = Bytes::from(buf);
+
+        let bytes_wrote = res?;
+        if bytes_wrote != self.bytes.len() {
+            return Err(StorageError::ShortWrite {
+                expected: self.bytes.len(),
+                written: bytes_wrote,
+            });
+        }
+        self.mark_clean();
 
         Ok(())
     }
 
+    fn direct_handle(&self, offset: usize, len: usize) -> Option<Arc<File>> {
+        self.direct
+            .clone()
+            .filter(|_| is_aligned(offset as u64, len))
+    }
+
     pub fn hash(&self) -> InfoHash {
         let mut hasher = Sha1::new();
         hasher.update(&self.bytes);
-        hasher.finalize().try_into().unwrap()
+        hasher.finalize().into()
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            cache_size: DEFAULT_CACHE_SIZE,
+            backend: IoBackend::default(),
+            allocation: Allocation::default(),
+            flush: FlushPolicy::default(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            direct_io: false,
+            hasher: HashPool::default(),
+            truncate_existing: false,
+            journal: false,
+            sink: None,
+            chunked_hashing: false,
+        }
     }
 }
 
 impl FileEntity {
-    pub fn new<F: AsRef<Path>>(file: F, piece_size: usize, size: usize) -> io::Result<Self> {
-        let meta = fs::metadata(&file);
+    pub fn new<F: AsRef<Path>>(file: F, piece_size: usize, size: usize) -> StorageResult<Self> {
+        FileEntity::with_config(file, piece_size, size, StorageConfig::default())
+    }
+
+    pub fn with_config<F: AsRef<Path>>(
+        file: F,
+        piece_size: usize,
+        size: usize,
+        config: StorageConfig,
+    ) -> StorageResult<Self> {
+        // Checked before anything is created, the piece count divides by it
+        if piece_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Piece size of 0").into());
+        }
+        let path = file.as_ref().to_owned();
+        let mut meta = fs::metadata(&file);
+        if let Ok(m) = &meta {
+            if !m.is_file() {
+                return Err(StorageError::NotAFile);
+            }
+            if config.truncate_existing {
+                fs::remove_file(&path)?;
+                meta = Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        let existing = meta.is_ok();
 
+        // An existing file of the right size is resumed from, after a recheck
         let file = match meta {
             Ok(m) => {
-                if m.is_file() && m.size() as usize != size {
-                    return Err(Error::new(
-                        io::ErrorKind::AlreadyExists,
-                        "File already exist",
-                    ));
+                if m.len() != size as u64 {
+                    return Err(StorageError::SizeMismatch {
+                        expected: size as u64,
+                        actual: m.len(),
+                    });
                 }
                 fs::OpenOptions::new().read(true).write(true).open(file)?
             }
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => fallocate(file, size)?,
-            Err(e) => return Err(e),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                storage::check_space(&path, size as u64)?;
+                allocate(file, size, config.allocation)?
+            }
+            Err(e) => return Err(e.into()),
         };
 
-        let pieces = if size % piece_size == 0 {
-            size / piece_size
-        } else {
-            size / piece_size + 1
+        let direct = match config.direct_io {
+            true => direct::open_direct(&path)?.map(Arc::new),
+            false => None,
+        };
+
+        let pieces = size.div_ceil(piece_size);
+
+        let journal_path = journal::journal_path(&path);
+        let journal = match (config.journal, existing) {
+            (true, true) => Some(PieceJournal::open(journal_path, pieces)?),
+            (true, false) => Some(PieceJournal::create(journal_path, pieces)?),
+            (false, _) => None,
         };
 
         Ok(FileEntity {
-            file,
-            ring: Arc::new(Mutex::new(rio::new()?)),
+            path,
+            file: Arc::new(file),
+            direct,
+            backend: config.backend,
             piece_size,
-            pieces: std::iter::repeat_with(|| None).take(pieces).collect(),
+            size,
+            num_pieces: pieces,
+            verified: vec![false; pieces],
+            pieces: PieceCache::new(config.cache_size),
+            existing,
+            flush: config.flush,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            read_ahead: config.read_ahead,
+            last_read: None,
+            prefetch: HashMap::new(),
+            hasher: config.hasher,
+            journal,
+            sink: config.sink,
+            chunked_hashing: config.chunked_hashing,
+            disk_stats: Arc::default(),
         })
     }
 
-    pub async fn load_piece(&mut self, index: usize) -> io::Result<()> {
-        if self.pieces[index].is_some() {
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the file, and its journal, to `path`. It is opened again there.
+    pub fn rename<P: AsRef<Path>>(&mut self, path: P) -> StorageResult<()> {
+        let path = path.as_ref().to_owned();
+        if path == self.path {
             return Ok(());
         }
+        if path.exists() {
+            return Err(StorageError::AlreadyExists);
+        }
+        storage::move_file(&self.path, &path)?;
+        if let Some(journal) = &mut self.journal {
+            if let Err(e) = journal.move_to(journal::journal_path(&path)) {
+                let _ = storage::move_file(&path, &self.path);
+                return Err(e.into());
+            }
+        }
 
-        // TODO: Handle the case of the last piece
-        let piece = Piece::new(self.piece_size, self.piece_size, self.ring.clone());
-        piece.read(&self.file, index * self.piece_size).await?;
-        self.pieces[index] = Some(piece);
-
+        self.file = Arc::new(fs::OpenOptions::new().read(true).write(true).open(&path)?);
+        if self.direct.is_some() {
+            self.direct = direct::open_direct(&path)?.map(Arc::new);
+        }
+        self.path = path;
         Ok(())
     }
 
-    pub fn sub_piece(&self, index: usize, offset: usize, length: usize) -> Vec<u8> {
-        if let Some(p) = &self.pieces[index] {
-            p.bytes[offset..offset + length].try_into().unwrap()
-        } else {
-            // TODO: change panic to error
-            panic!("Block at index: {} not loaded", index);
+    pub fn piece_size(&self) -> usize {
+        self.piece_size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Direct I/O was requested and the filesystem accepted it.
+    pub fn is_direct(&self) -> bool {
+        self.direct.is_some()
+    }
+
+    fn new_piece(&self, index: usize) -> StorageResult<Piece> {
+        Ok(Piece::new(
+            self.piece_size,
+            self.piece_len(index)?,
+            self.backend.clone(),
+        )
+        .with_direct(self.direct.clone()))
+    }
+
+    /// Time taken by the reads and writes of pieces, rechecks aside.
+    pub fn disk_stats(&self) -> &Arc<DiskStats> {
+        &self.disk_stats
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> StorageResult<()> {
+        let start = Instant::now();
+        let res = self.write_batch_to_disk(batch).await;
+        self.disk_stats.record_write(start.elapsed());
+        res
+    }
+
+    async fn write_batch_to_disk(&self, batch: WriteBatch) -> StorageResult<()> {
+        let Some(direct) = &self.direct else {
+            return Ok(self.backend.write_batch(&self.file, batch).await?);
+        };
+
+        for (offset, mut buf) in batch.into_ops() {
+            let written = match is_aligned(offset, buf.len()) {
+                true => write_direct(direct.clone(), &mut buf, offset).await?,
+                false => self.backend.write_at(&self.file, &mut buf, offset).await?,
+            };
+            if written != buf.len() {
+                return Err(StorageError::ShortWrite {
+                    expected: buf.len(),
+                    written,
+                });
+            }
         }
+        Ok(())
     }
 
-    pub async fn write_sub_piece(
+    /// The file existed before it was opened, [`FileEntity::recheck`] should
+    /// be run to find out which pieces it already holds.
+    pub fn needs_recheck(&self) -> bool {
+        self.existing
+    }
+
+    /// Hash every piece on disk against `hashes` and rebuild the verified
+    /// bitfield from scratch. Pieces are read one by one without going
+    /// through the cache. Returns the number of valid pieces.
+    pub async fn recheck(&mut self, hashes: &[PieceHash]) -> StorageResult<usize> {
+        let handle = RecheckHandle::unlimited(self.num_pieces);
+        self.recheck_with(hashes, &handle).await
+    }
+
+    /// [`FileEntity::recheck`] scheduled by the [`RecheckScheduler`] of
+    /// `handle`: it waits for its turn, reads no faster than allowed and
+    /// stops while paused. Progress is reported on `handle`.
+    ///
+    /// [`RecheckScheduler`]: crate::recheck::RecheckScheduler
+    pub async fn recheck_with(
         &mut self,
-        index: usize,
-        offset: usize,
-        buf: &[u8],
-    ) -> io::Result<()> {
-        if self.pieces[index].is_none() {
-            self.load_piece(index).await?;
+        hashes: &[PieceHash],
+        handle: &RecheckHandle,
+    ) -> StorageResult<usize> {
+        self.check_hashes(hashes)?;
+        let _slot = handle.start().await;
+
+        let mut valid = 0;
+        for (index, expected) in hashes.iter().enumerate() {
+            handle.throttle(self.piece_len(index)?).await;
+            self.verified[index] = self.check_on_disk(index, expected).await?;
+            handle.record(self.verified[index]);
+            if self.verified[index] {
+                valid += 1;
+            }
         }
+        self.existing = false;
 
-        let p = self.pieces[index].as_mut().unwrap();
-        for (x, &y) in p.bytes[offset..offset + buf.len()]
-            .iter_mut()
-            .zip(buf.iter())
-        {
-            *x = y;
+        Ok(valid)
+    }
+
+    /// Like [`FileEntity::recheck`], but trusting the journal: committed
+    /// pieces are valid without being read, and only pieces which may have
+    /// been written since are hashed. Without a journal, or with one started
+    /// after the file, everything is rechecked.
+    pub async fn recover(&mut self, hashes: &[PieceHash]) -> StorageResult<usize> {
+        self.check_hashes(hashes)?;
+        let states = match &self.journal {
+            Some(journal) if !journal.is_new() => journal.states().to_vec(),
+            _ => return self.recheck(hashes).await,
+        };
+
+        let mut valid = 0;
+        for (index, state) in states.into_iter().enumerate() {
+            self.verified[index] = match state {
+                PieceState::Untouched => false,
+                PieceState::Committed => true,
+                PieceState::Uncertain => {
+                    let matches = self.check_on_disk(index, &hashes[index]).await?;
+                    if matches {
+                        // Valid and on disk, the next sync commits it
+                        self.journal.as_mut().unwrap().verified(index);
+                    }
+                    matches
+                }
+            };
+            if self.verified[index] {
+                valid += 1;
+            }
+        }
+        self.existing = false;
+
+        Ok(valid)
+    }
+
+    // Hash a piece as it is in the file, bypassing the cache
+    async fn check_on_disk(&self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        let offset = index * self.piece_size;
+        if !self.chunked_hashing {
+            let mut piece = self.new_piece(index)?;
+            piece.read(&self.file, offset).await?;
+            let (_, matches) = self
+                .hasher
+                .matches(*expected, piece.bytes.into(), self.piece_size)
+                .await?;
+            return Ok(matches);
+        }
+
+        let mut hasher = PieceHasher::new(expected.kind(), self.piece_size);
+        let mut buf = Vec::with_capacity(BLOCK_SIZE);
+        let len = self.piece_len(index)?;
+        let mut pos = 0;
+        while pos < len {
+            buf.resize(BLOCK_SIZE.min(len - pos), 0);
+            let read = self
+                .backend
+                .read_at(&self.file, &mut buf, (offset + pos) as u64)
+                .await?;
+            if read != buf.len() {
+                return Err(StorageError::ShortRead {
+                    expected: buf.len(),
+                    read,
+                });
+            }
+            hasher.update(&buf);
+            pos += read;
         }
 
+        Ok(hasher.matches(expected))
+    }
+
+    // Record the pieces about to be written in the journal
+    fn journal_intent<I: IntoIterator<Item = usize>>(&mut self, pieces: I) -> StorageResult<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.intent(pieces)?;
+        }
         Ok(())
     }
-}
 
-// TODO: handle failed allocation
-fn fallocate<S: AsRef<Path>>(file: S, size: usize) -> io::Result<File> {
-    let file = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create_new(true)
-        .open(file)?;
+    /// Snapshot of the storage state. Blocks of unfinished pieces are written
+    /// to disk first so that they can be picked up after a restart.
+    pub async fn resume_data(&mut self) -> StorageResult<ResumeData> {
+        let mut unfinished = Vec::new();
+        for index in 0..self.num_pieces {
+            let offset = index * self.piece_size;
+            if self
+                .pieces
+                .peek(index)
+                .is_some_and(|p| p.is_downloading() && p.is_dirty())
+            {
+                self.journal_intent([index])?;
+            }
+            if let Some(p) = self.pieces.get_mut(index).filter(|p| p.is_downloading()) {
+                if p.is_dirty() {
+                    let start = Instant::now();
+                    p.write(&self.file, offset).await?;
+                    self.disk_stats.record_write(start.elapsed());
+                }
+                unfinished.push(UnfinishedPiece {
+                    index,
+                    blocks: p.received.clone(),
+                });
+            }
+        }
 
-    let fd = file.as_raw_fd();
-    let mode: c_int = 0;
-    let offset: libc::off_t = 0;
-    let len: libc::off_t = size as i64;
-    unsafe {
-        libc::fallocate(fd, mode, offset, len);
+        Ok(ResumeData {
+            piece_size: self.piece_size,
+            file_size: self.size as u64,
+            file_mtime: resume::file_mtime(&self.file)?,
+            pieces: self.verified.clone(),
+            unfinished,
+            ..Default::default()
+        })
     }
 
-    Ok(file)
-}
+    /// Restore the state saved by [`FileEntity::resume_data`]. Nothing is
+    /// applied and `false` is returned when the file changed in between, a
+    /// full recheck is needed then.
+    pub async fn apply_resume(&mut self, data: &ResumeData) -> StorageResult<bool> {
+        if !data.matches(&self.file, self.piece_size, self.size)? {
+            return Ok(false);
+        }
 
-#[cfg(test)]
-mod file_tests {
-    use super::*;
+        self.verified.clone_from(&data.pieces);
+        for unfinished in &data.unfinished {
+            if unfinished.index >= self.num_pieces || self.verified[unfinished.index] {
+                continue;
+            }
+            self.load_piece(unfinished.index).await?;
+            let piece = self
+                .pieces
+                .get_mut(unfinished.index)
+                .ok_or(StorageError::NotLoaded(unfinished.index))?;
+            if piece.received.len() == unfinished.blocks.len() {
+                piece.received.clone_from(&unfinished.blocks);
+            }
+        }
+        self.existing = false;
 
-    #[test]
-    fn allocate_file() {
-        const SIZE_10M: usize = 10 * 1024 * 1024;
-        const FILE: &str = "./test_allocate_file";
+        Ok(true)
+    }
 
-        assert!(fallocate(FILE, SIZE_10M).is_ok());
+    pub fn cache(&self) -> &PieceCache {
+        &self.pieces
+    }
 
-        let path = Path::new(FILE);
-        assert!(path.exists());
-        assert!(path.is_file());
+    pub fn is_verified(&self, index: usize) -> bool {
+        self.verified[index]
+    }
 
-        let meta = fs::metadata(FILE).unwrap();
-        assert_eq!(SIZE_10M, meta.size() as usize);
+    pub fn get_bitfield(&self) -> &Vec<bool> {
+        &self.verified
+    }
 
-        fs::remove_file(FILE).unwrap();
+    /// All the blocks of the piece were written with
+    /// [`FileEntity::write_sub_piece`] and it can be committed.
+    pub fn is_piece_complete(&self, index: usize) -> bool {
+        self.pieces.peek(index).is_some_and(Piece::is_complete)
     }
 
-    #[test]
-    fn create_new_file() {
-        const FILE: &str = "./non_existing";
-        const PSIZE: usize = 256;
-        const FSIZE: usize = 1024;
+    /// Check a complete piece against its expected hash. On success the
+    /// piece is written to disk and marked as verified, otherwise its blocks
+    /// are discarded so they can be downloaded again. Returns whether the
+    /// piece was valid.
+    pub async fn commit_piece(
+        &mut self,
+        index: usize,
+        expected: &PieceHash,
+    ) -> StorageResult<bool> {
+        let valid = match self.pieces.get_mut(index) {
+            Some(piece) => {
+                let len = piece.bytes.len();
+                let data = piece.take_buf();
+                let res = self.hasher.matches(*expected, data, self.piece_size).await;
+                // The piece can't have been evicted, `self` is borrowed
+                let piece = self.pieces.get_mut(index).unwrap();
+                match res {
+                    Ok((data, valid)) => {
+                        piece.bytes = Bytes::from(data);
+                        valid
+                    }
+                    Err(e) => {
+                        // The data is lost, keep the cache accounting right
+                        piece.bytes = Bytes::from(vec![0; len]);
+                        self.pieces.remove(index);
+                        return Err(e.into());
+                    }
+                }
+            }
+            None => false,
+        };
+        if !valid {
+            self.pieces.remove(index);
+            return Ok(false);
+        }
 
-        let fe = FileEntity::new(FILE, PSIZE, FSIZE);
-        assert!(fe.is_ok());
+        if let Some(sink) = self.sink.clone() {
+            let data = self.pieces.peek(index).unwrap().bytes.clone();
+            sink.deliver(index, data).await?;
+            if !sink.keeps_on_disk() {
+                self.pieces.remove(index);
+                self.verified[index] = true;
+                return Ok(true);
+            }
+        }
 
-        let fe = fe.unwrap();
-        assert_eq!(fe.piece_size, PSIZE);
-        assert_eq!(fe.pieces.len(), FSIZE / PSIZE);
+        self.journal_intent([index])?;
+        let offset = index * self.piece_size;
+        let piece = self.pieces.get_mut(index).unwrap();
+        let start = Instant::now();
+        piece.write(&self.file, offset).await?;
+        self.disk_stats.record_write(start.elapsed());
+        piece.received.iter_mut().for_each(|r| *r = false);
+        self.verified[index] = true;
+        if let Some(journal) = &mut self.journal {
+            journal.verified(index);
+        }
 
-        drop(fe);
-        fs::remove_file(FILE).unwrap();
+        if self.flush.on_verify {
+            self.sync().await?;
+        } else {
+            self.tick().await?;
+        }
+
+        Ok(true)
     }
 
-    #[test]
-    fn file_already_exist() {
-        let fe = FileEntity::new("./Cargo.toml", 0, 0);
-        assert!(fe.is_err());
-        if let Err(e) = fe {
-            assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
-        } else {
-            panic!();
+    /// Bytes in memory which aren't written to the file yet.
+    pub fn dirty_bytes(&self) -> usize {
+        self.pieces.dirty_bytes()
+    }
+
+    /// Bytes received or written since the data was last synced to disk.
+    pub fn unsynced(&self) -> usize {
+        self.unsynced
+    }
+
+    /// fsync the file, data already written is then safe from a crash.
+    pub async fn sync(&mut self) -> StorageResult<()> {
+        self.backend.sync(&self.file).await?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        if let Some(journal) = &mut self.journal {
+            journal.commit_pending()?;
         }
+
+        Ok(())
     }
 
-    #[test]
-    fn file_not_allowed() {
-        let fe = FileEntity::new("/root/haxxor", 1024, 1024);
-        assert!(fe.is_err());
-        if let Err(e) = fe {
-            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    /// Write every dirty piece, including unverified ones, and sync.
+    pub async fn flush(&mut self) -> StorageResult<()> {
+        let dirty = self.pieces.dirty();
+        self.journal_intent(dirty.iter().copied())?;
+        let mut batch = WriteBatch::new();
+        for index in dirty {
+            let offset = index * self.piece_size;
+            let piece = self.pieces.get_mut(index).unwrap();
+            batch.push(offset as u64, piece.bytes.to_vec());
+            piece.mark_clean();
+        }
+        if !batch.is_empty() {
+            self.write_batch(batch).await?;
+        }
+
+        self.sync().await
+    }
+
+    /// Flush if the policy says so. Should also be called periodically when
+    /// `FlushPolicy::interval` is set.
+    pub async fn tick(&mut self) -> StorageResult<()> {
+        let by_size = self.flush.every_bytes.is_some_and(|n| self.unsynced >= n);
+        let by_time = self
+            .flush
+            .interval
+            .is_some_and(|i| self.unsynced > 0 && self.last_sync.elapsed() >= i);
+
+        if by_size || by_time {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// To be called before dropping the storage.
+    pub async fn shutdown(&mut self) -> StorageResult<()> {
+        if self.flush.on_shutdown {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Actual length of the piece at `index`, only the last piece may be
+    /// shorter than the nominal piece size.
+    pub fn piece_len(&self, index: usize) -> StorageResult<usize> {
+        if index >= self.num_pieces {
+            return Err(StorageError::OutOfRange);
+        }
+        if index == self.num_pieces - 1 {
+            Ok(self.size - index * self.piece_size)
         } else {
-            panic!();
+            Ok(self.piece_size)
         }
     }
 
-    #[tokio::test]
-    async fn read_local_torrent() {
-        const TORRENT: &str = "./tests/torrent_files/test_local.torrent";
-        let fread = fs::read(TORRENT).unwrap();
-        let size = fs::metadata(TORRENT).unwrap().size();
-        let file = fs::OpenOptions::new().read(true).open(TORRENT).unwrap();
+    pub async fn load_piece(&mut self, index: usize) -> StorageResult<()> {
+        if self.pieces.contains(index) {
+            self.pieces.touch(index);
+            return Ok(());
+        }
 
-        let piece = Piece::new(
-            size as usize,
-            size as usize,
-            Arc::new(Mutex::new(rio::new().unwrap())),
-        );
-        let res = piece.read(&file, 0).await;
+        let piece = match self.prefetch.remove(&index) {
+            Some(handle) => handle.await.map_err(io::Error::other)??,
+            None => {
+                let mut piece = self.new_piece(index)?;
+                let start = Instant::now();
+                piece.read(&self.file, index * self.piece_size).await?;
+                self.disk_stats.record_read(start.elapsed());
+                piece
+            }
+        };
 
-        assert!(res.is_ok());
-        assert_eq!(fread, piece.bytes);
+        // Dirty pieces pushed out of the cache must reach the disk first
+        let mut batch = WriteBatch::new();
+        let mut evicted = self.pieces.insert(index, piece);
+        evicted.sort_unstable_by_key(|(i, _)| *i);
+        self.journal_intent(evicted.iter().map(|(i, _)| *i))?;
+        for (i, p) in evicted {
+            batch.push((i * self.piece_size) as u64, p.bytes.into());
+        }
+        if !batch.is_empty() {
+            self.unsynced += batch.bytes();
+            self.write_batch(batch).await?;
+        }
+
+        Ok(())
     }
 
-    #[tokio::test]
-    async fn write_local_torrent() {
-        const TORRENT: &str = "./tests/torrent_files/test_local.torrent";
-        const OUT_FILE: &str = "./duplicate.torrent";
-        let fread = fs::read(TORRENT).unwrap();
-        let size = fs::metadata(TORRENT).unwrap().size() as usize;
-        let fout = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(OUT_FILE)
-            .unwrap();
+    /// Read a block to send it to a peer. Once reads look sequential, the
+    /// next pieces are read in the background.
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        self.check_range(index, offset, length)?;
+        // Verified pieces only went to the sink
+        if self.sink.as_ref().is_some_and(|s| !s.keeps_on_disk()) && self.verified[index] {
+            return Err(StorageError::NotLoaded(index));
+        }
+        self.load_piece(index).await?;
+        self.read_ahead_from(index);
+
+        self.sub_piece(index, offset, length)
+    }
+
+    /// Number of pieces being prefetched.
+    pub fn prefetching(&self) -> usize {
+        self.prefetch.len()
+    }
+
+    fn read_ahead_from(&mut self, index: usize) {
+        let sequential = match self.last_read {
+            Some(last) => index == last || index == last + 1,
+            None => false,
+        };
+        self.last_read = Some(index);
+
+        if !sequential {
+            // Random access, stop reading pieces which won't be needed
+            self.prefetch.drain().for_each(|(_, h)| h.abort());
+            return;
+        }
+
+        let end = (index + self.read_ahead).min(self.num_pieces - 1);
+        for next in index + 1..=end {
+            if self.pieces.contains(next) || self.prefetch.contains_key(&next) {
+                continue;
+            }
+
+            let file = self.file.clone();
+            let offset = next * self.piece_size;
+            let Ok(mut piece) = self.new_piece(next) else {
+                break;
+            };
+            let disk_stats = self.disk_stats.clone();
+            let handle = tokio::spawn(async move {
+                let start = Instant::now();
+                piece.read(&file, offset).await?;
+                disk_stats.record_read(start.elapsed());
+                Ok(piece)
+            });
+            self.prefetch.insert(next, handle);
+        }
+    }
+
+    /// Write a loaded piece back to the file.
+    pub async fn write_piece(&mut self, index: usize) -> StorageResult<()> {
+        if self.pieces.contains(index) {
+            self.journal_intent([index])?;
+        }
+        let offset = index * self.piece_size;
+        let Some(piece) = self.pieces.get_mut(index) else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        let res = piece.write(&self.file, offset).await;
+        self.disk_stats.record_write(start.elapsed());
+        res
+    }
+
+    pub fn hash_piece(&self, index: usize) -> Option<InfoHash> {
+        self.pieces.peek(index).map(Piece::hash)
+    }
+
+    // One hash per piece, a malformed torrent may have more or less
+    fn check_hashes(&self, hashes: &[PieceHash]) -> StorageResult<()> {
+        if hashes.len() != self.num_pieces {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, index: usize, offset: usize, length: usize) -> StorageResult<()> {
+        // Offsets come from peers, they may overflow
+        let len = self.piece_len(index)?;
+        if offset.checked_add(length).is_none_or(|end| end > len) {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Part of a loaded piece, sharing its memory instead of copying it.
+    pub fn sub_piece(&self, index: usize, offset: usize, length: usize) -> StorageResult<Bytes> {
+        self.check_range(index, offset, length)?;
+        match self.pieces.peek(index) {
+            Some(p) => Ok(p.bytes.slice(offset..offset + length)),
+            None => Err(StorageError::NotLoaded(index)),
+        }
+    }
+
+    pub async fn write_sub_piece(
+        &mut self,
+        index: usize,
+        offset: usize,
+        buf: &[u8],
+    ) -> StorageResult<()> {
+        self.check_range(index, offset, buf.len())?;
+        self.load_piece(index).await?;
+        self.pieces
+            .get_mut(index)
+            .ok_or(StorageError::NotLoaded(index))?
+            .add_block(offset, buf)?;
+        self.unsynced += buf.len();
+
+        self.tick().await
+    }
+}
+
+impl TorrentStorage for FileEntity {
+    async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        FileEntity::read_block(self, index, begin, length).await
+    }
+
+    async fn write_block(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        self.write_sub_piece(index, begin, data).await
+    }
+
+    async fn verify_piece(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        self.check_range(index, 0, 0)?;
+        self.load_piece(index).await?;
+        self.commit_piece(index, expected).await
+    }
+
+    async fn flush(&mut self) -> StorageResult<()> {
+        FileEntity::flush(self).await
+    }
+
+    fn len(&self) -> u64 {
+        self.size as u64
+    }
+
+    fn dirty_bytes(&self) -> u64 {
+        FileEntity::dirty_bytes(self) as u64
+    }
+}
+
+/// Where the pieces of a torrent go: a single file, or the files of a
+/// multi-file torrent laid out in its directory.
+// One per torrent, the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Storage {
+    Single(FileEntity),
+    Multi(MultiFileStorage),
+}
+
+impl From<FileEntity> for Storage {
+    fn from(file: FileEntity) -> Self {
+        Storage::Single(file)
+    }
+}
+
+impl From<MultiFileStorage> for Storage {
+    fn from(storage: MultiFileStorage) -> Self {
+        Storage::Multi(storage)
+    }
+}
+
+impl Storage {
+    /// Storage of the content described by `info` in `dirs`, the files of a
+    /// multi-file torrent in a directory named after it. Paths come from the
+    /// torrent, those which would escape the save path are refused. Only
+    /// `MultiFileStorage` moves files out of an incomplete location, single
+    /// files go there too when `dirs` has one.
+    pub fn open(info: &Info, dirs: StorageDirs, config: StorageConfig) -> StorageResult<Self> {
+        let layout = Layout::from_info(info)?;
+        if info.files.is_some() || dirs.has_incomplete_location() {
+            return Ok(MultiFileStorage::with_config(dirs, layout, config)?.into());
+        }
+        let size = usize::try_from(layout.size())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Torrent too large"))?;
+        let path = dirs.save_path.join(&layout.files()[0].path);
+        Ok(FileEntity::with_config(path, layout.piece_size(), size, config)?.into())
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        match self {
+            Storage::Single(f) => f.num_pieces(),
+            Storage::Multi(s) => s.num_pieces(),
+        }
+    }
+
+    pub fn piece_size(&self) -> usize {
+        match self {
+            Storage::Single(f) => f.piece_size(),
+            Storage::Multi(s) => s.layout().piece_size(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Storage::Single(f) => f.size() as u64,
+            Storage::Multi(s) => s.layout().size(),
+        }
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        match self {
+            Storage::Single(f) => f.is_verified(index),
+            Storage::Multi(s) => s.is_verified(index),
+        }
+    }
+
+    pub fn get_bitfield(&self) -> &Vec<bool> {
+        match self {
+            Storage::Single(f) => f.get_bitfield(),
+            Storage::Multi(s) => s.get_bitfield(),
+        }
+    }
+
+    pub fn disk_stats(&self) -> &Arc<DiskStats> {
+        match self {
+            Storage::Single(f) => f.disk_stats(),
+            Storage::Multi(s) => s.disk_stats(),
+        }
+    }
+
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        match self {
+            Storage::Single(f) => f.read_block(index, begin, length).await,
+            Storage::Multi(s) => s.read_block(index, begin, length).await,
+        }
+    }
+
+    pub async fn write_sub_piece(
+        &mut self,
+        index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => f.write_sub_piece(index, begin, data).await,
+            Storage::Multi(s) => s.write_sub_piece(index, begin, data).await,
+        }
+    }
+
+    pub fn is_piece_complete(&self, index: usize) -> bool {
+        match self {
+            Storage::Single(f) => f.is_piece_complete(index),
+            Storage::Multi(s) => s.is_piece_complete(index),
+        }
+    }
+
+    pub async fn commit_piece(
+        &mut self,
+        index: usize,
+        expected: &PieceHash,
+    ) -> StorageResult<bool> {
+        match self {
+            Storage::Single(f) => f.commit_piece(index, expected).await,
+            Storage::Multi(s) => s.commit_piece(index, expected).await,
+        }
+    }
+
+    pub async fn resume_data(&mut self) -> StorageResult<ResumeData> {
+        match self {
+            Storage::Single(f) => f.resume_data().await,
+            Storage::Multi(s) => s.resume_data().await,
+        }
+    }
+
+    pub async fn apply_resume(&mut self, data: &ResumeData) -> StorageResult<bool> {
+        match self {
+            Storage::Single(f) => f.apply_resume(data).await,
+            Storage::Multi(s) => s.apply_resume(data).await,
+        }
+    }
+
+    /// What is on disk was there before the storage was opened, it must be
+    /// rechecked.
+    pub fn needs_recheck(&self) -> bool {
+        match self {
+            Storage::Single(f) => f.needs_recheck(),
+            Storage::Multi(s) => s.needs_recheck(),
+        }
+    }
+
+    /// Hash every piece on disk, returns the number of valid ones.
+    pub async fn recheck(&mut self, hashes: &[PieceHash]) -> StorageResult<usize> {
+        match self {
+            Storage::Single(f) => f.recheck(hashes).await,
+            Storage::Multi(s) => s.recheck(hashes).await,
+        }
+    }
+
+    pub async fn recheck_with(
+        &mut self,
+        hashes: &[PieceHash],
+        handle: &RecheckHandle,
+    ) -> StorageResult<usize> {
+        match self {
+            Storage::Single(f) => f.recheck_with(hashes, handle).await,
+            Storage::Multi(s) => s.recheck_with(hashes, handle).await,
+        }
+    }
+
+    /// Make what was written so far durable.
+    pub async fn sync(&mut self) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => f.sync().await,
+            Storage::Multi(s) => s.sync().await,
+        }
+    }
+
+    pub async fn flush(&mut self) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => f.flush().await,
+            Storage::Multi(s) => s.flush().await,
+        }
+    }
+
+    /// Delete what the torrent has on disk, and nothing else: the file and
+    /// its journal, or the files, parts file and directory of a multi-file
+    /// torrent.
+    pub fn delete_files(&mut self) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => {
+                storage::remove_existing(f.path())?;
+                Ok(storage::remove_existing(&journal::journal_path(f.path()))?)
+            }
+            Storage::Multi(s) => s.delete_files(),
+        }
+    }
+
+    /// Rename a file, its new path being relative to the save path. The
+    /// file of a single file torrent stays in its directory.
+    pub fn rename_file<P: AsRef<Path>>(&mut self, file: usize, path: P) -> StorageResult<()> {
+        match self {
+            Storage::Single(_) if file > 0 => Err(StorageError::OutOfRange),
+            Storage::Single(f) => f.rename(sibling(f.path(), path.as_ref())?),
+            Storage::Multi(s) if file >= s.layout().files().len() => Err(StorageError::OutOfRange),
+            Storage::Multi(s) => s.rename_file(file, path),
+        }
+    }
+
+    /// Rename the directory of a multi-file torrent, or the file of a single
+    /// file one.
+    pub fn rename_root(&mut self, name: &str) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => f.rename(sibling(f.path(), Path::new(name))?),
+            Storage::Multi(s) => s.rename_root(name),
+        }
+    }
+
+    /// Move what is on disk to another save path.
+    pub fn move_storage<P: AsRef<Path>>(&mut self, save_path: P) -> StorageResult<()> {
+        match self {
+            Storage::Single(f) => {
+                let name = f.path().file_name().unwrap_or_default().to_owned();
+                f.rename(save_path.as_ref().join(name))
+            }
+            Storage::Multi(s) => s.move_storage(save_path),
+        }
+    }
+
+    /// Once every wanted piece is verified, files are moved out of their
+    /// incomplete location and get their attributes.
+    pub fn complete(&mut self) -> StorageResult<()> {
+        match self {
+            Storage::Multi(s) if s.is_complete() => s.move_to_complete(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl TorrentStorage for Storage {
+    async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        Storage::read_block(self, index, begin, length).await
+    }
+
+    async fn write_block(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        self.write_sub_piece(index, begin, data).await
+    }
+
+    async fn verify_piece(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        match self {
+            Storage::Single(f) => TorrentStorage::verify_piece(f, index, expected).await,
+            Storage::Multi(s) => s.verify_piece(index, expected).await,
+        }
+    }
+
+    async fn flush(&mut self) -> StorageResult<()> {
+        Storage::flush(self).await
+    }
+
+    fn len(&self) -> u64 {
+        match self {
+            Storage::Single(f) => TorrentStorage::len(f),
+            Storage::Multi(s) => TorrentStorage::len(s),
+        }
+    }
+
+    fn dirty_bytes(&self) -> u64 {
+        match self {
+            Storage::Single(f) => TorrentStorage::dirty_bytes(f),
+            Storage::Multi(s) => s.dirty_bytes(),
+        }
+    }
+}
+
+// `path` in the directory of `file`, paths from users can't escape it
+fn sibling(file: &Path, path: &Path) -> io::Result<PathBuf> {
+    let path = Layout::checked_path(path.to_owned())?;
+    Ok(file.parent().unwrap_or(Path::new("")).join(path))
+}
+
+async fn write_direct(direct: Arc<File>, buf: &mut Vec<u8>, offset: u64) -> io::Result<usize> {
+    let owned = std::mem::take(buf);
+    let (owned, res) = tokio::task::spawn_blocking(move || {
+        let res = direct::pwrite_direct(&direct, &owned, offset);
+        (owned, res)
+    })
+    .await
+    .map_err(io::Error::other)?;
+    *buf = owned;
+    res
+}
+
+pub(crate) fn allocate<S: AsRef<Path>>(
+    file: S,
+    size: usize,
+    allocation: Allocation,
+) -> io::Result<File> {
+    let path = file.as_ref().to_owned();
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(file)?;
+
+    let res = match allocation {
+        Allocation::Sparse => file.set_len(size as u64),
+        Allocation::Fallocate => fallocate(&file, size),
+        Allocation::Full => zero_fill(&file, size),
+    };
+
+    // Don't leave a half allocated file behind, e.g. when the disk is full
+    if let Err(e) = res {
+        drop(file);
+        let _ = fs::remove_file(path);
+        return Err(e);
+    }
+
+    Ok(file)
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, size: usize) -> io::Result<()> {
+    use std::os::unix::prelude::AsRawFd;
+
+    if size == 0 {
+        return Ok(());
+    }
+
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+    if res == 0 {
+        return Ok(());
+    }
+
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => zero_fill(file, size),
+        _ => Err(e),
+    }
+}
+
+// No fallocate outside of Linux, at least give the file its final size
+#[cfg(not(target_os = "linux"))]
+fn fallocate(file: &File, size: usize) -> io::Result<()> {
+    file.set_len(size as u64)
+}
+
+fn zero_fill(mut file: &File, size: usize) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    const CHUNK: usize = 1024 * 1024;
+    let zeros = vec![0u8; CHUNK.min(size)];
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(CHUNK);
+        file.write_all(&zeros[..n])?;
+        left -= n;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod file_tests {
+    use super::*;
+    use crate::hash::HashKind;
+
+    #[test]
+    fn allocate_file() {
+        const SIZE_10M: usize = 10 * 1024 * 1024;
+        const FILE: &str = "./test_allocate_file";
+
+        for allocation in [Allocation::Sparse, Allocation::Fallocate, Allocation::Full] {
+            assert!(allocate(FILE, SIZE_10M, allocation).is_ok());
+
+            let path = Path::new(FILE);
+            assert!(path.exists());
+            assert!(path.is_file());
+
+            let meta = fs::metadata(FILE).unwrap();
+            assert_eq!(SIZE_10M, meta.len() as usize);
+
+            fs::remove_file(FILE).unwrap();
+        }
+    }
+
+    #[test]
+    fn zero_filled_file() {
+        const FILE: &str = "./test_zero_filled_file";
+        const SIZE: usize = 3 * 1024 * 1024 + 5;
+
+        allocate(FILE, SIZE, Allocation::Full).unwrap();
+        assert_eq!(vec![0u8; SIZE], fs::read(FILE).unwrap());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn create_new_file() {
+        const FILE: &str = "./non_existing";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1024;
+
+        let fe = FileEntity::new(FILE, PSIZE, FSIZE);
+        assert!(fe.is_ok());
+
+        let fe = fe.unwrap();
+        assert!(!fe.needs_recheck());
+        assert_eq!(fe.piece_size, PSIZE);
+        assert_eq!(fe.num_pieces(), FSIZE / PSIZE);
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+
+        // Refused before creating anything
+        assert!(FileEntity::new(FILE, 0, FSIZE).is_err());
+        assert!(!Path::new(FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn last_piece_shorter() {
+        const FILE: &str = "./test_last_piece_shorter";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1000;
+
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        assert_eq!(4, fe.num_pieces());
+        assert_eq!(PSIZE, fe.piece_len(0).unwrap());
+        assert_eq!(FSIZE - 3 * PSIZE, fe.piece_len(3).unwrap());
+        assert!(matches!(fe.piece_len(4), Err(StorageError::OutOfRange)));
+
+        let data = vec![42u8; FSIZE - 3 * PSIZE];
+        fe.write_sub_piece(3, 0, &data).await.unwrap();
+        fe.write_piece(3).await.unwrap();
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let expected: InfoHash = hasher.finalize().into();
+        assert_eq!(Some(expected), fe.hash_piece(3));
+
+        drop(fe);
+        let content = fs::read(FILE).unwrap();
+        assert_eq!(FSIZE, content.len());
+        assert_eq!(data, content[3 * PSIZE..]);
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloading_piece_is_not_evicted() {
+        const FILE: &str = "./test_downloading_piece";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1024;
+
+        let config = StorageConfig {
+            cache_size: 2 * PSIZE,
+            ..StorageConfig::default()
+        };
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config).unwrap();
+        fe.write_sub_piece(0, 0, &[1; PSIZE]).await.unwrap();
+        fe.load_piece(1).await.unwrap();
+        fe.load_piece(2).await.unwrap();
+
+        // Piece 0 is the oldest but isn't verified yet, 1 goes instead
+        assert!(fe.cache().contains(0));
+        assert!(!fe.cache().contains(1));
+        assert!(fe.cache().contains(2));
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commit_verified_piece() {
+        const FILE: &str = "./test_commit_verified_piece";
+        const FSIZE: usize = 3 * BLOCK_SIZE;
+
+        let data: Vec<u8> = (0..FSIZE).map(|x| x as u8).collect();
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let expected: InfoHash = hasher.finalize().into();
+
+        let mut fe = FileEntity::new(FILE, FSIZE, FSIZE).unwrap();
+        for chunk in (0..FSIZE).step_by(BLOCK_SIZE) {
+            assert!(!fe.is_piece_complete(0));
+            fe.write_sub_piece(0, chunk, &data[chunk..chunk + BLOCK_SIZE])
+                .await
+                .unwrap();
+        }
+        assert!(fe.is_piece_complete(0));
+
+        assert!(fe.commit_piece(0, &expected.into()).await.unwrap());
+        assert!(fe.is_verified(0));
+        assert!(!fe.cache().peek(0).unwrap().is_dirty());
+
+        drop(fe);
+        assert_eq!(data, fs::read(FILE).unwrap());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commit_v2_piece() {
+        const FILE: &str = "./test_commit_v2_piece";
+        const PSIZE: usize = 2 * BLOCK_SIZE;
+
+        let data = vec![5u8; PSIZE + 100];
+        let mut fe = FileEntity::new(FILE, PSIZE, data.len()).unwrap();
+        for (index, piece) in data.chunks(PSIZE).enumerate() {
+            fe.write_sub_piece(index, 0, piece).await.unwrap();
+            let v2 = PieceHash::compute(HashKind::Sha256, piece, PSIZE);
+            assert!(fe.commit_piece(index, &v2).await.unwrap());
+        }
+        assert_eq!(&vec![true, true], fe.get_bitfield());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn discard_corrupted_piece() {
+        const FILE: &str = "./test_discard_corrupted_piece";
+        const FSIZE: usize = BLOCK_SIZE;
+
+        let mut fe = FileEntity::new(FILE, FSIZE, FSIZE).unwrap();
+        fe.write_sub_piece(0, 0, &[1; FSIZE]).await.unwrap();
+        assert!(fe.is_piece_complete(0));
+
+        assert!(!fe.commit_piece(0, &[0; 20].into()).await.unwrap());
+        assert!(!fe.is_verified(0));
+        assert!(!fe.cache().contains(0));
+
+        drop(fe);
+        assert_eq!(vec![0; FSIZE], fs::read(FILE).unwrap());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recheck_existing_file() {
+        const FILE: &str = "./test_recheck_existing_file";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1000;
+
+        let data: Vec<u8> = (0..FSIZE).map(|x| x as u8).collect();
+        let hashes: Vec<PieceHash> = data
+            .chunks(PSIZE)
+            .map(|chunk| PieceHash::compute(HashKind::Sha1, chunk, PSIZE))
+            .collect();
+
+        let mut corrupted = data.clone();
+        corrupted[PSIZE + 1] ^= 0xff;
+        fs::write(FILE, &corrupted).unwrap();
+
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        assert!(fe.needs_recheck());
+        assert!(matches!(
+            fe.recheck(&hashes[1..]).await,
+            Err(StorageError::OutOfRange)
+        ));
+        assert_eq!(3, fe.recheck(&hashes).await.unwrap());
+        assert_eq!(&vec![true, false, true, true], fe.get_bitfield());
+        assert!(!fe.needs_recheck());
+        assert!(fe.cache().is_empty());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deliver_to_sink() {
+        const FILE: &str = "./test_deliver_to_sink";
+        const PSIZE: usize = 256;
+
+        let data = vec![7u8; PSIZE];
+        let hash = PieceHash::compute(HashKind::Sha1, &data, PSIZE);
+        let (sink, mut receiver) = PieceSink::channel(2);
+        let config = StorageConfig {
+            allocation: Allocation::Sparse,
+            sink: Some(sink.without_disk()),
+            ..Default::default()
+        };
+
+        let mut fe = FileEntity::with_config(FILE, PSIZE, 2 * PSIZE, config).unwrap();
+        fe.write_sub_piece(1, 0, &data).await.unwrap();
+        assert!(fe.commit_piece(1, &hash).await.unwrap());
+        fe.flush().await.unwrap();
+
+        let piece = receiver.recv().await.unwrap();
+        assert_eq!(1, piece.index);
+        assert_eq!(data, piece.data);
+        assert!(fe.is_verified(1) && fe.cache().is_empty());
+        assert_eq!(vec![0u8; PSIZE], fs::read(FILE).unwrap()[PSIZE..]);
+        assert!(matches!(
+            fe.read_block(1, 0, 16).await,
+            Err(StorageError::NotLoaded(1))
+        ));
+
+        // The receiver went away, the download can't go on
+        drop(receiver);
+        fe.write_sub_piece(0, 0, &data).await.unwrap();
+        assert!(fe.commit_piece(0, &hash).await.is_err());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recover_from_journal() {
+        const FILE: &str = "./test_recover_from_journal";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1000;
+
+        let data: Vec<u8> = (0..FSIZE).map(|x| x as u8).collect();
+        let hashes: Vec<PieceHash> = data
+            .chunks(PSIZE)
+            .map(|chunk| PieceHash::compute(HashKind::Sha1, chunk, PSIZE))
+            .collect();
+        let config = StorageConfig {
+            journal: true,
+            ..Default::default()
+        };
+
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config.clone()).unwrap();
+        for index in 0..2 {
+            let piece = &data[index * PSIZE..(index + 1) * PSIZE];
+            fe.write_sub_piece(index, 0, piece).await.unwrap();
+            assert!(fe.commit_piece(index, &hashes[index]).await.unwrap());
+            if index == 0 {
+                fe.sync().await.unwrap();
+            }
+        }
+        // Crash before piece 1 is synced
+        drop(fe);
+
+        // Committed pieces aren't read again
+        let mut content = fs::read(FILE).unwrap();
+        content[0] ^= 0xff;
+        fs::write(FILE, &content).unwrap();
+
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config.clone()).unwrap();
+        assert_eq!(2, fe.recover(&hashes).await.unwrap());
+        assert_eq!(&vec![true, true, false, false], fe.get_bitfield());
+        drop(fe);
+
+        // Without a journal to trust, everything is hashed
+        fs::remove_file(journal::journal_path(FILE)).unwrap();
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config).unwrap();
+        assert_eq!(1, fe.recover(&hashes).await.unwrap());
+        assert_eq!(&vec![false, true, false, false], fe.get_bitfield());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+        fs::remove_file(journal::journal_path(FILE)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_single_file() {
+        const DIR: &str = "./test_rename_single_file";
+        const PSIZE: usize = 256;
+        let dir = Path::new(DIR);
+        let data: Vec<u8> = (0..PSIZE).map(|x| x as u8).collect();
+        let hash = PieceHash::compute(HashKind::Sha1, &data, PSIZE);
+        let config = StorageConfig {
+            journal: true,
+            ..Default::default()
+        };
+        fs::create_dir_all(dir).unwrap();
+
+        let fe = FileEntity::with_config(dir.join("a"), PSIZE, PSIZE, config).unwrap();
+        let mut storage = Storage::from(fe);
+        storage.write_sub_piece(0, 0, &data).await.unwrap();
+        assert!(storage.commit_piece(0, &hash).await.unwrap());
+
+        storage.rename_file(0, "b").unwrap();
+        assert!(!dir.join("a").exists());
+        assert!(journal::journal_path(dir.join("b")).exists());
+        assert!(storage.rename_file(1, "c").is_err());
+        assert!(storage.rename_file(0, "../c").is_err());
+        storage.rename_root("c").unwrap();
+        storage.move_storage(dir.join("moved")).unwrap();
+        assert_eq!(data, fs::read(dir.join("moved/c")).unwrap());
+
+        // Still usable at the new place
+        storage.write_sub_piece(0, 0, &[0; PSIZE]).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(vec![0; PSIZE], fs::read(dir.join("moved/c")).unwrap());
+        assert!(journal::journal_path(dir.join("moved/c")).exists());
+        assert!(!dir.join("c").exists());
+
+        drop(storage);
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunked_recheck() {
+        const FILE: &str = "./test_chunked_recheck";
+        const PSIZE: usize = 4 * BLOCK_SIZE;
+        const FSIZE: usize = 2 * PSIZE + 100;
+
+        let data: Vec<u8> = (0..FSIZE).map(|x| (x / 7) as u8).collect();
+        let mut corrupted = data.clone();
+        corrupted[PSIZE + 3 * BLOCK_SIZE] ^= 0xff;
+        fs::write(FILE, &corrupted).unwrap();
+
+        let config = StorageConfig {
+            chunked_hashing: true,
+            ..Default::default()
+        };
+        for kind in [HashKind::Sha1, HashKind::Sha256] {
+            let hashes: Vec<PieceHash> = data
+                .chunks(PSIZE)
+                .map(|chunk| PieceHash::compute(kind, chunk, PSIZE))
+                .collect();
+            let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config.clone()).unwrap();
+            assert_eq!(2, fe.recheck(&hashes).await.unwrap());
+            assert_eq!(&vec![true, false, true], fe.get_bitfield());
+        }
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scheduled_recheck() {
+        use crate::recheck::{RecheckConfig, RecheckScheduler};
+
+        const FILE: &str = "./test_scheduled_recheck";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1000;
+
+        let data: Vec<u8> = (0..FSIZE).map(|x| x as u8).collect();
+        let hashes: Vec<PieceHash> = data
+            .chunks(PSIZE)
+            .map(|chunk| PieceHash::compute(HashKind::Sha1, chunk, PSIZE))
+            .collect();
+        fs::write(FILE, &data).unwrap();
+
+        // About 100 ms per piece
+        let scheduler = RecheckScheduler::new(RecheckConfig {
+            max_concurrent: 1,
+            bytes_per_sec: Some(2560),
+        });
+        let handle = scheduler.handle(hashes.len());
+        let progress = handle.clone();
+
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        let recheck = tokio::spawn(async move {
+            let valid = fe.recheck_with(&hashes, &handle).await.unwrap();
+            (fe, valid)
+        });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        progress.pause();
+        let paused_at = progress.progress().checked;
+        assert!(paused_at < 4);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // At most the piece already past the throttle
+        assert!(progress.progress().checked <= paused_at + 1);
+        progress.resume();
+
+        let (fe, valid) = recheck.await.unwrap();
+        assert_eq!(4, valid);
+        assert!(progress.progress().is_done());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_unfinished_piece() {
+        const FILE: &str = "./test_resume_unfinished_piece";
+        const FSIZE: usize = 4 * BLOCK_SIZE;
+        const PSIZE: usize = 2 * BLOCK_SIZE;
+
+        let data = vec![7u8; PSIZE];
+        let mut hasher = Sha1::new();
+        hasher.update(&data);
+        let expected: InfoHash = hasher.finalize().into();
+
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        fe.write_sub_piece(0, 0, &data).await.unwrap();
+        assert!(fe.commit_piece(0, &expected.into()).await.unwrap());
+        fe.write_sub_piece(1, 0, &data[..BLOCK_SIZE]).await.unwrap();
+
+        let saved = fe.resume_data().await.unwrap();
+        assert_eq!(vec![true, false], saved.pieces);
+        assert_eq!(1, saved.unfinished.len());
+        assert_eq!(vec![true, false], saved.unfinished[0].blocks);
+        drop(fe);
+
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        assert!(fe.apply_resume(&saved).await.unwrap());
+        assert!(!fe.needs_recheck());
+        assert!(fe.is_verified(0));
+        assert!(!fe.is_piece_complete(1));
+
+        fe.write_sub_piece(1, BLOCK_SIZE, &data[BLOCK_SIZE..])
+            .await
+            .unwrap();
+        assert!(fe.is_piece_complete(1));
+        assert!(fe.commit_piece(1, &expected.into()).await.unwrap());
+        drop(fe);
+
+        // The file changed behind our back
+        fs::OpenOptions::new()
+            .write(true)
+            .open(FILE)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+        let mut fe = FileEntity::new(FILE, PSIZE, FSIZE).unwrap();
+        assert!(!fe.apply_resume(&saved).await.unwrap());
+        assert!(fe.needs_recheck());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_blocks() {
+        const FILE: &str = "./test_shared_blocks";
+        const PSIZE: usize = 2 * BLOCK_SIZE;
+
+        fs::write(FILE, vec![1u8; PSIZE]).unwrap();
+        let mut fe = FileEntity::new(FILE, PSIZE, PSIZE).unwrap();
+        let block = fe.read_block(0, BLOCK_SIZE, BLOCK_SIZE).await.unwrap();
+        let piece = fe.cache().peek(0).unwrap();
+        assert_eq!(piece.bytes[BLOCK_SIZE..].as_ptr(), block.as_ptr());
+
+        // Blocks being sent keep their content when the piece changes
+        fe.write_sub_piece(0, BLOCK_SIZE, &[2; 10]).await.unwrap();
+        assert_eq!(vec![1u8; BLOCK_SIZE], block);
+        assert_eq!(2, fe.sub_piece(0, BLOCK_SIZE, 1).unwrap()[0]);
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_io() {
+        const FILE: &str = "./test_direct_io";
+        const PSIZE: usize = 2 * BLOCK_SIZE;
+        const FSIZE: usize = 2 * PSIZE + 100;
+
+        let config = StorageConfig {
+            direct_io: true,
+            ..StorageConfig::default()
+        };
+        let data: Vec<u8> = (0..FSIZE).map(|x| x as u8).collect();
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config).unwrap();
+        // Only the last piece isn't aligned and goes through the page cache
+        for (index, piece) in data.chunks(PSIZE).enumerate() {
+            fe.write_sub_piece(index, 0, piece).await.unwrap();
+            let hash = PieceHash::compute(HashKind::Sha1, piece, PSIZE);
+            assert!(fe.commit_piece(index, &hash).await.unwrap());
+        }
+        fe.flush().await.unwrap();
+        drop(fe);
+        assert_eq!(data, fs::read(FILE).unwrap());
+
+        let config = StorageConfig {
+            direct_io: true,
+            cache_size: PSIZE,
+            ..StorageConfig::default()
+        };
+        let mut fe = FileEntity::with_config(FILE, PSIZE, FSIZE, config).unwrap();
+        for index in 0..3 {
+            let len = fe.piece_len(index).unwrap();
+            let block = fe.read_block(index, 0, len).await.unwrap();
+            assert_eq!(data[index * PSIZE..index * PSIZE + len], block);
+        }
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_every_bytes() {
+        const FILE: &str = "./test_flush_every_bytes";
+        const FSIZE: usize = 4 * BLOCK_SIZE;
+
+        let config = StorageConfig {
+            flush: FlushPolicy {
+                every_bytes: Some(2 * BLOCK_SIZE),
+                ..FlushPolicy::default()
+            },
+            ..StorageConfig::default()
+        };
+        let mut fe = FileEntity::with_config(FILE, FSIZE, FSIZE, config).unwrap();
+
+        fe.write_sub_piece(0, 0, &[1; BLOCK_SIZE]).await.unwrap();
+        assert_eq!(BLOCK_SIZE, fe.unsynced());
+        assert_eq!(vec![0; BLOCK_SIZE], fs::read(FILE).unwrap()[..BLOCK_SIZE]);
+
+        // The unverified blocks reach the disk once the threshold is crossed
+        fe.write_sub_piece(0, BLOCK_SIZE, &[2; BLOCK_SIZE])
+            .await
+            .unwrap();
+        assert_eq!(0, fe.unsynced());
+        let content = fs::read(FILE).unwrap();
+        assert_eq!(vec![1; BLOCK_SIZE], content[..BLOCK_SIZE]);
+        assert_eq!(vec![2; BLOCK_SIZE], content[BLOCK_SIZE..2 * BLOCK_SIZE]);
+        assert!(!fe.cache().peek(0).unwrap().is_dirty());
+        assert!(fe.cache().peek(0).unwrap().is_downloading());
+
+        fe.write_sub_piece(0, 2 * BLOCK_SIZE, &[3; BLOCK_SIZE])
+            .await
+            .unwrap();
+        fe.shutdown().await.unwrap();
+        assert_eq!(
+            vec![3; BLOCK_SIZE],
+            fs::read(FILE).unwrap()[2 * BLOCK_SIZE..3 * BLOCK_SIZE]
+        );
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        const FILE: &str = "./test_read_ahead";
+        const PSIZE: usize = 256;
+
+        let data: Vec<u8> = (0..8 * PSIZE).map(|x| (x / PSIZE) as u8).collect();
+        fs::write(FILE, &data).unwrap();
+
+        let mut fe = FileEntity::new(FILE, PSIZE, 8 * PSIZE).unwrap();
+        fe.read_block(0, 0, 16).await.unwrap();
+        assert_eq!(0, fe.prefetching());
+
+        fe.read_block(1, 0, 16).await.unwrap();
+        assert_eq!(DEFAULT_READ_AHEAD, fe.prefetching());
+
+        // Served from the prefetched piece
+        assert_eq!(vec![2; 16], fe.read_block(2, 0, 16).await.unwrap());
+        assert!(fe.cache().contains(2));
+        assert_eq!(DEFAULT_READ_AHEAD, fe.prefetching());
+
+        fe.read_block(6, 0, 16).await.unwrap();
+        assert_eq!(0, fe.prefetching());
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn file_already_exist() {
+        let fe = FileEntity::new("./Cargo.toml", 16, 0);
+        assert!(fe.is_err());
+        if let Err(e) = fe {
+            assert!(matches!(e, StorageError::SizeMismatch { .. }));
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn open_existing_file() {
+        const FILE: &str = "./test_open_existing_file";
+        fs::write(FILE, [1u8; 100]).unwrap();
+
+        let fe = FileEntity::new(FILE, 64, 100).unwrap();
+        assert!(fe.needs_recheck());
+        drop(fe);
+
+        let res = FileEntity::new(FILE, 64, 200);
+        assert!(matches!(
+            res,
+            Err(StorageError::SizeMismatch {
+                expected: 200,
+                actual: 100
+            })
+        ));
+        assert!(matches!(
+            FileEntity::new("./src", 64, 100),
+            Err(StorageError::NotAFile)
+        ));
+
+        let config = StorageConfig {
+            truncate_existing: true,
+            ..StorageConfig::default()
+        };
+        let fe = FileEntity::with_config(FILE, 64, 200, config).unwrap();
+        assert!(!fe.needs_recheck());
+        drop(fe);
+        assert_eq!(vec![0u8; 200], fs::read(FILE).unwrap());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn file_not_allowed() {
+        let fe = FileEntity::new("/root/haxxor", 1024, 1024);
+        assert!(fe.is_err());
+        if let Err(e) = fe {
+            assert!(matches!(e, StorageError::PermissionDenied));
+        } else {
+            panic!();
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_errors() {
+        const FILE: &str = "./test_storage_errors";
+        const PSIZE: usize = 256;
+
+        let mut fe = FileEntity::new(FILE, PSIZE, 2 * PSIZE).unwrap();
+        assert!(matches!(
+            fe.sub_piece(1, 0, 16),
+            Err(StorageError::NotLoaded(1))
+        ));
+        assert!(matches!(
+            fe.write_sub_piece(1, PSIZE - 8, &[0; 16]).await,
+            Err(StorageError::OutOfRange)
+        ));
+        assert!(matches!(
+            fe.write_sub_piece(2, 0, &[0; 16]).await,
+            Err(StorageError::OutOfRange)
+        ));
+
+        // Someone else truncated the file
+        fs::OpenOptions::new()
+            .write(true)
+            .open(FILE)
+            .unwrap()
+            .set_len(PSIZE as u64)
+            .unwrap();
+        assert!(matches!(
+            fe.load_piece(1).await,
+            Err(StorageError::ShortRead {
+                expected: PSIZE,
+                read: 0
+            })
+        ));
+
+        drop(fe);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_local_torrent() {
+        const TORRENT: &str = "./tests/torrent_files/test_local.torrent";
+        let fread = fs::read(TORRENT).unwrap();
+        let size = fs::metadata(TORRENT).unwrap().len();
+        let file = Arc::new(fs::OpenOptions::new().read(true).open(TORRENT).unwrap());
+
+        let mut piece = Piece::new(size as usize, size as usize, IoBackend::default());
+        let res = piece.read(&file, 0).await;
+
+        assert!(res.is_ok());
+        assert_eq!(fread, piece.bytes);
+    }
+
+    #[tokio::test]
+    async fn write_local_torrent() {
+        const TORRENT: &str = "./tests/torrent_files/test_local.torrent";
+        const OUT_FILE: &str = "./duplicate.torrent";
+        let fread = fs::read(TORRENT).unwrap();
+        let size = fs::metadata(TORRENT).unwrap().len() as usize;
+        let fout = Arc::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(OUT_FILE)
+                .unwrap(),
+        );
 
-        let mut piece = Piece::new(size, size, Arc::new(Mutex::new(rio::new().unwrap())));
-        piece.update(0, &fread);
+        let mut piece = Piece::new(size, size, IoBackend::default());
+        piece.update(0, &fread).unwrap();
+        assert!(matches!(
+            piece.update(1, &fread),
+            Err(StorageError::OutOfRange)
+        ));
         assert_eq!(fread, piece.bytes);
         let res = piece.write(&fout, 0).await;
 
@@ -277,10 +2121,10 @@ mod file_tests {
     #[tokio::test]
     async fn hash_local_torrent() {
         const TORRENT: &str = "./tests/torrent_files/test_local.torrent";
-        let file = fs::OpenOptions::new().read(true).open(TORRENT).unwrap();
-        let size = fs::metadata(TORRENT).unwrap().size() as usize;
+        let file = Arc::new(fs::OpenOptions::new().read(true).open(TORRENT).unwrap());
+        let size = fs::metadata(TORRENT).unwrap().len() as usize;
 
-        let piece = Piece::new(size, size, Arc::new(Mutex::new(rio::new().unwrap())));
+        let mut piece = Piece::new(size, size, IoBackend::default());
         piece.read(&file, 0).await.unwrap();
 
         assert_eq!(
@@ -0,0 +1,667 @@
+// FUSE filesystem showing the files of torrents at their full size while
+// they download. Reads wait for the pieces they cover, which are asked for
+// first, so that a player can open a file right away. The kernel protocol
+// is spoken over /dev/fuse directly, mounting needs CAP_SYS_ADMIN or
+// fusermount3.
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    ptr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{runtime::Handle, task::AbortHandle};
+
+use crate::{layout::Layout, session::SharedTorrent};
+
+const ROOT: u64 = 1;
+// Version of the protocol spoken, the kernel settles for the lowest
+const KERNEL_VERSION: u32 = 7;
+const KERNEL_MINOR_VERSION: u32 = 31;
+const MAX_WRITE: u32 = 128 * 1024;
+// A request is at most MAX_WRITE bytes and its header
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+const HEADER_SIZE: usize = 40;
+// Seconds the kernel may cache names and attributes, they never change
+const TTL: u64 = 60;
+const BLOCK_SIZE: u32 = 4096;
+// Kernel may keep the pages of the file between opens
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+// Mounts for users without CAP_SYS_ADMIN
+const FUSERMOUNT: &str = "fusermount3";
+const FUSERMOUNT_OPTIONS: &str =
+    "ro,nosuid,nodev,default_permissions,fsname=torrent-rs,subtype=torrent-rs";
+
+const FUSE_LOOKUP: u32 = 1;
+const FUSE_FORGET: u32 = 2;
+const FUSE_GETATTR: u32 = 3;
+const FUSE_OPEN: u32 = 14;
+const FUSE_READ: u32 = 15;
+const FUSE_STATFS: u32 = 17;
+const FUSE_RELEASE: u32 = 18;
+const FUSE_FLUSH: u32 = 25;
+const FUSE_INIT: u32 = 26;
+const FUSE_OPENDIR: u32 = 27;
+const FUSE_READDIR: u32 = 28;
+const FUSE_RELEASEDIR: u32 = 29;
+const FUSE_INTERRUPT: u32 = 36;
+const FUSE_DESTROY: u32 = 38;
+const FUSE_BATCH_FORGET: u32 = 42;
+
+enum Node {
+    Dir {
+        parent: u64,
+        children: Vec<(OsString, u64)>,
+    },
+    File {
+        torrent: usize,
+        file: usize,
+        size: u64,
+    },
+}
+
+// Files of the torrents by inode, the inode of a node is its index plus one
+struct Tree {
+    nodes: Vec<Node>,
+    torrents: Vec<SharedTorrent>,
+    uid: u32,
+    gid: u32,
+    time: u64,
+}
+
+impl Tree {
+    fn new() -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Tree {
+            nodes: vec![Node::Dir {
+                parent: ROOT,
+                children: Vec::new(),
+            }],
+            torrents: Vec::new(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            time,
+        }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+
+    fn child(&self, dir: u64, name: &OsStr) -> Option<u64> {
+        match self.node(dir)? {
+            Node::Dir { children, .. } => children
+                .iter()
+                .find(|(child, _)| child == name)
+                .map(|&(_, ino)| ino),
+            Node::File { .. } => None,
+        }
+    }
+
+    fn add(&mut self, dir: u64, name: &OsStr, node: Node) -> u64 {
+        self.nodes.push(node);
+        let ino = self.nodes.len() as u64;
+        if let Node::Dir { children, .. } = &mut self.nodes[dir as usize - 1] {
+            children.push((name.to_os_string(), ino));
+        }
+        ino
+    }
+
+    // Paths of a layout are relative and only have normal components. A
+    // file already at the path of another torrent is left out.
+    fn insert(&mut self, path: &Path, torrent: usize, file: usize, size: u64) {
+        let mut dir = ROOT;
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            let name = component.as_os_str();
+            let child = self.child(dir, name);
+            if components.peek().is_none() {
+                if child.is_none() {
+                    let node = Node::File {
+                        torrent,
+                        file,
+                        size,
+                    };
+                    self.add(dir, name, node);
+                }
+                return;
+            }
+            dir = match child {
+                Some(ino) if matches!(self.node(ino), Some(Node::Dir { .. })) => ino,
+                Some(_) => return,
+                None => {
+                    let node = Node::Dir {
+                        parent: dir,
+                        children: Vec::new(),
+                    };
+                    self.add(dir, name, node)
+                }
+            };
+        }
+    }
+
+    // fuse_attr
+    fn attr(&self, ino: u64, node: &Node) -> Vec<u8> {
+        let (size, mode, nlink) = match node {
+            Node::Dir { .. } => (0, libc::S_IFDIR | 0o555, 2),
+            Node::File { size, .. } => (*size, libc::S_IFREG | 0o444, 1),
+        };
+        let mut buf = Vec::with_capacity(88);
+        for value in [
+            ino,
+            size,
+            size.div_ceil(512),
+            self.time,
+            self.time,
+            self.time,
+        ] {
+            buf.extend_from_slice(&value.to_ne_bytes());
+        }
+        for value in [0, 0, 0, mode, nlink, self.uid, self.gid, 0, BLOCK_SIZE, 0] {
+            buf.extend_from_slice(&value.to_ne_bytes());
+        }
+        buf
+    }
+
+    // fuse_entry_out
+    fn entry(&self, ino: u64, node: &Node) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        for value in [ino, 0, TTL, TTL] {
+            buf.extend_from_slice(&value.to_ne_bytes());
+        }
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&self.attr(ino, node));
+        buf
+    }
+
+    // fuse_dirent records from entry `offset`, at most `size` bytes
+    fn read_dir(&self, ino: u64, offset: usize, size: usize) -> Result<Vec<u8>, i32> {
+        let Some(Node::Dir { parent, children }) = self.node(ino) else {
+            return Err(libc::ENOTDIR);
+        };
+        let entries = [(OsStr::new("."), ino), (OsStr::new(".."), *parent)]
+            .into_iter()
+            .chain(children.iter().map(|(name, ino)| (name.as_os_str(), *ino)));
+        let mut buf = Vec::new();
+        for (i, (name, ino)) in entries.enumerate().skip(offset) {
+            let len = 24 + name.len();
+            if buf.len() + len.next_multiple_of(8) > size {
+                break;
+            }
+            let kind = match self.node(ino) {
+                Some(Node::File { .. }) => libc::DT_REG,
+                _ => libc::DT_DIR,
+            };
+            buf.extend_from_slice(&ino.to_ne_bytes());
+            buf.extend_from_slice(&(i as u64 + 1).to_ne_bytes());
+            buf.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            buf.extend_from_slice(&(kind as u32).to_ne_bytes());
+            buf.extend_from_slice(name.as_bytes());
+            buf.resize(buf.len() + len.next_multiple_of(8) - len, 0);
+        }
+        Ok(buf)
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(buf.get(at..at + 8)?.try_into().ok()?))
+}
+
+// Replies are written at once. Writing fails when the request was
+// interrupted in the meantime, which is fine.
+fn reply(fd: &File, unique: u64, res: Result<&[u8], i32>) {
+    let (error, data) = match res {
+        Ok(data) => (0, data),
+        Err(errno) => (-errno, &[][..]),
+    };
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&((16 + data.len()) as u32).to_ne_bytes());
+    buf.extend_from_slice(&error.to_ne_bytes());
+    buf.extend_from_slice(&unique.to_ne_bytes());
+    buf.extend_from_slice(data);
+    let _ = (&*fd).write(&buf);
+}
+
+struct Filesystem {
+    fd: Arc<File>,
+    tree: Tree,
+    runtime: Handle,
+    // Reads waiting for their pieces, which may be interrupted
+    reads: Arc<Mutex<HashMap<u64, AbortHandle>>>,
+}
+
+impl Filesystem {
+    // Serve requests until unmounted
+    fn run(self) {
+        let mut buf = vec![0; BUFFER_SIZE];
+        loop {
+            let n = match (&*self.fd).read(&mut buf) {
+                Ok(n) => n,
+                // The request was interrupted before being read
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOENT | libc::EINTR)) => continue,
+                Err(_) => return,
+            };
+            let header = &buf[..n];
+            let (Some(opcode), Some(unique), Some(ino)) =
+                (u32_at(header, 4), u64_at(header, 8), u64_at(header, 16))
+            else {
+                continue;
+            };
+            let body = &buf[HEADER_SIZE.min(n)..n];
+            if opcode == FUSE_DESTROY {
+                reply(&self.fd, unique, Ok(&[]));
+                return;
+            }
+            if let Some(res) = self.handle(opcode, unique, ino, body) {
+                reply(&self.fd, unique, res.as_deref().map_err(|&e| e));
+            }
+        }
+    }
+
+    // The reply to a request, none for those without or answered later
+    fn handle(
+        &self,
+        opcode: u32,
+        unique: u64,
+        ino: u64,
+        body: &[u8],
+    ) -> Option<Result<Vec<u8>, i32>> {
+        let res = match opcode {
+            FUSE_INIT => self.init(body),
+            FUSE_LOOKUP => {
+                let name = body.split(|&b| b == 0).next().unwrap_or_default();
+                let child = self.tree.child(ino, OsStr::from_bytes(name));
+                match child.and_then(|child| Some((child, self.tree.node(child)?))) {
+                    Some((child, node)) => Ok(self.tree.entry(child, node)),
+                    None => Err(libc::ENOENT),
+                }
+            }
+            FUSE_GETATTR => match self.tree.node(ino) {
+                Some(node) => {
+                    let mut buf = Vec::with_capacity(104);
+                    buf.extend_from_slice(&TTL.to_ne_bytes());
+                    buf.extend_from_slice(&[0; 8]);
+                    buf.extend_from_slice(&self.tree.attr(ino, node));
+                    Ok(buf)
+                }
+                None => Err(libc::ENOENT),
+            },
+            FUSE_OPEN => match (self.tree.node(ino), u32_at(body, 0)) {
+                (Some(Node::File { .. }), Some(flags))
+                    if flags as i32 & libc::O_ACCMODE == libc::O_RDONLY =>
+                {
+                    Ok(open_out(FOPEN_KEEP_CACHE))
+                }
+                (Some(Node::File { .. }), _) => Err(libc::EROFS),
+                (Some(Node::Dir { .. }), _) => Err(libc::EISDIR),
+                (None, _) => Err(libc::ENOENT),
+            },
+            FUSE_OPENDIR => Ok(open_out(0)),
+            FUSE_READ => return self.read(unique, ino, body).err().map(Err),
+            FUSE_READDIR => match (u64_at(body, 8), u32_at(body, 16)) {
+                (Some(offset), Some(size)) => {
+                    self.tree.read_dir(ino, offset as usize, size as usize)
+                }
+                _ => Err(libc::EINVAL),
+            },
+            FUSE_STATFS => {
+                // fuse_kstatfs, nothing to write to
+                let mut buf = vec![0; 40];
+                for value in [BLOCK_SIZE, 255, BLOCK_SIZE] {
+                    buf.extend_from_slice(&value.to_ne_bytes());
+                }
+                buf.resize(80, 0);
+                Ok(buf)
+            }
+            FUSE_RELEASE | FUSE_RELEASEDIR | FUSE_FLUSH => Ok(Vec::new()),
+            FUSE_INTERRUPT => {
+                let read = u64_at(body, 0)?;
+                let handle = self.reads.lock().unwrap().remove(&read)?;
+                handle.abort();
+                reply(&self.fd, read, Err(libc::EINTR));
+                return None;
+            }
+            FUSE_FORGET | FUSE_BATCH_FORGET => return None,
+            _ => Err(libc::ENOSYS),
+        };
+        Some(res)
+    }
+
+    // fuse_init_out
+    fn init(&self, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let (Some(major), Some(max_readahead)) = (u32_at(body, 0), u32_at(body, 8)) else {
+            return Err(libc::EINVAL);
+        };
+        if major < KERNEL_VERSION {
+            return Err(libc::EPROTO);
+        }
+        let mut buf = Vec::with_capacity(64);
+        for value in [KERNEL_VERSION, KERNEL_MINOR_VERSION, max_readahead, 0] {
+            buf.extend_from_slice(&value.to_ne_bytes());
+        }
+        // Background requests and congestion threshold
+        buf.extend_from_slice(&16u16.to_ne_bytes());
+        buf.extend_from_slice(&12u16.to_ne_bytes());
+        buf.extend_from_slice(&MAX_WRITE.to_ne_bytes());
+        // Granularity of the times, in nanoseconds
+        buf.extend_from_slice(&1u32.to_ne_bytes());
+        buf.resize(64, 0);
+        Ok(buf)
+    }
+
+    // Replied to once the pieces are there, unless interrupted first
+    fn read(&self, unique: u64, ino: u64, body: &[u8]) -> Result<(), i32> {
+        let Some(&Node::File {
+            torrent,
+            file,
+            size,
+        }) = self.tree.node(ino)
+        else {
+            return Err(libc::EISDIR);
+        };
+        let (Some(offset), Some(len)) = (u64_at(body, 8), u32_at(body, 16)) else {
+            return Err(libc::EINVAL);
+        };
+        let len = (len as u64).min(size.saturating_sub(offset)) as usize;
+        let torrent = self.tree.torrents[torrent].clone();
+        let (fd, reads) = (self.fd.clone(), self.reads.clone());
+
+        let mut pending = self.reads.lock().unwrap();
+        let task = self.runtime.spawn(async move {
+            let read = torrent.lock().await.read_range(file, offset, len);
+            let res = read.await;
+            if reads.lock().unwrap().remove(&unique).is_none() {
+                return;
+            }
+            match res {
+                Ok(bytes) => reply(&fd, unique, Ok(&bytes)),
+                Err(e) => reply(&fd, unique, Err(e.raw_os_error().unwrap_or(libc::EIO))),
+            }
+        });
+        pending.insert(unique, task.abort_handle());
+        Ok(())
+    }
+}
+
+// fuse_open_out
+fn open_out(flags: u32) -> Vec<u8> {
+    let mut buf = vec![0; 8];
+    buf.extend_from_slice(&flags.to_ne_bytes());
+    buf.resize(16, 0);
+    buf
+}
+
+/// A mounted filesystem, unmounted when dropped.
+pub struct FuseMount {
+    mountpoint: PathBuf,
+    // Mounted by fusermount3, which unmounts it too
+    fusermount: bool,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Mount the files of `torrents` read only on `mountpoint`, at the paths
+/// they are downloaded to. The torrents must keep stepping for reads to
+/// complete. Requests are served on a thread of their own and reads on the
+/// current runtime.
+pub async fn mount(
+    torrents: Vec<SharedTorrent>,
+    mountpoint: impl AsRef<Path>,
+) -> io::Result<FuseMount> {
+    let mut tree = Tree::new();
+    for (i, torrent) in torrents.iter().enumerate() {
+        let layout = Layout::from_info(&torrent.lock().await.meta().info)?;
+        for (index, file) in layout.files().iter().enumerate() {
+            if !file.attr.padding && file.attr.symlink.is_none() {
+                tree.insert(&file.path, i, index, file.length);
+            }
+        }
+    }
+    tree.torrents = torrents;
+
+    let mountpoint = mountpoint.as_ref().to_path_buf();
+    let (fd, fusermount) = match mount_kernel(&mountpoint, &tree) {
+        Ok(fd) => (fd, false),
+        // Users who may not mount go through the setuid helper of libfuse
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            match mount_fusermount(&mountpoint).await {
+                Ok(fd) => (fd, true),
+                Err(f) if f.kind() == io::ErrorKind::NotFound => return Err(e),
+                Err(f) => return Err(f),
+            }
+        }
+        Err(e) => return Err(e),
+    };
+
+    let fs = Filesystem {
+        fd: Arc::new(fd),
+        tree,
+        runtime: Handle::current(),
+        reads: Arc::default(),
+    };
+    let mut mount = FuseMount {
+        mountpoint,
+        fusermount,
+        thread: None,
+    };
+    mount.thread = Some(
+        thread::Builder::new()
+            .name("fuse".to_string())
+            .spawn(move || fs.run())?,
+    );
+    Ok(mount)
+}
+
+fn mount_kernel(mountpoint: &Path, tree: &Tree) -> io::Result<File> {
+    let fd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")?;
+    let target = CString::new(mountpoint.as_os_str().as_bytes())?;
+    let options = CString::new(format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        fd.as_raw_fd(),
+        tree.uid,
+        tree.gid
+    ))?;
+    let res = unsafe {
+        libc::mount(
+            c"torrent-rs".as_ptr(),
+            target.as_ptr(),
+            c"fuse.torrent-rs".as_ptr(),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+            options.as_ptr().cast(),
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+// fusermount3 opens /dev/fuse, mounts it and sends the descriptor back over
+// the socket named by _FUSE_COMMFD.
+async fn mount_fusermount(mountpoint: &Path) -> io::Result<File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (socket, theirs) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // Only their end is inherited
+    if unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let status = tokio::process::Command::new(FUSERMOUNT)
+        .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+        .args(["-o", FUSERMOUNT_OPTIONS, "--"])
+        .arg(mountpoint)
+        .status()
+        .await?;
+    drop(theirs);
+    if !status.success() {
+        return Err(io::Error::other(format!("{} {}", FUSERMOUNT, status)));
+    }
+    receive_fd(&socket)
+}
+
+fn receive_fd(socket: &OwnedFd) -> io::Result<File> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr().cast(),
+        iov_len: byte.len(),
+    };
+    // u64 for the alignment of the header
+    let mut control = [0u64; 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    if unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    if cmsg.is_null()
+        || unsafe { (*cmsg).cmsg_level } != libc::SOL_SOCKET
+        || unsafe { (*cmsg).cmsg_type } != libc::SCM_RIGHTS
+    {
+        return Err(io::Error::other(format!(
+            "{} sent no descriptor",
+            FUSERMOUNT
+        )));
+    }
+    let fd = unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>()) };
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+impl FuseMount {
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmount, failing while files are open.
+    pub fn unmount(mut self) -> io::Result<()> {
+        self.umount(0)?;
+        // Reading requests fails once unmounted
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+
+    fn umount(&self, flags: libc::c_int) -> io::Result<()> {
+        if self.fusermount {
+            let mut command = std::process::Command::new(FUSERMOUNT);
+            command.arg("-u");
+            if flags & libc::MNT_DETACH != 0 {
+                command.arg("-z");
+            }
+            let status = command.arg("--").arg(&self.mountpoint).status()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("{} -u {}", FUSERMOUNT, status)));
+            }
+            return Ok(());
+        }
+        let target = CString::new(self.mountpoint.as_os_str().as_bytes())?;
+        if unsafe { libc::umount2(target.as_ptr(), flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FuseMount {
+    fn drop(&mut self) {
+        // Lazily, the thread stops once the last file is closed
+        if self.thread.take().is_some() {
+            let _ = self.umount(libc::MNT_DETACH);
+        }
+    }
+}
+
+#[cfg(test)]
+mod fuse_tests {
+    use super::*;
+    use crate::decode_torrent::{self, Info, MetaInfo};
+    use crate::file::FileEntity;
+    use crate::hash::{HashKind, PieceHash};
+    use crate::torrent::Torrent;
+    use sha1::{Digest, Sha1};
+    use std::fs;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    #[tokio::test]
+    #[ignore = "needs /dev/fuse and the right to mount"]
+    async fn mount_and_read() {
+        const FILE: &str = "test_fuse_mount_and_read";
+        const MOUNTPOINT: &str = "test_fuse_mount_and_read_dir";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..2 * PIECE + 100).map(|i| (i % 251) as u8).collect();
+        let hashes: Vec<PieceHash> = data
+            .chunks(PIECE)
+            .map(|chunk| PieceHash::compute(HashKind::Sha1, chunk, PIECE))
+            .collect();
+        fs::write(FILE, &data).unwrap();
+        let mut file = FileEntity::new(FILE, PIECE, data.len()).unwrap();
+        assert_eq!(3, file.recheck(&hashes).await.unwrap());
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces: data
+                    .chunks(PIECE)
+                    .map(|c| decode_torrent::bytes_to_hash(&Sha1::digest(c).into()))
+                    .collect(),
+                name: FILE.to_string(),
+                file_length: data.len().to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let torrent = Arc::new(AsyncMutex::new(Torrent::with_file(meta, [1; 20], file)));
+
+        fs::create_dir_all(MOUNTPOINT).unwrap();
+        let mount = mount(vec![torrent], MOUNTPOINT).await.unwrap();
+        let path = Path::new(MOUNTPOINT).join(FILE);
+        let (names, read) = tokio::task::spawn_blocking(move || {
+            let names: Vec<_> = fs::read_dir(MOUNTPOINT)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            (names, fs::read(path).unwrap())
+        })
+        .await
+        .unwrap();
+        assert_eq!(vec![OsString::from(FILE)], names);
+        assert_eq!(data, read);
+
+        mount.unmount().unwrap();
+        fs::remove_dir(MOUNTPOINT).unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+}
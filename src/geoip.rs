@@ -0,0 +1,436 @@
+// Country and autonomous system of peers, from MaxMind databases
+// (GeoLite2-Country, -City or -ASN) in their binary `.mmdb` format.
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+};
+
+// Precedes the metadata, at the end of the file
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// Data structures deeper than this are taken as corrupt
+const MAX_DEPTH: usize = 32;
+
+/// Where a peer is, as far as the databases know.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerLocation {
+    // ISO 3166-1 code, e.g. `FR`
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    // Organization of the autonomous system
+    pub as_org: Option<String>,
+}
+
+impl PeerLocation {
+    fn merge(&mut self, other: PeerLocation) {
+        self.country = self.country.take().or(other.country);
+        self.asn = self.asn.or(other.asn);
+        self.as_org = self.as_org.take().or(other.as_org);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn be_uint(bytes: &[u8]) -> u128 {
+    bytes.iter().fold(0, |n, &b| (n << 8) | b as u128)
+}
+
+// Decoder of the data section, or of the metadata which uses the same format.
+// Pointers are offsets from the start of `buf`.
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.buf.get(offset..offset.checked_add(len)?)
+    }
+
+    // Value at `offset` and the offset following it
+    fn decode(&self, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let ctrl = *self.buf.get(offset)?;
+        let mut pos = offset + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            // Pointer, followed where it points to
+            let ss = ((ctrl >> 3) & 3) as usize;
+            let vvv = (ctrl & 7) as u128;
+            let extra = self.bytes(pos, ss + 1)?;
+            let target = match ss {
+                0 => (vvv << 8) | be_uint(extra),
+                1 => ((vvv << 16) | be_uint(extra)) + 2048,
+                2 => ((vvv << 24) | be_uint(extra)) + 526_336,
+                _ => be_uint(extra),
+            };
+            let (value, _) = self.decode(usize::try_from(target).ok()?, depth + 1)?;
+            return Some((value, pos + ss + 1));
+        }
+        if kind == 0 {
+            kind = self.buf.get(pos)?.checked_add(7)?;
+            pos += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        if size >= 29 && kind != 14 {
+            let n = size - 28;
+            let extra = be_uint(self.bytes(pos, n)?) as usize;
+            size = match n {
+                1 => 29 + extra,
+                2 => 285 + extra,
+                _ => 65_821 + extra,
+            };
+            pos += n;
+        }
+
+        let value = match kind {
+            2 => Value::String(String::from_utf8_lossy(self.bytes(pos, size)?).into_owned()),
+            3 => Value::Double(f64::from_be_bytes(self.bytes(pos, 8)?.try_into().ok()?)),
+            4 => Value::Bytes(self.bytes(pos, size)?.to_vec()),
+            5 | 6 | 9 | 10 if size <= 16 => Value::Uint(be_uint(self.bytes(pos, size)?)),
+            8 if size <= 4 => Value::Int(be_uint(self.bytes(pos, size)?) as u32 as i32),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    entries.push((key.as_str()?.to_string(), value));
+                    pos = next;
+                }
+                return Some((Value::Map(entries), pos));
+            }
+            11 => {
+                let mut values = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (value, next) = self.decode(pos, depth + 1)?;
+                    values.push(value);
+                    pos = next;
+                }
+                return Some((Value::Array(values), pos));
+            }
+            14 => return Some((Value::Bool(size != 0), pos)),
+            15 => Value::Double(f32::from_be_bytes(self.bytes(pos, 4)?.try_into().ok()?) as f64),
+            _ => return None,
+        };
+        let len = match kind {
+            3 => 8,
+            15 => 4,
+            _ => size,
+        };
+        Some((value, pos + len))
+    }
+}
+
+/// A database loaded in memory.
+#[derive(Debug, Clone)]
+pub struct Database {
+    buf: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    // Node IPv4 addresses start from in an IPv6 database
+    ipv4_start: usize,
+}
+
+impl Database {
+    pub fn from_bytes(buf: Vec<u8>) -> io::Result<Self> {
+        let marker = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or_else(|| invalid("No MaxMind metadata"))?;
+        let start = marker + METADATA_MARKER.len();
+        let metadata = Decoder { buf: &buf[start..] }
+            .decode(0, 0)
+            .map(|(value, _)| value)
+            .ok_or_else(|| invalid("Invalid MaxMind metadata"))?;
+        let field = |key| metadata.get(key).and_then(Value::as_uint);
+        let (Some(node_count), Some(record_size), Some(ip_version)) = (
+            field("node_count"),
+            field("record_size"),
+            field("ip_version"),
+        ) else {
+            return Err(invalid("Incomplete MaxMind metadata"));
+        };
+
+        let node_count = usize::try_from(node_count).map_err(|_| invalid("Too many nodes"))?;
+        let record_size = record_size as usize;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(invalid("Unsupported record size"));
+        }
+        if node_count
+            .checked_mul(record_size / 4)
+            .is_none_or(|size| size > marker)
+        {
+            return Err(invalid("Search tree past the end of the file"));
+        }
+        let mut res = Database {
+            buf,
+            node_count,
+            record_size,
+            ip_version: ip_version as u16,
+            ipv4_start: 0,
+        };
+        if res.ip_version == 6 {
+            for _ in 0..96 {
+                if res.ipv4_start >= node_count {
+                    break;
+                }
+                res.ipv4_start = res.record(res.ipv4_start, false)?;
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Database::from_bytes(fs::read(path)?)
+    }
+
+    fn tree_size(&self) -> usize {
+        self.node_count * self.record_size / 4
+    }
+
+    fn record(&self, node: usize, right: bool) -> io::Result<usize> {
+        let size = self.record_size / 4;
+        let bytes = self
+            .buf
+            .get(node * size..(node + 1) * size)
+            .ok_or_else(|| invalid("Node past the end of the tree"))?;
+        let value = match (self.record_size, right) {
+            (24, false) => be_uint(&bytes[..3]),
+            (24, true) => be_uint(&bytes[3..]),
+            (28, false) => ((bytes[3] as u128 >> 4) << 24) | be_uint(&bytes[..3]),
+            (28, true) => ((bytes[3] as u128 & 0x0f) << 24) | be_uint(&bytes[4..]),
+            (_, false) => be_uint(&bytes[..4]),
+            (_, true) => be_uint(&bytes[4..]),
+        };
+        Ok(value as usize)
+    }
+
+    // Data record of `ip`, `None` if the database has nothing for it
+    fn find(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, mut node): (Vec<bool>, usize) = match (ip.to_canonical(), self.ip_version) {
+            (IpAddr::V4(ip), 4) => (bits(&ip.octets()), 0),
+            (IpAddr::V4(ip), _) => (bits(&ip.octets()), self.ipv4_start),
+            (IpAddr::V6(ip), 6) => (bits(&ip.octets()), 0),
+            (IpAddr::V6(_), _) => return None,
+        };
+        for bit in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, bit).ok()?;
+        }
+        if node <= self.node_count {
+            return None;
+        }
+
+        let data = self.tree_size() + 16;
+        let offset = node - self.node_count - 16;
+        let decoder = Decoder {
+            buf: self.buf.get(data..)?,
+        };
+        decoder.decode(offset, 0).map(|(value, _)| value)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<PeerLocation> {
+        let record = self.find(ip)?;
+        let iso_code = |key| record.get(key)?.get("iso_code")?.as_str();
+        let res = PeerLocation {
+            country: iso_code("country")
+                .or_else(|| iso_code("registered_country"))
+                .map(str::to_string),
+            asn: record
+                .get("autonomous_system_number")
+                .and_then(Value::as_uint)
+                .and_then(|n| u32::try_from(n).ok()),
+            as_org: record
+                .get("autonomous_system_organization")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        };
+        Some(res).filter(|res| *res != PeerLocation::default())
+    }
+}
+
+fn bits(octets: &[u8]) -> Vec<bool> {
+    octets
+        .iter()
+        .flat_map(|b| (0..8).rev().map(move |i| b & (1 << i) != 0))
+        .collect()
+}
+
+/// Databases looked up together, e.g. a country one and an ASN one.
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    databases: Vec<Database>,
+}
+
+impl GeoIp {
+    pub fn new(databases: Vec<Database>) -> Self {
+        GeoIp { databases }
+    }
+
+    pub fn open_all<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        let databases = paths
+            .iter()
+            .map(Database::open)
+            .collect::<io::Result<_>>()?;
+        Ok(GeoIp::new(databases))
+    }
+
+    /// What the databases know of `ip`, the first one saying wins.
+    pub fn lookup(&self, ip: IpAddr) -> Option<PeerLocation> {
+        let mut res: Option<PeerLocation> = None;
+        for location in self.databases.iter().filter_map(|db| db.lookup(ip)) {
+            match &mut res {
+                Some(res) => res.merge(location),
+                None => res = Some(location),
+            }
+        }
+        res
+    }
+
+    pub fn lookup_addr(&self, addr: SocketAddr) -> Option<PeerLocation> {
+        self.lookup(addr.ip())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod geoip_tests {
+    use super::*;
+
+    fn control(kind: u8, size: usize, out: &mut Vec<u8>) {
+        assert!(size < 29);
+        match kind {
+            1..=7 => out.push((kind << 5) | size as u8),
+            _ => out.extend_from_slice(&[size as u8, kind - 7]),
+        }
+    }
+
+    fn string(s: &str, out: &mut Vec<u8>) {
+        control(2, s.len(), out);
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    fn uint32(n: u32, out: &mut Vec<u8>) {
+        control(6, 4, out);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+
+    /// IPv4 database of a single /8 network in the format of MaxMind's, with
+    /// both a country and an autonomous system.
+    pub(crate) fn database(first_octet: u8, country: &str, asn: u32) -> Vec<u8> {
+        const NODES: usize = 8;
+        let mut data = Vec::new();
+        control(7, 2, &mut data);
+        string("country", &mut data);
+        control(7, 1, &mut data);
+        string("iso_code", &mut data);
+        string(country, &mut data);
+        string("autonomous_system_number", &mut data);
+        uint32(asn, &mut data);
+
+        // One node per bit of the first octet, the other branches are empty
+        let mut buf = Vec::new();
+        for i in 0..NODES {
+            let bit = first_octet & (0x80 >> i) != 0;
+            let next = match i + 1 {
+                NODES => NODES + 16,
+                n => n,
+            };
+            let (left, right) = match bit {
+                true => (NODES, next),
+                false => (next, NODES),
+            };
+            buf.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+            buf.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        }
+        buf.extend_from_slice(&[0; 16]);
+        buf.extend_from_slice(&data);
+
+        buf.extend_from_slice(METADATA_MARKER);
+        control(7, 3, &mut buf);
+        string("node_count", &mut buf);
+        uint32(NODES as u32, &mut buf);
+        string("record_size", &mut buf);
+        control(5, 1, &mut buf);
+        buf.push(24);
+        string("ip_version", &mut buf);
+        control(5, 1, &mut buf);
+        buf.push(4);
+        buf
+    }
+
+    #[test]
+    fn lookup() {
+        let db = Database::from_bytes(database(10, "FR", 3215)).unwrap();
+        let expected = PeerLocation {
+            country: Some("FR".to_string()),
+            asn: Some(3215),
+            as_org: None,
+        };
+        assert_eq!(
+            Some(expected.clone()),
+            db.lookup("10.1.2.3".parse().unwrap())
+        );
+        assert_eq!(
+            Some(expected),
+            db.lookup("::ffff:10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(None, db.lookup("11.1.2.3".parse().unwrap()));
+        assert_eq!(None, db.lookup("2001:db8::1".parse().unwrap()));
+
+        let geoip = GeoIp::new(vec![
+            db,
+            Database::from_bytes(database(11, "DE", 1)).unwrap(),
+        ]);
+        let location = geoip.lookup("11.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(Some("DE"), location.country.as_deref());
+
+        assert!(Database::from_bytes(b"not a database".to_vec()).is_err());
+        let mut truncated = database(10, "FR", 1);
+        truncated.pop();
+        assert!(Database::from_bytes(truncated).is_err());
+    }
+}
@@ -1,6 +1,5 @@
 use crate::definitions::*;
-
-use std::error::Error;
+use crate::peer::PeerError;
 
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
@@ -8,9 +7,13 @@ use tokio::net::TcpStream;
 const PSTR: &[u8; 19] = b"BitTorrent protocol";
 const PSTR_LEN: usize = 19;
 const RESERVED_LEN: usize = 8;
-const HANDSHAKE_SIZE: usize = 1 + PSTR_LEN + RESERVED_LEN + INFO_HASH_LEN + PEER_ID_LEN;
+pub const HANDSHAKE_SIZE: usize = 1 + PSTR_LEN + RESERVED_LEN + INFO_HASH_LEN + PEER_ID_LEN;
+
+// Bit 20 counted from the right of the reserved bytes, see BEP 10
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Handshake {
     pstr_len: u8,
@@ -22,12 +25,16 @@ pub struct Handshake {
 
 impl Default for Handshake {
     fn default() -> Self {
+        let mut reserved = [0; RESERVED_LEN];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+
         Handshake {
             pstr_len: PSTR_LEN as u8,
             protocol: *PSTR,
-            reserved: [0; RESERVED_LEN],
+            reserved,
             info_hash: [0; INFO_HASH_LEN],
-            peer_id: *TORRENT_RS_PEER_ID,
+            // Set by the session or the torrent sending it
+            peer_id: [0; PEER_ID_LEN],
         }
     }
 }
@@ -49,6 +56,10 @@ impl Handshake {
         self.info_hash = *hash;
     }
 
+    pub fn set_peer_id(&mut self, peer_id: &PeerId) {
+        self.peer_id = *peer_id;
+    }
+
     pub fn get_hash(&self) -> &InfoHash {
         &self.info_hash
     }
@@ -57,19 +68,47 @@ impl Handshake {
         &self.peer_id
     }
 
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     // TODO: look for a more idiomatic / effective method
     pub fn to_bytes(self) -> [u8; HANDSHAKE_SIZE] {
         use std::mem;
         unsafe { mem::transmute(self) }
     }
 
-    pub async fn send(self, stream: &mut TcpStream) -> Result<Self, Box<dyn Error>> {
+    pub async fn send(self, stream: &mut TcpStream) -> Result<Self, PeerError> {
         let mut data = self.to_bytes();
 
         stream.write_all(&data).await?;
-        stream.read(&mut data).await?;
+        stream.read_exact(&mut data).await?;
+
+        let hs = Handshake::new(&data);
+        if !is_header_valid(&hs) {
+            return Err(PeerError::InvalidHandshake);
+        }
 
-        Ok(Handshake::new(&data))
+        Ok(hs)
+    }
+
+    /// Read the handshake of a peer which connected to us. The caller is
+    /// expected to answer with its own handshake using [`Handshake::reply`].
+    pub async fn receive(stream: &mut TcpStream) -> Result<Self, PeerError> {
+        let mut data = [0u8; HANDSHAKE_SIZE];
+        stream.read_exact(&mut data).await?;
+
+        let hs = Handshake::new(&data);
+        if !is_header_valid(&hs) {
+            return Err(PeerError::InvalidHandshake);
+        }
+
+        Ok(hs)
+    }
+
+    pub async fn reply(self, stream: &mut TcpStream) -> Result<(), PeerError> {
+        stream.write_all(&self.to_bytes()).await?;
+        Ok(())
     }
 }
 
@@ -108,6 +147,17 @@ mod handshake_tests {
         assert!(is_header_valid(&hs));
     }
 
+    #[test]
+    fn extension_protocol_bit() {
+        let bytes = Handshake::default().to_bytes();
+        assert_eq!(0x10, bytes[25]);
+        assert!(Handshake::new(&bytes).supports_extension_protocol());
+
+        let mut bytes = bytes;
+        bytes[25] = 0;
+        assert!(!Handshake::new(&bytes).supports_extension_protocol());
+    }
+
     #[test]
     fn handshake_to_bytes_to_handshake() {
         let bytes = Handshake::default().to_bytes();
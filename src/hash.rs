@@ -0,0 +1,361 @@
+// Piece hashes of v1 (SHA-1) and v2 (SHA-256 merkle, BEP 52) torrents
+use std::{io, sync::Arc};
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+
+use crate::definitions::{InfoHash, BLOCK_SIZE};
+
+pub type Sha256Hash = [u8; 32];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashKind {
+    Sha1,
+    Sha256,
+}
+
+/// Expected hash of a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    // SHA-1 of the whole piece
+    V1(InfoHash),
+    // Root of the merkle tree over the SHA-256 of the 16 KiB blocks
+    V2(Sha256Hash),
+}
+
+impl From<InfoHash> for PieceHash {
+    fn from(hash: InfoHash) -> Self {
+        PieceHash::V1(hash)
+    }
+}
+
+impl From<Sha256Hash> for PieceHash {
+    fn from(hash: Sha256Hash) -> Self {
+        PieceHash::V2(hash)
+    }
+}
+
+impl PieceHash {
+    pub fn kind(&self) -> HashKind {
+        match self {
+            PieceHash::V1(_) => HashKind::Sha1,
+            PieceHash::V2(_) => HashKind::Sha256,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            PieceHash::V1(h) => h,
+            PieceHash::V2(h) => h,
+        }
+    }
+
+    /// Hash `data`, a piece of `piece_size` bytes or the shorter last one.
+    pub fn compute(kind: HashKind, data: &[u8], piece_size: usize) -> Self {
+        match kind {
+            HashKind::Sha1 => PieceHash::V1(Sha1::digest(data).into()),
+            HashKind::Sha256 => {
+                let leaves = leaf_hashes(data);
+                PieceHash::V2(merkle_root(&leaves, piece_leaves(piece_size)))
+            }
+        }
+    }
+
+    pub fn matches(&self, data: &[u8], piece_size: usize) -> bool {
+        match self {
+            PieceHash::V1(_) => PieceHash::compute(HashKind::Sha1, data, piece_size) == *self,
+            PieceHash::V2(expected) => v2_matches(&leaf_hashes(data), piece_size, expected),
+        }
+    }
+}
+
+// Files of at most one piece have no piece layer, they are checked against
+// their root whose tree is only as wide as the file
+fn v2_matches(leaves: &[Sha256Hash], piece_size: usize, expected: &Sha256Hash) -> bool {
+    merkle_root(leaves, piece_leaves(piece_size)) == *expected
+        || merkle_root(leaves, leaves.len().next_power_of_two()) == *expected
+}
+
+/// Hash of a piece fed chunk by chunk, so that pieces of tens of megabytes
+/// can be checked without holding them in memory.
+#[derive(Debug, Clone)]
+pub struct PieceHasher {
+    piece_size: usize,
+    state: HasherState,
+}
+
+#[derive(Debug, Clone)]
+enum HasherState {
+    Sha1(Sha1),
+    Sha256 {
+        leaves: Vec<Sha256Hash>,
+        // Current 16 KiB block and the bytes it has so far
+        block: Sha256,
+        filled: usize,
+    },
+}
+
+impl PieceHasher {
+    pub fn new(kind: HashKind, piece_size: usize) -> Self {
+        let state = match kind {
+            HashKind::Sha1 => HasherState::Sha1(Sha1::new()),
+            HashKind::Sha256 => HasherState::Sha256 {
+                leaves: Vec::new(),
+                block: Sha256::new(),
+                filled: 0,
+            },
+        };
+        PieceHasher { piece_size, state }
+    }
+
+    /// Feed the next bytes of the piece, of any length.
+    pub fn update(&mut self, mut data: &[u8]) {
+        match &mut self.state {
+            HasherState::Sha1(hasher) => hasher.update(data),
+            HasherState::Sha256 {
+                leaves,
+                block,
+                filled,
+            } => {
+                while !data.is_empty() {
+                    let n = data.len().min(BLOCK_SIZE - *filled);
+                    block.update(&data[..n]);
+                    *filled += n;
+                    data = &data[n..];
+                    if *filled == BLOCK_SIZE {
+                        leaves.push(std::mem::take(block).finalize().into());
+                        *filled = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    fn into_leaves(self) -> Vec<Sha256Hash> {
+        match self.state {
+            HasherState::Sha1(_) => unreachable!(),
+            HasherState::Sha256 {
+                mut leaves,
+                block,
+                filled,
+            } => {
+                if filled > 0 {
+                    leaves.push(block.finalize().into());
+                }
+                leaves
+            }
+        }
+    }
+
+    pub fn finish(self) -> PieceHash {
+        match self.state {
+            HasherState::Sha1(hasher) => PieceHash::V1(hasher.finalize().into()),
+            HasherState::Sha256 { .. } => {
+                let width = piece_leaves(self.piece_size);
+                PieceHash::V2(merkle_root(&self.into_leaves(), width))
+            }
+        }
+    }
+
+    /// Same as [`PieceHash::matches`] on the data fed so far.
+    pub fn matches(self, expected: &PieceHash) -> bool {
+        match (&self.state, expected) {
+            (HasherState::Sha256 { .. }, PieceHash::V2(root)) => {
+                let piece_size = self.piece_size;
+                v2_matches(&self.into_leaves(), piece_size, root)
+            }
+            _ => self.finish() == *expected,
+        }
+    }
+}
+
+/// Hashes pieces on tokio's blocking pool, so that hashing megabytes doesn't
+/// stall the tasks of a runtime worker. At most `max_concurrent` pieces are
+/// hashed at once, clones share the limit.
+#[derive(Debug, Clone)]
+pub struct HashPool {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for HashPool {
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        HashPool::new(cores)
+    }
+}
+
+impl HashPool {
+    pub fn new(max_concurrent: usize) -> Self {
+        assert!(max_concurrent > 0);
+        HashPool {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    /// Run `f` on the data of a piece in the blocking pool. The data is moved
+    /// there and handed back.
+    async fn run<T, F>(&self, data: Vec<u8>, f: F) -> io::Result<(Vec<u8>, T)>
+    where
+        T: Send + 'static,
+        F: FnOnce(&[u8]) -> T + Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.map_err(io::Error::other)?;
+        tokio::task::spawn_blocking(move || {
+            let res = f(&data);
+            (data, res)
+        })
+        .await
+        .map_err(io::Error::other)
+    }
+
+    pub async fn compute(
+        &self,
+        kind: HashKind,
+        data: Vec<u8>,
+        piece_size: usize,
+    ) -> io::Result<(Vec<u8>, PieceHash)> {
+        self.run(data, move |d| PieceHash::compute(kind, d, piece_size))
+            .await
+    }
+
+    pub async fn matches(
+        &self,
+        expected: PieceHash,
+        data: Vec<u8>,
+        piece_size: usize,
+    ) -> io::Result<(Vec<u8>, bool)> {
+        self.run(data, move |d| expected.matches(d, piece_size))
+            .await
+    }
+}
+
+fn piece_leaves(piece_size: usize) -> usize {
+    piece_size.div_ceil(BLOCK_SIZE).next_power_of_two()
+}
+
+/// SHA-256 of every 16 KiB block, the last one may be shorter.
+pub fn leaf_hashes(data: &[u8]) -> Vec<Sha256Hash> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+/// Root of a merkle tree `width` leaves wide, missing leaves are zeros.
+pub fn merkle_root(leaves: &[Sha256Hash], width: usize) -> Sha256Hash {
+    assert!(width.is_power_of_two() && leaves.len() <= width);
+
+    let mut layer = leaves.to_vec();
+    layer.resize(width, [0; 32]);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| {
+                let mut hasher = Sha256::new();
+                hasher.update(pair[0]);
+                hasher.update(pair[1]);
+                hasher.finalize().into()
+            })
+            .collect();
+    }
+
+    layer[0]
+}
+
+#[cfg(test)]
+mod hash_tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> Sha256Hash {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn merkle() {
+        let a = sha256(b"a");
+        let b = sha256(b"b");
+        assert_eq!(a, merkle_root(&[a], 1));
+
+        let ab = sha256(&[a, b].concat());
+        assert_eq!(ab, merkle_root(&[a, b], 2));
+
+        // Missing leaves are zero hashes
+        let zeros = sha256(&[[0; 32], [0; 32]].concat());
+        assert_eq!(sha256(&[ab, zeros].concat()), merkle_root(&[a, b], 4));
+        let a0 = sha256(&[a, [0; 32]].concat());
+        assert_eq!(sha256(&[a0, zeros].concat()), merkle_root(&[a], 4));
+    }
+
+    #[test]
+    fn v2_piece() {
+        const PSIZE: usize = 4 * BLOCK_SIZE;
+        let data = vec![1u8; PSIZE];
+
+        let hash = PieceHash::compute(HashKind::Sha256, &data, PSIZE);
+        assert_eq!(HashKind::Sha256, hash.kind());
+        assert!(hash.matches(&data, PSIZE));
+        assert!(!hash.matches(&data[1..], PSIZE));
+
+        // The last piece of a file is padded to the piece width
+        let short = &data[..BLOCK_SIZE + 10];
+        let hash = PieceHash::compute(HashKind::Sha256, short, PSIZE);
+        let leaves = leaf_hashes(short);
+        assert_eq!(PieceHash::V2(merkle_root(&leaves, 4)), hash);
+
+        // A single piece file is checked against its own root
+        let root = merkle_root(&leaves, 2);
+        assert!(PieceHash::V2(root).matches(short, PSIZE));
+    }
+
+    #[tokio::test]
+    async fn hash_pool() {
+        let pool = HashPool::new(2);
+        let data = vec![3u8; 100];
+        let expected = PieceHash::compute(HashKind::Sha1, &data, 128);
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                let data = data.clone();
+                tokio::spawn(async move { pool.matches(expected, data, 128).await.unwrap() })
+            })
+            .collect();
+        for task in tasks {
+            let (back, valid) = task.await.unwrap();
+            assert!(valid);
+            assert_eq!(data, back);
+        }
+
+        let (_, hash) = pool.compute(HashKind::Sha1, data, 128).await.unwrap();
+        assert_eq!(expected, hash);
+    }
+
+    #[test]
+    fn chunked() {
+        const PSIZE: usize = 4 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..2 * BLOCK_SIZE + 100).map(|x| x as u8).collect();
+
+        for kind in [HashKind::Sha1, HashKind::Sha256] {
+            let expected = PieceHash::compute(kind, &data, PSIZE);
+            // Chunks not aligned on blocks
+            let mut hasher = PieceHasher::new(kind, PSIZE);
+            data.chunks(1000).for_each(|c| hasher.update(c));
+            assert_eq!(expected, hasher.clone().finish());
+            assert!(hasher.matches(&expected));
+        }
+
+        // A single piece file against its own root
+        let leaves = leaf_hashes(&data);
+        let mut hasher = PieceHasher::new(HashKind::Sha256, 2 * PSIZE);
+        hasher.update(&data);
+        assert!(hasher.matches(&PieceHash::V2(merkle_root(&leaves, 4))));
+    }
+
+    #[test]
+    fn v1_piece() {
+        let hash = PieceHash::compute(HashKind::Sha1, b"abc", 16);
+        assert_eq!(20, hash.as_bytes().len());
+        assert!(hash.matches(b"abc", 16));
+        assert!(!hash.matches(b"abd", 16));
+    }
+}
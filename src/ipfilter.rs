@@ -0,0 +1,316 @@
+// Blocking of peer addresses from blocklists, checked by the dialer and the
+// listener. Lists are plain CIDRs, PeerGuardian `.p2p` or eMule `.dat`, and
+// can be reloaded while the session runs.
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{self, Duration},
+};
+
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+// eMule `.dat` entries with a lower access level are blocked
+const DAT_BLOCK_LEVEL: u32 = 128;
+
+// Addresses as IPv6, IPv4 ones mapped, so that both are in the same ranges
+fn to_u128(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u128::from(ip.to_ipv6_mapped()),
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    // eMule lists pad with zeros, e.g. `001.002.003.004`
+    let octets: Vec<u8> = s
+        .split('.')
+        .map(|o| o.parse().ok())
+        .collect::<Option<_>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    Some(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let (start, end) = s.split_once('-')?;
+    Some((parse_ip(start)?, parse_ip(end)?))
+}
+
+fn parse_cidr(s: &str) -> Option<(IpAddr, IpAddr)> {
+    let (ip, prefix) = match s.split_once('/') {
+        Some((ip, prefix)) => (parse_ip(ip)?, Some(prefix.trim().parse::<u32>().ok()?)),
+        None => (parse_ip(s)?, None),
+    };
+    let (value, bits) = match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+        IpAddr::V6(ip) => (u128::from(ip), 128),
+    };
+    let prefix = prefix.unwrap_or(bits);
+    if prefix > bits {
+        return None;
+    }
+    let host_mask = match bits - prefix {
+        128 => u128::MAX,
+        n => (1u128 << n) - 1,
+    };
+    let (start, end) = (value & !host_mask, value | host_mask);
+    Some(match ip {
+        IpAddr::V4(_) => (
+            IpAddr::V4(Ipv4Addr::from(start as u32)),
+            IpAddr::V4(Ipv4Addr::from(end as u32)),
+        ),
+        IpAddr::V6(_) => (
+            IpAddr::V6(Ipv6Addr::from(start)),
+            IpAddr::V6(Ipv6Addr::from(end)),
+        ),
+    })
+}
+
+// Range of a line of any of the formats. `Ok(None)` for the ranges a `.dat`
+// list allows.
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, ()> {
+    // eMule: `start - end , level , description`
+    let mut fields = line.split(',');
+    if let (Some(range), Some(level)) = (fields.next(), fields.next()) {
+        if let (Some(range), Ok(level)) = (parse_range(range), level.trim().parse::<u32>()) {
+            return Ok((level < DAT_BLOCK_LEVEL).then_some(range));
+        }
+    }
+    // PeerGuardian: `description:start-end`, the description may hold colons
+    if let Some(range) = line.rsplit_once(':').and_then(|(_, r)| parse_range(r)) {
+        return Ok(Some(range));
+    }
+    parse_range(line)
+        .or_else(|| parse_cidr(line))
+        .map(Some)
+        .ok_or(())
+}
+
+/// Sorted, non-overlapping ranges of blocked addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    ranges: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    // Sort and merge overlapping or adjacent ranges
+    fn normalize(&mut self) {
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(u128, u128)> = Vec::with_capacity(self.ranges.len());
+        for &(start, end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        self.ranges = merged;
+    }
+
+    /// Block `start` to `end` included. Returns `false`, blocking nothing,
+    /// if they aren't of the same family or in order.
+    pub fn add_range(&mut self, start: IpAddr, end: IpAddr) -> bool {
+        if start.is_ipv4() != end.is_ipv4() || to_u128(start) > to_u128(end) {
+            return false;
+        }
+        self.ranges.push((to_u128(start), to_u128(end)));
+        self.normalize();
+        true
+    }
+
+    /// Block the addresses of another filter too.
+    pub fn extend(&mut self, other: &IpFilter) {
+        self.ranges.extend_from_slice(&other.ranges);
+        self.normalize();
+    }
+
+    /// Parse a blocklist, one range per line in any of the formats. Blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut res = IpFilter::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid blocklist line {}", n + 1),
+                )
+            };
+            match parse_line(line).map_err(|_| invalid())? {
+                Some((start, end)) if start.is_ipv4() == end.is_ipv4() => {
+                    let (start, end) = (to_u128(start), to_u128(end));
+                    res.ranges.push((start.min(end), start.max(end)));
+                }
+                Some(_) => return Err(invalid()),
+                None => (),
+            }
+        }
+        res.normalize();
+        Ok(res)
+    }
+
+    /// Read a blocklist file. Descriptions which aren't UTF-8, common in
+    /// old lists, don't matter.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        IpFilter::parse(&String::from_utf8_lossy(&fs::read(path)?))
+    }
+
+    /// Union of several blocklist files.
+    pub fn load_all<P: AsRef<Path>>(paths: &[P]) -> io::Result<Self> {
+        let mut res = IpFilter::new();
+        for path in paths {
+            res.extend(&IpFilter::load(path)?);
+        }
+        Ok(res)
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        // IPv4 peers may show up mapped, e.g. on a dual-stack socket
+        let ip = to_u128(ip.to_canonical());
+        let i = self.ranges.partition_point(|&(start, _)| start <= ip);
+        i > 0 && self.ranges[i - 1].1 >= ip
+    }
+
+    /// Number of ranges once merged.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// A filter shared by the dialer and the listener, replaced as a whole when
+/// the blocklists change. Clones share the filter.
+#[derive(Debug, Clone, Default)]
+pub struct SharedIpFilter(Arc<RwLock<Arc<IpFilter>>>);
+
+impl SharedIpFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        SharedIpFilter(Arc::new(RwLock::new(Arc::new(filter))))
+    }
+
+    pub fn get(&self) -> Arc<IpFilter> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, filter: IpFilter) {
+        *self.0.write().unwrap() = Arc::new(filter);
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.0.read().unwrap().is_blocked(ip)
+    }
+
+    /// Load `paths` now, then reload them whenever one of them is modified,
+    /// checking every `interval`. A list which fails to load leaves the
+    /// current filter in place until it is fixed.
+    pub fn watch(&self, paths: Vec<PathBuf>, interval: Duration) -> io::Result<JoinHandle<()>> {
+        let modified = |paths: &[PathBuf]| -> Vec<Option<SystemTime>> {
+            paths
+                .iter()
+                .map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+                .collect()
+        };
+        let mut last = modified(&paths);
+        self.set(IpFilter::load_all(&paths)?);
+
+        let filter = self.clone();
+        Ok(tokio::spawn(async move {
+            loop {
+                time::sleep(interval).await;
+                let current = modified(&paths);
+                if current == last {
+                    continue;
+                }
+                if let Ok(new) = IpFilter::load_all(&paths) {
+                    filter.set(new);
+                    last = current;
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod ipfilter_tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let text = "\
+# Comment
+10.0.0.0/8
+192.168.1.7
+2001:db8::/32
+Some org, with: colons:1.2.3.0-1.2.3.255
+001.002.004.000 - 001.002.004.255 , 000 , Bad
+005.000.000.000 - 005.255.255.255 , 200 , Allowed
+";
+        let filter = IpFilter::parse(text).unwrap();
+        assert!(filter.is_blocked(ip("10.1.2.3")));
+        assert!(filter.is_blocked(ip("192.168.1.7")));
+        assert!(!filter.is_blocked(ip("192.168.1.8")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(!filter.is_blocked(ip("2001:db9::1")));
+        assert!(filter.is_blocked(ip("::ffff:10.0.0.1")));
+        assert!(!filter.is_blocked(ip("::a00:1")));
+        // Adjacent ranges are merged
+        assert!(filter.is_blocked(ip("1.2.3.0")));
+        assert!(filter.is_blocked(ip("1.2.4.255")));
+        assert!(!filter.is_blocked(ip("1.2.5.0")));
+        assert!(!filter.is_blocked(ip("5.1.1.1")));
+        assert_eq!(4, filter.len());
+
+        let err = IpFilter::parse("10.0.0.0/8\nnot an address\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert!(IpFilter::parse("10.0.0.0/33").is_err());
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        const FILE: &str = "./test_ipfilter_reload.p2p";
+        fs::write(FILE, "A:1.1.1.1-1.1.1.1\n").unwrap();
+        let filter = SharedIpFilter::default();
+        let watch = filter
+            .watch(vec![PathBuf::from(FILE)], Duration::from_millis(20))
+            .unwrap();
+        assert!(filter.is_blocked(ip("1.1.1.1")));
+
+        // Modification times may be as coarse as a second
+        time::sleep(Duration::from_millis(1100)).await;
+        fs::write(FILE, "B:2.2.2.2-2.2.2.2\n").unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!filter.is_blocked(ip("1.1.1.1")));
+        assert!(filter.is_blocked(ip("2.2.2.2")));
+
+        // A broken list keeps the previous filter
+        time::sleep(Duration::from_millis(1100)).await;
+        fs::write(FILE, "garbage\n").unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(filter.is_blocked(ip("2.2.2.2")));
+
+        watch.abort();
+        fs::remove_file(FILE).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+// Write-ahead journal of the pieces written to a file. Before pieces are
+// written an intent record is synced, and once the file itself is synced the
+// verified ones get a commit record. After a crash committed pieces are known
+// to be on disk and only pieces with a pending intent need to be hashed again.
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::storage;
+
+pub const JOURNAL_EXTENSION: &str = "journal";
+
+// Record kind, followed by the piece index as a big endian u32
+const INTENT: u8 = b'W';
+const COMMIT: u8 = b'C';
+const RECORD_LEN: usize = 5;
+
+/// What the journal knows about a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PieceState {
+    // Never written
+    #[default]
+    Untouched,
+    // Written, maybe partially, and not verified since
+    Uncertain,
+    // Verified and synced to disk
+    Committed,
+}
+
+#[derive(Debug)]
+pub struct PieceJournal {
+    path: PathBuf,
+    file: File,
+    states: Vec<PieceState>,
+    // There was no journal before, it says nothing about the file yet
+    new: bool,
+    // Verified pieces waiting for the data to be synced
+    pending: Vec<usize>,
+}
+
+/// Path of the journal for the data at `path`.
+pub fn journal_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut res = path.as_ref().as_os_str().to_owned();
+    res.push(".");
+    res.push(JOURNAL_EXTENSION);
+    res.into()
+}
+
+fn record(kind: u8, index: usize) -> [u8; RECORD_LEN] {
+    let mut res = [kind, 0, 0, 0, 0];
+    res[1..].copy_from_slice(&(index as u32).to_be_bytes());
+    res
+}
+
+impl PieceJournal {
+    /// Open the journal at `path`, replaying what it holds. The journal is
+    /// then rewritten with one record per touched piece, so it doesn't grow
+    /// across restarts. A record torn by a crash is ignored.
+    pub fn open<P: AsRef<Path>>(path: P, num_pieces: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut states = vec![PieceState::Untouched; num_pieces];
+        let new = match fs::read(&path) {
+            Ok(bytes) => {
+                for rec in bytes.chunks_exact(RECORD_LEN) {
+                    let index = u32::from_be_bytes(rec[1..].try_into().unwrap()) as usize;
+                    let state = match rec[0] {
+                        INTENT => PieceState::Uncertain,
+                        COMMIT => PieceState::Committed,
+                        _ => continue,
+                    };
+                    if let Some(s) = states.get_mut(index) {
+                        *s = state;
+                    }
+                }
+                false
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        };
+
+        let mut compacted = Vec::new();
+        for (index, state) in states.iter().enumerate() {
+            match state {
+                PieceState::Untouched => (),
+                PieceState::Uncertain => compacted.extend(record(INTENT, index)),
+                PieceState::Committed => compacted.extend(record(COMMIT, index)),
+            }
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&compacted)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(PieceJournal {
+            path,
+            file,
+            states,
+            new,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Open the journal at `path` and forget what it holds, for a new file.
+    pub fn create<P: AsRef<Path>>(path: P, num_pieces: usize) -> io::Result<Self> {
+        match fs::remove_file(path.as_ref()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        PieceJournal::open(path, num_pieces)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the journal along with its file.
+    pub fn move_to<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_owned();
+        storage::move_file(&self.path, &path)?;
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        self.path = path;
+        Ok(())
+    }
+
+    /// The journal didn't exist when opened.
+    pub fn is_new(&self) -> bool {
+        self.new
+    }
+
+    pub fn state(&self, index: usize) -> PieceState {
+        self.states[index]
+    }
+
+    pub fn states(&self) -> &[PieceState] {
+        &self.states
+    }
+
+    /// Record that `pieces` are about to be written. The records are synced
+    /// before returning, the writes can be issued afterwards.
+    pub fn intent<I: IntoIterator<Item = usize>>(&mut self, pieces: I) -> io::Result<()> {
+        let mut records = Vec::new();
+        for index in pieces {
+            // A committed piece is unchanged when written again
+            if self.states[index] == PieceState::Untouched {
+                self.states[index] = PieceState::Uncertain;
+                records.extend(record(INTENT, index));
+            }
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()
+    }
+
+    /// The piece was written and verified, it is committed by the next call
+    /// to [`PieceJournal::commit_pending`].
+    pub fn verified(&mut self, index: usize) {
+        if self.states[index] != PieceState::Committed {
+            self.pending.push(index);
+        }
+    }
+
+    /// To be called once the data file was synced.
+    pub fn commit_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::new();
+        for index in self.pending.drain(..) {
+            self.states[index] = PieceState::Committed;
+            records.extend(record(COMMIT, index));
+        }
+        self.file.write_all(&records)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        const FILE: &str = "./test_journal_replay.journal";
+        let _ = fs::remove_file(FILE);
+
+        let mut journal = PieceJournal::open(FILE, 4).unwrap();
+        assert!(journal.is_new());
+        journal.intent([0, 1, 2]).unwrap();
+        journal.verified(0);
+        journal.verified(2);
+        // The data isn't synced yet, nothing is committed
+        drop(journal);
+
+        let mut journal = PieceJournal::open(FILE, 4).unwrap();
+        assert!(!journal.is_new());
+        use PieceState::*;
+        assert_eq!(
+            &[Uncertain, Uncertain, Uncertain, Untouched],
+            journal.states()
+        );
+        journal.verified(0);
+        journal.commit_pending().unwrap();
+        drop(journal);
+
+        // A torn record is ignored
+        let mut bytes = fs::read(FILE).unwrap();
+        assert_eq!(4 * RECORD_LEN, bytes.len());
+        bytes.extend([INTENT, 0, 0]);
+        fs::write(FILE, bytes).unwrap();
+
+        let journal = PieceJournal::open(FILE, 4).unwrap();
+        assert_eq!(
+            &[Committed, Uncertain, Uncertain, Untouched],
+            journal.states()
+        );
+        assert_eq!(3 * RECORD_LEN, fs::metadata(FILE).unwrap().len() as usize);
+
+        let journal = PieceJournal::create(FILE, 4).unwrap();
+        assert_eq!(&[Untouched; 4], journal.states());
+
+        drop(journal);
+        fs::remove_file(FILE).unwrap();
+    }
+}
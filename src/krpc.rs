@@ -0,0 +1,776 @@
+// KRPC, the bencoded messages of the DHT over UDP, see
+// http://bittorrent.org/beps/bep_0005.html#krpc-protocol
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use bendy::{
+    decoding::{DictDecoder, Error, FromBencode, Object},
+    encoding::{SingleItemEncoder, SortedDictEncoder, ToBencode},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::definitions::InfoHash;
+use crate::dht_item::{Item, Mutable, PublicKey, Value, MAX_VALUE_DEPTH};
+use crate::routing::{NodeId, NodeInfo, NODE_ID_LEN};
+
+// Codes of error messages
+pub const GENERIC_ERROR: i64 = 201;
+pub const SERVER_ERROR: i64 = 202;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+// Of the storage of items, see BEP 44
+pub const VALUE_TOO_BIG: i64 = 205;
+pub const INVALID_SIGNATURE: i64 = 206;
+pub const SALT_TOO_BIG: i64 = 207;
+pub const CAS_MISMATCH: i64 = 301;
+pub const SEQ_TOO_LOW: i64 = 302;
+
+const COMPACT_NODE_LEN: usize = NODE_ID_LEN + 6;
+pub const BLOOM_FILTER_LEN: usize = 256;
+const BLOOM_FILTER_BITS: usize = BLOOM_FILTER_LEN * 8;
+
+/// What a node asks of another, along with its ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    // With `scrape` the swarm is answered as bloom filters, with `noseed`
+    // the values are only downloaders, see BEP 33
+    GetPeers {
+        info_hash: InfoHash,
+        scrape: bool,
+        noseed: bool,
+    },
+    // With `implied_port` the peer is on the port the query came from
+    AnnouncePeer {
+        info_hash: InfoHash,
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+        seed: bool,
+    },
+    // Some of the infohashes stored by the node, see BEP 51
+    SampleInfohashes {
+        target: NodeId,
+    },
+    // Item stored under `target`, only if newer than `seq` when mutable
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    // Store an item, mutable ones only replacing the one of sequence `cas`
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+    // Method we don't know, answered with an error
+    Unknown(String),
+}
+
+/// Answer to any query, the fields depend on the query.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // Answers to a scrape
+    pub seeds: Option<BloomFilter>,
+    pub downloaders: Option<BloomFilter>,
+    // Answers to a get, the key and signature of mutable items
+    pub value: Option<Value>,
+    pub key: Option<PublicKey>,
+    pub seq: Option<i64>,
+    pub signature: Option<[u8; 64]>,
+    // Answers to a sample_infohashes, `interval` in seconds
+    pub interval: Option<u32>,
+    pub num: Option<u64>,
+    pub samples: Vec<InfoHash>,
+}
+
+/// Addresses of the peers of a swarm, as a bloom filter estimating their
+/// number, see http://bittorrent.org/beps/bep_0033.html.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter(Box<[u8; BLOOM_FILTER_LEN]>);
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        BloomFilter(Box::new([0; BLOOM_FILTER_LEN]))
+    }
+}
+
+impl BloomFilter {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok().map(|b| BloomFilter(Box::new(b)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for i in [0, 2] {
+            let index = (hash[i] as usize | (hash[i + 1] as usize) << 8) % BLOOM_FILTER_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Add the addresses of another filter.
+    pub fn union(&mut self, other: &BloomFilter) {
+        self.0
+            .iter_mut()
+            .zip(other.0.iter())
+            .for_each(|(a, b)| *a |= b);
+    }
+
+    /// Number of addresses inserted, estimated from the bits still clear.
+    pub fn estimate(&self) -> f64 {
+        let clear = BLOOM_FILTER_BITS
+            - self
+                .0
+                .iter()
+                .map(|b| b.count_ones() as usize)
+                .sum::<usize>();
+        let m = BLOOM_FILTER_BITS as f64;
+        // A full filter is as full as it can be measured
+        let c = clear.max(1) as f64;
+        (c / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query(NodeId, Query),
+    Response(NodeId, Response),
+    Error(i64, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    // Chosen by the querying node, echoed in the answer
+    pub transaction: Vec<u8>,
+    pub body: Body,
+    // Client of the sender
+    pub version: Option<Vec<u8>>,
+    // Address the sender sees the receiver at, BEP 42
+    pub ip: Option<SocketAddrV4>,
+    // Set on the queries of nodes which answer none, BEP 43
+    pub read_only: bool,
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::malformed_content(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+fn compact_addr(addr: &SocketAddrV4) -> [u8; 6] {
+    let mut res = [0; 6];
+    res[..4].copy_from_slice(&addr.ip().octets());
+    res[4..].copy_from_slice(&addr.port().to_be_bytes());
+    res
+}
+
+fn parse_compact_addr(bytes: &[u8]) -> SocketAddrV4 {
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    SocketAddrV4::new(ip, u16::from_be_bytes([bytes[4], bytes[5]]))
+}
+
+/// Nodes one after the other, 26 bytes each.
+pub fn compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        res.extend_from_slice(&node.id);
+        res.extend_from_slice(&compact_addr(&node.addr));
+    }
+    res
+}
+
+pub fn parse_compact_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes
+        .chunks_exact(COMPACT_NODE_LEN)
+        .map(|b| NodeInfo {
+            id: b[..NODE_ID_LEN].try_into().expect("20 bytes"),
+            addr: parse_compact_addr(&b[NODE_ID_LEN..]),
+        })
+        .collect()
+}
+
+fn id_from(bytes: &[u8]) -> Result<[u8; NODE_ID_LEN], Error> {
+    bytes.try_into().map_err(|_| malformed("Expected 20 bytes"))
+}
+
+impl Query {
+    pub fn method(&self) -> &str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode { .. } => "find_node",
+            Query::GetPeers { .. } => "get_peers",
+            Query::AnnouncePeer { .. } => "announce_peer",
+            Query::SampleInfohashes { .. } => "sample_infohashes",
+            Query::Get { .. } => "get",
+            Query::Put { .. } => "put",
+            Query::Unknown(method) => method,
+        }
+    }
+}
+
+impl Message {
+    pub fn query(transaction: Vec<u8>, id: NodeId, query: Query) -> Self {
+        Message {
+            transaction,
+            body: Body::Query(id, query),
+            version: None,
+            ip: None,
+            read_only: false,
+        }
+    }
+
+    pub fn response(transaction: Vec<u8>, id: NodeId, response: Response) -> Self {
+        Message {
+            transaction,
+            body: Body::Response(id, response),
+            version: None,
+            ip: None,
+            read_only: false,
+        }
+    }
+
+    pub fn error(transaction: Vec<u8>, code: i64, message: &str) -> Self {
+        Message {
+            transaction,
+            body: Body::Error(code, message.to_string()),
+            version: None,
+            ip: None,
+            read_only: false,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bendy::encoding::Error> {
+        self.to_bencode()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Message::from_bencode(bytes)
+    }
+}
+
+// Keys of dictionaries are emitted in order, as bencode wants them
+impl ToBencode for Message {
+    const MAX_DEPTH: usize = 2 + MAX_VALUE_DEPTH;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            // "ip" sorts between "a" or "e" and "q" or "r"
+            let emit_ip = |e: &mut SortedDictEncoder| match &self.ip {
+                Some(ip) => e.emit_pair_with(b"ip", |e| e.emit_bytes(&compact_addr(ip))),
+                None => Ok(()),
+            };
+            match &self.body {
+                Body::Query(id, query) => {
+                    e.emit_pair_with(b"a", |e| {
+                        e.emit_dict(|mut e| {
+                            // The only key sorted before the ID
+                            if let Query::Put { cas: Some(cas), .. } = query {
+                                e.emit_pair(b"cas", cas)?;
+                            }
+                            e.emit_pair_with(b"id", |e| e.emit_bytes(id))?;
+                            match query {
+                                Query::FindNode { target } | Query::SampleInfohashes { target } => {
+                                    e.emit_pair_with(b"target", |e| e.emit_bytes(target))?
+                                }
+                                Query::GetPeers {
+                                    info_hash,
+                                    scrape,
+                                    noseed,
+                                } => {
+                                    e.emit_pair_with(b"info_hash", |e| e.emit_bytes(info_hash))?;
+                                    if *noseed {
+                                        e.emit_pair(b"noseed", 1)?;
+                                    }
+                                    if *scrape {
+                                        e.emit_pair(b"scrape", 1)?;
+                                    }
+                                }
+                                Query::AnnouncePeer {
+                                    info_hash,
+                                    port,
+                                    implied_port,
+                                    token,
+                                    seed,
+                                } => {
+                                    e.emit_pair(b"implied_port", *implied_port as u8)?;
+                                    e.emit_pair_with(b"info_hash", |e| e.emit_bytes(info_hash))?;
+                                    e.emit_pair(b"port", port)?;
+                                    if *seed {
+                                        e.emit_pair(b"seed", 1)?;
+                                    }
+                                    e.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+                                }
+                                Query::Get { target, seq } => {
+                                    if let Some(seq) = seq {
+                                        e.emit_pair(b"seq", seq)?;
+                                    }
+                                    e.emit_pair_with(b"target", |e| e.emit_bytes(target))?;
+                                }
+                                Query::Put { token, item, .. } => {
+                                    let mutable = item.mutable.as_ref();
+                                    if let Some(mutable) = mutable {
+                                        e.emit_pair_with(b"k", |e| e.emit_bytes(&mutable.key))?;
+                                        if !mutable.salt.is_empty() {
+                                            e.emit_pair_with(b"salt", |e| {
+                                                e.emit_bytes(&mutable.salt)
+                                            })?;
+                                        }
+                                        e.emit_pair(b"seq", mutable.seq)?;
+                                        e.emit_pair_with(b"sig", |e| {
+                                            e.emit_bytes(&mutable.signature)
+                                        })?;
+                                    }
+                                    e.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+                                    e.emit_pair(b"v", &item.value)?;
+                                }
+                                Query::Ping | Query::Unknown(_) => (),
+                            }
+                            Ok(())
+                        })
+                    })?;
+                    emit_ip(&mut e)?;
+                    e.emit_pair(b"q", query.method())?;
+                }
+                Body::Response(id, response) => {
+                    emit_ip(&mut e)?;
+                    e.emit_pair_with(b"r", |e| {
+                        e.emit_dict(|mut e| {
+                            if let Some(downloaders) = &response.downloaders {
+                                e.emit_pair_with(b"BFpe", |e| {
+                                    e.emit_bytes(downloaders.as_bytes())
+                                })?;
+                            }
+                            if let Some(seeds) = &response.seeds {
+                                e.emit_pair_with(b"BFsd", |e| e.emit_bytes(seeds.as_bytes()))?;
+                            }
+                            e.emit_pair_with(b"id", |e| e.emit_bytes(id))?;
+                            if let Some(interval) = response.interval {
+                                e.emit_pair(b"interval", interval)?;
+                            }
+                            if let Some(key) = &response.key {
+                                e.emit_pair_with(b"k", |e| e.emit_bytes(key))?;
+                            }
+                            if !response.nodes.is_empty() {
+                                let nodes = compact_nodes(&response.nodes);
+                                e.emit_pair_with(b"nodes", |e| e.emit_bytes(&nodes))?;
+                            }
+                            if let Some(num) = response.num {
+                                e.emit_pair(b"num", num)?;
+                            }
+                            if response.num.is_some() || !response.samples.is_empty() {
+                                let samples = response.samples.concat();
+                                e.emit_pair_with(b"samples", |e| e.emit_bytes(&samples))?;
+                            }
+                            if let Some(seq) = response.seq {
+                                e.emit_pair(b"seq", seq)?;
+                            }
+                            if let Some(signature) = &response.signature {
+                                e.emit_pair_with(b"sig", |e| e.emit_bytes(signature))?;
+                            }
+                            if let Some(token) = &response.token {
+                                e.emit_pair_with(b"token", |e| e.emit_bytes(token))?;
+                            }
+                            if let Some(value) = &response.value {
+                                e.emit_pair(b"v", value)?;
+                            }
+                            if !response.values.is_empty() {
+                                e.emit_pair_with(b"values", |e| {
+                                    e.emit_list(|e| {
+                                        for value in &response.values {
+                                            if let SocketAddr::V4(addr) = value {
+                                                e.emit_bytes(&compact_addr(addr))?;
+                                            }
+                                        }
+                                        Ok(())
+                                    })
+                                })?;
+                            }
+                            Ok(())
+                        })
+                    })?;
+                }
+                Body::Error(code, message) => {
+                    e.emit_pair_with(b"e", |e| {
+                        e.emit_list(|e| {
+                            e.emit_int(*code)?;
+                            e.emit_str(message)
+                        })
+                    })?;
+                    emit_ip(&mut e)?;
+                }
+            }
+            if self.read_only {
+                e.emit_pair(b"ro", 1)?;
+            }
+            e.emit_pair_with(b"t", |e| e.emit_bytes(&self.transaction))?;
+            if let Some(version) = &self.version {
+                e.emit_pair_with(b"v", |e| e.emit_bytes(version))?;
+            }
+            let kind = match self.body {
+                Body::Query(..) => "q",
+                Body::Response(..) => "r",
+                Body::Error(..) => "e",
+            };
+            e.emit_pair(b"y", kind)
+        })
+    }
+}
+
+// Fields of the arguments of a query or of a response, all optional
+#[derive(Default)]
+struct Fields {
+    id: Option<NodeId>,
+    target: Option<NodeId>,
+    info_hash: Option<InfoHash>,
+    port: Option<u16>,
+    implied_port: bool,
+    token: Option<Vec<u8>>,
+    nodes: Vec<NodeInfo>,
+    values: Vec<SocketAddr>,
+    scrape: bool,
+    noseed: bool,
+    seed: bool,
+    seeds: Option<BloomFilter>,
+    downloaders: Option<BloomFilter>,
+    value: Option<Value>,
+    key: Option<PublicKey>,
+    salt: Vec<u8>,
+    seq: Option<i64>,
+    signature: Option<[u8; 64]>,
+    cas: Option<i64>,
+    interval: Option<u32>,
+    num: Option<u64>,
+    samples: Vec<InfoHash>,
+}
+
+impl Fields {
+    fn decode(mut dict: DictDecoder) -> Result<Self, Error> {
+        let mut res = Fields::default();
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"id", value) => res.id = Some(id_from(value.try_into_bytes()?)?),
+                (b"target", value) => res.target = Some(id_from(value.try_into_bytes()?)?),
+                (b"info_hash", value) => res.info_hash = Some(id_from(value.try_into_bytes()?)?),
+                (b"port", value) => res.port = Some(u16::decode_bencode_object(value)?),
+                (b"implied_port", value) => {
+                    res.implied_port = u8::decode_bencode_object(value)? != 0
+                }
+                (b"token", value) => res.token = Some(value.try_into_bytes()?.to_vec()),
+                (b"scrape", value) => res.scrape = u8::decode_bencode_object(value)? != 0,
+                (b"noseed", value) => res.noseed = u8::decode_bencode_object(value)? != 0,
+                (b"seed", value) => res.seed = u8::decode_bencode_object(value)? != 0,
+                (b"BFsd", value) => res.seeds = BloomFilter::from_bytes(value.try_into_bytes()?),
+                (b"BFpe", value) => {
+                    res.downloaders = BloomFilter::from_bytes(value.try_into_bytes()?)
+                }
+                (b"nodes", value) => res.nodes = parse_compact_nodes(value.try_into_bytes()?),
+                (b"v", value) => res.value = Some(Value::decode_bencode_object(value)?),
+                (b"k", value) => {
+                    let key = value.try_into_bytes()?.try_into();
+                    res.key = Some(key.map_err(|_| malformed("Expected 32 bytes"))?);
+                }
+                (b"salt", value) => res.salt = value.try_into_bytes()?.to_vec(),
+                (b"seq", value) => res.seq = Some(i64::decode_bencode_object(value)?),
+                (b"sig", value) => {
+                    let signature = value.try_into_bytes()?.try_into();
+                    res.signature = Some(signature.map_err(|_| malformed("Expected 64 bytes"))?);
+                }
+                (b"cas", value) => res.cas = Some(i64::decode_bencode_object(value)?),
+                (b"interval", value) => res.interval = Some(u32::decode_bencode_object(value)?),
+                (b"num", value) => res.num = Some(u64::decode_bencode_object(value)?),
+                (b"samples", value) => {
+                    let samples = value.try_into_bytes()?.chunks_exact(20);
+                    res.samples = samples.map(|s| s.try_into().expect("20 bytes")).collect();
+                }
+                (b"values", value) => {
+                    let mut list = value.try_into_list()?;
+                    while let Some(value) = list.next_object()? {
+                        // Only IPv4 peers are compact in 6 bytes
+                        let value = value.try_into_bytes()?;
+                        if value.len() == 6 {
+                            res.values.push(parse_compact_addr(value).into());
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    fn id(&self) -> Result<NodeId, Error> {
+        self.id.ok_or_else(|| Error::missing_field("id"))
+    }
+}
+
+impl FromBencode for Message {
+    const EXPECTED_RECURSION_DEPTH: usize = 2 + MAX_VALUE_DEPTH;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error> {
+        let mut dict = object.try_into_dictionary()?;
+        let (mut transaction, mut kind, mut method, mut version) = (None, None, None, None);
+        let (mut ip, mut read_only) = (None, false);
+        let (mut fields, mut error) = (None, None);
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"t", value) => transaction = Some(value.try_into_bytes()?.to_vec()),
+                (b"y", value) => kind = Some(value.try_into_bytes()?.to_vec()),
+                (b"q", value) => method = Some(String::from_utf8_lossy(value.try_into_bytes()?)),
+                (b"v", value) => version = Some(value.try_into_bytes()?.to_vec()),
+                (b"ip", value) => {
+                    let value = value.try_into_bytes()?;
+                    if value.len() == 6 {
+                        ip = Some(parse_compact_addr(value));
+                    }
+                }
+                (b"ro", value) => read_only = i64::decode_bencode_object(value)? == 1,
+                (b"a" | b"r", value) => {
+                    fields = Some(Fields::decode(value.try_into_dictionary()?)?)
+                }
+                (b"e", value) => {
+                    let mut list = value.try_into_list()?;
+                    let code = match list.next_object()? {
+                        Some(code) => i64::decode_bencode_object(code)?,
+                        None => return Err(Error::missing_field("e")),
+                    };
+                    let message = match list.next_object()? {
+                        Some(message) => {
+                            String::from_utf8_lossy(message.try_into_bytes()?).into_owned()
+                        }
+                        None => String::new(),
+                    };
+                    error = Some((code, message));
+                }
+                _ => (),
+            }
+        }
+        let transaction = transaction.ok_or_else(|| Error::missing_field("t"))?;
+
+        let body = match kind.as_deref() {
+            Some(b"q") => {
+                let fields = fields.ok_or_else(|| Error::missing_field("a"))?;
+                let method = method.ok_or_else(|| Error::missing_field("q"))?;
+                let missing = |field| move || Error::missing_field(field);
+                let query = match &*method {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode {
+                        target: fields.target.ok_or_else(missing("target"))?,
+                    },
+                    "get_peers" => Query::GetPeers {
+                        info_hash: fields.info_hash.ok_or_else(missing("info_hash"))?,
+                        scrape: fields.scrape,
+                        noseed: fields.noseed,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: fields.info_hash.ok_or_else(missing("info_hash"))?,
+                        // The port is useless when implied
+                        port: match fields.implied_port {
+                            true => fields.port.unwrap_or(0),
+                            false => fields.port.ok_or_else(missing("port"))?,
+                        },
+                        implied_port: fields.implied_port,
+                        token: fields.token.clone().ok_or_else(missing("token"))?,
+                        seed: fields.seed,
+                    },
+                    "sample_infohashes" => Query::SampleInfohashes {
+                        target: fields.target.ok_or_else(missing("target"))?,
+                    },
+                    "get" => Query::Get {
+                        target: fields.target.ok_or_else(missing("target"))?,
+                        seq: fields.seq,
+                    },
+                    "put" => {
+                        let mutable = match fields.key {
+                            Some(key) => Some(Mutable {
+                                key,
+                                salt: fields.salt.clone(),
+                                seq: fields.seq.ok_or_else(missing("seq"))?,
+                                signature: fields.signature.ok_or_else(missing("sig"))?,
+                            }),
+                            None => None,
+                        };
+                        Query::Put {
+                            token: fields.token.clone().ok_or_else(missing("token"))?,
+                            item: Item {
+                                value: fields.value.clone().ok_or_else(missing("v"))?,
+                                mutable,
+                            },
+                            cas: fields.cas,
+                        }
+                    }
+                    method => Query::Unknown(method.to_string()),
+                };
+                Body::Query(fields.id()?, query)
+            }
+            Some(b"r") => {
+                let fields = fields.ok_or_else(|| Error::missing_field("r"))?;
+                let id = fields.id()?;
+                Body::Response(
+                    id,
+                    Response {
+                        nodes: fields.nodes,
+                        values: fields.values,
+                        token: fields.token,
+                        seeds: fields.seeds,
+                        downloaders: fields.downloaders,
+                        value: fields.value,
+                        key: fields.key,
+                        seq: fields.seq,
+                        signature: fields.signature,
+                        interval: fields.interval,
+                        num: fields.num,
+                        samples: fields.samples,
+                    },
+                )
+            }
+            Some(b"e") => {
+                let (code, message) = error.ok_or_else(|| Error::missing_field("e"))?;
+                Body::Error(code, message)
+            }
+            _ => return Err(malformed("Unknown message type")),
+        };
+        Ok(Message {
+            transaction,
+            body,
+            version,
+            ip,
+            read_only,
+        })
+    }
+}
+
+#[cfg(test)]
+mod krpc_tests {
+    use super::*;
+
+    #[test]
+    fn bep5_examples() {
+        // Queries and answers as written in the BEP, ids aside
+        let id = *b"abcdefghij0123456789";
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let msg = Message::from_bytes(ping).unwrap();
+        assert_eq!(Message::query(b"aa".to_vec(), id, Query::Ping), msg);
+        assert_eq!(&ping[..], &msg.to_bytes().unwrap()[..]);
+
+        let announce = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+        let msg = Message::from_bytes(announce).unwrap();
+        let query = Query::AnnouncePeer {
+            info_hash: *b"mnopqrstuvwxyz123456",
+            port: 6881,
+            implied_port: true,
+            token: b"aoeusnth".to_vec(),
+            seed: false,
+        };
+        assert_eq!(Message::query(b"aa".to_vec(), id, query), msg);
+        assert_eq!(&announce[..], &msg.to_bytes().unwrap()[..]);
+
+        let peers = b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+        let msg = Message::from_bytes(peers).unwrap();
+        let Body::Response(_, response) = &msg.body else {
+            panic!("Expected a response");
+        };
+        assert_eq!(
+            vec![
+                "97.120.106.101:11893".parse::<SocketAddr>().unwrap(),
+                "105.100.104.116:28269".parse().unwrap()
+            ],
+            response.values
+        );
+        assert_eq!(&peers[..], &msg.to_bytes().unwrap()[..]);
+
+        let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let msg = Message::from_bytes(error).unwrap();
+        assert_eq!(
+            Body::Error(GENERIC_ERROR, "A Generic Error Ocurred".to_string()),
+            msg.body
+        );
+        assert_eq!(&error[..], &msg.to_bytes().unwrap()[..]);
+
+        // As in BEP 43
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe";
+        let msg = Message::from_bytes(ping).unwrap();
+        assert!(msg.read_only);
+        assert_eq!(&ping[..], &msg.to_bytes().unwrap()[..]);
+
+        // Nodes survive a round trip, an unknown method is kept
+        let nodes = vec![NodeInfo {
+            id,
+            addr: "1.2.3.4:5".parse().unwrap(),
+        }];
+        let response = Response {
+            nodes: nodes.clone(),
+            ..Default::default()
+        };
+        let msg = Message::response(b"t".to_vec(), id, response);
+        assert_eq!(msg, Message::from_bytes(&msg.to_bytes().unwrap()).unwrap());
+        let msg = Message::query(b"t".to_vec(), id, Query::Unknown("vote".to_string()));
+        assert_eq!(msg, Message::from_bytes(&msg.to_bytes().unwrap()).unwrap());
+        assert!(Message::from_bytes(b"d1:t2:aa1:y1:qe").is_err());
+
+        // Items too, the compare-and-swap sorted first
+        let key = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+        let item = Item::signed(Value::List(Vec::new()), &key, b"salt", 2).unwrap();
+        let put = Query::Put {
+            token: b"t".to_vec(),
+            item,
+            cas: Some(1),
+        };
+        let msg = Message::query(b"t".to_vec(), id, put);
+        assert_eq!(msg, Message::from_bytes(&msg.to_bytes().unwrap()).unwrap());
+
+        // The address of the receiver goes between the body and its kind
+        let mut msg = Message::response(b"aa".to_vec(), id, Response::default());
+        msg.ip = Some("97.120.106.101:11893".parse().unwrap());
+        let bytes = msg.to_bytes().unwrap();
+        assert_eq!(
+            &b"d2:ip6:axje.u1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re"[..],
+            &bytes[..]
+        );
+        assert_eq!(msg, Message::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn bep33_bloom_filter() {
+        // The example of the BEP, 256 IPv4 and 1000 IPv6 addresses
+        let mut filter = BloomFilter::default();
+        for i in 0..=255 {
+            filter.insert(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)));
+        }
+        for i in 0..1000u128 {
+            let ip = 0x2001_0db8_u128 << 96 | i;
+            filter.insert(IpAddr::V6(ip.into()));
+        }
+        assert!((filter.estimate() - 1224.9308).abs() < 0.001);
+        assert_eq!(0.0, BloomFilter::default().estimate());
+
+        let mut union = BloomFilter::default();
+        union.union(&filter);
+        assert_eq!(filter, union);
+
+        let scrape = Query::GetPeers {
+            info_hash: [1; 20],
+            scrape: true,
+            noseed: true,
+        };
+        let msg = Message::query(b"aa".to_vec(), [2; 20], scrape);
+        assert_eq!(msg, Message::from_bytes(&msg.to_bytes().unwrap()).unwrap());
+        let response = Response {
+            seeds: Some(filter),
+            downloaders: Some(BloomFilter::default()),
+            ..Default::default()
+        };
+        let msg = Message::response(b"aa".to_vec(), [2; 20], response);
+        assert_eq!(msg, Message::from_bytes(&msg.to_bytes().unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,410 @@
+// Mapping between the pieces of a torrent and the files they cover
+use std::{
+    io,
+    ops::Range,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::decode_torrent::{Info, MetaInfoError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutFile {
+    // Relative to the download directory
+    pub path: PathBuf,
+    pub length: u64,
+    // Offset of the first byte of the file in the torrent
+    pub offset: u64,
+    // Hex MD5 of the whole file, when the torrent has one
+    pub md5sum: Option<String>,
+    pub attr: FileAttr,
+}
+
+/// BEP 47 attributes of a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttr {
+    // Filler aligning the next file on a piece, not stored
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    // Target of a symlink, relative to the torrent directory
+    pub symlink: Option<PathBuf>,
+}
+
+/// Part of a piece stored in a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file: usize,
+    // Offset in the file
+    pub offset: u64,
+    pub length: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Layout {
+    piece_size: usize,
+    size: u64,
+    files: Vec<LayoutFile>,
+}
+
+fn invalid_path(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid path in torrent: {}", path.display()),
+    )
+}
+
+impl Layout {
+    /// Paths come from the torrent and must not escape the download
+    /// directory: only relative paths of plain names are accepted.
+    pub fn checked_path(path: PathBuf) -> io::Result<PathBuf> {
+        if path.as_os_str().is_empty()
+            || !path.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(invalid_path(&path));
+        }
+        Ok(path)
+    }
+
+    pub fn new(piece_size: usize, files: Vec<(PathBuf, u64)>) -> Self {
+        assert!(piece_size > 0);
+
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let res = LayoutFile {
+                    path,
+                    length,
+                    offset,
+                    md5sum: None,
+                    attr: FileAttr::default(),
+                };
+                offset += length;
+                res
+            })
+            .collect();
+
+        Layout {
+            piece_size,
+            size: offset,
+            files,
+        }
+    }
+
+    /// Layout of the files described by `info`. Multi-file torrents are
+    /// stored in a directory named after the torrent.
+    pub fn from_info(info: &Info) -> io::Result<Self> {
+        let invalid = |e: MetaInfoError| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        let piece_size = info.piece_size().map_err(invalid)?;
+        let name = Layout::checked_path(PathBuf::from(&info.name))?;
+
+        let (files, extras): (Vec<_>, Vec<_>) = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| {
+                    let path = Layout::checked_path(f.path.iter().collect())?;
+                    let symlink = match (&f.symlink_path, f.is_symlink()) {
+                        (Some(target), true) => {
+                            Some(Layout::checked_path(target.iter().collect())?)
+                        }
+                        _ => None,
+                    };
+                    let attr = FileAttr {
+                        padding: f.is_padding(),
+                        executable: f.is_executable(),
+                        hidden: f.is_hidden(),
+                        symlink,
+                    };
+                    Ok(((name.join(path), f.length), (f.md5sum.clone(), attr)))
+                })
+                .collect::<io::Result<Vec<_>>>()?
+                .into_iter()
+                .unzip(),
+            None => {
+                let length = info.length().map_err(invalid)?;
+                (
+                    vec![(name, length)],
+                    vec![(info.md5sum.clone(), FileAttr::default())],
+                )
+            }
+        };
+
+        let mut layout = Layout::new(piece_size, files);
+        for (file, (md5sum, attr)) in layout.files.iter_mut().zip(extras) {
+            file.md5sum = md5sum;
+            file.attr = attr;
+        }
+        // Every piece needs its hash, the storage relies on it
+        if info.num_pieces() != layout.num_pieces() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Torrent has {} piece hashes for {} pieces",
+                    info.num_pieces(),
+                    layout.num_pieces()
+                ),
+            ));
+        }
+        Ok(layout)
+    }
+
+    pub fn piece_size(&self) -> usize {
+        self.piece_size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn files(&self) -> &[LayoutFile] {
+        &self.files
+    }
+
+    /// Change the path of a file, relative to the download directory.
+    pub fn set_path(&mut self, file: usize, path: PathBuf) -> io::Result<()> {
+        self.files[file].path = Layout::checked_path(path)?;
+        Ok(())
+    }
+
+    /// Replace the first component of every path, i.e. the directory of a
+    /// multi-file torrent or the name of a single file.
+    pub fn rename_root(&mut self, name: &str) -> io::Result<()> {
+        let name = Layout::checked_path(PathBuf::from(name))?;
+        for file in &mut self.files {
+            let rest: PathBuf = file.path.components().skip(1).collect();
+            file.path = match rest.as_os_str().is_empty() {
+                true => name.clone(),
+                false => name.join(rest),
+            };
+        }
+        Ok(())
+    }
+
+    pub fn set_attr(&mut self, file: usize, attr: FileAttr) {
+        self.files[file].attr = attr;
+    }
+
+    pub fn set_md5sum(&mut self, file: usize, md5sum: Option<String>) {
+        self.files[file].md5sum = md5sum;
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        (self.size as usize).div_ceil(self.piece_size)
+    }
+
+    /// Length of the piece, `None` past the last one.
+    pub fn piece_len(&self, index: usize) -> Option<usize> {
+        match index.cmp(&(self.num_pieces().checked_sub(1)?)) {
+            std::cmp::Ordering::Less => Some(self.piece_size),
+            std::cmp::Ordering::Equal => Some(self.size as usize - index * self.piece_size),
+            std::cmp::Ordering::Greater => None,
+        }
+    }
+
+    /// Files holding at least one byte of the piece.
+    pub fn piece_files(&self, index: usize) -> Range<usize> {
+        let Some(len) = self.piece_len(index) else {
+            return 0..0;
+        };
+        let start = (index * self.piece_size) as u64;
+        let end = start + len as u64;
+
+        let first = self.files.partition_point(|f| f.offset + f.length <= start);
+        let last = self.files.partition_point(|f| f.offset < end);
+        first..last.max(first)
+    }
+
+    /// Length of the piece without the padding files at its end, which v2
+    /// piece hashes leave out.
+    pub fn content_len(&self, index: usize) -> Option<usize> {
+        let start = (index * self.piece_size) as u64;
+        let end = start + self.piece_len(index)? as u64;
+        let content_end = self
+            .piece_files(index)
+            .rev()
+            .map(|f| &self.files[f])
+            .find(|f| f.length > 0 && !f.attr.padding)
+            .map_or(start, |f| (f.offset + f.length).min(end));
+        Some((content_end - start) as usize)
+    }
+
+    /// Every byte of the piece is in BEP 47 padding files, it is all zeros
+    /// and never needs downloading.
+    pub fn is_padding_piece(&self, index: usize) -> bool {
+        let mut files = self
+            .piece_files(index)
+            .map(|f| &self.files[f])
+            .filter(|f| f.length > 0)
+            .peekable();
+        files.peek().is_some() && files.all(|f| f.attr.padding)
+    }
+
+    /// Pieces holding at least one byte of the file, empty for empty files.
+    pub fn file_pieces(&self, file: usize) -> Range<usize> {
+        let f = &self.files[file];
+        if f.length == 0 {
+            return 0..0;
+        }
+        let first = f.offset as usize / self.piece_size;
+        let last = (f.offset + f.length - 1) as usize / self.piece_size;
+        first..last + 1
+    }
+
+    /// Split a range of a piece along the file boundaries, `None` when the
+    /// range isn't in the piece.
+    pub fn slices(&self, index: usize, begin: usize, length: usize) -> Option<Vec<FileSlice>> {
+        let end = begin.checked_add(length)?;
+        if end > self.piece_len(index)? {
+            return None;
+        }
+
+        let mut res = Vec::new();
+        let mut pos = (index * self.piece_size + begin) as u64;
+        let end = pos + length as u64;
+
+        for file in self.piece_files(index) {
+            let f = &self.files[file];
+            if pos >= end {
+                break;
+            }
+            if f.offset + f.length <= pos {
+                continue;
+            }
+
+            let len = (f.offset + f.length).min(end) - pos;
+            res.push(FileSlice {
+                file,
+                offset: pos - f.offset,
+                length: len as usize,
+            });
+            pos += len;
+        }
+
+        Some(res)
+    }
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+
+    fn layout() -> Layout {
+        // Pieces of 4: |aaab|bbbb|bbcc|
+        Layout::new(
+            4,
+            vec![
+                (PathBuf::from("a"), 3),
+                (PathBuf::from("b"), 6),
+                (PathBuf::from("empty"), 0),
+                (PathBuf::from("c"), 2),
+            ],
+        )
+    }
+
+    #[test]
+    fn pieces_and_files() {
+        let layout = layout();
+        assert_eq!(11, layout.size());
+        assert_eq!(3, layout.num_pieces());
+        assert_eq!(Some(3), layout.piece_len(2));
+        assert_eq!(None, layout.piece_len(3));
+        assert_eq!(0..0, layout.piece_files(3));
+
+        assert_eq!(0..2, layout.piece_files(0));
+        assert_eq!(1..2, layout.piece_files(1));
+        assert_eq!(1..4, layout.piece_files(2));
+
+        assert_eq!(0..1, layout.file_pieces(0));
+        assert_eq!(0..3, layout.file_pieces(1));
+        assert_eq!(0..0, layout.file_pieces(2));
+        assert_eq!(2..3, layout.file_pieces(3));
+    }
+
+    #[test]
+    fn slices() {
+        let layout = layout();
+        assert_eq!(
+            vec![
+                FileSlice {
+                    file: 0,
+                    offset: 1,
+                    length: 2
+                },
+                FileSlice {
+                    file: 1,
+                    offset: 0,
+                    length: 1
+                },
+            ],
+            layout.slices(0, 1, 3).unwrap()
+        );
+        assert_eq!(
+            vec![
+                FileSlice {
+                    file: 1,
+                    offset: 5,
+                    length: 1
+                },
+                FileSlice {
+                    file: 3,
+                    offset: 0,
+                    length: 2
+                },
+            ],
+            layout.slices(2, 0, 3).unwrap()
+        );
+        assert_eq!(None, layout.slices(2, 0, 4));
+        assert_eq!(None, layout.slices(3, 0, 1));
+    }
+
+    #[test]
+    fn rename() {
+        let mut layout = layout();
+        layout.rename_root("dir").unwrap();
+        assert_eq!(Path::new("dir"), layout.files()[0].path);
+
+        let mut layout = Layout::new(
+            4,
+            vec![(PathBuf::from("t/a"), 1), (PathBuf::from("t/b/c"), 1)],
+        );
+        layout.rename_root("u").unwrap();
+        assert_eq!(Path::new("u/b/c"), layout.files()[1].path);
+        layout.set_path(0, PathBuf::from("u/x")).unwrap();
+        assert_eq!(Path::new("u/x"), layout.files()[0].path);
+        assert!(layout.set_path(0, PathBuf::from("../x")).is_err());
+        assert!(layout.rename_root("..").is_err());
+    }
+
+    #[test]
+    fn reject_escaping_paths() {
+        assert!(Layout::checked_path(PathBuf::from("../etc/passwd")).is_err());
+        assert!(Layout::checked_path(PathBuf::from("/etc/passwd")).is_err());
+        assert!(Layout::checked_path(PathBuf::from("")).is_err());
+        assert!(Layout::checked_path(PathBuf::from("dir/file")).is_ok());
+    }
+
+    #[test]
+    fn invalid_info() {
+        let info = Info {
+            piece_length: "0".to_string(),
+            pieces: Vec::new(),
+            name: "x".to_string(),
+            file_length: "4".to_string(),
+            md5sum: None,
+            files: None,
+            private: false,
+            meta_version: None,
+            file_tree: None,
+        };
+        assert!(Layout::from_info(&info).is_err());
+
+        // One hash short
+        let info = Info {
+            piece_length: "4".to_string(),
+            pieces: vec![String::new()],
+            file_length: "5".to_string(),
+            ..info
+        };
+        assert!(Layout::from_info(&info).is_err());
+    }
+}
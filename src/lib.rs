@@ -1,9 +1,67 @@
+pub mod availability;
+pub mod backend;
+pub mod blocks;
+pub mod cache;
+pub mod capture;
+pub mod create;
 pub mod decode_torrent;
 pub mod definitions;
+pub mod dht;
+pub mod dht_item;
+pub mod dialer;
+pub mod direct;
+pub mod error;
+pub mod events;
+pub mod extension;
+pub mod fairness;
+pub mod fastresume;
+pub mod fdpool;
+#[cfg(feature = "rss")]
+pub mod feed;
 pub mod file;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+pub mod fuse;
+#[cfg(feature = "geoip")]
+pub mod geoip;
 pub mod handshake;
+pub mod hash;
+pub mod ipfilter;
+pub mod journal;
+pub mod krpc;
+pub mod layout;
+pub mod listener;
+pub mod magnet;
+pub mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod parts;
 pub mod peer;
+pub mod pick;
+pub mod priority;
+pub mod profile;
+pub mod proxy;
+pub mod reader;
+pub mod recheck;
+pub mod resume;
+pub mod routing;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod scheduler;
+pub mod session;
+pub mod sink;
+pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod torrent;
 pub mod tracker;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring;
+pub mod watch;
+pub mod web_seed;
+#[cfg(feature = "webui")]
+pub mod webui;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+use crate::handshake::Handshake;
+use crate::ipfilter::SharedIpFilter;
+use crate::peer::PeerError;
+
+pub const DEFAULT_MAX_PENDING: usize = 32;
+pub const DEFAULT_INBOUND_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_PER_IP_HANDSHAKES: u32 = 5;
+pub const DEFAULT_PER_IP_WINDOW: Duration = Duration::from_secs(10);
+const LISTEN_BACKLOG: i32 = 1024;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct InboundConfig {
+    // Maximum number of accepted sockets which haven't handshaked yet
+    pub max_pending: usize,
+    // Connections not completing a valid handshake in time are dropped
+    pub handshake_timeout: Duration,
+    // A single IP may start at most `per_ip_handshakes` handshakes per window
+    pub per_ip_handshakes: u32,
+    pub per_ip_window: Duration,
+    // Connections from blocked addresses are dropped right away
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub ip_filter: SharedIpFilter,
+}
+
+/// Admission control for inbound peer connections, protecting a public
+/// listening port from connection floods.
+#[derive(Debug, Clone)]
+pub struct InboundLimiter {
+    config: InboundConfig,
+    pending: Arc<Semaphore>,
+    // ip -> (start of the current window, handshakes seen in that window)
+    per_ip: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+/// An admitted inbound connection which must complete its handshake before
+/// the deadline. Holds a pending slot until then.
+#[derive(Debug)]
+pub struct Pending {
+    stream: TcpStream,
+    addr: SocketAddr,
+    handshake_timeout: Duration,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Default for InboundConfig {
+    fn default() -> Self {
+        InboundConfig {
+            max_pending: DEFAULT_MAX_PENDING,
+            handshake_timeout: DEFAULT_INBOUND_HANDSHAKE_TIMEOUT,
+            per_ip_handshakes: DEFAULT_PER_IP_HANDSHAKES,
+            per_ip_window: DEFAULT_PER_IP_WINDOW,
+            ip_filter: SharedIpFilter::default(),
+        }
+    }
+}
+
+impl Default for InboundLimiter {
+    fn default() -> Self {
+        InboundLimiter::new(InboundConfig::default())
+    }
+}
+
+impl InboundLimiter {
+    pub fn new(config: InboundConfig) -> Self {
+        InboundLimiter {
+            pending: Arc::new(Semaphore::new(config.max_pending)),
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+            config,
+        }
+    }
+
+    /// Number of accepted connections still waiting for their handshake.
+    pub fn pending(&self) -> usize {
+        self.config.max_pending - self.pending.available_permits()
+    }
+
+    fn rate_limited(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let window = self.config.per_ip_window;
+        let mut per_ip = self.per_ip.lock().unwrap();
+
+        // Forget IPs whose window expired so the map doesn't grow forever
+        per_ip.retain(|_, (start, _)| now.duration_since(*start) < window);
+
+        let (_, count) = per_ip.entry(ip).or_insert((now, 0));
+        if *count >= self.config.per_ip_handshakes {
+            return true;
+        }
+        *count += 1;
+
+        false
+    }
+
+    /// Decide whether a freshly accepted connection may proceed to the
+    /// handshake. Rejected connections are simply dropped.
+    pub fn admit(&self, stream: TcpStream, addr: SocketAddr) -> Option<Pending> {
+        if self.config.ip_filter.is_blocked(addr.ip()) {
+            return None;
+        }
+        let permit = self.pending.clone().try_acquire_owned().ok()?;
+        if self.rate_limited(addr.ip()) {
+            return None;
+        }
+
+        Some(Pending {
+            stream,
+            addr,
+            handshake_timeout: self.config.handshake_timeout,
+            _permit: permit,
+        })
+    }
+
+    /// Accept connections on `listener` until one is admitted.
+    pub async fn accept(&self, listener: &TcpListener) -> io::Result<Pending> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            if let Some(pending) = self.admit(stream, canonical(addr)) {
+                return Ok(pending);
+            }
+        }
+    }
+}
+
+/// The address of a peer reached through an IPv6 socket accepting IPv4
+/// connections, without the IPv4-mapped form.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// A listening socket, refusing IPv4 connections if `only_v6` and `addr` is
+// an IPv6 address
+fn bind(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    // As `TcpListener::bind`, a restarted session gets its port back
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Listen for peers on `addr`. With `dual_stack`, an unspecified address
+/// listens on both families on the same port: `[::]` accepts IPv4 peers
+/// too, and `0.0.0.0` gets an IPv6 socket next to it, unless the host has
+/// no IPv6.
+pub fn bind_all(addr: SocketAddr, dual_stack: bool) -> io::Result<Vec<TcpListener>> {
+    if !dual_stack || !addr.ip().is_unspecified() {
+        return Ok(vec![bind(addr, true)?]);
+    }
+    if addr.is_ipv6() {
+        return Ok(vec![bind(addr, false)?]);
+    }
+
+    let v4 = bind(addr, true)?;
+    let port = v4.local_addr()?.port();
+    let mut res = vec![v4];
+    if let Ok(v6) = bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), true) {
+        res.push(v6);
+    }
+    Ok(res)
+}
+
+/// As `bind_all`, on the first free port of `ports` starting from a random
+/// one, ignoring the port of `addr`.
+pub fn bind_in_range(
+    addr: SocketAddr,
+    dual_stack: bool,
+    ports: RangeInclusive<u16>,
+) -> io::Result<Vec<TcpListener>> {
+    let (first, last) = (*ports.start(), *ports.end());
+    if ports.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "No port to listen on",
+        ));
+    }
+    let count = u32::from(last - first) + 1;
+    let start = rand::thread_rng().gen_range(0..count);
+    for i in 0..count {
+        let port = first + ((start + i) % count) as u16;
+        match bind_all(SocketAddr::new(addr.ip(), port), dual_stack) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            res => return res,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "Every port of the range is in use",
+    ))
+}
+
+impl Pending {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Address of the peer, the one the proxy reports for proxied peers.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wait for the remote handshake, dropping the connection if it is
+    /// invalid or doesn't arrive before the deadline.
+    pub async fn handshake(mut self) -> Result<(TcpStream, Handshake), PeerError> {
+        let hs = time::timeout(self.handshake_timeout, Handshake::receive(&mut self.stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))??;
+
+        Ok((self.stream, hs))
+    }
+}
+
+#[cfg(test)]
+mod listener_tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    async fn socket_pair(listener: &TcpListener) -> (TcpStream, TcpStream, SocketAddr) {
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, addr) = listener.accept().await.unwrap();
+        (client, server, addr)
+    }
+
+    #[tokio::test]
+    async fn blocked_address() {
+        use crate::ipfilter::{IpFilter, SharedIpFilter};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::new(InboundConfig {
+            ip_filter: SharedIpFilter::new(IpFilter::parse("127.0.0.1").unwrap()),
+            ..InboundConfig::default()
+        });
+        let (_client, server, addr) = socket_pair(&listener).await;
+        assert!(limiter.admit(server, addr).is_none());
+        assert_eq!(0, limiter.pending());
+    }
+
+    #[tokio::test]
+    async fn per_ip_rate_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::new(InboundConfig {
+            per_ip_handshakes: 2,
+            ..InboundConfig::default()
+        });
+
+        let mut admitted = vec![];
+        for _ in 0..3 {
+            let (client, server, addr) = socket_pair(&listener).await;
+            admitted.push((client, limiter.admit(server, addr)));
+        }
+
+        assert!(admitted[0].1.is_some());
+        assert!(admitted[1].1.is_some());
+        assert!(admitted[2].1.is_none());
+    }
+
+    #[tokio::test]
+    async fn per_ip_window_expires() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::new(InboundConfig {
+            per_ip_handshakes: 1,
+            per_ip_window: Duration::from_millis(50),
+            ..InboundConfig::default()
+        });
+
+        let (_c1, server, addr) = socket_pair(&listener).await;
+        assert!(limiter.admit(server, addr).is_some());
+
+        time::sleep(Duration::from_millis(60)).await;
+        let (_c2, server, addr) = socket_pair(&listener).await;
+        assert!(limiter.admit(server, addr).is_some());
+    }
+
+    #[tokio::test]
+    async fn pending_cap() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::new(InboundConfig {
+            max_pending: 1,
+            ..InboundConfig::default()
+        });
+
+        let (_c1, server, addr) = socket_pair(&listener).await;
+        let first = limiter.admit(server, addr);
+        assert!(first.is_some());
+        assert_eq!(1, limiter.pending());
+
+        let (_c2, server, addr) = socket_pair(&listener).await;
+        assert!(limiter.admit(server, addr).is_none());
+
+        drop(first);
+        assert_eq!(0, limiter.pending());
+    }
+
+    #[tokio::test]
+    async fn handshake_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::new(InboundConfig {
+            handshake_timeout: Duration::from_millis(50),
+            ..InboundConfig::default()
+        });
+
+        // Client connects but never sends anything
+        let (_client, server, addr) = socket_pair(&listener).await;
+        let pending = limiter.admit(server, addr).unwrap();
+
+        assert!(pending.handshake().await.is_err());
+        assert_eq!(0, limiter.pending());
+    }
+
+    #[tokio::test]
+    async fn dual_stack() {
+        let listeners = bind_all("0.0.0.0:0".parse().unwrap(), true).unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        for listener in &listeners {
+            assert_eq!(port, listener.local_addr().unwrap().port());
+        }
+        let _v4 = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        assert!(listeners[0].accept().await.unwrap().1.is_ipv4());
+        if let Some(v6) = listeners.get(1) {
+            let _client = TcpStream::connect(("::1", port)).await.unwrap();
+            assert!(v6.accept().await.unwrap().1.is_ipv6());
+        }
+
+        // A single socket for both, IPv4 peers keep their address
+        let listeners = bind_all("[::]:0".parse().unwrap(), true).unwrap();
+        assert_eq!(1, listeners.len());
+        let port = listeners[0].local_addr().unwrap().port();
+        let _v4 = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let pending = InboundLimiter::default()
+            .accept(&listeners[0])
+            .await
+            .unwrap();
+        assert_eq!(
+            "127.0.0.1".parse::<IpAddr>().unwrap(),
+            pending.peer_addr().ip()
+        );
+    }
+
+    #[tokio::test]
+    async fn port_range() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let listeners = bind_in_range(addr, false, port..=port + 1).unwrap();
+        assert_eq!(port + 1, listeners[0].local_addr().unwrap().port());
+
+        let err = bind_in_range(addr, false, port..=port).unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+    }
+
+    #[tokio::test]
+    async fn valid_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::default();
+
+        let (mut client, server, addr) = socket_pair(&listener).await;
+        let mut hs = Handshake::default();
+        hs.set_hash(&[7; 20]);
+        client.write_all(&hs.to_bytes()).await.unwrap();
+
+        let pending = limiter.admit(server, addr).unwrap();
+        let (_, received) = pending.handshake().await.unwrap();
+        assert_eq!(hs, received);
+    }
+
+    #[tokio::test]
+    async fn garbage_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let limiter = InboundLimiter::default();
+
+        let (mut client, server, addr) = socket_pair(&listener).await;
+        client.write_all(&[0xff; 68]).await.unwrap();
+
+        let pending = limiter.admit(server, addr).unwrap();
+        assert!(pending.handshake().await.is_err());
+    }
+}
@@ -0,0 +1,258 @@
+// Magnet links, see http://bittorrent.org/beps/bep_0009.html#magnet-uri-format
+use std::{fmt, io, net::SocketAddr, ops::RangeInclusive};
+
+use crate::decode_torrent::bytes_to_hash;
+use crate::definitions::InfoHash;
+use crate::priority::Priority;
+
+const BTIH_PREFIX: &str = "urn:btih:";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: InfoHash,
+    // Display name, until the metadata is known
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Peers given with `x.pe`
+    pub peers: Vec<SocketAddr>,
+    // URLs of the `.torrent` given with `xs` or `as`
+    pub sources: Vec<String>,
+    // Indices of the files to download given with `so` (BEP 53), all
+    // files if empty
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+fn percent_decode(input: &str) -> io::Result<String> {
+    let bytes = input.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = input
+                    .get(i + 1..i + 3)
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .ok_or_else(|| invalid("Invalid percent encoding"))?;
+                res.push(hex);
+                i += 3;
+            }
+            b'+' => {
+                res.push(b' ');
+                i += 1;
+            }
+            c => {
+                res.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(res).map_err(|_| invalid("Invalid UTF-8"))
+}
+
+pub(crate) fn percent_encode(input: &str) -> String {
+    let mut res = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                res.push(byte as char)
+            }
+            _ => res.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    res
+}
+
+// Comma separated indices and inclusive ranges, e.g. `0,2,4-6`
+fn parse_select_only(value: &str) -> Option<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .map(|item| match item.split_once('-') {
+            Some((first, last)) => {
+                let range = first.parse().ok()?..=last.parse().ok()?;
+                (!range.is_empty()).then_some(range)
+            }
+            None => item.parse().ok().map(|index| index..=index),
+        })
+        .collect()
+}
+
+// 40 hex digits, or 32 base32 characters for older links
+fn parse_btih(hash: &str) -> Option<InfoHash> {
+    let mut res = [0u8; 20];
+    match hash.len() {
+        40 => {
+            for (i, byte) in res.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(hash.get(2 * i..2 * i + 2)?, 16).ok()?;
+            }
+        }
+        32 => {
+            let mut bits = 0u64;
+            let mut len = 0;
+            let mut i = 0;
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return None,
+                };
+                bits = bits << 5 | value as u64;
+                len += 5;
+                if len >= 8 {
+                    len -= 8;
+                    res[i] = (bits >> len) as u8;
+                    i += 1;
+                }
+            }
+        }
+        _ => return None,
+    }
+    Some(res)
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> io::Result<Self> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| invalid("Not a magnet link"))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        let mut sources = Vec::new();
+        let mut select_only = Vec::new();
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "xt" => {
+                    // Other kinds of exact topics (e.g. BEP 52 btmh) are skipped
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash =
+                            Some(parse_btih(hash).ok_or_else(|| invalid("Invalid info hash"))?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => {
+                    // Unresolved host names are left out
+                    if let Ok(addr) = value.parse() {
+                        peers.push(addr);
+                    }
+                }
+                "xs" | "as" => sources.push(value),
+                "so" => select_only.extend(
+                    parse_select_only(&value).ok_or_else(|| invalid("Invalid file selection"))?,
+                ),
+                _ => (),
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or_else(|| invalid("Missing info hash"))?,
+            name,
+            trackers,
+            peers,
+            sources,
+            select_only,
+        })
+    }
+
+    /// Priorities of the `num_files` files of the torrent, skipping those
+    /// not selected, or `None` if all of them are.
+    pub fn file_priorities(&self, num_files: usize) -> Option<Vec<Priority>> {
+        if self.select_only.is_empty() {
+            return None;
+        }
+        let mut files = vec![Priority::Skip; num_files];
+        for range in &self.select_only {
+            for index in range.clone().take_while(|&i| i < num_files) {
+                files[index] = Priority::Normal;
+            }
+        }
+        Some(files)
+    }
+}
+
+impl fmt::Display for Magnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "magnet:?xt={}{}",
+            BTIH_PREFIX,
+            bytes_to_hash(&self.info_hash)
+        )?;
+        if let Some(name) = &self.name {
+            write!(f, "&dn={}", percent_encode(name))?;
+        }
+        for tracker in &self.trackers {
+            write!(f, "&tr={}", percent_encode(tracker))?;
+        }
+        for peer in &self.peers {
+            write!(f, "&x.pe={}", percent_encode(&peer.to_string()))?;
+        }
+        for source in &self.sources {
+            write!(f, "&xs={}", percent_encode(source))?;
+        }
+        let select_only: Vec<_> = self
+            .select_only
+            .iter()
+            .map(|range| match range.start() == range.end() {
+                true => range.start().to_string(),
+                false => format!("{}-{}", range.start(), range.end()),
+            })
+            .collect();
+        if !select_only.is_empty() {
+            write!(f, "&so={}", select_only.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod magnet_tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789ABCDEF01234567&dn=Some+file%21\
+             &tr=udp%3A%2F%2Ftracker.example%3A6969&tr=udp://other:80&x.pe=10.0.0.1:6881\
+             &xs=http%3A%2F%2Fexample.com%2Fa.torrent&as=http://mirror/a.torrent&so=0,2,4-6",
+        )
+        .unwrap();
+        assert_eq!(0x01, magnet.info_hash[0]);
+        assert_eq!(0x67, magnet.info_hash[19]);
+        assert_eq!(Some("Some file!".to_string()), magnet.name);
+        assert_eq!(
+            vec!["udp://tracker.example:6969", "udp://other:80"],
+            magnet.trackers
+        );
+        assert_eq!(vec![SocketAddr::from(([10, 0, 0, 1], 6881))], magnet.peers);
+        assert_eq!(
+            vec!["http://example.com/a.torrent", "http://mirror/a.torrent"],
+            magnet.sources
+        );
+        assert_eq!(vec![0..=0, 2..=2, 4..=6], magnet.select_only);
+        let (skip, normal) = (Priority::Skip, Priority::Normal);
+        assert_eq!(
+            Some(vec![normal, skip, normal, skip, normal, normal]),
+            magnet.file_priorities(6)
+        );
+
+        // Base32 encodes the same bytes
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+        assert_eq!(magnet.info_hash, base32.info_hash);
+
+        assert_eq!(magnet, Magnet::parse(&magnet.to_string()).unwrap());
+
+        assert!(Magnet::parse("magnet:?dn=x").is_err());
+        assert!(Magnet::parse("http://example.com").is_err());
+        assert!(Magnet::parse("magnet:?xt=urn:btih:0123").is_err());
+        assert!(Magnet::parse(&format!("{}&so=3-1", base32)).is_err());
+    }
+}
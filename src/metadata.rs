@@ -0,0 +1,391 @@
+// Metadata exchange, see http://bittorrent.org/beps/bep_0009.html. Peers of
+// a magnet link send the info dictionary in pieces of 16 KiB.
+use std::{collections::BTreeMap, io, net::SocketAddr};
+
+use bendy::{
+    decoding::{Decoder, Error, FromBencode, Object},
+    encoding::{SingleItemEncoder, ToBencode},
+};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::capture::Capture;
+use crate::definitions::InfoHash;
+use crate::extension::{self, ExtensionHandshake};
+use crate::listener;
+use crate::peer::PeerError;
+
+pub const UT_METADATA: &str = "ut_metadata";
+// Id we ask peers to use for the ut_metadata messages they send us
+pub const UT_METADATA_ID: u8 = 1;
+pub const METADATA_PIECE_SIZE: usize = 16384;
+// Larger info dictionaries are refused rather than allocated
+pub const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+// Room for a metadata piece and its header
+const MAX_MESSAGE_SIZE: usize = 2 * METADATA_PIECE_SIZE;
+
+const REQUEST: u8 = 0;
+const DATA: u8 = 1;
+const REJECT: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: u64,
+        payload: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+// Dictionary in front of the message, a data message appends the piece to it
+struct Header {
+    msg_type: u8,
+    piece: usize,
+    total_size: Option<u64>,
+}
+
+impl ToBencode for Header {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"msg_type", self.msg_type)?;
+            e.emit_pair(b"piece", self.piece)?;
+            if let Some(size) = self.total_size {
+                e.emit_pair(b"total_size", size)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::malformed_content(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+}
+
+impl MetadataMessage {
+    pub fn piece(&self) -> usize {
+        match self {
+            MetadataMessage::Request { piece }
+            | MetadataMessage::Data { piece, .. }
+            | MetadataMessage::Reject { piece } => *piece,
+        }
+    }
+
+    /// Payload of the extended message, without the extended message ids.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, bendy::encoding::Error> {
+        let (msg_type, total_size, payload) = match self {
+            MetadataMessage::Request { .. } => (REQUEST, None, &[][..]),
+            MetadataMessage::Data {
+                total_size,
+                payload,
+                ..
+            } => (DATA, Some(*total_size), &payload[..]),
+            MetadataMessage::Reject { .. } => (REJECT, None, &[][..]),
+        };
+        let header = Header {
+            msg_type,
+            piece: self.piece(),
+            total_size,
+        };
+        let mut res = header.to_bencode()?;
+        res.extend_from_slice(payload);
+        Ok(res)
+    }
+
+    /// Full wire message, `id` is the one the peer gave to ut_metadata.
+    pub fn to_message(&self, id: u8) -> Result<Vec<u8>, bendy::encoding::Error> {
+        let payload = self.to_bytes()?;
+        let mut res = Vec::with_capacity(6 + payload.len());
+        res.extend_from_slice(&(payload.len() as u32 + 2).to_be_bytes());
+        res.push(extension::EXTENDED_MSG_ID);
+        res.push(id);
+        res.extend_from_slice(&payload);
+        Ok(res)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder::new(bytes);
+        let mut dict = match decoder.next_object()? {
+            Some(Object::Dict(dict)) => dict,
+            _ => return Err(malformed("Expected a dictionary")),
+        };
+
+        let (mut msg_type, mut piece, mut total_size) = (None, None, None);
+        while let Some(pair) = dict.next_pair()? {
+            match pair {
+                (b"msg_type", value) => msg_type = Some(u8::decode_bencode_object(value)?),
+                (b"piece", value) => piece = Some(usize::decode_bencode_object(value)?),
+                (b"total_size", value) => total_size = Some(u64::decode_bencode_object(value)?),
+                _ => (),
+            }
+        }
+        let header_len = dict.into_raw()?.len();
+        let piece = piece.ok_or_else(|| Error::missing_field("piece"))?;
+
+        match msg_type.ok_or_else(|| Error::missing_field("msg_type"))? {
+            REQUEST => Ok(MetadataMessage::Request { piece }),
+            DATA => Ok(MetadataMessage::Data {
+                piece,
+                total_size: total_size.ok_or_else(|| Error::missing_field("total_size"))?,
+                payload: bytes[header_len..].to_vec(),
+            }),
+            REJECT => Ok(MetadataMessage::Reject { piece }),
+            n => Err(malformed(&format!("Unknown msg_type {}", n))),
+        }
+    }
+}
+
+/// Pieces of the info dictionary received so far.
+#[derive(Debug)]
+pub struct MetadataDownload {
+    size: usize,
+    pieces: BTreeMap<usize, Vec<u8>>,
+}
+
+impl MetadataDownload {
+    pub fn new(size: usize) -> Self {
+        MetadataDownload {
+            size,
+            pieces: BTreeMap::new(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.size.div_ceil(METADATA_PIECE_SIZE)
+    }
+
+    fn piece_len(&self, piece: usize) -> usize {
+        (self.size - piece * METADATA_PIECE_SIZE).min(METADATA_PIECE_SIZE)
+    }
+
+    /// Pieces still to request.
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.num_pieces())
+            .filter(|p| !self.pieces.contains_key(p))
+            .collect()
+    }
+
+    /// Returns `false` if the piece doesn't fit the announced size.
+    pub fn received(&mut self, piece: usize, data: Vec<u8>) -> bool {
+        if piece >= self.num_pieces() || data.len() != self.piece_len(piece) {
+            return false;
+        }
+        self.pieces.insert(piece, data);
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.len() == self.num_pieces()
+    }
+
+    /// The whole dictionary, once complete and matching `info_hash`.
+    pub fn finish(self, info_hash: &InfoHash) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let res: Vec<u8> = self.pieces.into_values().flatten().collect();
+        (Sha1::digest(&res)[..] == info_hash[..]).then_some(res)
+    }
+}
+
+// bendy errors don't implement `std::error::Error`
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn malformed_message<E>(_: E) -> PeerError {
+    PeerError::Malformed("ut_metadata")
+}
+
+// Where the messages of the connection are recorded, if anywhere
+type Recorder<'a> = Option<(&'a Capture, SocketAddr)>;
+
+async fn read_message(stream: &mut TcpStream, capture: Recorder<'_>) -> io::Result<Vec<u8>> {
+    let mut prefix = [0u8; 4];
+    stream.read_exact(&mut prefix).await?;
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        // Bitfields of huge torrents are skipped, not buffered
+        let mut rest = (&mut *stream).take(len as u64);
+        tokio::io::copy(&mut rest, &mut tokio::io::sink()).await?;
+        return Ok(Vec::new());
+    }
+    let mut msg = vec![0u8; len];
+    stream.read_exact(&mut msg).await?;
+    if let Some((capture, addr)) = capture {
+        capture.peer_in(addr, &[&prefix[..], &msg].concat());
+    }
+    Ok(msg)
+}
+
+async fn write_message(
+    stream: &mut TcpStream,
+    msg: &[u8],
+    capture: Recorder<'_>,
+) -> io::Result<()> {
+    stream.write_all(msg).await?;
+    if let Some((capture, addr)) = capture {
+        capture.peer_out(addr, msg);
+    }
+    Ok(())
+}
+
+/// Download the info dictionary from a peer which we just handshaked with
+/// the extension protocol bit set. Other messages of the peer are dropped,
+/// the connection is meant to be closed afterwards. `listen_port` is
+/// advertised to the peer. The messages are recorded to `capture` if given.
+pub async fn fetch(
+    stream: &mut TcpStream,
+    info_hash: &InfoHash,
+    listen_port: Option<u16>,
+    capture: Option<&Capture>,
+) -> Result<Vec<u8>, PeerError> {
+    let peer_addr = stream.peer_addr().ok();
+    let recorder = capture.zip(peer_addr);
+    let peer_ip = peer_addr.map(|a| listener::canonical(a).ip());
+    let mut ours = ExtensionHandshake::ours(listen_port, peer_ip, None);
+    ours.m.insert(UT_METADATA.to_string(), UT_METADATA_ID);
+    let msg = ours.to_message().map_err(invalid_data)?;
+    write_message(stream, &msg, recorder).await?;
+
+    let mut download: Option<(u8, MetadataDownload)> = None;
+    loop {
+        let msg = read_message(stream, recorder).await?;
+        if msg.len() < 2 || msg[0] != extension::EXTENDED_MSG_ID {
+            continue;
+        }
+
+        match (msg[1], &mut download) {
+            (extension::HANDSHAKE_EXT_ID, None) => {
+                let hs = ExtensionHandshake::from_bencode(&msg[2..]).map_err(malformed_message)?;
+                let id = hs
+                    .extension_id(UT_METADATA)
+                    .ok_or(PeerError::Metadata("Peer doesn't support ut_metadata"))?;
+                let size = hs
+                    .metadata_size
+                    .filter(|&s| s > 0 && s <= MAX_METADATA_SIZE)
+                    .ok_or(PeerError::Metadata("Invalid metadata size"))?;
+
+                let pieces = MetadataDownload::new(size as usize);
+                for piece in pieces.missing() {
+                    let request = MetadataMessage::Request { piece };
+                    let msg = request.to_message(id).map_err(invalid_data)?;
+                    write_message(stream, &msg, recorder).await?;
+                }
+                download = Some((id, pieces));
+            }
+            (UT_METADATA_ID, Some((_, pieces))) => {
+                match MetadataMessage::from_bytes(&msg[2..]).map_err(malformed_message)? {
+                    MetadataMessage::Data { piece, payload, .. } => {
+                        if !pieces.received(piece, payload) {
+                            return Err(PeerError::Metadata("Invalid metadata piece"));
+                        }
+                        if pieces.is_complete() {
+                            let (_, pieces) = download.take().unwrap();
+                            return pieces
+                                .finish(info_hash)
+                                .ok_or(PeerError::Metadata("Info hash mismatch"));
+                        }
+                    }
+                    MetadataMessage::Reject { .. } => {
+                        return Err(PeerError::Metadata("Metadata request rejected"))
+                    }
+                    // We don't have the metadata either
+                    MetadataMessage::Request { piece } => {
+                        let (id, _) = download.as_ref().unwrap();
+                        let reject = MetadataMessage::Reject { piece };
+                        let msg = reject.to_message(*id).map_err(invalid_data)?;
+                        write_message(stream, &msg, recorder).await?;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod metadata_tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn messages() {
+        let data = MetadataMessage::Data {
+            piece: 1,
+            total_size: 20000,
+            payload: b"d4:spami1ee".to_vec(),
+        };
+        let bytes = data.to_bytes().unwrap();
+        assert_eq!(
+            b"d8:msg_typei1e5:piecei1e10:total_sizei20000eed4:spami1ee".to_vec(),
+            bytes
+        );
+        assert_eq!(data, MetadataMessage::from_bytes(&bytes).unwrap());
+
+        let request = MetadataMessage::Request { piece: 0 };
+        assert_eq!(
+            &[0, 0, 0, 27, extension::EXTENDED_MSG_ID, 3],
+            &request.to_message(3).unwrap()[..6]
+        );
+        assert!(MetadataMessage::from_bytes(b"d8:msg_typei1e5:piecei0ee").is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_from_a_peer() {
+        let metadata: Vec<u8> = (0..METADATA_PIECE_SIZE + 100).map(|i| i as u8).collect();
+        let info_hash: InfoHash = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut remote, _) = listener.accept().await.unwrap();
+        let served = metadata.clone();
+        let server = tokio::spawn(async move {
+            let mut hs = ExtensionHandshake::default();
+            hs.m.insert(UT_METADATA.to_string(), 7);
+            hs.metadata_size = Some(served.len() as u64);
+            remote.write_all(&hs.to_message().unwrap()).await.unwrap();
+            let mut port = None;
+            while let Ok(msg) = read_message(&mut remote, None).await {
+                if msg.get(1) == Some(&extension::HANDSHAKE_EXT_ID) {
+                    port = ExtensionHandshake::from_bencode(&msg[2..]).unwrap().p;
+                }
+                if msg.get(1) != Some(&7) {
+                    continue;
+                }
+                let piece = MetadataMessage::from_bytes(&msg[2..]).unwrap().piece();
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = served.len().min(start + METADATA_PIECE_SIZE);
+                let data = MetadataMessage::Data {
+                    piece,
+                    total_size: served.len() as u64,
+                    payload: served[start..end].to_vec(),
+                };
+                let msg = data.to_message(UT_METADATA_ID).unwrap();
+                remote.write_all(&msg).await.unwrap();
+            }
+            port
+        });
+
+        assert_eq!(
+            metadata,
+            fetch(&mut stream, &info_hash, Some(6881), None)
+                .await
+                .unwrap()
+        );
+        drop(stream);
+        assert_eq!(Some(6881), server.await.unwrap());
+    }
+}
@@ -0,0 +1,303 @@
+// Prometheus exporter of the session metrics, for seedbox monitoring. Each
+// torrent is a series labelled with its info hash and name.
+use std::{convert::Infallible, fmt::Write, io, net::SocketAddr, sync::Arc};
+
+use hyper::{
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::task::JoinHandle;
+
+use crate::decode_torrent::bytes_to_hash;
+use crate::definitions::InfoHash;
+use crate::dht::DhtStats;
+use crate::routing::K;
+use crate::session::Session;
+use crate::stats::TorrentStats;
+
+pub const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+type Getter = fn(&TorrentStats) -> Option<f64>;
+
+// Name, type, help and value of the per-torrent metrics
+const METRICS: [(&str, &str, &str, Getter); 13] = [
+    (
+        "torrent_rs_progress_ratio",
+        "gauge",
+        "Fraction of the pieces verified",
+        |s| Some(s.progress),
+    ),
+    (
+        "torrent_rs_download_rate_bytes",
+        "gauge",
+        "Download rate in bytes per second",
+        |s| Some(s.download_rate),
+    ),
+    (
+        "torrent_rs_upload_rate_bytes",
+        "gauge",
+        "Upload rate in bytes per second",
+        |s| Some(s.upload_rate),
+    ),
+    (
+        "torrent_rs_downloaded_bytes_total",
+        "counter",
+        "Payload bytes downloaded",
+        |s| Some(s.downloaded as f64),
+    ),
+    (
+        "torrent_rs_uploaded_bytes_total",
+        "counter",
+        "Payload bytes uploaded",
+        |s| Some(s.uploaded as f64),
+    ),
+    (
+        "torrent_rs_peers_connected",
+        "gauge",
+        "Connected peers",
+        |s| Some(s.connected_peers as f64),
+    ),
+    (
+        "torrent_rs_peers_known",
+        "gauge",
+        "Peers heard of, connected or not",
+        |s| Some(s.known_peers as f64),
+    ),
+    (
+        "torrent_rs_piece_failures_total",
+        "counter",
+        "Pieces which didn't match their hash",
+        |s| Some(s.piece_failures as f64),
+    ),
+    // Absent until the first announce
+    (
+        "torrent_rs_tracker_up",
+        "gauge",
+        "Whether the last announce succeeded",
+        |s| match (&s.tracker_error, s.seeds) {
+            (Some(_), _) => Some(0.0),
+            (None, Some(_)) => Some(1.0),
+            (None, None) => None,
+        },
+    ),
+    (
+        "torrent_rs_disk_reads_total",
+        "counter",
+        "Piece reads from the disk",
+        |s| Some(s.disk.reads as f64),
+    ),
+    (
+        "torrent_rs_disk_read_seconds_total",
+        "counter",
+        "Time spent reading pieces",
+        |s| Some(s.disk.read_time.as_secs_f64()),
+    ),
+    (
+        "torrent_rs_disk_writes_total",
+        "counter",
+        "Writes of pieces to the disk",
+        |s| Some(s.disk.writes as f64),
+    ),
+    (
+        "torrent_rs_disk_write_seconds_total",
+        "counter",
+        "Time spent writing pieces",
+        |s| Some(s.disk.write_time.as_secs_f64()),
+    ),
+];
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus text format of the stats of some torrents.
+pub fn render_stats(torrents: &[(InfoHash, String, TorrentStats)]) -> String {
+    let mut res = String::new();
+    let _ = writeln!(res, "# HELP torrent_rs_torrents Torrents in the session");
+    let _ = writeln!(res, "# TYPE torrent_rs_torrents gauge");
+    let _ = writeln!(res, "torrent_rs_torrents {}", torrents.len());
+
+    for (name, kind, help, getter) in METRICS {
+        let _ = writeln!(res, "# HELP {} {}", name, help);
+        let _ = writeln!(res, "# TYPE {} {}", name, kind);
+        for (info_hash, torrent, stats) in torrents {
+            if let Some(value) = getter(stats) {
+                let _ = writeln!(
+                    res,
+                    "{}{{info_hash=\"{}\",name=\"{}\"}} {}",
+                    name,
+                    bytes_to_hash(info_hash),
+                    escape_label(torrent),
+                    value
+                );
+            }
+        }
+    }
+    res
+}
+
+/// Prometheus text format of the health of a DHT node.
+pub fn render_dht(stats: &DhtStats) -> String {
+    let capacity = (stats.buckets.len() * K).max(1);
+    let gauges = [
+        (
+            "torrent_rs_dht_nodes",
+            "Nodes in the DHT routing table",
+            stats.nodes as f64,
+        ),
+        (
+            "torrent_rs_dht_good_nodes",
+            "Nodes of the routing table which answered lately",
+            stats.good_nodes as f64,
+        ),
+        (
+            "torrent_rs_dht_buckets",
+            "Buckets of the routing table",
+            stats.buckets.len() as f64,
+        ),
+        (
+            "torrent_rs_dht_bucket_fullness_ratio",
+            "Nodes in the buckets, of those they fit",
+            stats.nodes as f64 / capacity as f64,
+        ),
+    ];
+    let mut res = String::new();
+    for (name, help, value) in gauges {
+        let _ = writeln!(res, "# HELP {} {}", name, help);
+        let _ = writeln!(res, "# TYPE {} gauge", name);
+        let _ = writeln!(res, "{} {}", name, value);
+    }
+    if let Some(last) = stats.last_refresh {
+        let name = "torrent_rs_dht_last_refresh_seconds";
+        let _ = writeln!(res, "# HELP {} Since the routing table was refreshed", name);
+        let _ = writeln!(res, "# TYPE {} gauge", name);
+        let _ = writeln!(res, "{} {}", name, last.as_secs_f64());
+    }
+    res
+}
+
+/// Current metrics of the torrents of `session`, and of its DHT node.
+pub async fn render(session: &Session) -> String {
+    let mut torrents = Vec::new();
+    for info_hash in session.list() {
+        if let Some(torrent) = session.get(&info_hash) {
+            let torrent = torrent.lock().await;
+            torrents.push((info_hash, torrent.meta().info.name.clone(), torrent.stats()));
+        }
+    }
+    let mut res = render_stats(&torrents);
+    if let Some(dht) = session.dht() {
+        res.push_str(&render_dht(&dht.stats()));
+    }
+    res
+}
+
+async fn handle(session: Arc<Session>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, METRICS_PATH) => Response::builder()
+            .header("Content-Type", CONTENT_TYPE)
+            .body(Body::from(render(&session).await)),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(res.expect("Valid response"))
+}
+
+/// Serve the metrics of `session` on `addr` until the task is aborted.
+/// Returns the address bound, e.g. when `addr` has port 0.
+pub fn serve(session: Arc<Session>, addr: SocketAddr) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    let incoming = AddrIncoming::bind(&addr).map_err(io::Error::other)?;
+    let local_addr = incoming.local_addr();
+    let make_service = make_service_fn(move |_| {
+        let session = session.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(session.clone(), req))) }
+    });
+    let server = Server::builder(incoming).serve(make_service);
+    let handle = tokio::spawn(async move {
+        let _ = server.await;
+    });
+    Ok((local_addr, handle))
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+    use crate::decode_torrent::{self, Info, MetaInfo};
+    use crate::session::SessionConfig;
+    use std::fs;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn scrape() {
+        const FILE: &str = "test_metrics_scrape";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let session = Arc::new(Session::new(config).await.unwrap());
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: FILE.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        session
+            .add_torrent(session.open_torrent(meta, [1; 20]).await.unwrap())
+            .unwrap();
+
+        let (addr, server) = serve(session, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let res = get(addr, METRICS_PATH).await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("\ntorrent_rs_torrents 1\n"));
+        assert!(res.contains(&format!(
+            "\ntorrent_rs_peers_connected{{info_hash=\"{}\",name=\"{}\"}} 0\n",
+            "01".repeat(20),
+            FILE
+        )));
+        assert!(res.contains("# TYPE torrent_rs_piece_failures_total counter\n"));
+        // Never announced
+        assert!(!res.contains("torrent_rs_tracker_up{"));
+        // No DHT
+        assert!(!res.contains("torrent_rs_dht_nodes"));
+
+        assert!(get(addr, "/other").await.starts_with("HTTP/1.1 404"));
+        server.abort();
+        fs::remove_file(FILE).unwrap();
+    }
+}
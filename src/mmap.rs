@@ -0,0 +1,323 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use bytes::Bytes;
+use memmap2::{MmapMut, MmapOptions};
+
+use sha1::{Digest, Sha1};
+
+use crate::definitions::InfoHash;
+use crate::hash::PieceHash;
+use crate::storage::{StorageError, StorageResult, TorrentStorage};
+
+/// Expected access pattern, forwarded to the kernel with `madvise`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessPattern {
+    Normal,
+    // Streaming or rechecking, read ahead aggressively
+    Sequential,
+    // Typical seeding to many peers
+    Random,
+}
+
+/// Storage mapping the whole file in memory. The page cache does the caching,
+/// which suits read-heavy seeding better than the piece cache of
+/// [`crate::file::FileEntity`].
+#[derive(Debug)]
+pub struct MmapStorage {
+    file: File,
+    // None for empty files which can't be mapped
+    map: Option<MmapMut>,
+    piece_size: usize,
+    size: usize,
+    num_pieces: usize,
+    verified: Vec<bool>,
+    pattern: AccessPattern,
+}
+
+impl MmapStorage {
+    pub fn new<F: AsRef<Path>>(file: F, piece_size: usize, size: usize) -> StorageResult<Self> {
+        let existing = match fs::metadata(&file) {
+            Ok(m) if !m.is_file() => return Err(StorageError::NotAFile),
+            Ok(m) => Some(m.len() as usize),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(len) = existing.filter(|&len| len != 0 && len != size) {
+            return Err(StorageError::SizeMismatch {
+                expected: size as u64,
+                actual: len as u64,
+            });
+        }
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(file)?;
+
+        let mut res = MmapStorage {
+            file,
+            map: None,
+            piece_size,
+            size,
+            num_pieces: size.div_ceil(piece_size),
+            verified: vec![false; size.div_ceil(piece_size)],
+            pattern: AccessPattern::Normal,
+        };
+        res.grow()?;
+
+        Ok(res)
+    }
+
+    // The file must be at least as long as the mapping, touching a mapped
+    // page past the end of the file raises SIGBUS.
+    fn grow(&mut self) -> io::Result<()> {
+        if (self.file.metadata()?.len() as usize) < self.size {
+            self.file.set_len(self.size as u64)?;
+        }
+
+        if self.size == 0 {
+            return Ok(());
+        }
+
+        // SAFETY: the file is opened read/write by us and sized to at least
+        // `size` bytes. Truncation by another process is detected by
+        // `check_len` before every access.
+        let map = unsafe { MmapOptions::new().len(self.size).map_mut(&self.file)? };
+        self.map = Some(map);
+        self.apply_pattern()
+    }
+
+    fn check_len(&self) -> StorageResult<()> {
+        if (self.file.metadata()?.len() as usize) < self.size {
+            return Err(StorageError::Truncated);
+        }
+        Ok(())
+    }
+
+    fn apply_pattern(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(map) = &self.map {
+            use memmap2::Advice;
+
+            map.advise(match self.pattern {
+                AccessPattern::Normal => Advice::Normal,
+                AccessPattern::Sequential => Advice::Sequential,
+                AccessPattern::Random => Advice::Random,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_access_pattern(&mut self, pattern: AccessPattern) -> StorageResult<()> {
+        self.pattern = pattern;
+        Ok(self.apply_pattern()?)
+    }
+
+    /// Hint the kernel that a piece will be read soon.
+    pub fn will_need(&self, index: usize) -> StorageResult<()> {
+        #[cfg(unix)]
+        if let Some(map) = &self.map {
+            map.advise_range(
+                memmap2::Advice::WillNeed,
+                index * self.piece_size,
+                self.piece_len(index),
+            )?;
+        }
+
+        #[cfg(not(unix))]
+        let _ = index;
+
+        Ok(())
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn piece_len(&self, index: usize) -> usize {
+        assert!(index < self.num_pieces);
+        if index == self.num_pieces - 1 {
+            self.size - index * self.piece_size
+        } else {
+            self.piece_size
+        }
+    }
+
+    fn range(&self, index: usize, begin: usize, length: usize) -> StorageResult<(usize, usize)> {
+        if index >= self.num_pieces || begin + length > self.piece_len(index) {
+            return Err(StorageError::OutOfRange);
+        }
+        let start = index * self.piece_size + begin;
+        Ok((start, start + length))
+    }
+
+    /// Borrow a block straight from the mapping, without copying it.
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> StorageResult<&[u8]> {
+        let (start, end) = self.range(index, begin, length)?;
+        self.check_len()?;
+
+        match &self.map {
+            Some(map) => Ok(&map[start..end]),
+            None => Ok(&[]),
+        }
+    }
+
+    pub fn write_block(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        let (start, end) = self.range(index, begin, data.len())?;
+        self.check_len()?;
+
+        if let Some(map) = &mut self.map {
+            map[start..end].copy_from_slice(data);
+        }
+
+        Ok(())
+    }
+
+    pub fn hash_piece(&self, index: usize) -> StorageResult<InfoHash> {
+        let mut hasher = Sha1::new();
+        hasher.update(self.read_block(index, 0, self.piece_len(index))?);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Hash a piece and mark it as verified if it matches `expected`.
+    pub fn verify_piece(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        let data = MmapStorage::read_block(self, index, 0, self.piece_len(index))?;
+        let valid = expected.matches(data, self.piece_size);
+        self.verified[index] = valid;
+        Ok(valid)
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        self.verified[index]
+    }
+
+    pub fn get_bitfield(&self) -> &Vec<bool> {
+        &self.verified
+    }
+
+    /// Write the dirty pages of a piece back to the file.
+    pub fn flush_piece(&self, index: usize) -> StorageResult<()> {
+        match &self.map {
+            Some(map) => Ok(map.flush_range(index * self.piece_size, self.piece_len(index))?),
+            None => Ok(()),
+        }
+    }
+
+    pub fn flush(&self) -> StorageResult<()> {
+        match &self.map {
+            Some(map) => Ok(map.flush()?),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TorrentStorage for MmapStorage {
+    async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        Ok(Bytes::copy_from_slice(MmapStorage::read_block(
+            self, index, begin, length,
+        )?))
+    }
+
+    async fn write_block(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        MmapStorage::write_block(self, index, begin, data)
+    }
+
+    async fn verify_piece(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        MmapStorage::verify_piece(self, index, expected)
+    }
+
+    async fn flush(&mut self) -> StorageResult<()> {
+        MmapStorage::flush(self)
+    }
+
+    fn len(&self) -> u64 {
+        self.size as u64
+    }
+}
+
+#[cfg(test)]
+mod mmap_tests {
+    use super::*;
+
+    #[test]
+    fn write_read_flush() {
+        const FILE: &str = "./test_mmap_write_read";
+        const PSIZE: usize = 256;
+        const FSIZE: usize = 1000;
+
+        let mut storage = MmapStorage::new(FILE, PSIZE, FSIZE).unwrap();
+        assert_eq!(4, storage.num_pieces());
+        assert_eq!(FSIZE - 3 * PSIZE, storage.piece_len(3));
+
+        storage.write_block(3, 10, &[7; 20]).unwrap();
+        assert_eq!(&[7; 20], storage.read_block(3, 10, 20).unwrap());
+        assert!(storage.write_block(3, 230, &[0; 10]).is_err());
+
+        storage.set_access_pattern(AccessPattern::Random).unwrap();
+        storage.will_need(0).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let content = fs::read(FILE).unwrap();
+        assert_eq!(FSIZE, content.len());
+        assert_eq!(vec![7; 20], content[3 * PSIZE + 10..3 * PSIZE + 30]);
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn verify_existing_data() {
+        const FILE: &str = "./test_mmap_verify";
+        let data = vec![3u8; 512];
+        fs::write(FILE, &data).unwrap();
+
+        let mut hasher = Sha1::new();
+        hasher.update(&data[..256]);
+        let expected: InfoHash = hasher.finalize().into();
+
+        let mut storage = MmapStorage::new(FILE, 256, 512).unwrap();
+        assert!(storage.verify_piece(0, &expected.into()).unwrap());
+        assert!(storage.verify_piece(1, &expected.into()).unwrap());
+        assert_eq!(&vec![true, true], storage.get_bitfield());
+
+        drop(storage);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn truncated_file() {
+        const FILE: &str = "./test_mmap_truncated";
+
+        let storage = MmapStorage::new(FILE, 256, 512).unwrap();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(FILE)
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+
+        let res = storage.read_block(1, 0, 256);
+        assert!(matches!(res, Err(StorageError::Truncated)));
+
+        drop(storage);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn wrong_size() {
+        let res = MmapStorage::new("./Cargo.toml", 256, 1);
+        assert!(matches!(res, Err(StorageError::SizeMismatch { .. })));
+    }
+}
@@ -0,0 +1,299 @@
+// Sidecar file holding pieces which overlap files that aren't downloaded.
+// Such a piece can't be written to its files, but it is still needed to
+// complete, verify and serve the neighbouring files.
+//
+// Format: magic, number of pieces and piece size, then one u32 per piece
+// holding its slot + 1 (0 when absent), then the slots of `piece_size` bytes.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::backend::{pread_exact, pwrite_all};
+
+const MAGIC: &[u8; 8] = b"TRSPARTS";
+const HEADER_LEN: usize = MAGIC.len() + 8;
+
+#[derive(Debug)]
+pub struct PartsFile {
+    path: PathBuf,
+    // Created with the first stored piece
+    file: Option<Arc<File>>,
+    num_pieces: usize,
+    piece_size: usize,
+    slots: BTreeMap<usize, usize>,
+    free: BTreeSet<usize>,
+}
+
+fn corrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Corrupted parts file")
+}
+
+fn out_of_range() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Out of the parts file pieces")
+}
+
+impl PartsFile {
+    /// Open the parts file at `path`, loading its table if it exists.
+    pub fn open<P: AsRef<Path>>(path: P, num_pieces: usize, piece_size: usize) -> io::Result<Self> {
+        let mut res = PartsFile {
+            path: path.as_ref().to_owned(),
+            file: None,
+            num_pieces,
+            piece_size,
+            slots: BTreeMap::new(),
+            free: BTreeSet::new(),
+        };
+
+        let file = match fs::OpenOptions::new().read(true).write(true).open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(res),
+            Err(e) => return Err(e),
+        };
+
+        let mut header = vec![0u8; HEADER_LEN + 4 * num_pieces];
+        pread_exact(&file, &mut header, 0).map_err(|_| corrupted())?;
+        if &header[..8] != MAGIC
+            || header[8..12] != (num_pieces as u32).to_be_bytes()
+            || header[12..16] != (piece_size as u32).to_be_bytes()
+        {
+            return Err(corrupted());
+        }
+
+        for (index, entry) in header[HEADER_LEN..].chunks(4).enumerate() {
+            let slot = u32::from_be_bytes(entry.try_into().unwrap()) as usize;
+            if slot != 0 {
+                res.slots.insert(index, slot - 1);
+            }
+        }
+
+        let used: BTreeSet<usize> = res.slots.values().copied().collect();
+        if used.len() != res.slots.len() {
+            return Err(corrupted());
+        }
+        let max = used.last().map_or(0, |&s| s + 1);
+        res.free = (0..max).filter(|s| !used.contains(s)).collect();
+        res.file = Some(Arc::new(file));
+
+        Ok(res)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.slots.contains_key(&index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn pieces(&self) -> impl Iterator<Item = usize> + '_ {
+        self.slots.keys().copied()
+    }
+
+    fn slot_offset(&self, slot: usize) -> u64 {
+        (HEADER_LEN + 4 * self.num_pieces + slot * self.piece_size) as u64
+    }
+
+    fn write_entry(&self, index: usize, value: u32) -> io::Result<()> {
+        let file = self.file.as_ref().unwrap();
+        pwrite_all(file, &value.to_be_bytes(), (HEADER_LEN + 4 * index) as u64)
+    }
+
+    fn create(&mut self) -> io::Result<&Arc<File>> {
+        if self.file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&self.path)?;
+
+            let mut header = Vec::with_capacity(HEADER_LEN);
+            header.extend_from_slice(MAGIC);
+            header.extend_from_slice(&(self.num_pieces as u32).to_be_bytes());
+            header.extend_from_slice(&(self.piece_size as u32).to_be_bytes());
+            pwrite_all(&file, &header, 0)?;
+            file.set_len(self.slot_offset(0))?;
+
+            self.file = Some(Arc::new(file));
+        }
+        Ok(self.file.as_ref().unwrap())
+    }
+
+    /// Write data of a piece, allocating a slot for it if needed.
+    pub fn write(&mut self, index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let (file, offset) = self.locate_write(index, begin, data.len())?;
+        pwrite_all(&file, data, offset)
+    }
+
+    /// Where to write data of a piece, allocating a slot for it if needed.
+    /// The write itself is left to the caller.
+    pub fn locate_write(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<(Arc<File>, u64)> {
+        if index >= self.num_pieces
+            || begin
+                .checked_add(length)
+                .is_none_or(|end| end > self.piece_size)
+        {
+            return Err(out_of_range());
+        }
+        self.create()?;
+
+        let slot = match self.slots.get(&index) {
+            Some(&slot) => slot,
+            None => {
+                let slot = match self.free.pop_first() {
+                    Some(slot) => slot,
+                    None => self.slots.values().max().map_or(0, |&s| s + 1),
+                };
+                self.write_entry(index, slot as u32 + 1)?;
+                self.slots.insert(index, slot);
+                slot
+            }
+        };
+
+        let file = self.file.clone().unwrap();
+        Ok((file, self.slot_offset(slot) + begin as u64))
+    }
+
+    pub fn read(&self, index: usize, begin: usize, length: usize) -> io::Result<Vec<u8>> {
+        let (file, offset, available) = self.locate_read(index, begin, length)?;
+        let mut res = vec![0u8; length];
+        pread_exact(&file, &mut res[..available], offset)?;
+        Ok(res)
+    }
+
+    /// Where data of a stored piece is, and how many of the `length` bytes
+    /// were written. The rest reads as zeros. The read itself is left to the
+    /// caller.
+    pub fn locate_read(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<(Arc<File>, u64, usize)> {
+        if begin
+            .checked_add(length)
+            .is_none_or(|end| end > self.piece_size)
+        {
+            return Err(out_of_range());
+        }
+        let slot = *self
+            .slots
+            .get(&index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Piece not in parts file"))?;
+
+        let file = self.file.clone().unwrap();
+        let offset = self.slot_offset(slot) + begin as u64;
+        // Slots are only as long as what was written in them
+        let available = file
+            .metadata()?
+            .len()
+            .saturating_sub(offset)
+            .min(length as u64) as usize;
+
+        Ok((file, offset, available))
+    }
+
+    /// Handle of the file, once created.
+    pub fn handle(&self) -> Option<&Arc<File>> {
+        self.file.as_ref()
+    }
+
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Release the file handle, e.g. before renaming the file. The parts
+    /// file must be opened again before being used.
+    pub fn close(&mut self) {
+        self.file = None;
+    }
+
+    /// Forget a piece. The file is deleted once it doesn't hold anything.
+    pub fn remove(&mut self, index: usize) -> io::Result<()> {
+        let Some(slot) = self.slots.remove(&index) else {
+            return Ok(());
+        };
+
+        if self.slots.is_empty() {
+            self.file = None;
+            self.free.clear();
+            return fs::remove_file(&self.path);
+        }
+
+        self.write_entry(index, 0)?;
+        self.free.insert(slot);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod parts_tests {
+    use super::*;
+
+    #[test]
+    fn store_and_reload() {
+        const FILE: &str = "./test_parts_store_and_reload.parts";
+
+        let mut parts = PartsFile::open(FILE, 10, 8).unwrap();
+        assert!(parts.is_empty());
+        assert!(!Path::new(FILE).exists());
+
+        parts.write(7, 0, &[7; 8]).unwrap();
+        parts.write(2, 4, &[2; 4]).unwrap();
+        assert_eq!(vec![0, 0, 0, 0, 2, 2, 2, 2], parts.read(2, 0, 8).unwrap());
+        drop(parts);
+
+        let mut parts = PartsFile::open(FILE, 10, 8).unwrap();
+        assert_eq!(vec![2, 7], parts.pieces().collect::<Vec<_>>());
+        assert_eq!(vec![7; 8], parts.read(7, 0, 8).unwrap());
+
+        // The slot of 7 is reused
+        parts.remove(7).unwrap();
+        parts.write(3, 0, &[3; 8]).unwrap();
+        assert_eq!(
+            HEADER_LEN + 4 * 10 + 2 * 8,
+            fs::metadata(FILE).unwrap().len() as usize
+        );
+        assert!(parts.read(7, 0, 8).is_err());
+
+        parts.remove(3).unwrap();
+        parts.remove(2).unwrap();
+        assert!(!Path::new(FILE).exists());
+    }
+
+    #[test]
+    fn wrong_geometry() {
+        const FILE: &str = "./test_parts_wrong_geometry.parts";
+
+        let mut parts = PartsFile::open(FILE, 10, 8).unwrap();
+        parts.write(0, 0, &[1; 8]).unwrap();
+        drop(parts);
+
+        let res = PartsFile::open(FILE, 10, 16);
+        assert_eq!(io::ErrorKind::InvalidData, res.unwrap_err().kind());
+
+        fs::remove_file(FILE).unwrap();
+    }
+}
@@ -1,17 +1,110 @@
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, Mutex, RwLock};
 use tokio::time::{self, Duration};
 
-use std::error::Error;
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use crate::decode_torrent::MetaInfo;
-use crate::file::FileEntity;
+use bendy::decoding::FromBencode;
+use thiserror::Error;
+
+use crate::availability::SharedAvailability;
+use crate::capture::Capture;
+use crate::decode_torrent::{Info, MetaInfo, MetaInfoError};
+use crate::definitions::InfoHash;
+use crate::extension::{self, ExtensionHandshake};
+use crate::fairness::RateBudget;
+use crate::file::{SharedFile, Storage, StorageConfig};
+use crate::storage::{StorageDirs, StorageError};
+
+// Larger messages are refused rather than allocated, a bitfield of 16M
+// pieces still fits
+pub const MAX_MESSAGE_LEN: usize = 2 * 1024 * 1024 + 1;
+
+/// Why a connection to a peer was dropped. Misbehaving peers only lose their
+/// own connection.
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("Connection error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid handshake")]
+    InvalidHandshake,
+    #[error("Peer has another torrent")]
+    WrongTorrent,
+    // Peers of private torrents only come from their tracker, see
+    // `PeerSource::is_allowed_for_private`
+    #[error("Peers from {0:?} are not allowed for a private torrent")]
+    ForbiddenSource(PeerSource),
+    #[error("Message of {0} bytes is too long")]
+    MessageTooLong(usize),
+    // Message of a known type but the wrong length
+    #[error("Malformed {0} message")]
+    Malformed(&'static str),
+    #[error("Metadata exchange failed: {0}")]
+    Metadata(&'static str),
+    #[error(transparent)]
+    MetaInfo(#[from] MetaInfoError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl From<PeerError> for io::Error {
+    fn from(e: PeerError) -> Self {
+        match e {
+            PeerError::Io(e) => e,
+            PeerError::Storage(e) => e.into(),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// Where a peer candidate was learned from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Lsd,
+    Incoming,
+    Manual,
+}
+
+/// Number of peers per source, for UI breakdowns.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeerSourceStats {
+    counts: [usize; PeerSource::ALL.len()],
+}
+
+/// What happened on a connection, for the torrent coordinating its peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    // The peer choked (`true`) or unchoked us
+    Choke(bool),
+    Have(usize),
+    // Bitfield, have-all or have-none
+    Bitfield(Vec<bool>),
+    // Answer to a request, whether it was needed or not
+    Block {
+        index: usize,
+        begin: usize,
+        length: usize,
+    },
+    // Bytes of a block we sent
+    Uploaded(usize),
+    PieceVerified {
+        index: usize,
+        valid: bool,
+    },
+    Closed,
+}
+
+pub type PeerEventSender = mpsc::UnboundedSender<(SocketAddr, PeerEvent)>;
 
-// TODO: Add a list of shared files with peer
 pub struct Peer {
     am_choking: bool,
     am_interested: bool,
@@ -19,8 +112,77 @@ pub struct Peer {
     peer_interested: bool,
     stream: TcpStream,
     have: Vec<bool>,
-    torrent: MetaInfo,
-    file: FileEntity,
+    // Both unknown until the metadata of a magnet link is fetched
+    torrent: Option<Arc<MetaInfo>>,
+    file: Option<SharedFile>,
+    // Hash the metadata is checked against, for magnet links
+    info_hash: Option<InfoHash>,
+    // Where the storage is created once the metadata is known
+    save_path: PathBuf,
+    source: PeerSource,
+    // Remote extension handshake, if the peer sent one
+    extension: Option<ExtensionHandshake>,
+    // Requests sent to the peer which haven't been answered yet
+    outstanding_requests: usize,
+    // Last storage failure, left for the torrent to act upon
+    storage_error: Option<StorageError>,
+    // The disk filled up, nothing is downloaded until `resume` is called
+    disk_full: bool,
+    // Piece counts of the torrent, kept up to date with `have`
+    availability: Option<SharedAvailability>,
+    // Bytes the torrent may upload, shared by its peers
+    upload_budget: Option<Arc<RateBudget>>,
+    // Where the messages of the connection are recorded, if anywhere
+    capture: Option<Capture>,
+    addr: Option<SocketAddr>,
+    events: Option<PeerEventSender>,
+}
+
+impl PeerSource {
+    pub const ALL: [PeerSource; 6] = [
+        PeerSource::Tracker,
+        PeerSource::Pex,
+        PeerSource::Dht,
+        PeerSource::Lsd,
+        PeerSource::Incoming,
+        PeerSource::Manual,
+    ];
+
+    /// Private torrents (BEP 27) must only talk to peers handed out by their
+    /// tracker, or which connected to us / were added by the user.
+    pub fn is_allowed_for_private(&self) -> bool {
+        matches!(
+            self,
+            PeerSource::Tracker | PeerSource::Incoming | PeerSource::Manual
+        )
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+impl PeerSourceStats {
+    pub fn add(&mut self, source: PeerSource) {
+        self.counts[source.index()] += 1;
+    }
+
+    pub fn remove(&mut self, source: PeerSource) {
+        let count = &mut self.counts[source.index()];
+        *count = count.saturating_sub(1);
+    }
+
+    pub fn get(&self, source: PeerSource) -> usize {
+        self.counts[source.index()]
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PeerSource, usize)> + '_ {
+        PeerSource::ALL.iter().map(move |&s| (s, self.get(s)))
+    }
 }
 
 // According to https://wiki.theory.org/index.php/BitTorrentSpecification#keep-alive:_.3Clen.3D0000.3E
@@ -34,94 +196,143 @@ async fn keepalive(peer: &Arc<RwLock<Peer>>) {
     loop {
         interval.tick().await;
 
-        loop {
-            let tw_res = peer.write().await.stream.try_write(&PAYLOAD);
-
-            match tw_res {
-                Ok(n) => {
-                    assert!(n == PAYLOAD.len());
-                    break;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
-                }
-                Err(_e) => {
-                    // Maybe the socket closed
-                    return;
-                }
-            }
+        if peer.write().await.send(&PAYLOAD).await.is_err() {
+            // Maybe the socket closed
+            return;
         }
     }
 }
 
-async fn listen_and_dispatch(peer: &Arc<RwLock<Peer>>) {
+// Fixed-size fields of a message, `None` if it is too short
+fn be_u32(buffer: &[u8], pos: usize) -> Option<u32> {
+    let bytes = buffer.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+async fn listen_and_dispatch(peer: &Arc<RwLock<Peer>>) -> Result<(), PeerError> {
     loop {
         let mut size = [0u8; 4];
         let resp = peer.write().await.stream.try_read(&mut size);
 
-        if let Err(e) = resp {
-            if e.kind() == io::ErrorKind::WouldBlock {
+        let read = match resp {
+            // The remote closed the connection
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                 // Doesn't please me, should find a way to read only when data is available
                 time::sleep(time::Duration::from_millis(100)).await;
                 continue;
-            } else {
-                return;
             }
+            Err(e) => return Err(e.into()),
+        };
+        // The length prefix may be split across segments
+        if read < size.len() {
+            peer.write()
+                .await
+                .stream
+                .read_exact(&mut size[read..])
+                .await?;
         }
-        let size = u32::from_be_bytes(size);
+        let prefix = size;
+        let size = u32::from_be_bytes(size) as usize;
 
         if size == 0 {
             // Keep-alive
+            peer.read().await.capture_in(&prefix, &[]);
             continue;
         }
+        if size > MAX_MESSAGE_LEN {
+            return Err(PeerError::MessageTooLong(size));
+        }
 
-        let mut buffer = vec![];
-        buffer.resize(size as usize, 0u8);
+        let mut buffer = vec![0u8; size];
 
-        peer.write()
-            .await
-            .stream
-            .read_exact(&mut buffer)
-            .await
-            .unwrap();
+        peer.write().await.stream.read_exact(&mut buffer).await?;
+        peer.read().await.capture_in(&prefix, &buffer);
 
         match buffer[0] {
-            0 => choke(&peer).await,
-            1 => unchoke(&peer).await,
-            2 => interested(&peer).await,
-            3 => not_interested(&peer).await,
-            4 => have(&peer, &buffer[1..]).await,
-            5 => bitfield(&peer, &buffer[1..]).await,
-            6 => request(&peer, &buffer[1..]).await,
-            7 => piece(&peer, &buffer[1..]).await,
-            8 => cancel(&peer, &buffer[1..]).await,
-            n => panic!("Not implemented: {}", n),
+            0 => choke(peer).await,
+            1 => unchoke(peer).await,
+            2 => interested(peer).await,
+            3 => not_interested(peer).await,
+            4 => have(peer, &buffer[1..]).await?,
+            5 => bitfield(peer, &buffer[1..]).await?,
+            6 => request(peer, &buffer[1..]).await?,
+            7 => piece(peer, &buffer[1..]).await?,
+            8 => cancel(peer, &buffer[1..]).await?,
+            HAVE_ALL => set_all(peer, true).await,
+            HAVE_NONE => set_all(peer, false).await,
+            extension::EXTENDED_MSG_ID => extended(peer, &buffer[1..]).await,
+            // Extensions we didn't advertise, ignored as the spec says
+            _ => (),
         };
     }
 }
 
 async fn choke(peer: &Arc<RwLock<Peer>>) {
-    unimplemented!("choke");
+    let mut peer = peer.write().await;
+    peer.peer_choking = true;
+    peer.emit(PeerEvent::Choke(true));
 }
 
 async fn unchoke(peer: &Arc<RwLock<Peer>>) {
-    unimplemented!("unchoke");
+    let mut peer = peer.write().await;
+    peer.peer_choking = false;
+    peer.emit(PeerEvent::Choke(false));
 }
 
 async fn interested(peer: &Arc<RwLock<Peer>>) {
-    unimplemented!("interested");
+    peer.write().await.peer_interested = true;
 }
 
 async fn not_interested(peer: &Arc<RwLock<Peer>>) {
-    unimplemented!("not_interested");
+    peer.write().await.peer_interested = false;
+}
+
+async fn have(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) -> Result<(), PeerError> {
+    let index = be_u32(buffer, 0)
+        .filter(|_| buffer.len() == 4)
+        .ok_or(PeerError::Malformed("have"))? as usize;
+    let mut peer = peer.write().await;
+    // Without metadata the number of pieces is unknown, bounded by what a
+    // bitfield could hold
+    if peer.torrent.is_none() && index >= peer.have.len() && index < MAX_MESSAGE_LEN * 8 {
+        peer.have.resize(index + 1, false);
+    }
+    if let Some(have) = peer.have.get_mut(index).filter(|h| !**h) {
+        *have = true;
+        if let Some(availability) = &peer.availability {
+            availability.lock().unwrap().add_have(index);
+        }
+        peer.emit(PeerEvent::Have(index));
+    }
+    Ok(())
 }
 
-async fn have(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
-    peer.write().await.have[u32::from_be_bytes(buffer.try_into().unwrap()) as usize] = true;
+// Fast extension (BEP 6) replacements of the bitfield
+const HAVE_ALL: u8 = 14;
+const HAVE_NONE: u8 = 15;
+
+async fn set_all(peer: &Arc<RwLock<Peer>>, value: bool) {
+    let mut peer = peer.write().await;
+    let new = vec![value; peer.have.len()];
+    peer.set_have(new);
+    peer.emit(PeerEvent::Bitfield(peer.have.clone()));
 }
 
-async fn bitfield(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
-    assert!(peer.read().await.have.len() <= buffer.len() * 8);
+async fn bitfield(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) -> Result<(), PeerError> {
+    let old = peer.read().await.have.clone();
+    {
+        // Without metadata the number of pieces is unknown, every bit is
+        // kept until it is
+        let mut peer = peer.write().await;
+        if peer.torrent.is_none() {
+            peer.have = vec![false; buffer.len() * 8];
+        }
+    }
+    if peer.read().await.have.len() > buffer.len() * 8 {
+        return Err(PeerError::Malformed("bitfield"));
+    }
     let mut idx = 0;
     let len = peer.read().await.have.len();
 
@@ -130,7 +341,7 @@ async fn bitfield(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
         let x = buffer[idx / 8];
         let mut peer = peer.write().await;
 
-        peer.have[idx + 0] = x & (1 << 7) != 0;
+        peer.have[idx] = x & (1 << 7) != 0;
         peer.have[idx + 1] = x & (1 << 6) != 0;
         peer.have[idx + 2] = x & (1 << 5) != 0;
         peer.have[idx + 3] = x & (1 << 4) != 0;
@@ -150,87 +361,448 @@ async fn bitfield(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
         idx += 1;
         shift -= 1;
     }
+
+    if let Some(availability) = &peer.availability {
+        availability.lock().unwrap().update(&old, &peer.have);
+    }
+    peer.emit(PeerEvent::Bitfield(peer.have.clone()));
+    Ok(())
 }
 
 // TODO: check if piece is downloaded
 // A peer shouldn't request a piece we don't have but…
-async fn request(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
-    let index = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-    let begin = u32::from_be_bytes(buffer[4..8].try_into().unwrap());
-    let length = u32::from_be_bytes(buffer[8..12].try_into().unwrap());
+async fn request(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) -> Result<(), PeerError> {
+    let (Some(index), Some(begin), Some(length)) =
+        (be_u32(buffer, 0), be_u32(buffer, 4), be_u32(buffer, 8))
+    else {
+        return Err(PeerError::Malformed("request"));
+    };
 
+    // Requests of choked peers are dropped, they know we won't answer
+    let budget = {
+        let peer = peer.read().await;
+        if peer.am_choking {
+            return Ok(());
+        }
+        peer.upload_budget.clone()
+    };
     let peer = peer.clone();
 
     tokio::spawn(async move {
-        let res = peer.write().await.file.load_piece(index as usize).await;
-        if res.is_err() {
-            panic!("request: load_piece failed: {:?}", res);
+        if let Some(budget) = budget {
+            budget.take(length as u64).await;
         }
+        let Some(file) = peer.read().await.file.clone() else {
+            return;
+        };
+        let res = file
+            .lock()
+            .await
+            .read_block(index as usize, begin as usize, length as usize)
+            .await;
+        let block = match res {
+            Ok(block) => block,
+            // A bad request isn't a problem with our storage
+            Err(StorageError::OutOfRange) => return,
+            Err(e) => {
+                peer.write().await.storage_error = Some(e);
+                return;
+            }
+        };
 
-        let peer_lock = peer.write().await;
-        let buf = peer_lock
-            .file
-            .sub_piece(index as usize, begin as usize, length as usize);
+        // The connection is gone, the read loop finds out as well
+        if send_piece(&peer, index, begin, &block).await.is_err() {
+            return;
+        }
+        peer.read().await.emit(PeerEvent::Uploaded(length as usize));
+    });
+    Ok(())
+}
 
-        let mut start = 0;
+async fn piece(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) -> Result<(), PeerError> {
+    let (Some(index), Some(begin)) = (be_u32(buffer, 0), be_u32(buffer, 4)) else {
+        return Err(PeerError::Malformed("piece"));
+    };
+    let (index, begin) = (index as usize, begin as usize);
+    let block = &buffer[8..];
 
-        loop {
-            match peer_lock.stream.try_write(&buf[start..]) {
-                Ok(n) if n == buf.len() - start => break,
-                Ok(n) => start += n,
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
-                Err(e) => panic!("Unexpected error: {:?}", e),
-            }
-        }
+    let mut peer = peer.write().await;
+    peer.outstanding_requests = peer.outstanding_requests.saturating_sub(1);
+    peer.emit(PeerEvent::Block {
+        index,
+        begin,
+        length: block.len(),
     });
+    let (Some(file), Some(torrent)) = (peer.file.clone(), peer.torrent.clone()) else {
+        return Ok(());
+    };
+    if peer.disk_full {
+        return Ok(());
+    }
+    match store_block(&file, &torrent, index, begin, block).await {
+        Ok(Some(valid)) => peer.emit(PeerEvent::PieceVerified { index, valid }),
+        Ok(None) => (),
+        Err(PeerError::Storage(e)) => peer.set_storage_error(e),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Write a block of `torrent` received from anywhere, and verify its piece
+/// once complete. Returns whether the piece was valid, if it was complete.
+pub async fn store_block(
+    file: &SharedFile,
+    torrent: &MetaInfo,
+    index: usize,
+    begin: usize,
+    block: &[u8],
+) -> Result<Option<bool>, PeerError> {
+    let mut file = file.lock().await;
+    if index >= file.num_pieces() || file.is_verified(index) {
+        return Ok(None);
+    }
+    file.write_sub_piece(index, begin, block).await?;
+    if !file.is_piece_complete(index) {
+        return Ok(None);
+    }
+    let expected = torrent
+        .piece_hash(index)?
+        .ok_or(PeerError::Malformed("piece"))?;
+    // On mismatch the blocks are dropped and the piece is missing again
+    let valid = file.commit_piece(index, &expected).await?;
+    if valid {
+        file.complete()?;
+    }
+    Ok(Some(valid))
+}
+
+// Requests are answered as soon as they arrive, there is nothing left to
+// cancel
+async fn cancel(_peer: &Arc<RwLock<Peer>>, buffer: &[u8]) -> Result<(), PeerError> {
+    match buffer.len() {
+        12 => Ok(()),
+        _ => Err(PeerError::Malformed("cancel")),
+    }
+}
+
+async fn extended(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
+    if buffer.is_empty() {
+        return;
+    }
+
+    // TODO: dispatch the extensions we advertised
+    if buffer[0] == extension::HANDSHAKE_EXT_ID {
+        // A malformed dictionary only means we don't get to use extensions
+        if let Ok(hs) = ExtensionHandshake::from_bencode(&buffer[1..]) {
+            peer.write().await.extension = Some(hs);
+        }
+    }
 }
 
-async fn piece(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
-    unimplemented!("piece");
+/// Send our extension handshake. Only meaningful if the remote handshake had
+/// the extension protocol bit set.
+pub async fn send_extension_handshake(
+    peer: &Arc<RwLock<Peer>>,
+    hs: &ExtensionHandshake,
+) -> io::Result<()> {
+    let msg = hs
+        .to_message()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    peer.write().await.send(&msg).await
 }
 
-async fn cancel(peer: &Arc<RwLock<Peer>>, buffer: &[u8]) {
-    unimplemented!("cancel");
+/// Tell the peer whether we want its pieces, it won't unchoke us otherwise.
+pub async fn send_interested(peer: &Arc<RwLock<Peer>>, interested: bool) -> io::Result<()> {
+    let id = match interested {
+        true => 2,
+        false => 3,
+    };
+    let mut peer = peer.write().await;
+    peer.send(&[0, 0, 0, 1, id]).await?;
+    peer.am_interested = interested;
+
+    Ok(())
+}
+
+/// Choke or unchoke the peer, it only gets blocks while unchoked.
+pub async fn send_choke(peer: &Arc<RwLock<Peer>>, choke: bool) -> io::Result<()> {
+    let id = match choke {
+        true => 0,
+        false => 1,
+    };
+    let mut peer = peer.write().await;
+    peer.send(&[0, 0, 0, 1, id]).await?;
+    peer.am_choking = choke;
+
+    Ok(())
+}
+
+/// Request a block from the peer. Returns `false` without sending anything if
+/// the peer's request queue (`reqq`) is already full.
+pub async fn send_request(
+    peer: &Arc<RwLock<Peer>>,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> io::Result<bool> {
+    let mut peer = peer.write().await;
+    if !peer.can_request() {
+        return Ok(false);
+    }
+
+    let mut msg = Vec::with_capacity(17);
+    msg.extend_from_slice(&13u32.to_be_bytes());
+    msg.push(6);
+    msg.extend_from_slice(&index.to_be_bytes());
+    msg.extend_from_slice(&begin.to_be_bytes());
+    msg.extend_from_slice(&length.to_be_bytes());
+
+    peer.send(&msg).await?;
+    peer.outstanding_requests += 1;
+
+    Ok(true)
+}
+
+/// Withdraw a request sent earlier, e.g. a block received from another peer
+/// in endgame.
+pub async fn send_cancel(
+    peer: &Arc<RwLock<Peer>>,
+    index: u32,
+    begin: u32,
+    length: u32,
+) -> io::Result<()> {
+    let mut msg = Vec::with_capacity(17);
+    msg.extend_from_slice(&13u32.to_be_bytes());
+    msg.push(8);
+    msg.extend_from_slice(&index.to_be_bytes());
+    msg.extend_from_slice(&begin.to_be_bytes());
+    msg.extend_from_slice(&length.to_be_bytes());
+
+    let mut peer = peer.write().await;
+    peer.send(&msg).await?;
+    peer.outstanding_requests = peer.outstanding_requests.saturating_sub(1);
+
+    Ok(())
+}
+
+/// Send a block of a piece we have, in answer to a request.
+pub async fn send_piece(
+    peer: &Arc<RwLock<Peer>>,
+    index: u32,
+    begin: u32,
+    block: &[u8],
+) -> io::Result<()> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(9 + block.len() as u32).to_be_bytes());
+    header.push(7);
+    header.extend_from_slice(&index.to_be_bytes());
+    header.extend_from_slice(&begin.to_be_bytes());
+
+    peer.write().await.send_parts(&[&header, block]).await
 }
 
 impl Peer {
-    pub async fn new(
+    pub async fn new<P: AsRef<Path>>(
         ip: Ipv4Addr,
         port: u16,
         torrent: MetaInfo,
-    ) -> Result<Arc<RwLock<Self>>, Box<dyn Error>> {
-        let file = FileEntity::new(
-            &torrent.info.name,
-            torrent
-                .info
-                .piece_length
-                .parse::<usize>()
-                .expect("Failed to convert piece length"),
-            torrent
-                .info
-                .file_length
-                .parse::<usize>()
-                .expect("Failed to convert file length"),
-        )?;
-
-        let res = Arc::new(RwLock::new(Peer {
+        save_path: P,
+    ) -> Result<Arc<RwLock<Self>>, PeerError> {
+        let stream = TcpStream::connect(format!("{:?}:{}", ip, port)).await?;
+
+        Peer::from_stream(stream, torrent, save_path, PeerSource::Manual)
+    }
+
+    /// Build a peer on top of an already established connection, e.g. one
+    /// obtained through a [`crate::dialer::Dialer`]. The torrent is stored
+    /// in `save_path` under its name.
+    pub fn from_stream<P: AsRef<Path>>(
+        stream: TcpStream,
+        torrent: MetaInfo,
+        save_path: P,
+        source: PeerSource,
+    ) -> Result<Arc<RwLock<Self>>, PeerError> {
+        let dirs = StorageDirs::new(save_path);
+        let file = Storage::open(&torrent.info, dirs, StorageConfig::default())?;
+
+        Ok(Peer::for_torrent(
+            stream,
+            Arc::new(torrent),
+            Arc::new(Mutex::new(file)),
+            source,
+        ))
+    }
+
+    /// Build a peer of a torrent, sharing its storage with the other peers.
+    pub fn for_torrent(
+        stream: TcpStream,
+        torrent: Arc<MetaInfo>,
+        file: SharedFile,
+        source: PeerSource,
+    ) -> Arc<RwLock<Self>> {
+        let mut peer = Peer::without_metadata(stream, source);
+        peer.have = vec![false; torrent.info.num_pieces()];
+        peer.torrent = Some(torrent);
+        peer.file = Some(file);
+
+        Peer::start(peer)
+    }
+
+    /// Build a peer for a magnet link, of which only the info hash is known.
+    /// Nothing is stored in `save_path` until [`Peer::set_metadata`] is
+    /// given the info dictionary.
+    pub fn from_magnet<P: AsRef<Path>>(
+        stream: TcpStream,
+        info_hash: InfoHash,
+        save_path: P,
+        source: PeerSource,
+    ) -> Arc<RwLock<Self>> {
+        let mut peer = Peer::without_metadata(stream, source);
+        peer.info_hash = Some(info_hash);
+        peer.save_path = save_path.as_ref().to_owned();
+
+        Peer::start(peer)
+    }
+
+    fn without_metadata(stream: TcpStream, source: PeerSource) -> Self {
+        Peer {
+            addr: stream.peer_addr().ok(),
+            events: None,
             am_choking: true,
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-            stream: TcpStream::connect(format!("{:?}:{}", ip, port)).await?,
-            have: vec![false; torrent.info.pieces.len()],
-            torrent,
-            file,
-        }));
+            stream,
+            have: Vec::new(),
+            torrent: None,
+            file: None,
+            info_hash: None,
+            save_path: PathBuf::new(),
+            source,
+            extension: None,
+            outstanding_requests: 0,
+            storage_error: None,
+            disk_full: false,
+            availability: None,
+            upload_budget: None,
+            capture: None,
+        }
+    }
+
+    fn start(peer: Self) -> Arc<RwLock<Self>> {
+        let res = Arc::new(RwLock::new(peer));
 
         let alive = res.clone();
         tokio::spawn(async move { keepalive(&alive).await });
 
         let listen = res.clone();
-        tokio::spawn(async move { listen_and_dispatch(&listen).await });
+        tokio::spawn(async move {
+            // Whatever went wrong, only this connection is dropped
+            let _ = listen_and_dispatch(&listen).await;
+            listen.read().await.emit(PeerEvent::Closed);
+        });
+
+        res
+    }
+
+    /// Report what happens on the connection to `events`. Only later events
+    /// are sent, the current state is read from the peer.
+    pub fn set_events(&mut self, events: PeerEventSender) {
+        self.events = Some(events);
+    }
+
+    fn emit(&self, event: PeerEvent) {
+        if let (Some(events), Some(addr)) = (&self.events, self.addr) {
+            // The torrent went away, nobody is listening
+            let _ = events.send((addr, event));
+        }
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    /// Count the pieces of this peer in the availability of its torrent,
+    /// until it is dropped.
+    pub fn set_availability(&mut self, availability: SharedAvailability) {
+        if let Some(old) = &self.availability {
+            old.lock().unwrap().remove_peer(&self.have);
+        }
+        availability.lock().unwrap().add_peer(&self.have);
+        self.availability = Some(availability);
+    }
+
+    /// Wait for `budget` before sending each block.
+    pub fn set_upload_budget(&mut self, budget: Arc<RateBudget>) {
+        self.upload_budget = Some(budget);
+    }
+
+    /// Record the messages sent and received from now on to `capture`.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    // Write a whole message, recording it
+    async fn send(&mut self, msg: &[u8]) -> io::Result<()> {
+        self.send_parts(&[msg]).await
+    }
+
+    // Write a message in parts, blocks aren't copied after their header
+    async fn send_parts(&mut self, parts: &[&[u8]]) -> io::Result<()> {
+        for part in parts {
+            self.stream.write_all(part).await?;
+        }
+        if let (Some(capture), Some(addr)) = (&self.capture, self.addr) {
+            capture.peer_out(addr, &parts.concat());
+        }
+        Ok(())
+    }
+
+    fn capture_in(&self, prefix: &[u8; 4], msg: &[u8]) {
+        if let (Some(capture), Some(addr)) = (&self.capture, self.addr) {
+            capture.peer_in(addr, &[&prefix[..], msg].concat());
+        }
+    }
+
+    fn set_have(&mut self, have: Vec<bool>) {
+        if let Some(availability) = &self.availability {
+            availability.lock().unwrap().update(&self.have, &have);
+        }
+        self.have = have;
+    }
+
+    pub fn has_metadata(&self) -> bool {
+        self.torrent.is_some()
+    }
+
+    /// Check the info dictionary fetched for a magnet link against the info
+    /// hash and create the storage. Pieces the peer announced meanwhile are
+    /// kept.
+    pub fn set_metadata(&mut self, metadata: &[u8]) -> Result<(), PeerError> {
+        let Some(info_hash) = self.info_hash.filter(|_| self.torrent.is_none()) else {
+            return Ok(());
+        };
+        let info = Info::from_metadata(metadata, &info_hash)?;
+        let dirs = StorageDirs::new(&self.save_path);
+        let file = Storage::open(&info, dirs, StorageConfig::default())?;
 
-        Ok(res)
+        self.have.resize(info.num_pieces(), false);
+        // Trackers come from the magnet link, not from the metadata
+        self.torrent = Some(Arc::new(MetaInfo {
+            announce: String::new(),
+            info,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        }));
+        self.file = Some(Arc::new(Mutex::new(file)));
+
+        Ok(())
     }
 
     pub fn get_stream(&self) -> &TcpStream {
@@ -244,4 +816,621 @@ impl Peer {
     pub fn get_bitfield(&self) -> &Vec<bool> {
         &self.have
     }
+
+    pub fn get_file(&self) -> Option<&SharedFile> {
+        self.file.as_ref()
+    }
+
+    pub fn get_source(&self) -> PeerSource {
+        self.source
+    }
+
+    pub fn get_extension(&self) -> Option<&ExtensionHandshake> {
+        self.extension.as_ref()
+    }
+
+    /// Maximum number of requests which may be pipelined to this peer.
+    pub fn request_limit(&self) -> usize {
+        self.extension
+            .as_ref()
+            .map(ExtensionHandshake::request_limit)
+            .unwrap_or(extension::DEFAULT_REQQ as usize)
+    }
+
+    pub fn outstanding_requests(&self) -> usize {
+        self.outstanding_requests
+    }
+
+    pub fn can_request(&self) -> bool {
+        !self.disk_full && self.outstanding_requests < self.request_limit()
+    }
+
+    fn set_storage_error(&mut self, e: StorageError) {
+        self.disk_full |= e.is_disk_full();
+        self.storage_error = Some(e);
+    }
+
+    /// Why the download is paused, if it is.
+    pub fn pause_reason(&self) -> Option<&'static str> {
+        self.disk_full.then_some("torrent paused: disk full")
+    }
+
+    /// Resume a download paused on a full disk, once space was freed.
+    pub fn resume(&mut self) {
+        self.disk_full = false;
+    }
+
+    pub fn storage_error(&self) -> Option<&StorageError> {
+        self.storage_error.as_ref()
+    }
+
+    /// Take the last storage error, e.g. once the torrent handled it.
+    pub fn take_storage_error(&mut self) -> Option<StorageError> {
+        self.storage_error.take()
+    }
+
+    pub fn get_torrent(&self) -> Option<&MetaInfo> {
+        self.torrent.as_deref()
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        if let Some(availability) = &self.availability {
+            availability.lock().unwrap().remove_peer(&self.have);
+        }
+    }
+}
+
+#[cfg(test)]
+mod peer_tests {
+    use super::*;
+    use crate::decode_torrent::Info;
+    use std::fs;
+    use tokio::net::TcpListener;
+
+    fn small_torrent(name: &str) -> MetaInfo {
+        MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![String::new(); 4],
+                name: name.to_string(),
+                file_length: "65536".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        }
+    }
+
+    async fn connected_peer(name: &str) -> (Arc<RwLock<Peer>>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let peer = Peer::from_stream(stream, small_torrent(name), ".", PeerSource::Manual).unwrap();
+        (peer, remote)
+    }
+
+    #[tokio::test]
+    async fn enforce_reqq() {
+        const FILE: &str = "test_enforce_reqq";
+        let (peer, mut remote) = connected_peer(FILE).await;
+
+        let hs = ExtensionHandshake {
+            reqq: Some(2),
+            ..Default::default()
+        };
+        let msg = hs.to_message().unwrap();
+        remote.write_all(&msg).await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!(2, peer.read().await.request_limit());
+        assert!(send_request(&peer, 0, 0, 16384).await.unwrap());
+        assert!(send_request(&peer, 1, 0, 16384).await.unwrap());
+        assert!(!send_request(&peer, 2, 0, 16384).await.unwrap());
+        assert_eq!(2, peer.read().await.outstanding_requests());
+
+        let mut buf = [0u8; 17];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&[0, 0, 0, 13, 6, 0, 0, 0, 0], &buf[..9]);
+        remote.read_exact(&mut buf).await.unwrap();
+
+        // A cancel frees its slot
+        send_cancel(&peer, 0, 0, 16384).await.unwrap();
+        assert_eq!(1, peer.read().await.outstanding_requests());
+        assert!(send_request(&peer, 2, 0, 16384).await.unwrap());
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&[0, 0, 0, 13, 8, 0, 0, 0, 0], &buf[..9]);
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn answer_requests() {
+        const FILE: &str = "test_answer_requests";
+        let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+        fs::write(FILE, &data).unwrap();
+        let (peer, mut remote) = connected_peer(FILE).await;
+
+        send_choke(&peer, false).await.unwrap();
+        let mut unchoke = [0u8; 5];
+        remote.read_exact(&mut unchoke).await.unwrap();
+        assert_eq!([0, 0, 0, 1, 1], unchoke);
+
+        let mut request = vec![0, 0, 0, 13, 6];
+        for x in [1u32, 100, 1000] {
+            request.extend_from_slice(&x.to_be_bytes());
+        }
+        remote.write_all(&request).await.unwrap();
+
+        let mut len = [0u8; 4];
+        remote.read_exact(&mut len).await.unwrap();
+        assert_eq!(9 + 1000, u32::from_be_bytes(len));
+        let mut msg = vec![0u8; 9 + 1000];
+        remote.read_exact(&mut msg).await.unwrap();
+        assert_eq!(7, msg[0]);
+        assert_eq!(1u32.to_be_bytes(), msg[1..5]);
+        assert_eq!(100u32.to_be_bytes(), msg[5..9]);
+        assert_eq!(data[16384 + 100..16384 + 1100], msg[9..]);
+
+        drop(peer);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    async fn send_piece(remote: &mut TcpStream, index: u32, begin: u32, block: &[u8]) {
+        let mut msg = vec![];
+        msg.extend_from_slice(&(9 + block.len() as u32).to_be_bytes());
+        msg.push(7);
+        msg.extend_from_slice(&index.to_be_bytes());
+        msg.extend_from_slice(&begin.to_be_bytes());
+        msg.extend_from_slice(block);
+        remote.write_all(&msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_received_pieces() {
+        use crate::decode_torrent::bytes_to_hash;
+        use sha1::{Digest, Sha1};
+
+        const FILE: &str = "test_verify_received_pieces";
+        let good = vec![1u8; 16384];
+        let mut hasher = Sha1::new();
+        hasher.update(&good);
+        let hash: [u8; 20] = hasher.finalize().into();
+
+        let mut torrent = small_torrent(FILE);
+        torrent.info.pieces = vec![bytes_to_hash(&hash); 4];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let peer = Peer::from_stream(stream, torrent, ".", PeerSource::Manual).unwrap();
+
+        send_piece(&mut remote, 0, 0, &good).await;
+        send_piece(&mut remote, 1, 0, &[2u8; 16384]).await;
+        time::sleep(Duration::from_millis(500)).await;
+
+        {
+            let peer = peer.read().await;
+            let file = peer.get_file().unwrap().lock().await;
+            assert!(file.is_verified(0));
+            assert!(!file.is_verified(1));
+            let Storage::Single(file) = &*file else {
+                panic!("single file torrent");
+            };
+            assert!(!file.cache().contains(1));
+        }
+
+        let content = fs::read(FILE).unwrap();
+        assert_eq!(good, content[..16384]);
+        assert_eq!(vec![0u8; 16384], content[16384..32768]);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verify_v2_pieces() {
+        use crate::decode_torrent::TreeFile;
+        use crate::file::StorageConfig;
+        use crate::hash::{leaf_hashes, merkle_root, HashKind, PieceHash};
+        use crate::storage::StorageDirs;
+        use bendy::encoding::ToBencode;
+        use std::collections::BTreeMap;
+
+        const DIR: &str = "test_peer_verify_v2_pieces";
+        const PIECE: usize = 32768;
+        let a: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        let b = b"hello".to_vec();
+        let v2 = |data: &[u8]| match PieceHash::compute(HashKind::Sha256, data, PIECE) {
+            PieceHash::V2(hash) => hash,
+            PieceHash::V1(_) => unreachable!(),
+        };
+        let leaves = leaf_hashes(&a);
+        let root_a = merkle_root(&leaves, leaves.len().next_power_of_two());
+        let root_b = v2(&b);
+        let file = |path: &str, length: usize, root| TreeFile {
+            path: vec![path.to_string()],
+            length: length as u64,
+            pieces_root: Some(root),
+        };
+        let mut torrent = small_torrent("t");
+        torrent.info.piece_length = PIECE.to_string();
+        torrent.info.pieces = Vec::new();
+        torrent.info.meta_version = Some(2);
+        torrent.info.file_tree = Some(vec![file("a", a.len(), root_a), file("b", 5, root_b)]);
+        torrent.piece_layers = Some(BTreeMap::from([(
+            root_a,
+            vec![v2(&a[..PIECE]), v2(&a[PIECE..])],
+        )]));
+        // The files, with the padding after `a`, are made up when decoding
+        let torrent = MetaInfo::from_bencode(&torrent.to_bencode().unwrap()).unwrap();
+
+        let storage = Storage::open(
+            &torrent.info,
+            StorageDirs::new(DIR),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        let file = Arc::new(Mutex::new(storage));
+        let mut padded = a[PIECE..].to_vec();
+        padded.resize(PIECE, 0);
+        let store = |index, begin, block: Vec<u8>| {
+            let file = file.clone();
+            let torrent = &torrent;
+            async move {
+                store_block(&file, torrent, index, begin, &block)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(None, store(0, 0, a[..16384].to_vec()).await);
+        assert_eq!(Some(true), store(0, 16384, a[16384..PIECE].to_vec()).await);
+        assert_eq!(None, store(1, 0, padded[..16384].to_vec()).await);
+        assert_eq!(Some(true), store(1, 16384, padded[16384..].to_vec()).await);
+        assert_eq!(Some(false), store(2, 0, b"jello".to_vec()).await);
+        assert_eq!(Some(true), store(2, 0, b.clone()).await);
+        file.lock().await.flush().await.unwrap();
+        assert_eq!(a, fs::read(format!("{}/t/a", DIR)).unwrap());
+        assert_eq!(b, fs::read(format!("{}/t/b", DIR)).unwrap());
+
+        // And as rechecked from disk
+        let mut storage = Storage::open(
+            &torrent.info,
+            StorageDirs::new(DIR),
+            StorageConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            3,
+            storage
+                .recheck(&torrent.piece_hashes().unwrap())
+                .await
+                .unwrap()
+        );
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_prefix_and_close() {
+        const FILE: &str = "test_split_prefix_and_close";
+        let (peer, mut remote) = connected_peer(FILE).await;
+        let (sender, mut events) = mpsc::unbounded_channel();
+        peer.write().await.set_events(sender);
+
+        // Unchoke, its length prefix in two segments
+        remote.write_all(&[0, 0]).await.unwrap();
+        remote.flush().await.unwrap();
+        time::sleep(Duration::from_millis(150)).await;
+        remote.write_all(&[0, 1, 1]).await.unwrap();
+        let event = time::timeout(Duration::from_secs(1), events.recv()).await;
+        assert_eq!(PeerEvent::Choke(false), event.unwrap().unwrap().1);
+
+        drop(remote);
+        let event = time::timeout(Duration::from_secs(1), events.recv()).await;
+        assert_eq!(PeerEvent::Closed, event.unwrap().unwrap().1);
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_malformed_messages() {
+        const FILE: &str = "test_drop_malformed_messages";
+        let (peer, mut remote) = connected_peer(FILE).await;
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        peer.write().await.set_events(events);
+
+        // Unknown message, then a have of the wrong length
+        remote.write_all(&[0, 0, 0, 2, 42, 0]).await.unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 3, 4, 0, 0]).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(Some((_, event))) =
+            time::timeout(Duration::from_millis(500), receiver.recv()).await
+        {
+            received.push(event);
+        }
+        assert!(matches!(
+            received[..],
+            [PeerEvent::Have(1), PeerEvent::Closed]
+        ));
+
+        // Same for a length no message can have
+        let (peer, mut remote) = connected_peer(FILE).await;
+        let (events, mut receiver) = mpsc::unbounded_channel();
+        peer.write().await.set_events(events);
+        remote.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let event = time::timeout(Duration::from_millis(500), receiver.recv()).await;
+        assert!(matches!(event, Ok(Some((_, PeerEvent::Closed)))));
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn report_storage_error() {
+        const FILE: &str = "test_report_storage_error";
+        let (peer, mut remote) = connected_peer(FILE).await;
+
+        // Block past the end of the piece
+        send_piece(&mut remote, 0, 16380, &[1u8; 16]).await;
+        time::sleep(Duration::from_millis(300)).await;
+
+        let err = peer.write().await.take_storage_error();
+        assert!(matches!(err, Some(StorageError::OutOfRange)));
+        assert!(peer.read().await.storage_error().is_none());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pause_on_disk_full() {
+        const FILE: &str = "test_pause_on_disk_full";
+        let (peer, _remote) = connected_peer(FILE).await;
+
+        let mut peer = peer.write().await;
+        peer.set_storage_error(StorageError::OutOfRange);
+        assert!(peer.pause_reason().is_none());
+
+        peer.set_storage_error(StorageError::OutOfSpace);
+        assert_eq!(Some("torrent paused: disk full"), peer.pause_reason());
+        assert!(!peer.can_request());
+
+        peer.resume();
+        assert!(peer.can_request());
+
+        drop(peer);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn metadata_from_magnet() {
+        use sha1::{Digest, Sha1};
+
+        const FILE: &str = "test_metadata_from_magnet";
+        let mut metadata = b"d6:lengthi65536e4:name25:test_metadata_from_magnet".to_vec();
+        metadata.extend_from_slice(b"12:piece lengthi16384e6:pieces80:");
+        metadata.extend_from_slice(&[0u8; 80]);
+        metadata.push(b'e');
+        let info_hash: InfoHash = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let peer = Peer::from_magnet(stream, info_hash, ".", PeerSource::Manual);
+
+        // Announced pieces are kept until the metadata is known
+        remote
+            .write_all(&[0, 0, 0, 2, 5, 0b1000_0000])
+            .await
+            .unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 2])
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        let mut peer = peer.write().await;
+        assert!(!peer.has_metadata());
+        assert!(peer.get_file().is_none());
+        assert!(!std::path::Path::new(FILE).exists());
+
+        assert!(peer.set_metadata(&metadata[1..]).is_err());
+        assert!(!peer.has_metadata());
+
+        peer.set_metadata(&metadata).unwrap();
+        assert!(peer.has_metadata());
+        assert_eq!(&vec![true, false, true, false], peer.get_bitfield());
+        assert_eq!(4, peer.get_file().unwrap().lock().await.num_pieces());
+        assert_eq!(65536, fs::metadata(FILE).unwrap().len());
+
+        drop(peer);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn track_availability() {
+        use crate::availability::Availability;
+
+        const FILE: &str = "test_track_availability";
+        let (peer, mut remote) = connected_peer(FILE).await;
+        let availability = Availability::shared(4);
+        peer.write().await.set_availability(availability.clone());
+        assert_eq!(1, availability.lock().unwrap().peers());
+
+        // Bitfield, have, then have-all
+        remote
+            .write_all(&[0, 0, 0, 2, 5, 0b1100_0000])
+            .await
+            .unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 3])
+            .await
+            .unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 3])
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(&[1, 1, 0, 1], availability.lock().unwrap().counts());
+
+        remote.write_all(&[0, 0, 0, 1, HAVE_ALL]).await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(&[1, 1, 1, 1], availability.lock().unwrap().counts());
+
+        // Moving to another torrent's counts removes the peer
+        peer.write().await.set_availability(Availability::shared(4));
+        assert_eq!(&[0, 0, 0, 0], availability.lock().unwrap().counts());
+        assert_eq!(0, availability.lock().unwrap().peers());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn private_sources() {
+        let allowed: Vec<PeerSource> = PeerSource::ALL
+            .into_iter()
+            .filter(PeerSource::is_allowed_for_private)
+            .collect();
+
+        assert_eq!(
+            allowed,
+            vec![
+                PeerSource::Tracker,
+                PeerSource::Incoming,
+                PeerSource::Manual
+            ]
+        );
+    }
+
+    #[test]
+    fn source_stats() {
+        let mut stats = PeerSourceStats::default();
+        stats.add(PeerSource::Tracker);
+        stats.add(PeerSource::Tracker);
+        stats.add(PeerSource::Dht);
+        stats.remove(PeerSource::Tracker);
+        stats.remove(PeerSource::Lsd);
+
+        assert_eq!(1, stats.get(PeerSource::Tracker));
+        assert_eq!(1, stats.get(PeerSource::Dht));
+        assert_eq!(0, stats.get(PeerSource::Lsd));
+        assert_eq!(2, stats.total());
+        assert_eq!(PeerSource::ALL.len(), stats.iter().count());
+    }
+
+    #[tokio::test]
+    async fn capture_messages() {
+        use crate::capture::{self, Direction};
+        use std::io::BufReader;
+
+        const FILE: &str = "test_capture_messages";
+        const CAPTURE: &str = "./test_capture_messages.log";
+        let (peer, mut remote) = connected_peer(FILE).await;
+        let addr = peer.read().await.addr().unwrap();
+        peer.write()
+            .await
+            .set_capture(Some(Capture::create(CAPTURE).unwrap()));
+
+        send_interested(&peer, true).await.unwrap();
+        remote
+            .write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 0]).await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        let file = fs::File::open(CAPTURE).unwrap();
+        let records: Vec<_> = capture::read(BufReader::new(file))
+            .map(|r| r.map(|r| (r.direction, r.addr, r.data)))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        fs::remove_file(CAPTURE).unwrap();
+        fs::remove_file(FILE).unwrap();
+
+        assert_eq!(
+            vec![
+                (Direction::Out, addr, vec![0, 0, 0, 1, 2]),
+                (Direction::In, addr, vec![0, 0, 0, 5, 4, 0, 0, 0, 1]),
+                (Direction::In, addr, vec![0, 0, 0, 0]),
+            ],
+            records
+        );
+    }
+
+    #[tokio::test]
+    async fn capture_uploads() {
+        use crate::capture::{self, Direction};
+        use std::io::BufReader;
+
+        const FILE: &str = "test_capture_uploads";
+        const CAPTURE: &str = "./test_capture_uploads.log";
+        let data: Vec<u8> = (0..65536).map(|i| (i % 251) as u8).collect();
+        fs::write(FILE, &data).unwrap();
+        let (peer, mut remote) = connected_peer(FILE).await;
+        peer.write()
+            .await
+            .set_capture(Some(Capture::create(CAPTURE).unwrap()));
+
+        send_choke(&peer, false).await.unwrap();
+        let mut request = vec![0, 0, 0, 13, 6];
+        for x in [2u32, 0, 16] {
+            request.extend_from_slice(&x.to_be_bytes());
+        }
+        remote.write_all(&request).await.unwrap();
+        let mut sent = vec![0u8; 5 + 4 + 9 + 16];
+        remote.read_exact(&mut sent).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        let file = fs::File::open(CAPTURE).unwrap();
+        let records: Vec<_> = capture::read(BufReader::new(file))
+            .map(|r| r.map(|r| (r.direction, r.data)))
+            .collect::<io::Result<_>>()
+            .unwrap();
+        drop(peer);
+        fs::remove_file(CAPTURE).unwrap();
+        fs::remove_file(FILE).unwrap();
+
+        // What was captured is what was sent, and parses as a piece message
+        let piece = &records.last().unwrap().1;
+        assert_eq!(Direction::Out, records.last().unwrap().0);
+        assert_eq!(&sent[5..], &piece[..]);
+        assert_eq!(&(9u32 + 16).to_be_bytes(), &piece[..4]);
+        assert_eq!(&[7, 0, 0, 0, 2, 0, 0, 0, 0], &piece[4..13]);
+        assert_eq!(&data[2 * 16384..2 * 16384 + 16], &piece[13..]);
+    }
 }
@@ -0,0 +1,185 @@
+// Strategies choosing the next piece to start. The scheduler finishes the
+// pieces already started and handles endgame itself, strategies only decide
+// which new piece a peer is asked for.
+use std::{fmt, time::Instant};
+
+use rand::seq::SliceRandom;
+
+use crate::priority::Priority;
+use crate::scheduler::Scheduler;
+
+/// Pieces picked at random before switching to rarest first.
+pub const DEFAULT_RANDOM_FIRST: usize = 4;
+
+/// What a strategy can look at to choose a piece.
+pub struct PickContext<'a> {
+    scheduler: &'a Scheduler,
+    now: Instant,
+}
+
+impl<'a> PickContext<'a> {
+    pub(crate) fn new(scheduler: &'a Scheduler, now: Instant) -> Self {
+        PickContext { scheduler, now }
+    }
+
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Number of connected peers with the piece.
+    pub fn availability(&self, index: usize) -> u32 {
+        self.scheduler.availability().get(index)
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.scheduler.priority(index)
+    }
+
+    pub fn is_preview(&self, index: usize) -> bool {
+        self.scheduler.is_preview(index)
+    }
+
+    pub fn deadline(&self, index: usize) -> Option<Instant> {
+        self.scheduler.deadline(index)
+    }
+
+    /// Pieces we have verified.
+    pub fn verified(&self) -> usize {
+        (0..self.scheduler.num_pieces())
+            .filter(|&i| self.scheduler.has_piece(i))
+            .count()
+    }
+
+    /// Candidates of the highest priority, preview pieces first.
+    pub fn top_ranked(&self, candidates: &[usize]) -> Vec<usize> {
+        let rank = |i: usize| (self.priority(i), self.is_preview(i));
+        let top = candidates.iter().map(|&i| rank(i)).max();
+        candidates
+            .iter()
+            .copied()
+            .filter(|&i| Some(rank(i)) == top)
+            .collect()
+    }
+}
+
+pub trait PickStrategy: fmt::Debug + Send {
+    /// Choose the piece to start among `candidates`, in index order. They
+    /// are all wanted, not started and had by the peer to ask.
+    fn pick(&self, candidates: &[usize], ctx: &PickContext) -> Option<usize>;
+}
+
+/// Rarest piece of the highest priority, after a few random ones to get
+/// something to trade quickly.
+#[derive(Debug, Clone)]
+pub struct RarestFirst {
+    random_first: usize,
+}
+
+impl Default for RarestFirst {
+    fn default() -> Self {
+        RarestFirst::new(DEFAULT_RANDOM_FIRST)
+    }
+}
+
+impl RarestFirst {
+    /// Pick at random until `random_first` pieces are verified.
+    pub fn new(random_first: usize) -> Self {
+        RarestFirst { random_first }
+    }
+}
+
+impl PickStrategy for RarestFirst {
+    fn pick(&self, candidates: &[usize], ctx: &PickContext) -> Option<usize> {
+        let top = ctx.top_ranked(candidates);
+        match ctx.verified() < self.random_first {
+            true => top.choose(&mut rand::thread_rng()).copied(),
+            false => top.into_iter().min_by_key(|&i| ctx.availability(i)),
+        }
+    }
+}
+
+/// Pieces in order, e.g. to play a file while it downloads.
+#[derive(Debug, Clone, Default)]
+pub struct Sequential;
+
+impl PickStrategy for Sequential {
+    fn pick(&self, candidates: &[usize], ctx: &PickContext) -> Option<usize> {
+        ctx.top_ranked(candidates).first().copied()
+    }
+}
+
+/// Pieces with a deadline first, the earliest one first, then the pieces
+/// `fallback` picks.
+#[derive(Debug)]
+pub struct Deadline<S> {
+    fallback: S,
+}
+
+impl<S: PickStrategy> Deadline<S> {
+    pub fn new(fallback: S) -> Self {
+        Deadline { fallback }
+    }
+}
+
+impl Default for Deadline<RarestFirst> {
+    fn default() -> Self {
+        Deadline::new(RarestFirst::default())
+    }
+}
+
+impl<S: PickStrategy> PickStrategy for Deadline<S> {
+    fn pick(&self, candidates: &[usize], ctx: &PickContext) -> Option<usize> {
+        let earliest = candidates
+            .iter()
+            .filter_map(|&i| Some((ctx.deadline(i)?, i)))
+            .min();
+        match earliest {
+            Some((_, index)) => Some(index),
+            None => self.fallback.pick(candidates, ctx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pick_tests {
+    use super::*;
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::definitions::BLOCK_SIZE;
+
+    #[derive(Debug)]
+    struct Last;
+
+    impl PickStrategy for Last {
+        fn pick(&self, candidates: &[usize], _ctx: &PickContext) -> Option<usize> {
+            candidates.last().copied()
+        }
+    }
+
+    fn first_pick(scheduler: &mut Scheduler) -> usize {
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        scheduler.add_peer(peer, vec![true; scheduler.num_pieces()], 1);
+        scheduler.set_choking(peer, false);
+        scheduler.schedule(Instant::now())[0].index
+    }
+
+    #[test]
+    fn strategies() {
+        let new = || Scheduler::new(BLOCK_SIZE, 8 * BLOCK_SIZE as u64);
+
+        let mut scheduler = new();
+        scheduler.set_strategy(Sequential);
+        scheduler.set_piece_priority(0, Priority::Low);
+        assert_eq!(1, first_pick(&mut scheduler));
+
+        let mut scheduler = new();
+        scheduler.set_strategy(Last);
+        assert_eq!(7, first_pick(&mut scheduler));
+
+        let mut scheduler = new();
+        scheduler.set_strategy(Deadline::new(Sequential));
+        scheduler.set_piece_deadline(6, Duration::from_secs(10));
+        scheduler.set_piece_deadline(4, Duration::from_secs(5));
+        assert_eq!(4, first_pick(&mut scheduler));
+    }
+}
@@ -0,0 +1,73 @@
+// Download priorities. They are set per file, and a piece gets the highest
+// priority of the files it holds bytes of, unless it is set explicitly.
+use crate::layout::Layout;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    // Not downloaded at all
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl Priority {
+    pub fn is_wanted(self) -> bool {
+        self != Priority::Skip
+    }
+}
+
+/// Priority of each piece from the priorities of the files.
+pub fn piece_priorities(layout: &Layout, files: &[Priority]) -> Vec<Priority> {
+    (0..layout.num_pieces())
+        .map(|index| {
+            layout
+                .piece_files(index)
+                .map(|f| files[f])
+                .max()
+                .unwrap_or(Priority::Skip)
+        })
+        .collect()
+}
+
+/// First and last `count` pieces of each wanted file, so that media can be
+/// previewed and seeked early.
+pub fn preview_pieces(layout: &Layout, files: &[Priority], count: usize) -> Vec<usize> {
+    let mut res = Vec::new();
+    for (file, f) in layout.files().iter().enumerate() {
+        if !files[file].is_wanted() || f.attr.padding {
+            continue;
+        }
+        let pieces = layout.file_pieces(file);
+        res.extend(pieces.clone().take(count));
+        res.extend(pieces.rev().take(count));
+    }
+    res.sort_unstable();
+    res.dedup();
+    res
+}
+
+#[cfg(test)]
+mod priority_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn highest_file_wins() {
+        let files = vec![
+            (PathBuf::from("t/a"), 10),
+            (PathBuf::from("t/b"), 14),
+            (PathBuf::from("t/c"), 8),
+        ];
+        // Pieces of 8 bytes: a | a b | b | c
+        let layout = Layout::new(8, files);
+        let priorities = [Priority::Low, Priority::Skip, Priority::High];
+        assert_eq!(
+            vec![Priority::Low, Priority::Low, Priority::Skip, Priority::High],
+            piece_priorities(&layout, &priorities)
+        );
+        assert_eq!(vec![0, 1, 3], preview_pieces(&layout, &priorities, 1));
+    }
+}
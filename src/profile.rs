@@ -0,0 +1,65 @@
+// Identity a torrent presents to trackers and peers: the prefix of its peer
+// ID and the version in its extension handshake. Some private trackers only
+// accept a few clients, recognized from these.
+use crate::definitions::{self, PeerId, PEER_ID_PREFIX};
+use crate::extension::CLIENT_VERSION;
+
+/// Names accepted by `ClientProfile::named`.
+pub const PROFILE_NAMES: [&str; 3] = ["torrent-rs", "qbittorrent", "transmission"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientProfile {
+    // Azureus style, e.g. `-qB4650-`, completed with random characters
+    pub peer_id_prefix: String,
+    // `v` of the extension handshake
+    pub version: String,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        ClientProfile::new(PEER_ID_PREFIX, CLIENT_VERSION)
+    }
+}
+
+impl ClientProfile {
+    pub fn new(peer_id_prefix: &str, version: &str) -> Self {
+        ClientProfile {
+            peer_id_prefix: peer_id_prefix.to_string(),
+            version: version.to_string(),
+        }
+    }
+
+    /// A profile of `PROFILE_NAMES`, case insensitive.
+    pub fn named(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "torrent-rs" => Some(ClientProfile::default()),
+            "qbittorrent" => Some(ClientProfile::new("-qB4650-", "qBittorrent/4.6.5")),
+            "transmission" => Some(ClientProfile::new("-TR4060-", "Transmission 4.0.6")),
+            _ => None,
+        }
+    }
+
+    /// A new peer ID with the prefix of the profile, truncated if longer
+    /// than a peer ID.
+    pub fn peer_id(&self) -> PeerId {
+        definitions::peer_id_with_prefix(self.peer_id_prefix.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod profile_tests {
+    use super::*;
+
+    #[test]
+    fn named_profiles() {
+        for name in PROFILE_NAMES {
+            assert!(ClientProfile::named(name).is_some());
+        }
+        assert_eq!(None, ClientProfile::named("unknown"));
+
+        let profile = ClientProfile::named("qBittorrent").unwrap();
+        let peer_id = profile.peer_id();
+        assert_eq!(b"-qB4650-", &peer_id[..8]);
+        assert_ne!(peer_id, profile.peer_id());
+    }
+}
@@ -0,0 +1,407 @@
+// SOCKS5 client, see https://datatracker.ietf.org/doc/html/rfc1928 and
+// https://datatracker.ietf.org/doc/html/rfc1929 for the username/password
+// authentication. Peers are dialed with CONNECT, and the proxy listens for
+// them with BIND.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProxyConfig {
+    pub addr: SocketAddr,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// A listening socket opened on the proxy with the BIND command. The remote
+/// peer has to connect to [`ProxyBind::bound_addr`].
+#[derive(Debug)]
+pub struct ProxyBind {
+    stream: TcpStream,
+    bound: SocketAddr,
+}
+
+fn proxy_error(msg: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5: {}", msg))
+}
+
+fn reply_error(rep: u8) -> io::Error {
+    match rep {
+        0x02 => io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5: not allowed"),
+        0x03 => io::Error::other("SOCKS5: network unreachable"),
+        0x04 => io::Error::other("SOCKS5: host unreachable"),
+        0x05 => io::Error::new(io::ErrorKind::ConnectionRefused, "SOCKS5: refused"),
+        0x06 => io::Error::new(io::ErrorKind::TimedOut, "SOCKS5: TTL expired"),
+        0x07 => io::Error::new(io::ErrorKind::Unsupported, "SOCKS5: command unsupported"),
+        0x08 => io::Error::new(io::ErrorKind::Unsupported, "SOCKS5: address unsupported"),
+        _ => proxy_error("general failure"),
+    }
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn negotiate(stream: &mut TcpStream, auth: &Option<ProxyAuth>) -> io::Result<()> {
+    match auth {
+        Some(_) => {
+            stream
+                .write_all(&[VERSION, 2, METHOD_NO_AUTH, METHOD_USER_PASS])
+                .await?
+        }
+        None => stream.write_all(&[VERSION, 1, METHOD_NO_AUTH]).await?,
+    }
+
+    let mut resp = [0u8; 2];
+    stream.read_exact(&mut resp).await?;
+    if resp[0] != VERSION {
+        return Err(proxy_error("unexpected version"));
+    }
+
+    match (resp[1], auth) {
+        (METHOD_NO_AUTH, _) => Ok(()),
+        (METHOD_USER_PASS, Some(auth)) => authenticate(stream, auth).await,
+        (METHOD_NONE_ACCEPTABLE, _) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5: no acceptable authentication method",
+        )),
+        _ => Err(proxy_error("unexpected authentication method")),
+    }
+}
+
+async fn authenticate(stream: &mut TcpStream, auth: &ProxyAuth) -> io::Result<()> {
+    let user = auth.username.as_bytes();
+    let pass = auth.password.as_bytes();
+    if user.len() > u8::MAX as usize || pass.len() > u8::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SOCKS5: credentials too long",
+        ));
+    }
+
+    let mut buf = Vec::with_capacity(3 + user.len() + pass.len());
+    buf.push(AUTH_VERSION);
+    buf.push(user.len() as u8);
+    buf.extend_from_slice(user);
+    buf.push(pass.len() as u8);
+    buf.extend_from_slice(pass);
+    stream.write_all(&buf).await?;
+
+    let mut resp = [0u8; 2];
+    stream.read_exact(&mut resp).await?;
+    if resp[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SOCKS5: authentication failed",
+        ));
+    }
+
+    Ok(())
+}
+
+async fn read_reply(stream: &mut TcpStream) -> io::Result<SocketAddr> {
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != VERSION {
+        return Err(proxy_error("unexpected version"));
+    }
+    if head[1] != 0 {
+        return Err(reply_error(head[1]));
+    }
+
+    let ip = match head[3] {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        ATYP_DOMAIN => {
+            // We can't do anything useful with a name, skip it
+            let len = stream.read_u8().await?;
+            let mut name = vec![0u8; len as usize];
+            stream.read_exact(&mut name).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => return Err(proxy_error("unexpected address type")),
+    };
+    let port = stream.read_u16().await?;
+
+    Ok(SocketAddr::new(ip, port))
+}
+
+async fn command(
+    proxy: &ProxyConfig,
+    cmd: u8,
+    target: SocketAddr,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut stream = TcpStream::connect(proxy.addr).await?;
+    negotiate(&mut stream, &proxy.auth).await?;
+
+    let mut req = vec![VERSION, cmd, 0];
+    encode_addr(&mut req, target);
+    stream.write_all(&req).await?;
+
+    let bound = read_reply(&mut stream).await?;
+
+    Ok((stream, bound))
+}
+
+/// Open a connection to `target` through the proxy. The returned stream is
+/// already relayed and can be used like a direct connection.
+pub async fn connect(proxy: &ProxyConfig, target: SocketAddr) -> io::Result<TcpStream> {
+    command(proxy, CMD_CONNECT, target).await.map(|(s, _)| s)
+}
+
+/// Ask the proxy to listen for an incoming connection from `peer`. Not every
+/// proxy implements BIND, in which case an `Unsupported` error is returned.
+pub async fn bind(proxy: &ProxyConfig, peer: SocketAddr) -> io::Result<ProxyBind> {
+    let (stream, mut bound) = command(proxy, CMD_BIND, peer).await?;
+
+    // Some proxies answer with 0.0.0.0, meaning "same address as me"
+    if bound.ip().is_unspecified() {
+        bound.set_ip(proxy.addr.ip());
+    }
+
+    Ok(ProxyBind { stream, bound })
+}
+
+impl ProxyBind {
+    pub fn bound_addr(&self) -> SocketAddr {
+        self.bound
+    }
+
+    /// Wait for the proxy's second reply, sent once the peer connected.
+    pub async fn accept(mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        let peer = read_reply(&mut self.stream).await?;
+        Ok((self.stream, peer))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod proxy_tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // Minimal SOCKS5 server accepting a single client
+    pub(crate) async fn fake_proxy(auth: Option<ProxyAuth>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await.unwrap();
+
+            let mut head = [0u8; 2];
+            client.read_exact(&mut head).await.unwrap();
+            let mut methods = vec![0u8; head[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+
+            if let Some(auth) = auth {
+                if !methods.contains(&METHOD_USER_PASS) {
+                    client
+                        .write_all(&[VERSION, METHOD_NONE_ACCEPTABLE])
+                        .await
+                        .unwrap();
+                    return;
+                }
+                client
+                    .write_all(&[VERSION, METHOD_USER_PASS])
+                    .await
+                    .unwrap();
+
+                let mut ver_len = [0u8; 2];
+                client.read_exact(&mut ver_len).await.unwrap();
+                let mut user = vec![0u8; ver_len[1] as usize];
+                client.read_exact(&mut user).await.unwrap();
+                let mut pass = vec![0u8; client.read_u8().await.unwrap() as usize];
+                client.read_exact(&mut pass).await.unwrap();
+
+                let ok = user == auth.username.as_bytes() && pass == auth.password.as_bytes();
+                client
+                    .write_all(&[AUTH_VERSION, if ok { 0 } else { 1 }])
+                    .await
+                    .unwrap();
+                if !ok {
+                    return;
+                }
+            } else {
+                client.write_all(&[VERSION, METHOD_NO_AUTH]).await.unwrap();
+            }
+
+            let mut req = [0u8; 4];
+            client.read_exact(&mut req).await.unwrap();
+            assert_eq!(ATYP_IPV4, req[3]);
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await.unwrap();
+            let port = client.read_u16().await.unwrap();
+            let target = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port);
+
+            match req[1] {
+                CMD_CONNECT => {
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    let mut reply = vec![VERSION, 0, 0];
+                    encode_addr(&mut reply, upstream.local_addr().unwrap());
+                    client.write_all(&reply).await.unwrap();
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream)
+                        .await
+                        .ok();
+                }
+                CMD_BIND => {
+                    let bound = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                    let mut reply = vec![VERSION, 0, 0];
+                    encode_addr(&mut reply, bound.local_addr().unwrap());
+                    client.write_all(&reply).await.unwrap();
+
+                    let (mut incoming, from) = bound.accept().await.unwrap();
+                    let mut reply = vec![VERSION, 0, 0];
+                    encode_addr(&mut reply, from);
+                    client.write_all(&reply).await.unwrap();
+                    tokio::io::copy_bidirectional(&mut client, &mut incoming)
+                        .await
+                        .ok();
+                }
+                _ => client.write_all(&[VERSION, 0x07, 0]).await.unwrap(),
+            }
+        });
+
+        addr
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await.ok();
+        });
+        addr
+    }
+
+    async fn ping(stream: &mut TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf);
+    }
+
+    #[tokio::test]
+    async fn connect_no_auth() {
+        let target = echo_server().await;
+        let proxy = ProxyConfig {
+            addr: fake_proxy(None).await,
+            auth: None,
+        };
+
+        let mut stream = connect(&proxy, target).await.unwrap();
+        ping(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn connect_user_pass() {
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let target = echo_server().await;
+        let proxy = ProxyConfig {
+            addr: fake_proxy(Some(auth.clone())).await,
+            auth: Some(auth),
+        };
+
+        let mut stream = connect(&proxy, target).await.unwrap();
+        ping(&mut stream).await;
+    }
+
+    #[tokio::test]
+    async fn connect_bad_credentials() {
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let proxy = ProxyConfig {
+            addr: fake_proxy(Some(auth)).await,
+            auth: Some(ProxyAuth {
+                username: "user".to_string(),
+                password: "wrong".to_string(),
+            }),
+        };
+
+        let res = connect(&proxy, "127.0.0.1:1".parse().unwrap()).await;
+        assert_eq!(io::ErrorKind::PermissionDenied, res.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn connect_auth_required() {
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "hunter2".to_string(),
+        };
+        let proxy = ProxyConfig {
+            addr: fake_proxy(Some(auth)).await,
+            auth: None,
+        };
+
+        let res = connect(&proxy, "127.0.0.1:1".parse().unwrap()).await;
+        assert_eq!(io::ErrorKind::PermissionDenied, res.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn bind_and_accept() {
+        let proxy = ProxyConfig {
+            addr: fake_proxy(None).await,
+            auth: None,
+        };
+
+        let listener = bind(&proxy, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let bound = listener.bound_addr();
+
+        let remote = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(bound).await.unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        ping(&mut stream).await;
+        remote.await.unwrap();
+    }
+}
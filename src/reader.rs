@@ -0,0 +1,225 @@
+// Reading a file of a torrent while it downloads, e.g. to play a video or
+// serve HTTP ranges. The pieces under the read position are asked for first
+// and reads wait until they are verified.
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncSeek, ReadBuf},
+    sync::{mpsc, watch},
+    time::Duration,
+};
+
+use crate::file::SharedFile;
+
+// Pieces after the one being read which are asked for too
+pub const READ_AHEAD_PIECES: usize = 4;
+// Deadline of the piece being read, each piece ahead gets one more
+pub const READ_DEADLINE: Duration = Duration::from_secs(2);
+
+/// A piece wanted by a reader, by when.
+pub(crate) type PieceRequest = (usize, Duration);
+
+type ReadFuture = Pin<Box<dyn Future<Output = io::Result<Bytes>> + Send>>;
+
+/// A file of a torrent, see `Torrent::open_file`. Reads past what was
+/// downloaded wait for the pieces, as long as the torrent is running.
+pub struct FileReader {
+    file: SharedFile,
+    piece_size: u64,
+    // Offset of the file in the torrent and its length
+    start: u64,
+    len: u64,
+    pos: u64,
+    requests: mpsc::UnboundedSender<PieceRequest>,
+    verified: watch::Receiver<()>,
+    read: Option<ReadFuture>,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Torrent closed")
+}
+
+// Wait for the piece and read `length` bytes of it from `offset`
+async fn read_piece(
+    file: SharedFile,
+    mut verified: watch::Receiver<()>,
+    index: usize,
+    offset: usize,
+    length: usize,
+) -> io::Result<Bytes> {
+    loop {
+        verified.borrow_and_update();
+        let mut f = file.lock().await;
+        if f.is_verified(index) {
+            return Ok(f.read_block(index, offset, length).await?);
+        }
+        drop(f);
+        verified.changed().await.map_err(|_| closed())?;
+    }
+}
+
+impl FileReader {
+    pub(crate) fn new(
+        file: SharedFile,
+        piece_size: usize,
+        range: (u64, u64),
+        requests: mpsc::UnboundedSender<PieceRequest>,
+        verified: watch::Receiver<()>,
+    ) -> Self {
+        FileReader {
+            file,
+            piece_size: piece_size as u64,
+            start: range.0,
+            len: range.1,
+            pos: 0,
+            requests,
+            verified,
+            read: None,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Current position in the file.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // Ask for the piece at the position and the next ones of the file
+    fn request(&self, index: usize) -> io::Result<()> {
+        let last = ((self.start + self.len - 1) / self.piece_size) as usize;
+        for (i, ahead) in (index..=last.min(index + READ_AHEAD_PIECES)).enumerate() {
+            self.requests
+                .send((ahead, READ_DEADLINE * (i as u32 + 1)))
+                .map_err(|_| closed())?;
+        }
+        Ok(())
+    }
+
+    /// `len` bytes at `offset` in the file, once the pieces holding them are
+    /// verified. They are all asked for at once, the position is left as is.
+    pub fn read_at(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = io::Result<Bytes>> + Send + 'static {
+        let range = offset
+            .checked_add(len as u64)
+            .filter(|&end| end <= self.len)
+            .map(|end| (self.start + offset, self.start + end));
+        let requests = self.requests.clone();
+        let (file, verified, piece_size) =
+            (self.file.clone(), self.verified.clone(), self.piece_size);
+
+        async move {
+            let (start, end) = range.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Past the end of the file")
+            })?;
+            if start == end {
+                return Ok(Bytes::new());
+            }
+            let first = (start / piece_size) as usize;
+            let last = ((end - 1) / piece_size) as usize;
+            for (i, index) in (first..=last).enumerate() {
+                requests
+                    .send((index, READ_DEADLINE * (i as u32 + 1)))
+                    .map_err(|_| closed())?;
+            }
+
+            let mut res = BytesMut::with_capacity(len);
+            let mut pos = start;
+            while pos < end {
+                let index = (pos / piece_size) as usize;
+                let offset = pos % piece_size;
+                let length = (piece_size - offset).min(end - pos);
+                let bytes = read_piece(
+                    file.clone(),
+                    verified.clone(),
+                    index,
+                    offset as usize,
+                    length as usize,
+                );
+                res.extend_from_slice(&bytes.await?);
+                pos += length;
+            }
+            Ok(res.freeze())
+        }
+    }
+
+    // Read of the rest of the piece at the position, up to `max` bytes
+    fn start_read(&self, max: usize) -> io::Result<ReadFuture> {
+        let abs = self.start + self.pos;
+        let index = (abs / self.piece_size) as usize;
+        let offset = abs % self.piece_size;
+        let length = (self.piece_size - offset)
+            .min(self.len - self.pos)
+            .min(max as u64) as usize;
+        self.request(index)?;
+        Ok(Box::pin(read_piece(
+            self.file.clone(),
+            self.verified.clone(),
+            index,
+            offset as usize,
+            length,
+        )))
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos >= self.len || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if self.read.is_none() {
+            let read = self.start_read(buf.remaining())?;
+            self.read = Some(read);
+        }
+        let res = match self.read.as_mut() {
+            Some(read) => std::task::ready!(read.as_mut().poll(cx)),
+            None => unreachable!(),
+        };
+        self.read = None;
+        let bytes = res?;
+        // The buffer may be smaller than the one the read started with
+        let n = bytes.len().min(buf.remaining());
+        buf.put_slice(&bytes[..n]);
+        self.pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start"))?;
+        // The read under way was for the previous position
+        self.read = None;
+        self.pos = pos;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
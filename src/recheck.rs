@@ -0,0 +1,254 @@
+// Rechecks read every piece of a torrent and can saturate a disk shared with
+// other services. They go through a scheduler limiting how many run at once
+// and how fast they read, and can be paused.
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use tokio::{
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+    time::{self, Duration, Instant},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct RecheckConfig {
+    // Torrents rechecked at the same time, the others wait their turn
+    pub max_concurrent: usize,
+    // Read rate shared by all rechecks, unlimited if `None`
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl Default for RecheckConfig {
+    fn default() -> Self {
+        RecheckConfig {
+            max_concurrent: 1,
+            bytes_per_sec: None,
+        }
+    }
+}
+
+// Each read reserves the time it takes at the configured rate after the
+// previous reservation, and waits for its slot
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    next: Instant,
+}
+
+impl RateLimiter {
+    fn reserve(&mut self, bytes: usize) -> Option<Instant> {
+        let rate = self.bytes_per_sec?;
+        let now = Instant::now();
+        let start = self.next.max(now);
+        self.next = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        (start > now).then_some(start)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecheckScheduler {
+    slots: Arc<Semaphore>,
+    limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl Default for RecheckScheduler {
+    fn default() -> Self {
+        RecheckScheduler::new(RecheckConfig::default())
+    }
+}
+
+impl RecheckScheduler {
+    pub fn new(config: RecheckConfig) -> Self {
+        assert!(config.max_concurrent > 0);
+        RecheckScheduler {
+            slots: Arc::new(Semaphore::new(config.max_concurrent)),
+            limiter: Arc::new(Mutex::new(RateLimiter {
+                bytes_per_sec: config.bytes_per_sec,
+                next: Instant::now(),
+            })),
+        }
+    }
+
+    /// Change the read rate, it applies to the next reads of every recheck.
+    pub fn set_rate_limit(&self, bytes_per_sec: Option<u64>) {
+        let mut limiter = self.limiter.lock().unwrap();
+        limiter.bytes_per_sec = bytes_per_sec;
+        limiter.next = Instant::now();
+    }
+
+    /// Handle for the recheck of a torrent of `num_pieces` pieces.
+    pub fn handle(&self, num_pieces: usize) -> RecheckHandle {
+        RecheckHandle {
+            scheduler: self.clone(),
+            state: Arc::new(RecheckState {
+                paused: watch::Sender::new(false),
+                checked: AtomicUsize::new(0),
+                valid: AtomicUsize::new(0),
+                total: num_pieces,
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RecheckState {
+    paused: watch::Sender<bool>,
+    checked: AtomicUsize,
+    valid: AtomicUsize,
+    total: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecheckProgress {
+    pub checked: usize,
+    pub valid: usize,
+    pub total: usize,
+    pub paused: bool,
+}
+
+impl RecheckProgress {
+    pub fn is_done(&self) -> bool {
+        self.checked == self.total
+    }
+
+    /// Between 0 and 1.
+    pub fn fraction(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.checked as f64 / total as f64,
+        }
+    }
+}
+
+/// Controls and progress of the recheck of one torrent. Clones share them,
+/// one is given to the recheck and others kept by the UI.
+#[derive(Debug, Clone)]
+pub struct RecheckHandle {
+    scheduler: RecheckScheduler,
+    state: Arc<RecheckState>,
+}
+
+impl RecheckHandle {
+    /// Handle which never waits, for rechecks outside of a scheduler.
+    pub fn unlimited(num_pieces: usize) -> Self {
+        let config = RecheckConfig {
+            max_concurrent: Semaphore::MAX_PERMITS,
+            bytes_per_sec: None,
+        };
+        RecheckScheduler::new(config).handle(num_pieces)
+    }
+
+    pub fn pause(&self) {
+        self.state.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.state.paused.borrow()
+    }
+
+    pub fn progress(&self) -> RecheckProgress {
+        RecheckProgress {
+            checked: self.state.checked.load(Ordering::Relaxed),
+            valid: self.state.valid.load(Ordering::Relaxed),
+            total: self.state.total,
+            paused: self.is_paused(),
+        }
+    }
+
+    /// Wait for a recheck slot, held until the permit is dropped.
+    pub(crate) async fn start(&self) -> OwnedSemaphorePermit {
+        self.state.checked.store(0, Ordering::Relaxed);
+        self.state.valid.store(0, Ordering::Relaxed);
+        // The semaphore is never closed
+        self.scheduler.slots.clone().acquire_owned().await.unwrap()
+    }
+
+    /// Wait until reading `bytes` more is allowed.
+    pub(crate) async fn throttle(&self, bytes: usize) {
+        let mut paused = self.state.paused.subscribe();
+        // The sender lives in `self`
+        let _ = paused.wait_for(|p| !p).await;
+
+        let until = self.scheduler.limiter.lock().unwrap().reserve(bytes);
+        if let Some(until) = until {
+            time::sleep_until(until).await;
+        }
+    }
+
+    pub(crate) fn record(&self, valid: bool) {
+        self.state.checked.fetch_add(1, Ordering::Relaxed);
+        if valid {
+            self.state.valid.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod recheck_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let config = RecheckConfig {
+            max_concurrent: 1,
+            bytes_per_sec: Some(100_000),
+        };
+        let handle = RecheckScheduler::new(config).handle(3);
+
+        let start = Instant::now();
+        for _ in 0..3 {
+            handle.throttle(10_000).await;
+            handle.record(true);
+        }
+        // The first read goes right away, each one after waits 100 ms
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let progress = handle.progress();
+        assert!(progress.is_done());
+        assert_eq!(3, progress.valid);
+    }
+
+    #[tokio::test]
+    async fn pause_and_resume() {
+        let handle = RecheckHandle::unlimited(2);
+        handle.pause();
+        assert!(handle.progress().paused);
+
+        let recheck = handle.clone();
+        let task = tokio::spawn(async move {
+            recheck.throttle(1).await;
+            recheck.record(false);
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(0, handle.progress().checked);
+
+        handle.resume();
+        task.await.unwrap();
+        assert_eq!(1, handle.progress().checked);
+        assert_eq!(0.5, handle.progress().fraction());
+    }
+
+    #[tokio::test]
+    async fn one_at_a_time() {
+        let scheduler = RecheckScheduler::default();
+        let first = scheduler.handle(1);
+        let second = scheduler.handle(1);
+
+        let slot = first.start().await;
+        let waiting = tokio::spawn(async move { second.start().await });
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(slot);
+        drop(waiting.await.unwrap());
+    }
+}
@@ -0,0 +1,275 @@
+// Fast-resume data, saved next to the downloaded file so that a restart
+// doesn't require hashing the whole content again
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object, ResultExt},
+    encoding::{AsString, SingleItemEncoder, ToBencode},
+};
+
+pub const RESUME_EXTENSION: &str = "resume";
+
+/// A piece which was partially downloaded, its received blocks are on disk.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnfinishedPiece {
+    pub index: usize,
+    // One entry per block of the piece
+    pub blocks: Vec<bool>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeData {
+    pub piece_size: usize,
+    pub file_size: u64,
+    // Modification time of the file when the data was saved, in seconds
+    pub file_mtime: u64,
+    // Verified pieces
+    pub pieces: Vec<bool>,
+    pub unfinished: Vec<UnfinishedPiece>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// Path of the resume file for the data at `path`.
+pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut res = path.as_ref().as_os_str().to_owned();
+    res.push(".");
+    res.push(RESUME_EXTENSION);
+    res.into()
+}
+
+pub fn file_mtime(file: &File) -> io::Result<u64> {
+    let modified = file.metadata()?.modified()?;
+    Ok(modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0))
+}
+
+pub(crate) fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut res = vec![0u8; bits.len().div_ceil(8)];
+    for (i, _) in bits.iter().enumerate().filter(|(_, &b)| b) {
+        res[i / 8] |= 1 << (7 - i % 8);
+    }
+    res
+}
+
+pub(crate) fn unpack_bits(bytes: &[u8], len: usize) -> Option<Vec<bool>> {
+    if bytes.len() != len.div_ceil(8) {
+        return None;
+    }
+    Some(
+        (0..len)
+            .map(|i| bytes[i / 8] & (1 << (7 - i % 8)) != 0)
+            .collect(),
+    )
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl ResumeData {
+    /// Whether the data still describes `file`, i.e. it wasn't modified or
+    /// resized since the resume data was saved.
+    pub fn matches(&self, file: &File, piece_size: usize, size: usize) -> io::Result<bool> {
+        Ok(self.piece_size == piece_size
+            && self.file_size == size as u64
+            && self.pieces.len() == size.div_ceil(piece_size)
+            && file.metadata()?.len() == size as u64
+            && file_mtime(file)? == self.file_mtime)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        ResumeData::from_bencode(&fs::read(path)?).map_err(invalid_data)
+    }
+
+    /// Write the resume data to `path`. A temporary file is renamed over the
+    /// previous one so a crash never leaves a truncated file behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let bytes = self.to_bencode().map_err(invalid_data)?;
+
+        let mut tmp = path.as_ref().as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+impl ToBencode for UnfinishedPiece {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"blocks", AsString(pack_bits(&self.blocks)))?;
+            e.emit_pair(b"count", self.blocks.len())?;
+            e.emit_pair(b"piece", self.index)
+        })
+    }
+}
+
+impl ToBencode for ResumeData {
+    const MAX_DEPTH: usize = 3;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"file_mtime", self.file_mtime)?;
+            e.emit_pair(b"file_size", self.file_size)?;
+            e.emit_pair(b"piece_count", self.pieces.len())?;
+            e.emit_pair(b"piece_size", self.piece_size)?;
+            e.emit_pair(b"pieces", AsString(pack_bits(&self.pieces)))?;
+            e.emit_pair(b"unfinished", &self.unfinished)?;
+            e.emit_pair(b"uploaded", self.uploaded)
+        })
+    }
+}
+
+impl FromBencode for UnfinishedPiece {
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut res = UnfinishedPiece::default();
+        let mut blocks = None;
+        let mut count = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"blocks", value) => {
+                    blocks = Some(value.try_into_bytes().context("blocks")?.to_vec());
+                }
+                (b"count", value) => {
+                    count = Some(usize::decode_bencode_object(value).context("count")?);
+                }
+                (b"piece", value) => {
+                    res.index = usize::decode_bencode_object(value).context("piece")?;
+                }
+                _ => (),
+            }
+        }
+
+        let blocks = blocks.ok_or_else(|| Error::missing_field("blocks"))?;
+        let count = count.ok_or_else(|| Error::missing_field("count"))?;
+        res.blocks = unpack_bits(&blocks, count)
+            .ok_or_else(|| Error::malformed_content(invalid_data("Invalid block bitfield")))?;
+
+        Ok(res)
+    }
+}
+
+impl FromBencode for ResumeData {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut res = ResumeData::default();
+        let mut pieces = None;
+        let mut count = None;
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"downloaded", value) => {
+                    res.downloaded = u64::decode_bencode_object(value).context("downloaded")?;
+                }
+                (b"file_mtime", value) => {
+                    res.file_mtime = u64::decode_bencode_object(value).context("file_mtime")?;
+                }
+                (b"file_size", value) => {
+                    res.file_size = u64::decode_bencode_object(value).context("file_size")?;
+                }
+                (b"piece_count", value) => {
+                    count = Some(usize::decode_bencode_object(value).context("piece_count")?);
+                }
+                (b"piece_size", value) => {
+                    res.piece_size = usize::decode_bencode_object(value).context("piece_size")?;
+                }
+                (b"pieces", value) => {
+                    pieces = Some(value.try_into_bytes().context("pieces")?.to_vec());
+                }
+                (b"unfinished", value) => {
+                    res.unfinished = Vec::decode_bencode_object(value).context("unfinished")?;
+                }
+                (b"uploaded", value) => {
+                    res.uploaded = u64::decode_bencode_object(value).context("uploaded")?;
+                }
+                _ => (),
+            }
+        }
+
+        let pieces = pieces.ok_or_else(|| Error::missing_field("pieces"))?;
+        let count = count.ok_or_else(|| Error::missing_field("piece_count"))?;
+        res.pieces = unpack_bits(&pieces, count)
+            .ok_or_else(|| Error::malformed_content(invalid_data("Invalid piece bitfield")))?;
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod resume_tests {
+    use super::*;
+
+    #[test]
+    fn bits() {
+        let bits = vec![
+            true, false, false, true, true, false, false, false, true, true,
+        ];
+        let packed = pack_bits(&bits);
+        assert_eq!(vec![0b1001_1000, 0b1100_0000], packed);
+        assert_eq!(Some(bits), unpack_bits(&packed, 10));
+        assert_eq!(None, unpack_bits(&packed, 17));
+    }
+
+    #[test]
+    fn save_and_load() {
+        const FILE: &str = "./test_resume_save_and_load.resume";
+
+        let data = ResumeData {
+            piece_size: 256,
+            file_size: 1000,
+            file_mtime: 1_700_000_000,
+            pieces: vec![true, false, true, false],
+            unfinished: vec![UnfinishedPiece {
+                index: 1,
+                blocks: vec![true, false, true],
+            }],
+            uploaded: 42,
+            downloaded: 512,
+        };
+        data.save(FILE).unwrap();
+        assert_eq!(data, ResumeData::load(FILE).unwrap());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn load_garbage() {
+        const FILE: &str = "./test_resume_load_garbage.resume";
+        fs::write(FILE, b"d5:piecei1ee").unwrap();
+
+        let res = ResumeData::load(FILE);
+        assert_eq!(io::ErrorKind::InvalidData, res.unwrap_err().kind());
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[test]
+    fn sidecar() {
+        assert_eq!(
+            PathBuf::from("/data/file.iso.resume"),
+            sidecar_path("/data/file.iso")
+        );
+    }
+}
@@ -0,0 +1,431 @@
+// Kademlia routing table of the DHT, see
+// http://bittorrent.org/beps/bep_0005.html#routing-table. Nodes are kept in
+// buckets of `K` by the length of the prefix they share with our ID, the
+// bucket of the longest prefix is split when full so we know our
+// neighbourhood best.
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use tokio::time::{Duration, Instant};
+
+pub const NODE_ID_LEN: usize = 20;
+// Nodes per bucket
+pub const K: usize = 8;
+// Nodes not heard of for longer are questionable, as are their buckets
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+// Failed queries after which a node is bad and replaced
+pub const MAX_FAILURES: u32 = 2;
+
+pub type NodeId = [u8; NODE_ID_LEN];
+
+/// A node of the DHT as sent in compact form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Good,
+    Questionable,
+    Bad,
+}
+
+/// What `RoutingTable::insert` did with a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Inserted {
+    Added,
+    Updated,
+    // The bucket is full of good nodes, or of nodes to ping before one of
+    // them may be replaced
+    Full { questionable: Option<NodeInfo> },
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut res = [0; NODE_ID_LEN];
+    for (r, (a, b)) in res.iter_mut().zip(a.iter().zip(b)) {
+        *r = a ^ b;
+    }
+    res
+}
+
+pub fn random_id() -> NodeId {
+    rand::random()
+}
+
+// Castagnoli CRC, bit by bit as only 4 bytes are hashed at a time
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x82f6_3b78,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+// First 21 bits of the IDs allowed for `ip`, given the 3 bits of `r`
+fn id_prefix(ip: Ipv4Addr, r: u8) -> u32 {
+    let mut ip = ip.octets();
+    for (byte, mask) in ip.iter_mut().zip([0x03, 0x0f, 0x3f, 0xff]) {
+        *byte &= mask;
+    }
+    ip[0] |= (r & 0x7) << 5;
+    crc32c(&ip)
+}
+
+/// Random ID restricted to `ip` (BEP 42), so that nodes can't pick where
+/// they land in the DHT. See http://bittorrent.org/beps/bep_0042.html
+pub fn secure_id(ip: Ipv4Addr) -> NodeId {
+    let mut id = random_id();
+    let crc = id_prefix(ip, id[NODE_ID_LEN - 1]).to_be_bytes();
+    id[0] = crc[0];
+    id[1] = crc[1];
+    id[2] = (crc[2] & 0xf8) | (id[2] & 0x7);
+    id
+}
+
+/// Addresses of local networks, whose nodes may have any ID.
+pub fn is_local(ip: Ipv4Addr) -> bool {
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+}
+
+/// Whether `id` may be used from `ip`, see `secure_id`.
+pub fn is_secure_id(id: &NodeId, ip: Ipv4Addr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let crc = id_prefix(ip, id[NODE_ID_LEN - 1]).to_be_bytes();
+    id[0] == crc[0] && id[1] == crc[1] && (id[2] & 0xf8) == (crc[2] & 0xf8)
+}
+
+// Bits shared by both IDs from the start
+fn common_prefix(a: &NodeId, b: &NodeId) -> usize {
+    let d = distance(a, b);
+    match d.iter().position(|&b| b != 0) {
+        Some(i) => i * 8 + d[i].leading_zeros() as usize,
+        None => NODE_ID_LEN * 8,
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    info: NodeInfo,
+    last_seen: Instant,
+    failures: u32,
+}
+
+impl Node {
+    fn status(&self, now: Instant) -> NodeStatus {
+        if self.failures >= MAX_FAILURES {
+            NodeStatus::Bad
+        } else if self.failures == 0 && now.duration_since(self.last_seen) < NODE_TIMEOUT {
+            NodeStatus::Good
+        } else {
+            NodeStatus::Questionable
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    nodes: Vec<Node>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Bucket {
+            nodes: Vec::with_capacity(K),
+            last_changed: now,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    id: NodeId,
+    // Bucket `i` holds the nodes sharing `i` bits with our ID, the last one
+    // those sharing more
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        RoutingTable {
+            id,
+            buckets: vec![Bucket::new(Instant::now())],
+        }
+    }
+
+    pub fn id(&self) -> &NodeId {
+        &self.id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        common_prefix(&self.id, id).min(self.buckets.len() - 1)
+    }
+
+    /// Add a node we heard from, or refresh it. Our own ID is never added.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> Inserted {
+        if info.id == self.id {
+            return Inserted::Updated;
+        }
+        loop {
+            let index = self.bucket_index(&info.id);
+            let can_split = index == self.buckets.len() - 1 && self.buckets.len() < NODE_ID_LEN * 8;
+            let bucket = &mut self.buckets[index];
+            if let Some(node) = bucket.nodes.iter_mut().find(|n| n.info.id == info.id) {
+                // The address may change, e.g. behind a NAT
+                node.info = info;
+                node.last_seen = now;
+                node.failures = 0;
+                bucket.last_changed = now;
+                return Inserted::Updated;
+            }
+            let node = Node {
+                info,
+                last_seen: now,
+                failures: 0,
+            };
+            if bucket.nodes.len() < K {
+                bucket.nodes.push(node);
+                bucket.last_changed = now;
+                return Inserted::Added;
+            }
+            if let Some(bad) = bucket
+                .nodes
+                .iter_mut()
+                .find(|n| n.status(now) == NodeStatus::Bad)
+            {
+                *bad = node;
+                bucket.last_changed = now;
+                return Inserted::Added;
+            }
+            if can_split {
+                self.split();
+                continue;
+            }
+            let questionable = bucket
+                .nodes
+                .iter()
+                .filter(|n| n.status(now) == NodeStatus::Questionable)
+                .min_by_key(|n| n.last_seen)
+                .map(|n| n.info);
+            return Inserted::Full { questionable };
+        }
+    }
+
+    // Move the nodes of the last bucket sharing more bits with us to a new one
+    fn split(&mut self) {
+        let index = self.buckets.len() - 1;
+        let last_changed = self.buckets[index].last_changed;
+        let (farther, closer) = self.buckets[index]
+            .nodes
+            .drain(..)
+            .partition(|n| common_prefix(&self.id, &n.info.id) == index);
+        self.buckets[index].nodes = farther;
+        self.buckets.push(Bucket {
+            nodes: closer,
+            last_changed,
+        });
+    }
+
+    /// A query to the node timed out, it is replaced once bad.
+    pub fn failed(&mut self, addr: &SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            if let Some(node) = bucket.nodes.iter_mut().find(|n| n.info.addr == *addr) {
+                node.failures += 1;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> bool {
+        let index = self.bucket_index(id);
+        let nodes = &mut self.buckets[index].nodes;
+        let before = nodes.len();
+        nodes.retain(|n| n.info.id != *id);
+        nodes.len() != before
+    }
+
+    pub fn status(&self, id: &NodeId, now: Instant) -> Option<NodeStatus> {
+        self.buckets[self.bucket_index(id)]
+            .nodes
+            .iter()
+            .find(|n| n.info.id == *id)
+            .map(|n| n.status(now))
+    }
+
+    /// Up to `count` nodes closest to `target`, the bad ones aside.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let now = Instant::now();
+        let mut nodes: Vec<NodeInfo> = self
+            .buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|n| n.status(now) != NodeStatus::Bad)
+            .map(|n| n.info)
+            .collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flat_map(|b| &b.nodes).map(|n| n.info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn good_nodes(&self, now: Instant) -> usize {
+        self.buckets
+            .iter()
+            .flat_map(|b| &b.nodes)
+            .filter(|n| n.status(now) == NodeStatus::Good)
+            .count()
+    }
+
+    /// Nodes in each bucket, of `K`, those farthest from us first.
+    pub fn buckets(&self) -> Vec<usize> {
+        self.buckets.iter().map(|b| b.nodes.len()).collect()
+    }
+
+    /// A random ID in each bucket not changed for `NODE_TIMEOUT`, to look up
+    /// for fresh nodes.
+    pub fn refresh_targets(&self, now: Instant) -> Vec<NodeId> {
+        let last = self.buckets.len() - 1;
+        (0..self.buckets.len())
+            .filter(|&i| now.duration_since(self.buckets[i].last_changed) >= NODE_TIMEOUT)
+            .map(|i| {
+                // Our first `i` bits, the next one flipped unless it is the
+                // last bucket
+                let mut id = random_id();
+                for bit in 0..=i.min(NODE_ID_LEN * 8 - 1) {
+                    let (byte, mask) = (bit / 8, 0x80 >> (bit % 8));
+                    let ours = self.id[byte] & mask;
+                    let ours = match bit == i && i < last {
+                        true => ours ^ mask,
+                        false => ours,
+                    };
+                    id[byte] = (id[byte] & !mask) | ours;
+                }
+                id
+            })
+            .collect()
+    }
+
+    /// Mark every bucket as refreshed, e.g. after a lookup of our own ID.
+    pub fn touch(&mut self, now: Instant) {
+        for bucket in &mut self.buckets {
+            bucket.last_changed = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod routing_tests {
+    use super::*;
+
+    fn node(first: u8, n: u16) -> NodeInfo {
+        let mut id = [0; NODE_ID_LEN];
+        id[0] = first;
+        id[18..].copy_from_slice(&n.to_be_bytes());
+        NodeInfo {
+            id,
+            addr: SocketAddrV4::new([10, 0, (n >> 8) as u8, n as u8].into(), 6881),
+        }
+    }
+
+    #[test]
+    fn bep42_ids() {
+        // Examples of the BEP: IP, last byte of the ID and its first bytes
+        let examples = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, last, prefix) in examples {
+            let ip: Ipv4Addr = ip.parse().unwrap();
+            let mut id = [0; NODE_ID_LEN];
+            id[..3].copy_from_slice(&prefix);
+            id[NODE_ID_LEN - 1] = last;
+            assert!(is_secure_id(&id, ip));
+            id[1] ^= 1;
+            assert!(!is_secure_id(&id, ip));
+
+            let id = secure_id(ip);
+            assert!(is_secure_id(&id, ip));
+            assert!(!is_secure_id(&id, Ipv4Addr::new(1, 2, 3, 4)));
+        }
+        // Nodes of local networks may pick any
+        assert!(is_secure_id(
+            &[0; NODE_ID_LEN],
+            Ipv4Addr::new(192, 168, 1, 2)
+        ));
+    }
+
+    #[test]
+    fn buckets_split_near_us() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; NODE_ID_LEN]);
+        // Far nodes only fill one bucket, the rest waits for a bad one
+        for n in 0..K as u16 {
+            assert_eq!(Inserted::Added, table.insert(node(0x80, n), now));
+        }
+        assert_eq!(
+            Inserted::Full { questionable: None },
+            table.insert(node(0x80, 100), now)
+        );
+        assert_eq!(Inserted::Updated, table.insert(node(0x80, 0), now));
+        // Closer ones split the bucket of our neighbourhood
+        for n in 0..K as u16 {
+            assert_eq!(Inserted::Added, table.insert(node(0x40, n), now));
+            assert_eq!(Inserted::Added, table.insert(node(0x01, n), now));
+        }
+        assert_eq!(3 * K, table.len());
+
+        let target = node(0x01, 3).id;
+        let closest = table.closest(&target, 3);
+        assert_eq!(target, closest[0].id);
+        assert!(closest.iter().all(|n| n.id[0] == 0x01));
+
+        // Failing nodes go questionable, then bad and replaced
+        let far = node(0x80, 1);
+        table.failed(&far.addr);
+        assert_eq!(Some(NodeStatus::Questionable), table.status(&far.id, now));
+        assert_eq!(
+            Inserted::Full {
+                questionable: Some(far)
+            },
+            table.insert(node(0x80, 100), now)
+        );
+        table.failed(&far.addr);
+        assert_eq!(Some(NodeStatus::Bad), table.status(&far.id, now));
+        assert!(!table.closest(&far.id, 3 * K).contains(&far));
+        assert_eq!(Inserted::Added, table.insert(node(0x80, 100), now));
+        assert_eq!(None, table.status(&far.id, now));
+
+        // Stale buckets are looked up in their range
+        let later = now + NODE_TIMEOUT;
+        let indexes: Vec<usize> = table
+            .refresh_targets(later)
+            .iter()
+            .map(|target| table.bucket_index(target))
+            .collect();
+        assert_eq!((0..table.buckets.len()).collect::<Vec<_>>(), indexes);
+        table.touch(later);
+        assert!(table.refresh_targets(later).is_empty());
+    }
+}
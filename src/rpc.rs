@@ -0,0 +1,664 @@
+// Remote control of a session over HTTP, speaking the Transmission RPC
+// protocol so that its web UIs, mobile apps and dashboards work with
+// torrent-rs. Only the methods in `Rpc::call` are implemented.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Instant,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bendy::decoding::FromBencode;
+use hyper::{
+    body,
+    header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::conn::AddrIncoming,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::{json, Map, Value};
+use tokio::task::JoinHandle;
+
+use crate::decode_torrent::{self, MetaInfo, MetaInfoError};
+use crate::definitions::InfoHash;
+use crate::error::{self, Error};
+use crate::magnet::Magnet;
+use crate::session::{Session, SharedTorrent};
+use crate::torrent::{Torrent, TorrentState};
+use crate::tracker::hash_to_bytes;
+
+pub const RPC_PATH: &str = "/transmission/rpc";
+// Token against cross-site requests, sent back by the clients after a 409
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+pub const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+const MAX_BODY: u64 = 16 * 1024 * 1024;
+
+// Values of the `status` field
+const STATUS_STOPPED: u8 = 0;
+const STATUS_DOWNLOAD_WAIT: u8 = 3;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED_WAIT: u8 = 5;
+const STATUS_SEED: u8 = 6;
+// Values of the `error` field
+const ERROR_NONE: u8 = 0;
+const ERROR_TRACKER: u8 = 2;
+
+// Transmission identifies torrents by small integers, given in the order
+// the torrents are first seen and never reused
+#[derive(Debug, Default)]
+struct Ids {
+    next: i64,
+    by_hash: HashMap<InfoHash, i64>,
+}
+
+/// Transmission RPC endpoint for a session.
+pub struct Rpc {
+    session: Arc<Session>,
+    session_id: String,
+    // `user:password` expected with HTTP basic authentication
+    credentials: Option<String>,
+    ids: StdMutex<Ids>,
+    started: Instant,
+}
+
+fn status(torrent: &Torrent) -> u8 {
+    match (torrent.state(), torrent.is_finished()) {
+        (TorrentState::Stopped | TorrentState::Paused, _) => STATUS_STOPPED,
+        (TorrentState::Queued, false) => STATUS_DOWNLOAD_WAIT,
+        (TorrentState::Queued, true) => STATUS_SEED_WAIT,
+        (TorrentState::Running, false) => STATUS_DOWNLOAD,
+        (TorrentState::Running, true) => STATUS_SEED,
+    }
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .expect("Valid response")
+}
+
+fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("Valid response")
+}
+
+impl Rpc {
+    pub fn new(session: Arc<Session>) -> Self {
+        let session_id: String = (0..24)
+            .map(|_| char::from(b'a' + rand::random::<u8>() % 26))
+            .collect();
+        Rpc {
+            session,
+            session_id,
+            credentials: None,
+            ids: StdMutex::new(Ids::default()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Require HTTP basic authentication, the RPC can add files anywhere the
+    /// process can read.
+    pub fn credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some(format!("{}:{}", user, password));
+        self
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    // Torrents of the session with their id, by id
+    fn torrents(&self) -> Vec<(i64, InfoHash)> {
+        let mut ids = self.ids.lock().unwrap();
+        let mut res: Vec<(i64, InfoHash)> = self
+            .session
+            .list()
+            .into_iter()
+            .map(|hash| {
+                let next = ids.next + 1;
+                let id = *ids.by_hash.entry(hash).or_insert(next);
+                ids.next = ids.next.max(id);
+                (id, hash)
+            })
+            .collect();
+        res.sort_unstable();
+        res
+    }
+
+    // Torrents picked by an `ids` argument: absent for all, an id, a hash
+    // or a list of those
+    fn select(&self, ids: &Value) -> Vec<(i64, InfoHash)> {
+        let torrents = self.torrents();
+        let wanted: Vec<&Value> = match ids {
+            Value::Null => return torrents,
+            // No activity tracking, all of them are "recently active"
+            Value::String(s) if s == "recently-active" => return torrents,
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        torrents
+            .into_iter()
+            .filter(|(id, hash)| {
+                wanted.iter().any(|w| match w {
+                    Value::Number(n) => n.as_i64() == Some(*id),
+                    Value::String(s) => hash_to_bytes(&s.to_lowercase()).ok() == Some(*hash),
+                    _ => false,
+                })
+            })
+            .collect()
+    }
+
+    fn summary(&self, torrent: &Torrent) -> Value {
+        let hash = *torrent.info_hash();
+        let id = self
+            .torrents()
+            .into_iter()
+            .find(|(_, h)| *h == hash)
+            .map(|(id, _)| id);
+        json!({
+            "id": id,
+            "name": torrent.meta().info.name,
+            "hashString": decode_torrent::bytes_to_hash(&hash),
+        })
+    }
+
+    fn torrent_fields(&self, id: i64, torrent: &Torrent) -> Map<String, Value> {
+        let stats = torrent.stats();
+        let size = stats.bytes_done + stats.bytes_remaining;
+        let peers: Vec<Value> = torrent
+            .peers()
+            .iter()
+            .map(|&addr| {
+                #[allow(unused_mut)]
+                let mut peer = json!({ "address": addr.ip().to_string(), "port": addr.port() });
+                #[cfg(feature = "geoip")]
+                if let Some(location) = torrent.peer_location(addr) {
+                    peer["country"] = json!(location.country);
+                    peer["asn"] = json!(location.asn);
+                    peer["asOrganization"] = json!(location.as_org);
+                }
+                peer
+            })
+            .collect();
+        // A single tracker, with the result of the last announce
+        let announce = &torrent.meta().announce;
+        let tracker_stats = json!([{
+            "id": 0,
+            "tier": 0,
+            "announce": announce,
+            "hasAnnounced": stats.seeds.is_some() || stats.tracker_error.is_some(),
+            "lastAnnounceSucceeded": stats.seeds.is_some() && stats.tracker_error.is_none(),
+            "lastAnnounceResult": stats.tracker_error.as_deref().unwrap_or("Success"),
+            "seederCount": stats.seeds.map_or(-1, |n| n as i64),
+            "leecherCount": stats.leechers.map_or(-1, |n| n as i64),
+        }]);
+        let fields = json!({
+            "id": id,
+            "hashString": decode_torrent::bytes_to_hash(torrent.info_hash()),
+            "name": torrent.meta().info.name,
+            "status": status(torrent),
+            "error": match stats.tracker_error {
+                Some(_) => ERROR_TRACKER,
+                None => ERROR_NONE,
+            },
+            "errorString": stats.tracker_error.clone().unwrap_or_default(),
+            "percentDone": stats.progress,
+            "isFinished": torrent.is_finished(),
+            "totalSize": size,
+            "sizeWhenDone": size,
+            "leftUntilDone": stats.bytes_remaining,
+            "haveValid": stats.bytes_done,
+            "downloadedEver": stats.downloaded,
+            "uploadedEver": stats.uploaded,
+            "uploadRatio": stats.ratio,
+            "rateDownload": stats.download_rate as u64,
+            "rateUpload": stats.upload_rate as u64,
+            "eta": stats.eta.map_or(-1, |eta| eta.as_secs() as i64),
+            "peersConnected": stats.connected_peers,
+            "peers": peers,
+            "trackers": [{ "id": 0, "tier": 0, "announce": announce }],
+            "trackerStats": tracker_stats,
+            "secondsSeeding": stats.seed_time.as_secs(),
+            "queuePosition": self.session.queue_position(torrent.info_hash()),
+            "downloadDir": self.session.config().save_path.display().to_string(),
+            "isPrivate": torrent.meta().info.private,
+            "comment": torrent.meta().comment.clone().unwrap_or_default(),
+            "creator": torrent.meta().created_by.clone().unwrap_or_default(),
+            "dateCreated": torrent.meta().creation_date.unwrap_or(0),
+        });
+        match fields {
+            Value::Object(map) => map,
+            _ => unreachable!(),
+        }
+    }
+
+    async fn torrent_get(&self, args: &Value) -> Value {
+        // Every field when none is asked for
+        let wanted: Option<Vec<&str>> = args["fields"]
+            .as_array()
+            .map(|fields| fields.iter().filter_map(Value::as_str).collect());
+        let mut torrents = Vec::new();
+        for (id, hash) in self.select(&args["ids"]) {
+            let Some(torrent) = self.session.get(&hash) else {
+                continue;
+            };
+            let mut fields = self.torrent_fields(id, &*torrent.lock().await);
+            if let Some(wanted) = &wanted {
+                fields.retain(|key, _| wanted.contains(&key.as_str()));
+            }
+            torrents.push(Value::Object(fields));
+        }
+        json!({ "torrents": torrents })
+    }
+
+    async fn add_bytes(&self, bytes: &[u8]) -> error::Result<(SharedTorrent, bool)> {
+        // Decoded first, the info hash is only looked for in a valid torrent
+        let meta = MetaInfo::from_bencode(bytes).map_err(MetaInfoError::from)?;
+        let hash = decode_torrent::get_info_hash(bytes)?;
+        if let Some(torrent) = self.session.get(&hash) {
+            return Ok((torrent, true));
+        }
+        let torrent = self.session.open_torrent(meta, hash).await?;
+        Ok((self.session.add_torrent(torrent)?, false))
+    }
+
+    async fn torrent_add(&self, args: &Value) -> Result<Value, String> {
+        let source = (args["filename"].as_str(), args["metainfo"].as_str());
+        let (torrent, duplicate) = match source {
+            (Some(uri), _) if uri.starts_with("magnet:") => {
+                let magnet = Magnet::parse(uri).map_err(|e| e.to_string())?;
+                match self.session.get(&magnet.info_hash) {
+                    Some(torrent) => Ok((torrent, true)),
+                    None => self.session.add_magnet(uri).await.map(|t| (t, false)),
+                }
+            }
+            (Some(path), _) => {
+                let bytes = fs::read(path).map_err(|e| e.to_string())?;
+                self.add_bytes(&bytes).await
+            }
+            (None, Some(metainfo)) => {
+                let bytes = STANDARD
+                    .decode(metainfo.trim())
+                    .map_err(|_| "Invalid metainfo".to_string())?;
+                self.add_bytes(&bytes).await
+            }
+            (None, None) => return Err("No filename or metainfo".to_string()),
+        }
+        .map_err(|e: Error| e.to_string())?;
+
+        let key = match duplicate {
+            true => "torrent-duplicate",
+            false => "torrent-added",
+        };
+        if !duplicate && args["paused"].as_bool() == Some(true) {
+            torrent.lock().await.pause();
+        }
+        self.session.update_queue().await;
+        let summary = self.summary(&*torrent.lock().await);
+        Ok(json!({ key: summary }))
+    }
+
+    async fn torrent_remove(&self, args: &Value) -> Result<Value, String> {
+        let delete = args["delete-local-data"].as_bool() == Some(true);
+        for (_, hash) in self.select(&args["ids"]) {
+            let Some(torrent) = self.session.remove_torrent(&hash).await else {
+                continue;
+            };
+            // Only the files of the torrent, their paths were checked
+            if delete {
+                let t = torrent.lock().await;
+                let mut file = t.file().lock().await;
+                file.delete_files().map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(json!({}))
+    }
+
+    // `torrent-start` waits for a queue slot, `torrent-start-now` doesn't
+    async fn torrent_start(&self, args: &Value, now: bool) -> Value {
+        for (_, hash) in self.select(&args["ids"]) {
+            if let Some(torrent) = self.session.get(&hash) {
+                let mut torrent = torrent.lock().await;
+                match now {
+                    true => torrent.start(),
+                    false => torrent.queue(),
+                }
+            }
+        }
+        if !now {
+            self.session.update_queue().await;
+        }
+        json!({})
+    }
+
+    async fn torrent_stop(&self, args: &Value) -> Value {
+        for (_, hash) in self.select(&args["ids"]) {
+            if let Some(torrent) = self.session.get(&hash) {
+                torrent.lock().await.stop().await;
+            }
+        }
+        self.session.update_queue().await;
+        json!({})
+    }
+
+    async fn session_stats(&self) -> Value {
+        let (mut active, mut paused) = (0, 0);
+        let (mut download_rate, mut upload_rate) = (0.0, 0.0);
+        let (mut downloaded, mut uploaded) = (0, 0);
+        let torrents = self.torrents();
+        for (_, hash) in &torrents {
+            let Some(torrent) = self.session.get(hash) else {
+                continue;
+            };
+            let torrent = torrent.lock().await;
+            let stats = torrent.stats();
+            match torrent.state() {
+                TorrentState::Running => active += 1,
+                TorrentState::Paused | TorrentState::Stopped => paused += 1,
+                TorrentState::Queued => (),
+            }
+            download_rate += stats.download_rate;
+            upload_rate += stats.upload_rate;
+            downloaded += stats.session_downloaded;
+            uploaded += stats.session_uploaded;
+        }
+        let current = json!({
+            "downloadedBytes": downloaded,
+            "uploadedBytes": uploaded,
+            "secondsActive": self.started.elapsed().as_secs(),
+            "sessionCount": 1,
+        });
+        // Previous runs are included when the session state is saved
+        let totals = self.session.totals().await;
+        let cumulative = json!({
+            "downloadedBytes": totals.downloaded,
+            "uploadedBytes": totals.uploaded,
+            "secondsActive": self.started.elapsed().as_secs(),
+            "sessionCount": 1,
+        });
+        json!({
+            "torrentCount": torrents.len(),
+            "activeTorrentCount": active,
+            "pausedTorrentCount": paused,
+            "downloadSpeed": download_rate as u64,
+            "uploadSpeed": upload_rate as u64,
+            "current-stats": current,
+            "cumulative-stats": cumulative,
+        })
+    }
+
+    fn session_get(&self) -> Value {
+        json!({
+            "version": concat!("torrent-rs ", env!("CARGO_PKG_VERSION")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "download-dir": self.session.config().save_path.display().to_string(),
+            "peer-port": self.session.local_addr().port(),
+        })
+    }
+
+    async fn call(&self, method: &str, args: &Value) -> Result<Value, String> {
+        match method {
+            "torrent-get" => Ok(self.torrent_get(args).await),
+            "torrent-add" => self.torrent_add(args).await,
+            "torrent-remove" => self.torrent_remove(args).await,
+            "torrent-start" => Ok(self.torrent_start(args, false).await),
+            "torrent-start-now" => Ok(self.torrent_start(args, true).await),
+            "torrent-stop" => Ok(self.torrent_stop(args).await),
+            "session-get" => Ok(self.session_get()),
+            "session-stats" => Ok(self.session_stats().await),
+            method => Err(format!("Method not supported: {}", method)),
+        }
+    }
+
+    /// Answer of a decoded request, `result` is "success" or the error.
+    pub async fn handle(&self, request: &Value) -> Value {
+        let args = &request["arguments"];
+        let (result, arguments) = match request["method"].as_str() {
+            Some(method) => match self.call(method, args).await {
+                Ok(arguments) => ("success".to_string(), arguments),
+                Err(e) => (e, json!({})),
+            },
+            None => ("No method".to_string(), json!({})),
+        };
+        let mut res = json!({ "result": result, "arguments": arguments });
+        if !request["tag"].is_null() {
+            res["tag"] = request["tag"].clone();
+        }
+        res
+    }
+
+    fn authorized(&self, req: &Request<Body>) -> bool {
+        let Some(credentials) = &self.credentials else {
+            return true;
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Basic "))
+            .and_then(|h| STANDARD.decode(h.trim()).ok())
+            .is_some_and(|h| h == credentials.as_bytes())
+    }
+
+    async fn handle_http(
+        self: Arc<Self>,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        // Also for the web UI, so that the browser asks for the password
+        if !self.authorized(&req) {
+            let mut res = empty_response(StatusCode::UNAUTHORIZED);
+            res.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                "Basic realm=\"torrent-rs\"".parse().expect("Valid header"),
+            );
+            return Ok(res);
+        }
+        #[cfg(feature = "webui")]
+        if let Some((content_type, content)) = crate::webui::asset(req.uri().path()) {
+            let res = Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(content))
+                .expect("Valid response");
+            return Ok(res);
+        }
+        if req.uri().path() != RPC_PATH {
+            return Ok(empty_response(StatusCode::NOT_FOUND));
+        }
+        let session_id = req.headers().get(SESSION_ID_HEADER);
+        if session_id.is_none_or(|id| id.as_bytes() != self.session_id.as_bytes()) {
+            let mut res = empty_response(StatusCode::CONFLICT);
+            res.headers_mut().insert(
+                SESSION_ID_HEADER,
+                self.session_id.parse().expect("Valid header"),
+            );
+            return Ok(res);
+        }
+        if req.method() != Method::POST {
+            return Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED));
+        }
+        let length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|h| h.to_str().ok()?.parse::<u64>().ok());
+        if length.is_none_or(|length| length > MAX_BODY) {
+            return Ok(empty_response(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+
+        let request = match body::to_bytes(req.into_body()).await {
+            Ok(bytes) => serde_json::from_slice::<Value>(&bytes),
+            Err(_) => return Ok(empty_response(StatusCode::BAD_REQUEST)),
+        };
+        match request {
+            Ok(request) => Ok(json_response(StatusCode::OK, &self.handle(&request).await)),
+            Err(_) => Ok(empty_response(StatusCode::BAD_REQUEST)),
+        }
+    }
+
+    /// Serve the RPC on `addr` until the task is aborted, with the web UI
+    /// when enabled. Returns the address bound, e.g. when `addr` has port 0.
+    pub fn serve(self, addr: SocketAddr) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+        let incoming = AddrIncoming::bind(&addr).map_err(io::Error::other)?;
+        let local_addr = incoming.local_addr();
+        let rpc = Arc::new(self);
+        let make_service = make_service_fn(move |_| {
+            let rpc = rpc.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| rpc.clone().handle_http(req))) }
+        });
+        let server = Server::builder(incoming).serve(make_service);
+        let handle = tokio::spawn(async move {
+            let _ = server.await;
+        });
+        Ok((local_addr, handle))
+    }
+}
+
+#[cfg(test)]
+mod rpc_tests {
+    use super::*;
+    use crate::create::TorrentBuilder;
+    use crate::session::SessionConfig;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    async fn session(save_path: &str) -> Arc<Session> {
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(save_path)
+            .build()
+            .unwrap();
+        Arc::new(Session::new(config).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn control_torrents() {
+        const DIR: &str = "./test_rpc_control_torrents";
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(DIR).unwrap();
+        fs::write(format!("{}/content", DIR), vec![1; 20000]).unwrap();
+        let new = TorrentBuilder::new(format!("{}/content", DIR))
+            .announce("udp://x:1")
+            .build()
+            .unwrap();
+        let rpc = Rpc::new(session(DIR).await);
+
+        let add = json!({
+            "method": "torrent-add",
+            "arguments": { "metainfo": STANDARD.encode(&new.bytes), "paused": true },
+            "tag": 7,
+        });
+        let res = rpc.handle(&add).await;
+        assert_eq!("success", res["result"]);
+        assert_eq!(7, res["tag"]);
+        assert_eq!(1, res["arguments"]["torrent-added"]["id"]);
+        let res = rpc.handle(&add).await;
+        assert_eq!("content", res["arguments"]["torrent-duplicate"]["name"]);
+
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "fields": ["id", "status", "totalSize"], "ids": [1] },
+        });
+        let res = rpc.handle(&get).await;
+        let torrent = &res["arguments"]["torrents"][0];
+        assert_eq!(STATUS_STOPPED, torrent["status"]);
+        assert_eq!(20000, torrent["totalSize"]);
+        assert!(torrent.get("name").is_none());
+
+        let hash = decode_torrent::bytes_to_hash(&new.info_hash);
+        let start = json!({ "method": "torrent-start", "arguments": { "ids": [hash] } });
+        assert_eq!("success", rpc.handle(&start).await["result"]);
+        // The content was already there, rechecked when added
+        let res = rpc.handle(&get).await;
+        assert_eq!(STATUS_SEED, res["arguments"]["torrents"][0]["status"]);
+
+        let stats = rpc.handle(&json!({ "method": "session-stats" })).await;
+        assert_eq!(1, stats["arguments"]["activeTorrentCount"]);
+        let res = rpc.handle(&json!({ "method": "torrent-verify" })).await;
+        assert_ne!("success", res["result"]);
+
+        let remove = json!({ "method": "torrent-remove", "arguments": { "ids": 1 } });
+        assert_eq!("success", rpc.handle(&remove).await["result"]);
+        assert!(rpc.session.list().is_empty());
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_local_data() {
+        const DIR: &str = "./test_rpc_delete_local_data";
+        let _ = fs::remove_dir_all(DIR);
+        fs::create_dir_all(format!("{}/content/sub", DIR)).unwrap();
+        fs::write(format!("{}/content/a", DIR), vec![1; 20000]).unwrap();
+        fs::write(format!("{}/content/sub/b", DIR), vec![2; 100]).unwrap();
+        fs::write(format!("{}/other", DIR), b"kept").unwrap();
+        let new = TorrentBuilder::new(format!("{}/content", DIR))
+            .announce("udp://x:1")
+            .build()
+            .unwrap();
+        let rpc = Rpc::new(session(DIR).await);
+
+        let add = json!({
+            "method": "torrent-add",
+            "arguments": { "metainfo": STANDARD.encode(&new.bytes), "paused": true },
+        });
+        assert_eq!("success", rpc.handle(&add).await["result"]);
+        let remove = json!({
+            "method": "torrent-remove",
+            "arguments": { "ids": 1, "delete-local-data": true },
+        });
+        assert_eq!("success", rpc.handle(&remove).await["result"]);
+
+        // The directory of the torrent goes, what is next to it stays
+        assert!(!std::path::Path::new(DIR).join("content").exists());
+        assert_eq!(b"kept", &fs::read(format!("{}/other", DIR)).unwrap()[..]);
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    async fn post(addr: SocketAddr, headers: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "POST {} HTTP/1.1\r\nHost: x\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            RPC_PATH,
+            headers,
+            body.len(),
+            body
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn http_session_id() {
+        let rpc = Rpc::new(session(".").await).credentials("user", "secret");
+        let session_id = rpc.session_id().to_string();
+        let (addr, server) = rpc.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let body = r#"{"method":"session-get"}"#;
+        let auth = format!(
+            "Authorization: Basic {}\r\n",
+            STANDARD.encode("user:secret")
+        );
+
+        assert!(post(addr, "", body).await.starts_with("HTTP/1.1 401"));
+        let res = post(addr, &auth, body).await;
+        assert!(res.starts_with("HTTP/1.1 409"));
+        assert!(res
+            .to_lowercase()
+            .contains(&format!("x-transmission-session-id: {}", session_id)));
+
+        let headers = format!("{}{}: {}\r\n", auth, SESSION_ID_HEADER, session_id);
+        let res = post(addr, &headers, body).await;
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains(&format!("\"rpc-version\":{}", RPC_VERSION)));
+        server.abort();
+    }
+}
@@ -0,0 +1,1091 @@
+// Decides which blocks to request from which peer. Peers are filled up to
+// their pipeline depth, pieces already started are finished first and new
+// ones are chosen by a pluggable strategy, rarest first by default. A block
+// is only requested from one peer at a time, except in endgame when every
+// missing block is already requested.
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::availability::Availability;
+use crate::blocks::{BlockState, BlockTracker};
+use crate::definitions::BLOCK_SIZE;
+use crate::layout::Layout;
+use crate::peer::{self, Peer};
+use crate::pick::{Deadline, PickContext, PickStrategy};
+use crate::priority::{self, Priority};
+use crate::resume::{ResumeData, UnfinishedPiece};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub peer: SocketAddr,
+    pub index: usize,
+    pub begin: usize,
+    pub length: usize,
+}
+
+/// Requests outstanding on a peer until its rate and latency are known.
+pub const INITIAL_REQUESTS: usize = 4;
+/// Fewest requests outstanding on a peer, however slow.
+pub const MIN_REQUESTS: usize = 2;
+// Requests above the bandwidth-delay product, so that a peer which could go
+// faster gets the chance to show it
+const HEADROOM: usize = 2;
+// Rate samples are taken over this interval and smoothed
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_SMOOTHING: f64 = 0.3;
+
+// Throughput and latency of a peer, sizing how many requests it gets
+#[derive(Debug, Default)]
+struct PeerRate {
+    // Bytes per second, 0 until the first sample
+    rate: f64,
+    bytes: usize,
+    since: Option<Instant>,
+    // The lowest latency doesn't include the time requests wait in the
+    // peer's queue, the window doesn't grow from its own queueing
+    min_rtt: Option<Duration>,
+}
+
+impl PeerRate {
+    fn received(&mut self, length: usize, sent: Instant, now: Instant) {
+        let rtt = now.saturating_duration_since(sent);
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+
+        let Some(since) = self.since else {
+            self.since = Some(now);
+            return;
+        };
+        self.bytes += length;
+        let elapsed = now.saturating_duration_since(since);
+        if elapsed >= RATE_INTERVAL {
+            let sample = self.bytes as f64 / elapsed.as_secs_f64();
+            self.rate = match self.rate {
+                0.0 => sample,
+                rate => rate + RATE_SMOOTHING * (sample - rate),
+            };
+            self.bytes = 0;
+            self.since = Some(now);
+        }
+    }
+
+    fn budget(&self) -> usize {
+        match self.min_rtt {
+            Some(rtt) if self.rate > 0.0 => {
+                let bdp = self.rate * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+                bdp.ceil() as usize + HEADROOM
+            }
+            _ => INITIAL_REQUESTS,
+        }
+    }
+}
+
+/// Where a piece is, e.g. to draw a piece bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceProgress {
+    Missing,
+    // Blocks received and none requested, the piece is stalled
+    Partial { received: usize, blocks: usize },
+    Downloading { received: usize, blocks: usize },
+    Verified,
+}
+
+#[derive(Debug)]
+struct PeerSlot {
+    have: Vec<bool>,
+    choking: bool,
+    // Most requests the peer accepts at once
+    limit: usize,
+    // (piece, block) requested and not received yet, with when they were sent
+    pending: HashMap<(usize, usize), Instant>,
+    rate: PeerRate,
+}
+
+impl PeerSlot {
+    fn has(&self, index: usize) -> bool {
+        self.have.get(index).copied().unwrap_or(false)
+    }
+
+    fn budget(&self) -> usize {
+        self.rate.budget().max(MIN_REQUESTS).min(self.limit)
+    }
+
+    fn capacity(&self) -> usize {
+        match self.choking {
+            true => 0,
+            false => self.budget().saturating_sub(self.pending.len()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    blocks: BlockTracker,
+    availability: Availability,
+    // Our verified pieces
+    have: Vec<bool>,
+    // From the priorities of the files
+    file_priorities: Vec<Priority>,
+    // Set for the piece itself
+    piece_priorities: HashMap<usize, Priority>,
+    // Ahead of the other pieces of their priority
+    preview: HashSet<usize>,
+    deadlines: HashMap<usize, Instant>,
+    strategy: Box<dyn PickStrategy>,
+    peers: HashMap<SocketAddr, PeerSlot>,
+}
+
+impl Scheduler {
+    pub fn new(piece_size: usize, size: u64) -> Self {
+        let blocks = BlockTracker::new(piece_size, size);
+        let num_pieces = blocks.num_pieces();
+        Scheduler {
+            blocks,
+            availability: Availability::new(num_pieces),
+            have: vec![false; num_pieces],
+            file_priorities: vec![Priority::Normal; num_pieces],
+            piece_priorities: HashMap::new(),
+            preview: HashSet::new(),
+            deadlines: HashMap::new(),
+            strategy: Box::new(Deadline::default()),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Scheduler for the pieces of `layout`. Pieces made only of padding
+    /// files are never requested.
+    pub fn from_layout(layout: &Layout) -> Self {
+        let mut scheduler = Scheduler::new(layout.piece_size(), layout.size());
+        for index in 0..scheduler.num_pieces() {
+            scheduler.have[index] = layout.is_padding_piece(index);
+        }
+        scheduler
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn blocks(&self) -> &BlockTracker {
+        &self.blocks
+    }
+
+    pub fn blocks_mut(&mut self) -> &mut BlockTracker {
+        &mut self.blocks
+    }
+
+    pub fn availability(&self) -> &Availability {
+        &self.availability
+    }
+
+    /// Pieces already verified, e.g. after a recheck.
+    pub fn set_have(&mut self, have: &[bool]) {
+        self.have.copy_from_slice(have);
+    }
+
+    pub fn piece_progress(&self, index: usize) -> PieceProgress {
+        if self.have[index] {
+            return PieceProgress::Verified;
+        }
+        let Some(states) = self.blocks.blocks(index) else {
+            return PieceProgress::Missing;
+        };
+        let received = states
+            .iter()
+            .filter(|&&b| b == BlockState::Received)
+            .count();
+        let blocks = states.len();
+        let requested = states.iter().any(|b| {
+            matches!(
+                b,
+                BlockState::Requested { .. } | BlockState::TimedOut { .. }
+            )
+        });
+        match (requested, received) {
+            (true, _) => PieceProgress::Downloading { received, blocks },
+            (false, 0) => PieceProgress::Missing,
+            (false, _) => PieceProgress::Partial { received, blocks },
+        }
+    }
+
+    pub fn progress(&self) -> Vec<PieceProgress> {
+        (0..self.num_pieces())
+            .map(|i| self.piece_progress(i))
+            .collect()
+    }
+
+    /// State of each block of the piece, to see what a stalled piece waits
+    /// for.
+    pub fn block_progress(&self, index: usize) -> Vec<BlockState> {
+        match self.blocks.blocks(index) {
+            Some(states) => states.to_vec(),
+            None => {
+                let state = match self.have[index] {
+                    true => BlockState::Received,
+                    false => BlockState::Missing,
+                };
+                vec![state; self.blocks.num_blocks(index)]
+            }
+        }
+    }
+
+    /// Pieces partially received, saved in the resume data along with the
+    /// blocks written to disk.
+    pub fn unfinished(&self) -> Vec<UnfinishedPiece> {
+        self.blocks
+            .unfinished()
+            .into_iter()
+            .filter(|p| !self.have[p.index])
+            .collect()
+    }
+
+    /// Pick up from resume data after a restart. Verified pieces and the
+    /// blocks received of unfinished ones aren't requested again.
+    pub fn apply_resume(&mut self, data: &ResumeData) {
+        for (have, &verified) in self.have.iter_mut().zip(&data.pieces) {
+            *have |= verified;
+        }
+        for piece in &data.unfinished {
+            if piece.index < self.num_pieces() && !self.have[piece.index] {
+                self.blocks.restore(piece);
+            }
+        }
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.have[index]
+    }
+
+    /// How new pieces are chosen, pieces with a deadline then rarest first
+    /// by default.
+    pub fn set_strategy<S: PickStrategy + 'static>(&mut self, strategy: S) {
+        self.strategy = Box::new(strategy);
+    }
+
+    pub fn set_wanted(&mut self, index: usize, wanted: bool) {
+        let priority = match wanted {
+            true => Priority::Normal,
+            false => Priority::Skip,
+        };
+        self.set_piece_priority(index, priority);
+    }
+
+    /// Priority of the piece, overriding the one of its files.
+    pub fn set_piece_priority(&mut self, index: usize, priority: Priority) {
+        self.piece_priorities.insert(index, priority);
+    }
+
+    /// Go back to the priority of the files of the piece.
+    pub fn clear_piece_priority(&mut self, index: usize) {
+        self.piece_priorities.remove(&index);
+    }
+
+    /// Priorities of the files of the torrent, see
+    /// [`MultiFileStorage::file_priorities`](crate::storage::MultiFileStorage::file_priorities).
+    pub fn set_file_priorities(&mut self, layout: &Layout, files: &[Priority]) {
+        self.file_priorities = priority::piece_priorities(layout, files);
+    }
+
+    /// Preview mode, the first and last `count` pieces of each wanted file
+    /// go first. A `count` of 0 turns it off.
+    pub fn set_preview(&mut self, layout: &Layout, files: &[Priority], count: usize) {
+        self.preview = priority::preview_pieces(layout, files, count)
+            .into_iter()
+            .collect();
+    }
+
+    pub fn is_preview(&self, index: usize) -> bool {
+        self.preview.contains(&index)
+    }
+
+    // Started pieces are finished from the highest rank
+    fn rank(&self, index: usize) -> (Priority, bool) {
+        (self.priority(index), self.is_preview(index))
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        match self.piece_priorities.get(&index) {
+            Some(&priority) => priority,
+            None => self.file_priorities[index],
+        }
+    }
+
+    /// Every wanted piece is verified.
+    pub fn is_finished(&self) -> bool {
+        (0..self.num_pieces()).all(|i| self.have[i] || !self.needs_piece(i))
+    }
+
+    /// Bytes of the pieces we have.
+    pub fn bytes_done(&self) -> u64 {
+        (0..self.num_pieces())
+            .filter(|&i| self.have[i])
+            .map(|i| self.blocks.piece_len(i) as u64)
+            .sum()
+    }
+
+    /// Bytes of the wanted pieces we don't have yet.
+    pub fn bytes_left(&self) -> u64 {
+        (0..self.num_pieces())
+            .filter(|&i| self.needs_piece(i))
+            .map(|i| self.blocks.piece_len(i) as u64)
+            .sum()
+    }
+
+    /// A new peer, choking us until it says otherwise.
+    pub fn add_peer(&mut self, addr: SocketAddr, have: Vec<bool>, limit: usize) {
+        self.remove_peer(addr);
+        self.availability.add_peer(&have);
+        let slot = PeerSlot {
+            have,
+            choking: true,
+            limit,
+            pending: HashMap::new(),
+            rate: PeerRate::default(),
+        };
+        self.peers.insert(addr, slot);
+    }
+
+    /// The peer disconnected, the blocks it was asked for are reclaimed.
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        if let Some(slot) = self.peers.remove(&addr) {
+            self.availability.remove_peer(&slot.have);
+            self.blocks.peer_gone(addr);
+        }
+    }
+
+    pub fn peer_have(&mut self, addr: SocketAddr, index: usize) {
+        let Some(slot) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        if index < slot.have.len() && !slot.have[index] {
+            slot.have[index] = true;
+            self.availability.add_have(index);
+        }
+    }
+
+    pub fn peer_bitfield(&mut self, addr: SocketAddr, have: Vec<bool>) {
+        if let Some(slot) = self.peers.get_mut(&addr) {
+            self.availability.update(&slot.have, &have);
+            slot.have = have;
+        }
+    }
+
+    /// A choking peer drops our requests, its blocks are reclaimed.
+    pub fn set_choking(&mut self, addr: SocketAddr, choking: bool) {
+        let Some(slot) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        slot.choking = choking;
+        if choking {
+            slot.pending.clear();
+            self.blocks.peer_gone(addr);
+        }
+    }
+
+    /// Most requests the peer accepts at once, from its extension handshake.
+    /// Fewer are sent when its rate and latency don't need as many.
+    pub fn set_request_limit(&mut self, addr: SocketAddr, limit: usize) {
+        if let Some(slot) = self.peers.get_mut(&addr) {
+            slot.limit = limit;
+        }
+    }
+
+    /// Requests the peer gets at once for its measured rate and latency.
+    pub fn request_budget(&self, addr: SocketAddr) -> usize {
+        self.peers.get(&addr).map_or(0, PeerSlot::budget)
+    }
+
+    /// Requests sent to the peer and not answered yet.
+    pub fn pending(&self, addr: SocketAddr) -> usize {
+        self.peers.get(&addr).map_or(0, |s| s.pending.len())
+    }
+
+    fn needs_piece(&self, index: usize) -> bool {
+        !self.have[index] && self.priority(index).is_wanted()
+    }
+
+    /// Every block still needed is requested, they may be requested from
+    /// several peers.
+    pub fn is_endgame(&self) -> bool {
+        let mut requested = false;
+        for index in (0..self.num_pieces()).filter(|&i| self.needs_piece(i)) {
+            match self.blocks.blocks(index) {
+                None => return false,
+                Some(blocks) => {
+                    if blocks.iter().any(BlockState::is_needed) {
+                        return false;
+                    }
+                    requested |= blocks
+                        .iter()
+                        .any(|b| matches!(b, BlockState::Requested { .. }));
+                }
+            }
+        }
+        requested
+    }
+
+    // Peer with requests pending on the piece
+    fn delivering(&self, index: usize) -> Option<SocketAddr> {
+        self.blocks.blocks(index)?.iter().find_map(|b| match b {
+            BlockState::Requested { peer, .. } => Some(*peer),
+            _ => None,
+        })
+    }
+
+    // A block of the piece already requested from another peer
+    fn duplicate_block(&self, slot: &PeerSlot, index: usize) -> Option<usize> {
+        let blocks = self.blocks.blocks(index)?;
+        (0..blocks.len()).find(|&b| {
+            matches!(blocks[b], BlockState::Requested { .. })
+                && !slot.pending.contains_key(&(index, b))
+        })
+    }
+
+    // Next block to ask `addr` for, and whether it is already requested from
+    // someone else
+    fn next_block(&self, addr: SocketAddr, now: Instant) -> Option<(usize, usize, bool)> {
+        let slot = &self.peers[&addr];
+
+        // Started pieces with a deadline go first, overdue ones are asked to
+        // several peers. A peer without them falls back to normal picking.
+        let mut deadlines: Vec<(Instant, usize)> = self
+            .deadlines
+            .iter()
+            .filter(|(&i, _)| self.needs_piece(i) && slot.has(i) && self.blocks.is_in_progress(i))
+            .map(|(&i, &at)| (at, i))
+            .collect();
+        deadlines.sort_unstable();
+        for &(at, index) in &deadlines {
+            if let Some(&block) = self.blocks.needed_blocks(index).first() {
+                return Some((index, block, false));
+            }
+            if at <= now {
+                if let Some(block) = self.duplicate_block(slot, index) {
+                    return Some((index, block, true));
+                }
+            }
+        }
+
+        let mut started: Vec<usize> = self
+            .blocks
+            .in_progress()
+            .filter(|&i| self.needs_piece(i) && slot.has(i))
+            .collect();
+        // Pieces the peer is delivering first, to finish them with fewer
+        // pieces held partially
+        started.sort_unstable_by_key(|&i| {
+            (Reverse(self.rank(i)), self.delivering(i) != Some(addr), i)
+        });
+
+        for &index in &started {
+            let Some(&block) = self.blocks.needed_blocks(index).first() else {
+                continue;
+            };
+            // Left to the peer delivering it while it can take more
+            let other = self.delivering(index).filter(|&p| p != addr);
+            if other.is_some_and(|p| self.peers.get(&p).is_some_and(|s| s.capacity() > 0)) {
+                continue;
+            }
+            return Some((index, block, false));
+        }
+
+        let candidates: Vec<usize> = (0..self.num_pieces())
+            .filter(|&i| self.needs_piece(i) && slot.has(i) && !self.blocks.is_in_progress(i))
+            .collect();
+        if !candidates.is_empty() {
+            let ctx = PickContext::new(self, now);
+            // A strategy can't make us start a piece we can't get
+            if let Some(index) = self.strategy.pick(&candidates, &ctx) {
+                if candidates.binary_search(&index).is_ok() {
+                    return Some((index, 0, false));
+                }
+            }
+        }
+
+        if self.is_endgame() {
+            for &index in &started {
+                if let Some(block) = self.duplicate_block(slot, index) {
+                    return Some((index, block, true));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Ask for the piece to be downloaded within `duration`, before other
+    /// pieces. Once the deadline passed its blocks are also requested from
+    /// other peers than the ones already asked.
+    pub fn set_piece_deadline(&mut self, index: usize, duration: Duration) {
+        self.deadlines.insert(index, Instant::now() + duration);
+    }
+
+    pub fn deadline(&self, index: usize) -> Option<Instant> {
+        self.deadlines.get(&index).copied()
+    }
+
+    pub fn clear_piece_deadline(&mut self, index: usize) {
+        self.deadlines.remove(&index);
+    }
+
+    /// Pieces not verified by their deadline.
+    pub fn missed_deadlines(&self, now: Instant) -> Vec<usize> {
+        let mut res: Vec<usize> = self
+            .deadlines
+            .iter()
+            .filter(|&(_, &at)| at <= now)
+            .map(|(&i, _)| i)
+            .collect();
+        res.sort_unstable();
+        res
+    }
+
+    /// Requests to send now, filling every unchoked peer up to its budget.
+    /// Requests which timed out are given to other peers.
+    pub fn schedule(&mut self, now: Instant) -> Vec<Request> {
+        for (index, block) in self.blocks.expire(now) {
+            for slot in self.peers.values_mut() {
+                slot.pending.remove(&(index, block));
+            }
+        }
+
+        let mut addrs: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        addrs.sort_unstable();
+
+        let mut res = Vec::new();
+        for addr in addrs {
+            while self.peers[&addr].capacity() > 0 {
+                let Some((index, block, duplicate)) = self.next_block(addr, now) else {
+                    break;
+                };
+                // Duplicates leave the first request in the tracker
+                if !duplicate {
+                    self.blocks.request(index, block, addr, now);
+                }
+                self.peers
+                    .get_mut(&addr)
+                    .unwrap()
+                    .pending
+                    .insert((index, block), now);
+
+                let (begin, length) = self.blocks.block_range(index, block);
+                res.push(Request {
+                    peer: addr,
+                    index,
+                    begin,
+                    length,
+                });
+            }
+        }
+        res
+    }
+
+    /// A request couldn't be sent, the block is needed again.
+    pub fn cancel_request(&mut self, request: &Request) {
+        let block = request.begin / BLOCK_SIZE;
+        if let Some(slot) = self.peers.get_mut(&request.peer) {
+            slot.pending.remove(&(request.index, block));
+        }
+        let owned = self.blocks.blocks(request.index).is_some_and(|blocks| {
+            matches!(blocks[block], BlockState::Requested { peer, .. } if peer == request.peer)
+        });
+        if owned {
+            self.blocks.cancel(request.index, block);
+        }
+    }
+
+    /// A block arrived from `addr`. Returns `None` if it wasn't needed,
+    /// otherwise the duplicate requests of endgame to cancel.
+    pub fn block_received(
+        &mut self,
+        addr: SocketAddr,
+        index: usize,
+        begin: usize,
+    ) -> Option<Vec<Request>> {
+        self.block_received_at(addr, index, begin, Instant::now())
+    }
+
+    /// Same as `block_received`, for a block which arrived at `now`.
+    pub fn block_received_at(
+        &mut self,
+        addr: SocketAddr,
+        index: usize,
+        begin: usize,
+        now: Instant,
+    ) -> Option<Vec<Request>> {
+        let block = begin / BLOCK_SIZE;
+        let length = match index < self.num_pieces() && block < self.blocks.num_blocks(index) {
+            true => self.blocks.block_range(index, block).1,
+            false => 0,
+        };
+        if let Some(slot) = self.peers.get_mut(&addr) {
+            if let Some(sent) = slot.pending.remove(&(index, block)) {
+                slot.rate.received(length, sent, now);
+            }
+        }
+        if index >= self.num_pieces() || !self.needs_piece(index) {
+            return None;
+        }
+        if !self.blocks.received(index, begin) {
+            return None;
+        }
+
+        let (begin, length) = self.blocks.block_range(index, block);
+        let mut cancels = Vec::new();
+        for (&peer, slot) in self.peers.iter_mut() {
+            if slot.pending.remove(&(index, block)).is_some() {
+                cancels.push(Request {
+                    peer,
+                    index,
+                    begin,
+                    length,
+                });
+            }
+        }
+        Some(cancels)
+    }
+
+    /// Every block of the piece was received, it can be verified.
+    pub fn is_piece_complete(&self, index: usize) -> bool {
+        self.blocks.is_complete(index)
+    }
+
+    /// Result of the verification of a complete piece. An invalid piece is
+    /// downloaded again from scratch.
+    pub fn piece_verified(&mut self, index: usize, valid: bool) {
+        self.blocks.remove(index);
+        self.have[index] = valid;
+        if valid {
+            self.deadlines.remove(&index);
+        }
+    }
+}
+
+/// Send the requests through their peers. Requests a peer can't take, or
+/// for peers which are gone, are handed back to the scheduler. Peers which
+/// fail to take them are removed with their blocks and returned.
+pub async fn send_requests(
+    scheduler: &mut Scheduler,
+    peers: &HashMap<SocketAddr, Arc<RwLock<Peer>>>,
+    requests: Vec<Request>,
+) -> Vec<SocketAddr> {
+    let mut failed = Vec::new();
+    for request in requests {
+        let sent = match peers.get(&request.peer) {
+            Some(_) if failed.contains(&request.peer) => false,
+            Some(p) => {
+                let sent = peer::send_request(
+                    p,
+                    request.index as u32,
+                    request.begin as u32,
+                    request.length as u32,
+                )
+                .await;
+                sent.unwrap_or_else(|_| {
+                    failed.push(request.peer);
+                    false
+                })
+            }
+            None => false,
+        };
+        if !sent {
+            scheduler.cancel_request(&request);
+        }
+    }
+    for &addr in &failed {
+        scheduler.remove_peer(addr);
+    }
+    failed
+}
+
+/// Withdraw the duplicate requests of endgame. Returns the peers the
+/// cancels couldn't be sent to.
+pub async fn send_cancels(
+    peers: &HashMap<SocketAddr, Arc<RwLock<Peer>>>,
+    cancels: Vec<Request>,
+) -> Vec<SocketAddr> {
+    let mut failed = Vec::new();
+    for cancel in cancels {
+        let Some(p) = peers.get(&cancel.peer) else {
+            continue;
+        };
+        if failed.contains(&cancel.peer) {
+            continue;
+        }
+        let sent = peer::send_cancel(
+            p,
+            cancel.index as u32,
+            cancel.begin as u32,
+            cancel.length as u32,
+        )
+        .await;
+        if sent.is_err() {
+            failed.push(cancel.peer);
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::layout::FileAttr;
+    use crate::pick::{self, RarestFirst};
+
+    fn addr(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    #[test]
+    fn rarest_first_and_pipeline() {
+        // 3 pieces of 2 blocks
+        let mut scheduler = Scheduler::new(2 * BLOCK_SIZE, 6 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(Deadline::new(RarestFirst::new(0)));
+        scheduler.add_peer(addr(1), vec![true, true, true], 3);
+        scheduler.add_peer(addr(2), vec![true, true, false], 3);
+
+        // Choking peers get nothing
+        assert!(scheduler.schedule(Instant::now()).is_empty());
+
+        scheduler.set_choking(addr(1), false);
+        let requests = scheduler.schedule(Instant::now());
+        let pieces: Vec<(usize, usize)> = requests.iter().map(|r| (r.index, r.begin)).collect();
+        // Piece 2 is the rarest and is finished before starting another one
+        assert_eq!(vec![(2, 0), (2, BLOCK_SIZE), (0, 0)], pieces);
+        assert_eq!(3, scheduler.pending(addr(1)));
+
+        // No block is asked twice
+        scheduler.set_choking(addr(2), false);
+        let requests = scheduler.schedule(Instant::now());
+        let pieces: Vec<(usize, usize)> = requests.iter().map(|r| (r.index, r.begin)).collect();
+        assert_eq!(vec![(0, BLOCK_SIZE), (1, 0), (1, BLOCK_SIZE)], pieces);
+    }
+
+    #[test]
+    fn reclaim_from_choking_peer() {
+        let mut scheduler = Scheduler::new(2 * BLOCK_SIZE, 4 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(Deadline::new(RarestFirst::new(0)));
+        scheduler.add_peer(addr(1), vec![true; 2], 2);
+        scheduler.add_peer(addr(2), vec![true; 2], 2);
+        scheduler.set_choking(addr(1), false);
+        assert_eq!(2, scheduler.schedule(Instant::now()).len());
+
+        assert!(scheduler.block_received(addr(1), 0, 0).is_some());
+        // Not asked for, or already received
+        assert!(scheduler.block_received(addr(1), 0, 0).is_none());
+
+        scheduler.set_choking(addr(1), true);
+        scheduler.set_choking(addr(2), false);
+        let requests = scheduler.schedule(Instant::now());
+        assert_eq!((0, BLOCK_SIZE), (requests[0].index, requests[0].begin));
+        assert_eq!(addr(2), requests[0].peer);
+
+        // A peer leaving frees its blocks too
+        scheduler.remove_peer(addr(2));
+        assert_eq!(vec![1], scheduler.blocks().needed_blocks(0));
+        assert_eq!(1, scheduler.availability().peers());
+    }
+
+    #[test]
+    fn endgame() {
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, 2 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(Deadline::new(RarestFirst::new(0)));
+        scheduler.add_peer(addr(1), vec![true; 2], 4);
+        scheduler.add_peer(addr(2), vec![true; 2], 4);
+        scheduler.set_choking(addr(1), false);
+        scheduler.set_choking(addr(2), false);
+
+        let requests = scheduler.schedule(Instant::now());
+        // Both pieces go to the first peer, then the second one asks again
+        assert_eq!(4, requests.len());
+        assert!(scheduler.is_endgame());
+        assert_eq!(addr(2), requests[2].peer);
+
+        let cancels = scheduler.block_received(addr(2), 0, 0).unwrap();
+        assert_eq!(addr(1), cancels[0].peer);
+        assert!(scheduler.is_piece_complete(0));
+        scheduler.piece_verified(0, true);
+        assert!(!scheduler.is_finished());
+
+        // An invalid piece starts over
+        scheduler.block_received(addr(1), 1, 0).unwrap();
+        scheduler.piece_verified(1, false);
+        assert_eq!(vec![0], scheduler.blocks().needed_blocks(1));
+        assert!(!scheduler.is_endgame());
+    }
+
+    #[test]
+    fn piece_deadline() {
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, 4 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(Deadline::new(RarestFirst::new(0)));
+        scheduler.blocks_mut().set_timeout(Duration::from_secs(600));
+        scheduler.add_peer(addr(1), vec![true; 4], 1);
+        scheduler.add_peer(addr(2), vec![true; 4], 1);
+        scheduler.set_choking(addr(1), false);
+        scheduler.set_choking(addr(2), false);
+        scheduler.set_piece_deadline(3, Duration::from_secs(60));
+
+        let now = Instant::now();
+        let requests = scheduler.schedule(now);
+        assert_eq!((addr(1), 3), (requests[0].peer, requests[0].index));
+        // Piece 3 is already requested and not late yet
+        assert_eq!((addr(2), 0), (requests[1].peer, requests[1].index));
+
+        // Too late, the second peer is asked as well
+        let later = now + Duration::from_secs(120);
+        assert_eq!(vec![3], scheduler.missed_deadlines(later));
+        scheduler.block_received(addr(2), 0, 0).unwrap();
+        let requests = scheduler.schedule(later);
+        assert_eq!((addr(2), 3), (requests[0].peer, requests[0].index));
+
+        let cancels = scheduler.block_received(addr(2), 3, 0).unwrap();
+        assert_eq!(addr(1), cancels[0].peer);
+        scheduler.piece_verified(3, true);
+        assert!(scheduler.missed_deadlines(later).is_empty());
+    }
+
+    #[test]
+    fn random_first() {
+        let mut common = vec![true; 8];
+        common[5] = false;
+        let first_picks: HashSet<usize> = (0..20)
+            .map(|_| {
+                let mut scheduler = Scheduler::new(BLOCK_SIZE, 8 * BLOCK_SIZE as u64);
+                scheduler.add_peer(addr(1), vec![true; 8], 1);
+                scheduler.add_peer(addr(2), common.clone(), 0);
+                scheduler.set_choking(addr(1), false);
+                scheduler.schedule(Instant::now())[0].index
+            })
+            .collect();
+        assert!(first_picks.len() > 1);
+
+        // Enough pieces to trade, the rarest goes first
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, 8 * BLOCK_SIZE as u64);
+        let mut have = vec![false; 8];
+        have[..pick::DEFAULT_RANDOM_FIRST].fill(true);
+        scheduler.set_have(&have);
+        scheduler.add_peer(addr(1), vec![true; 8], 1);
+        scheduler.add_peer(addr(2), common, 0);
+        scheduler.set_choking(addr(1), false);
+        assert_eq!(5, scheduler.schedule(Instant::now())[0].index);
+    }
+
+    #[test]
+    fn priorities() {
+        let layout = Layout::new(
+            BLOCK_SIZE,
+            vec![
+                (PathBuf::from("t/a"), 2 * BLOCK_SIZE as u64),
+                (PathBuf::from("t/b"), 2 * BLOCK_SIZE as u64),
+            ],
+        );
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, layout.size());
+        scheduler.set_file_priorities(&layout, &[Priority::Low, Priority::High]);
+        scheduler.set_piece_priority(0, Priority::Skip);
+        scheduler.add_peer(addr(1), vec![true; 4], 4);
+        scheduler.set_choking(addr(1), false);
+
+        // High pieces first, whatever the picking mode
+        let requests = scheduler.schedule(Instant::now());
+        let mut first: Vec<usize> = requests[..2].iter().map(|r| r.index).collect();
+        first.sort_unstable();
+        assert_eq!(vec![2, 3], first);
+        assert_eq!(1, requests[2].index);
+        assert_eq!(3, requests.len());
+
+        for index in 1..4 {
+            scheduler.block_received(addr(1), index, 0).unwrap();
+            scheduler.piece_verified(index, true);
+        }
+        assert!(scheduler.is_finished());
+    }
+
+    #[test]
+    fn preview() {
+        let layout = Layout::new(
+            BLOCK_SIZE,
+            vec![
+                (PathBuf::from("t/a"), 4 * BLOCK_SIZE as u64),
+                (PathBuf::from("t/b"), 4 * BLOCK_SIZE as u64),
+            ],
+        );
+        let files = [Priority::Normal, Priority::Skip];
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, layout.size());
+        scheduler.set_file_priorities(&layout, &files);
+        scheduler.set_preview(&layout, &files, 1);
+        scheduler.add_peer(addr(1), vec![true; 8], 2);
+        scheduler.set_choking(addr(1), false);
+
+        let mut first: Vec<usize> = scheduler
+            .schedule(Instant::now())
+            .iter()
+            .map(|r| r.index)
+            .collect();
+        first.sort_unstable();
+        assert_eq!(vec![0, 3], first);
+    }
+
+    #[test]
+    fn skip_padding() {
+        let mut layout = Layout::new(
+            BLOCK_SIZE,
+            vec![
+                (PathBuf::from("t/a"), BLOCK_SIZE as u64),
+                (PathBuf::from("t/.pad/0"), 2 * BLOCK_SIZE as u64),
+                (PathBuf::from("t/b"), BLOCK_SIZE as u64),
+            ],
+        );
+        let padding = FileAttr {
+            padding: true,
+            ..Default::default()
+        };
+        layout.set_attr(1, padding);
+
+        let mut scheduler = Scheduler::from_layout(&layout);
+        scheduler.add_peer(addr(1), vec![true; 4], 4);
+        scheduler.set_choking(addr(1), false);
+        let mut pieces: Vec<usize> = scheduler
+            .schedule(Instant::now())
+            .iter()
+            .map(|r| r.index)
+            .collect();
+        pieces.sort_unstable();
+        assert_eq!(vec![0, 3], pieces);
+        assert!(scheduler.has_piece(1) && scheduler.has_piece(2));
+    }
+
+    #[test]
+    fn request_budget() {
+        // Fast peer 50 ms away, everything asked is answered
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, 1000 * BLOCK_SIZE as u64);
+        scheduler.add_peer(addr(1), vec![true; 1000], 250);
+        scheduler.set_choking(addr(1), false);
+        assert_eq!(INITIAL_REQUESTS, scheduler.request_budget(addr(1)));
+
+        let mut now = Instant::now();
+        for _ in 0..60 {
+            let requests = scheduler.schedule(now);
+            now += Duration::from_millis(50);
+            for r in requests {
+                scheduler.block_received_at(r.peer, r.index, r.begin, now);
+            }
+        }
+        assert!(scheduler.request_budget(addr(1)) > INITIAL_REQUESTS);
+
+        // Slow peer sending a block a second, the window stays small however
+        // late the blocks come
+        let mut scheduler = Scheduler::new(BLOCK_SIZE, 10 * BLOCK_SIZE as u64);
+        scheduler.add_peer(addr(2), vec![true; 10], 250);
+        scheduler.set_choking(addr(2), false);
+        let start = Instant::now();
+        let requests = scheduler.schedule(start);
+        for (n, r) in requests.iter().enumerate() {
+            let at = start + Duration::from_secs(n as u64 + 1);
+            scheduler.block_received_at(r.peer, r.index, r.begin, at);
+        }
+        assert_eq!(1 + HEADROOM, scheduler.request_budget(addr(2)));
+    }
+
+    #[test]
+    fn piece_affinity() {
+        let mut scheduler = Scheduler::new(4 * BLOCK_SIZE, 8 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(RarestFirst::new(0));
+        scheduler.add_peer(addr(1), vec![true; 2], 2);
+        scheduler.add_peer(addr(2), vec![true; 2], 2);
+        scheduler.set_choking(addr(2), false);
+        assert_eq!(2, scheduler.schedule(Instant::now()).len());
+        scheduler.block_received(addr(2), 0, 0).unwrap();
+
+        // The rest of piece 0 waits for the peer delivering it
+        scheduler.set_choking(addr(1), false);
+        let requests = scheduler.schedule(Instant::now());
+        let blocks: Vec<(SocketAddr, usize, usize)> = requests
+            .iter()
+            .map(|r| (r.peer, r.index, r.begin))
+            .collect();
+        assert_eq!(
+            vec![
+                (addr(1), 1, 0),
+                (addr(1), 1, BLOCK_SIZE),
+                (addr(2), 0, 2 * BLOCK_SIZE)
+            ],
+            blocks
+        );
+    }
+
+    #[test]
+    fn resume_partial_pieces() {
+        let mut scheduler = Scheduler::new(2 * BLOCK_SIZE, 4 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(RarestFirst::new(0));
+        scheduler.add_peer(addr(1), vec![true; 2], 4);
+        scheduler.set_choking(addr(1), false);
+        scheduler.schedule(Instant::now());
+        scheduler.block_received(addr(1), 0, 0).unwrap();
+        scheduler.block_received(addr(1), 1, BLOCK_SIZE).unwrap();
+        scheduler.block_received(addr(1), 1, 0).unwrap();
+        scheduler.piece_verified(1, true);
+
+        let data = ResumeData {
+            pieces: vec![false, true],
+            unfinished: scheduler.unfinished(),
+            ..Default::default()
+        };
+        assert_eq!(1, data.unfinished.len());
+
+        // Only the missing block is asked after the restart
+        let mut scheduler = Scheduler::new(2 * BLOCK_SIZE, 4 * BLOCK_SIZE as u64);
+        scheduler.apply_resume(&data);
+        scheduler.add_peer(addr(1), vec![true; 2], 4);
+        scheduler.set_choking(addr(1), false);
+        let requests = scheduler.schedule(Instant::now());
+        assert_eq!(1, requests.len());
+        assert_eq!((0, BLOCK_SIZE), (requests[0].index, requests[0].begin));
+    }
+
+    #[test]
+    fn piece_progress() {
+        let mut scheduler = Scheduler::new(2 * BLOCK_SIZE, 6 * BLOCK_SIZE as u64);
+        scheduler.set_strategy(RarestFirst::new(0));
+        scheduler.add_peer(addr(1), vec![true, true, false], 4);
+        scheduler.set_choking(addr(1), false);
+        scheduler.schedule(Instant::now());
+        for (index, begin) in [(0, 0), (0, BLOCK_SIZE), (1, 0)] {
+            scheduler.block_received(addr(1), index, begin).unwrap();
+        }
+        scheduler.piece_verified(0, true);
+        assert_eq!(
+            vec![
+                PieceProgress::Verified,
+                PieceProgress::Downloading {
+                    received: 1,
+                    blocks: 2
+                },
+                PieceProgress::Missing
+            ],
+            scheduler.progress()
+        );
+
+        // The peer choked us in the middle of piece 1
+        scheduler.set_choking(addr(1), true);
+        assert_eq!(
+            PieceProgress::Partial {
+                received: 1,
+                blocks: 2
+            },
+            scheduler.piece_progress(1)
+        );
+        assert_eq!(
+            vec![BlockState::Received, BlockState::Missing],
+            scheduler.block_progress(1)
+        );
+        assert_eq!(vec![BlockState::Received; 2], scheduler.block_progress(0));
+    }
+}
@@ -0,0 +1,2239 @@
+// Several torrents sharing one listening port, peer ID, dialer and disk
+// configuration. Incoming connections go to the torrent of their info hash.
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use bendy::{decoding::FromBencode, encoding::ToBencode};
+use tokio::{
+    sync::{broadcast, Mutex},
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+
+use crate::capture::Capture;
+use crate::decode_torrent::{self, Info, MetaInfo, MetaInfoError};
+use crate::definitions::{self, InfoHash, PeerId, PEER_ID_LEN};
+use crate::dht::{self, Dht, DhtConfig, DhtState};
+use crate::dialer::{DialConfig, Dialer};
+use crate::error::{Error, Result};
+use crate::events::{Event, EventSender, Events, EVENT_CAPACITY};
+use crate::fairness::{self, ConnectionLimit, Demand, Demands, FairnessConfig, Shares};
+use crate::file::{Storage, StorageConfig};
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::handshake::Handshake;
+use crate::ipfilter::SharedIpFilter;
+use crate::listener::{self, InboundConfig, InboundLimiter, Pending};
+use crate::magnet::Magnet;
+use crate::metadata;
+use crate::peer::{PeerError, PeerSource};
+use crate::proxy::{self, ProxyConfig};
+use crate::recheck::{RecheckConfig, RecheckScheduler};
+use crate::resume::{ResumeData, RESUME_EXTENSION};
+use crate::state::{self, SessionState, TorrentEntry, TORRENT_EXTENSION};
+use crate::stats::TransferTotals;
+use crate::storage::StorageDirs;
+use crate::torrent::{self, SeedLimits, Torrent, TorrentState, DEFAULT_TRACKER_BIND};
+use crate::tracker::AnnounceCounters;
+use crate::web_seed;
+
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+// Dynamic ports, see `SessionConfigBuilder::random_listen_port`
+pub const RANDOM_LISTEN_PORTS: RangeInclusive<u16> = 49152..=65535;
+// A peer not sending the whole metadata in time is given up for the next one
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(300);
+pub const DEFAULT_CHECKPOINT_PIECES: usize = 100;
+// Each running torrent is announced to the DHT this often
+pub const DHT_ANNOUNCE_INTERVAL: Duration = dht::ANNOUNCE_INTERVAL;
+// Stale buckets of the DHT are looked up this often
+pub const DHT_REFRESH_INTERVAL: Duration = dht::REFRESH_INTERVAL;
+// A proxy failing to listen for us is asked again after this long
+pub const PROXY_BIND_RETRY: Duration = Duration::from_secs(30);
+
+pub type SharedTorrent = Arc<Mutex<Torrent>>;
+type Torrents = Arc<StdMutex<HashMap<InfoHash, SharedTorrent>>>;
+
+/// When `Session::step` saves the state on its own, so that a crash loses
+/// little of what was verified. Only with a state directory.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct CheckpointConfig {
+    // Longest time between two saves
+    pub interval: Option<Duration>,
+    // Pieces verified across the torrents since the last save
+    pub pieces: Option<usize>,
+    pub on_completed: bool,
+}
+
+impl Default for CheckpointConfig {
+    fn default() -> Self {
+        CheckpointConfig {
+            interval: Some(DEFAULT_CHECKPOINT_INTERVAL),
+            pieces: Some(DEFAULT_CHECKPOINT_PIECES),
+            on_completed: true,
+        }
+    }
+}
+
+// What happened since the last checkpoint
+struct CheckpointState {
+    last: Instant,
+    events: Events,
+    pieces: usize,
+    completed: bool,
+}
+
+impl CheckpointState {
+    fn is_due(&mut self, config: &CheckpointConfig, now: Instant) -> bool {
+        let missed = self.events.missed();
+        while let Some(event) = self.events.try_recv() {
+            match event {
+                Event::PieceVerified { .. } => self.pieces += 1,
+                Event::TorrentCompleted { .. } => self.completed = true,
+                _ => (),
+            }
+        }
+        // Events dropped in between may have been either
+        if self.events.missed() > missed {
+            self.pieces = usize::MAX;
+            self.completed = true;
+        }
+        config
+            .interval
+            .is_some_and(|interval| now.saturating_duration_since(self.last) >= interval)
+            || config.pieces.is_some_and(|pieces| self.pieces >= pieces)
+            || (config.on_completed && self.completed)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct SessionConfig {
+    // Port 0 picks a free one, see `Session::local_addr`
+    pub listen_addr: SocketAddr,
+    // A free port of the range, picked at random on start, instead of the
+    // port of `listen_addr`
+    pub listen_ports: Option<RangeInclusive<u16>>,
+    // An unspecified `listen_addr` takes peers of both IPv4 and IPv6
+    pub dual_stack: bool,
+    // Peers are also accepted through `dial.proxy`, with its BIND command
+    pub proxy_listen: bool,
+    // Local address announces to UDP trackers are sent from
+    pub tracker_bind: SocketAddr,
+    pub peer_id: PeerId,
+    // Message stream encryption and uTP, neither is supported yet and
+    // `SessionConfigBuilder::build` refuses to turn them on
+    pub encryption: bool,
+    pub utp: bool,
+    // Peers of each torrent, more are refused
+    pub max_peers_per_torrent: Option<usize>,
+    // Torrents downloading or seeding at the same time, the next ones in
+    // the queue wait for a slot
+    pub max_active_downloads: Option<usize>,
+    pub max_active_seeds: Option<usize>,
+    // Default limits of the torrents added
+    pub seed_limits: SeedLimits,
+    // Rates and slots shared between the running torrents
+    pub fairness: FairnessConfig,
+    pub dial: DialConfig,
+    pub inbound: InboundConfig,
+    pub recheck: RecheckConfig,
+    // Storage of every torrent, the hashing pool included
+    pub storage: StorageConfig,
+    // Directory the torrents opened by the session are saved in
+    pub save_path: PathBuf,
+    // Torrents are downloaded there and moved to `save_path` once complete
+    pub incomplete_path: Option<PathBuf>,
+    // Files are named with `storage::INCOMPLETE_SUFFIX` until complete
+    pub incomplete_suffix: bool,
+    // Where `Session::save_state` saves the session, restored on start
+    pub state_dir: Option<PathBuf>,
+    pub checkpoint: CheckpointConfig,
+    // File the messages exchanged with peers and trackers are recorded to,
+    // see `crate::capture`
+    pub capture: Option<PathBuf>,
+    // Finds peers without trackers, off unless given
+    pub dht: Option<DhtConfig>,
+    // Locates the peers of every torrent
+    #[cfg(feature = "geoip")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub geoip: Option<Arc<GeoIp>>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], DEFAULT_LISTEN_PORT)),
+            listen_ports: None,
+            dual_stack: true,
+            proxy_listen: false,
+            tracker_bind: DEFAULT_TRACKER_BIND,
+            peer_id: definitions::new_peer_id(),
+            encryption: false,
+            utp: false,
+            max_peers_per_torrent: None,
+            max_active_downloads: None,
+            max_active_seeds: None,
+            seed_limits: SeedLimits::default(),
+            fairness: FairnessConfig::default(),
+            dial: DialConfig::default(),
+            inbound: InboundConfig::default(),
+            recheck: RecheckConfig::default(),
+            storage: StorageConfig::default(),
+            save_path: PathBuf::from("."),
+            incomplete_path: None,
+            incomplete_suffix: false,
+            state_dir: None,
+            checkpoint: CheckpointConfig::default(),
+            capture: None,
+            dht: None,
+            #[cfg(feature = "geoip")]
+            geoip: None,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn builder() -> SessionConfigBuilder {
+        SessionConfigBuilder::default()
+    }
+
+    /// Where the files of the torrents opened by the session go.
+    pub fn storage_dirs(&self) -> StorageDirs {
+        StorageDirs {
+            save_path: self.save_path.clone(),
+            incomplete_path: self.incomplete_path.clone(),
+            incomplete_suffix: self.incomplete_suffix,
+        }
+    }
+}
+
+/// Build a `SessionConfig` from the defaults, checking the settings.
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfigBuilder {
+    config: SessionConfig,
+    peer_id_prefix: Option<String>,
+    ip_filter: Option<SharedIpFilter>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.to_string())
+}
+
+impl SessionConfigBuilder {
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.listen_addr = addr;
+        self
+    }
+
+    /// Listen on all interfaces on `port`, 0 for any free one.
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.config.listen_addr.set_port(port);
+        self.config.listen_ports = None;
+        self
+    }
+
+    /// Listen on a free port of `ports`, a different one on each start.
+    pub fn listen_port_range(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.config.listen_ports = Some(ports);
+        self
+    }
+
+    /// Listen on a random free port of `RANDOM_LISTEN_PORTS`, making the
+    /// session harder to tell apart from its port.
+    pub fn random_listen_port(self) -> Self {
+        self.listen_port_range(RANDOM_LISTEN_PORTS)
+    }
+
+    /// Listen on IPv6 as well as on IPv4, or the other way around, if the
+    /// listen address is unspecified. On by default.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn tracker_bind(mut self, addr: SocketAddr) -> Self {
+        self.config.tracker_bind = addr;
+        self
+    }
+
+    pub fn peer_id(mut self, peer_id: PeerId) -> Self {
+        self.config.peer_id = peer_id;
+        self.peer_id_prefix = None;
+        self
+    }
+
+    /// Random peer ID starting with `prefix`, e.g. `-XX0100-`.
+    pub fn peer_id_prefix(mut self, prefix: &str) -> Self {
+        self.peer_id_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Encrypt the connections to peers (MSE), not supported yet.
+    pub fn encryption(mut self, encryption: bool) -> Self {
+        self.config.encryption = encryption;
+        self
+    }
+
+    /// Talk to peers over uTP as well as TCP, not supported yet.
+    pub fn utp(mut self, utp: bool) -> Self {
+        self.config.utp = utp;
+        self
+    }
+
+    pub fn max_peers_per_torrent(mut self, max: usize) -> Self {
+        self.config.max_peers_per_torrent = Some(max);
+        self
+    }
+
+    pub fn max_active_downloads(mut self, max: usize) -> Self {
+        self.config.max_active_downloads = Some(max);
+        self
+    }
+
+    pub fn max_active_seeds(mut self, max: usize) -> Self {
+        self.config.max_active_seeds = Some(max);
+        self
+    }
+
+    pub fn seed_limits(mut self, limits: SeedLimits) -> Self {
+        self.config.seed_limits = limits;
+        self
+    }
+
+    /// Upload rate of all the torrents, in bytes per second.
+    pub fn upload_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.config.fairness.upload_rate = bytes_per_sec;
+        self
+    }
+
+    /// Download rate of all the torrents, in bytes per second.
+    pub fn download_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.config.fairness.download_rate = bytes_per_sec;
+        self
+    }
+
+    /// Peers unchoked at the same time, across the torrents.
+    pub fn max_upload_slots(mut self, max: usize) -> Self {
+        self.config.fairness.upload_slots = Some(max);
+        self
+    }
+
+    /// Peers connected at the same time, across the torrents.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.fairness.connections = Some(max);
+        self
+    }
+
+    pub fn fairness(mut self, fairness: FairnessConfig) -> Self {
+        self.config.fairness = fairness;
+        self
+    }
+
+    /// Outbound connections being established at the same time.
+    pub fn max_half_open(mut self, max: usize) -> Self {
+        self.config.dial.max_half_open = max;
+        self
+    }
+
+    /// Inbound connections waiting for their handshake.
+    pub fn max_pending_inbound(mut self, max: usize) -> Self {
+        self.config.inbound.max_pending = max;
+        self
+    }
+
+    /// Dial peers through a SOCKS5 proxy. Peers connecting to the session
+    /// still reach its listener directly, unless `proxy_listen`.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.config.dial.proxy = Some(proxy);
+        self
+    }
+
+    /// Also accept peers through the proxy, which listens for them with the
+    /// SOCKS5 BIND command. Each BIND takes a single peer, the port given to
+    /// trackers and peers is that of the current one.
+    pub fn proxy_listen(mut self, listen: bool) -> Self {
+        self.config.proxy_listen = listen;
+        self
+    }
+
+    pub fn save_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.save_path = path.into();
+        self
+    }
+
+    /// Download to `path`, torrents are moved to the save path once
+    /// complete.
+    pub fn incomplete_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.incomplete_path = Some(path.into());
+        self
+    }
+
+    /// Mark the files of torrents being downloaded with
+    /// `storage::INCOMPLETE_SUFFIX`.
+    pub fn incomplete_suffix(mut self, suffix: bool) -> Self {
+        self.config.incomplete_suffix = suffix;
+        self
+    }
+
+    /// Bytes of pieces kept in memory by each torrent.
+    pub fn cache_size(mut self, bytes: usize) -> Self {
+        self.config.storage.cache_size = bytes;
+        self
+    }
+
+    /// Read rate shared by the rechecks of every torrent.
+    pub fn recheck_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.config.recheck.bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    pub fn max_concurrent_rechecks(mut self, max: usize) -> Self {
+        self.config.recheck.max_concurrent = max;
+        self
+    }
+
+    pub fn dial(mut self, dial: DialConfig) -> Self {
+        self.config.dial = dial;
+        self
+    }
+
+    pub fn inbound(mut self, inbound: InboundConfig) -> Self {
+        self.config.inbound = inbound;
+        self
+    }
+
+    pub fn storage(mut self, storage: StorageConfig) -> Self {
+        self.config.storage = storage;
+        self
+    }
+
+    /// Run a DHT node, see `crate::dht`. Its port defaults to the one of
+    /// the session.
+    pub fn dht(mut self, config: DhtConfig) -> Self {
+        self.config.dht = Some(config);
+        self
+    }
+
+    /// Save and restore the torrents of the session in `dir`.
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Save the state every `interval` at most, see `CheckpointConfig`.
+    pub fn checkpoint_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.checkpoint.interval = interval;
+        self
+    }
+
+    /// Record every message exchanged with peers and trackers to `path`,
+    /// to debug problems with other clients.
+    pub fn capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.capture = Some(path.into());
+        self
+    }
+
+    pub fn checkpoint(mut self, checkpoint: CheckpointConfig) -> Self {
+        self.config.checkpoint = checkpoint;
+        self
+    }
+
+    #[cfg(feature = "geoip")]
+    pub fn geoip(mut self, geoip: GeoIp) -> Self {
+        self.config.geoip = Some(Arc::new(geoip));
+        self
+    }
+
+    /// Blocklist of both the dialer and the listener, whatever `dial` and
+    /// `inbound` say.
+    pub fn ip_filter(mut self, filter: SharedIpFilter) -> Self {
+        self.ip_filter = Some(filter);
+        self
+    }
+
+    pub fn build(self) -> io::Result<SessionConfig> {
+        let mut config = self.config;
+        if let Some(prefix) = self.peer_id_prefix {
+            if prefix.len() > PEER_ID_LEN {
+                return Err(invalid("Peer ID prefix too long"));
+            }
+            config.peer_id = definitions::peer_id_with_prefix(prefix.as_bytes());
+        }
+        if let Some(filter) = self.ip_filter {
+            config.dial.ip_filter = filter.clone();
+            config.inbound.ip_filter = filter;
+        }
+        if config.encryption || config.utp {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Encryption and uTP aren't supported",
+            ));
+        }
+        if config.dial.max_half_open == 0 {
+            return Err(invalid("No half-open connection allowed"));
+        }
+        if config.inbound.max_pending == 0 || config.recheck.max_concurrent == 0 {
+            return Err(invalid("Limits must be positive"));
+        }
+        if config.max_peers_per_torrent == Some(0) {
+            return Err(invalid("Limits must be positive"));
+        }
+        let fairness = &config.fairness;
+        if [fairness.upload_rate, fairness.download_rate].contains(&Some(0))
+            || [fairness.upload_slots, fairness.connections].contains(&Some(0))
+        {
+            return Err(invalid("Limits must be positive"));
+        }
+        if let Some(ports) = &config.listen_ports {
+            if ports.is_empty() || *ports.start() == 0 {
+                return Err(invalid("Invalid listen port range"));
+            }
+        }
+        if config.proxy_listen && config.dial.proxy.is_none() {
+            return Err(invalid("No proxy to listen on"));
+        }
+        Ok(config)
+    }
+}
+
+pub struct Session {
+    config: SessionConfig,
+    dialer: Dialer,
+    // Peers of every torrent, as many as `fairness.connections`
+    connections: ConnectionLimit,
+    recheck: RecheckScheduler,
+    torrents: Torrents,
+    // Info hashes by queue position
+    queue: StdMutex<Vec<InfoHash>>,
+    // Transfer not counted by the current torrents: of the removed ones and
+    // of previous runs
+    retired: StdMutex<TransferTotals>,
+    events: EventSender,
+    // When the limits were last shared between the torrents
+    last_share: StdMutex<Instant>,
+    checkpoint: StdMutex<CheckpointState>,
+    capture: Option<Capture>,
+    dht: Option<Arc<Dht>>,
+    // When the DHT was last refreshed, and each torrent announced to it
+    dht_refresh: StdMutex<Instant>,
+    dht_announces: StdMutex<HashMap<InfoHash, Instant>>,
+    local_addrs: Vec<SocketAddr>,
+    // Where the proxy listens for us, with `proxy_listen`
+    proxy_bound: Arc<StdMutex<Option<SocketAddr>>>,
+    accept: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for accept in &self.accept {
+            accept.abort();
+        }
+    }
+}
+
+// Hand an incoming connection to the torrent it asks for, dropping it if we
+// don't have that torrent. The reply carries the peer ID of the torrent.
+async fn dispatch(
+    pending: Pending,
+    torrents: Torrents,
+    capture: Option<Capture>,
+) -> std::result::Result<(), PeerError> {
+    let addr = pending.addr();
+    let (mut stream, remote) = pending.handshake().await?;
+    if let Some(capture) = &capture {
+        capture.peer_in(addr, &remote.to_bytes());
+    }
+    let hash = *remote.get_hash();
+    let torrent = match torrents.lock().unwrap().get(&hash) {
+        Some(torrent) => torrent.clone(),
+        None => return Err(PeerError::WrongTorrent),
+    };
+
+    let peer_id = *torrent.lock().await.peer_id();
+    let mut hs = Handshake::default();
+    hs.set_hash(&hash);
+    hs.set_peer_id(&peer_id);
+    hs.reply(&mut stream).await?;
+    if let Some(capture) = &capture {
+        capture.peer_out(addr, &hs.to_bytes());
+    }
+    torrent
+        .lock()
+        .await
+        .add_handshaked(stream, addr, PeerSource::Incoming, &remote)
+        .await?;
+
+    Ok(())
+}
+
+// Accept peers one at a time through the proxy, binding again after each.
+// A proxy without BIND leaves the session's own listener alone.
+async fn proxy_accept(
+    proxy: ProxyConfig,
+    inbound: InboundLimiter,
+    torrents: Torrents,
+    capture: Option<Capture>,
+    bound: Arc<StdMutex<Option<SocketAddr>>>,
+) {
+    loop {
+        let res = match proxy::bind(&proxy, SocketAddr::from(([0, 0, 0, 0], 0))).await {
+            Ok(bind) => {
+                *bound.lock().unwrap() = Some(bind.bound_addr());
+                let torrents: Vec<_> = torrents.lock().unwrap().values().cloned().collect();
+                for torrent in torrents {
+                    torrent
+                        .lock()
+                        .await
+                        .set_listen_port(bind.bound_addr().port());
+                }
+                bind.accept().await
+            }
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok((stream, addr)) => {
+                if let Some(pending) = inbound.admit(stream, listener::canonical(addr)) {
+                    let torrents = torrents.clone();
+                    let capture = capture.clone();
+                    tokio::spawn(async move {
+                        let _ = dispatch(pending, torrents, capture).await;
+                    });
+                }
+            }
+            Err(e) => {
+                *bound.lock().unwrap() = None;
+                if e.kind() == io::ErrorKind::Unsupported {
+                    return;
+                }
+                time::sleep(PROXY_BIND_RETRY).await;
+            }
+        }
+    }
+}
+
+fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+impl Session {
+    /// Start listening for peers on `config.listen_addr`, or a port of
+    /// `config.listen_ports`, with the torrents saved in `config.state_dir`
+    /// if any.
+    pub async fn new(config: SessionConfig) -> io::Result<Self> {
+        let listeners = match &config.listen_ports {
+            Some(ports) => {
+                listener::bind_in_range(config.listen_addr, config.dual_stack, ports.clone())?
+            }
+            None => listener::bind_all(config.listen_addr, config.dual_stack)?,
+        };
+        let local_addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|l| l.local_addr())
+            .collect::<io::Result<_>>()?;
+        let torrents: Torrents = Arc::default();
+        let capture = config.capture.as_ref().map(Capture::create).transpose()?;
+
+        // Both families share the limits of inbound connections
+        let inbound = InboundLimiter::new(config.inbound.clone());
+        let mut accept: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                let inbound = inbound.clone();
+                let torrents = torrents.clone();
+                let capture = capture.clone();
+                tokio::spawn(async move {
+                    while let Ok(pending) = inbound.accept(&listener).await {
+                        let torrents = torrents.clone();
+                        let capture = capture.clone();
+                        tokio::spawn(async move {
+                            let _ = dispatch(pending, torrents, capture).await;
+                        });
+                    }
+                })
+            })
+            .collect();
+        let proxy_bound = Arc::default();
+        if let (true, Some(proxy)) = (config.proxy_listen, &config.dial.proxy) {
+            accept.push(tokio::spawn(proxy_accept(
+                proxy.clone(),
+                inbound,
+                torrents.clone(),
+                capture.clone(),
+                Arc::clone(&proxy_bound),
+            )));
+        }
+
+        let dht = match &config.dht {
+            Some(dht) => {
+                let mut dht = dht.clone();
+                if dht.bind.port() == 0 {
+                    dht.bind.set_port(local_addrs[0].port());
+                }
+                let dht = Arc::new(Dht::bind(dht).await?);
+                if let Some(dir) = &config.state_dir {
+                    if let Ok(Some(state)) = DhtState::load(dir) {
+                        dht.restore(&state);
+                    }
+                }
+                let bootstrap = dht.clone();
+                tokio::spawn(async move { bootstrap.bootstrap().await });
+                Some(dht)
+            }
+            None => None,
+        };
+
+        let events = broadcast::channel(EVENT_CAPACITY).0;
+        let checkpoint = CheckpointState {
+            last: Instant::now(),
+            events: Events::new(events.subscribe()),
+            pieces: 0,
+            completed: false,
+        };
+        let session = Session {
+            dialer: Dialer::new(config.dial.clone()),
+            connections: ConnectionLimit::new(config.fairness.connections),
+            recheck: RecheckScheduler::new(config.recheck.clone()),
+            config,
+            torrents,
+            queue: StdMutex::new(Vec::new()),
+            retired: StdMutex::default(),
+            events,
+            last_share: StdMutex::new(Instant::now()),
+            checkpoint: StdMutex::new(checkpoint),
+            capture,
+            dht,
+            dht_refresh: StdMutex::new(Instant::now()),
+            dht_announces: StdMutex::default(),
+            local_addrs,
+            proxy_bound,
+            accept,
+        };
+        session.restore_state().await.map_err(into_io)?;
+        Ok(session)
+    }
+
+    // Add back the torrents saved by `save_state`, with their queue order,
+    // settings and totals
+    async fn restore_state(&self) -> Result<()> {
+        let Some(dir) = &self.config.state_dir else {
+            return Ok(());
+        };
+        let Some(saved) = SessionState::load(dir)? else {
+            return Ok(());
+        };
+
+        for entry in saved.torrents {
+            let bytes = fs::read(state::torrent_path(dir, &entry.info_hash))?;
+            if decode_torrent::get_info_hash(&bytes)? != entry.info_hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Saved torrent doesn't match its info hash",
+                )
+                .into());
+            }
+            let meta = MetaInfo::from_bencode(&bytes).map_err(MetaInfoError::from)?;
+
+            let mut file = self.open_file(&meta.info)?;
+            // Resume data is trusted, without it, or when it doesn't match
+            // the files, what is on disk is rechecked
+            let resume = ResumeData::load(state::resume_path(dir, &entry.info_hash)).ok();
+            let resumed = match &resume {
+                Some(data) => file.apply_resume(data).await?,
+                None => false,
+            };
+            if !resumed {
+                self.check_file(&meta, &mut file).await?;
+            }
+            let mut torrent = Torrent::with_file(meta, entry.info_hash, file);
+            if let (true, Some(data)) = (resumed, &resume) {
+                torrent.scheduler_mut().apply_resume(data);
+            }
+            torrent.restore_totals(entry.downloaded, entry.uploaded, entry.seed_time);
+            if let Some(profile) = entry.profile {
+                torrent.set_profile(profile);
+            }
+
+            let torrent = self.add_torrent(torrent)?;
+            let mut t = torrent.lock().await;
+            t.set_max_peers(entry.max_peers);
+            t.set_weight(entry.weight);
+            t.set_seed_limits(entry.seed_limits);
+            match entry.state {
+                TorrentState::Paused => t.pause(),
+                TorrentState::Stopped => t.stop().await,
+                TorrentState::Running | TorrentState::Queued => (),
+            }
+        }
+        self.update_queue().await;
+
+        let restored = self.totals().await;
+        *self.retired.lock().unwrap() = TransferTotals {
+            downloaded: saved.totals.downloaded.saturating_sub(restored.downloaded),
+            uploaded: saved.totals.uploaded.saturating_sub(restored.uploaded),
+        };
+        Ok(())
+    }
+
+    /// Save the torrents in queue order, their resume data, settings and
+    /// totals in `config.state_dir`, for the next `Session::new`. Does
+    /// nothing without a state directory.
+    pub async fn save_state(&self) -> Result<()> {
+        let Some(dir) = &self.config.state_dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir)?;
+
+        let mut saved = SessionState {
+            totals: self.totals().await,
+            ..Default::default()
+        };
+        for info_hash in self.queue() {
+            let Some(torrent) = self.get(&info_hash) else {
+                continue;
+            };
+            let t = torrent.lock().await;
+            let stats = t.stats();
+            let mut resume = t.file().lock().await.resume_data().await?;
+            resume.downloaded = stats.downloaded;
+            resume.uploaded = stats.uploaded;
+            resume.save(state::resume_path(dir, &info_hash))?;
+
+            let meta = t
+                .meta()
+                .to_bencode()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            state::write_atomic(state::torrent_path(dir, &info_hash), &meta)?;
+
+            saved.torrents.push(TorrentEntry {
+                info_hash,
+                state: t.state(),
+                downloaded: stats.downloaded,
+                uploaded: stats.uploaded,
+                seed_time: stats.seed_time,
+                max_peers: t.max_peers(),
+                weight: t.weight(),
+                seed_limits: t.seed_limits(),
+                profile: t.profile().cloned(),
+            });
+        }
+        saved.save(dir)?;
+        if let Some(dht) = &self.dht {
+            dht.state().save(dir)?;
+        }
+
+        // Files of the torrents removed since the last save
+        let kept: HashSet<String> = saved
+            .torrents
+            .iter()
+            .map(|entry| decode_torrent::bytes_to_hash(&entry.info_hash))
+            .collect();
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            let ext = path.extension().and_then(|e| e.to_str());
+            let stem = path.file_stem().and_then(|s| s.to_str());
+            if let (Some(ext), Some(stem)) = (ext, stem) {
+                let ours = ext == TORRENT_EXTENSION || ext == RESUME_EXTENSION;
+                if ours && stem.len() == 40 && !kept.contains(stem) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> &SessionConfig {
+        &self.config
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Addresses peers are accepted on, one per family with `dual_stack`.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Port given to trackers and peers to reach us, the same on every
+    /// listening address, or the one the proxy listens on for us.
+    pub fn listen_port(&self) -> u16 {
+        match self.proxy_addr() {
+            Some(addr) => addr.port(),
+            None => self.local_addr().port(),
+        }
+    }
+
+    /// Address the proxy listens on for us, with `proxy_listen` and once the
+    /// proxy accepted to.
+    pub fn proxy_addr(&self) -> Option<SocketAddr> {
+        *self.proxy_bound.lock().unwrap()
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.config.peer_id
+    }
+
+    /// Subscribe to the events of every torrent of the session.
+    pub fn events(&self) -> Events {
+        Events::new(self.events.subscribe())
+    }
+
+    pub fn dialer(&self) -> &Dialer {
+        &self.dialer
+    }
+
+    /// Peers connected to any of the torrents.
+    pub fn connections(&self) -> usize {
+        self.connections.connections()
+    }
+
+    /// Shared by the torrents so that rechecks don't saturate the disk.
+    pub fn recheck_scheduler(&self) -> &RecheckScheduler {
+        &self.recheck
+    }
+
+    /// Torrent saved under its name in `config.save_path`, with the
+    /// session's storage configuration. Data already there is rechecked
+    /// through the session's `RecheckScheduler`. It still has to be added.
+    /// Torrents with a path escaping the save path are refused.
+    pub async fn open_torrent(&self, meta: MetaInfo, info_hash: InfoHash) -> Result<Torrent> {
+        let mut file = self.open_file(&meta.info)?;
+        self.check_file(&meta, &mut file).await?;
+        Ok(Torrent::with_file(meta, info_hash, file))
+    }
+
+    fn open_file(&self, info: &Info) -> Result<Storage> {
+        let dirs = self.config.storage_dirs();
+        Ok(Storage::open(info, dirs, self.config.storage.clone())?)
+    }
+
+    // Hash what was on disk before the storage was opened, waiting for a
+    // recheck slot
+    async fn check_file(&self, meta: &MetaInfo, file: &mut Storage) -> Result<()> {
+        if file.needs_recheck() {
+            let handle = self.recheck.handle(file.num_pieces());
+            file.recheck_with(&meta.piece_hashes()?, &handle).await?;
+        }
+        Ok(())
+    }
+
+    /// Host `torrent`, it then uses the session's peer ID and dialer. A
+    /// stopped torrent is queued, it starts once it gets a slot.
+    pub fn add_torrent(&self, mut torrent: Torrent) -> io::Result<SharedTorrent> {
+        let hash = *torrent.info_hash();
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Torrent already in the session",
+            ));
+        }
+
+        if torrent.profile().is_none() {
+            torrent.set_peer_id(self.config.peer_id);
+        }
+        torrent.set_dialer(self.dialer.clone());
+        torrent.set_connection_limit(self.connections.clone());
+        torrent.set_capture(self.capture.clone());
+        torrent.set_event_sender(self.events.clone());
+        torrent.set_tracker_bind(self.config.tracker_bind);
+        torrent.set_listen_port(self.listen_port());
+        torrent.set_max_peers(self.config.max_peers_per_torrent);
+        torrent.set_seed_limits(self.config.seed_limits);
+        #[cfg(feature = "geoip")]
+        torrent.set_geoip(self.config.geoip.clone());
+        if torrent.state() == TorrentState::Stopped {
+            torrent.queue();
+        }
+        // Trackerless torrents name nodes to bootstrap from
+        if let (Some(dht), Some(nodes)) = (&self.dht, &torrent.meta().nodes) {
+            for (host, port) in nodes.clone() {
+                let dht = dht.clone();
+                tokio::spawn(async move { dht.add_host(&host, port).await });
+            }
+        }
+        let torrent = Arc::new(Mutex::new(torrent));
+        torrents.insert(hash, torrent.clone());
+        self.queue.lock().unwrap().push(hash);
+
+        Ok(torrent)
+    }
+
+    /// Open and add the torrent of a `.torrent` file, see `open_torrent`.
+    pub async fn add_torrent_bytes(&self, bytes: &[u8]) -> Result<SharedTorrent> {
+        let meta = MetaInfo::from_bencode(bytes).map_err(MetaInfoError::from)?;
+        let torrent = self
+            .open_torrent(meta, decode_torrent::get_info_hash(bytes)?)
+            .await?;
+        Ok(self.add_torrent(torrent)?)
+    }
+
+    /// Stop the torrent and forget it, its files are left as they are.
+    pub async fn remove_torrent(&self, info_hash: &InfoHash) -> Option<SharedTorrent> {
+        let torrent = self.torrents.lock().unwrap().remove(info_hash)?;
+        self.queue.lock().unwrap().retain(|h| h != info_hash);
+        {
+            let mut t = torrent.lock().await;
+            t.stop().await;
+            *self.retired.lock().unwrap() += t.totals();
+        }
+        self.update_queue().await;
+        Some(torrent)
+    }
+
+    /// Fetch the metadata of a magnet link from its exact sources, or else
+    /// from the peers of its trackers, and add the torrent, connected to
+    /// those peers and started. Only the files the link selects are
+    /// downloaded.
+    pub async fn add_magnet(&self, uri: &str) -> Result<SharedTorrent> {
+        let magnet = Magnet::parse(uri)?;
+        let hash = magnet.info_hash;
+        if self.get(&hash).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Torrent already in the session",
+            )
+            .into());
+        }
+
+        let mut meta = None;
+        for source in &magnet.sources {
+            meta = self.fetch_torrent(source, &hash).await;
+            if meta.is_some() {
+                break;
+            }
+        }
+
+        let mut peers: Vec<(SocketAddr, PeerSource)> = magnet
+            .peers
+            .iter()
+            .map(|&addr| (addr, PeerSource::Manual))
+            .collect();
+        for tracker in &magnet.trackers {
+            // Other trackers may still answer
+            let res = torrent::announce(
+                tracker,
+                &hash,
+                &self.config.peer_id,
+                self.config.tracker_bind,
+                self.listen_port(),
+                AnnounceCounters::default(),
+                self.capture.as_ref(),
+            );
+            if let Ok(res) = res.await {
+                peers.extend(
+                    res.peers
+                        .into_iter()
+                        .map(|addr| (addr, PeerSource::Tracker)),
+                );
+            }
+        }
+
+        if let Some(dht) = &self.dht {
+            let found = dht.get_peers(hash).await;
+            peers.extend(found.into_iter().map(|addr| (addr, PeerSource::Dht)));
+        }
+
+        let mut info = None;
+        for &(addr, _) in peers.iter().filter(|_| meta.is_none()) {
+            if let Ok(Ok(metadata)) =
+                time::timeout(METADATA_TIMEOUT, self.fetch_metadata(addr, &hash)).await
+            {
+                info = Info::from_metadata(&metadata, &hash).ok();
+                if info.is_some() {
+                    break;
+                }
+            }
+        }
+        let meta = match meta {
+            Some(meta) => meta,
+            None => MetaInfo {
+                announce: magnet.trackers.first().cloned().unwrap_or_default(),
+                info: info.ok_or(Error::NoMetadata)?,
+                comment: None,
+                created_by: None,
+                creation_date: None,
+                http_seeds: None,
+                url_list: None,
+                nodes: None,
+                piece_layers: None,
+            },
+        };
+
+        let num_files = meta.info.files.as_ref().map_or(1, Vec::len);
+        let mut torrent = self.open_torrent(meta, hash).await?;
+        if let Some(files) = magnet.file_priorities(num_files) {
+            torrent.set_file_priorities(&files)?;
+        }
+        let torrent = self.add_torrent(torrent)?;
+        {
+            let mut t = torrent.lock().await;
+            for (addr, source) in peers {
+                // Peers without the torrent or unreachable are skipped
+                let _ = t.connect(addr, source).await;
+            }
+        }
+        self.update_queue().await;
+        Ok(torrent)
+    }
+
+    // The `.torrent` at `url`, if it is the one of `info_hash`. Only plain
+    // HTTP is spoken, HTTPS sources are skipped.
+    async fn fetch_torrent(&self, url: &str, info_hash: &InfoHash) -> Option<MetaInfo> {
+        let limit = 2 * metadata::MAX_METADATA_SIZE as usize;
+        let get = web_seed::get(&self.dialer, url, None, limit);
+        let (status, body) = time::timeout(METADATA_TIMEOUT, get).await.ok()?.ok()?;
+        if status != 200 {
+            return None;
+        }
+        let meta = MetaInfo::from_bencode(&body).ok()?;
+        (decode_torrent::get_info_hash(&body).ok()? == *info_hash).then_some(meta)
+    }
+
+    async fn fetch_metadata(
+        &self,
+        addr: SocketAddr,
+        info_hash: &InfoHash,
+    ) -> std::result::Result<Vec<u8>, PeerError> {
+        let mut hs = Handshake::default();
+        hs.set_hash(info_hash);
+        hs.set_peer_id(&self.config.peer_id);
+        let (mut stream, remote) = self.dialer.connect(addr).await?.handshake(hs).await?;
+        if let Some(capture) = &self.capture {
+            capture.peer_out(addr, &hs.to_bytes());
+            capture.peer_in(addr, &remote.to_bytes());
+        }
+        if remote.get_hash() != info_hash {
+            return Err(PeerError::WrongTorrent);
+        }
+        if !remote.supports_extension_protocol() {
+            return Err(PeerError::Metadata("Peer doesn't support extensions"));
+        }
+        let port = Some(self.listen_port());
+        metadata::fetch(&mut stream, info_hash, port, self.capture.as_ref()).await
+    }
+
+    /// Bytes transferred over every run of the session, see
+    /// `SessionConfig::state_dir`. Removed torrents still count.
+    pub async fn totals(&self) -> TransferTotals {
+        let torrents: Vec<SharedTorrent> =
+            self.torrents.lock().unwrap().values().cloned().collect();
+        let mut res = *self.retired.lock().unwrap();
+        for torrent in torrents {
+            res += torrent.lock().await.totals();
+        }
+        res
+    }
+
+    pub fn get(&self, info_hash: &InfoHash) -> Option<SharedTorrent> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    // The torrent must be in the session
+    fn known(&self, info_hash: &InfoHash) -> Result<SharedTorrent> {
+        self.get(info_hash)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unknown torrent").into())
+    }
+
+    /// Rename file `index` of a torrent, see `Torrent::rename_file`.
+    pub async fn rename_file<P: AsRef<Path>>(
+        &self,
+        info_hash: &InfoHash,
+        index: usize,
+        path: P,
+    ) -> Result<()> {
+        self.known(info_hash)?
+            .lock()
+            .await
+            .rename_file(index, path)
+            .await
+    }
+
+    /// Rename the directory, or the file, of a torrent.
+    pub async fn rename_root(&self, info_hash: &InfoHash, name: &str) -> Result<()> {
+        self.known(info_hash)?.lock().await.rename_root(name).await
+    }
+
+    /// Move the files of a torrent to `save_path`.
+    pub async fn move_storage<P: AsRef<Path>>(
+        &self,
+        info_hash: &InfoHash,
+        save_path: P,
+    ) -> Result<()> {
+        self.known(info_hash)?
+            .lock()
+            .await
+            .move_storage(save_path)
+            .await
+    }
+
+    /// Info hashes of the torrents, sorted.
+    pub fn list(&self) -> Vec<InfoHash> {
+        let mut res: Vec<InfoHash> = self.torrents.lock().unwrap().keys().copied().collect();
+        res.sort_unstable();
+        res
+    }
+
+    /// Info hashes by queue position.
+    pub fn queue(&self) -> Vec<InfoHash> {
+        self.queue.lock().unwrap().clone()
+    }
+
+    pub fn queue_position(&self, info_hash: &InfoHash) -> Option<usize> {
+        self.queue
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|h| h == info_hash)
+    }
+
+    /// Move the torrent to `position`, or last if it is past the end.
+    /// Returns `false` if the torrent isn't in the session.
+    pub fn set_queue_position(&self, info_hash: &InfoHash, position: usize) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let Some(current) = queue.iter().position(|h| h == info_hash) else {
+            return false;
+        };
+        let hash = queue.remove(current);
+        let position = position.min(queue.len());
+        queue.insert(position, hash);
+        true
+    }
+
+    /// Start the first queued torrents while there are free slots and queue
+    /// the running ones beyond the limits. Paused and stopped torrents are
+    /// left alone and don't take a slot.
+    pub async fn update_queue(&self) {
+        let queue: Vec<SharedTorrent> = {
+            let torrents = self.torrents.lock().unwrap();
+            let queue = self.queue.lock().unwrap();
+            queue
+                .iter()
+                .filter_map(|h| torrents.get(h).cloned())
+                .collect()
+        };
+
+        let (mut downloads, mut seeds) = (0, 0);
+        for torrent in queue {
+            let mut t = torrent.lock().await;
+            if !matches!(t.state(), TorrentState::Running | TorrentState::Queued) {
+                continue;
+            }
+            let (active, max) = match t.is_finished() {
+                true => (&mut seeds, self.config.max_active_seeds),
+                false => (&mut downloads, self.config.max_active_downloads),
+            };
+            if max.is_none_or(|max| *active < max) {
+                *active += 1;
+                t.start();
+            } else {
+                t.queue();
+            }
+        }
+    }
+
+    // Split the limits of the session between the running torrents, by
+    // weight and as much as each would use. The others have no use for any.
+    /// The DHT node of the session, if it runs one.
+    pub fn dht(&self) -> Option<&Dht> {
+        self.dht.as_deref()
+    }
+
+    // Refresh the routing table and announce the running torrents, connecting
+    // to the peers found. Lookups take a while, they don't hold the step.
+    async fn step_dht(&self, torrents: &[SharedTorrent]) {
+        let Some(dht) = &self.dht else {
+            return;
+        };
+        let now = Instant::now();
+        {
+            let mut last = self.dht_refresh.lock().unwrap();
+            if now.duration_since(*last) >= DHT_REFRESH_INTERVAL {
+                *last = now;
+                let dht = dht.clone();
+                tokio::spawn(async move { dht.refresh().await });
+            }
+        }
+
+        let port = self.listen_port();
+        let mut running = Vec::new();
+        for torrent in torrents {
+            let t = torrent.lock().await;
+            if t.state() == TorrentState::Running && t.allows_source(PeerSource::Dht) {
+                // Only the DHT knows the swarm of trackerless torrents
+                let trackerless = t.meta().announce.is_empty();
+                running.push((*t.info_hash(), torrent, t.is_finished(), trackerless));
+            }
+        }
+        // Torrents announced again once they run again
+        let mut announces = self.dht_announces.lock().unwrap();
+        announces.retain(|hash, _| running.iter().any(|(h, ..)| h == hash));
+
+        for (hash, torrent, seed, trackerless) in running {
+            let due = announces
+                .get(&hash)
+                .is_none_or(|at| now.duration_since(*at) >= DHT_ANNOUNCE_INTERVAL);
+            if !due {
+                continue;
+            }
+            announces.insert(hash, now);
+            let (dht, torrent) = (dht.clone(), torrent.clone());
+            tokio::spawn(async move {
+                let peers = dht.announce(hash, Some(port), seed).await;
+                let scrape = match trackerless {
+                    true => Some(dht.scrape(hash).await),
+                    false => None,
+                };
+                let mut t = torrent.lock().await;
+                if let Some(scrape) = scrape {
+                    t.set_swarm(scrape.seeds, scrape.leechers);
+                }
+                t.add_known_peers(peers.iter().copied(), PeerSource::Dht);
+                // Dialed in the background, the lock isn't held meanwhile
+                t.dial(peers, PeerSource::Dht);
+            });
+        }
+    }
+
+    async fn share_limits(&self, torrents: &[SharedTorrent]) {
+        let now = Instant::now();
+        let elapsed = {
+            let mut last = self.last_share.lock().unwrap();
+            now.saturating_duration_since(std::mem::replace(&mut *last, now))
+        };
+        let mut running = Vec::new();
+        // Peers of paused torrents stay connected, the rest goes to the others
+        let mut idle_peers = 0;
+        for torrent in torrents {
+            let t = torrent.lock().await;
+            match t.state() {
+                TorrentState::Running => running.push((torrent.clone(), t.weight(), t.demands())),
+                _ => idle_peers += t.peers().len(),
+            }
+        }
+
+        let demands = |wanted: fn(&Demands) -> u64| -> Vec<Demand> {
+            running
+                .iter()
+                .map(|(_, weight, demands)| Demand {
+                    weight: *weight,
+                    wanted: wanted(demands),
+                })
+                .collect()
+        };
+        let config = &self.config.fairness;
+        let bytes = |rate: u64| (rate as f64 * elapsed.as_secs_f64()) as u64;
+        let min = bytes(config.min_rate);
+        let upload =
+            fairness::share_limit(config.upload_rate.map(bytes), min, &demands(|d| d.upload));
+        let download = fairness::share_limit(
+            config.download_rate.map(bytes),
+            min,
+            &demands(|d| d.download),
+        );
+        // One more connection than known makes room for those connecting to us
+        let slots = |max: Option<usize>, wanted| {
+            fairness::share_limit(max.map(|max| max as u64), 1, &demands(wanted))
+        };
+        let upload_slots = slots(config.upload_slots, |d| d.upload_slots as u64);
+        let connections = slots(
+            config.connections.map(|max| max.saturating_sub(idle_peers)),
+            |d| d.connections as u64 + 1,
+        );
+
+        for (i, (torrent, ..)) in running.iter().enumerate() {
+            torrent.lock().await.set_shares(Shares {
+                upload: upload[i],
+                download: download[i],
+                upload_slots: upload_slots[i].map(|n| n as usize),
+                connections: connections[i].map(|n| n as usize),
+            });
+        }
+    }
+
+    /// Sync what the torrents wrote and save the state, see `save_state`.
+    /// `step` does it on its own according to `config.checkpoint`.
+    pub async fn checkpoint(&self) -> Result<()> {
+        if self.config.state_dir.is_none() {
+            return Ok(());
+        }
+        let torrents: Vec<SharedTorrent> =
+            self.torrents.lock().unwrap().values().cloned().collect();
+        for torrent in torrents {
+            let t = torrent.lock().await;
+            t.file().lock().await.sync().await?;
+        }
+        self.save_state().await?;
+
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.last = Instant::now();
+        checkpoint.pieces = 0;
+        checkpoint.completed = false;
+        Ok(())
+    }
+
+    /// Update the queue, share the limits between the running torrents and
+    /// step every torrent, see `Torrent::step`. The state is saved when a
+    /// checkpoint is due.
+    pub async fn step(&self) -> io::Result<()> {
+        self.update_queue().await;
+        let torrents: Vec<SharedTorrent> =
+            self.torrents.lock().unwrap().values().cloned().collect();
+        self.share_limits(&torrents).await;
+        for torrent in &torrents {
+            torrent.lock().await.step().await?;
+        }
+        self.step_dht(&torrents).await;
+
+        let due = self.config.state_dir.is_some()
+            && self
+                .checkpoint
+                .lock()
+                .unwrap()
+                .is_due(&self.config.checkpoint, Instant::now());
+        if due {
+            self.checkpoint().await.map_err(into_io)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::*;
+    use crate::decode_torrent::{self, Info};
+    use crate::events::Event;
+    use crate::extension::{ExtensionHandshake, EXTENDED_MSG_ID};
+    use crate::metadata::{MetadataMessage, UT_METADATA, UT_METADATA_ID};
+    use crate::profile::ClientProfile;
+    use sha1::{Digest, Sha1};
+    use std::{fs, path::Path};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    fn meta(name: &str) -> MetaInfo {
+        MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: name.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_through_proxy() {
+        const FILE: &str = "test_session_accept_through_proxy";
+        let proxy = ProxyConfig {
+            addr: proxy::proxy_tests::fake_proxy(None).await,
+            auth: None,
+        };
+        assert!(SessionConfig::builder().proxy_listen(true).build().is_err());
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .proxy(proxy)
+            .proxy_listen(true)
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let torrent = session
+            .add_torrent(session.open_torrent(meta(FILE), [1; 20]).await.unwrap())
+            .unwrap();
+        let mut bound = None;
+        for _ in 0..50 {
+            bound = session.proxy_addr();
+            if bound.is_some() {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let bound = bound.unwrap();
+        assert_eq!(bound.port(), session.listen_port());
+        assert_ne!(session.local_addr().port(), session.listen_port());
+
+        // The peer is known by its own address, not the proxy's
+        let mut stream = TcpStream::connect(bound).await.unwrap();
+        let mut hs = Handshake::default();
+        hs.set_hash(&[1; 20]);
+        let remote = hs.send(&mut stream).await.unwrap();
+        assert_eq!(session.peer_id(), remote.get_peer_id());
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            vec![stream.local_addr().unwrap()],
+            torrent.lock().await.peers()
+        );
+        fs::remove_file(FILE).ok();
+    }
+
+    #[tokio::test]
+    async fn dispatch_by_info_hash() {
+        const FILE: &str = "test_session_dispatch_by_info_hash";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .peer_id(*b"-RS0001-SESSION_TEST")
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let torrent = session
+            .add_torrent(session.open_torrent(meta(FILE), [1; 20]).await.unwrap())
+            .unwrap();
+        let again = session.open_torrent(meta(FILE), [1; 20]).await.unwrap();
+        assert!(session.add_torrent(again).is_err());
+        assert_eq!(vec![[1; 20]], session.list());
+
+        // Known torrent, we get the session's peer ID back and join it
+        let mut events = session.events();
+        let mut stream = TcpStream::connect(session.local_addr()).await.unwrap();
+        let mut hs = Handshake::default();
+        hs.set_hash(&[1; 20]);
+        let remote = hs.send(&mut stream).await.unwrap();
+        assert_eq!(&[1; 20], remote.get_hash());
+        assert_eq!(session.peer_id(), remote.get_peer_id());
+        let event = time::timeout(Duration::from_secs(1), events.recv()).await;
+        let addr = match event.unwrap() {
+            Some(Event::PeerConnected {
+                info_hash, addr, ..
+            }) if info_hash == [1; 20] => addr,
+            e => panic!("Unexpected event {:?}", e),
+        };
+        assert_eq!(vec![addr], torrent.lock().await.peers());
+
+        drop(stream);
+        time::sleep(Duration::from_millis(300)).await;
+        session.step().await.unwrap();
+        assert_eq!(
+            Some(Event::PeerDisconnected {
+                info_hash: [1; 20],
+                addr
+            }),
+            events.try_recv()
+        );
+
+        // Unknown torrent, the connection is dropped
+        let mut other = TcpStream::connect(session.local_addr()).await.unwrap();
+        let mut hs = Handshake::default();
+        hs.set_hash(&[2; 20]);
+        let mut buf = Vec::new();
+        hs.reply(&mut other).await.unwrap();
+        assert_eq!(0, other.read_to_end(&mut buf).await.unwrap());
+
+        assert!(session.remove_torrent(&[1; 20]).await.is_some());
+        assert!(session.list().is_empty());
+        assert!(torrent.lock().await.peers().is_empty());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn storage_paths() {
+        use crate::decode_torrent::FileInfo;
+
+        const DIR: &str = "test_session_storage_paths";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(Path::new(DIR).join("done"))
+            .incomplete_path(Path::new(DIR).join("incomplete"))
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+
+        // Names come from untrusted torrents
+        for name in ["../escape", "/tmp/escape", "", "a/../.."] {
+            assert!(session.open_torrent(meta(name), [1; 20]).await.is_err());
+        }
+        let multi = |b: &[&str]| {
+            let file = |length: u64, path: &[&str]| FileInfo {
+                length,
+                path: path.iter().map(|p| p.to_string()).collect(),
+                md5sum: None,
+                attr: None,
+                symlink_path: None,
+                sha1: None,
+            };
+            let mut res = meta("t");
+            res.info.files = Some(vec![file(10000, &["a"]), file(6384, b)]);
+            res
+        };
+        assert!(session
+            .open_torrent(multi(&["..", "b"]), [1; 20])
+            .await
+            .is_err());
+
+        let torrent = session
+            .open_torrent(multi(&["sub", "b"]), [1; 20])
+            .await
+            .unwrap();
+        match &*torrent.file().lock().await {
+            Storage::Multi(storage) => {
+                let incomplete = Path::new(DIR).join("incomplete/t");
+                assert_eq!(incomplete.join("a"), storage.file_path(0));
+                assert_eq!(incomplete.join("sub/b"), storage.file_path(1));
+                assert_eq!(Path::new(DIR).join("done/t/sub/b"), storage.final_path(1));
+            }
+            Storage::Single(_) => panic!("Multi-file torrent in a single file"),
+        }
+
+        drop(torrent);
+        let _ = fs::remove_dir_all(DIR);
+    }
+
+    #[tokio::test]
+    async fn torrent_profile() {
+        const FILE: &str = "test_session_torrent_profile";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let mut torrent = session.open_torrent(meta(FILE), [1; 20]).await.unwrap();
+        torrent.set_profile(ClientProfile::named("transmission").unwrap());
+        let peer_id = *torrent.peer_id();
+        session.add_torrent(torrent).unwrap();
+
+        let mut stream = TcpStream::connect(session.local_addr()).await.unwrap();
+        let mut hs = Handshake::default();
+        hs.set_hash(&[1; 20]);
+        let remote = hs.send(&mut stream).await.unwrap();
+        assert_eq!(&peer_id, remote.get_peer_id());
+        assert_eq!(b"-TR4060-", &peer_id[..8]);
+
+        let ext = loop {
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut msg).await.unwrap();
+            if msg.starts_with(&[EXTENDED_MSG_ID, 0]) {
+                break ExtensionHandshake::from_bencode(&msg[2..]).unwrap();
+            }
+        };
+        assert_eq!(Some("Transmission 4.0.6"), ext.v.as_deref());
+        assert_eq!(Some(session.listen_port()), ext.p);
+
+        session.remove_torrent(&[1; 20]).await.unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+
+    // A peer of the torrent which only has its metadata
+    async fn metadata_peer(mut stream: TcpStream, metadata: Vec<u8>) {
+        let remote = Handshake::receive(&mut stream).await.unwrap();
+        let mut hs = Handshake::default();
+        hs.set_hash(remote.get_hash());
+        hs.reply(&mut stream).await.unwrap();
+
+        let mut ext = ExtensionHandshake::default();
+        ext.m.insert(UT_METADATA.to_string(), 7);
+        ext.metadata_size = Some(metadata.len() as u64);
+        stream.write_all(&ext.to_message().unwrap()).await.unwrap();
+        loop {
+            let mut len = [0u8; 4];
+            if stream.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut msg).await.unwrap();
+            if msg.len() > 2 && msg[..2] == [EXTENDED_MSG_ID, 7] {
+                let data = MetadataMessage::Data {
+                    piece: 0,
+                    total_size: metadata.len() as u64,
+                    payload: metadata.clone(),
+                };
+                let msg = data.to_message(UT_METADATA_ID).unwrap();
+                stream.write_all(&msg).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn add_magnet() {
+        const FILE: &str = "test_session_add_magnet";
+        let mut metadata = format!("d6:lengthi16384e4:name{}:{}", FILE.len(), FILE).into_bytes();
+        metadata.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+        metadata.extend_from_slice(&[0u8; 20]);
+        metadata.push(b'e');
+        let info_hash: InfoHash = Sha1::digest(&metadata).into();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(metadata_peer(stream, metadata.clone()));
+            }
+        });
+
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            decode_torrent::bytes_to_hash(&info_hash),
+            peer
+        );
+        let torrent = session.add_magnet(&uri).await.unwrap();
+        assert_eq!(vec![info_hash], session.list());
+        {
+            let torrent = torrent.lock().await;
+            assert_eq!(FILE, torrent.meta().info.name);
+            assert_eq!(vec![peer], torrent.peers());
+        }
+        assert!(session.add_magnet(&uri).await.is_err());
+
+        session.remove_torrent(&info_hash).await.unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn add_magnet_from_source() {
+        const FILE: &str = "test_session_add_magnet_from_source";
+        let torrent = |name: &str| {
+            let mut torrent = format!("d8:announce0:4:infod6:lengthi16384e4:name{}:", name.len());
+            torrent.push_str(name);
+            let mut torrent = torrent.into_bytes();
+            torrent.extend_from_slice(b"12:piece lengthi16384e6:pieces20:");
+            torrent.extend_from_slice(&[0u8; 20]);
+            torrent.extend_from_slice(b"ee");
+            torrent
+        };
+        let right = torrent(FILE);
+        let info_hash = decode_torrent::get_info_hash(&right).unwrap();
+
+        // An HTTP server with another torrent at /wrong
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let body = match head.starts_with(b"GET /wrong ") {
+                    true => torrent("other"),
+                    false => right.clone(),
+                };
+                let reply = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                stream.write_all(reply.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&xs=http://{}/wrong&as=http://{}/right",
+            decode_torrent::bytes_to_hash(&info_hash),
+            server,
+            server
+        );
+        let torrent = session.add_magnet(&uri).await.unwrap();
+        assert_eq!(FILE, torrent.lock().await.meta().info.name);
+
+        session.remove_torrent(&info_hash).await.unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        // Missing settings take their default
+        let json = r#"{"max_peers_per_torrent": 30, "dht": {"read_only": true}}"#;
+        let config: SessionConfig = serde_json::from_str(json).unwrap();
+        assert_eq!(Some(30), config.max_peers_per_torrent);
+        let dht = config.dht.unwrap();
+        assert!(dht.read_only);
+        assert_eq!(DhtConfig::default().bootstrap, dht.bootstrap);
+        assert_eq!(SessionConfig::default().listen_addr, config.listen_addr);
+
+        let json = serde_json::to_string(&SessionConfig::default()).unwrap();
+        let config: SessionConfig = serde_json::from_str(&json).unwrap();
+        assert_eq!(SessionConfig::default().seed_limits, config.seed_limits);
+
+        let event = Event::TrackerError {
+            info_hash: [1; 20],
+            message: "Timed out".to_string(),
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(event, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn config_builder() {
+        let config = SessionConfig::builder()
+            .listen_port(0)
+            .peer_id_prefix("-XX0100-")
+            .max_peers_per_torrent(30)
+            .recheck_rate_limit(Some(1 << 20))
+            .save_path("/tmp")
+            .build()
+            .unwrap();
+        assert_eq!(0, config.listen_addr.port());
+        assert!(config.dual_stack);
+        assert_eq!(b"-XX0100-", &config.peer_id[..8]);
+        assert!(config.peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(Some(30), config.max_peers_per_torrent);
+        assert_eq!(Some(1 << 20), config.recheck.bytes_per_sec);
+        assert_eq!(PathBuf::from("/tmp"), config.save_path);
+
+        let builder = SessionConfig::builder();
+        assert!(builder
+            .clone()
+            .peer_id_prefix(&"x".repeat(21))
+            .build()
+            .is_err());
+        assert!(builder.clone().max_half_open(0).build().is_err());
+        assert!(builder.clone().max_peers_per_torrent(0).build().is_err());
+        assert!(builder.clone().max_connections(0).build().is_err());
+        assert!(builder.clone().upload_rate_limit(Some(0)).build().is_err());
+        let err = builder.clone().encryption(true).build().unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+        assert!(builder.clone().utp(true).build().is_err());
+        assert!(builder.listen_port_range(0..=9).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn fair_shares_by_weight() {
+        const FILES: [&str; 2] = ["test_session_fair_shares_1", "test_session_fair_shares_2"];
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .max_connections(9)
+            .upload_rate_limit(Some(1 << 20))
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        for (i, file) in FILES.iter().enumerate() {
+            let mut torrent = session
+                .open_torrent(meta(file), [i as u8; 20])
+                .await
+                .unwrap();
+            torrent.set_weight(i as u32 + 1);
+            // More peers than both can connect to
+            let peers = (1..=20).map(|port| SocketAddr::from(([10, 0, 0, 1], port)));
+            torrent.add_known_peers(peers, PeerSource::Tracker);
+            session.add_torrent(torrent).unwrap();
+        }
+        session.step().await.unwrap();
+
+        let mut shares = Vec::new();
+        for i in 0..2 {
+            shares.push(session.get(&[i; 20]).unwrap().lock().await.shares());
+        }
+        let (light, heavy) = (shares[0], shares[1]);
+        // One each first, the rest by weight
+        assert_eq!((Some(3), Some(6)), (light.connections, heavy.connections));
+        assert!(light.upload.is_some());
+        assert_eq!((None, None), (light.download, light.upload_slots));
+
+        for (i, file) in FILES.iter().enumerate() {
+            session.remove_torrent(&[i as u8; 20]).await.unwrap();
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_to_dht() {
+        const FILE: &str = "test_session_announce_to_dht";
+        let local_dht = || DhtConfig {
+            bind: "127.0.0.1:0".parse().unwrap(),
+            bootstrap: Vec::new(),
+            query_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        // The node of the other session stores the announce
+        let other = Dht::bind(local_dht()).await.unwrap();
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .dht(local_dht())
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let dht = session.dht().unwrap();
+        assert_eq!(session.listen_port(), dht.local_addr().unwrap().port());
+        other.add_node(dht.local_addr().unwrap()).await.unwrap();
+
+        let torrent = session.open_torrent(meta(FILE), [3; 20]).await.unwrap();
+        let torrent = session.add_torrent(torrent).unwrap();
+        session.step().await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+        // Without a tracker the swarm is scraped from the DHT
+        let stats = torrent.lock().await.stats();
+        assert_eq!((Some(0), Some(1)), (stats.seeds, stats.leechers));
+
+        // Found by others through that node
+        let client = Dht::bind(local_dht()).await.unwrap();
+        client.add_node(other.local_addr().unwrap()).await.unwrap();
+        assert_eq!(vec![session.local_addr()], client.get_peers([3; 20]).await);
+
+        session.remove_torrent(&[3; 20]).await.unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn global_connection_limit() {
+        const FILES: [&str; 2] = [
+            "test_session_connection_limit_1",
+            "test_session_connection_limit_2",
+        ];
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .max_connections(1)
+            .build()
+            .unwrap();
+        let session = &Session::new(config).await.unwrap();
+        for (i, file) in FILES.iter().enumerate() {
+            let torrent = session
+                .open_torrent(meta(file), [i as u8; 20])
+                .await
+                .unwrap();
+            session.add_torrent(torrent).unwrap();
+        }
+        let connect = |hash: u8| {
+            let addr = session.local_addr();
+            async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                let mut hs = Handshake::default();
+                hs.set_hash(&[hash; 20]);
+                hs.send(&mut stream).await.unwrap();
+                stream
+            }
+        };
+        let connections = |expected: usize| async move {
+            for _ in 0..50 {
+                if session.connections() == expected {
+                    return true;
+                }
+                time::sleep(Duration::from_millis(20)).await;
+            }
+            false
+        };
+
+        let _first = connect(0).await;
+        assert!(connections(1).await);
+        // The other torrent has no room left either
+        let mut second = connect(1).await;
+        let mut buf = [0; 1];
+        let read = time::timeout(Duration::from_secs(1), second.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        assert_eq!(1, session.connections());
+
+        // A stopped torrent leaves its room to the others
+        session.get(&[0; 20]).unwrap().lock().await.stop().await;
+        assert!(connections(0).await);
+        let _second = connect(1).await;
+        assert!(connections(1).await);
+        assert_eq!(1, session.get(&[1; 20]).unwrap().lock().await.peers().len());
+
+        for (i, file) in FILES.iter().enumerate() {
+            session.remove_torrent(&[i as u8; 20]).await.unwrap();
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn checkpoints() {
+        const DIR: &str = "./test_session_checkpoints";
+        const FILE: &str = "test_session_checkpoints_file";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .state_dir(DIR)
+            .checkpoint(CheckpointConfig {
+                interval: None,
+                pieces: Some(2),
+                on_completed: true,
+            })
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let torrent = session.open_torrent(meta(FILE), [1; 20]).await.unwrap();
+        session.add_torrent(torrent).unwrap();
+        let state = Path::new(DIR).join(state::STATE_FILE);
+
+        let verified = Event::PieceVerified {
+            info_hash: [1; 20],
+            index: 0,
+        };
+        session.events.send(verified.clone()).unwrap();
+        session.step().await.unwrap();
+        assert!(!state.exists());
+        session.events.send(verified).unwrap();
+        session.step().await.unwrap();
+        assert!(state.exists());
+
+        // Counted again from the last save
+        fs::remove_file(&state).unwrap();
+        session.step().await.unwrap();
+        assert!(!state.exists());
+        let info_hash = [1; 20];
+        session
+            .events
+            .send(Event::TorrentCompleted { info_hash })
+            .unwrap();
+        session.step().await.unwrap();
+        assert!(state.exists());
+
+        session.remove_torrent(&info_hash).await.unwrap();
+        fs::remove_dir_all(DIR).unwrap();
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn random_listen_port() {
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .random_listen_port()
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        assert!(RANDOM_LISTEN_PORTS.contains(&session.listen_port()));
+        assert_eq!(session.listen_port(), session.local_addr().port());
+    }
+
+    #[tokio::test]
+    async fn queue() {
+        const FILES: [&str; 3] = [
+            "test_session_queue_0",
+            "test_session_queue_1",
+            "test_session_queue_2",
+        ];
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .max_active_downloads(1)
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let mut torrents = Vec::new();
+        for (i, file) in FILES.iter().enumerate() {
+            let torrent = session
+                .open_torrent(meta(file), [i as u8; 20])
+                .await
+                .unwrap();
+            torrents.push(session.add_torrent(torrent).unwrap());
+        }
+        let states = || async {
+            let mut res = Vec::new();
+            for torrent in &torrents {
+                res.push(torrent.lock().await.state());
+            }
+            res
+        };
+        use TorrentState::*;
+
+        session.update_queue().await;
+        assert_eq!(vec![Running, Queued, Queued], states().await);
+
+        // The last one jumps ahead
+        assert!(session.set_queue_position(&[2; 20], 0));
+        assert_eq!(vec![[2; 20], [0; 20], [1; 20]], session.queue());
+        session.update_queue().await;
+        assert_eq!(vec![Queued, Queued, Running], states().await);
+
+        // A paused torrent gives its slot away
+        torrents[2].lock().await.pause();
+        session.update_queue().await;
+        assert_eq!(vec![Running, Queued, Paused], states().await);
+
+        session.remove_torrent(&[0; 20]).await.unwrap();
+        assert_eq!(Running, torrents[1].lock().await.state());
+        assert_eq!(Some(1), session.queue_position(&[1; 20]));
+
+        for file in FILES {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn recheck_existing_data() {
+        const DIR: &str = "./test_session_recheck_existing_data";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .build()
+            .unwrap();
+        let session = Arc::new(Session::new(config).await.unwrap());
+        let data = vec![7u8; 16384];
+        let mut meta = meta("data");
+        meta.info.pieces = vec![decode_torrent::bytes_to_hash(&Sha1::digest(&data).into())];
+        fs::create_dir_all(DIR).unwrap();
+        fs::write(Path::new(DIR).join("data"), &data).unwrap();
+
+        // Waits for the recheck slot taken by another torrent
+        let other = session.recheck_scheduler().handle(1);
+        let slot = other.start().await;
+        let opening = {
+            let session = session.clone();
+            tokio::spawn(async move { session.open_torrent(meta, [1; 20]).await })
+        };
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!opening.is_finished());
+        drop(slot);
+        let torrent = opening.await.unwrap().unwrap();
+        assert!(torrent.is_finished());
+
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn move_and_rename() {
+        const DIR: &str = "./test_session_move_and_rename";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .build()
+            .unwrap();
+        let session = Session::new(config).await.unwrap();
+        let data = vec![7u8; 16384];
+        let mut meta = meta("data");
+        meta.info.pieces = vec![decode_torrent::bytes_to_hash(&Sha1::digest(&data).into())];
+        fs::create_dir_all(DIR).unwrap();
+        fs::write(Path::new(DIR).join("data"), &data).unwrap();
+        let torrent = session.open_torrent(meta, [1; 20]).await.unwrap();
+        session.add_torrent(torrent).unwrap();
+
+        session.rename_file(&[1; 20], 0, "renamed").await.unwrap();
+        session
+            .move_storage(&[1; 20], Path::new(DIR).join("moved"))
+            .await
+            .unwrap();
+        assert_eq!(
+            data,
+            fs::read(Path::new(DIR).join("moved/renamed")).unwrap()
+        );
+        assert!(session.rename_root(&[2; 20], "other").await.is_err());
+
+        drop(session);
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restore_without_resume_data() {
+        const DIR: &str = "./test_session_restore_without_resume_data";
+        let state_dir = Path::new(DIR).join("state");
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .state_dir(&state_dir)
+            .build()
+            .unwrap();
+        let data = vec![7u8; 16384];
+        let mut meta = meta("data");
+        meta.info.pieces = vec![decode_torrent::bytes_to_hash(&Sha1::digest(&data).into())];
+        let hash = decode_torrent::get_info_hash(&meta.to_bencode().unwrap()).unwrap();
+        fs::create_dir_all(DIR).unwrap();
+
+        let session = Session::new(config.clone()).await.unwrap();
+        let torrent = session.open_torrent(meta, hash).await.unwrap();
+        assert!(!torrent.is_finished());
+        session.add_torrent(torrent).unwrap();
+        session.save_state().await.unwrap();
+        drop(session);
+
+        // The data was put there meanwhile and the resume data lost
+        fs::write(Path::new(DIR).join("data"), &data).unwrap();
+        fs::remove_file(state::resume_path(&state_dir, &hash)).unwrap();
+        let session = Session::new(config).await.unwrap();
+        let torrent = session.get(&hash).unwrap();
+        assert!(torrent.lock().await.is_finished());
+
+        drop(session);
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_and_restore_state() {
+        const DIR: &str = "./test_session_save_and_restore_state";
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .state_dir(Path::new(DIR).join("state"))
+            .build()
+            .unwrap();
+        fs::create_dir_all(DIR).unwrap();
+
+        let mut hashes = Vec::new();
+        let session = Session::new(config.clone()).await.unwrap();
+        for name in ["first", "second", "third"] {
+            let meta = meta(name);
+            let hash = decode_torrent::get_info_hash(&meta.to_bencode().unwrap()).unwrap();
+            let torrent = session.add_torrent(session.open_torrent(meta, hash).await.unwrap());
+            torrent
+                .unwrap()
+                .lock()
+                .await
+                .set_max_peers(Some(hashes.len() + 1));
+            hashes.push(hash);
+        }
+        session.update_queue().await;
+        session.set_queue_position(&hashes[2], 0);
+        {
+            let torrent = session.get(&hashes[1]).unwrap();
+            let mut t = torrent.lock().await;
+            t.pause();
+            t.restore_totals(100, 250, Duration::from_secs(30));
+        }
+        session.save_state().await.unwrap();
+        // A torrent removed afterwards leaves nothing behind but its totals
+        let removed = session.get(&hashes[0]).unwrap();
+        removed
+            .lock()
+            .await
+            .restore_totals(1000, 10, Duration::ZERO);
+        session.remove_torrent(&hashes[0]).await.unwrap();
+        session.save_state().await.unwrap();
+        drop(session);
+        assert!(!state::torrent_path(Path::new(DIR).join("state"), &hashes[0]).exists());
+
+        let session = Session::new(config).await.unwrap();
+        assert_eq!(vec![hashes[2], hashes[1]], session.queue());
+        let totals = session.totals().await;
+        assert_eq!((1100, 260), (totals.downloaded, totals.uploaded));
+        let torrent = session.get(&hashes[1]).unwrap();
+        let t = torrent.lock().await;
+        assert_eq!(TorrentState::Paused, t.state());
+        assert_eq!(Some(2), t.max_peers());
+        assert_eq!(100, t.stats().downloaded);
+        assert_eq!(250, t.stats().uploaded);
+        assert_eq!(Duration::from_secs(30), t.seed_time());
+        assert_eq!(
+            TorrentState::Running,
+            session.get(&hashes[2]).unwrap().lock().await.state()
+        );
+
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
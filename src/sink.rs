@@ -0,0 +1,112 @@
+// Verified pieces handed to the application as they complete, e.g. to pipe a
+// download into a player or to process data without keeping it on disk.
+use std::{collections::BTreeMap, io};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedPiece {
+    pub index: usize,
+    pub data: Bytes,
+}
+
+/// Where verified pieces are delivered. A full channel holds the download
+/// back until the receiver catches up.
+#[derive(Debug, Clone)]
+pub struct PieceSink {
+    sender: mpsc::Sender<VerifiedPiece>,
+    // Also write the pieces to the file, so that they can be seeded
+    keep_on_disk: bool,
+}
+
+impl PieceSink {
+    /// Sink delivering pieces in addition to writing them to disk, along
+    /// with the receiving end holding at most `capacity` pieces.
+    pub fn channel(capacity: usize) -> (Self, mpsc::Receiver<VerifiedPiece>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let sink = PieceSink {
+            sender,
+            keep_on_disk: true,
+        };
+        (sink, receiver)
+    }
+
+    /// Deliver pieces instead of writing them. They can't be served to other
+    /// peers then.
+    pub fn without_disk(mut self) -> Self {
+        self.keep_on_disk = false;
+        self
+    }
+
+    pub fn keeps_on_disk(&self) -> bool {
+        self.keep_on_disk
+    }
+
+    pub async fn deliver(&self, index: usize, data: Bytes) -> io::Result<()> {
+        self.sender
+            .send(VerifiedPiece { index, data })
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "piece sink closed"))
+    }
+}
+
+/// Write the pieces received on `receiver` to `out` in index order, starting
+/// at `first`. Pieces arriving early are kept in memory until the ones before
+/// them are written. Returns the number of pieces written once the channel
+/// closes.
+pub async fn write_in_order<W>(
+    mut receiver: mpsc::Receiver<VerifiedPiece>,
+    mut out: W,
+    first: usize,
+) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut next = first;
+    let mut early = BTreeMap::new();
+    while let Some(piece) = receiver.recv().await {
+        if piece.index < next {
+            continue;
+        }
+        early.insert(piece.index, piece.data);
+        while let Some(data) = early.remove(&next) {
+            out.write_all(&data).await?;
+            next += 1;
+        }
+    }
+    out.flush().await?;
+
+    Ok(next - first)
+}
+
+#[cfg(test)]
+mod sink_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ordered_output() {
+        let (sink, receiver) = PieceSink::channel(4);
+        assert!(sink.keeps_on_disk());
+        assert!(!sink.clone().without_disk().keeps_on_disk());
+
+        for index in [2, 0, 1, 4] {
+            let data = Bytes::from(vec![index as u8; 2]);
+            sink.deliver(index, data).await.unwrap();
+        }
+        drop(sink);
+
+        // Piece 4 never gets its turn without piece 3
+        let mut out = Vec::new();
+        assert_eq!(3, write_in_order(receiver, &mut out, 0).await.unwrap());
+        assert_eq!(vec![0, 0, 1, 1, 2, 2], out);
+
+        let (sink, receiver) = PieceSink::channel(1);
+        drop(receiver);
+        let err = sink.deliver(0, Bytes::new()).await.unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+    }
+}
@@ -0,0 +1,342 @@
+// State of a session kept across restarts: the torrents in queue order with
+// their settings, next to the metainfo and resume data of each one in the
+// same directory.
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bendy::{
+    decoding::{Error, FromBencode, Object, ResultExt},
+    encoding::{AsString, SingleItemEncoder, ToBencode},
+};
+
+use crate::decode_torrent::bytes_to_hash;
+use crate::definitions::InfoHash;
+use crate::fairness::DEFAULT_WEIGHT;
+use crate::profile::ClientProfile;
+use crate::resume::RESUME_EXTENSION;
+use crate::stats::TransferTotals;
+use crate::torrent::{SeedLimitAction, SeedLimits, TorrentState};
+
+pub const STATE_FILE: &str = "session.state";
+pub const TORRENT_EXTENSION: &str = "torrent";
+
+/// A torrent of the session, its content is found from its metainfo file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentEntry {
+    pub info_hash: InfoHash,
+    pub state: TorrentState,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub seed_time: Duration,
+    pub max_peers: Option<usize>,
+    pub weight: u32,
+    pub seed_limits: SeedLimits,
+    pub profile: Option<ClientProfile>,
+}
+
+/// Torrents by queue position.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionState {
+    pub torrents: Vec<TorrentEntry>,
+    // Transfer of every run of the session, removed torrents included
+    pub totals: TransferTotals,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Replace the file at `path` with `bytes`, through a synced temporary file
+/// so that it is either the previous content or the new one after a crash.
+pub fn write_atomic<P: AsRef<Path>>(path: P, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_ref().as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Path of the metainfo of a torrent in the state directory `dir`.
+pub fn torrent_path<P: AsRef<Path>>(dir: P, info_hash: &InfoHash) -> PathBuf {
+    dir.as_ref().join(format!(
+        "{}.{}",
+        bytes_to_hash(info_hash),
+        TORRENT_EXTENSION
+    ))
+}
+
+/// Path of the resume data of a torrent in the state directory `dir`.
+pub fn resume_path<P: AsRef<Path>>(dir: P, info_hash: &InfoHash) -> PathBuf {
+    dir.as_ref()
+        .join(format!("{}.{}", bytes_to_hash(info_hash), RESUME_EXTENSION))
+}
+
+impl SessionState {
+    /// Read the state saved in `dir`, `None` if there is none yet.
+    pub fn load<P: AsRef<Path>>(dir: P) -> io::Result<Option<Self>> {
+        match fs::read(dir.as_ref().join(STATE_FILE)) {
+            Ok(bytes) => SessionState::from_bencode(&bytes)
+                .map(Some)
+                .map_err(invalid_data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let bytes = self.to_bencode().map_err(invalid_data)?;
+        write_atomic(dir.as_ref().join(STATE_FILE), &bytes)
+    }
+}
+
+fn state_name(state: TorrentState) -> &'static str {
+    match state {
+        TorrentState::Stopped => "stopped",
+        TorrentState::Running => "running",
+        TorrentState::Paused => "paused",
+        TorrentState::Queued => "queued",
+    }
+}
+
+fn parse_state(name: &[u8]) -> Option<TorrentState> {
+    match name {
+        b"stopped" => Some(TorrentState::Stopped),
+        b"running" => Some(TorrentState::Running),
+        b"paused" => Some(TorrentState::Paused),
+        b"queued" => Some(TorrentState::Queued),
+        _ => None,
+    }
+}
+
+// Bencode has no floats, ratios are saved in thousandths
+const RATIO_SCALE: f64 = 1000.0;
+
+impl ToBencode for TorrentEntry {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        let limits = &self.seed_limits;
+        encoder.emit_dict(|mut e| {
+            if let Some(profile) = &self.profile {
+                e.emit_pair(b"client_version", &profile.version)?;
+            }
+            e.emit_pair(b"downloaded", self.downloaded)?;
+            e.emit_pair(b"info_hash", AsString(&self.info_hash))?;
+            if let Some(max) = self.max_peers {
+                e.emit_pair(b"max_peers", max)?;
+            }
+            if let Some(profile) = &self.profile {
+                e.emit_pair(b"peer_id_prefix", &profile.peer_id_prefix)?;
+            }
+            let action = match limits.action {
+                SeedLimitAction::Pause => "pause",
+                SeedLimitAction::Stop => "stop",
+            };
+            e.emit_pair(b"seed_action", action)?;
+            if let Some(ratio) = limits.ratio {
+                e.emit_pair(b"seed_ratio", (ratio * RATIO_SCALE).round() as u64)?;
+            }
+            e.emit_pair(b"seed_time", self.seed_time.as_secs())?;
+            if let Some(time) = limits.time {
+                e.emit_pair(b"seed_time_limit", time.as_secs())?;
+            }
+            e.emit_pair(b"state", state_name(self.state))?;
+            e.emit_pair(b"uploaded", self.uploaded)?;
+            e.emit_pair(b"weight", self.weight)
+        })
+    }
+}
+
+impl ToBencode for SessionState {
+    const MAX_DEPTH: usize = TorrentEntry::MAX_DEPTH + 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), bendy::encoding::Error> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"downloaded", self.totals.downloaded)?;
+            e.emit_pair(b"torrents", &self.torrents)?;
+            e.emit_pair(b"uploaded", self.totals.uploaded)
+        })
+    }
+}
+
+impl FromBencode for TorrentEntry {
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut info_hash = None;
+        let mut state = None;
+        let mut version = None;
+        let mut peer_id_prefix = None;
+        let mut res = TorrentEntry {
+            info_hash: InfoHash::default(),
+            state: TorrentState::Queued,
+            downloaded: 0,
+            uploaded: 0,
+            seed_time: Duration::ZERO,
+            max_peers: None,
+            weight: DEFAULT_WEIGHT,
+            seed_limits: SeedLimits::default(),
+            profile: None,
+        };
+
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"client_version", value) => {
+                    version = Some(String::decode_bencode_object(value).context("client_version")?);
+                }
+                (b"downloaded", value) => {
+                    res.downloaded = u64::decode_bencode_object(value).context("downloaded")?;
+                }
+                (b"info_hash", value) => {
+                    let bytes = value.try_into_bytes().context("info_hash")?;
+                    info_hash = Some(InfoHash::try_from(bytes).map_err(|_| {
+                        Error::malformed_content(invalid_data("Invalid info hash"))
+                            .context("info_hash")
+                    })?);
+                }
+                (b"max_peers", value) => {
+                    res.max_peers = Some(usize::decode_bencode_object(value).context("max_peers")?);
+                }
+                (b"peer_id_prefix", value) => {
+                    peer_id_prefix =
+                        Some(String::decode_bencode_object(value).context("peer_id_prefix")?);
+                }
+                (b"seed_action", value) => {
+                    res.seed_limits.action = match value.try_into_bytes().context("seed_action")? {
+                        b"pause" => SeedLimitAction::Pause,
+                        b"stop" => SeedLimitAction::Stop,
+                        _ => {
+                            return Err(Error::unexpected_token("pause or stop", "other")
+                                .context("seed_action"))
+                        }
+                    };
+                }
+                (b"seed_ratio", value) => {
+                    let ratio = u64::decode_bencode_object(value).context("seed_ratio")?;
+                    res.seed_limits.ratio = Some(ratio as f64 / RATIO_SCALE);
+                }
+                (b"seed_time_limit", value) => {
+                    let secs = u64::decode_bencode_object(value).context("seed_time_limit")?;
+                    res.seed_limits.time = Some(Duration::from_secs(secs));
+                }
+                (b"seed_time", value) => {
+                    let secs = u64::decode_bencode_object(value).context("seed_time")?;
+                    res.seed_time = Duration::from_secs(secs);
+                }
+                (b"state", value) => {
+                    let name = value.try_into_bytes().context("state")?;
+                    state = Some(parse_state(name).ok_or_else(|| {
+                        Error::unexpected_token("torrent state", "other").context("state")
+                    })?);
+                }
+                (b"uploaded", value) => {
+                    res.uploaded = u64::decode_bencode_object(value).context("uploaded")?;
+                }
+                (b"weight", value) => {
+                    res.weight = u32::decode_bencode_object(value).context("weight")?;
+                }
+                _ => (),
+            }
+        }
+
+        res.info_hash = info_hash.ok_or_else(|| Error::missing_field("info_hash"))?;
+        res.state = state.ok_or_else(|| Error::missing_field("state"))?;
+        if let (Some(prefix), Some(version)) = (peer_id_prefix, version) {
+            res.profile = Some(ClientProfile::new(&prefix, &version));
+        }
+        Ok(res)
+    }
+}
+
+impl FromBencode for SessionState {
+    const EXPECTED_RECURSION_DEPTH: usize = 3;
+
+    fn decode_bencode_object(object: Object) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut torrents = None;
+        let mut totals = TransferTotals::default();
+        let mut dict_dec = object.try_into_dictionary()?;
+        while let Some(pair) = dict_dec.next_pair()? {
+            match pair {
+                (b"downloaded", value) => {
+                    totals.downloaded = u64::decode_bencode_object(value).context("downloaded")?;
+                }
+                (b"torrents", value) => {
+                    torrents = Some(Vec::decode_bencode_object(value).context("torrents")?);
+                }
+                (b"uploaded", value) => {
+                    totals.uploaded = u64::decode_bencode_object(value).context("uploaded")?;
+                }
+                _ => (),
+            }
+        }
+        Ok(SessionState {
+            torrents: torrents.ok_or_else(|| Error::missing_field("torrents"))?,
+            totals,
+        })
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        const DIR: &str = "./test_state_save_and_load";
+        fs::create_dir_all(DIR).unwrap();
+        assert_eq!(None, SessionState::load(DIR).unwrap());
+
+        let state = SessionState {
+            torrents: vec![
+                TorrentEntry {
+                    info_hash: [7; 20],
+                    state: TorrentState::Paused,
+                    downloaded: 1 << 40,
+                    uploaded: 12,
+                    seed_time: Duration::from_secs(3600),
+                    max_peers: Some(8),
+                    weight: 3,
+                    seed_limits: SeedLimits {
+                        ratio: Some(1.5),
+                        time: Some(Duration::from_secs(60)),
+                        action: SeedLimitAction::Stop,
+                    },
+                    profile: ClientProfile::named("transmission"),
+                },
+                TorrentEntry {
+                    info_hash: [1; 20],
+                    state: TorrentState::Queued,
+                    downloaded: 0,
+                    uploaded: 0,
+                    seed_time: Duration::ZERO,
+                    max_peers: None,
+                    weight: DEFAULT_WEIGHT,
+                    seed_limits: SeedLimits::default(),
+                    profile: None,
+                },
+            ],
+            totals: TransferTotals {
+                downloaded: 1 << 41,
+                uploaded: 99,
+            },
+        };
+        state.save(DIR).unwrap();
+        assert_eq!(Some(state), SessionState::load(DIR).unwrap());
+
+        fs::write(Path::new(DIR).join(STATE_FILE), b"d8:torrentsi1ee").unwrap();
+        let err = SessionState::load(DIR).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+// Transfer statistics of a torrent, snapshots are meant to drive a UI
+// refreshing every second or so.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Bytes transferred in one direction, with the current and average rates.
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    started: Instant,
+    total: u64,
+    // Bytes of previous runs, part of the total but not of the rates
+    restored: u64,
+    // Bytes per second, smoothed over the last samples
+    rate: f64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> Self {
+        RateMeter {
+            started: now,
+            total: 0,
+            restored: 0,
+            rate: 0.0,
+            bytes: 0,
+            since: now,
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+        self.total += bytes as u64;
+    }
+
+    /// Take a rate sample if one is due. Must be called periodically, also
+    /// when nothing is transferred for the rate to drop.
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.since);
+        if elapsed >= RATE_INTERVAL {
+            let sample = self.bytes as f64 / elapsed.as_secs_f64();
+            self.rate = match self.rate {
+                0.0 => sample,
+                rate => rate + RATE_SMOOTHING * (sample - rate),
+            };
+            self.bytes = 0;
+            self.since = now;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.restored + self.total
+    }
+
+    /// Bytes of this run only.
+    pub fn session_total(&self) -> u64 {
+        self.total
+    }
+
+    /// Count the bytes of a previous run in the total.
+    pub fn restore(&mut self, total: u64) {
+        self.restored = total;
+    }
+
+    /// Bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Bytes per second since the meter was created.
+    pub fn average(&self, now: Instant) -> f64 {
+        match now.saturating_duration_since(self.started).as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.total as f64 / secs,
+        }
+    }
+}
+
+/// Time spent in disk reads and writes, shared with the background tasks
+/// doing them.
+#[derive(Debug, Default)]
+pub struct DiskStats {
+    reads: AtomicU64,
+    read_micros: AtomicU64,
+    writes: AtomicU64,
+    write_micros: AtomicU64,
+}
+
+impl DiskStats {
+    pub fn record_read(&self, elapsed: Duration) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.read_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_write(&self, elapsed: Duration) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.write_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> DiskLatency {
+        DiskLatency {
+            reads: self.reads.load(Ordering::Relaxed),
+            read_time: Duration::from_micros(self.read_micros.load(Ordering::Relaxed)),
+            writes: self.writes.load(Ordering::Relaxed),
+            write_time: Duration::from_micros(self.write_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Disk operations so far and the time they took, the average latency is
+/// the time over the count.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiskLatency {
+    pub reads: u64,
+    pub read_time: Duration,
+    pub writes: u64,
+    pub write_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TorrentStats {
+    // Bytes of the verified pieces
+    pub bytes_done: u64,
+    // Bytes of the wanted pieces still to download
+    pub bytes_remaining: u64,
+    // Fraction of the pieces verified, between 0 and 1
+    pub progress: f64,
+    // Payload bytes transferred since the torrent was created, previous runs
+    // of a restored session included
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Payload bytes transferred since the torrent was opened
+    pub session_downloaded: u64,
+    pub session_uploaded: u64,
+    // Bytes per second
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub average_download_rate: f64,
+    pub average_upload_rate: f64,
+    // `None` while nothing is downloaded
+    pub eta: Option<Duration>,
+    pub connected_peers: usize,
+    // Peers heard of, connected or not
+    pub known_peers: usize,
+    // Swarm size reported by the last announce
+    pub seeds: Option<u32>,
+    pub leechers: Option<u32>,
+    // Uploaded over downloaded bytes
+    pub ratio: f64,
+    // Time spent seeding, see `SeedLimits`
+    pub seed_time: Duration,
+    // Pieces which didn't match their hash
+    pub piece_failures: u64,
+    // Why the last announce failed, `None` once one succeeds
+    pub tracker_error: Option<String>,
+    pub disk: DiskLatency,
+}
+
+/// Bytes transferred by a torrent or a whole session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransferTotals {
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl TransferTotals {
+    /// Uploaded bytes over downloaded ones, 0 before any download.
+    pub fn ratio(&self) -> f64 {
+        match self.downloaded {
+            0 => 0.0,
+            n => self.uploaded as f64 / n as f64,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TransferTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.downloaded += other.downloaded;
+        self.uploaded += other.uploaded;
+    }
+}
+
+/// Time left to download `remaining` bytes at `rate` bytes per second.
+pub fn eta(remaining: u64, rate: f64) -> Option<Duration> {
+    match remaining {
+        0 => Some(Duration::ZERO),
+        _ if rate > 0.0 => Some(Duration::from_secs_f64(remaining as f64 / rate)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        meter.add(1000);
+        meter.tick(start + Duration::from_millis(500));
+        assert_eq!(0.0, meter.rate());
+
+        meter.tick(start + Duration::from_secs(1));
+        assert_eq!(1000.0, meter.rate());
+        // Nothing more came, the rate drops
+        meter.tick(start + Duration::from_secs(2));
+        assert_eq!(700.0, meter.rate());
+        assert_eq!(250.0, meter.average(start + Duration::from_secs(4)));
+        assert_eq!(1000, meter.total());
+
+        assert_eq!(Some(Duration::from_secs(5)), eta(1000, 200.0));
+        assert_eq!(None, eta(1000, 0.0));
+        assert_eq!(Some(Duration::ZERO), eta(0, 0.0));
+    }
+}
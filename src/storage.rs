@@ -0,0 +1,2200 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    future::Future,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Mutex, task::JoinHandle, time};
+
+use bytes::Bytes;
+use md5::Md5;
+use sha1::Digest;
+use thiserror::Error;
+
+use crate::backend::IoBackend;
+use crate::cache::PieceCache;
+use crate::definitions::{InfoHash, BLOCK_SIZE};
+use crate::fdpool::FilePool;
+use crate::file::{self, Allocation, FlushPolicy, Piece, StorageConfig};
+use crate::hash::{HashKind, HashPool, PieceHash, PieceHasher};
+use crate::layout::Layout;
+use crate::parts::PartsFile;
+use crate::priority::Priority;
+use crate::recheck::RecheckHandle;
+use crate::resume::{ResumeData, UnfinishedPiece};
+use crate::sink::PieceSink;
+use crate::stats::DiskStats;
+
+/// Errors of the storage layer. They are typed so that callers can recover,
+/// e.g. pause a torrent when the disk is full, instead of crashing.
+#[derive(Debug, Error)]
+pub enum StorageError {
+    // The piece isn't in memory
+    #[error("Piece {0} not loaded")]
+    NotLoaded(usize),
+    #[error("No space left on device")]
+    OutOfSpace,
+    // Checked before allocating
+    #[error("Not enough disk space: {needed} bytes needed, {available} available")]
+    InsufficientSpace { needed: u64, available: u64 },
+    #[error("Permission denied")]
+    PermissionDenied,
+    #[error("File already exist")]
+    AlreadyExists,
+    // An existing file doesn't have the expected size
+    #[error("Existing file has {actual} bytes instead of {expected}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    // Something which isn't a regular file is in the way
+    #[error("Not a regular file")]
+    NotAFile,
+    // The file is shorter than expected
+    #[error("Short read: {read} bytes out of {expected}")]
+    ShortRead { expected: usize, read: usize },
+    #[error("Short write: {written} bytes out of {expected}")]
+    ShortWrite { expected: usize, written: usize },
+    // Piece, offset or length outside of the torrent
+    #[error("Block out of range")]
+    OutOfRange,
+    // The file was truncated by someone else while in use
+    #[error("File was truncated while in use")]
+    Truncated,
+    #[error("I/O error: {0}")]
+    Io(#[source] io::Error),
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+impl StorageError {
+    /// The disk is full, the download can go on once space is freed.
+    pub fn is_disk_full(&self) -> bool {
+        matches!(
+            self,
+            StorageError::OutOfSpace | StorageError::InsufficientSpace { .. }
+        )
+    }
+}
+
+/// Free space available to us on the filesystem holding `path`, which
+/// doesn't need to exist yet. `None` when it can't be known.
+pub fn available_space<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
+    let Some(existing) = path.as_ref().ancestors().find(|p| p.exists()) else {
+        return Ok(None);
+    };
+    let existing = match existing.as_os_str().is_empty() {
+        true => Path::new("."),
+        false => existing,
+    };
+
+    #[cfg(unix)]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let path = CString::new(existing.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = existing;
+        Ok(None)
+    }
+}
+
+/// Fail early when `needed` bytes can't fit on the filesystem of `path`.
+pub fn check_space<P: AsRef<Path>>(path: P, needed: u64) -> StorageResult<()> {
+    match available_space(path)? {
+        Some(available) if available < needed => {
+            Err(StorageError::InsufficientSpace { needed, available })
+        }
+        _ => Ok(()),
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::StorageFull => StorageError::OutOfSpace,
+            io::ErrorKind::PermissionDenied => StorageError::PermissionDenied,
+            io::ErrorKind::AlreadyExists => StorageError::AlreadyExists,
+            _ => StorageError::Io(e),
+        }
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        let kind = match e {
+            StorageError::Io(e) => return e,
+            StorageError::NotLoaded(_) => io::ErrorKind::NotFound,
+            StorageError::OutOfSpace | StorageError::InsufficientSpace { .. } => {
+                io::ErrorKind::StorageFull
+            }
+            StorageError::PermissionDenied => io::ErrorKind::PermissionDenied,
+            StorageError::AlreadyExists | StorageError::SizeMismatch { .. } => {
+                io::ErrorKind::AlreadyExists
+            }
+            StorageError::NotAFile => io::ErrorKind::InvalidInput,
+            StorageError::ShortRead { .. } | StorageError::Truncated => {
+                io::ErrorKind::UnexpectedEof
+            }
+            StorageError::ShortWrite { .. } => io::ErrorKind::WriteZero,
+            StorageError::OutOfRange => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, e.to_string())
+    }
+}
+
+/// Backing store of a torrent. `FileEntity`, `MultiFileStorage` and
+/// `MmapStorage` are implementations, others can keep the data in object
+/// storage, a database or on a network filesystem.
+pub trait TorrentStorage: Send {
+    fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> impl Future<Output = StorageResult<Bytes>> + Send;
+
+    fn write_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> impl Future<Output = StorageResult<()>> + Send;
+
+    /// Hash a piece and mark it as verified if it matches `expected`.
+    fn verify_piece(
+        &mut self,
+        index: usize,
+        expected: &PieceHash,
+    ) -> impl Future<Output = StorageResult<bool>> + Send;
+
+    /// Make the written data durable.
+    fn flush(&mut self) -> impl Future<Output = StorageResult<()>> + Send;
+
+    /// Size of the torrent in bytes.
+    fn len(&self) -> u64;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes held in memory which a crash would lose.
+    fn dirty_bytes(&self) -> u64 {
+        0
+    }
+}
+
+/// When the background flusher writes dirty data out.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FlusherConfig {
+    // Longest time dirty data stays in memory
+    pub interval: Duration,
+    // Flush as soon as this much data is dirty
+    pub max_dirty_bytes: u64,
+    // How often the dirty byte count is looked at
+    pub check_every: Duration,
+}
+
+impl Default for FlusherConfig {
+    fn default() -> Self {
+        FlusherConfig {
+            interval: Duration::from_secs(30),
+            max_dirty_bytes: 16 * 1024 * 1024,
+            check_every: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Flush `storage` in the background so that a crash only loses a bounded
+/// amount of data. The task ends once every other reference to the storage
+/// is dropped, or on the first error.
+pub fn spawn_flusher<S>(
+    storage: Arc<Mutex<S>>,
+    config: FlusherConfig,
+) -> JoinHandle<StorageResult<()>>
+where
+    S: TorrentStorage + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticker = time::interval(config.check_every);
+        let mut last_flush = Instant::now();
+        loop {
+            ticker.tick().await;
+            if Arc::strong_count(&storage) == 1 {
+                return Ok(());
+            }
+
+            let mut storage = storage.lock().await;
+            let dirty = storage.dirty_bytes();
+            if dirty >= config.max_dirty_bytes.max(1)
+                || (dirty > 0 && last_flush.elapsed() >= config.interval)
+            {
+                storage.flush().await?;
+                last_flush = Instant::now();
+            }
+        }
+    })
+}
+
+/// Something the storage found out which the caller may act on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageEvent {
+    // A completed file was checked against the MD5 of the torrent. A
+    // mismatch means corruption the piece hashes didn't catch.
+    FileVerified { file: usize, valid: bool },
+    // Every piece of the file is verified, it can be used even though the
+    // rest of the torrent isn't done
+    FileCompleted { file: usize },
+}
+
+pub const PARTS_EXTENSION: &str = "parts";
+pub const INCOMPLETE_SUFFIX: &str = ".!incomplete";
+
+/// Where the files of a torrent live.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageDirs {
+    // Final location of the files
+    pub save_path: PathBuf,
+    // Files are downloaded here and moved to `save_path` once complete
+    pub incomplete_path: Option<PathBuf>,
+    // Append `INCOMPLETE_SUFFIX` to the file names until complete
+    pub incomplete_suffix: bool,
+}
+
+impl StorageDirs {
+    pub fn new<P: AsRef<Path>>(save_path: P) -> Self {
+        StorageDirs {
+            save_path: save_path.as_ref().to_owned(),
+            incomplete_path: None,
+            incomplete_suffix: false,
+        }
+    }
+
+    /// Files are somewhere else, or named otherwise, until complete.
+    pub fn has_incomplete_location(&self) -> bool {
+        self.incomplete_path.is_some() || self.incomplete_suffix
+    }
+}
+
+/// Storage for torrents made of several files, which can be selected for
+/// download individually. Files are only created once something is written
+/// to them, and pieces overlapping a skipped file are kept in a `.parts`
+/// sidecar instead.
+#[derive(Debug)]
+pub struct MultiFileStorage {
+    dirs: StorageDirs,
+    // Files were moved to their final location
+    moved: bool,
+    layout: Layout,
+    handles: FilePool,
+    wanted: Vec<bool>,
+    priorities: Vec<Priority>,
+    parts: PartsFile,
+    verified: Vec<bool>,
+    // Check the MD5 of files once they are complete
+    check_md5: bool,
+    events: Vec<StorageEvent>,
+    // Pieces being downloaded, kept in memory until they are verified
+    downloading: HashMap<usize, PendingPiece>,
+    disk_stats: Arc<DiskStats>,
+    backend: IoBackend,
+    hasher: HashPool,
+    // How new files are allocated
+    allocation: Allocation,
+    flush: FlushPolicy,
+    // Bytes received or written since the last sync
+    unsynced: usize,
+    last_sync: Instant,
+    // Verified pieces read to be sent to peers
+    cache: PieceCache,
+    read_ahead: usize,
+    // Last piece read with `read_block`, to detect sequential access
+    last_read: Option<usize>,
+    prefetch: HashMap<usize, JoinHandle<StorageResult<Vec<u8>>>>,
+    sink: Option<PieceSink>,
+    chunked_hashing: bool,
+    // Files were already on disk when opened
+    existing: bool,
+}
+
+// Where the bytes of a range are: file, offset and length, no file for
+// padding which reads as zeros
+type FileReads = Vec<(Option<Arc<File>>, u64, usize)>;
+
+#[derive(Debug)]
+struct PendingPiece {
+    data: Vec<u8>,
+    // One entry per block
+    received: Vec<bool>,
+    // Received blocks not saved in the parts file yet
+    dirty: bool,
+}
+
+/// Hidden sidecar next to the top-level file or directory of the torrent.
+pub fn parts_path<P: AsRef<Path>>(root: P, layout: &Layout) -> PathBuf {
+    let name = layout
+        .files()
+        .first()
+        .and_then(|f| f.path.components().next())
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    root.as_ref().join(format!(".{}.{}", name, PARTS_EXTENSION))
+}
+
+impl MultiFileStorage {
+    pub fn new<P: AsRef<Path>>(root: P, layout: Layout) -> StorageResult<Self> {
+        MultiFileStorage::with_dirs(StorageDirs::new(root), layout)
+    }
+
+    pub fn with_dirs(dirs: StorageDirs, layout: Layout) -> StorageResult<Self> {
+        MultiFileStorage::with_config(dirs, layout, StorageConfig::default())
+    }
+
+    /// Storage following `config`. The journal and direct I/O are only
+    /// implemented by `FileEntity`, asking for them is an error.
+    pub fn with_config(
+        dirs: StorageDirs,
+        layout: Layout,
+        config: StorageConfig,
+    ) -> StorageResult<Self> {
+        if config.journal || config.direct_io {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Journal and direct I/O are only supported for single files",
+            )
+            .into());
+        }
+        let root = dirs.incomplete_path.as_ref().unwrap_or(&dirs.save_path);
+        if config.truncate_existing {
+            for f in layout.files().iter().filter(|f| !f.attr.padding) {
+                let mut path = root.join(&f.path).into_os_string();
+                remove_existing(Path::new(&path))?;
+                path.push(INCOMPLETE_SUFFIX);
+                remove_existing(Path::new(&path))?;
+            }
+            remove_existing(&parts_path(root, &layout))?;
+        }
+        let missing = layout
+            .files()
+            .iter()
+            .filter(|f| !f.attr.padding && !root.join(&f.path).exists())
+            .map(|f| f.length)
+            .sum();
+        check_space(root, missing)?;
+        let parts = PartsFile::open(
+            parts_path(root, &layout),
+            layout.num_pieces(),
+            layout.piece_size(),
+        )?;
+
+        let mut storage = MultiFileStorage {
+            moved: !dirs.has_incomplete_location(),
+            dirs,
+            handles: FilePool::default(),
+            wanted: vec![true; layout.files().len()],
+            priorities: vec![Priority::Normal; layout.files().len()],
+            verified: (0..layout.num_pieces())
+                .map(|i| layout.is_padding_piece(i))
+                .collect(),
+            check_md5: false,
+            events: Vec::new(),
+            downloading: HashMap::new(),
+            disk_stats: Arc::default(),
+            backend: config.backend,
+            hasher: config.hasher,
+            allocation: config.allocation,
+            flush: config.flush,
+            unsynced: 0,
+            last_sync: Instant::now(),
+            cache: PieceCache::new(config.cache_size),
+            read_ahead: config.read_ahead,
+            last_read: None,
+            prefetch: HashMap::new(),
+            sink: config.sink,
+            chunked_hashing: config.chunked_hashing,
+            existing: false,
+            parts,
+            layout,
+        };
+        storage.existing = !storage.parts.is_empty()
+            || (0..storage.layout.files().len()).any(|file| {
+                !storage.layout.files()[file].attr.padding && storage.file_path(file).exists()
+            });
+        Ok(storage)
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn parts(&self) -> &PartsFile {
+        &self.parts
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.layout.num_pieces()
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        self.verified[index]
+    }
+
+    pub fn get_bitfield(&self) -> &Vec<bool> {
+        &self.verified
+    }
+
+    pub fn is_wanted(&self, file: usize) -> bool {
+        self.wanted[file]
+    }
+
+    pub fn file_priorities(&self) -> &[Priority] {
+        &self.priorities
+    }
+
+    pub fn dirs(&self) -> &StorageDirs {
+        &self.dirs
+    }
+
+    /// Time taken by the reads and writes of blocks.
+    pub fn disk_stats(&self) -> &Arc<DiskStats> {
+        &self.disk_stats
+    }
+
+    /// Check completed files having an `md5sum` in the torrent.
+    pub fn set_check_md5(&mut self, check: bool) {
+        self.check_md5 = check;
+    }
+
+    /// Events since the last call.
+    pub fn take_events(&mut self) -> Vec<StorageEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Path of a file once the download is complete.
+    pub fn final_path(&self, file: usize) -> PathBuf {
+        self.dirs.save_path.join(&self.layout.files()[file].path)
+    }
+
+    /// Current path of a file on disk.
+    pub fn file_path(&self, file: usize) -> PathBuf {
+        if self.moved {
+            return self.final_path(file);
+        }
+
+        let root = self
+            .dirs
+            .incomplete_path
+            .as_ref()
+            .unwrap_or(&self.dirs.save_path);
+        let mut res = root.join(&self.layout.files()[file].path).into_os_string();
+        if self.dirs.incomplete_suffix {
+            res.push(INCOMPLETE_SUFFIX);
+        }
+        res.into()
+    }
+
+    /// Every piece holding data of the file is verified.
+    pub fn is_file_complete(&self, file: usize) -> bool {
+        self.layout
+            .file_pieces(file)
+            .all(|index| self.verified[index])
+    }
+
+    /// Byte ranges of the file covered by verified pieces, in order and
+    /// merged.
+    pub fn verified_ranges(&self, file: usize) -> Vec<Range<u64>> {
+        let f = &self.layout.files()[file];
+        let piece_size = self.layout.piece_size() as u64;
+
+        let mut res: Vec<Range<u64>> = Vec::new();
+        for index in self.layout.file_pieces(file).filter(|&i| self.verified[i]) {
+            let start = index as u64 * piece_size;
+            let end = start + self.layout.piece_len(index).unwrap_or(0) as u64;
+            let range = start.max(f.offset) - f.offset..end.min(f.offset + f.length) - f.offset;
+            match res.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => res.push(range),
+            }
+        }
+        res
+    }
+
+    /// Verified bytes of the file.
+    pub fn file_progress(&self, file: usize) -> u64 {
+        self.verified_ranges(file)
+            .iter()
+            .map(|r| r.end - r.start)
+            .sum()
+    }
+
+    /// Every piece holding data of a selected file is verified.
+    pub fn is_complete(&self) -> bool {
+        (0..self.layout.files().len())
+            .filter(|&f| self.wanted[f])
+            .flat_map(|f| self.layout.file_pieces(f))
+            .all(|index| self.verified[index])
+    }
+
+    /// Move the files, and the parts file, from their incomplete location to
+    /// the save path. Each file is renamed in one step, so it is never seen
+    /// half written at its final path. Files on another filesystem are
+    /// copied to a temporary name first. File attributes are applied last.
+    pub fn move_to_complete(&mut self) -> StorageResult<()> {
+        if !self.moved {
+            self.relocate(|s| {
+                s.moved = true;
+                Ok(())
+            })?;
+        }
+        self.apply_attributes()
+    }
+
+    /// Set the executable bit of executable files and create the symlinks of
+    /// the torrent (BEP 47). Only unix has them, elsewhere this does nothing.
+    pub fn apply_attributes(&self) -> StorageResult<()> {
+        for (file, f) in self.layout.files().iter().enumerate() {
+            let path = self.file_path(file);
+            if let Some(target) = &f.attr.symlink {
+                // The target is relative to the torrent directory, the link
+                // to the directory holding it
+                let depth = f.path.components().count().saturating_sub(2);
+                let mut relative: PathBuf = std::iter::repeat_n("..", depth).collect();
+                relative.push(target);
+                create_symlink(&relative, &path)?;
+            } else if f.attr.executable && self.wanted[file] {
+                set_executable(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete the files of the torrent, its parts file and its directory.
+    /// Paths are checked again, and the directory is only removed when it is
+    /// within the save path, or the incomplete path while there.
+    pub fn delete_files(&mut self) -> StorageResult<()> {
+        self.prefetch.drain().for_each(|(_, h)| h.abort());
+        self.cache = PieceCache::new(self.cache.budget());
+        self.handles.clear();
+        self.parts.close();
+        for (file, f) in self.layout.files().iter().enumerate() {
+            Layout::checked_path(f.path.clone())?;
+            remove_existing(&self.file_path(file))?;
+        }
+        remove_existing(self.parts.path())?;
+
+        // Files of multi-file torrents share the directory named after it
+        let mut tops = self.layout.files().iter().map(|f| {
+            let mut components = f.path.components();
+            components.next().filter(|_| components.next().is_some())
+        });
+        let Some(Some(top)) = tops.next() else {
+            return Ok(());
+        };
+        if !tops.all(|t| t == Some(top)) {
+            return Ok(());
+        }
+        let root = self.parts_root();
+        let dir = root.join(Layout::checked_path(PathBuf::from(top.as_os_str()))?);
+        match (fs::canonicalize(&dir), fs::canonicalize(root)) {
+            (Ok(dir), Ok(root)) if dir.starts_with(&root) && dir != root => {
+                Ok(fs::remove_dir_all(dir)?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Rename a file, its new path being relative to the save path.
+    pub fn rename_file<P: AsRef<Path>>(&mut self, file: usize, path: P) -> StorageResult<()> {
+        let path = path.as_ref().to_owned();
+        self.relocate(|s| s.layout.set_path(file, path))
+    }
+
+    /// Rename the directory of a multi-file torrent, or the file of a single
+    /// file one.
+    pub fn rename_root(&mut self, name: &str) -> StorageResult<()> {
+        self.relocate(|s| s.layout.rename_root(name))
+    }
+
+    /// Move the files to another save path. Files still in an incomplete
+    /// directory stay there and will be moved to the new save path once
+    /// complete.
+    pub fn move_storage<P: AsRef<Path>>(&mut self, save_path: P) -> StorageResult<()> {
+        let save_path = save_path.as_ref().to_owned();
+        self.relocate(|s| {
+            s.dirs.save_path = save_path;
+            Ok(())
+        })
+    }
+
+    fn parts_root(&self) -> &Path {
+        match &self.dirs.incomplete_path {
+            Some(path) if !self.moved => path,
+            _ => &self.dirs.save_path,
+        }
+    }
+
+    // Apply a change of the paths and move what is on disk along. Requests
+    // can't be in flight, they borrow the storage. On failure the files moved
+    // so far are put back.
+    fn relocate<F>(&mut self, change: F) -> StorageResult<()>
+    where
+        F: FnOnce(&mut Self) -> io::Result<()>,
+    {
+        let num_files = self.layout.files().len();
+        let old_paths: Vec<PathBuf> = (0..num_files).map(|f| self.file_path(f)).collect();
+        let old_parts = self.parts.path().to_owned();
+        let backup = (self.dirs.clone(), self.moved, self.layout.clone());
+
+        change(self)?;
+        let new_paths = (0..num_files).map(|f| self.file_path(f));
+        let new_parts = parts_path(self.parts_root(), &self.layout);
+        let moves: Vec<(PathBuf, PathBuf)> = old_paths
+            .into_iter()
+            .zip(new_paths)
+            .chain(std::iter::once((old_parts, new_parts)))
+            .filter(|(from, to)| from != to && from.exists())
+            .collect();
+
+        // Close everything before renaming, Windows doesn't allow renaming
+        // open files
+        self.handles.clear();
+        self.parts.close();
+
+        let mut res = Ok(());
+        let mut done = 0;
+        for (from, to) in &moves {
+            if to.exists() {
+                res = Err(StorageError::AlreadyExists);
+                break;
+            }
+            if let Err(e) = move_file(from, to) {
+                res = Err(e.into());
+                break;
+            }
+            done += 1;
+        }
+        if res.is_err() {
+            for (from, to) in moves[..done].iter().rev() {
+                let _ = move_file(to, from);
+            }
+            (self.dirs, self.moved, self.layout) = backup;
+        }
+
+        let (num_pieces, piece_size) = (self.layout.num_pieces(), self.layout.piece_size());
+        self.parts = PartsFile::open(
+            parts_path(self.parts_root(), &self.layout),
+            num_pieces,
+            piece_size,
+        )?;
+
+        res
+    }
+
+    // A piece goes to the parts file when any of its files is skipped
+    fn in_parts(&self, index: usize) -> bool {
+        self.layout
+            .piece_files(index)
+            .any(|f| !self.wanted[f] && self.layout.files()[f].length > 0)
+    }
+
+    fn handle(&mut self, file: usize) -> io::Result<Arc<File>> {
+        let path = self.file_path(file);
+        let length = self.layout.files()[file].length;
+        let allocation = self.allocation;
+
+        self.handles.get_or_open(file, || {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = match fs::OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(handle) => handle,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let size = usize::try_from(length).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "File too large")
+                    })?;
+                    return file::allocate(&path, size, allocation);
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Sparse, blocks get allocated as they are written
+            if handle.metadata()?.len() < length {
+                handle.set_len(length)?;
+            }
+            Ok(handle)
+        })
+    }
+
+    /// Cap the number of files kept open at once.
+    pub fn set_max_open_files(&mut self, max: usize) {
+        self.handles.set_max_open(max);
+    }
+
+    pub fn open_files(&self) -> usize {
+        self.handles.len()
+    }
+
+    async fn write_files(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        let slices = self
+            .layout
+            .slices(index, begin, data.len())
+            .ok_or(StorageError::OutOfRange)?;
+        let mut pos = 0;
+        for slice in slices {
+            // Padding files are never stored
+            if !self.layout.files()[slice.file].attr.padding {
+                let handle = self.handle(slice.file)?;
+                let mut buf = data[pos..pos + slice.length].to_vec();
+                self.backend
+                    .write_at(&handle, &mut buf, slice.offset)
+                    .await?;
+            }
+            pos += slice.length;
+        }
+        Ok(())
+    }
+
+    fn file_reads(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<FileReads> {
+        let slices = self
+            .layout
+            .slices(index, begin, length)
+            .ok_or(StorageError::OutOfRange)?;
+        let mut res = Vec::with_capacity(slices.len());
+        for slice in slices {
+            let handle = match self.layout.files()[slice.file].attr.padding {
+                true => None,
+                false => Some(self.handle(slice.file)?),
+            };
+            res.push((handle, slice.offset, slice.length));
+        }
+        Ok(res)
+    }
+
+    async fn read_files(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Vec<u8>> {
+        let reads = self.file_reads(index, begin, length)?;
+        read_slices(&self.backend, reads).await
+    }
+
+    async fn write_parts(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        let (file, offset) = self.parts.locate_write(index, begin, data.len())?;
+        self.backend
+            .write_at(&file, &mut data.to_vec(), offset)
+            .await?;
+        Ok(())
+    }
+
+    async fn read_parts(
+        &self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Vec<u8>> {
+        let (file, offset, available) = self.parts.locate_read(index, begin, length)?;
+        let mut res = vec![0u8; available];
+        let read = self.backend.read_at(&file, &mut res, offset).await?;
+        if read != available {
+            return Err(StorageError::ShortRead {
+                expected: available,
+                read,
+            });
+        }
+        res.resize(length, 0);
+        Ok(res)
+    }
+
+    fn piece_len(&self, index: usize) -> StorageResult<usize> {
+        self.layout.piece_len(index).ok_or(StorageError::OutOfRange)
+    }
+
+    fn check_range(&self, index: usize, begin: usize, length: usize) -> StorageResult<()> {
+        // Offsets come from peers, they may overflow
+        let len = self.piece_len(index)?;
+        if begin.checked_add(length).is_none_or(|end| end > len) {
+            return Err(StorageError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    pub async fn write_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> StorageResult<()> {
+        self.check_range(index, begin, data.len())?;
+        self.forget(index);
+        let start = Instant::now();
+        let res = match self.in_parts(index) {
+            true => self.write_parts(index, begin, data).await,
+            false => self.write_files(index, begin, data).await,
+        };
+        self.disk_stats.record_write(start.elapsed());
+        res
+    }
+
+    /// Store a block received from a peer. It is kept in memory until its
+    /// piece is verified by `commit_piece`, only valid pieces reach the files.
+    pub async fn write_sub_piece(
+        &mut self,
+        index: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> StorageResult<()> {
+        self.check_range(index, begin, data.len())?;
+        let len = self.piece_len(index)?;
+        let piece = self
+            .downloading
+            .entry(index)
+            .or_insert_with(|| PendingPiece {
+                data: vec![0; len],
+                received: vec![false; len.div_ceil(BLOCK_SIZE)],
+                dirty: false,
+            });
+        piece.data[begin..begin + data.len()].copy_from_slice(data);
+        if !data.is_empty() {
+            let blocks = begin / BLOCK_SIZE..=(begin + data.len() - 1) / BLOCK_SIZE;
+            piece.received[blocks].fill(true);
+            piece.dirty = true;
+        }
+        self.unsynced += data.len();
+
+        self.tick().await
+    }
+
+    /// Every block of the piece was received with `write_sub_piece`.
+    pub fn is_piece_complete(&self, index: usize) -> bool {
+        self.downloading
+            .get(&index)
+            .is_some_and(|p| p.received.iter().all(|&r| r))
+    }
+
+    /// Check a complete piece against its expected hash. On success it is
+    /// written to the files and marked as verified, otherwise its blocks are
+    /// discarded so they can be downloaded again. Returns whether the piece
+    /// was valid.
+    pub async fn commit_piece(
+        &mut self,
+        index: usize,
+        expected: &PieceHash,
+    ) -> StorageResult<bool> {
+        let Some(piece) = self.downloading.remove(&index) else {
+            return Ok(false);
+        };
+        let mut data = piece.data;
+        let len = data.len();
+        data.truncate(self.hashed_len(index, expected)?);
+        let (mut data, valid) = self
+            .hasher
+            .matches(*expected, data, self.layout.piece_size())
+            .await?;
+        // What v2 leaves out is padding, all zeros
+        data.resize(len, 0);
+        // The copy saved by `resume_data` is of no use anymore, unless the
+        // piece is stored in the parts file
+        if self.parts.contains(index) && !self.in_parts(index) {
+            self.parts.remove(index)?;
+        }
+        if !valid {
+            return Ok(false);
+        }
+
+        let data = Bytes::from(data);
+        if let Some(sink) = self.sink.clone() {
+            sink.deliver(index, data.clone()).await?;
+            if !sink.keeps_on_disk() {
+                self.verified[index] = true;
+                return Ok(true);
+            }
+        }
+        self.write_block(index, 0, &data).await?;
+        self.set_verified(index).await?;
+
+        if self.flush.on_verify {
+            self.sync().await?;
+        } else {
+            self.tick().await?;
+        }
+        Ok(true)
+    }
+
+    /// Bytes received of the pieces being downloaded which a crash would
+    /// lose, see `flush`.
+    pub fn dirty_bytes(&self) -> u64 {
+        self.downloading
+            .values()
+            .filter(|p| p.dirty)
+            .flat_map(|p| {
+                let len = p.data.len();
+                p.received
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &r)| r)
+                    .map(move |(block, _)| BLOCK_SIZE.min(len - block * BLOCK_SIZE) as u64)
+            })
+            .sum()
+    }
+
+    /// fsync the open files and the parts file, data already written is then
+    /// safe from a crash.
+    pub async fn sync(&mut self) -> StorageResult<()> {
+        let files: Vec<Arc<File>> = self
+            .handles
+            .files()
+            .chain(self.parts.handle())
+            .cloned()
+            .collect();
+        for file in files {
+            self.backend.sync(&file).await?;
+        }
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Save the pieces being downloaded in the parts file, and sync.
+    pub async fn flush(&mut self) -> StorageResult<()> {
+        self.save_unfinished().await?;
+        self.sync().await
+    }
+
+    /// Flush if the policy says so. Should also be called periodically when
+    /// `FlushPolicy::interval` is set.
+    pub async fn tick(&mut self) -> StorageResult<()> {
+        let by_size = self.flush.every_bytes.is_some_and(|n| self.unsynced >= n);
+        let by_time = self
+            .flush
+            .interval
+            .is_some_and(|i| self.unsynced > 0 && self.last_sync.elapsed() >= i);
+
+        if by_size || by_time {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// To be called before dropping the storage.
+    pub async fn shutdown(&mut self) -> StorageResult<()> {
+        if self.flush.on_shutdown {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    // Unverified data never goes to the files, the pieces being downloaded
+    // are saved in the parts file instead
+    async fn save_unfinished(&mut self) -> StorageResult<()> {
+        let mut dirty: Vec<usize> = self
+            .downloading
+            .iter()
+            .filter(|(_, p)| p.dirty)
+            .map(|(&index, _)| index)
+            .collect();
+        dirty.sort_unstable();
+        for index in dirty {
+            let data = self.downloading[&index].data.clone();
+            self.write_parts(index, 0, &data).await?;
+            if let Some(piece) = self.downloading.get_mut(&index) {
+                piece.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the storage state. Blocks of unfinished pieces are saved
+    /// in the parts file first so that they can be picked up after a
+    /// restart.
+    pub async fn resume_data(&mut self) -> StorageResult<ResumeData> {
+        self.save_unfinished().await?;
+        let mut unfinished: Vec<UnfinishedPiece> = self
+            .downloading
+            .iter()
+            .map(|(&index, p)| UnfinishedPiece {
+                index,
+                blocks: p.received.clone(),
+            })
+            .collect();
+        unfinished.sort_unstable_by_key(|p| p.index);
+
+        Ok(ResumeData {
+            piece_size: self.layout.piece_size(),
+            file_size: self.layout.size(),
+            pieces: self.verified.clone(),
+            unfinished,
+            ..Default::default()
+        })
+    }
+
+    /// Restore the state saved by `resume_data`. Nothing is applied and
+    /// `false` is returned when a file holding verified pieces is missing or
+    /// was resized.
+    pub async fn apply_resume(&mut self, data: &ResumeData) -> StorageResult<bool> {
+        let layout = &self.layout;
+        if data.piece_size != layout.piece_size()
+            || data.file_size != layout.size()
+            || data.pieces.len() != layout.num_pieces()
+        {
+            return Ok(false);
+        }
+        let on_disk = |file: usize| {
+            let f = &layout.files()[file];
+            f.attr.padding
+                || f.length == 0
+                || fs::metadata(self.file_path(file)).is_ok_and(|m| m.len() == f.length)
+        };
+        let present = (0..layout.num_pieces())
+            .filter(|&index| data.pieces[index])
+            .all(|index| self.parts.contains(index) || layout.piece_files(index).all(on_disk));
+        if !present {
+            return Ok(false);
+        }
+
+        for (verified, &saved) in self.verified.iter_mut().zip(&data.pieces) {
+            *verified |= saved;
+        }
+        for unfinished in &data.unfinished {
+            let index = unfinished.index;
+            if index >= self.num_pieces() || self.verified[index] || !self.parts.contains(index) {
+                continue;
+            }
+            let len = self.piece_len(index)?;
+            if unfinished.blocks.len() != len.div_ceil(BLOCK_SIZE) {
+                continue;
+            }
+            let piece = PendingPiece {
+                data: self.read_parts(index, 0, len).await?,
+                received: unfinished.blocks.clone(),
+                dirty: false,
+            };
+            self.downloading.insert(index, piece);
+        }
+        Ok(true)
+    }
+
+    /// Read a block to send it to a peer. Verified pieces are kept in memory
+    /// and, once reads look sequential, the next ones are read in the
+    /// background.
+    pub async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        self.check_range(index, begin, length)?;
+        if !self.verified[index] {
+            return self.read_disk(index, begin, length).await.map(Bytes::from);
+        }
+        // Verified pieces only went to the sink
+        if self.sink.as_ref().is_some_and(|s| !s.keeps_on_disk()) {
+            return Err(StorageError::NotLoaded(index));
+        }
+        self.load_piece(index).await?;
+        self.read_ahead_from(index);
+
+        match self.cache.peek(index) {
+            Some(piece) => Ok(piece.bytes.slice(begin..begin + length)),
+            // Larger than the whole cache
+            None => self.read_disk(index, begin, length).await.map(Bytes::from),
+        }
+    }
+
+    /// Number of pieces being prefetched.
+    pub fn prefetching(&self) -> usize {
+        self.prefetch.len()
+    }
+
+    async fn load_piece(&mut self, index: usize) -> StorageResult<()> {
+        if self.cache.contains(index) {
+            self.cache.touch(index);
+            return Ok(());
+        }
+        let len = self.piece_len(index)?;
+        if len > self.cache.budget() {
+            return Ok(());
+        }
+        let data = match self.prefetch.remove(&index) {
+            Some(handle) => handle.await.map_err(io::Error::other)??,
+            None => self.read_disk(index, 0, len).await?,
+        };
+        let piece = Piece::loaded(
+            self.layout.piece_size(),
+            Bytes::from(data),
+            self.backend.clone(),
+        );
+        // Cached pieces are clean, nothing comes back to be written
+        self.cache.insert(index, piece);
+        Ok(())
+    }
+
+    fn read_ahead_from(&mut self, index: usize) {
+        let sequential = match self.last_read {
+            Some(last) => index == last || index == last + 1,
+            None => false,
+        };
+        self.last_read = Some(index);
+
+        if !sequential {
+            // Random access, stop reading pieces which won't be needed
+            self.prefetch.drain().for_each(|(_, h)| h.abort());
+            return;
+        }
+
+        let end = (index + self.read_ahead).min(self.num_pieces() - 1);
+        for next in index + 1..=end {
+            // Pieces of the parts file are rare, they are read when asked for
+            if !self.verified[next]
+                || self.in_parts(next)
+                || self.cache.contains(next)
+                || self.prefetch.contains_key(&next)
+            {
+                continue;
+            }
+            let Ok(len) = self.piece_len(next) else {
+                break;
+            };
+            let Ok(reads) = self.file_reads(next, 0, len) else {
+                break;
+            };
+            let backend = self.backend.clone();
+            let disk_stats = self.disk_stats.clone();
+            let handle = tokio::spawn(async move {
+                let start = Instant::now();
+                let res = read_slices(&backend, reads).await;
+                disk_stats.record_read(start.elapsed());
+                res
+            });
+            self.prefetch.insert(next, handle);
+        }
+    }
+
+    // The piece changed on disk, what was read of it is stale
+    fn forget(&mut self, index: usize) {
+        self.cache.remove(index);
+        if let Some(handle) = self.prefetch.remove(&index) {
+            handle.abort();
+        }
+    }
+
+    // Read from the files or the parts file, bypassing the cache
+    async fn read_disk(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Vec<u8>> {
+        self.check_range(index, begin, length)?;
+        let start = Instant::now();
+        let res = match (self.in_parts(index), self.parts.contains(index)) {
+            (true, true) => self.read_parts(index, begin, length).await,
+            // Nothing was written yet
+            (true, false) => Ok(vec![0u8; length]),
+            (false, _) => self.read_files(index, begin, length).await,
+        };
+        self.disk_stats.record_read(start.elapsed());
+        res
+    }
+
+    pub async fn hash_piece(&mut self, index: usize) -> StorageResult<InfoHash> {
+        let data = self.read_disk(index, 0, self.piece_len(index)?).await?;
+        let (_, hash) = self
+            .hasher
+            .compute(HashKind::Sha1, data, self.layout.piece_size())
+            .await?;
+        match hash {
+            PieceHash::V1(hash) => Ok(hash),
+            PieceHash::V2(_) => unreachable!(),
+        }
+    }
+
+    /// Hash a piece and mark it as verified if it matches `expected`.
+    pub async fn verify_piece(
+        &mut self,
+        index: usize,
+        expected: &PieceHash,
+    ) -> StorageResult<bool> {
+        // Only zeros, nothing to read
+        if self.layout.is_padding_piece(index) {
+            self.verified[index] = true;
+            return Ok(true);
+        }
+        let valid = self.check_on_disk(index, expected).await?;
+        match valid {
+            true => self.set_verified(index).await?,
+            false => {
+                self.verified[index] = false;
+                self.forget(index);
+            }
+        }
+
+        Ok(valid)
+    }
+
+    /// Files, or a parts file, were already on disk when the storage was
+    /// opened. Their content must be rechecked before being resumed from.
+    pub fn needs_recheck(&self) -> bool {
+        self.existing
+    }
+
+    /// Hash every piece on disk against `hashes` and rebuild the verified
+    /// bitfield from the result. Pieces of missing files are invalid without
+    /// being read, nothing gets created. Returns the number of valid pieces.
+    pub async fn recheck(&mut self, hashes: &[PieceHash]) -> StorageResult<usize> {
+        let handle = RecheckHandle::unlimited(self.num_pieces());
+        self.recheck_with(hashes, &handle).await
+    }
+
+    /// [`MultiFileStorage::recheck`] scheduled by the `RecheckScheduler` of
+    /// `handle`, see [`FileEntity::recheck_with`].
+    ///
+    /// [`FileEntity::recheck_with`]: crate::file::FileEntity::recheck_with
+    pub async fn recheck_with(
+        &mut self,
+        hashes: &[PieceHash],
+        handle: &RecheckHandle,
+    ) -> StorageResult<usize> {
+        // One hash per piece, a malformed torrent may have more or less
+        if hashes.len() != self.num_pieces() {
+            return Err(StorageError::OutOfRange);
+        }
+        let _slot = handle.start().await;
+
+        let mut valid = 0;
+        for (index, expected) in hashes.iter().enumerate() {
+            handle.throttle(self.piece_len(index)?).await;
+            self.forget(index);
+            self.verified[index] = match self.layout.is_padding_piece(index) {
+                true => true,
+                false => self.piece_on_disk(index) && self.check_on_disk(index, expected).await?,
+            };
+            handle.record(self.verified[index]);
+            if self.verified[index] {
+                valid += 1;
+            }
+        }
+        self.existing = false;
+
+        Ok(valid)
+    }
+
+    // Every byte of the piece can be read without creating anything
+    fn piece_on_disk(&self, index: usize) -> bool {
+        if self.in_parts(index) {
+            return self.parts.contains(index);
+        }
+        self.layout.piece_files(index).all(|file| {
+            let f = &self.layout.files()[file];
+            f.attr.padding
+                || f.length == 0
+                || fs::metadata(self.file_path(file)).is_ok_and(|m| m.len() == f.length)
+        })
+    }
+
+    // v2 hashes the files of a piece without the padding aligning them
+    fn hashed_len(&self, index: usize, expected: &PieceHash) -> StorageResult<usize> {
+        match expected {
+            PieceHash::V1(_) => self.piece_len(index),
+            PieceHash::V2(_) => self
+                .layout
+                .content_len(index)
+                .ok_or(StorageError::OutOfRange),
+        }
+    }
+
+    // Hash a piece as it is on disk, bypassing the cache
+    async fn check_on_disk(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        let len = self.hashed_len(index, expected)?;
+        if !self.chunked_hashing {
+            let data = self.read_disk(index, 0, len).await?;
+            let (_, valid) = self
+                .hasher
+                .matches(*expected, data, self.layout.piece_size())
+                .await?;
+            return Ok(valid);
+        }
+
+        let mut hasher = PieceHasher::new(expected.kind(), self.layout.piece_size());
+        let mut pos = 0;
+        while pos < len {
+            let length = BLOCK_SIZE.min(len - pos);
+            hasher.update(&self.read_disk(index, pos, length).await?);
+            pos += length;
+        }
+        Ok(hasher.matches(expected))
+    }
+
+    // Mark a valid piece, reporting the files it completes
+    async fn set_verified(&mut self, index: usize) -> StorageResult<()> {
+        if std::mem::replace(&mut self.verified[index], true) {
+            return Ok(());
+        }
+        for file in self.layout.piece_files(index) {
+            let f = &self.layout.files()[file];
+            if f.length == 0 || f.attr.padding || !self.is_file_complete(file) {
+                continue;
+            }
+            self.events.push(StorageEvent::FileCompleted { file });
+            if self.check_md5 {
+                self.check_file_md5(file).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_file_md5(&mut self, file: usize) -> StorageResult<()> {
+        let Some(expected) = self.layout.files()[file].md5sum.clone() else {
+            return Ok(());
+        };
+        if !self.layout.file_pieces(file).all(|i| self.verified[i]) {
+            return Ok(());
+        }
+
+        let hash = self.file_md5(file).await?;
+        let hex: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        self.events.push(StorageEvent::FileVerified {
+            file,
+            valid: hex.eq_ignore_ascii_case(expected.trim()),
+        });
+        Ok(())
+    }
+
+    /// MD5 of the content of a file, read through the pieces so that data
+    /// kept in the parts file is included. Hashing runs on the blocking pool.
+    pub async fn file_md5(&mut self, file: usize) -> StorageResult<[u8; 16]> {
+        let f = self.layout.files()[file].clone();
+        let piece_size = self.layout.piece_size() as u64;
+        let mut hasher = Md5::new();
+
+        for index in self.layout.file_pieces(file) {
+            let start = index as u64 * piece_size;
+            let begin = f.offset.max(start) - start;
+            let end = (f.offset + f.length).min(start + self.piece_len(index)? as u64) - start;
+            let data = self
+                .read_disk(index, begin as usize, (end - begin) as usize)
+                .await?;
+            hasher = tokio::task::spawn_blocking(move || {
+                hasher.update(data);
+                hasher
+            })
+            .await
+            .map_err(io::Error::other)?;
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Set the download priority of a file, skipping it as `set_wanted`
+    /// does for [`Priority::Skip`].
+    pub async fn set_file_priority(
+        &mut self,
+        file: usize,
+        priority: Priority,
+    ) -> StorageResult<()> {
+        self.set_wanted(file, priority.is_wanted()).await?;
+        self.priorities[file] = priority;
+        Ok(())
+    }
+
+    /// Select or skip a file. Pieces shared with neighbouring files are moved
+    /// between the files and the parts file so they stay available.
+    pub async fn set_wanted(&mut self, file: usize, wanted: bool) -> StorageResult<()> {
+        if self.wanted[file] == wanted {
+            return Ok(());
+        }
+        self.priorities[file] = match wanted {
+            true => Priority::Normal,
+            false => Priority::Skip,
+        };
+
+        let pieces = self.layout.file_pieces(file);
+        if wanted {
+            self.wanted[file] = true;
+            // Unfinished pieces saved by `resume_data` stay in the parts file
+            for index in pieces {
+                if self.verified[index] && self.parts.contains(index) && !self.in_parts(index) {
+                    let len = self.piece_len(index)?;
+                    let data = self.read_parts(index, 0, len).await?;
+                    self.write_files(index, 0, &data).await?;
+                    self.parts.remove(index)?;
+                }
+            }
+        } else {
+            // Verified pieces must be read before their location changes
+            let mut moved = Vec::new();
+            for index in pieces {
+                if self.verified[index] && !self.in_parts(index) {
+                    let len = self.piece_len(index)?;
+                    moved.push((index, self.read_files(index, 0, len).await?));
+                }
+            }
+
+            self.wanted[file] = false;
+            for (index, data) in moved {
+                if self.in_parts(index) {
+                    self.write_parts(index, 0, &data).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TorrentStorage for MultiFileStorage {
+    async fn read_block(
+        &mut self,
+        index: usize,
+        begin: usize,
+        length: usize,
+    ) -> StorageResult<Bytes> {
+        MultiFileStorage::read_block(self, index, begin, length).await
+    }
+
+    async fn write_block(&mut self, index: usize, begin: usize, data: &[u8]) -> StorageResult<()> {
+        MultiFileStorage::write_block(self, index, begin, data).await
+    }
+
+    async fn verify_piece(&mut self, index: usize, expected: &PieceHash) -> StorageResult<bool> {
+        MultiFileStorage::verify_piece(self, index, expected).await
+    }
+
+    async fn flush(&mut self) -> StorageResult<()> {
+        MultiFileStorage::flush(self).await
+    }
+
+    fn len(&self) -> u64 {
+        self.layout.size()
+    }
+
+    fn dirty_bytes(&self) -> u64 {
+        MultiFileStorage::dirty_bytes(self)
+    }
+}
+
+// Read the parts of a range in order
+async fn read_slices(backend: &IoBackend, reads: FileReads) -> StorageResult<Vec<u8>> {
+    let mut res = Vec::with_capacity(reads.iter().map(|(_, _, length)| length).sum());
+    for (handle, offset, length) in reads {
+        let Some(handle) = handle else {
+            res.resize(res.len() + length, 0);
+            continue;
+        };
+        let mut buf = vec![0u8; length];
+        let read = backend.read_at(&handle, &mut buf, offset).await?;
+        if read != length {
+            return Err(StorageError::ShortRead {
+                expected: length,
+                read,
+            });
+        }
+        res.extend_from_slice(&buf);
+    }
+    Ok(res)
+}
+
+/// Remove a file, which may already be gone.
+pub fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+pub(crate) fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut tmp = to.as_os_str().to_owned();
+            tmp.push(INCOMPLETE_SUFFIX);
+            fs::copy(from, &tmp)?;
+            fs::rename(&tmp, to)?;
+            fs::remove_file(from)
+        }
+        res => res,
+    }
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> io::Result<()> {
+    if link.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = link.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_mode(perms.mode() | 0o111);
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+    use crate::definitions::BLOCK_SIZE;
+    use crate::layout::FileAttr;
+    use crate::sink::PieceSink;
+    use sha1::Sha1;
+
+    fn sha1(data: &[u8]) -> InfoHash {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+
+    #[tokio::test]
+    async fn skipped_file() {
+        const ROOT: &str = "./test_storage_skipped_file";
+
+        // Pieces of 4: |aaab|bbbb|bbcc|
+        let layout = Layout::new(
+            4,
+            vec![
+                (PathBuf::from("dir/a"), 3),
+                (PathBuf::from("dir/b"), 6),
+                (PathBuf::from("dir/c"), 2),
+            ],
+        );
+        let data: Vec<u8> = (1..=11).collect();
+
+        let mut storage = MultiFileStorage::new(ROOT, layout).unwrap();
+        storage.set_wanted(1, false).await.unwrap();
+        for index in 0..3 {
+            let chunk = &data[index * 4..(index * 4 + 4).min(11)];
+            storage.write_block(index, 0, chunk).await.unwrap();
+            assert!(storage
+                .verify_piece(index, &sha1(chunk).into())
+                .await
+                .unwrap());
+        }
+
+        // Every piece touches b, nothing reaches the files
+        assert_eq!(vec![0, 1, 2], storage.parts().pieces().collect::<Vec<_>>());
+        assert_eq!(data[..4], storage.read_block(0, 0, 4).await.unwrap());
+        assert!(!storage.file_path(1).exists());
+
+        storage.set_file_priority(1, Priority::High).await.unwrap();
+        assert_eq!(Priority::High, storage.file_priorities()[1]);
+        assert!(storage.parts().is_empty());
+        assert!(!parts_path(ROOT, storage.layout()).exists());
+        assert_eq!(data[..3], fs::read(storage.file_path(0)).unwrap());
+        assert_eq!(data[3..9], fs::read(storage.file_path(1)).unwrap());
+        for index in 0..3 {
+            let chunk = &data[index * 4..(index * 4 + 4).min(11)];
+            assert!(storage
+                .verify_piece(index, &sha1(chunk).into())
+                .await
+                .unwrap());
+        }
+
+        // Skipping it again keeps the verified boundary pieces
+        storage.set_wanted(2, false).await.unwrap();
+        assert_eq!(vec![2], storage.parts().pieces().collect::<Vec<_>>());
+        assert_eq!(data[8..], storage.read_block(2, 0, 3).await.unwrap());
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn move_on_complete() {
+        const ROOT: &str = "./test_storage_move_on_complete";
+        let save_path = Path::new(ROOT).join("done");
+
+        let layout = Layout::new(
+            4,
+            vec![(PathBuf::from("dir/a"), 6), (PathBuf::from("dir/b"), 2)],
+        );
+        let dirs = StorageDirs {
+            save_path: save_path.clone(),
+            incomplete_path: Some(Path::new(ROOT).join("incomplete")),
+            incomplete_suffix: true,
+        };
+        let data: Vec<u8> = (1..=8).collect();
+
+        let mut storage = MultiFileStorage::with_dirs(dirs, layout).unwrap();
+        assert_eq!(
+            Path::new(ROOT).join("incomplete/dir/a.!incomplete"),
+            storage.file_path(0)
+        );
+
+        storage.write_block(0, 0, &data[..4]).await.unwrap();
+        assert!(storage
+            .verify_piece(0, &sha1(&data[..4]).into())
+            .await
+            .unwrap());
+        assert!(!storage.is_complete());
+        storage.write_block(1, 0, &data[4..]).await.unwrap();
+        assert!(storage
+            .verify_piece(1, &sha1(&data[4..]).into())
+            .await
+            .unwrap());
+        assert!(storage.is_complete());
+
+        storage.move_to_complete().unwrap();
+        assert_eq!(save_path.join("dir/a"), storage.file_path(0));
+        assert_eq!(data[..6], fs::read(save_path.join("dir/a")).unwrap());
+        assert_eq!(data[6..], fs::read(save_path.join("dir/b")).unwrap());
+        assert!(!Path::new(ROOT)
+            .join("incomplete/dir/a.!incomplete")
+            .exists());
+        assert_eq!(data[4..], storage.read_block(1, 0, 4).await.unwrap());
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_completion() {
+        const ROOT: &str = "./test_storage_file_completion";
+
+        let layout = Layout::new(
+            4,
+            vec![
+                (PathBuf::from("dir/a"), 3),
+                (PathBuf::from("dir/b"), 7),
+                (PathBuf::from("dir/c"), 2),
+            ],
+        );
+        let data: Vec<u8> = (1..=12).collect();
+        let mut storage = MultiFileStorage::new(ROOT, layout).unwrap();
+
+        // Piece 0 completes `a` only, `b` needs pieces 0 to 2
+        storage.write_block(0, 0, &data[..4]).await.unwrap();
+        assert!(storage
+            .verify_piece(0, &sha1(&data[..4]).into())
+            .await
+            .unwrap());
+        assert_eq!(
+            vec![StorageEvent::FileCompleted { file: 0 }],
+            storage.take_events()
+        );
+        assert_eq!(vec![0..1], storage.verified_ranges(1));
+
+        storage.write_block(2, 0, &data[8..]).await.unwrap();
+        assert!(storage
+            .verify_piece(2, &sha1(&data[8..]).into())
+            .await
+            .unwrap());
+        assert_eq!(
+            vec![StorageEvent::FileCompleted { file: 2 }],
+            storage.take_events()
+        );
+        assert_eq!(vec![0..1, 5..7], storage.verified_ranges(1));
+        assert_eq!(3, storage.file_progress(1));
+        assert!(!storage.is_file_complete(1));
+
+        storage.write_block(1, 0, &data[4..8]).await.unwrap();
+        assert!(storage
+            .verify_piece(1, &sha1(&data[4..8]).into())
+            .await
+            .unwrap());
+        assert_eq!(
+            vec![StorageEvent::FileCompleted { file: 1 }],
+            storage.take_events()
+        );
+        assert_eq!(vec![0..7], storage.verified_ranges(1));
+
+        // Verifying again isn't a new completion
+        assert!(storage
+            .verify_piece(1, &sha1(&data[4..8]).into())
+            .await
+            .unwrap());
+        assert!(storage.take_events().is_empty());
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn write_only_verified_pieces() {
+        const ROOT: &str = "./test_storage_write_only_verified_pieces";
+        let root = Path::new(ROOT);
+
+        // Pieces of two blocks over two files
+        let len = 2 * BLOCK_SIZE;
+        let layout = Layout::new(
+            len,
+            vec![
+                (PathBuf::from("t/a"), 3 * BLOCK_SIZE as u64),
+                (PathBuf::from("t/b"), 100),
+            ],
+        );
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let hash = |index: usize| -> PieceHash {
+            sha1(&data[index * len..((index + 1) * len).min(data.len())]).into()
+        };
+
+        let mut storage = MultiFileStorage::new(root, layout.clone()).unwrap();
+        storage
+            .write_sub_piece(0, 0, &[9; BLOCK_SIZE])
+            .await
+            .unwrap();
+        storage
+            .write_sub_piece(0, BLOCK_SIZE, &data[BLOCK_SIZE..len])
+            .await
+            .unwrap();
+        assert!(storage.is_piece_complete(0));
+        assert!(!storage.commit_piece(0, &hash(0)).await.unwrap());
+        // Nothing reached the files
+        assert!(!root.join("t/a").exists());
+        assert!(!storage.is_piece_complete(0));
+
+        storage.write_sub_piece(0, 0, &data[..len]).await.unwrap();
+        assert!(storage.commit_piece(0, &hash(0)).await.unwrap());
+        assert_eq!(data[..len], fs::read(root.join("t/a")).unwrap()[..len]);
+
+        // Half of the last piece, kept across a restart
+        storage
+            .write_sub_piece(1, BLOCK_SIZE, &data[len + BLOCK_SIZE..])
+            .await
+            .unwrap();
+        assert_eq!(100, storage.dirty_bytes());
+        assert!(storage.write_sub_piece(1, usize::MAX, &[1]).await.is_err());
+        let resume = storage.resume_data().await.unwrap();
+        assert_eq!(0, storage.dirty_bytes());
+        assert_eq!(
+            vec![UnfinishedPiece {
+                index: 1,
+                blocks: vec![false, true]
+            }],
+            resume.unfinished
+        );
+        assert!(!root.join("t/b").exists());
+        drop(storage);
+
+        let mut storage = MultiFileStorage::new(root, layout).unwrap();
+        assert!(storage.apply_resume(&resume).await.unwrap());
+        assert!(storage.is_verified(0));
+        storage
+            .write_sub_piece(1, 0, &data[len..len + BLOCK_SIZE])
+            .await
+            .unwrap();
+        assert!(storage.is_piece_complete(1));
+        assert!(storage.commit_piece(1, &hash(1)).await.unwrap());
+        assert!(storage.parts().is_empty());
+        assert_eq!(
+            data[len + BLOCK_SIZE..],
+            fs::read(root.join("t/b")).unwrap()
+        );
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn storage_config() {
+        const ROOT: &str = "./test_storage_storage_config";
+        let dirs = StorageDirs::new(ROOT);
+
+        // Four pieces of one block over two files
+        let layout = Layout::new(
+            BLOCK_SIZE,
+            vec![
+                (PathBuf::from("t/a"), 3 * BLOCK_SIZE as u64),
+                (PathBuf::from("t/b"), BLOCK_SIZE as u64),
+            ],
+        );
+        let data: Vec<u8> = (0..4 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+        let hash = |index: usize| -> PieceHash {
+            sha1(&data[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE]).into()
+        };
+
+        let config = StorageConfig {
+            journal: true,
+            ..Default::default()
+        };
+        let res = MultiFileStorage::with_config(dirs.clone(), layout.clone(), config);
+        assert!(matches!(res, Err(StorageError::Io(e)) if e.kind() == io::ErrorKind::Unsupported));
+
+        // Pieces go to the sink, blocks are saved once a piece worth arrived
+        let (sink, mut receiver) = PieceSink::channel(4);
+        let config = StorageConfig {
+            sink: Some(sink),
+            flush: FlushPolicy {
+                every_bytes: Some(BLOCK_SIZE),
+                ..Default::default()
+            },
+            read_ahead: 2,
+            ..Default::default()
+        };
+        let mut storage =
+            MultiFileStorage::with_config(dirs.clone(), layout.clone(), config).unwrap();
+        storage
+            .write_sub_piece(0, 0, &data[..BLOCK_SIZE / 2])
+            .await
+            .unwrap();
+        assert!(!storage.parts().contains(0));
+        storage
+            .write_sub_piece(0, BLOCK_SIZE / 2, &data[BLOCK_SIZE / 2..BLOCK_SIZE])
+            .await
+            .unwrap();
+        assert!(storage.parts().contains(0));
+        for index in 0..4 {
+            let piece = &data[index * BLOCK_SIZE..(index + 1) * BLOCK_SIZE];
+            storage.write_sub_piece(index, 0, piece).await.unwrap();
+            assert!(storage.commit_piece(index, &hash(index)).await.unwrap());
+            assert_eq!(piece, &receiver.recv().await.unwrap().data[..]);
+        }
+
+        // Sequential reads prefetch the next pieces
+        assert_eq!(data[..16], storage.read_block(0, 0, 16).await.unwrap());
+        assert_eq!(0, storage.prefetching());
+        assert_eq!(
+            data[BLOCK_SIZE..BLOCK_SIZE + 16],
+            storage.read_block(1, 0, 16).await.unwrap()
+        );
+        assert_eq!(2, storage.prefetching());
+        drop(storage);
+
+        // Start over, delivering pieces without writing them
+        let (sink, mut receiver) = PieceSink::channel(1);
+        let config = StorageConfig {
+            sink: Some(sink.without_disk()),
+            truncate_existing: true,
+            chunked_hashing: true,
+            ..Default::default()
+        };
+        let mut storage = MultiFileStorage::with_config(dirs, layout, config).unwrap();
+        assert!(!Path::new(ROOT).join("t/a").exists());
+        assert!(!storage.verify_piece(3, &hash(3)).await.unwrap());
+        storage
+            .write_sub_piece(3, 0, &data[3 * BLOCK_SIZE..])
+            .await
+            .unwrap();
+        assert!(storage.commit_piece(3, &hash(3)).await.unwrap());
+        assert_eq!(3, receiver.recv().await.unwrap().index);
+        assert!(storage.is_verified(3));
+        assert_eq!(
+            vec![0; BLOCK_SIZE],
+            fs::read(Path::new(ROOT).join("t/b")).unwrap()
+        );
+        assert!(matches!(
+            storage.read_block(3, 0, 16).await,
+            Err(StorageError::NotLoaded(3))
+        ));
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_attributes() {
+        const ROOT: &str = "./test_storage_file_attributes";
+        let root = Path::new(ROOT);
+
+        // The padding aligns `dir/b` on the second piece
+        let mut layout = Layout::new(
+            4,
+            vec![
+                (PathBuf::from("dir/a"), 3),
+                (PathBuf::from("dir/.pad/1"), 1),
+                (PathBuf::from("dir/b"), 4),
+                (PathBuf::from("dir/sub/link"), 0),
+            ],
+        );
+        let padding = FileAttr {
+            padding: true,
+            ..Default::default()
+        };
+        layout.set_attr(1, padding);
+        let executable = FileAttr {
+            executable: true,
+            ..Default::default()
+        };
+        layout.set_attr(2, executable);
+        let symlink = FileAttr {
+            symlink: Some(PathBuf::from("b")),
+            ..Default::default()
+        };
+        layout.set_attr(3, symlink);
+
+        let mut storage = MultiFileStorage::new(ROOT, layout).unwrap();
+        storage.write_block(0, 0, &[1, 2, 3, 9]).await.unwrap();
+        storage.write_block(1, 0, &[4, 5, 6, 7]).await.unwrap();
+        assert_eq!(vec![1, 2, 3, 0], storage.read_block(0, 0, 4).await.unwrap());
+        assert!(!root.join("dir/.pad").exists());
+
+        storage.move_to_complete().unwrap();
+        // Applying twice is harmless
+        storage.move_to_complete().unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(root.join("dir/b"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(0o111, mode & 0o111);
+            assert_eq!(
+                Path::new("../b"),
+                fs::read_link(root.join("dir/sub/link")).unwrap()
+            );
+            assert_eq!(
+                vec![4, 5, 6, 7],
+                fs::read(root.join("dir/sub/link")).unwrap()
+            );
+        }
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    // Goes through the trait only, like a torrent backed by a custom store
+    async fn download<S: TorrentStorage>(storage: &mut S, data: &[u8], piece_size: usize) {
+        assert_eq!(data.len() as u64, storage.len());
+        for (index, chunk) in data.chunks(piece_size).enumerate() {
+            storage.write_block(index, 0, chunk).await.unwrap();
+            assert!(storage
+                .verify_piece(index, &sha1(chunk).into())
+                .await
+                .unwrap());
+            assert_eq!(
+                chunk,
+                storage.read_block(index, 0, chunk.len()).await.unwrap()
+            );
+        }
+        assert!(!storage.verify_piece(0, &[0; 20].into()).await.unwrap());
+        storage.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn storage_trait() {
+        const ROOT: &str = "./test_storage_trait";
+        const FILE: &str = "./test_storage_trait_file";
+        let data: Vec<u8> = (0..100).collect();
+
+        let layout = Layout::new(16, vec![(PathBuf::from("a"), 40), (PathBuf::from("b"), 60)]);
+        let mut storage = MultiFileStorage::new(ROOT, layout).unwrap();
+        download(&mut storage, &data, 16).await;
+        drop(storage);
+        assert_eq!(data[40..], fs::read(Path::new(ROOT).join("b")).unwrap());
+        fs::remove_dir_all(ROOT).unwrap();
+
+        let mut file = crate::file::FileEntity::new(FILE, 16, 100).unwrap();
+        download(&mut file, &data, 16).await;
+        drop(file);
+        assert_eq!(data, fs::read(FILE).unwrap());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_md5() {
+        const ROOT: &str = "./test_storage_file_md5";
+        let data: Vec<u8> = (0..30).collect();
+        let md5 = |d: &[u8]| -> String {
+            Md5::digest(d)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        };
+
+        let mut layout = Layout::new(8, vec![(PathBuf::from("a"), 10), (PathBuf::from("b"), 20)]);
+        layout.set_md5sum(0, Some(md5(&data[..10])));
+        layout.set_md5sum(1, Some(md5(&data[..20])));
+
+        let mut storage = MultiFileStorage::new(ROOT, layout).unwrap();
+        storage.set_check_md5(true);
+        for (index, chunk) in data.chunks(8).enumerate() {
+            storage.write_block(index, 0, chunk).await.unwrap();
+            assert!(storage
+                .verify_piece(index, &sha1(chunk).into())
+                .await
+                .unwrap());
+            if index == 0 {
+                assert!(storage.take_events().is_empty());
+            }
+        }
+
+        // The wrong MD5 of b is reported even though every piece is valid
+        assert_eq!(
+            vec![
+                StorageEvent::FileCompleted { file: 0 },
+                StorageEvent::FileVerified {
+                    file: 0,
+                    valid: true
+                },
+                StorageEvent::FileCompleted { file: 1 },
+                StorageEvent::FileVerified {
+                    file: 1,
+                    valid: false
+                },
+            ],
+            storage.take_events()
+        );
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn limit_open_files() {
+        const ROOT: &str = "./test_storage_limit_open_files";
+        let files = (0..10)
+            .map(|i| (PathBuf::from(format!("{}", i)), 3))
+            .collect();
+        let data: Vec<u8> = (0..30).collect();
+
+        let mut storage = MultiFileStorage::new(ROOT, Layout::new(8, files)).unwrap();
+        storage.set_max_open_files(2);
+        for (index, chunk) in data.chunks(8).enumerate() {
+            storage.write_block(index, 0, chunk).await.unwrap();
+            assert!(storage.open_files() <= 2);
+        }
+        for (index, chunk) in data.chunks(8).enumerate() {
+            assert_eq!(
+                chunk,
+                storage.read_block(index, 0, chunk.len()).await.unwrap()
+            );
+        }
+        assert_eq!(2, storage.open_files());
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+
+    #[tokio::test]
+    async fn insufficient_space() {
+        const FILE: &str = "./test_storage_insufficient_space";
+
+        assert!(available_space("./no/such/dir")
+            .unwrap()
+            .is_some_and(|n| n > 0));
+        assert!(check_space(".", 1).is_ok());
+
+        let res = crate::file::FileEntity::new(FILE, 1 << 20, usize::MAX >> 1);
+        assert!(matches!(res, Err(StorageError::InsufficientSpace { .. })));
+        assert!(res.unwrap_err().is_disk_full());
+        assert!(!Path::new(FILE).exists());
+    }
+
+    #[tokio::test]
+    async fn background_flusher() {
+        const FILE: &str = "./test_storage_background_flusher";
+
+        let file = crate::file::FileEntity::new(FILE, 4 * BLOCK_SIZE, 4 * BLOCK_SIZE).unwrap();
+        let storage = Arc::new(Mutex::new(file));
+        let config = FlusherConfig {
+            max_dirty_bytes: 3 * BLOCK_SIZE as u64,
+            check_every: Duration::from_millis(10),
+            ..FlusherConfig::default()
+        };
+        let flusher = spawn_flusher(storage.clone(), config);
+
+        // Under the threshold, kept in memory
+        let block = vec![1u8; BLOCK_SIZE + 1];
+        storage
+            .lock()
+            .await
+            .write_block(0, 0, &block)
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            2 * BLOCK_SIZE as u64,
+            TorrentStorage::dirty_bytes(&*storage.lock().await)
+        );
+        storage
+            .lock()
+            .await
+            .write_block(0, 3 * BLOCK_SIZE, &block[1..])
+            .await
+            .unwrap();
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(0, TorrentStorage::dirty_bytes(&*storage.lock().await));
+        assert_eq!(block, fs::read(FILE).unwrap()[..BLOCK_SIZE + 1]);
+
+        drop(storage);
+        assert!(flusher.await.unwrap().is_ok());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rename_and_relocate() {
+        const ROOT: &str = "./test_storage_rename_and_relocate";
+        let root = Path::new(ROOT);
+
+        // Pieces of 4: |aaab|b
+        let layout = Layout::new(
+            4,
+            vec![(PathBuf::from("t/a"), 3), (PathBuf::from("t/b"), 2)],
+        );
+        let mut storage = MultiFileStorage::new(root.join("one"), layout).unwrap();
+        storage.set_wanted(0, false).await.unwrap();
+        storage.write_block(0, 0, &[1, 2, 3, 4]).await.unwrap();
+        storage.write_block(1, 0, &[5]).await.unwrap();
+
+        storage.rename_file(1, "t/sub/b").unwrap();
+        assert!(root.join("one/t/sub/b").exists());
+        assert!(!root.join("one/t/b").exists());
+
+        storage.rename_root("u").unwrap();
+        assert_eq!(root.join("one/u/sub/b"), storage.file_path(1));
+        assert!(parts_path(root.join("one"), storage.layout()).exists());
+
+        storage.move_storage(root.join("two")).unwrap();
+        // The first byte of b is in the parts file with piece 0
+        assert_eq!(vec![0, 5], fs::read(root.join("two/u/sub/b")).unwrap());
+        assert!(!root.join("one/u/sub/b").exists());
+        assert_eq!(vec![1, 2, 3, 4], storage.read_block(0, 0, 4).await.unwrap());
+
+        // Nothing is overwritten, and nothing moves
+        fs::create_dir_all(root.join("three/u/sub")).unwrap();
+        fs::write(root.join("three/u/sub/b"), b"other").unwrap();
+        let res = storage.move_storage(root.join("three"));
+        assert!(matches!(res, Err(StorageError::AlreadyExists)));
+        assert_eq!(root.join("two/u/sub/b"), storage.file_path(1));
+        assert!(parts_path(root.join("two"), storage.layout()).exists());
+        assert_eq!(vec![1, 2, 3, 4], storage.read_block(0, 0, 4).await.unwrap());
+
+        drop(storage);
+        fs::remove_dir_all(ROOT).unwrap();
+    }
+}
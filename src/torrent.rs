@@ -0,0 +1,2082 @@
+// A torrent being downloaded or seeded. It owns the storage shared by its
+// peers, hears what happens on their connections and has the scheduler
+// decide what to ask them.
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bendy::decoding::FromBencode;
+use bytes::Bytes;
+use tokio::{
+    io::AsyncWriteExt,
+    net::TcpStream,
+    sync::{mpsc, oneshot, watch, Mutex, RwLock},
+};
+
+use crate::availability::{Availability, SharedAvailability};
+use crate::capture::Capture;
+use crate::decode_torrent::{self, MetaInfo, MetaInfoError};
+use crate::definitions::{self, InfoHash, PeerId};
+use crate::dialer::Dialer;
+use crate::error;
+use crate::events::{Event, EventSender};
+use crate::extension::ExtensionHandshake;
+use crate::fairness::{
+    ConnectionLimit, ConnectionSlot, Demands, RateBudget, Shares, DEFAULT_WEIGHT,
+};
+use crate::file::{SharedFile, Storage, StorageConfig};
+#[cfg(feature = "geoip")]
+use crate::geoip::{GeoIp, PeerLocation};
+use crate::handshake::Handshake;
+use crate::layout::Layout;
+use crate::listener;
+use crate::peer::{self, Peer, PeerError, PeerEvent, PeerEventSender, PeerSource};
+use crate::priority::Priority;
+use crate::profile::ClientProfile;
+use crate::reader::{FileReader, PieceRequest};
+use crate::recheck::RecheckHandle;
+use crate::scheduler::{self, Request, Scheduler};
+use crate::stats::{self, DiskStats, RateMeter, TorrentStats, TransferTotals};
+use crate::storage::StorageDirs;
+use crate::tracker::{AnnounceCounters, TrackerError, UdpConnection};
+use crate::web_seed::{WebSeed, WebSeedStats, WEB_SEED_REQUESTS};
+
+/// Peers asked from the tracker on each announce.
+pub const ANNOUNCE_NUM_WANT: u32 = 50;
+/// Time between announces of a running torrent, unless the tracker asks
+/// for another interval.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(300);
+pub use crate::tracker::DEFAULT_TRACKER_BIND;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TorrentState {
+    Stopped,
+    Running,
+    // Peers stay connected but nothing is requested
+    Paused,
+    // Waiting for a session to have a slot to run it
+    Queued,
+}
+
+pub struct Torrent {
+    meta: Arc<MetaInfo>,
+    info_hash: InfoHash,
+    peer_id: PeerId,
+    file: SharedFile,
+    scheduler: Scheduler,
+    availability: SharedAvailability,
+    peers: HashMap<SocketAddr, Arc<RwLock<Peer>>>,
+    // From the url-list and httpseeds, scheduled along with the peers
+    web_seeds: Vec<WebSeed>,
+    // Slots of the peers in the connections of the session, freed with them
+    connection_slots: HashMap<SocketAddr, ConnectionSlot>,
+    connection_limit: ConnectionLimit,
+    dialer: Dialer,
+    // Peers being dialed, added by `step` once handshaked
+    dialing: HashSet<SocketAddr>,
+    dialed: mpsc::UnboundedSender<Dialed>,
+    dialed_receiver: mpsc::UnboundedReceiver<Dialed>,
+    events: PeerEventSender,
+    receiver: mpsc::UnboundedReceiver<(SocketAddr, PeerEvent)>,
+    // Pieces wanted by the readers of `open_file`, told of verified pieces
+    read_requests: mpsc::UnboundedSender<PieceRequest>,
+    read_receiver: mpsc::UnboundedReceiver<PieceRequest>,
+    verified: watch::Sender<()>,
+    // Subscribers to what happens to the torrent, e.g. those of a session
+    subscribers: Option<EventSender>,
+    state: TorrentState,
+    download: RateMeter,
+    upload: RateMeter,
+    // Peers from the tracker or which we were connected to
+    known: HashSet<SocketAddr>,
+    // Seeds and leechers, as last announced
+    swarm: Option<(u32, u32)>,
+    // Cleared by a successful announce
+    tracker_error: Option<String>,
+    // When the next announce is due, now if unset
+    next_announce: Option<Instant>,
+    announcing: Option<oneshot::Receiver<Result<AnnounceResponse, TrackerError>>>,
+    piece_failures: u64,
+    // Endgame duplicates to withdraw on the next step
+    cancels: Vec<Request>,
+    disk_stats: Arc<DiskStats>,
+    // Local address announces are sent from
+    tracker_bind: SocketAddr,
+    // Port peers reach us on, given to the tracker
+    listen_port: u16,
+    // Identity of the torrent, instead of the one of its session
+    profile: Option<ClientProfile>,
+    // Connected peers, more are refused
+    max_peers: Option<usize>,
+    // Part of the limits of a session, relative to the other torrents
+    weight: u32,
+    // Shares of those limits, unlimited outside of a session
+    upload_budget: Arc<RateBudget>,
+    download_budget: RateBudget,
+    shares: Shares,
+    seed_limits: SeedLimits,
+    // Time spent running with every wanted piece
+    seed_time: Duration,
+    last_step: Instant,
+    // Records the messages of its peers and tracker
+    capture: Option<Capture>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<GeoIp>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SeedLimitAction {
+    // Peers stay connected, e.g. to resume later
+    #[default]
+    Pause,
+    Stop,
+}
+
+/// When a finished torrent stops seeding, once any limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct SeedLimits {
+    // Uploaded bytes over downloaded ones
+    pub ratio: Option<f64>,
+    pub time: Option<Duration>,
+    pub action: SeedLimitAction,
+}
+
+/// What a tracker answered to an announce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnnounceResponse {
+    pub peers: Vec<SocketAddr>,
+    pub seeds: u32,
+    pub leechers: u32,
+    // Zero if the tracker didn't say
+    pub interval: Duration,
+}
+
+// A peer dialed in the background, with the result of its handshake
+type Dialed = (
+    SocketAddr,
+    PeerSource,
+    Result<(TcpStream, Handshake), PeerError>,
+);
+
+// Host and port of a `udp://host:port/announce` tracker
+fn udp_tracker(announce: &str) -> Option<&str> {
+    let rest = announce.strip_prefix("udp://")?;
+    Some(rest.split('/').next().unwrap_or(rest))
+}
+
+/// Ask the tracker at `announce` for peers of `info_hash`, e.g. before the
+/// metadata of a magnet link is known, from the local address `bind`, for
+/// peers to connect to `port`. Only UDP trackers are supported. The
+/// exchange is recorded to `capture` if given.
+pub async fn announce(
+    announce: &str,
+    info_hash: &InfoHash,
+    peer_id: &PeerId,
+    bind: SocketAddr,
+    port: u16,
+    counters: AnnounceCounters,
+    capture: Option<&Capture>,
+) -> Result<AnnounceResponse, TrackerError> {
+    let tracker =
+        udp_tracker(announce).ok_or_else(|| TrackerError::Unsupported(announce.to_string()))?;
+    let mut conn = UdpConnection::bind(tracker, bind, None).await?;
+    conn.set_capture(capture.cloned());
+    conn.connect().await?;
+
+    let hash = decode_torrent::bytes_to_hash(info_hash);
+    let res = conn
+        .announce_with(
+            &hash,
+            Some(peer_id),
+            Some(ANNOUNCE_NUM_WANT),
+            port,
+            counters,
+        )
+        .await?;
+    let peers = res
+        .get_peers()
+        .map(|peers| {
+            peers
+                .iter()
+                .filter_map(|p| p.to_socket_addrs().ok()?.next())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(AnnounceResponse {
+        peers,
+        seeds: res.seeders(),
+        leechers: res.leechers(),
+        interval: Duration::from_secs(res.interval().into()),
+    })
+}
+
+impl Torrent {
+    /// Torrent stored in the current directory under its name.
+    pub async fn new(meta: MetaInfo, info_hash: InfoHash) -> error::Result<Self> {
+        Torrent::with_save_path(meta, info_hash, ".").await
+    }
+
+    /// Torrent stored in `save_path` under its name. Data already there is
+    /// rechecked and resumed from.
+    pub async fn with_save_path<P: AsRef<Path>>(
+        meta: MetaInfo,
+        info_hash: InfoHash,
+        save_path: P,
+    ) -> error::Result<Self> {
+        let dirs = StorageDirs::new(save_path);
+        let mut file = Storage::open(&meta.info, dirs, StorageConfig::default())?;
+        if file.needs_recheck() {
+            file.recheck(&meta.piece_hashes()?).await?;
+        }
+        Ok(Torrent::with_file(meta, info_hash, file))
+    }
+
+    /// Decode a `.torrent` file.
+    pub async fn from_bytes(torrent: &[u8]) -> error::Result<Self> {
+        let meta = MetaInfo::from_bencode(torrent).map_err(MetaInfoError::from)?;
+        Torrent::new(meta, decode_torrent::get_info_hash(torrent)?).await
+    }
+
+    pub fn with_file(meta: MetaInfo, info_hash: InfoHash, file: impl Into<Storage>) -> Self {
+        let file = file.into();
+        let mut scheduler = Scheduler::new(file.piece_size(), file.size());
+        scheduler.set_have(file.get_bitfield());
+        let (events, receiver) = mpsc::unbounded_channel();
+        let (read_requests, read_receiver) = mpsc::unbounded_channel();
+        let (dialed, dialed_receiver) = mpsc::unbounded_channel();
+        let disk_stats = file.disk_stats().clone();
+        let web_seeds = WebSeed::for_torrent(&meta, info_hash);
+
+        Torrent {
+            availability: Availability::shared(scheduler.num_pieces()),
+            meta: Arc::new(meta),
+            info_hash,
+            peer_id: definitions::new_peer_id(),
+            file: Arc::new(Mutex::new(file)),
+            scheduler,
+            peers: HashMap::new(),
+            web_seeds,
+            connection_slots: HashMap::new(),
+            connection_limit: ConnectionLimit::default(),
+            dialer: Dialer::default(),
+            dialing: HashSet::new(),
+            dialed,
+            dialed_receiver,
+            events,
+            receiver,
+            read_requests,
+            read_receiver,
+            verified: watch::channel(()).0,
+            subscribers: None,
+            state: TorrentState::Stopped,
+            download: RateMeter::new(Instant::now()),
+            upload: RateMeter::new(Instant::now()),
+            known: HashSet::new(),
+            swarm: None,
+            tracker_error: None,
+            next_announce: None,
+            announcing: None,
+            piece_failures: 0,
+            cancels: Vec::new(),
+            disk_stats,
+            tracker_bind: DEFAULT_TRACKER_BIND,
+            listen_port: 0,
+            profile: None,
+            max_peers: None,
+            weight: DEFAULT_WEIGHT,
+            upload_budget: Arc::default(),
+            download_budget: RateBudget::default(),
+            shares: Shares::default(),
+            seed_limits: SeedLimits::default(),
+            seed_time: Duration::ZERO,
+            last_step: Instant::now(),
+            capture: None,
+            #[cfg(feature = "geoip")]
+            geoip: None,
+        }
+    }
+
+    pub fn meta(&self) -> &MetaInfo {
+        &self.meta
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn file(&self) -> &SharedFile {
+        &self.file
+    }
+
+    /// Hash every piece on disk again, what is missing is downloaded.
+    /// Returns the number of valid pieces.
+    pub async fn recheck(&mut self) -> error::Result<usize> {
+        let handle = RecheckHandle::unlimited(self.scheduler.num_pieces());
+        self.recheck_with(&handle).await
+    }
+
+    /// [`Torrent::recheck`] waiting for its turn in the `RecheckScheduler` of
+    /// `handle`, which also reports its progress and can pause it.
+    pub async fn recheck_with(&mut self, handle: &RecheckHandle) -> error::Result<usize> {
+        let hashes = self.meta.piece_hashes()?;
+        let mut file = self.file.lock().await;
+        let valid = file.recheck_with(&hashes, handle).await?;
+        self.scheduler.set_have(file.get_bitfield());
+        Ok(valid)
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn scheduler_mut(&mut self) -> &mut Scheduler {
+        &mut self.scheduler
+    }
+
+    pub fn availability(&self) -> &SharedAvailability {
+        &self.availability
+    }
+
+    pub fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+
+    /// Peer ID sent in handshakes and announces.
+    pub fn set_peer_id(&mut self, peer_id: PeerId) {
+        self.peer_id = peer_id;
+    }
+
+    /// Dialer for outbound connections, e.g. one shared by a session.
+    pub fn set_dialer(&mut self, dialer: Dialer) {
+        self.dialer = dialer;
+    }
+
+    /// Take a slot of `limit` for each peer, those of a session share it.
+    pub fn set_connection_limit(&mut self, limit: ConnectionLimit) {
+        self.connection_limit = limit;
+    }
+
+    /// Record the messages exchanged with the peers connected from now on
+    /// and with the tracker, see `crate::capture`.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref()
+    }
+
+    pub fn set_tracker_bind(&mut self, bind: SocketAddr) {
+        self.tracker_bind = bind;
+    }
+
+    /// Port announced to the tracker, 0 lets it use the one announces are
+    /// sent from.
+    pub fn set_listen_port(&mut self, port: u16) {
+        self.listen_port = port;
+    }
+
+    /// Present the torrent as another client to its tracker and peers, with
+    /// a new peer ID. Kept when added to a session.
+    pub fn set_profile(&mut self, profile: ClientProfile) {
+        self.peer_id = profile.peer_id();
+        self.profile = Some(profile);
+    }
+
+    pub fn profile(&self) -> Option<&ClientProfile> {
+        self.profile.as_ref()
+    }
+
+    /// Our extension handshake for the peer at `addr`, with the version of
+    /// the torrent's profile.
+    pub fn extension_handshake(&self, addr: SocketAddr) -> ExtensionHandshake {
+        let port = Some(self.listen_port).filter(|&port| port != 0);
+        let mut res = ExtensionHandshake::ours(port, Some(addr.ip()), None);
+        if let Some(profile) = &self.profile {
+            res.v = Some(profile.version.clone());
+        }
+        res
+    }
+
+    /// Refuse peers beyond `max`, `None` for no limit.
+    pub fn set_max_peers(&mut self, max: Option<usize>) {
+        self.max_peers = max;
+    }
+
+    pub fn max_peers(&self) -> Option<usize> {
+        self.max_peers
+    }
+
+    /// Part of the limits of a session the torrent gets, relative to the
+    /// weights of the others. A weight of 0 only gets the minimum share.
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// What the torrent would use of the limits of a session.
+    pub fn demands(&self) -> Demands {
+        let interested = self
+            .peers
+            .values()
+            .filter(|p| p.try_read().is_ok_and(|p| p.peer_interested()))
+            .count();
+        let connections = self.known.len().max(self.peers.len());
+        Demands {
+            upload: self.upload_budget.demand(),
+            download: self.download_budget.demand(),
+            upload_slots: interested,
+            connections: self
+                .max_peers
+                .map_or(connections, |max| connections.min(max)),
+        }
+    }
+
+    /// Limit the torrent to `shares` until the next ones. Peers beyond the
+    /// connection share are refused but those connected stay.
+    pub fn set_shares(&mut self, shares: Shares) {
+        self.upload_budget.refill(shares.upload);
+        self.download_budget.refill(shares.download);
+        self.shares = shares;
+    }
+
+    pub fn shares(&self) -> Shares {
+        self.shares
+    }
+
+    pub fn set_seed_limits(&mut self, limits: SeedLimits) {
+        self.seed_limits = limits;
+    }
+
+    pub fn seed_limits(&self) -> SeedLimits {
+        self.seed_limits
+    }
+
+    /// Carry over the totals of a previous run, e.g. from the saved state of
+    /// a session.
+    pub fn restore_totals(&mut self, downloaded: u64, uploaded: u64, seed_time: Duration) {
+        self.download.restore(downloaded);
+        self.upload.restore(uploaded);
+        self.seed_time = seed_time;
+    }
+
+    /// Bytes transferred over the lifetime of the torrent, previous runs
+    /// included.
+    pub fn totals(&self) -> TransferTotals {
+        TransferTotals {
+            downloaded: self.download.total(),
+            uploaded: self.upload.total(),
+        }
+    }
+
+    /// What the tracker is told in announces, the same as `stats`.
+    pub fn counters(&self) -> AnnounceCounters {
+        let totals = self.totals();
+        AnnounceCounters {
+            downloaded: totals.downloaded,
+            left: self.scheduler.bytes_left(),
+            uploaded: totals.uploaded,
+        }
+    }
+
+    /// Uploaded bytes over downloaded ones, or over the bytes we have when
+    /// the content was already there.
+    pub fn ratio(&self) -> f64 {
+        match self.download.total().max(self.scheduler.bytes_done()) {
+            0 => 0.0,
+            n => self.upload.total() as f64 / n as f64,
+        }
+    }
+
+    pub fn seed_time(&self) -> Duration {
+        self.seed_time
+    }
+
+    fn seed_limit_reached(&self) -> bool {
+        let limits = &self.seed_limits;
+        limits.ratio.is_some_and(|r| self.ratio() >= r)
+            || limits.time.is_some_and(|t| self.seed_time >= t)
+    }
+
+    /// Report what happens to the torrent to `sender`.
+    pub fn set_event_sender(&mut self, sender: EventSender) {
+        self.subscribers = Some(sender);
+    }
+
+    fn emit(&self, event: Event) {
+        if let Some(subscribers) = &self.subscribers {
+            // Nobody subscribed yet
+            let _ = subscribers.send(event);
+        }
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state
+    }
+
+    pub fn start(&mut self) {
+        self.state = TorrentState::Running;
+    }
+
+    pub fn pause(&mut self) {
+        self.state = TorrentState::Paused;
+    }
+
+    /// Wait for a slot, nothing is requested meanwhile.
+    pub fn queue(&mut self) {
+        self.state = TorrentState::Queued;
+    }
+
+    /// Disconnect every peer.
+    pub async fn stop(&mut self) {
+        self.state = TorrentState::Stopped;
+        // Announced again once started
+        self.next_announce = None;
+        self.announcing = None;
+        self.connection_slots.clear();
+        for (addr, peer) in self.peers.drain() {
+            self.scheduler.remove_peer(addr);
+            // The peer's tasks end once the remote closes its side
+            let _ = peer.write().await.get_stream_mut().shutdown().await;
+        }
+    }
+
+    /// Fraction of the pieces verified, between 0 and 1.
+    pub fn progress(&self) -> f64 {
+        match self.scheduler.num_pieces() {
+            0 => 1.0,
+            n => (0..n).filter(|&i| self.scheduler.has_piece(i)).count() as f64 / n as f64,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.scheduler.is_finished()
+    }
+
+    /// Snapshot of the transfer, rates are updated by `step`.
+    pub fn stats(&self) -> TorrentStats {
+        let now = Instant::now();
+        let bytes_remaining = self.scheduler.bytes_left();
+        TorrentStats {
+            bytes_done: self.scheduler.bytes_done(),
+            bytes_remaining,
+            progress: self.progress(),
+            downloaded: self.download.total(),
+            uploaded: self.upload.total(),
+            session_downloaded: self.download.session_total(),
+            session_uploaded: self.upload.session_total(),
+            download_rate: self.download.rate(),
+            upload_rate: self.upload.rate(),
+            average_download_rate: self.download.average(now),
+            average_upload_rate: self.upload.average(now),
+            eta: stats::eta(bytes_remaining, self.download.rate()),
+            connected_peers: self.peers.len(),
+            known_peers: self.known.len(),
+            seeds: self.swarm.map(|(seeds, _)| seeds),
+            leechers: self.swarm.map(|(_, leechers)| leechers),
+            ratio: self.ratio(),
+            seed_time: self.seed_time,
+            piece_failures: self.piece_failures,
+            tracker_error: self.tracker_error.clone(),
+            disk: self.disk_stats.snapshot(),
+        }
+    }
+
+    /// Addresses of the connected peers.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut res: Vec<SocketAddr> = self.peers.keys().copied().collect();
+        res.sort_unstable();
+        res
+    }
+
+    pub fn web_seeds(&self) -> Vec<WebSeedStats> {
+        self.web_seeds.iter().map(WebSeed::stats).collect()
+    }
+
+    pub fn peer(&self, addr: SocketAddr) -> Option<&Arc<RwLock<Peer>>> {
+        self.peers.get(&addr)
+    }
+
+    /// Databases the peers are located with, see `peer_location`.
+    #[cfg(feature = "geoip")]
+    pub fn set_geoip(&mut self, geoip: Option<Arc<GeoIp>>) {
+        self.geoip = geoip;
+    }
+
+    /// Country and autonomous system of a peer, connected or not.
+    #[cfg(feature = "geoip")]
+    pub fn peer_location(&self, addr: SocketAddr) -> Option<PeerLocation> {
+        self.geoip.as_ref()?.lookup_addr(addr)
+    }
+
+    /// Seeds and leechers as estimated without the tracker, e.g. by the DHT.
+    pub fn set_swarm(&mut self, seeds: u32, leechers: u32) {
+        self.swarm = Some((seeds, leechers));
+    }
+
+    /// Ask the tracker for peers. Only UDP trackers are supported.
+    pub async fn announce(&mut self) -> Result<Vec<SocketAddr>, TrackerError> {
+        let res = announce(
+            &self.meta.announce,
+            &self.info_hash,
+            &self.peer_id,
+            self.tracker_bind,
+            self.listen_port,
+            self.counters(),
+            self.capture.as_ref(),
+        );
+        let res = res.await;
+        self.announced(res)
+    }
+
+    fn announced(
+        &mut self,
+        res: Result<AnnounceResponse, TrackerError>,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        match res {
+            Ok(res) => {
+                self.add_known_peers(res.peers.iter().copied(), PeerSource::Tracker);
+                self.swarm = Some((res.seeds, res.leechers));
+                self.tracker_error = None;
+                if !res.interval.is_zero() {
+                    self.next_announce = Some(Instant::now() + res.interval);
+                }
+                Ok(res.peers)
+            }
+            Err(e) => {
+                self.tracker_error = Some(e.to_string());
+                self.emit(Event::TrackerError {
+                    info_hash: self.info_hash,
+                    message: e.to_string(),
+                });
+                Err(e)
+            }
+        }
+    }
+
+    // Announce in the background when it is due, and dial the peers the
+    // tracker answered with
+    fn step_announce(&mut self, now: Instant) {
+        if let Some(receiver) = &mut self.announcing {
+            let res = match receiver.try_recv() {
+                Ok(res) => res,
+                Err(oneshot::error::TryRecvError::Empty) => return,
+                Err(oneshot::error::TryRecvError::Closed) => {
+                    Err(io::Error::other("Announce interrupted").into())
+                }
+            };
+            self.announcing = None;
+            if let Ok(peers) = self.announced(res) {
+                self.dial(peers, PeerSource::Tracker);
+            }
+        }
+        let due = self.next_announce.is_none_or(|at| at <= now);
+        if self.state != TorrentState::Running || self.meta.announce.is_empty() || !due {
+            return;
+        }
+        self.next_announce = Some(now + ANNOUNCE_INTERVAL);
+        let (sender, receiver) = oneshot::channel();
+        let (url, info_hash, peer_id) = (self.meta.announce.clone(), self.info_hash, self.peer_id);
+        let (bind, port, counters) = (self.tracker_bind, self.listen_port, self.counters());
+        let capture = self.capture.clone();
+        tokio::spawn(async move {
+            let res = announce(
+                &url,
+                &info_hash,
+                &peer_id,
+                bind,
+                port,
+                counters,
+                capture.as_ref(),
+            );
+            let _ = sender.send(res.await);
+        });
+        self.announcing = Some(receiver);
+    }
+
+    /// Priorities of the files of the torrent, in the order of the metadata.
+    pub fn set_file_priorities(&mut self, files: &[Priority]) -> io::Result<()> {
+        let layout = Layout::from_info(&self.meta.info)?;
+        if files.len() != layout.files().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Not a priority for each file",
+            ));
+        }
+        self.scheduler.set_file_priorities(&layout, files);
+        Ok(())
+    }
+
+    /// Rename file `index`, its new path being relative to the save path.
+    /// Blocks are neither read nor written meanwhile.
+    pub async fn rename_file<P: AsRef<Path>>(&self, index: usize, path: P) -> error::Result<()> {
+        Ok(self.file.lock().await.rename_file(index, path)?)
+    }
+
+    /// Rename the directory of the torrent, or its file.
+    pub async fn rename_root(&self, name: &str) -> error::Result<()> {
+        Ok(self.file.lock().await.rename_root(name)?)
+    }
+
+    /// Move the files to `save_path`.
+    pub async fn move_storage<P: AsRef<Path>>(&self, save_path: P) -> error::Result<()> {
+        Ok(self.file.lock().await.move_storage(save_path)?)
+    }
+
+    /// Read file `index` of the torrent while it downloads. The pieces under
+    /// the read position go first, even those of skipped files.
+    pub fn open_file(&self, index: usize) -> io::Result<FileReader> {
+        let layout = Layout::from_info(&self.meta.info)?;
+        let file = layout.files().get(index).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "No such file in the torrent")
+        })?;
+        Ok(FileReader::new(
+            self.file.clone(),
+            layout.piece_size(),
+            (file.offset, file.length),
+            self.read_requests.clone(),
+            self.verified.subscribe(),
+        ))
+    }
+
+    /// `len` bytes at `offset` of file `index`, once the pieces holding them
+    /// are verified. Those pieces are asked for first. The future doesn't
+    /// borrow the torrent, which must keep stepping until it completes.
+    pub fn read_range(
+        &self,
+        index: usize,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = io::Result<Bytes>> + Send + 'static {
+        let read = self
+            .open_file(index)
+            .map(|reader| reader.read_at(offset, len));
+        async move { read?.await }
+    }
+
+    // Pieces under the position of readers get a deadline
+    fn handle_read_requests(&mut self, now: Instant) {
+        while let Ok((index, deadline)) = self.read_receiver.try_recv() {
+            if index >= self.scheduler.num_pieces() || self.scheduler.has_piece(index) {
+                continue;
+            }
+            if self.scheduler.priority(index) == Priority::Skip {
+                self.scheduler.set_piece_priority(index, Priority::Normal);
+            }
+            if self
+                .scheduler
+                .deadline(index)
+                .is_none_or(|at| at > now + deadline)
+            {
+                self.scheduler.set_piece_deadline(index, deadline);
+            }
+        }
+    }
+
+    /// Whether the torrent may use peers from `source`: private torrents
+    /// (BEP 27) don't use PEX, the DHT or LSD. Peers from everywhere go
+    /// through this, the discovery mechanisms don't have to check the flag.
+    pub fn allows_source(&self, source: PeerSource) -> bool {
+        !self.meta.info.private || source.is_allowed_for_private()
+    }
+
+    /// Remember peers to connect to, learned from `source`. Returns how many
+    /// were taken, none if the source isn't allowed.
+    pub fn add_known_peers(
+        &mut self,
+        peers: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        if !self.allows_source(source) {
+            return 0;
+        }
+        let before = self.known.len();
+        self.known.extend(peers);
+        self.known.len() - before
+    }
+
+    /// Connect to a peer and add it once the handshake went through.
+    pub async fn connect(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> Result<SocketAddr, PeerError> {
+        if !self.allows_source(source) {
+            return Err(PeerError::ForbiddenSource(source));
+        }
+        self.check_room()?;
+        let hs = self.handshake();
+        let res = self.dialer.connect(addr).await?.handshake(hs).await?;
+        self.add_dialed(addr, source, res).await
+    }
+
+    /// Connect to peers in the background, as many at once as the half-open
+    /// limit of the dialer allows. They are added by `step` once handshaked.
+    /// Returns how many are dialed, those connected or dialed already and
+    /// those beyond the peer limits aren't.
+    pub fn dial(
+        &mut self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        source: PeerSource,
+    ) -> usize {
+        if !self.allows_source(source) {
+            return 0;
+        }
+        let room = self
+            .peer_limit()
+            .map(|max| max.saturating_sub(self.peers.len() + self.dialing.len()));
+        let mut dialed = 0;
+        for addr in addrs {
+            if room.is_some_and(|room| dialed >= room) {
+                break;
+            }
+            if self.peers.contains_key(&addr) || !self.dialing.insert(addr) {
+                continue;
+            }
+            let (dialer, hs, sender) = (self.dialer.clone(), self.handshake(), self.dialed.clone());
+            tokio::spawn(async move {
+                let res = match dialer.connect(addr).await {
+                    Ok(half_open) => half_open.handshake(hs).await,
+                    Err(e) => Err(e.into()),
+                };
+                let _ = sender.send((addr, source, res));
+            });
+            dialed += 1;
+        }
+        dialed
+    }
+
+    // Peers dialed in the background
+    async fn add_dialed_peers(&mut self) {
+        while let Ok((addr, source, res)) = self.dialed_receiver.try_recv() {
+            self.dialing.remove(&addr);
+            // Unreachable peers are common, the others are enough
+            if let Ok(res) = res {
+                let _ = self.add_dialed(addr, source, res).await;
+            }
+        }
+    }
+
+    fn handshake(&self) -> Handshake {
+        let mut hs = Handshake::default();
+        hs.set_hash(&self.info_hash);
+        hs.set_peer_id(&self.peer_id);
+        hs
+    }
+
+    // Add a peer we dialed, once it answered our handshake
+    async fn add_dialed(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+        (stream, remote): (TcpStream, Handshake),
+    ) -> Result<SocketAddr, PeerError> {
+        if let Some(capture) = &self.capture {
+            capture.peer_out(addr, &self.handshake().to_bytes());
+            capture.peer_in(addr, &remote.to_bytes());
+        }
+        if remote.get_hash() != &self.info_hash {
+            return Err(PeerError::WrongTorrent);
+        }
+
+        Ok(self.add_handshaked(stream, addr, source, &remote).await?)
+    }
+
+    /// Add a peer whose handshake is done, sending it our extension
+    /// handshake if `remote` supports the extension protocol. `addr` is that
+    /// of the peer, not of the proxy the stream may go through.
+    pub async fn add_handshaked(
+        &mut self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        source: PeerSource,
+        remote: &Handshake,
+    ) -> io::Result<SocketAddr> {
+        let addr = self
+            .add_peer(stream, listener::canonical(addr), source)
+            .await?;
+        if remote.supports_extension_protocol() {
+            if let Some(peer) = self.peers.get(&addr) {
+                peer::send_extension_handshake(peer, &self.extension_handshake(addr)).await?;
+            }
+        }
+        Ok(addr)
+    }
+
+    // Peers allowed by the limits of the torrent and those of its session
+    fn peer_limit(&self) -> Option<usize> {
+        self.max_peers
+            .into_iter()
+            .chain(self.shares.connections)
+            .min()
+    }
+
+    // Room for one more peer
+    fn check_room(&self) -> io::Result<()> {
+        if self.peer_limit().is_some_and(|max| self.peers.len() >= max) {
+            return Err(io::Error::other("Too many peers"));
+        }
+        if self.connection_limit.is_full() {
+            return Err(io::Error::other("Too many connections in the session"));
+        }
+        Ok(())
+    }
+
+    /// Add a peer whose handshake is done, e.g. one which connected to us.
+    pub async fn add_stream(
+        &mut self,
+        stream: TcpStream,
+        source: PeerSource,
+    ) -> io::Result<SocketAddr> {
+        let addr = listener::canonical(stream.peer_addr()?);
+        self.add_peer(stream, addr, source).await
+    }
+
+    async fn add_peer(
+        &mut self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        source: PeerSource,
+    ) -> io::Result<SocketAddr> {
+        self.check_room()?;
+        if !self.allows_source(source) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                PeerError::ForbiddenSource(source),
+            ));
+        }
+        let slot = self
+            .connection_limit
+            .try_acquire()
+            .ok_or_else(|| io::Error::other("Too many connections in the session"))?;
+        let peer = Peer::for_torrent(stream, self.meta.clone(), self.file.clone(), source);
+        {
+            // Events are only sent for what happens after, the rest is read
+            // under the same lock
+            let mut p = peer.write().await;
+            p.set_availability(self.availability.clone());
+            p.set_events(self.events.clone());
+            p.set_upload_budget(self.upload_budget.clone());
+            p.set_capture(self.capture.clone());
+            self.scheduler
+                .add_peer(addr, p.get_bitfield().clone(), p.request_limit());
+            self.scheduler.set_choking(addr, p.peer_choking());
+        }
+        peer::send_interested(&peer, true).await?;
+        self.peers.insert(addr, peer);
+        self.connection_slots.insert(addr, slot);
+        self.known.insert(addr);
+        self.emit(Event::PeerConnected {
+            info_hash: self.info_hash,
+            addr,
+            #[cfg(feature = "geoip")]
+            location: self.peer_location(addr),
+        });
+
+        Ok(addr)
+    }
+
+    fn handle_event(&mut self, addr: SocketAddr, event: PeerEvent) {
+        match event {
+            PeerEvent::Choke(choking) => self.scheduler.set_choking(addr, choking),
+            PeerEvent::Have(index) => self.scheduler.peer_have(addr, index),
+            PeerEvent::Bitfield(have) => self.scheduler.peer_bitfield(addr, have),
+            PeerEvent::Block {
+                index,
+                begin,
+                length,
+            } => {
+                self.download.add(length);
+                if let Some(seed) = self.web_seeds.iter_mut().find(|s| s.addr() == addr) {
+                    seed.received(length);
+                }
+                if let Some(cancels) = self.scheduler.block_received(addr, index, begin) {
+                    self.cancels.extend(cancels);
+                }
+            }
+            PeerEvent::Uploaded(length) => self.upload.add(length),
+            PeerEvent::PieceVerified { index, valid } => {
+                let finished = self.is_finished();
+                self.scheduler.piece_verified(index, valid);
+                if valid {
+                    self.verified.send_replace(());
+                } else {
+                    self.piece_failures += 1;
+                }
+                let info_hash = self.info_hash;
+                self.emit(match valid {
+                    true => Event::PieceVerified { info_hash, index },
+                    false => Event::PieceFailed { info_hash, index },
+                });
+                if !finished && self.is_finished() {
+                    self.emit(Event::TorrentCompleted { info_hash });
+                }
+            }
+            PeerEvent::Closed => {
+                self.scheduler.remove_peer(addr);
+                if let Some(seed) = self.web_seeds.iter_mut().find(|s| s.addr() == addr) {
+                    seed.failed(Instant::now());
+                }
+                self.connection_slots.remove(&addr);
+                if self.peers.remove(&addr).is_some() {
+                    self.emit(Event::PeerDisconnected {
+                        info_hash: self.info_hash,
+                        addr,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Catch up with what the peers reported and send the requests the
+    /// scheduler asks for. Meant to be called periodically.
+    pub async fn step(&mut self) -> io::Result<()> {
+        while let Ok((addr, event)) = self.receiver.try_recv() {
+            self.handle_event(addr, event);
+        }
+        self.add_dialed_peers().await;
+        let now = Instant::now();
+        self.step_announce(now);
+        self.handle_read_requests(now);
+        self.download.tick(now);
+        self.upload.tick(now);
+        for seed in &mut self.web_seeds {
+            seed.tick(now);
+        }
+        if self.state == TorrentState::Running && self.is_finished() {
+            self.seed_time += now.saturating_duration_since(self.last_step);
+            if self.seed_limit_reached() {
+                match self.seed_limits.action {
+                    SeedLimitAction::Pause => self.pause(),
+                    SeedLimitAction::Stop => self.stop().await,
+                }
+                self.emit(Event::SeedLimitReached {
+                    info_hash: self.info_hash,
+                });
+            }
+        }
+        self.last_step = now;
+        let mut disk_errors = Vec::new();
+        for (&addr, peer) in &self.peers {
+            // The limit only changes with the extension handshake
+            if let Ok(mut p) = peer.try_write() {
+                self.scheduler.set_request_limit(addr, p.request_limit());
+                disk_errors.extend(p.take_storage_error());
+            }
+        }
+        for e in disk_errors {
+            self.emit(Event::DiskError {
+                info_hash: self.info_hash,
+                message: e.to_string(),
+            });
+        }
+
+        self.update_choking().await;
+
+        if self.state == TorrentState::Running {
+            // Web seeds are peers having every piece
+            let num_pieces = self.scheduler.num_pieces();
+            for seed in &mut self.web_seeds {
+                if !self.scheduler.is_finished() && seed.activate(now) {
+                    let have = vec![true; num_pieces];
+                    self.scheduler
+                        .add_peer(seed.addr(), have, WEB_SEED_REQUESTS);
+                    self.scheduler.set_choking(seed.addr(), false);
+                }
+            }
+            // Requests beyond the download budget wait for the next one
+            let (requests, later): (Vec<_>, Vec<_>) = self
+                .scheduler
+                .schedule(now)
+                .into_iter()
+                .partition(|r| self.download_budget.try_take(r.length as u64));
+            for request in &later {
+                self.scheduler.cancel_request(request);
+            }
+            let (web, requests): (Vec<_>, Vec<_>) = requests
+                .into_iter()
+                .partition(|r| r.peer.ip().is_unspecified());
+            self.send_web_seed_requests(web);
+            let failed = scheduler::send_requests(&mut self.scheduler, &self.peers, requests).await;
+            for addr in failed {
+                self.handle_event(addr, PeerEvent::Closed);
+            }
+        }
+        let cancels = std::mem::take(&mut self.cancels);
+        for addr in scheduler::send_cancels(&self.peers, cancels).await {
+            self.handle_event(addr, PeerEvent::Closed);
+        }
+        Ok(())
+    }
+
+    // Contiguous blocks of a piece are fetched at once
+    fn send_web_seed_requests(&self, mut requests: Vec<Request>) {
+        requests.sort_unstable_by_key(|r| (r.peer, r.index, r.begin));
+        let mut runs: Vec<Vec<Request>> = Vec::new();
+        for request in requests {
+            let follows = |last: &Request| {
+                (last.peer, last.index, last.begin + last.length)
+                    == (request.peer, request.index, request.begin)
+            };
+            match runs.last_mut() {
+                Some(run) if run.last().is_some_and(follows) => run.push(request),
+                _ => runs.push(vec![request]),
+            }
+        }
+        for run in runs {
+            if let Some(seed) = self.web_seeds.iter().find(|s| s.addr() == run[0].peer) {
+                seed.spawn_fetch(
+                    run,
+                    self.dialer.clone(),
+                    self.file.clone(),
+                    self.meta.clone(),
+                    self.events.clone(),
+                );
+            }
+        }
+    }
+
+    // Unchoke the interested peers up to the upload slots. Those unchoked
+    // keep their slot as long as they are interested and the slots allow.
+    async fn update_choking(&mut self) {
+        let slots = self.shares.upload_slots.unwrap_or(usize::MAX);
+        let (mut unchoked, mut choke, mut candidates) = (0, Vec::new(), Vec::new());
+        for peer in self.peers.values() {
+            let Ok(p) = peer.try_read() else {
+                continue;
+            };
+            match (p.peer_interested(), p.am_choking()) {
+                (true, false) if unchoked < slots => unchoked += 1,
+                (_, false) => choke.push(peer.clone()),
+                (true, true) => candidates.push(peer.clone()),
+                (false, true) => (),
+            }
+        }
+        // A failure means the connection is gone, its read loop finds out
+        for peer in choke {
+            let _ = peer::send_choke(&peer, true).await;
+        }
+        for peer in candidates.into_iter().take(slots - unchoked) {
+            let _ = peer::send_choke(&peer, false).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod torrent_tests {
+    use super::*;
+    use crate::decode_torrent::{FileInfo, Info};
+    use crate::events::{Events, EVENT_CAPACITY};
+    use crate::file::FileEntity;
+    use sha1::{Digest, Sha1};
+    use std::fs;
+    use tokio::{
+        io::AsyncReadExt,
+        net::TcpListener,
+        sync::broadcast,
+        time::{self, Duration},
+    };
+
+    // Answer every request with `data`
+    async fn seed(mut remote: TcpStream, data: Vec<u8>, piece_size: usize) {
+        remote
+            .write_all(&[0, 0, 0, 2, 5, 0b1100_0000])
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+        loop {
+            let mut len = [0u8; 4];
+            if remote.read_exact(&mut len).await.is_err() {
+                return;
+            }
+            let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+            remote.read_exact(&mut msg).await.unwrap();
+            if msg.first() != Some(&6) {
+                continue;
+            }
+            let index = u32::from_be_bytes(msg[1..5].try_into().unwrap()) as usize;
+            let begin = u32::from_be_bytes(msg[5..9].try_into().unwrap()) as usize;
+            let length = u32::from_be_bytes(msg[9..13].try_into().unwrap()) as usize;
+            let start = index * piece_size + begin;
+
+            let mut reply = Vec::new();
+            reply.extend_from_slice(&(9 + length as u32).to_be_bytes());
+            reply.push(7);
+            reply.extend_from_slice(&msg[1..9]);
+            reply.extend_from_slice(&data[start..start + length]);
+            remote.write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn download_from_a_peer() {
+        const FILE: &str = "test_torrent_download_from_a_peer";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..2 * PIECE).map(|i| (i % 251) as u8).collect();
+        let pieces = data
+            .chunks(PIECE)
+            .map(|c| decode_torrent::bytes_to_hash(&Sha1::digest(c).into()))
+            .collect();
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces,
+                name: FILE.to_string(),
+                file_length: (2 * PIECE).to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let mut torrent = Torrent::new(meta, [1; 20]).await.unwrap();
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        torrent.set_event_sender(sender.clone());
+        let mut events = Events::new(sender.subscribe());
+        #[cfg(feature = "geoip")]
+        {
+            use crate::geoip::{geoip_tests, Database, GeoIp};
+            let db = Database::from_bytes(geoip_tests::database(127, "FR", 3215)).unwrap();
+            torrent.set_geoip(Some(Arc::new(GeoIp::new(vec![db]))));
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(seed(remote, data.clone(), PIECE));
+
+        let addr = torrent
+            .add_stream(stream, PeerSource::Manual)
+            .await
+            .unwrap();
+        assert_eq!(vec![addr], torrent.peers());
+
+        // Paused, nothing is asked
+        torrent.pause();
+        time::sleep(Duration::from_millis(300)).await;
+        torrent.step().await.unwrap();
+        assert_eq!(0, torrent.scheduler().pending(addr));
+        assert_eq!(2 * PIECE as u64, torrent.stats().bytes_remaining);
+        assert_eq!(None, torrent.stats().eta);
+
+        torrent.start();
+        for _ in 0..50 {
+            torrent.step().await.unwrap();
+            if torrent.is_finished() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(1.0, torrent.progress());
+        let stats = torrent.stats();
+        assert_eq!(
+            (2 * PIECE as u64, 0),
+            (stats.bytes_done, stats.bytes_remaining)
+        );
+        assert_eq!(2 * PIECE as u64, stats.downloaded);
+        assert_eq!(Some(Duration::ZERO), stats.eta);
+        assert_eq!((1, 1), (stats.connected_peers, stats.known_peers));
+        assert_eq!((2, 0), (stats.disk.writes, stats.piece_failures));
+        let events: Vec<Event> = std::iter::from_fn(|| events.try_recv()).collect();
+        assert_eq!(4, events.len());
+        assert!(matches!(events[0], Event::PeerConnected { .. }));
+        #[cfg(feature = "geoip")]
+        match &events[0] {
+            Event::PeerConnected { location, .. } => {
+                let location = location.as_ref().unwrap();
+                assert_eq!(Some("FR"), location.country.as_deref());
+                assert_eq!(Some(3215), location.asn);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(Event::TorrentCompleted { info_hash: [1; 20] }, events[3]);
+        torrent.file().lock().await.sync().await.unwrap();
+        assert_eq!(data, fs::read(FILE).unwrap());
+
+        torrent.stop().await;
+        assert!(torrent.peers().is_empty());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_while_downloading() {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+        const FILE: &str = "test_torrent_read_while_downloading";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..3 * PIECE).map(|i| (i % 251) as u8).collect();
+        let pieces = data
+            .chunks(PIECE)
+            .map(|c| decode_torrent::bytes_to_hash(&Sha1::digest(c).into()))
+            .collect();
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces,
+                name: FILE.to_string(),
+                file_length: (3 * PIECE).to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let mut torrent = Torrent::new(meta, [1; 20]).await.unwrap();
+        // Only what is read gets downloaded
+        for index in 0..3 {
+            torrent.scheduler_mut().set_wanted(index, false);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(seed(remote, data.clone(), PIECE));
+        torrent
+            .add_stream(stream, PeerSource::Manual)
+            .await
+            .unwrap();
+        torrent.start();
+
+        let mut reader = torrent.open_file(0).unwrap();
+        assert_eq!(3 * PIECE as u64, reader.len());
+        assert!(torrent.open_file(1).is_err());
+        let read = tokio::spawn(async move {
+            reader
+                .seek(io::SeekFrom::Start(PIECE as u64 + 10))
+                .await
+                .unwrap();
+            let mut buf = vec![0; PIECE - 20];
+            reader.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+        for _ in 0..50 {
+            torrent.step().await.unwrap();
+            if read.is_finished() {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let buf = time::timeout(Duration::from_secs(1), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data[PIECE + 10..2 * PIECE - 10], &buf[..]);
+        // The piece before the position was left alone
+        assert!(!torrent.scheduler().has_piece(0));
+        assert!(torrent.scheduler().has_piece(1));
+
+        // Across two pieces, one already there
+        let read = tokio::spawn(torrent.read_range(0, PIECE as u64 - 5, 10));
+        for _ in 0..50 {
+            torrent.step().await.unwrap();
+            if read.is_finished() {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        let bytes = time::timeout(Duration::from_secs(1), read)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&data[PIECE - 5..PIECE + 5], &bytes[..]);
+        let past_end = torrent.read_range(0, 3 * PIECE as u64 - 5, 10).await;
+        assert_eq!(io::ErrorKind::UnexpectedEof, past_end.unwrap_err().kind());
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn private_torrent_sources() {
+        const FILE: &str = "./test_torrent_private_torrent_sources";
+        let meta = |private| MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: FILE.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, 16384, 16384).unwrap();
+        let mut torrent = Torrent::with_file(meta(true), [1; 20], file);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert_eq!(0, torrent.add_known_peers([addr], PeerSource::Dht));
+        assert_eq!(1, torrent.add_known_peers([addr], PeerSource::Tracker));
+        assert!(matches!(
+            torrent.connect(addr, PeerSource::Pex).await,
+            Err(PeerError::ForbiddenSource(PeerSource::Pex))
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let err = torrent
+            .add_stream(stream, PeerSource::Lsd)
+            .await
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        assert!(torrent.peers().is_empty());
+
+        // Other torrents keep every source
+        let file = FileEntity::new(FILE, 16384, 16384).unwrap();
+        let torrent = Torrent::with_file(meta(false), [2; 20], file);
+        assert!(torrent.allows_source(PeerSource::Dht));
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn dial_in_the_background() {
+        const FILE: &str = "test_torrent_dial_in_the_background";
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: FILE.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, 16384, 16384).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        torrent.start();
+        let mut addrs = Vec::new();
+        for _ in 0..2 {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            // Answers with our own handshake
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 68];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                let _ = stream.read(&mut buf).await;
+            });
+        }
+
+        torrent.set_max_peers(Some(1));
+        assert_eq!(1, torrent.dial(addrs.clone(), PeerSource::Tracker));
+        // Dialed already
+        assert_eq!(0, torrent.dial(addrs.clone(), PeerSource::Tracker));
+        for _ in 0..20 {
+            torrent.step().await.unwrap();
+            if !torrent.peers().is_empty() {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(vec![addrs[0]], torrent.peers());
+        // Connected already, and no room left
+        assert_eq!(0, torrent.dial(addrs, PeerSource::Tracker));
+
+        torrent.stop().await;
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_slots() {
+        const FILE: &str = "./test_torrent_upload_slots";
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: FILE.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, 16384, 16384).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut remotes = Vec::new();
+        for _ in 0..2 {
+            let mut remote = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            torrent
+                .add_stream(stream, PeerSource::Manual)
+                .await
+                .unwrap();
+            remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+            remotes.push(remote);
+        }
+        for _ in 0..50 {
+            if torrent.demands().upload_slots == 2 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(2, torrent.demands().upload_slots);
+
+        // Whether the remote was unchoked since the last call
+        async fn unchoked(remote: &mut TcpStream) -> bool {
+            loop {
+                let mut len = [0u8; 4];
+                let read = time::timeout(Duration::from_millis(100), remote.read_exact(&mut len));
+                if read.await.is_err() {
+                    return false;
+                }
+                let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+                remote.read_exact(&mut msg).await.unwrap();
+                if msg == [1] {
+                    return true;
+                }
+            }
+        }
+        torrent.set_shares(Shares {
+            upload_slots: Some(1),
+            ..Default::default()
+        });
+        for _ in 0..5 {
+            torrent.step().await.unwrap();
+        }
+        let first = [
+            unchoked(&mut remotes[0]).await,
+            unchoked(&mut remotes[1]).await,
+        ];
+        assert_eq!(1, first.iter().filter(|&&u| u).count());
+
+        // A free slot goes to the one left
+        torrent.set_shares(Shares::default());
+        for _ in 0..5 {
+            torrent.step().await.unwrap();
+        }
+        let last = if first[0] { 1 } else { 0 };
+        assert!(unchoked(&mut remotes[last]).await);
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_unchoked_peers() {
+        const FILE: &str = "./test_torrent_serve_unchoked_peers";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..PIECE).map(|i| (i % 251) as u8).collect();
+        fs::write(FILE, &data).unwrap();
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&Sha1::digest(&data).into())],
+                name: FILE.to_string(),
+                file_length: PIECE.to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, PIECE, PIECE).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        torrent
+            .add_stream(stream, PeerSource::Manual)
+            .await
+            .unwrap();
+        remote.write_all(&[0, 0, 0, 1, 2]).await.unwrap();
+        for _ in 0..50 {
+            if torrent.demands().upload_slots == 1 {
+                break;
+            }
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        torrent.step().await.unwrap();
+
+        // Decode every message up to the block, the stream must stay in sync
+        async fn next_message(remote: &mut TcpStream) -> Vec<u8> {
+            let mut len = [0u8; 4];
+            remote.read_exact(&mut len).await.unwrap();
+            let mut msg = vec![0u8; u32::from_be_bytes(len) as usize];
+            remote.read_exact(&mut msg).await.unwrap();
+            msg
+        }
+        while next_message(&mut remote).await != [1] {}
+        let mut request = vec![0, 0, 0, 13, 6];
+        for x in [0, 512, 1024u32] {
+            request.extend_from_slice(&x.to_be_bytes());
+        }
+        remote.write_all(&request).await.unwrap();
+        let msg = loop {
+            let msg = time::timeout(Duration::from_secs(5), next_message(&mut remote))
+                .await
+                .unwrap();
+            if msg.first() == Some(&7) {
+                break msg;
+            }
+        };
+        assert_eq!(9 + 1024, msg.len());
+        assert_eq!([0, 0, 0, 0], msg[1..5]);
+        assert_eq!(512u32.to_be_bytes(), msg[5..9]);
+        assert_eq!(data[512..1536], msg[9..]);
+
+        torrent.stop().await;
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seed_limits() {
+        const FILE: &str = "./test_torrent_seed_limits";
+        const PIECE: usize = 16384;
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20]); 2],
+                name: FILE.to_string(),
+                file_length: (2 * PIECE).to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, PIECE, 2 * PIECE).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        torrent.scheduler_mut().set_have(&[true, true]);
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        torrent.set_event_sender(sender.clone());
+        let mut events = Events::new(sender.subscribe());
+
+        // Seeding what was already there, the ratio is over the content
+        torrent.set_seed_limits(SeedLimits {
+            ratio: Some(1.0),
+            ..Default::default()
+        });
+        torrent.start();
+        torrent.upload.add(PIECE);
+        torrent.step().await.unwrap();
+        assert_eq!(0.5, torrent.stats().ratio);
+        assert_eq!(TorrentState::Running, torrent.state());
+        torrent.upload.add(PIECE);
+        torrent.step().await.unwrap();
+        assert_eq!(TorrentState::Paused, torrent.state());
+        assert_eq!(
+            Some(Event::SeedLimitReached { info_hash: [1; 20] }),
+            events.try_recv()
+        );
+
+        torrent.set_seed_limits(SeedLimits {
+            time: Some(Duration::from_millis(100)),
+            action: SeedLimitAction::Stop,
+            ..Default::default()
+        });
+        torrent.start();
+        torrent.step().await.unwrap();
+        assert_eq!(TorrentState::Running, torrent.state());
+        time::sleep(Duration::from_millis(150)).await;
+        torrent.step().await.unwrap();
+        assert_eq!(TorrentState::Stopped, torrent.state());
+        assert!(torrent.seed_time() >= Duration::from_millis(100));
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn announce_counters() {
+        const FILE: &str = "./test_torrent_announce_counters";
+        const PIECE: usize = 16384;
+        let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let meta = MetaInfo {
+            announce: format!("udp://{}/announce", tracker.local_addr().unwrap()),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20]); 2],
+                name: FILE.to_string(),
+                file_length: (2 * PIECE).to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, PIECE, 2 * PIECE).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        torrent.scheduler_mut().set_have(&[true, false]);
+        torrent.set_tracker_bind("127.0.0.1:0".parse().unwrap());
+        torrent.set_listen_port(6881);
+        // Totals of a previous run are reported too
+        torrent.restore_totals(5000, 700, Duration::ZERO);
+        torrent.upload.add(300);
+
+        let fake = tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&[9; 8]);
+            tracker.send_to(&reply, from).await.unwrap();
+
+            let (n, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(98, n);
+            let mut reply = vec![0, 0, 0, 1];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&[0; 12]);
+            tracker.send_to(&reply, from).await.unwrap();
+            let be_u64 = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+            let port = u16::from_be_bytes([buf[96], buf[97]]);
+            (be_u64(56), be_u64(64), be_u64(72), port)
+        });
+        torrent.announce().await.unwrap();
+        assert_eq!((5000, PIECE as u64, 1000, 6881), fake.await.unwrap());
+        assert_eq!(
+            AnnounceCounters {
+                downloaded: 5000,
+                left: PIECE as u64,
+                uploaded: 1000,
+            },
+            torrent.counters()
+        );
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn announce_on_step() {
+        const FILE: &str = "test_torrent_announce_on_step";
+        let tracker = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let meta = MetaInfo {
+            announce: format!("udp://{}/announce", tracker.local_addr().unwrap()),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: FILE.to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let file = FileEntity::new(FILE, 16384, 16384).unwrap();
+        let mut torrent = Torrent::with_file(meta, [1; 20], file);
+        torrent.set_tracker_bind("127.0.0.1:0".parse().unwrap());
+
+        let fake = tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 0];
+            reply.extend_from_slice(&buf[12..16]);
+            reply.extend_from_slice(&[9; 8]);
+            tracker.send_to(&reply, from).await.unwrap();
+
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0, 0, 0, 1];
+            reply.extend_from_slice(&buf[12..16]);
+            // Interval, leechers, seeders and a peer
+            reply.extend_from_slice(&1800u32.to_be_bytes());
+            reply.extend_from_slice(&2u32.to_be_bytes());
+            reply.extend_from_slice(&3u32.to_be_bytes());
+            reply.extend_from_slice(&[127, 0, 0, 1, 0, 1]);
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        // Stopped torrents don't announce
+        torrent.step().await.unwrap();
+        assert!(torrent.announcing.is_none());
+
+        torrent.start();
+        for _ in 0..50 {
+            torrent.step().await.unwrap();
+            if torrent.stats().seeds.is_some() {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        fake.await.unwrap();
+        let stats = torrent.stats();
+        assert_eq!((Some(3), Some(2)), (stats.seeds, stats.leechers));
+        assert_eq!(1, stats.known_peers);
+
+        // Not due again before the interval of the tracker
+        torrent.step().await.unwrap();
+        assert!(torrent.announcing.is_none());
+        assert!(torrent.next_announce.unwrap() > Instant::now() + ANNOUNCE_INTERVAL);
+
+        fs::remove_file(FILE).unwrap();
+    }
+
+    // Answer the ranged GETs of the files at their path
+    async fn web_seed(listener: TcpListener, files: Vec<(String, Vec<u8>)>) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+            let path = head.split(' ').nth(1).unwrap();
+            let range = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Range: bytes="))
+                .unwrap();
+            let (start, end) = range.split_once('-').unwrap();
+            let range = start.parse::<usize>().unwrap()..=end.parse().unwrap();
+            let reply = match files.iter().find(|(p, _)| p == path) {
+                Some((_, data)) => {
+                    let body = &data[range];
+                    let mut reply = format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\r\n",
+                        body.len()
+                    )
+                    .into_bytes();
+                    reply.extend_from_slice(body);
+                    reply
+                }
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            };
+            stream.write_all(&reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn download_from_a_web_seed() {
+        const DIR: &str = "test_torrent_download_from_a_web_seed";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..2 * PIECE).map(|i| (i % 251) as u8).collect();
+        let pieces = data
+            .chunks(PIECE)
+            .map(|c| decode_torrent::bytes_to_hash(&Sha1::digest(c).into()))
+            .collect();
+        // The second piece spans both files
+        let (a, c) = data.split_at(PIECE + 100);
+        let file = |length: usize, path: &[&str]| FileInfo {
+            length: length as u64,
+            path: path.iter().map(|p| p.to_string()).collect(),
+            md5sum: None,
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/seed/", listener.local_addr().unwrap());
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces,
+                name: DIR.to_string(),
+                file_length: (2 * PIECE).to_string(),
+                md5sum: None,
+                files: Some(vec![file(a.len(), &["a"]), file(c.len(), &["b dir", "c"])]),
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            // HTTPS isn't spoken, the other one does it all
+            url_list: Some(vec!["https://example.org/".to_string(), url]),
+            nodes: None,
+            piece_layers: None,
+        };
+        let files = vec![
+            (format!("/seed/{}/a", DIR), a.to_vec()),
+            (format!("/seed/{}/b%20dir/c", DIR), c.to_vec()),
+        ];
+        tokio::spawn(web_seed(listener, files));
+
+        let mut torrent = Torrent::new(meta, [1; 20]).await.unwrap();
+        assert_eq!(1, torrent.web_seeds.len());
+        torrent.start();
+        for _ in 0..50 {
+            torrent.step().await.unwrap();
+            if torrent.is_finished() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(1.0, torrent.progress());
+        let stats = torrent.stats();
+        assert_eq!(
+            (2 * PIECE as u64, 0),
+            (stats.downloaded, stats.piece_failures)
+        );
+        assert_eq!(0, stats.connected_peers);
+        // Stored in the directory of the torrent
+        torrent.file().lock().await.flush().await.unwrap();
+        assert_eq!(a, fs::read(format!("{}/a", DIR)).unwrap());
+        assert_eq!(c, fs::read(format!("{}/b dir/c", DIR)).unwrap());
+
+        fs::remove_dir_all(DIR).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recheck_on_open() {
+        const DIR: &str = "test_torrent_recheck_on_open";
+        const PIECE: usize = 16384;
+        let data: Vec<u8> = (0..2 * PIECE).map(|i| (i % 251) as u8).collect();
+        let pieces: Vec<String> = data
+            .chunks(PIECE)
+            .map(|c| decode_torrent::bytes_to_hash(&Sha1::digest(c).into()))
+            .collect();
+        let (a, c) = data.split_at(PIECE + 100);
+        let file = |length: usize, path: &str| FileInfo {
+            length: length as u64,
+            path: vec![path.to_string()],
+            md5sum: None,
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        };
+        let meta = |files: Option<Vec<FileInfo>>| MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: PIECE.to_string(),
+                pieces: pieces.clone(),
+                name: "t".to_string(),
+                file_length: (2 * PIECE).to_string(),
+                md5sum: None,
+                files,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+
+        // A single file with a corrupted second piece
+        fs::create_dir_all(DIR).unwrap();
+        let mut corrupted = data.clone();
+        corrupted[PIECE] ^= 1;
+        fs::write(format!("{}/t", DIR), &corrupted).unwrap();
+        let torrent = Torrent::with_save_path(meta(None), [1; 20], DIR)
+            .await
+            .unwrap();
+        assert!(torrent.scheduler().has_piece(0));
+        assert!(!torrent.scheduler().has_piece(1));
+        fs::remove_file(format!("{}/t", DIR)).unwrap();
+
+        // The second file of a multi-file torrent is missing
+        let files = Some(vec![file(a.len(), "a"), file(c.len(), "c")]);
+        fs::create_dir_all(format!("{}/t", DIR)).unwrap();
+        fs::write(format!("{}/t/a", DIR), a).unwrap();
+        let mut torrent = Torrent::with_save_path(meta(files), [1; 20], DIR)
+            .await
+            .unwrap();
+        assert_eq!(0.5, torrent.progress());
+        assert!(!Path::new(&format!("{}/t/c", DIR)).exists());
+
+        fs::write(format!("{}/t/c", DIR), c).unwrap();
+        assert_eq!(2, torrent.recheck().await.unwrap());
+        assert!(torrent.is_finished());
+
+        drop(torrent);
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
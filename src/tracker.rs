@@ -1,20 +1,87 @@
 use std::mem;
-use std::{io, net::Ipv4Addr};
-use tokio::net::UdpSocket;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+use thiserror::Error;
+use tokio::net::{ToSocketAddrs, UdpSocket};
 
-use crate::definitions::{InfoHash, PeerId, INFO_HASH_LEN, TORRENT_RS_PEER_ID};
+use crate::capture::{Capture, Direction, Protocol};
+use crate::decode_torrent::MetaInfoError;
+use crate::definitions::{self, InfoHash, PeerId, INFO_HASH_LEN};
 
 pub type ConnectionId = u64;
 
 pub type TransactionId = u32;
 
-const SOCKET_BIND: &str = "0.0.0.0:8080";
+/// Any interface, a port picked by the system.
+pub const DEFAULT_TRACKER_BIND: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+// Room for the error message of a tracker
+const MIN_RESPONSE_BUF: usize = 512;
+
+/// Errors talking to a tracker. None of them are fatal, the torrent can go
+/// on with other peer sources.
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("Tracker unreachable: {0}")]
+    Io(#[from] io::Error),
+    // Announce URL we can't talk to, e.g. HTTP
+    #[error("Unsupported tracker {0}")]
+    Unsupported(String),
+    // Error message sent by the tracker
+    #[error("Tracker error: {0}")]
+    Failure(String),
+    #[error("Invalid tracker response")]
+    InvalidResponse,
+    #[error("Tracker response to another transaction")]
+    TransactionMismatch,
+    #[error(transparent)]
+    MetaInfo(#[from] MetaInfoError),
+}
+
+impl From<TrackerError> for io::Error {
+    fn from(e: TrackerError) -> Self {
+        match e {
+            TrackerError::Io(e) => e,
+            TrackerError::Unsupported(_) => io::Error::new(io::ErrorKind::Unsupported, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// Every response starts with the action and the transaction ID, error
+// responses are followed by a message. Returns what follows them.
+fn check_response(buf: &[u8], action: u32, tid: TransactionId) -> Result<&[u8], TrackerError> {
+    if buf.len() < 8 {
+        return Err(TrackerError::InvalidResponse);
+    }
+    let (header, payload) = buf.split_at(8);
+    if header[4..8] != tid.to_ne_bytes() {
+        return Err(TrackerError::TransactionMismatch);
+    }
+    match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        ACTION_ERROR => Err(TrackerError::Failure(
+            String::from_utf8_lossy(payload).into_owned(),
+        )),
+        a if a == action => Ok(payload),
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
 
 #[derive(Debug)]
 pub struct UdpConnection {
     socket: UdpSocket,
     cid: ConnectionId,
     tid: TransactionId,
+    capture: Option<Capture>,
 }
 
 #[repr(C, align(4))]
@@ -25,15 +92,7 @@ struct ConnectIn {
     tid: TransactionId,
 }
 
-#[repr(C, align(4))]
-#[derive(Debug)]
-struct ConnectOut {
-    action: u32,
-    tid: TransactionId,
-    cid: ConnectionId,
-}
-
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
 struct AnnounceIn {
     cid: ConnectionId,
@@ -51,10 +110,19 @@ struct AnnounceIn {
     port: u16,
 }
 
-#[repr(packed)]
+/// Transfer of a torrent as reported to its tracker, in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AnnounceCounters {
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+}
+
 #[derive(Debug)]
 pub struct AnnounceOut {
+    #[allow(dead_code)]
     action: u32,
+    #[allow(dead_code)]
     tid: TransactionId,
     interval: u32,
     leechers: u32,
@@ -62,24 +130,33 @@ pub struct AnnounceOut {
     peers: Option<Vec<(Ipv4Addr, u16)>>,
 }
 
-// TODO: return Result
-pub fn hash_to_bytes(hash: &str) -> InfoHash {
+/// Bytes of a hash in hex, as the piece hashes of `Info`.
+pub fn hash_to_bytes(hash: &str) -> Result<InfoHash, MetaInfoError> {
+    let invalid = || MetaInfoError::InvalidHash(hash.to_string());
+    if hash.len() != 2 * INFO_HASH_LEN {
+        return Err(invalid());
+    }
     let mut res = [0u8; INFO_HASH_LEN];
+    for (i, byte) in res.iter_mut().enumerate() {
+        let digits = hash.get(2 * i..2 * i + 2).ok_or_else(invalid)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid())?;
+    }
 
-    // TODO: look for another way to split the str
-    hash.as_bytes()
-        .chunks(2)
-        .map(|b| std::str::from_utf8(b).unwrap())
-        .map(|n| u8::from_str_radix(n, 16).unwrap())
-        .enumerate()
-        .for_each(|(i, x)| res[i] = x);
-
-    res
+    Ok(res)
 }
 
 impl UdpConnection {
     pub async fn new(tracker: &str, id: Option<TransactionId>) -> io::Result<Self> {
-        let sock = UdpSocket::bind(SOCKET_BIND).await?;
+        UdpConnection::bind(tracker, DEFAULT_TRACKER_BIND, id).await
+    }
+
+    /// Talk to `tracker` from the local address `local`.
+    pub async fn bind<A: ToSocketAddrs>(
+        tracker: &str,
+        local: A,
+        id: Option<TransactionId>,
+    ) -> io::Result<Self> {
+        let sock = UdpSocket::bind(local).await?;
         sock.connect(tracker).await?;
         let tid = id.unwrap_or_default();
 
@@ -87,32 +164,51 @@ impl UdpConnection {
             socket: sock,
             cid: ConnectionId::default(),
             tid,
+            capture: None,
         })
     }
 
-    pub async fn connect(&mut self) -> io::Result<()> {
+    /// Record the datagrams exchanged with the tracker to `capture`.
+    pub fn set_capture(&mut self, capture: Option<Capture>) {
+        self.capture = capture;
+    }
+
+    // Send a request and wait for the answer, returning its length
+    async fn exchange(&self, request: &[u8], response: &mut [u8]) -> io::Result<usize> {
+        let record = |direction, data: &[u8]| {
+            if let (Some(capture), Ok(addr)) = (&self.capture, self.socket.peer_addr()) {
+                capture.record(direction, Protocol::Tracker, addr, data);
+            }
+        };
+        self.socket.send(request).await?;
+        record(Direction::Out, request);
+        let n = self.socket.recv(response).await?;
+        record(Direction::In, &response[..n]);
+        Ok(n)
+    }
+
+    pub async fn connect(&mut self) -> Result<(), TrackerError> {
         let tid = rand::random();
         let cin = ConnectIn {
             cid: 0x8019102717040000,
-            action: 0,
+            action: ACTION_CONNECT,
             tid,
         };
 
         let data_in: [u8; mem::size_of::<ConnectIn>()] = unsafe { mem::transmute(cin) };
-        let mut data_out = [0u8; mem::size_of::<ConnectOut>()];
-
-        self.socket.send(&data_in).await?;
-        self.socket.recv(&mut data_out).await?;
+        let mut data_out = [0u8; MIN_RESPONSE_BUF];
 
-        let cout: ConnectOut = unsafe { mem::transmute(data_out) };
+        let n = self.exchange(&data_in, &mut data_out).await?;
 
-        // TODO: fail gracefully
-        assert!(cout.action == 0);
-        assert!(cout.tid == tid);
-        assert!(cout.cid != 0);
+        // Sent back as is, in our byte order
+        let cid = check_response(&data_out[..n], ACTION_CONNECT, tid)?
+            .get(..8)
+            .map(|b| ConnectionId::from_ne_bytes(b.try_into().expect("8 bytes")))
+            .filter(|&cid| cid != 0)
+            .ok_or(TrackerError::InvalidResponse)?;
 
         self.tid = tid;
-        self.cid = cout.cid;
+        self.cid = cid;
 
         Ok(())
     }
@@ -122,46 +218,70 @@ impl UdpConnection {
         info_hash: &str,
         peer_id: Option<&PeerId>,
         num_peers: Option<u32>,
-    ) -> io::Result<AnnounceOut> {
-        let pid = peer_id.unwrap_or(TORRENT_RS_PEER_ID);
+    ) -> Result<AnnounceOut, TrackerError> {
+        self.announce_with(
+            info_hash,
+            peer_id,
+            num_peers,
+            0,
+            AnnounceCounters::default(),
+        )
+        .await
+    }
+
+    /// Announce with what was transferred so far, see `Torrent::counters`,
+    /// and the port peers may connect to, 0 for the one of this socket.
+    pub async fn announce_with(
+        &self,
+        info_hash: &str,
+        peer_id: Option<&PeerId>,
+        num_peers: Option<u32>,
+        port: u16,
+        counters: AnnounceCounters,
+    ) -> Result<AnnounceOut, TrackerError> {
+        let pid = peer_id.copied().unwrap_or_else(definitions::new_peer_id);
         let num_peers = num_peers.unwrap_or(1);
 
         let ann = AnnounceIn {
             cid: self.cid,
-            action: (1_u32).to_be(),
+            action: ACTION_ANNOUNCE.to_be(),
             tid: self.tid,
-            info_hash: hash_to_bytes(info_hash),
-            peer_id: *pid,
-            downloaded: 0,
-            left: 0,
-            uploaded: 0,
+            info_hash: hash_to_bytes(info_hash)?,
+            peer_id: pid,
+            downloaded: counters.downloaded.to_be(),
+            left: counters.left.to_be(),
+            uploaded: counters.uploaded.to_be(),
             event: 0,
             ipv4: 0,
             key: 0,
             num_want: num_peers.to_be(),
-            port: 0,
+            port: port.to_be(),
         };
 
-        let mut buf = vec![0u8; 20 + 6 * num_peers as usize];
+        let mut buf = vec![0u8; (20 + 6 * num_peers as usize).max(MIN_RESPONSE_BUF)];
         let data: [u8; std::mem::size_of::<AnnounceIn>()] = unsafe { mem::transmute(ann) };
-        self.socket.send(&data).await?;
-        self.socket.recv(&mut buf).await?;
+        let n = self.exchange(&data, &mut buf).await?;
 
+        let payload = check_response(&buf[..n], ACTION_ANNOUNCE, self.tid)?;
+        if payload.len() < 12 {
+            return Err(TrackerError::InvalidResponse);
+        }
         let res = AnnounceOut {
-            action: u32::from_be_bytes(buf[0..4].try_into().unwrap()),
-            tid: u32::from_ne_bytes(buf[4..8].try_into().unwrap()),
-            interval: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
-            leechers: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
-            seeders: u32::from_be_bytes(buf[16..20].try_into().unwrap()),
+            action: ACTION_ANNOUNCE,
+            tid: self.tid,
+            interval: be_u32(&payload[0..4]),
+            leechers: be_u32(&payload[4..8]),
+            seeders: be_u32(&payload[8..12]),
             peers: match num_peers {
                 0 => None,
                 n => Some(
-                    (0..n as usize)
-                        .map(|x| {
-                            let idx = 20 + 6 * x;
+                    payload[12..]
+                        .chunks_exact(6)
+                        .take(n as usize)
+                        .map(|p| {
                             (
-                                Ipv4Addr::new(buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]),
-                                u16::from_be_bytes(buf[24 + x * 6..26 + x * 6].try_into().unwrap()),
+                                Ipv4Addr::new(p[0], p[1], p[2], p[3]),
+                                u16::from_be_bytes([p[4], p[5]]),
                             )
                         })
                         .filter(|ipport| *ipport != (Ipv4Addr::new(0, 0, 0, 0), 0))
@@ -178,6 +298,18 @@ impl AnnounceOut {
     pub fn get_peers(&self) -> Option<&Vec<(Ipv4Addr, u16)>> {
         self.peers.as_ref()
     }
+
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    pub fn leechers(&self) -> u32 {
+        self.leechers
+    }
+
+    pub fn seeders(&self) -> u32 {
+        self.seeders
+    }
 }
 
 #[cfg(test)]
@@ -222,4 +354,28 @@ mod tracker_tests {
         // Shouldn't be true for every case
         assert_ne!(None, ann.peers);
     }
+
+    #[test]
+    fn malformed_responses() {
+        let tid: TransactionId = 7;
+        let mut error = vec![0, 0, 0, 3];
+        error.extend_from_slice(&tid.to_ne_bytes());
+        error.extend_from_slice(b"Unregistered torrent");
+        assert!(matches!(
+            check_response(&error, ACTION_ANNOUNCE, tid),
+            Err(TrackerError::Failure(msg)) if msg == "Unregistered torrent"
+        ));
+        assert!(matches!(
+            check_response(&error, ACTION_ANNOUNCE, 8),
+            Err(TrackerError::TransactionMismatch)
+        ));
+        assert!(matches!(
+            check_response(&[0, 0, 0], ACTION_CONNECT, tid),
+            Err(TrackerError::InvalidResponse)
+        ));
+
+        assert_eq!([0x52; 20], hash_to_bytes(&"52".repeat(20)).unwrap());
+        assert!(hash_to_bytes("52b6").is_err());
+        assert!(hash_to_bytes(&"zz".repeat(20)).is_err());
+    }
 }
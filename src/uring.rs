@@ -0,0 +1,451 @@
+// io_uring backend of the disk I/O. A thread owns the ring: operations are
+// handed to it over a channel with the buffers they work on, and come back
+// through a oneshot channel once complete. Buffers small enough go through
+// buffers registered with the ring, and files through its table of fixed
+// descriptors, which spares the kernel mapping them on every operation.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{mpsc, Arc, Weak},
+    thread,
+};
+
+use io_uring::{opcode, types, IoUring};
+use tokio::sync::oneshot;
+
+use crate::backend::UringConfig;
+
+// user_data of the read of the eventfd waking up the ring thread
+const WAKE: u64 = u64::MAX;
+// Milliseconds the kernel thread polls the submission queue before sleeping
+const SQ_POLL_IDLE: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OpKind {
+    Read,
+    Write,
+    Sync,
+}
+
+/// An operation on `buf[pos..]` at `offset` of the file, the buffer is
+/// handed back with the result.
+pub(crate) struct Op {
+    pub kind: OpKind,
+    pub file: Arc<File>,
+    pub buf: Vec<u8>,
+    pub pos: usize,
+    pub offset: u64,
+}
+
+type Completion = oneshot::Receiver<(Vec<u8>, io::Result<usize>)>;
+type Done = oneshot::Sender<(Vec<u8>, io::Result<usize>)>;
+
+enum Msg {
+    Op(Op, Done),
+    // The last handle is gone
+    Stop,
+}
+
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd(fd))
+    }
+
+    fn notify(&self) {
+        let one = 1u64.to_ne_bytes();
+        // Only fails if the counter would overflow, it is then readable anyway
+        unsafe { libc::write(self.0, one.as_ptr().cast(), one.len()) };
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Handle on the thread owning the ring.
+pub struct Ring {
+    sender: mpsc::Sender<Msg>,
+    wake: Arc<EventFd>,
+    registered: (usize, usize),
+}
+
+impl std::fmt::Debug for Ring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ring")
+            .field("registered_buffers", &self.registered.0)
+            .field("fixed_files", &self.registered.1)
+            .finish()
+    }
+}
+
+impl Ring {
+    pub fn new(config: &UringConfig) -> io::Result<Self> {
+        let depth = config.depth.max(2);
+        let mut builder = IoUring::builder();
+        if config.sq_poll {
+            builder.setup_sqpoll(SQ_POLL_IDLE);
+        }
+        let ring = builder.build(depth as u32)?;
+
+        // Both are optional, the ring works without when the kernel or the
+        // limit of locked memory doesn't allow them
+        let mut buffers: Vec<Vec<u8>> = (0..config.registered_buffers)
+            .map(|_| vec![0; config.buffer_size])
+            .collect();
+        let iovecs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr().cast(),
+                iov_len: b.len(),
+            })
+            .collect();
+        // The buffers live as long as the ring, in its thread
+        if iovecs.is_empty() || unsafe { ring.submitter().register_buffers(&iovecs) }.is_err() {
+            buffers.clear();
+        }
+        let slots = config.fixed_files.min(u32::MAX as usize);
+        let slots = match slots > 0 && ring.submitter().register_files(&vec![-1; slots]).is_ok() {
+            true => slots,
+            false => 0,
+        };
+
+        let wake = Arc::new(EventFd::new()?);
+        let (sender, receiver) = mpsc::channel();
+        let registered = (buffers.len(), slots);
+        let driver = Driver {
+            ring,
+            receiver,
+            wake: wake.clone(),
+            wake_buf: Box::new([0; 8]),
+            wake_armed: false,
+            depth,
+            backlog: VecDeque::new(),
+            running: HashMap::new(),
+            next_id: 0,
+            free_buffers: (0..buffers.len() as u16).collect(),
+            buffers,
+            files: FileTable::new(slots),
+        };
+        thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || driver.run())?;
+
+        Ok(Ring {
+            sender,
+            wake,
+            registered,
+        })
+    }
+
+    /// Number of registered buffers and of fixed file slots.
+    pub fn registered(&self) -> (usize, usize) {
+        self.registered
+    }
+
+    /// Queue operations, they are submitted together.
+    pub(crate) fn submit(&self, ops: Vec<Op>) -> Vec<Completion> {
+        let completions = ops
+            .into_iter()
+            .map(|op| {
+                let (sender, receiver) = oneshot::channel();
+                // The thread only stops once every handle is gone
+                let _ = self.sender.send(Msg::Op(op, sender));
+                receiver
+            })
+            .collect();
+        self.wake.notify();
+        completions
+    }
+
+    pub(crate) async fn run(&self, op: Op) -> (Vec<u8>, io::Result<usize>) {
+        let completion = self.submit(vec![op]).pop();
+        wait(completion.expect("One completion per operation")).await
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Msg::Stop);
+        self.wake.notify();
+    }
+}
+
+pub(crate) async fn wait(completion: Completion) -> (Vec<u8>, io::Result<usize>) {
+    completion
+        .await
+        .unwrap_or_else(|_| (Vec::new(), Err(io::Error::other("io_uring thread stopped"))))
+}
+
+// Where an operation finds its file
+#[derive(Clone, Copy)]
+enum Target {
+    Fd(RawFd),
+    Fixed(u32),
+}
+
+macro_rules! with_target {
+    ($target:expr, |$fd:ident| $entry:expr) => {
+        match $target {
+            Target::Fd(fd) => {
+                let $fd = types::Fd(fd);
+                $entry
+            }
+            Target::Fixed(slot) => {
+                let $fd = types::Fixed(slot);
+                $entry
+            }
+        }
+    };
+}
+
+// Files registered with the ring, by the address of their Arc. The Weak
+// keeps the address from being reused while the slot is taken.
+struct FileTable {
+    slots: HashMap<usize, (u32, Weak<File>, u64)>,
+    free: Vec<u32>,
+    uses: u64,
+}
+
+impl FileTable {
+    fn new(slots: usize) -> Self {
+        FileTable {
+            slots: HashMap::new(),
+            free: (0..slots as u32).rev().collect(),
+            uses: 0,
+        }
+    }
+
+    fn target(&mut self, ring: &IoUring, file: &Arc<File>) -> Target {
+        self.uses += 1;
+        let key = Arc::as_ptr(file) as usize;
+        if let Some((slot, _, used)) = self.slots.get_mut(&key) {
+            *used = self.uses;
+            return Target::Fixed(*slot);
+        }
+        let Some(slot) = self.free_slot(ring) else {
+            return Target::Fd(file.as_raw_fd());
+        };
+        match ring
+            .submitter()
+            .register_files_update(slot, &[file.as_raw_fd()])
+        {
+            Ok(_) => {
+                self.slots
+                    .insert(key, (slot, Arc::downgrade(file), self.uses));
+                Target::Fixed(slot)
+            }
+            Err(_) => {
+                self.free.push(slot);
+                Target::Fd(file.as_raw_fd())
+            }
+        }
+    }
+
+    // Files dropped since are let go first, then the least recently used.
+    // The kernel keeps a file alive for the operations still using it.
+    fn free_slot(&mut self, ring: &IoUring) -> Option<u32> {
+        if self.free.is_empty() {
+            let dropped: Vec<usize> = self
+                .slots
+                .iter()
+                .filter(|(_, (_, file, _))| file.strong_count() == 0)
+                .map(|(&key, _)| key)
+                .collect();
+            let evicted = match dropped.is_empty() {
+                true => self
+                    .slots
+                    .iter()
+                    .min_by_key(|(_, (_, _, used))| *used)
+                    .map(|(&key, _)| key)
+                    .into_iter()
+                    .collect(),
+                false => dropped,
+            };
+            for key in evicted {
+                if let Some((slot, ..)) = self.slots.remove(&key) {
+                    let _ = ring.submitter().register_files_update(slot, &[-1]);
+                    self.free.push(slot);
+                }
+            }
+        }
+        self.free.pop()
+    }
+}
+
+struct Running {
+    op: Op,
+    done: Done,
+    buffer: Option<u16>,
+}
+
+struct Driver {
+    ring: IoUring,
+    receiver: mpsc::Receiver<Msg>,
+    wake: Arc<EventFd>,
+    wake_buf: Box<[u8; 8]>,
+    wake_armed: bool,
+    // Operations in flight at most, the rest waits in the backlog
+    depth: usize,
+    backlog: VecDeque<(Op, Done)>,
+    running: HashMap<u64, Running>,
+    next_id: u64,
+    buffers: Vec<Vec<u8>>,
+    free_buffers: Vec<u16>,
+    files: FileTable,
+}
+
+impl Driver {
+    fn run(mut self) {
+        let mut open = true;
+        loop {
+            while open {
+                match self.receiver.try_recv() {
+                    Ok(Msg::Op(op, done)) => self.backlog.push_back((op, done)),
+                    Ok(Msg::Stop) | Err(mpsc::TryRecvError::Disconnected) => open = false,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                }
+            }
+            if open && !self.wake_armed {
+                self.arm_wake();
+            }
+            while self.running.len() < self.depth {
+                let Some((op, done)) = self.backlog.pop_front() else {
+                    break;
+                };
+                self.start(op, done);
+            }
+            // Buffers can only go once the kernel is done with them
+            if !open && self.running.is_empty() && !self.wake_armed {
+                return;
+            }
+
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY)) => (),
+                Err(_) => {
+                    // The kernel may still write to the buffers in flight
+                    for (_, running) in self.running.drain() {
+                        std::mem::forget(running.op.buf);
+                    }
+                    std::mem::forget(std::mem::take(&mut self.buffers));
+                    std::mem::forget(std::mem::take(&mut self.wake_buf));
+                    return;
+                }
+            }
+            self.reap();
+        }
+    }
+
+    fn arm_wake(&mut self) {
+        let entry = opcode::Read::new(types::Fd(self.wake.0), self.wake_buf.as_mut_ptr(), 8)
+            .build()
+            .user_data(WAKE);
+        self.wake_armed = self.push(&entry).is_ok();
+    }
+
+    fn push(&mut self, entry: &io_uring::squeue::Entry) -> io::Result<()> {
+        // The queue holds `depth` entries, a full one is submitted first
+        while unsafe { self.ring.submission().push(entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, mut op: Op, done: Done) {
+        let target = self.files.target(&self.ring, &op.file);
+        let len = op.buf.len().saturating_sub(op.pos);
+        let buffer = match op.kind {
+            OpKind::Sync => None,
+            _ => self
+                .buffers
+                .first()
+                .filter(|b| len <= b.len())
+                .and_then(|_| self.free_buffers.pop()),
+        };
+        let entry = match (op.kind, buffer) {
+            (OpKind::Sync, _) => with_target!(target, |fd| opcode::Fsync::new(fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build()),
+            (OpKind::Read, Some(index)) => {
+                let ptr = self.buffers[index as usize].as_mut_ptr();
+                with_target!(target, |fd| opcode::ReadFixed::new(
+                    fd, ptr, len as u32, index
+                )
+                .offset(op.offset)
+                .build())
+            }
+            (OpKind::Write, Some(index)) => {
+                let fixed = &mut self.buffers[index as usize];
+                fixed[..len].copy_from_slice(&op.buf[op.pos..]);
+                let ptr = fixed.as_ptr();
+                with_target!(target, |fd| opcode::WriteFixed::new(
+                    fd, ptr, len as u32, index
+                )
+                .offset(op.offset)
+                .build())
+            }
+            (OpKind::Read, None) => {
+                let ptr = op.buf[op.pos..].as_mut_ptr();
+                with_target!(target, |fd| opcode::Read::new(fd, ptr, len as u32)
+                    .offset(op.offset)
+                    .build())
+            }
+            (OpKind::Write, None) => {
+                let ptr = op.buf[op.pos..].as_ptr();
+                with_target!(target, |fd| opcode::Write::new(fd, ptr, len as u32)
+                    .offset(op.offset)
+                    .build())
+            }
+        };
+        let id = self.next_id;
+        self.next_id = (self.next_id + 1) % WAKE;
+        if let Err(e) = self.push(&entry.user_data(id)) {
+            if let Some(index) = buffer {
+                self.free_buffers.push(index);
+            }
+            let _ = done.send((op.buf, Err(e)));
+            return;
+        }
+        self.running.insert(id, Running { op, done, buffer });
+    }
+
+    fn reap(&mut self) {
+        let completed: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (id, res) in completed {
+            if id == WAKE {
+                self.wake_armed = false;
+                continue;
+            }
+            let Some(Running { op, done, buffer }) = self.running.remove(&id) else {
+                continue;
+            };
+            let res = match res {
+                res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                res => Ok(res as usize),
+            };
+            let mut buf = op.buf;
+            if let Some(index) = buffer {
+                if let (OpKind::Read, Ok(n)) = (op.kind, &res) {
+                    let pos = op.pos;
+                    buf[pos..pos + n].copy_from_slice(&self.buffers[index as usize][..*n]);
+                }
+                self.free_buffers.push(index);
+            }
+            let _ = done.send((buf, res));
+        }
+    }
+}
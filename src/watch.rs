@@ -0,0 +1,251 @@
+// Torrents added by dropping `.torrent` files, or `.magnet` files holding a
+// magnet link, in a directory scanned by the session, e.g. the downloads
+// directory of a browser.
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex as StdMutex},
+    time::SystemTime,
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{self, Duration},
+};
+
+use crate::error::{Error, Result};
+use crate::session::{Session, SharedTorrent};
+use crate::state::TORRENT_EXTENSION;
+use crate::torrent::SeedLimits;
+
+pub const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+pub const MAGNET_EXTENSION: &str = "magnet";
+// Appended to the names of the files loaded and of those which failed, when
+// they stay in the watched directory
+pub const ADDED_SUFFIX: &str = "added";
+pub const INVALID_SUFFIX: &str = "invalid";
+
+/// A watched directory and the settings of the torrents added from it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchConfig {
+    pub dir: PathBuf,
+    // Loaded files are moved there, or renamed to `<name>.added` in place
+    pub move_to: Option<PathBuf>,
+    // Added paused instead of waiting for a slot in the queue
+    pub paused: bool,
+    // The session's settings if `None`
+    pub max_peers: Option<usize>,
+    pub seed_limits: Option<SeedLimits>,
+    pub interval: Duration,
+}
+
+impl WatchConfig {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WatchConfig {
+            dir: dir.into(),
+            move_to: None,
+            paused: false,
+            max_peers: None,
+            seed_limits: None,
+            interval: DEFAULT_SCAN_INTERVAL,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Torrent,
+    Magnet,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let ext = path.extension()?.to_str()?;
+    if ext.eq_ignore_ascii_case(TORRENT_EXTENSION) {
+        Some(Kind::Torrent)
+    } else if ext.eq_ignore_ascii_case(MAGNET_EXTENSION) {
+        Some(Kind::Magnet)
+    } else {
+        None
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut res: OsString = path.as_os_str().to_owned();
+    res.push(".");
+    res.push(suffix);
+    res.into()
+}
+
+type Modified = (u64, Option<SystemTime>);
+
+// Files to load in `dir` and when they were last modified
+fn scan(dir: &Path) -> HashMap<PathBuf, Modified> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            kind(&path)?;
+            let meta = fs::metadata(&path).ok().filter(|m| m.is_file())?;
+            Some((path, (meta.len(), meta.modified().ok())))
+        })
+        .collect()
+}
+
+fn already_added(e: &Error) -> bool {
+    matches!(e, Error::Io(e) if e.kind() == io::ErrorKind::AlreadyExists)
+}
+
+// Add the torrent of the file at `path` with the settings of the directory.
+// `Ok(None)` if the session already has it.
+async fn load(
+    session: &Session,
+    config: &WatchConfig,
+    path: &Path,
+) -> Result<Option<SharedTorrent>> {
+    let res = match kind(path) {
+        Some(Kind::Torrent) => session.add_torrent_bytes(&fs::read(path)?).await,
+        Some(Kind::Magnet) => session.add_magnet(fs::read_to_string(path)?.trim()).await,
+        None => return Ok(None),
+    };
+    let torrent = match res {
+        Ok(torrent) => torrent,
+        Err(e) if already_added(&e) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    {
+        let mut t = torrent.lock().await;
+        if let Some(max) = config.max_peers {
+            t.set_max_peers(Some(max));
+        }
+        if let Some(limits) = config.seed_limits {
+            t.set_seed_limits(limits);
+        }
+        if config.paused {
+            t.pause();
+        }
+    }
+    session.update_queue().await;
+    Ok(Some(torrent))
+}
+
+// Load the file and move it out of the way, whether it worked or not, so
+// that it isn't loaded again
+async fn process(session: &Session, config: &WatchConfig, path: &Path) -> io::Result<()> {
+    let target = match load(session, config, path).await {
+        Ok(_) => match (&config.move_to, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => with_suffix(path, ADDED_SUFFIX),
+        },
+        Err(_) => with_suffix(path, INVALID_SUFFIX),
+    };
+    fs::rename(path, target)
+}
+
+/// Scan `config.dir` every `config.interval` and add the torrents of the
+/// files found there, once they didn't change since the previous scan so
+/// that files still being written are left alone. Magnet links are fetched
+/// in the background.
+pub fn watch(session: Arc<Session>, config: WatchConfig) -> io::Result<JoinHandle<()>> {
+    fs::read_dir(&config.dir)?;
+    if let Some(dir) = &config.move_to {
+        fs::create_dir_all(dir)?;
+    }
+
+    let config = Arc::new(config);
+    let loading: Arc<StdMutex<HashSet<PathBuf>>> = Arc::default();
+    Ok(tokio::spawn(async move {
+        let mut last = HashMap::new();
+        loop {
+            let current = scan(&config.dir);
+            for (path, modified) in &current {
+                let stable = last.get(path) == Some(modified);
+                if !stable || !loading.lock().unwrap().insert(path.clone()) {
+                    continue;
+                }
+                let (session, config, loading) = (session.clone(), config.clone(), loading.clone());
+                let path = path.clone();
+                tokio::spawn(async move {
+                    // A file which can't be moved is tried again
+                    let _ = process(&session, &config, &path).await;
+                    loading.lock().unwrap().remove(&path);
+                });
+            }
+            last = current;
+            time::sleep(config.interval).await;
+        }
+    }))
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use crate::decode_torrent::{self, Info, MetaInfo};
+    use crate::session::SessionConfig;
+    use crate::torrent::TorrentState;
+    use bendy::encoding::ToBencode;
+
+    #[tokio::test]
+    async fn add_dropped_files() {
+        const DIR: &str = "./test_watch_add_dropped_files";
+        let watched = Path::new(DIR).join("watched");
+        fs::create_dir_all(&watched).unwrap();
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .save_path(DIR)
+            .build()
+            .unwrap();
+        let session = Arc::new(Session::new(config).await.unwrap());
+        let mut config = WatchConfig::new(&watched);
+        config.move_to = Some(Path::new(DIR).join("loaded"));
+        config.paused = true;
+        config.max_peers = Some(3);
+        config.interval = Duration::from_millis(20);
+        let watcher = watch(session.clone(), config).unwrap();
+
+        let meta = MetaInfo {
+            announce: String::new(),
+            info: Info {
+                piece_length: "16384".to_string(),
+                pieces: vec![decode_torrent::bytes_to_hash(&[0; 20])],
+                name: "content".to_string(),
+                file_length: "16384".to_string(),
+                md5sum: None,
+                files: None,
+                private: false,
+                meta_version: None,
+                file_tree: None,
+            },
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            http_seeds: None,
+            url_list: None,
+            nodes: None,
+            piece_layers: None,
+        };
+        let bytes = meta.to_bencode().unwrap();
+        fs::write(watched.join("a.torrent"), &bytes).unwrap();
+        fs::write(watched.join("broken.torrent"), b"garbage").unwrap();
+        fs::write(watched.join("notes.txt"), b"ignored").unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        let hash = decode_torrent::get_info_hash(&bytes).unwrap();
+        let torrent = session.get(&hash).unwrap();
+        let t = torrent.lock().await;
+        assert_eq!(TorrentState::Paused, t.state());
+        assert_eq!(Some(3), t.max_peers());
+        assert!(Path::new(DIR).join("loaded/a.torrent").exists());
+        assert!(!watched.join("a.torrent").exists());
+        assert!(watched.join("broken.torrent.invalid").exists());
+        assert!(watched.join("notes.txt").exists());
+
+        watcher.abort();
+        fs::remove_dir_all(DIR).unwrap();
+    }
+}
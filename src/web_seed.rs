@@ -0,0 +1,572 @@
+// HTTP seeds of a torrent: those of the `url-list`, see
+// http://bittorrent.org/beps/bep_0019.html, and the seed services of
+// `httpseeds`, see http://bittorrent.org/beps/bep_0017.html. A web seed
+// stands in the scheduler as a peer with every piece. The blocks it is asked
+// for are fetched with ranged GETs of the files they cover, or of the piece
+// for a seed service, and stored like those of a peer. Only plain HTTP is
+// spoken.
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, TcpStream},
+    time::{self, Duration},
+};
+
+use crate::decode_torrent::MetaInfo;
+use crate::definitions::InfoHash;
+use crate::dialer::Dialer;
+use crate::file::SharedFile;
+use crate::layout::Layout;
+use crate::magnet::percent_encode;
+use crate::peer::{self, PeerEvent, PeerEventSender};
+use crate::scheduler::Request;
+use crate::stats::RateMeter;
+
+// Blocks outstanding on a web seed at most, its rate sizes how many it gets
+pub const WEB_SEED_REQUESTS: usize = 64;
+// A web seed which failed is left alone this long, twice longer at each
+// failure in a row up to the max, unless it tells how long
+pub const WEB_SEED_RETRY: Duration = Duration::from_secs(60);
+pub const MAX_WEB_SEED_RETRY: Duration = Duration::from_secs(60 * 60);
+pub const WEB_SEED_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: usize = 5;
+const MAX_HEADER_LEN: usize = 16384;
+
+#[derive(Debug, Error)]
+pub enum WebSeedError {
+    #[error("Web seed unreachable: {0}")]
+    Io(#[from] io::Error),
+    // e.g. HTTPS
+    #[error("Unsupported web seed {0}")]
+    Unsupported(String),
+    #[error("Web seed answered {0}")]
+    Status(u16),
+    // BEP 17 seeds tell when to come back
+    #[error("Web seed busy, retry in {0:?}")]
+    Busy(Duration),
+    #[error("Invalid web seed response")]
+    InvalidResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HttpUrl {
+    host: String,
+    port: u16,
+    // With the query, if any
+    path: String,
+}
+
+impl HttpUrl {
+    fn parse(url: &str) -> Result<Self, WebSeedError> {
+        let unsupported = || WebSeedError::Unsupported(url.to_string());
+        let rest = url.strip_prefix("http://").ok_or_else(unsupported)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| unsupported())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(unsupported());
+        }
+        Ok(HttpUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    // Location of a redirect, absolute or on the same server
+    fn join(&self, location: &str) -> Result<Self, WebSeedError> {
+        match location.starts_with('/') {
+            true => Ok(HttpUrl {
+                path: location.to_string(),
+                ..self.clone()
+            }),
+            false => HttpUrl::parse(location),
+        }
+    }
+}
+
+/// URL of a file of the torrent on the web seed at `base`. A directory
+/// holds the files under their path in the torrent, name first.
+pub fn file_url(base: &str, path: &Path, multi_file: bool) -> String {
+    if !multi_file && !base.ends_with('/') {
+        return base.to_string();
+    }
+    let mut res = base.trim_end_matches('/').to_string();
+    for component in path.iter() {
+        res.push('/');
+        res.push_str(&percent_encode(&component.to_string_lossy()));
+    }
+    res
+}
+
+// How the pieces are asked for
+#[derive(Debug, Clone)]
+enum Protocol {
+    // BEP 19, byte ranges of the files. Paths as in the torrent
+    UrlList {
+        layout: Arc<Layout>,
+        multi_file: bool,
+    },
+    // BEP 17, pieces by index
+    HttpSeed {
+        info_hash: InfoHash,
+    },
+}
+
+/// Transfer of a web seed, see `Torrent::web_seeds`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WebSeedStats {
+    pub url: String,
+    // Scheduled, i.e. not backing off after a failure
+    pub active: bool,
+    // Failures in a row
+    pub failures: u32,
+    pub downloaded: u64,
+    // Bytes per second
+    pub download_rate: f64,
+}
+
+/// A web seed of a torrent, as the torrent schedules it.
+#[derive(Debug)]
+pub struct WebSeed {
+    url: String,
+    addr: SocketAddr,
+    protocol: Protocol,
+    // In the scheduler
+    active: bool,
+    failures: u32,
+    // When it may be scheduled again after a failure
+    retry_at: Option<Instant>,
+    // Asked by the seed when busy, set by the fetch which failed
+    retry_after: Arc<Mutex<Option<Duration>>>,
+    download: RateMeter,
+}
+
+impl WebSeed {
+    fn new(url: &str, index: usize, protocol: Protocol) -> Option<Self> {
+        HttpUrl::parse(url).ok()?;
+        Some(WebSeed {
+            url: url.to_string(),
+            // The unspecified address is no peer's
+            addr: SocketAddr::from((Ipv4Addr::UNSPECIFIED, index as u16)),
+            protocol,
+            active: false,
+            failures: 0,
+            retry_at: None,
+            retry_after: Arc::default(),
+            download: RateMeter::new(Instant::now()),
+        })
+    }
+
+    /// The web seeds of a torrent which can be used, those of the url-list
+    /// then those of httpseeds.
+    pub fn for_torrent(meta: &MetaInfo, info_hash: InfoHash) -> Vec<Self> {
+        let url_list = meta.url_list.as_deref().unwrap_or_default();
+        let http_seeds = meta.http_seeds.as_deref().unwrap_or_default();
+        let mut res = Vec::new();
+        if let (false, Ok(layout)) = (url_list.is_empty(), Layout::from_info(&meta.info)) {
+            let protocol = Protocol::UrlList {
+                layout: Arc::new(layout),
+                multi_file: meta.info.files.is_some(),
+            };
+            res.extend(
+                url_list
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, url)| WebSeed::new(url, i, protocol.clone())),
+            );
+        }
+        let protocol = Protocol::HttpSeed { info_hash };
+        res.extend(
+            http_seeds
+                .iter()
+                .enumerate()
+                .filter_map(|(i, url)| WebSeed::new(url, url_list.len() + i, protocol.clone())),
+        );
+        res
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Where the web seed stands in the scheduler.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn stats(&self) -> WebSeedStats {
+        WebSeedStats {
+            url: self.url.clone(),
+            active: self.active,
+            failures: self.failures,
+            downloaded: self.download.total(),
+            download_rate: self.download.rate(),
+        }
+    }
+
+    /// Whether the web seed is to be added to the scheduler now: it isn't
+    /// and didn't fail lately.
+    pub fn activate(&mut self, now: Instant) -> bool {
+        if self.active || self.retry_at.is_some_and(|at| now < at) {
+            return false;
+        }
+        self.active = true;
+        true
+    }
+
+    pub fn received(&mut self, length: usize) {
+        self.download.add(length);
+        // Fetches sent before a failure don't reset the backoff
+        if self.active {
+            self.failures = 0;
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.download.tick(now);
+    }
+
+    /// Left out of the scheduler for as long as the seed asked, or twice
+    /// longer at each failure in a row otherwise.
+    pub fn failed(&mut self, now: Instant) {
+        if !self.active {
+            return;
+        }
+        self.active = false;
+        self.failures += 1;
+        let backoff = WEB_SEED_RETRY.saturating_mul(1 << (self.failures - 1).min(16));
+        let asked = self.retry_after.lock().unwrap().take();
+        self.retry_at = Some(now + asked.unwrap_or(backoff.min(MAX_WEB_SEED_RETRY)));
+    }
+
+    /// Fetch the blocks of `requests`, contiguous in one piece, and store
+    /// them in `file` like those of a peer. The outcome is sent through
+    /// `events`, a failure closes the web seed.
+    pub fn spawn_fetch(
+        &self,
+        requests: Vec<Request>,
+        dialer: Dialer,
+        file: SharedFile,
+        meta: Arc<MetaInfo>,
+        events: PeerEventSender,
+    ) {
+        let (url, addr) = (self.url.clone(), self.addr);
+        let (protocol, retry_after) = (self.protocol.clone(), self.retry_after.clone());
+        tokio::spawn(async move {
+            let (Some(first), Some(last)) = (requests.first(), requests.last()) else {
+                return;
+            };
+            let (index, begin) = (first.index, first.begin);
+            let length = last.begin + last.length - begin;
+            let fetch = fetch(&protocol, &dialer, &url, index, begin, length);
+            let data = match time::timeout(WEB_SEED_TIMEOUT, fetch).await {
+                Ok(Ok(data)) => data,
+                res => {
+                    if let Ok(Err(WebSeedError::Busy(after))) = res {
+                        *retry_after.lock().unwrap() = Some(after);
+                    }
+                    let _ = events.send((addr, PeerEvent::Closed));
+                    return;
+                }
+            };
+            for request in requests {
+                let offset = request.begin - begin;
+                let block = &data[offset..offset + request.length];
+                let event = PeerEvent::Block {
+                    index,
+                    begin: request.begin,
+                    length: request.length,
+                };
+                let _ = events.send((addr, event));
+                match peer::store_block(&file, &meta, index, request.begin, block).await {
+                    Ok(Some(valid)) => {
+                        let _ = events.send((addr, PeerEvent::PieceVerified { index, valid }));
+                    }
+                    Ok(None) => (),
+                    Err(_) => {
+                        let _ = events.send((addr, PeerEvent::Closed));
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn fetch(
+    protocol: &Protocol,
+    dialer: &Dialer,
+    url: &str,
+    index: usize,
+    begin: usize,
+    length: usize,
+) -> Result<Vec<u8>, WebSeedError> {
+    let (layout, multi_file) = match protocol {
+        Protocol::UrlList { layout, multi_file } => (layout, *multi_file),
+        Protocol::HttpSeed { info_hash } => {
+            return get_piece(dialer, url, info_hash, index, begin, length).await
+        }
+    };
+    let mut data = Vec::with_capacity(length);
+    let slices = layout
+        .slices(index, begin, length)
+        .ok_or(WebSeedError::InvalidResponse)?;
+    for slice in slices {
+        let f = &layout.files()[slice.file];
+        if f.attr.padding {
+            data.resize(data.len() + slice.length, 0);
+            continue;
+        }
+        let url = file_url(url, &f.path, multi_file);
+        data.extend(get_range(dialer, &url, slice.offset, slice.length).await?);
+    }
+    Ok(data)
+}
+
+/// Status and body of a GET of `url`, with the `Range` of `range` if any,
+/// following redirects. Reading stops after `limit` bytes of body.
+pub async fn get(
+    dialer: &Dialer,
+    url: &str,
+    range: Option<(u64, usize)>,
+    limit: usize,
+) -> Result<(u16, Vec<u8>), WebSeedError> {
+    let mut url = HttpUrl::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let addr = lookup_host((url.host.as_str(), url.port))
+            .await?
+            .next()
+            .ok_or(WebSeedError::InvalidResponse)?;
+        let mut stream = dialer.connect(addr).await?.into_stream();
+        let mut request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n", url.path, url.host);
+        if let Some((offset, length)) = range {
+            let end = offset + length as u64 - 1;
+            request.push_str(&format!("Range: bytes={}-{}\r\n", offset, end));
+        }
+        request.push_str("Connection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        let (status, headers, mut body) = read_head(&mut stream).await?;
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        if let 301 | 302 | 303 | 307 | 308 = status {
+            let location = header("Location").ok_or(WebSeedError::InvalidResponse)?;
+            url = url.join(location)?;
+            continue;
+        }
+        // Without a length, the body ends with the connection
+        let length = header("Content-Length").and_then(|l| l.parse::<usize>().ok());
+        let limit = length.map_or(limit, |length| length.min(limit));
+        while body.len() < limit {
+            let mut buf = vec![0; (limit - body.len()).min(65536)];
+            match stream.read(&mut buf).await? {
+                0 => break,
+                n => body.extend_from_slice(&buf[..n]),
+            }
+        }
+        body.truncate(limit);
+        return Ok((status, body));
+    }
+    Err(WebSeedError::InvalidResponse)
+}
+
+/// `length` bytes of the file at `url` from `offset`, see BEP 19.
+pub async fn get_range(
+    dialer: &Dialer,
+    url: &str,
+    offset: u64,
+    length: usize,
+) -> Result<Vec<u8>, WebSeedError> {
+    let limit = offset as usize + length;
+    let (status, body) = get(dialer, url, Some((offset, length)), limit).await?;
+    let skip = match status {
+        206 => 0,
+        // The range was ignored, the whole file follows
+        200 => offset as usize,
+        status => return Err(WebSeedError::Status(status)),
+    };
+    body.get(skip..skip + length)
+        .map(<[u8]>::to_vec)
+        .ok_or(WebSeedError::InvalidResponse)
+}
+
+/// `length` bytes of piece `index` of the torrent of `info_hash` from
+/// `begin`, from the seed service at `url`, see BEP 17.
+pub async fn get_piece(
+    dialer: &Dialer,
+    url: &str,
+    info_hash: &InfoHash,
+    index: usize,
+    begin: usize,
+    length: usize,
+) -> Result<Vec<u8>, WebSeedError> {
+    let url = piece_url(url, info_hash, index, begin, length);
+    match get(dialer, &url, None, length).await? {
+        (200, body) if body.len() == length => Ok(body),
+        // The body is how many seconds to wait
+        (503, body) => {
+            let secs = String::from_utf8_lossy(&body).trim().parse().unwrap_or(0);
+            Err(WebSeedError::Busy(Duration::from_secs(secs)))
+        }
+        (200, _) => Err(WebSeedError::InvalidResponse),
+        (status, _) => Err(WebSeedError::Status(status)),
+    }
+}
+
+fn piece_url(url: &str, info_hash: &InfoHash, index: usize, begin: usize, length: usize) -> String {
+    let escaped: String = info_hash.iter().map(|b| format!("%{:02X}", b)).collect();
+    let separator = match url.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    format!(
+        "{}{}info_hash={}&piece={}&ranges={}-{}",
+        url,
+        separator,
+        escaped,
+        index,
+        begin,
+        begin + length - 1
+    )
+}
+
+// Status, headers and what was read of the body
+async fn read_head(
+    stream: &mut TcpStream,
+) -> Result<(u16, Vec<(String, String)>, Vec<u8>), WebSeedError> {
+    let mut buf = Vec::new();
+    let end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buf.len() > MAX_HEADER_LEN {
+            return Err(WebSeedError::InvalidResponse);
+        }
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk).await? {
+            0 => return Err(WebSeedError::InvalidResponse),
+            n => buf.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or(WebSeedError::InvalidResponse)?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok((status, headers, buf.split_off(end + 4)))
+}
+
+#[cfg(test)]
+mod web_seed_tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn urls() {
+        let path = PathBuf::from("name").join("dir a").join("b.iso");
+        assert_eq!(
+            "http://h/seed/name/dir%20a/b.iso",
+            file_url("http://h/seed/", &path, true)
+        );
+        assert_eq!(
+            "http://h/seed/name/dir%20a/b.iso",
+            file_url("http://h/seed", &path, true)
+        );
+        assert_eq!(
+            "http://h/x.iso",
+            file_url("http://h/x.iso", Path::new("name"), false)
+        );
+        assert_eq!(
+            "http://h/name",
+            file_url("http://h/", Path::new("name"), false)
+        );
+
+        let url = HttpUrl::parse("http://example.org:8080/a?b").unwrap();
+        assert_eq!(
+            ("example.org", 8080, "/a?b"),
+            (&*url.host, url.port, &*url.path)
+        );
+        assert_eq!("/c", url.join("/c").unwrap().path);
+        assert_eq!(80, HttpUrl::parse("http://example.org").unwrap().port);
+        assert!(matches!(
+            HttpUrl::parse("https://example.org/"),
+            Err(WebSeedError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn http_seed_service() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/seed?x=1", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut paths = Vec::new();
+            for reply in ["503 Service Unavailable\r\n\r\n30", "200 OK\r\n\r\nabcd"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                paths.push(head.split(' ').nth(1).unwrap().to_string());
+                let reply = format!("HTTP/1.1 {}", reply);
+                stream.write_all(reply.as_bytes()).await.unwrap();
+            }
+            paths
+        });
+
+        let dialer = Dialer::default();
+        let info_hash = [0xab; 20];
+        let res = get_piece(&dialer, &url, &info_hash, 3, 16384, 4).await;
+        assert!(matches!(res, Err(WebSeedError::Busy(d)) if d == Duration::from_secs(30)));
+        let res = get_piece(&dialer, &url, &info_hash, 3, 16384, 4).await;
+        assert_eq!(b"abcd".to_vec(), res.unwrap());
+        let path = format!(
+            "/seed?x=1&info_hash={}&piece=3&ranges=16384-16387",
+            "%AB".repeat(20)
+        );
+        assert_eq!(vec![path.clone(), path], server.await.unwrap());
+
+        // Failures back off, unless the seed tells how long
+        let protocol = Protocol::HttpSeed { info_hash };
+        let mut seed = WebSeed::new(&url, 0, protocol).unwrap();
+        let mut now = Instant::now();
+        for failures in 1..=3 {
+            assert!(seed.activate(now));
+            seed.failed(now);
+            let backoff = WEB_SEED_RETRY * (1 << (failures - 1));
+            assert_eq!(Some(now + backoff), seed.retry_at);
+            assert!(!seed.activate(now));
+            now += backoff;
+        }
+        assert!(seed.activate(now));
+        *seed.retry_after.lock().unwrap() = Some(Duration::from_secs(30));
+        seed.failed(now);
+        assert_eq!(Some(now + Duration::from_secs(30)), seed.retry_at);
+        assert_eq!(4, seed.stats().failures);
+    }
+}
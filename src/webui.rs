@@ -0,0 +1,72 @@
+// Small web UI served next to the RPC, which it uses for everything. The
+// assets are built into the binary so that nothing has to be installed.
+
+pub const INDEX_PATH: &str = "/";
+
+const ASSETS: [(&str, &str, &str); 4] = [
+    (
+        INDEX_PATH,
+        "text/html; charset=utf-8",
+        include_str!("../assets/webui/index.html"),
+    ),
+    (
+        "/index.html",
+        "text/html; charset=utf-8",
+        include_str!("../assets/webui/index.html"),
+    ),
+    (
+        "/app.js",
+        "text/javascript; charset=utf-8",
+        include_str!("../assets/webui/app.js"),
+    ),
+    (
+        "/style.css",
+        "text/css; charset=utf-8",
+        include_str!("../assets/webui/style.css"),
+    ),
+];
+
+/// Content type and content of the asset at `path`.
+pub fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    ASSETS
+        .iter()
+        .find(|(p, _, _)| *p == path)
+        .map(|&(_, content_type, content)| (content_type, content))
+}
+
+#[cfg(test)]
+mod webui_tests {
+    use super::*;
+    use crate::rpc::Rpc;
+    use crate::session::{Session, SessionConfig};
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    #[tokio::test]
+    async fn serve_assets() {
+        let config = SessionConfig::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .build()
+            .unwrap();
+        let session = Arc::new(Session::new(config).await.unwrap());
+        let (addr, server) = Rpc::new(session)
+            .serve("127.0.0.1:0".parse().unwrap())
+            .unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = "GET /app.js HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"));
+        assert!(res.contains("text/javascript"));
+        assert!(res.contains("torrent-get"));
+
+        assert!(asset(INDEX_PATH).unwrap().1.contains("app.js"));
+        assert!(asset("/missing").is_none());
+        server.abort();
+    }
+}
@@ -13,7 +13,7 @@ async fn connect_announce_handshake() {
     let mut udpc = tracker::UdpConnection::new(TRACKER, None).await.unwrap();
     udpc.connect().await.unwrap();
 
-    let hash_bytes: definitions::InfoHash = tracker::hash_to_bytes(HASH);
+    let hash_bytes: definitions::InfoHash = tracker::hash_to_bytes(HASH).unwrap();
 
     let ann = udpc.announce(HASH, None, Some(1)).await.unwrap();
 
@@ -22,8 +22,10 @@ async fn connect_announce_handshake() {
         .await
         .unwrap();
 
+    let peer_id = definitions::new_peer_id();
     let mut hs = handshake::Handshake::default();
     hs.set_hash(&hash_bytes);
+    hs.set_peer_id(&peer_id);
 
     let hs = match hs.send(&mut stream).await {
         Ok(hs) => hs,
@@ -31,5 +33,5 @@ async fn connect_announce_handshake() {
     };
 
     assert_eq!(hash_bytes, *hs.get_hash());
-    assert_ne!(*definitions::TORRENT_RS_PEER_ID, *hs.get_peer_id());
+    assert_ne!(peer_id, *hs.get_peer_id());
 }
@@ -11,7 +11,7 @@ use serial_test::serial;
 async fn common() -> (handshake::Handshake, Arc<RwLock<peer::Peer>>) {
     let torrent = fs::read("./tests/torrent_files/test_local.torrent").unwrap();
     let meta_info = decode_torrent::MetaInfo::from_bencode(&torrent).unwrap();
-    let info_hash = decode_torrent::get_info_hash(&torrent);
+    let info_hash = decode_torrent::get_info_hash(&torrent).unwrap();
     let hash = decode_torrent::bytes_to_hash(&info_hash);
 
     let mut udpc = tracker::UdpConnection::new(&meta_info.announce[6..], None)
@@ -24,7 +24,7 @@ async fn common() -> (handshake::Handshake, Arc<RwLock<peer::Peer>>) {
 
     let mut hs = handshake::Handshake::default();
     hs.set_hash(&info_hash);
-    let peer = peer::Peer::new(addr, port, meta_info).await.unwrap();
+    let peer = peer::Peer::new(addr, port, meta_info, ".").await.unwrap();
     {
         let mut peer = peer.write().await;
         let stream = peer.get_stream_mut();